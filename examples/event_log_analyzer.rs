@@ -0,0 +1,71 @@
+//! Replay an event log written by a database opened with
+//! DBConfig::event_log_file and print how the LSM tree changed shape.
+//!
+//! Usage: cargo run --features cli --example event_log_analyzer -- <event log>
+
+use std::{env, process};
+
+use rebeldb::{env::PosixEnv, replay_event_log};
+
+/// Width of the bar drawn for the largest level
+const BAR_WIDTH: u64 = 40;
+
+fn main() {
+    let fname = match env::args().nth(1) {
+        Some(fname) => fname,
+        None => {
+            eprintln!("usage: event_log_analyzer <event log>");
+            process::exit(2);
+        }
+    };
+    let events = match replay_event_log(&PosixEnv::new(), &fname) {
+        Ok(events) => events,
+        Err(error) => {
+            eprintln!("{}: {}", fname, error);
+            process::exit(1);
+        }
+    };
+    let start = match events.first() {
+        Some(event) => event.time_micros,
+        None => return,
+    };
+
+    let max_bytes = events
+        .iter()
+        .flat_map(|event| event.bytes_per_level.iter().copied())
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    for event in &events {
+        println!(
+            "+{:.3}s {}",
+            (event.time_micros - start) as f64 / 1e6,
+            event.event
+        );
+        for (level, (files, bytes)) in event
+            .files_per_level
+            .iter()
+            .zip(&event.bytes_per_level)
+            .enumerate()
+        {
+            if *files == 0 {
+                continue;
+            }
+            let bar = "#".repeat((bytes * BAR_WIDTH).div_ceil(max_bytes) as usize);
+            println!(
+                "  L{} {:>4} files {:>12} bytes {}",
+                level, files, bytes, bar
+            );
+        }
+    }
+
+    let last = events.last().unwrap();
+    let total: u64 = last.bytes_per_level.iter().sum();
+    println!(
+        "{} events over {:.3}s, {} bytes in {} files at the end",
+        events.len(),
+        (last.time_micros - start) as f64 / 1e6,
+        total,
+        last.files_per_level.iter().sum::<u64>()
+    );
+}
@@ -0,0 +1,180 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, ThreadId},
+    time::{Duration, Instant},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OperationType {
+    /// Writing a memtable out as a level-0 table
+    Flush,
+    /// Merging tables of one level into the next
+    Compaction,
+}
+
+/// What a flush or compaction was doing when
+/// DB::get_background_operations() was called.
+#[derive(Clone, Debug)]
+pub struct BackgroundOperation {
+    /// The thread running the operation
+    pub thread_id: ThreadId,
+    pub operation_type: OperationType,
+    /// Level the inputs are read from; None for flushes, which read a
+    /// memtable
+    pub input_level: Option<usize>,
+    /// Level the outputs are written to
+    pub output_level: usize,
+    /// Bytes of input consumed so far
+    pub bytes_read: u64,
+    /// Bytes of table files written so far
+    pub bytes_written: u64,
+    /// Time since the operation started
+    pub elapsed: Duration,
+}
+
+struct OperationState {
+    thread_id: ThreadId,
+    operation_type: OperationType,
+    input_level: Option<usize>,
+    output_level: usize,
+    start: Instant,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+}
+
+/// The flushes and compactions of a DB that are in progress.  Progress
+/// counters are updated without taking the lock, so they are cheap to
+/// bump once per compacted entry.
+#[derive(Default)]
+pub struct OperationTracker {
+    operations: Mutex<Vec<Arc<OperationState>>>,
+}
+
+impl OperationTracker {
+    /// Register an operation running on the calling thread.  It is
+    /// listed until the returned handle is dropped.
+    pub fn start(
+        self: &Arc<Self>,
+        operation_type: OperationType,
+        input_level: Option<usize>,
+        output_level: usize,
+    ) -> OperationHandle {
+        let state = Arc::new(OperationState {
+            thread_id: thread::current().id(),
+            operation_type,
+            input_level,
+            output_level,
+            start: Instant::now(),
+            bytes_read: AtomicU64::new(0),
+            bytes_written: AtomicU64::new(0),
+        });
+        self.operations.lock().unwrap().push(state.clone());
+        OperationHandle {
+            tracker: self.clone(),
+            state,
+        }
+    }
+
+    /// A snapshot of all operations in progress, oldest first.
+    pub fn operations(&self) -> Vec<BackgroundOperation> {
+        self.operations
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|state| BackgroundOperation {
+                thread_id: state.thread_id,
+                operation_type: state.operation_type,
+                input_level: state.input_level,
+                output_level: state.output_level,
+                bytes_read: state.bytes_read.load(Ordering::Relaxed),
+                bytes_written: state.bytes_written.load(Ordering::Relaxed),
+                elapsed: state.start.elapsed(),
+            })
+            .collect()
+    }
+}
+
+/// Reports the progress of one operation and unregisters it when dropped.
+pub struct OperationHandle {
+    tracker: Arc<OperationTracker>,
+    state: Arc<OperationState>,
+}
+
+impl OperationHandle {
+    pub fn add_bytes_read(&self, bytes: u64) {
+        self.state.bytes_read.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn set_bytes_written(&self, bytes: u64) {
+        self.state.bytes_written.store(bytes, Ordering::Relaxed);
+    }
+}
+
+impl Drop for OperationHandle {
+    fn drop(&mut self) {
+        self.tracker
+            .operations
+            .lock()
+            .unwrap()
+            .retain(|state| !Arc::ptr_eq(state, &self.state));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::*;
+
+    #[test]
+    fn test_operation_tracker() {
+        let tracker = Arc::new(OperationTracker::default());
+        assert!(tracker.operations().is_empty());
+
+        let flush = tracker.start(OperationType::Flush, None, 0);
+        flush.add_bytes_read(100);
+        flush.set_bytes_written(40);
+
+        // An operation on another thread shows up with its thread id
+        let (started, wait_started) = mpsc::channel();
+        let (finish, wait_finish) = mpsc::channel::<()>();
+        let background = {
+            let tracker = tracker.clone();
+            thread::spawn(move || {
+                let compaction = tracker.start(OperationType::Compaction, Some(1), 2);
+                compaction.add_bytes_read(10);
+                compaction.add_bytes_read(20);
+                started.send(()).unwrap();
+                wait_finish.recv().unwrap();
+                drop(compaction);
+                thread::current().id()
+            })
+        };
+        wait_started.recv().unwrap();
+
+        let operations = tracker.operations();
+        assert_eq!(2, operations.len());
+        assert_eq!(OperationType::Flush, operations[0].operation_type);
+        assert_eq!(None, operations[0].input_level);
+        assert_eq!(100, operations[0].bytes_read);
+        assert_eq!(40, operations[0].bytes_written);
+        assert_eq!(thread::current().id(), operations[0].thread_id);
+        assert_eq!(OperationType::Compaction, operations[1].operation_type);
+        assert_eq!(Some(1), operations[1].input_level);
+        assert_eq!(2, operations[1].output_level);
+        assert_eq!(30, operations[1].bytes_read);
+
+        finish.send(()).unwrap();
+        let background_id = background.join().unwrap();
+        assert_eq!(background_id, operations[1].thread_id);
+        let operations = tracker.operations();
+        assert_eq!(1, operations.len());
+        assert_eq!(OperationType::Flush, operations[0].operation_type);
+
+        drop(flush);
+        assert!(tracker.operations().is_empty());
+    }
+}
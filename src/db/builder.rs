@@ -0,0 +1,83 @@
+use super::{DBConfig, ReadConfig};
+use crate::{
+    dbformat::RangeTombstone,
+    env::{Env, WriteHint},
+    filename::table_file_name_for,
+    iterator::Iterator,
+    table::{TableBuilder, TableCache},
+    version::FileMetaData,
+    Result,
+};
+
+/// Build a Table file from the contents of "iter" and the range
+/// tombstones "range_tombstones".  The generated file will be named
+/// according to meta.number.  On success, the rest of "meta" will be
+/// filled with metadata about the generated table.  If no data is
+/// present in "iter" and there are no tombstones, meta.file_size will be
+/// set to zero, and no Table file will be produced.
+pub fn build_table(
+    dbname: &str,
+    env: &dyn Env,
+    config: &DBConfig,
+    table_cache: &TableCache,
+    iter: &mut dyn Iterator,
+    range_tombstones: &[RangeTombstone],
+    meta: &mut FileMetaData,
+) -> Result<()> {
+    meta.file_size = 0;
+    iter.seek_to_first();
+
+    let fname = table_file_name_for(dbname, meta.number, config.table_file_extension);
+    let mut result = Ok(());
+    if iter.valid() || !range_tombstones.is_empty() {
+        let file = env.new_writable_file(&fname, WriteHint::TableHot)?;
+        let mut builder = TableBuilder::new(config, file);
+        while iter.valid() && builder.add(iter.key(), iter.value()).is_ok() {
+            iter.next();
+        }
+        for tombstone in range_tombstones {
+            builder.add_range_tombstone(tombstone.clone());
+        }
+
+        // Finish and check for builder errors
+        result = builder.finish();
+        if result.is_ok() {
+            meta.file_size = builder.file_size();
+            assert!(meta.file_size > 0);
+            if let (Some(smallest), Some(largest)) = (builder.smallest_key(), builder.largest_key())
+            {
+                meta.smallest.decode_from(smallest);
+                meta.largest.decode_from(largest);
+            }
+            // The sanitized comparator of the config orders internal keys
+            for tombstone in range_tombstones {
+                meta.extend_to_range_tombstone(config.comparator.as_ref(), tombstone);
+            }
+        }
+
+        // Finish and check for file errors
+        if result.is_ok() {
+            result = builder.file().sync();
+        }
+        if result.is_ok() {
+            result = builder.file().close();
+        }
+
+        if result.is_ok() {
+            // Verify that the table is usable
+            result = table_cache
+                .new_iterator(&ReadConfig::default(), meta.number, meta.file_size)
+                .status();
+        }
+    }
+
+    // Check for input iterator errors
+    if let Err(error) = iter.status() {
+        result = Err(error);
+    }
+
+    if result.is_err() || meta.file_size == 0 {
+        let _ = env.remove_file(&fname);
+    }
+    result
+}
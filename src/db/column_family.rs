@@ -0,0 +1,50 @@
+use std::sync::Arc;
+
+use crate::memtable::MemTable;
+
+/// The id of the column family every database has.  It holds what is
+/// written without a column family handle.
+pub const DEFAULT_COLUMN_FAMILY: u32 = 0;
+
+/// The name of the default column family
+pub const DEFAULT_COLUMN_FAMILY_NAME: &str = "default";
+
+/// Refers to a column family of a DB, a keyspace with its own memtable
+/// and levels of table files.  All the column families of a DB share
+/// its log and MANIFEST, so that a write batch that spans several of
+/// them is still applied atomically.
+///
+/// Handles are returned by DB::create_column_family() and
+/// DB::column_family(), and stay valid until the column family is
+/// dropped.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ColumnFamilyHandle {
+    id: u32,
+    name: String,
+}
+
+impl ColumnFamilyHandle {
+    pub(crate) fn new(id: u32, name: &str) -> Self {
+        Self {
+            id,
+            name: name.to_string(),
+        }
+    }
+
+    /// Return the id under which the column family is stored.  Ids are
+    /// never reused by a DB.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// What a DB keeps for a column family other than the default one, whose
+/// memtables are the DB's own
+pub(super) struct ColumnFamilyData {
+    pub(super) handle: ColumnFamilyHandle,
+    pub(super) mem: Arc<MemTable>,
+}
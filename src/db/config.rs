@@ -1,5 +1,571 @@
-pub struct DBConfig {}
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
-pub struct WriteConfig {}
+use super::{EventListener, Snapshot};
+use crate::{
+    dbformat::{L0_SLOWDOWN_WRITES_TRIGGER, L0_STOP_WRITES_TRIGGER},
+    env::{Env, PosixEnv},
+    table::Block,
+    util::{
+        BytewiseComparator, Cache, CompactionFilter, Comparator, Error, FilterPolicy,
+        MergeOperator, OutputPartitioner, PrefixExtractor, RateLimiter, Result, WriteBufferManager,
+    },
+};
 
-pub struct ReadConfig {}
+/// DB contents are stored in a set of blocks, each of which holds a
+/// sequence of key,value pairs.  Each block may be compressed before
+/// being stored in a file.  The following enum describes which
+/// compression method (if any) is used to compress a block.
+///
+/// NOTE: do not change the values of existing entries, as these are
+/// part of the persistent format on disk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum CompressionType {
+    None = 0x0,
+    /// Only available with the "snappy" cargo feature, which is on by
+    /// default.  Without it, blocks are stored uncompressed and snappy
+    /// blocks cannot be read.
+    Snappy = 0x1,
+    /// Only available with the "zstd" cargo feature.  Without it, blocks
+    /// are stored uncompressed and zstd blocks cannot be read.
+    Zstd = 0x2,
+}
+
+impl TryFrom<u8> for CompressionType {
+    type Error = u8;
+
+    fn try_from(value: u8) -> std::result::Result<Self, u8> {
+        match value {
+            0x0 => Ok(Self::None),
+            0x1 => Ok(Self::Snappy),
+            0x2 => Ok(Self::Zstd),
+            _ => Err(value),
+        }
+    }
+}
+
+/// Extension given to newly written table files.  Tables with either
+/// extension are read, whatever this is set to: ".sst" is what leveldb
+/// wrote before switching to ".ldb", and is still expected by some tools.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TableFileExtension {
+    Ldb,
+    Sst,
+}
+
+impl TableFileExtension {
+    /// The extension that is not preferred, tried when a table is not
+    /// found under the preferred one.
+    pub fn alternate(self) -> Self {
+        match self {
+            Self::Ldb => Self::Sst,
+            Self::Sst => Self::Ldb,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct DBConfig {
+    /// Comparator used to define the order of keys in the table.
+    /// Default: a comparator that uses lexicographic byte-wise ordering
+    ///
+    /// REQUIRES: The client must ensure that the comparator supplied
+    /// here has the same name and orders keys *exactly* the same as the
+    /// comparator provided to previous open calls on the same DB.  The
+    /// name is recorded in the MANIFEST, and opening the DB with a
+    /// comparator of another name fails with an error that satisfies
+    /// Error::is_invalid_argument().
+    pub comparator: Arc<dyn Comparator>,
+    /// If true, the database will be created if it is missing.
+    pub create_if_missing: bool,
+    /// If true, an error is raised if the database already exists.
+    pub error_if_exists: bool,
+    /// If true, the implementation will do aggressive checking of the
+    /// data it is processing and will stop early if it detects any
+    /// errors.  This may have unforeseen ramifications: for example, a
+    /// corruption of one DB entry may cause a large number of entries to
+    /// become unreadable or for the entire DB to become unopenable.
+    ///
+    /// If false, corrupted log records found while recovering are
+    /// skipped, and reported in the event log (see event_log_file).
+    /// Default: false
+    pub paranoid_checks: bool,
+    /// Use the specified object to interact with the environment,
+    /// e.g. to read/write files, schedule background work, etc.
+    /// Default: PosixEnv
+    pub env: Arc<dyn Env>,
+    /// Amount of data to build up in memory (backed by an unsorted log
+    /// on disk) before converting to a sorted on-disk file.
+    ///
+    /// Larger values increase performance, especially during bulk loads.
+    /// Up to one write buffer may be held in memory at a time, so you
+    /// may wish to adjust this parameter to control memory usage.
+    /// Also, a larger write buffer will result in a longer recovery time
+    /// the next time the database is opened.
+    pub write_buffer_size: usize,
+    /// If not None, the memory of the memtable is accounted for in this
+    /// manager, and the memtable is flushed before it reaches
+    /// write_buffer_size once the memtables of all the databases sharing
+    /// the manager together use more than its buffer size.
+    pub write_buffer_manager: Option<Arc<WriteBufferManager>>,
+    /// Once level-0 holds this many files, each write is delayed by 1ms
+    /// to let compactions catch up.  Rather than delaying a single write
+    /// by seconds once the stop trigger is hit, this spreads the delay.
+    /// Default: 8
+    pub l0_slowdown_writes_trigger: usize,
+    /// Once level-0 holds this many files, writes stop until level-0 has
+    /// been compacted.  Compactions run on the writing thread, so the
+    /// write that hits the trigger compacts level-0 before it goes on.
+    /// Default: 12
+    pub l0_stop_writes_trigger: usize,
+    /// The database will write up to this amount of bytes to a file before
+    /// switching to a new one.
+    /// Most clients should leave this parameter alone.  However if your
+    /// filesystem is more efficient with larger files, you could
+    /// consider increasing the value.  The downside will be longer
+    /// compactions and hence longer latency/performance hiccups.
+    /// Another reason to increase this parameter might be when you are
+    /// initially populating a large database.
+    pub max_file_size: usize,
+    /// Number of open files that can be used by the DB.  You may need to
+    /// increase this if your database has a large working set (budget
+    /// one open file per 2MB of working set).
+    pub max_open_files: usize,
+    /// Extension of the table files written by flushes and compactions.
+    /// Default: TableFileExtension::Ldb
+    pub table_file_extension: TableFileExtension,
+    /// Capacity in bytes of the cache of uncompressed data blocks shared
+    /// by all tables of the database.  Reads that hit the cache do not
+    /// touch the file; read-heavy workloads may want to raise this.
+    /// Zero turns the block cache off.
+    /// Default: 8MB
+    pub block_cache_size: usize,
+    /// If not None, data blocks are cached in this cache instead of one
+    /// of block_cache_size bytes private to the database, so that several
+    /// databases can share one budget for cached blocks.
+    pub block_cache: Option<Arc<dyn Cache<Block>>>,
+    /// Approximate size of user data packed per block.  Note that the
+    /// block size specified here corresponds to uncompressed data.  The
+    /// actual size of the unit read from disk may be smaller if
+    /// compression is enabled.
+    pub block_size: usize,
+    /// Number of keys between restart points for delta encoding of keys.
+    /// This parameter can be changed dynamically.  Most clients should
+    /// leave this parameter alone.
+    pub block_restart_interval: usize,
+    /// Compress blocks using the specified compression algorithm.  This
+    /// parameter can be changed dynamically.
+    ///
+    /// Default: Snappy, which gives lightweight but fast compression.
+    /// Blocks that do not shrink by at least 12.5% are stored
+    /// uncompressed, so incompressible data costs little CPU on reads.
+    pub compression: CompressionType,
+    /// Compression level for zstd.  Higher levels trade compression
+    /// speed for smaller files; the encoder currently compresses every
+    /// level like level 1, but the setting is kept so that tables built
+    /// later benefit from better encoders without a config change.
+    /// Default: 1
+    pub zstd_compression_level: i32,
+    /// If true, the records written to the log are compressed with
+    /// snappy, so that large write batches take less log space.  Records
+    /// that do not shrink by at least 12.5% are written uncompressed.
+    /// Logs written with compression can only be recovered by builds
+    /// with the snappy feature.
+    /// Default: false
+    pub compress_wal: bool,
+    /// If not None, use the specified filter policy to reduce disk reads.
+    /// Many applications will benefit from passing the result of
+    /// BloomFilterPolicy::new() here.
+    pub filter_policy: Option<Arc<dyn FilterPolicy>>,
+    /// If not None, each memtable keeps a bloom filter over the prefixes
+    /// of its keys, as extracted by this, and point lookups of keys whose
+    /// prefix is not in the filter skip searching the memtable.  Worth it
+    /// when most lookups miss the memtable, e.g. when reading keys that
+    /// were written long ago.  Iterators use the filters for seeks with
+    /// ReadConfig::prefix_same_as_start.
+    pub prefix_extractor: Option<Arc<dyn PrefixExtractor>>,
+    /// Size of the prefix bloom filter of a memtable, as a fraction of
+    /// write_buffer_size.  Only used if prefix_extractor is set; zero
+    /// turns the filter off.
+    /// Default: 0.1
+    pub memtable_prefix_bloom_size_ratio: f64,
+    /// Combines the operands written with WriteBatch::merge() into the
+    /// values of their keys.  Needed to read or compact a database that
+    /// holds merge operands.
+    /// REQUIRES: The operator must be the one the operands were written
+    /// for, as databases do not record which one that is.
+    pub merge_operator: Option<Arc<dyn MergeOperator>>,
+    /// If not None, compactions pass the values they rewrite to this
+    /// filter, which may remove or change them.
+    pub compaction_filter: Option<Arc<dyn CompactionFilter>>,
+    /// If not None, compactions also cut their output files where this
+    /// partitioner says so, e.g. between the keys of two tenants.
+    pub output_partitioner: Option<Arc<dyn OutputPartitioner>>,
+    /// If not None, the bandwidth of table writes done by flushes and
+    /// compactions is limited by this limiter.  An auto-tuned limiter
+    /// (RateLimiter::new_auto_tuned()) is adjusted by the database from
+    /// the write stalls and compaction debt it observes.
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+    /// If set, flushes and compactions are journaled to this file as one
+    /// JSON object per line (see replay_event_log).  The file is appended
+    /// to, so it covers the history of the database across reopens.
+    pub event_log_file: Option<String>,
+    /// If not zero, one in this many keys read by gets and iterators is
+    /// sampled to estimate the hottest keys (see DB::hottest_keys()).
+    /// Default: 0, which turns sampling off
+    pub hot_key_sample_rate: u32,
+    /// If true, the database directory is synced before new files are
+    /// recorded in the MANIFEST and after the CURRENT file is replaced,
+    /// so that new log, table and MANIFEST files cannot vanish in a crash
+    /// together with their directory entries.  Otherwise only the Env
+    /// decides, e.g. the PosixEnv syncs the directory of a MANIFEST when
+    /// syncing the MANIFEST.
+    /// Default: false
+    pub sync_dir_after_create: bool,
+    /// If true, DB::open_read_only() does not take the lock of the
+    /// database, e.g. to open a copy on a read-only file system, or a
+    /// database that another process has open.  That process may then
+    /// delete the files the read-only instance reads.
+    /// Default: false
+    pub skip_lock_when_read_only: bool,
+    /// Notified of flushes, compactions, file creations and deletions and
+    /// background errors, in the order they were added.
+    pub listeners: Vec<Arc<dyn EventListener>>,
+}
+
+impl Default for DBConfig {
+    fn default() -> Self {
+        Self {
+            comparator: Arc::new(BytewiseComparator::new()),
+            create_if_missing: false,
+            error_if_exists: false,
+            paranoid_checks: false,
+            env: Arc::new(PosixEnv::new()),
+            write_buffer_size: 4 * 1024 * 1024,
+            write_buffer_manager: None,
+            l0_slowdown_writes_trigger: L0_SLOWDOWN_WRITES_TRIGGER,
+            l0_stop_writes_trigger: L0_STOP_WRITES_TRIGGER,
+            max_file_size: 2 * 1024 * 1024,
+            max_open_files: 1000,
+            table_file_extension: TableFileExtension::Ldb,
+            block_cache_size: 8 * 1024 * 1024,
+            block_cache: None,
+            block_size: 4 * 1024,
+            block_restart_interval: 16,
+            compression: CompressionType::Snappy,
+            zstd_compression_level: 1,
+            compress_wal: false,
+            filter_policy: None,
+            prefix_extractor: None,
+            memtable_prefix_bloom_size_ratio: 0.1,
+            merge_operator: None,
+            compaction_filter: None,
+            output_partitioner: None,
+            rate_limiter: None,
+            event_log_file: None,
+            hot_key_sample_rate: 0,
+            sync_dir_after_create: false,
+            skip_lock_when_read_only: false,
+            listeners: vec![],
+        }
+    }
+}
+
+/// Builder-style setters, one per option, e.g.
+///
+///    DBConfig::default().create_if_missing(true).write_buffer_size(1 << 20)
+impl DBConfig {
+    pub fn comparator(mut self, comparator: Arc<dyn Comparator>) -> Self {
+        self.comparator = comparator;
+        self
+    }
+
+    pub fn create_if_missing(mut self, create_if_missing: bool) -> Self {
+        self.create_if_missing = create_if_missing;
+        self
+    }
+
+    pub fn error_if_exists(mut self, error_if_exists: bool) -> Self {
+        self.error_if_exists = error_if_exists;
+        self
+    }
+
+    pub fn paranoid_checks(mut self, paranoid_checks: bool) -> Self {
+        self.paranoid_checks = paranoid_checks;
+        self
+    }
+
+    pub fn env(mut self, env: Arc<dyn Env>) -> Self {
+        self.env = env;
+        self
+    }
+
+    pub fn write_buffer_size(mut self, write_buffer_size: usize) -> Self {
+        self.write_buffer_size = write_buffer_size;
+        self
+    }
+
+    pub fn max_file_size(mut self, max_file_size: usize) -> Self {
+        self.max_file_size = max_file_size;
+        self
+    }
+
+    pub fn max_open_files(mut self, max_open_files: usize) -> Self {
+        self.max_open_files = max_open_files;
+        self
+    }
+
+    pub fn block_size(mut self, block_size: usize) -> Self {
+        self.block_size = block_size;
+        self
+    }
+
+    pub fn block_restart_interval(mut self, block_restart_interval: usize) -> Self {
+        self.block_restart_interval = block_restart_interval;
+        self
+    }
+
+    pub fn compression(mut self, compression: CompressionType) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    pub fn compress_wal(mut self, compress_wal: bool) -> Self {
+        self.compress_wal = compress_wal;
+        self
+    }
+
+    pub fn filter_policy(mut self, filter_policy: Arc<dyn FilterPolicy>) -> Self {
+        self.filter_policy = Some(filter_policy);
+        self
+    }
+
+    pub fn prefix_extractor(mut self, prefix_extractor: Arc<dyn PrefixExtractor>) -> Self {
+        self.prefix_extractor = Some(prefix_extractor);
+        self
+    }
+
+    pub fn merge_operator(mut self, merge_operator: Arc<dyn MergeOperator>) -> Self {
+        self.merge_operator = Some(merge_operator);
+        self
+    }
+
+    pub fn compaction_filter(mut self, compaction_filter: Arc<dyn CompactionFilter>) -> Self {
+        self.compaction_filter = Some(compaction_filter);
+        self
+    }
+
+    pub fn add_event_listener(mut self, listener: Arc<dyn EventListener>) -> Self {
+        self.listeners.push(listener);
+        self
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct WriteConfig {
+    /// If set, the write fails with an error that satisfies
+    /// Error::is_timed_out() instead of waiting past this point in time,
+    /// e.g. behind a memtable flush.  A write that times out is not
+    /// applied.
+    pub deadline: Option<Instant>,
+    /// If true, the write will be flushed from the operating system
+    /// buffer cache (by calling WritableFile::sync()) before the write
+    /// is considered complete.  If this flag is true, writes will be
+    /// slower.
+    ///
+    /// If this flag is false, and the machine crashes, some recent
+    /// writes may be lost.  Note that if it is just the process that
+    /// crashes (i.e., the machine does not reboot), no writes will be
+    /// lost even if sync==false.
+    ///
+    /// In other words, a DB write with sync==false has similar
+    /// crash semantics as the "write()" system call.  A DB write
+    /// with sync==true has similar crash semantics to a "write()"
+    /// system call followed by "fsync()".
+    pub sync: bool,
+}
+
+/// Builder-style setters, e.g. WriteConfig::default().sync(true)
+impl WriteConfig {
+    pub fn sync(mut self, sync: bool) -> Self {
+        self.sync = sync;
+        self
+    }
+
+    pub fn deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+}
+
+/// Options that control DB::ingest_external_file()
+#[derive(Clone)]
+pub struct IngestConfig {
+    /// If true, the checksums of the blocks of the external files are
+    /// verified as they are read.
+    pub verify_checksums: bool,
+    /// If true, the external files are removed once ingested, as if
+    /// moved into the database.
+    pub move_files: bool,
+}
+
+impl Default for IngestConfig {
+    fn default() -> Self {
+        Self {
+            verify_checksums: true,
+            move_files: false,
+        }
+    }
+}
+
+/// Builder-style setters, e.g. IngestConfig::default().move_files(true)
+impl IngestConfig {
+    pub fn verify_checksums(mut self, verify_checksums: bool) -> Self {
+        self.verify_checksums = verify_checksums;
+        self
+    }
+
+    pub fn move_files(mut self, move_files: bool) -> Self {
+        self.move_files = move_files;
+        self
+    }
+}
+
+#[derive(Clone)]
+pub struct ReadConfig {
+    /// If true, all data read from underlying storage will be
+    /// verified against corresponding checksums.
+    pub verify_checksums: bool,
+    /// Should the data read for this iteration be cached in memory?
+    /// Callers may wish to set this field to false for bulk scans.
+    pub fill_cache: bool,
+    /// If true, reads fail with the stored background error (see
+    /// DB::background_error) instead of serving data that a failed
+    /// flush or compaction may have left stale.
+    pub fail_on_background_error: bool,
+    /// If set, the read fails with an error that satisfies
+    /// Error::is_timed_out() once this point in time has passed.  The
+    /// deadline is checked between file reads, so a single slow read may
+    /// overrun it; use io_timeout to bound those.
+    pub deadline: Option<Instant>,
+    /// If set, the read fails with an error that satisfies
+    /// Error::is_timed_out() if a single file read takes longer than this.
+    pub io_timeout: Option<Duration>,
+    /// If set, reads and iterators stop with an error that satisfies
+    /// Error::is_cancelled() once the flag becomes true.  Iterators check
+    /// it whenever they move on to another block, so an abandoned scan
+    /// does little more work after being cancelled.
+    pub cancel: Option<Arc<AtomicBool>>,
+    /// If set, read as of the supplied snapshot (which must belong to the
+    /// DB that is being read and which must not have been released).
+    /// If None, use an implicit snapshot of the state at the beginning
+    /// of this read operation.
+    pub snapshot: Option<Arc<Snapshot>>,
+    /// If set, iterators only visit user keys >= this bound.  A lower
+    /// bound above iterate_upper_bound, according to the comparator,
+    /// makes DB::iter() return an iterator whose status() is an error
+    /// that satisfies Error::is_invalid_argument().
+    pub iterate_lower_bound: Option<Vec<u8>>,
+    /// If set, iterators only visit user keys < this bound.
+    pub iterate_upper_bound: Option<Vec<u8>>,
+    /// If set, an iterator holds on to the memtable, table files and
+    /// blocks it reads from for at most this long.  Once that time has
+    /// passed, the next move of the iterator lets go of them and either
+    /// opens them afresh, reading at the same sequence number and
+    /// picking up where it was, or fails (see refresh_iterators).
+    pub max_iterator_pin: Option<Duration>,
+    /// If true, an iterator that outlives max_iterator_pin refreshes
+    /// itself transparently.  If false, it stops with an error that
+    /// satisfies Error::is_expired() instead.
+    pub refresh_iterators: bool,
+    /// If true and DBConfig::prefix_extractor is set, an iterator only
+    /// visits the keys with the prefix of where it was positioned: that
+    /// of the seek target, or if the target has none, that of the first
+    /// key found.  It becomes invalid at the first key with another
+    /// prefix.  Seeks then skip the memtables whose prefix bloom rules
+    /// the prefix out.  Iterate bounds with different prefixes, which it
+    /// would stop short of, make iterators fail with an invalid argument.
+    pub prefix_same_as_start: bool,
+}
+
+impl Default for ReadConfig {
+    fn default() -> Self {
+        Self {
+            verify_checksums: false,
+            fill_cache: true,
+            fail_on_background_error: false,
+            deadline: None,
+            io_timeout: None,
+            cancel: None,
+            snapshot: None,
+            iterate_lower_bound: None,
+            iterate_upper_bound: None,
+            max_iterator_pin: None,
+            refresh_iterators: true,
+            prefix_same_as_start: false,
+        }
+    }
+}
+
+/// Builder-style setters, e.g. ReadConfig::default().snapshot(snapshot)
+impl ReadConfig {
+    pub fn verify_checksums(mut self, verify_checksums: bool) -> Self {
+        self.verify_checksums = verify_checksums;
+        self
+    }
+
+    pub fn fill_cache(mut self, fill_cache: bool) -> Self {
+        self.fill_cache = fill_cache;
+        self
+    }
+
+    pub fn snapshot(mut self, snapshot: Arc<Snapshot>) -> Self {
+        self.snapshot = Some(snapshot);
+        self
+    }
+
+    pub fn deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    pub fn iterate_lower_bound(mut self, key: &[u8]) -> Self {
+        self.iterate_lower_bound = Some(key.to_vec());
+        self
+    }
+
+    pub fn iterate_upper_bound(mut self, key: &[u8]) -> Self {
+        self.iterate_upper_bound = Some(key.to_vec());
+        self
+    }
+
+    pub fn prefix_same_as_start(mut self, prefix_same_as_start: bool) -> Self {
+        self.prefix_same_as_start = prefix_same_as_start;
+        self
+    }
+
+    pub(crate) fn check_cancelled(&self) -> Result<()> {
+        match self.cancel.as_ref() {
+            Some(cancel) if cancel.load(Ordering::Relaxed) => {
+                Err(Error::cancelled("read cancelled by caller"))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Fail with a timed out error if "deadline" has passed.
+pub(crate) fn check_deadline(deadline: Option<Instant>) -> Result<()> {
+    match deadline {
+        Some(deadline) if Instant::now() >= deadline => {
+            Err(Error::timed_out("operation deadline exceeded"))
+        }
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,777 @@
+use std::{
+    cmp::Ordering,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use super::{
+    check_deadline, hot_keys::HotKeySampler, merge::full_merge,
+    range_del::FragmentedRangeTombstones,
+};
+use crate::{
+    dbformat::{
+        extract_user_key, ParsedInternalKey, ValueType, MAX_SEQUENCE_NUMBER, VALUE_TYPE_FOR_SEEK,
+    },
+    iterator::Iterator,
+    util::{Comparator, Error, MergeOperator, PrefixExtractor, Result},
+    version::Version,
+};
+
+/// What a DBIterator reads: an iterator over internal keys, the version
+/// its tables belong to, the sequence number its entries are read as
+/// of, and the range tombstones among them.
+pub struct IteratorSources<'a> {
+    pub iter: Box<dyn Iterator + 'a>,
+    pub version: Arc<Version>,
+    pub sequence: u64,
+    pub range_tombstones: Arc<FragmentedRangeTombstones>,
+}
+
+/// Opens the sources of a DBIterator afresh, as of now.
+pub type IteratorSource<'a> = Box<dyn Fn() -> Result<IteratorSources<'a>> + 'a>;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Forward,
+    Reverse,
+}
+
+/// The keys a DBIterator with a prefix extractor is limited to
+enum PrefixLimit {
+    /// Those with the prefix of the first key found
+    FirstKey,
+    /// Those with this prefix, or with none if None
+    Prefix(Option<Vec<u8>>),
+}
+
+/// Memtables and sstables that make the DB representation contain
+/// (userkey,seq,type) => uservalue entries.  DBIterator
+/// combines multiple entries for the same userkey found in the DB
+/// representation into a single entry while accounting for sequence
+/// numbers, deletion markers, overwrites, etc.
+pub struct DBIterator<'a> {
+    user_comparator: Arc<dyn Comparator>,
+    iter: Box<dyn Iterator + 'a>,
+    sequence: u64,
+    lower_bound: Option<Vec<u8>>,
+    upper_bound: Option<Vec<u8>>,
+    deadline: Option<Instant>,
+    /// Keeps the table files being iterated from being deleted as
+    /// obsolete, until the iterator is dropped or refreshed.
+    version: Option<Arc<Version>>,
+    /// If set, the keys the iterator stops at are sampled into it.
+    key_sampler: Option<Arc<HotKeySampler>>,
+    /// The range tombstones of the sources, which hide the older entries
+    /// they cover
+    range_tombstones: Option<Arc<FragmentedRangeTombstones>>,
+    /// Combines the merge operands of a key with its value
+    merge_operator: Option<Arc<dyn MergeOperator>>,
+    /// If set, the iteration stops at the first key whose prefix is not
+    /// that of "prefix_limit"
+    prefix_extractor: Option<Arc<dyn PrefixExtractor>>,
+    prefix_limit: PrefixLimit,
+    /// How long "iter" and "version" may be held on to, if limited
+    max_pin: Option<Duration>,
+    /// Reopens the sources of the iterator on refresh()
+    source: Option<IteratorSource<'a>>,
+    /// Whether "iter" and "version" are reopened with "source" once they
+    /// have been held on to for longer than max_pin, rather than the
+    /// iterator expiring
+    refresh_pinned: bool,
+    pinned_since: Instant,
+
+    status: Result<()>,
+    /// == current key when direction == Reverse
+    saved_key: Vec<u8>,
+    /// == current raw value when direction == Reverse
+    saved_value: Vec<u8>,
+    /// The current entry is the result of merge operands, held in
+    /// saved_key and saved_value even though direction == Forward, and
+    /// iter is past the entries of its key that it was merged from
+    merged: bool,
+    direction: Direction,
+    valid: bool,
+}
+
+impl<'a> DBIterator<'a> {
+    /// Return an iterator over the user keys in "iter", an iterator over
+    /// internal keys, as of "sequence".  Only keys within
+    /// [lower_bound, upper_bound) are visited.  "version" is the version
+    /// the tables in "iter" belong to, if any.
+    pub fn new(
+        user_comparator: Arc<dyn Comparator>,
+        iter: Box<dyn Iterator + 'a>,
+        sequence: u64,
+        lower_bound: Option<Vec<u8>>,
+        upper_bound: Option<Vec<u8>>,
+        deadline: Option<Instant>,
+        version: Option<Arc<Version>>,
+    ) -> Self {
+        Self {
+            user_comparator,
+            iter,
+            sequence,
+            lower_bound,
+            upper_bound,
+            deadline,
+            version,
+            key_sampler: None,
+            range_tombstones: None,
+            merge_operator: None,
+            prefix_extractor: None,
+            prefix_limit: PrefixLimit::FirstKey,
+            max_pin: None,
+            source: None,
+            refresh_pinned: false,
+            pinned_since: Instant::now(),
+            status: Ok(()),
+            saved_key: vec![],
+            saved_value: vec![],
+            merged: false,
+            direction: Direction::Forward,
+            valid: false,
+        }
+    }
+
+    /// Sample the keys the iterator stops at into "sampler".
+    pub fn set_key_sampler(&mut self, sampler: Arc<HotKeySampler>) {
+        self.key_sampler = Some(sampler);
+    }
+
+    /// Hide the entries of "iter" covered by newer range tombstones of
+    /// "tombstones".  Tombstones stay valid when the sources are
+    /// refreshed, since compactions only drop the entries that the
+    /// tombstones they drop cover.
+    pub fn set_range_tombstones(&mut self, tombstones: Arc<FragmentedRangeTombstones>) {
+        if !tombstones.is_empty() {
+            self.range_tombstones = Some(tombstones);
+        }
+    }
+
+    /// Combine the merge operands of the keys with "merge_operator".
+    /// Without one, the iteration stops with an error at the first key
+    /// that has merge operands.
+    pub fn set_merge_operator(&mut self, merge_operator: Arc<dyn MergeOperator>) {
+        self.merge_operator = Some(merge_operator);
+    }
+
+    /// Only visit the keys with the prefix of where the iterator was
+    /// positioned, as extracted by "extractor": that of the seek target,
+    /// or if it has none, that of the first key found.  The seek() of
+    /// "iter" may then leave out the keys of other prefixes than that of
+    /// its target.
+    pub fn set_prefix_extractor(&mut self, extractor: Arc<dyn PrefixExtractor>) {
+        self.prefix_extractor = Some(extractor);
+    }
+
+    /// Reopen the sources of the iterator with "source" on refresh().
+    pub fn set_source(&mut self, source: IteratorSource<'a>) {
+        self.source = Some(source);
+    }
+
+    /// Hold on to the sources of the iterator for at most "max_pin".
+    /// After that, the iterator reopens them if "refresh" is set and it
+    /// has a source, and stops with an expired error if not.
+    pub fn set_max_pin(&mut self, max_pin: Duration, refresh: bool) {
+        self.max_pin = Some(max_pin);
+        self.refresh_pinned = refresh;
+        self.pinned_since = Instant::now();
+    }
+
+    /// Let go of the sources of the iterator if it has held on to them
+    /// for too long, reopening them and going back to the current entry
+    /// if possible.  Returns false if the iteration must stop.
+    fn check_pin(&mut self) -> bool {
+        match self.max_pin {
+            Some(max_pin) if self.pinned_since.elapsed() >= max_pin => {}
+            _ => return true,
+        }
+        let reopened = match self.source.as_ref() {
+            Some(source) if self.refresh_pinned => source(),
+            _ => Err(Error::expired("iterator pinned its sources for too long")),
+        };
+        let sources = match reopened {
+            Ok(sources) => sources,
+            Err(error) => {
+                if self.status.is_ok() {
+                    self.status = Err(error);
+                }
+                self.valid = false;
+                self.saved_key.clear();
+                self.saved_value.clear();
+                return false;
+            }
+        };
+        let current = self.valid.then(|| self.key().to_vec());
+        self.iter = sources.iter;
+        self.version = Some(sources.version);
+        self.pinned_since = Instant::now();
+        // The new sources hold the same entries as of our sequence, so
+        // the current key is still there to go back to, and our range
+        // tombstones still apply to them.
+        match current {
+            Some(key) => {
+                self.seek_internal(&key);
+                self.valid
+            }
+            None => true,
+        }
+    }
+
+    /// Stop the iteration if "iter" stopped at an error, rather than
+    /// yield an entry it could not read all of.  Returns whether it did.
+    fn stop_at_error(&mut self) -> bool {
+        match self.iter.status() {
+            Ok(()) => false,
+            Err(error) => {
+                if self.status.is_ok() {
+                    self.status = Err(error);
+                }
+                self.valid = false;
+                self.saved_key.clear();
+                self.saved_value.clear();
+                true
+            }
+        }
+    }
+
+    /// Parse the key "iter" is positioned at.  A key that cannot be
+    /// parsed is recorded as corruption and skipped by the callers.
+    fn parse_key(&mut self) -> Option<ParsedInternalKey<'_>> {
+        match ParsedInternalKey::parse(self.iter.key()) {
+            Some(ikey) => Some(ikey),
+            None => {
+                if self.status.is_ok() {
+                    self.status = Err(Error::corruption("corrupted internal key in DBIterator"));
+                }
+                None
+            }
+        }
+    }
+
+    /// The type of an entry as seen by the iterator: a value or merge
+    /// operand covered by a newer range tombstone is deleted, and so is
+    /// the start of a range tombstone, should one show up among the
+    /// entries.
+    fn visible_type(&self, user_key: &[u8], type_: ValueType, sequence: u64) -> ValueType {
+        match type_ {
+            ValueType::Value | ValueType::Merge
+                if self.range_tombstones.as_ref().is_some_and(|tombstones| {
+                    sequence < tombstones.max_covering_sequence(user_key, self.sequence)
+                }) =>
+            {
+                ValueType::Deletion
+            }
+            ValueType::RangeDeletion => ValueType::Deletion,
+            type_ => type_,
+        }
+    }
+
+    fn below_lower_bound(&self, user_key: &[u8]) -> bool {
+        self.lower_bound
+            .as_deref()
+            .is_some_and(|lower| self.user_comparator.compare(user_key, lower) == Ordering::Less)
+    }
+
+    fn at_or_above_upper_bound(&self, user_key: &[u8]) -> bool {
+        self.upper_bound
+            .as_deref()
+            .is_some_and(|upper| self.user_comparator.compare(user_key, upper) != Ordering::Less)
+    }
+
+    /// Limit the iteration to the prefix of "target", or if there is no
+    /// target or it has no prefix, to that of the first key found.
+    fn start_prefix(&mut self, target: Option<&[u8]>) {
+        self.prefix_limit = match (self.prefix_extractor.as_ref(), target) {
+            (Some(extractor), Some(target)) if extractor.in_domain(target) => {
+                PrefixLimit::Prefix(Some(extractor.transform(target).to_vec()))
+            }
+            _ => PrefixLimit::FirstKey,
+        };
+    }
+
+    /// Limit the iteration to the prefix of the key found, if it is to be
+    /// that of the first key.
+    fn finish_prefix(&mut self) {
+        let extractor = match (self.prefix_extractor.as_ref(), &self.prefix_limit) {
+            (Some(extractor), PrefixLimit::FirstKey) if self.valid => extractor.clone(),
+            _ => return,
+        };
+        let key = self.key();
+        let prefix = extractor
+            .in_domain(key)
+            .then(|| extractor.transform(key).to_vec());
+        self.prefix_limit = PrefixLimit::Prefix(prefix);
+    }
+
+    fn outside_prefix(&self, user_key: &[u8]) -> bool {
+        match (self.prefix_extractor.as_ref(), &self.prefix_limit) {
+            (Some(extractor), PrefixLimit::Prefix(prefix)) => {
+                let key_prefix = extractor
+                    .in_domain(user_key)
+                    .then(|| extractor.transform(user_key));
+                prefix.as_deref() != key_prefix
+            }
+            _ => false,
+        }
+    }
+
+    /// Stop the iteration if the caller's deadline has passed.
+    fn check_deadline(&mut self) -> bool {
+        match check_deadline(self.deadline) {
+            Ok(()) => true,
+            Err(error) => {
+                if self.status.is_ok() {
+                    self.status = Err(error);
+                }
+                self.valid = false;
+                self.saved_key.clear();
+                self.saved_value.clear();
+                false
+            }
+        }
+    }
+
+    fn find_next_user_entry(&mut self, mut skipping: bool) {
+        // Loop until we hit an acceptable entry to yield
+        assert!(self.iter.valid());
+        assert!(self.direction == Direction::Forward);
+        loop {
+            let sequence = self.sequence;
+            let mut entry = None;
+            if let Some(ikey) = self.parse_key() {
+                if ikey.sequence() <= sequence {
+                    entry = Some((ikey.user_key().to_vec(), ikey.type_(), ikey.sequence()));
+                }
+            }
+            if let Some((user_key, type_, entry_sequence)) = entry {
+                let type_ = self.visible_type(&user_key, type_, entry_sequence);
+                if self.at_or_above_upper_bound(&user_key) || self.outside_prefix(&user_key) {
+                    break;
+                }
+                match type_ {
+                    ValueType::Deletion | ValueType::RangeDeletion => {
+                        // Arrange to skip all upcoming entries for this key
+                        // since they are hidden by this deletion.
+                        self.saved_key = user_key;
+                        skipping = true;
+                    }
+                    ValueType::Value | ValueType::Merge => {
+                        if skipping
+                            && self.user_comparator.compare(&user_key, &self.saved_key)
+                                != Ordering::Greater
+                        {
+                            // Entry hidden
+                        } else if type_ == ValueType::Merge {
+                            self.merge_forward(user_key);
+                            return;
+                        } else {
+                            if let Some(sampler) = self.key_sampler.as_ref() {
+                                sampler.record(&user_key);
+                            }
+                            self.valid = true;
+                            self.saved_key.clear();
+                            return;
+                        }
+                    }
+                }
+            }
+            self.iter.next();
+            if !self.iter.valid() {
+                break;
+            }
+        }
+        self.saved_key.clear();
+        self.valid = false;
+    }
+
+    /// Merge the operands of "user_key", starting with the one iter is
+    /// positioned at, into the value of the key that iter stops at, if
+    /// any.  The result becomes the current entry.
+    fn merge_forward(&mut self, user_key: Vec<u8>) {
+        // Newest first
+        let mut operands = vec![self.iter.value().to_vec()];
+        let mut existing_value = None;
+        loop {
+            self.iter.next();
+            if !self.iter.valid() {
+                break;
+            }
+            let entry = self
+                .parse_key()
+                .map(|ikey| (ikey.user_key().to_vec(), ikey.type_(), ikey.sequence()));
+            let (key, type_, sequence) = match entry {
+                Some(entry) => entry,
+                None => break,
+            };
+            if self.user_comparator.compare(&key, &user_key) != Ordering::Equal {
+                break;
+            }
+            match self.visible_type(&key, type_, sequence) {
+                ValueType::Merge => operands.push(self.iter.value().to_vec()),
+                ValueType::Value => {
+                    existing_value = Some(self.iter.value().to_vec());
+                    break;
+                }
+                ValueType::Deletion | ValueType::RangeDeletion => break,
+            }
+        }
+        if self.stop_at_error() {
+            return;
+        }
+        operands.reverse();
+        self.finish_merge(user_key, existing_value.as_deref(), &operands);
+    }
+
+    /// Make the result of merging "operands", oldest first, into
+    /// "existing_value" the current entry, or stop the iteration if they
+    /// can't be merged.
+    fn finish_merge(
+        &mut self,
+        user_key: Vec<u8>,
+        existing_value: Option<&[u8]>,
+        operands: &[Vec<u8>],
+    ) {
+        match full_merge(
+            self.merge_operator.as_ref(),
+            &user_key,
+            existing_value,
+            operands,
+        ) {
+            Ok(value) => {
+                if let Some(sampler) = self.key_sampler.as_ref() {
+                    sampler.record(&user_key);
+                }
+                self.saved_key = user_key;
+                self.saved_value = value;
+                self.merged = self.direction == Direction::Forward;
+                self.valid = true;
+            }
+            Err(error) => {
+                if self.status.is_ok() {
+                    self.status = Err(error);
+                }
+                self.saved_key.clear();
+                self.saved_value.clear();
+                self.valid = false;
+            }
+        }
+    }
+
+    fn find_prev_user_entry(&mut self) {
+        assert!(self.direction == Direction::Reverse);
+
+        let mut value_type = ValueType::Deletion;
+        // The merge operands of saved_key, oldest first, and whether
+        // saved_value holds the value they apply to
+        let mut operands = vec![];
+        let mut has_existing_value = false;
+        while self.iter.valid() {
+            let sequence = self.sequence;
+            let mut entry = None;
+            if let Some(ikey) = self.parse_key() {
+                if ikey.sequence() <= sequence {
+                    entry = Some((ikey.user_key().to_vec(), ikey.type_(), ikey.sequence()));
+                }
+            }
+            if let Some((user_key, type_, entry_sequence)) = entry {
+                let type_ = self.visible_type(&user_key, type_, entry_sequence);
+                if self.below_lower_bound(&user_key) || self.outside_prefix(&user_key) {
+                    break;
+                }
+                if value_type != ValueType::Deletion
+                    && self.user_comparator.compare(&user_key, &self.saved_key) == Ordering::Less
+                {
+                    // We encountered a non-deleted value in entries for previous keys,
+                    break;
+                }
+                value_type = type_;
+                match value_type {
+                    ValueType::Deletion | ValueType::RangeDeletion => {
+                        value_type = ValueType::Deletion;
+                        self.saved_key.clear();
+                        self.saved_value.clear();
+                        operands.clear();
+                        has_existing_value = false;
+                    }
+                    ValueType::Value => {
+                        self.saved_key = user_key;
+                        self.saved_value = self.iter.value().to_vec();
+                        operands.clear();
+                        has_existing_value = true;
+                    }
+                    ValueType::Merge => {
+                        self.saved_key = user_key;
+                        operands.push(self.iter.value().to_vec());
+                    }
+                }
+            }
+            self.iter.prev();
+        }
+        // The newer entries of saved_key come after the ones seen
+        if self.stop_at_error() {
+            self.direction = Direction::Forward;
+            return;
+        }
+
+        if value_type == ValueType::Deletion {
+            // End
+            self.valid = false;
+            self.saved_key.clear();
+            self.saved_value.clear();
+            self.direction = Direction::Forward;
+        } else if value_type == ValueType::Merge {
+            let user_key = std::mem::take(&mut self.saved_key);
+            let existing_value = std::mem::take(&mut self.saved_value);
+            self.finish_merge(
+                user_key,
+                has_existing_value.then_some(existing_value.as_slice()),
+                &operands,
+            );
+        } else {
+            if let Some(sampler) = self.key_sampler.as_ref() {
+                sampler.record(&self.saved_key);
+            }
+            self.valid = true;
+        }
+    }
+
+    /// Position at the first entry with a user key >= "target", which
+    /// must not be below the lower bound.
+    fn seek_internal(&mut self, target: &[u8]) {
+        self.direction = Direction::Forward;
+        self.merged = false;
+        self.saved_value.clear();
+        self.saved_key.clear();
+        ParsedInternalKey::new(target, self.sequence, VALUE_TYPE_FOR_SEEK)
+            .append_to(&mut self.saved_key);
+        self.iter.seek(&self.saved_key);
+        if self.iter.valid() {
+            self.find_next_user_entry(false);
+        } else {
+            self.valid = false;
+        }
+    }
+}
+
+impl Iterator for DBIterator<'_> {
+    fn valid(&self) -> bool {
+        self.valid
+    }
+
+    fn seek_to_first(&mut self) {
+        if !self.check_deadline() || !self.check_pin() {
+            return;
+        }
+        if let Some(lower) = self.lower_bound.clone() {
+            // As a seek of the lower bound, prefix included
+            self.start_prefix(Some(&lower));
+            self.seek_internal(&lower);
+            self.finish_prefix();
+            return;
+        }
+        self.start_prefix(None);
+        self.direction = Direction::Forward;
+        self.merged = false;
+        self.saved_value.clear();
+        self.saved_key.clear();
+        self.iter.seek_to_first();
+        if self.iter.valid() {
+            self.find_next_user_entry(false);
+        } else {
+            self.valid = false;
+        }
+        self.finish_prefix();
+    }
+
+    fn seek_to_last(&mut self) {
+        if !self.check_deadline() || !self.check_pin() {
+            return;
+        }
+        self.start_prefix(None);
+        self.direction = Direction::Reverse;
+        self.merged = false;
+        self.saved_value.clear();
+        match self.upper_bound.clone() {
+            Some(upper) => {
+                // Position at the last entry before the upper bound.  Not
+                // with seek(), which may leave out the keys of prefixes
+                // other than that of the bound.
+                let mut target = vec![];
+                ParsedInternalKey::new(&upper, MAX_SEQUENCE_NUMBER, VALUE_TYPE_FOR_SEEK)
+                    .append_to(&mut target);
+                self.iter.seek_for_prev(&target);
+            }
+            None => self.iter.seek_to_last(),
+        }
+        self.find_prev_user_entry();
+        self.finish_prefix();
+    }
+
+    fn seek(&mut self, target: &[u8]) {
+        if !self.check_deadline() || !self.check_pin() {
+            return;
+        }
+        let target = match self.lower_bound.clone() {
+            Some(lower) if self.below_lower_bound(target) => lower,
+            _ => target.to_vec(),
+        };
+        self.start_prefix(Some(&target));
+        self.seek_internal(&target);
+        self.finish_prefix();
+    }
+
+    fn seek_for_prev(&mut self, target: &[u8]) {
+        if !self.check_deadline() || !self.check_pin() {
+            return;
+        }
+        if self.at_or_above_upper_bound(target) {
+            self.seek_to_last();
+            return;
+        }
+        self.start_prefix(Some(target));
+        self.direction = Direction::Reverse;
+        self.merged = false;
+        self.saved_value.clear();
+        // Sorts after every entry of "target"
+        let mut key = vec![];
+        ParsedInternalKey::new(target, 0, ValueType::Deletion).append_to(&mut key);
+        self.iter.seek_for_prev(&key);
+        self.find_prev_user_entry();
+        self.finish_prefix();
+    }
+
+    fn next(&mut self) {
+        assert!(self.valid);
+        if !self.check_deadline() || !self.check_pin() {
+            return;
+        }
+
+        if self.direction == Direction::Reverse {
+            // Switch directions?
+            self.direction = Direction::Forward;
+            // iter is pointing just before the entries for key(),
+            // so advance into the range of entries for key() and then
+            // use the normal skipping code below.
+            if !self.iter.valid() {
+                self.iter.seek_to_first();
+            } else {
+                self.iter.next();
+            }
+            if !self.iter.valid() {
+                self.valid = false;
+                self.saved_key.clear();
+                return;
+            }
+            // saved_key already contains the key to skip past.
+        } else {
+            // Store in saved_key the current key so we skip it below.
+            self.saved_key = self.key().to_vec();
+
+            // iter is pointing to current key. We can now safely move to the next to
+            // avoid checking current key.  A merged entry is already past it.
+            if !std::mem::take(&mut self.merged) {
+                self.iter.next();
+            }
+            if !self.iter.valid() {
+                self.valid = false;
+                self.saved_key.clear();
+                return;
+            }
+        }
+
+        self.find_next_user_entry(true);
+    }
+
+    fn prev(&mut self) {
+        assert!(self.valid);
+        if !self.check_deadline() || !self.check_pin() {
+            return;
+        }
+
+        if self.direction == Direction::Forward {
+            // Switch directions?
+            // iter is pointing at the current entry.  Scan backwards until
+            // the key changes so we can use the normal reverse scanning code.
+            // A merged entry left iter past its entries, possibly at the end.
+            self.saved_key = self.key().to_vec();
+            if std::mem::take(&mut self.merged) && !self.iter.valid() {
+                self.iter.seek_to_last();
+            }
+            assert!(self.iter.valid()); // Otherwise valid would have been false
+            loop {
+                self.iter.prev();
+                if !self.iter.valid() {
+                    self.valid = false;
+                    self.saved_key.clear();
+                    self.saved_value.clear();
+                    return;
+                }
+                if self
+                    .user_comparator
+                    .compare(extract_user_key(self.iter.key()), &self.saved_key)
+                    == Ordering::Less
+                {
+                    break;
+                }
+            }
+            self.direction = Direction::Reverse;
+        }
+
+        self.find_prev_user_entry();
+    }
+
+    fn key(&self) -> &[u8] {
+        assert!(self.valid);
+        match self.direction {
+            Direction::Forward if !self.merged => extract_user_key(self.iter.key()),
+            _ => &self.saved_key,
+        }
+    }
+
+    fn value(&self) -> &[u8] {
+        assert!(self.valid);
+        match self.direction {
+            Direction::Forward if !self.merged => self.iter.value(),
+            _ => &self.saved_value,
+        }
+    }
+
+    fn status(&self) -> Result<()> {
+        self.status.clone()?;
+        self.iter.status()
+    }
+
+    fn refresh(&mut self) -> Result<()> {
+        let source = self
+            .source
+            .as_ref()
+            .ok_or_else(|| Error::not_supported("iterator has no source to refresh"))?;
+        let sources = source()?;
+        let current = self.valid.then(|| self.key().to_vec());
+        let direction = self.direction;
+        self.iter = sources.iter;
+        self.version = Some(sources.version);
+        self.sequence = sources.sequence;
+        self.range_tombstones = None;
+        self.set_range_tombstones(sources.range_tombstones);
+        self.pinned_since = Instant::now();
+        self.status = Ok(());
+        self.merged = false;
+        self.saved_key.clear();
+        self.saved_value.clear();
+        let key = match current {
+            Some(key) => key,
+            None => {
+                self.valid = false;
+                self.direction = Direction::Forward;
+                return Ok(());
+            }
+        };
+        match direction {
+            Direction::Forward => self.seek_internal(&key),
+            Direction::Reverse => self.seek_for_prev(&key),
+        }
+        Ok(())
+    }
+}
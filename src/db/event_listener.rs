@@ -0,0 +1,129 @@
+use std::time::Duration;
+
+use crate::{Error, Result};
+
+/// Callbacks for what the database does behind the scenes, e.g. to
+/// export flushes and compactions to a metrics system.  All callbacks do
+/// nothing by default.
+///
+/// Callbacks run on the thread of the DB call during which the event
+/// happened, so they should return quickly.  They are given no access to
+/// the DB itself.
+pub trait EventListener: Send + Sync {
+    /// A memtable was written out to a level-0 table, or failed to be.
+    fn on_flush_completed(&self, _info: &FlushJobInfo) {}
+
+    /// A compaction finished, successfully or not.
+    fn on_compaction_completed(&self, _info: &CompactionJobInfo) {}
+
+    /// A table file was written by a flush, a compaction, the recovery
+    /// of a log or an ingestion.  A table of a job that fails is deleted
+    /// again.
+    fn on_table_file_created(&self, _info: &TableFileCreationInfo) {}
+
+    /// A table file that is not used any more was deleted.
+    fn on_table_file_deleted(&self, _info: &TableFileDeletionInfo) {}
+
+    /// A new log file was started.
+    fn on_wal_file_created(&self, _info: &WalFileInfo) {}
+
+    /// A log file whose updates are all in tables was deleted.
+    fn on_wal_file_deleted(&self, _info: &WalFileInfo) {}
+
+    /// A background job or a log write failed.  The first such error
+    /// stops all writes, see DB::background_error().
+    fn on_background_error(&self, _info: &BackgroundErrorInfo) {}
+}
+
+/// Describes the flush of a memtable
+#[derive(Clone, Debug)]
+pub struct FlushJobInfo {
+    /// Numbers the flushes and compactions of the DB, as in the event log
+    pub job_id: u64,
+    /// Name of the column family of the memtable
+    pub column_family: String,
+    /// The table written, None if the memtable needed none
+    pub file_number: Option<u64>,
+    pub file_size: u64,
+    /// Number of entries in the memtable, deletions included
+    pub num_entries: u64,
+    pub duration: Duration,
+    pub status: Result<()>,
+}
+
+/// Describes a compaction, or the move of a file to the next level
+#[derive(Clone, Debug)]
+pub struct CompactionJobInfo {
+    /// Numbers the flushes and compactions of the DB, as in the event log
+    pub job_id: u64,
+    /// Name of the column family compacted
+    pub column_family: String,
+    pub level: usize,
+    pub output_level: usize,
+    /// The files of "level" and "output_level" read
+    pub input_files: Vec<u64>,
+    pub output_files: Vec<u64>,
+    pub input_bytes: u64,
+    pub output_bytes: u64,
+    /// Whether the compaction was asked for with DB::compact_range()
+    pub is_manual: bool,
+    /// Whether the input file was moved to "output_level" as it is,
+    /// without being rewritten
+    pub is_trivial_move: bool,
+    pub duration: Duration,
+    pub status: Result<()>,
+}
+
+/// What a table file was written for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TableFileCreationReason {
+    Flush,
+    Compaction,
+    /// Written from a log while opening the database
+    Recovery,
+    /// Written by DB::ingest_external_file()
+    Ingestion,
+}
+
+/// Describes a table file written by the database
+#[derive(Clone, Debug)]
+pub struct TableFileCreationInfo {
+    /// The flush or compaction that wrote the file, or zero for recovery
+    /// and ingestion
+    pub job_id: u64,
+    pub file_path: String,
+    pub file_number: u64,
+    pub file_size: u64,
+    pub reason: TableFileCreationReason,
+}
+
+/// Describes the deletion of a table file
+#[derive(Clone, Debug)]
+pub struct TableFileDeletionInfo {
+    pub file_path: String,
+    pub file_number: u64,
+    pub status: Result<()>,
+}
+
+/// Describes a log file
+#[derive(Clone, Debug)]
+pub struct WalFileInfo {
+    pub file_path: String,
+    pub log_number: u64,
+}
+
+/// What failed in the background
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BackgroundErrorReason {
+    Flush,
+    Compaction,
+    /// Appending to, syncing or creating a log file
+    WriteLog,
+}
+
+/// Describes a background error
+#[derive(Clone, Debug)]
+pub struct BackgroundErrorInfo {
+    pub reason: BackgroundErrorReason,
+    pub error: Error,
+}
@@ -0,0 +1,217 @@
+use std::{
+    cell::RefCell,
+    fmt::Write,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    env::{read_file_to_vec, Env, WritableFile},
+    util::{Error, Result},
+};
+
+/// Appends one JSON object per line to the event log, describing flushes
+/// and compactions.  Every event carries "time_micros", "event" and the
+/// shape of the LSM tree after the event as "lsm_files" and "lsm_bytes"
+/// (one entry per level), which is what replay_event_log() reads back.
+///
+/// The event log is best effort: write errors are ignored, they must not
+/// fail the flush or compaction being described.
+pub struct EventLogger {
+    file: RefCell<Box<dyn WritableFile>>,
+}
+
+impl EventLogger {
+    pub fn new(env: &dyn Env, fname: &str) -> Result<Self> {
+        Ok(Self {
+            file: RefCell::new(env.new_appendable_file(fname)?),
+        })
+    }
+
+    /// Start a new event named "event".  It is written out when the
+    /// returned builder is dropped.
+    pub fn event(&self, event: &str) -> EventBuilder<'_> {
+        let mut line = String::new();
+        let time_micros = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros();
+        let _ = write!(line, "{{\"time_micros\": {}", time_micros);
+        let mut builder = EventBuilder { logger: self, line };
+        builder.add_str("event", event);
+        builder
+    }
+}
+
+pub struct EventBuilder<'a> {
+    logger: &'a EventLogger,
+    line: String,
+}
+
+impl EventBuilder<'_> {
+    pub fn add_u64(&mut self, key: &str, value: u64) -> &mut Self {
+        let _ = write!(self.line, ", \"{}\": {}", key, value);
+        self
+    }
+
+    pub fn add_str(&mut self, key: &str, value: &str) -> &mut Self {
+        let _ = write!(self.line, ", \"{}\": \"", key);
+        for ch in value.chars() {
+            match ch {
+                '"' => self.line.push_str("\\\""),
+                '\\' => self.line.push_str("\\\\"),
+                ch if ch.is_control() => {
+                    let _ = write!(self.line, "\\u{:04x}", ch as u32);
+                }
+                ch => self.line.push(ch),
+            }
+        }
+        self.line.push('"');
+        self
+    }
+
+    pub fn add_array(&mut self, key: &str, values: impl IntoIterator<Item = u64>) -> &mut Self {
+        let values: Vec<String> = values.into_iter().map(|value| value.to_string()).collect();
+        let _ = write!(self.line, ", \"{}\": [{}]", key, values.join(", "));
+        self
+    }
+
+    /// Add the outcome of the operation as "status": "OK" or the error.
+    pub fn add_status(&mut self, result: &Result<()>) -> &mut Self {
+        match result {
+            Ok(()) => self.add_str("status", "OK"),
+            Err(error) => self.add_str("status", &error.to_string()),
+        }
+    }
+}
+
+impl Drop for EventBuilder<'_> {
+    fn drop(&mut self) {
+        self.line.push_str("}\n");
+        let mut file = self.logger.file.borrow_mut();
+        let _ = file.append(self.line.as_bytes());
+        let _ = file.flush();
+    }
+}
+
+/// One event of an event log, as read back by replay_event_log().
+#[derive(Clone, Debug, PartialEq)]
+pub struct LsmEvent {
+    pub time_micros: u64,
+    pub event: String,
+    /// Number of files at each level after the event
+    pub files_per_level: Vec<u64>,
+    /// Combined file size at each level after the event
+    pub bytes_per_level: Vec<u64>,
+}
+
+/// Read back the event log written by a database opened with
+/// DBConfig::event_log_file, so that the shape of the LSM tree can be
+/// followed over time.  Events that do not carry the LSM shape are
+/// skipped.
+pub fn replay_event_log(env: &dyn Env, fname: &str) -> Result<Vec<LsmEvent>> {
+    let contents = read_file_to_vec(env, fname)?;
+    let contents =
+        String::from_utf8(contents).map_err(|_| Error::corruption("event log is not UTF-8"))?;
+    let mut events = vec![];
+    for line in contents.lines() {
+        // A crash may leave a partial last line behind
+        if !line.ends_with('}') {
+            continue;
+        }
+        let (time_micros, event) = match (field(line, "time_micros"), field(line, "event")) {
+            (Some(time_micros), Some(event)) => (time_micros, event),
+            _ => return Err(Error::corruption("event without time or name")),
+        };
+        let (files, bytes) = match (field(line, "lsm_files"), field(line, "lsm_bytes")) {
+            (Some(files), Some(bytes)) => (files, bytes),
+            _ => continue,
+        };
+        events.push(LsmEvent {
+            time_micros: parse_u64(time_micros)?,
+            event: event.trim_matches('"').to_string(),
+            files_per_level: parse_array(files)?,
+            bytes_per_level: parse_array(bytes)?,
+        });
+    }
+    Ok(events)
+}
+
+/// The raw value of "key" in a line written by EventBuilder.
+fn field<'a>(line: &'a str, key: &str) -> Option<&'a str> {
+    let start = line.find(&format!("\"{}\": ", key))? + key.len() + 4;
+    let rest = &line[start..];
+    let end = if rest.starts_with('[') {
+        rest.find(']')? + 1
+    } else if let Some(string) = rest.strip_prefix('"') {
+        string.find('"')? + 2
+    } else {
+        rest.find([',', '}'])?
+    };
+    Some(&rest[..end])
+}
+
+fn parse_u64(value: &str) -> Result<u64> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| Error::corruption("bad number in event log"))
+}
+
+fn parse_array(value: &str) -> Result<Vec<u64>> {
+    let inner = value.trim_start_matches('[').trim_end_matches(']');
+    if inner.trim().is_empty() {
+        return Ok(vec![]);
+    }
+    inner.split(',').map(parse_u64).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::env::{test_dir, write_data_to_file, PosixEnv};
+
+    #[test]
+    fn test_event_log_replay() {
+        let env = PosixEnv::new();
+        let dir = test_dir("event_log_replay");
+        let fname = format!("{}/EVENTS", dir);
+
+        let logger = EventLogger::new(&env, &fname).unwrap();
+        logger
+            .event("flush_started")
+            .add_u64("job", 1)
+            .add_str("reason", "memtable \"full\"");
+        logger
+            .event("flush_finished")
+            .add_u64("job", 1)
+            .add_array("lsm_files", [1, 0])
+            .add_array("lsm_bytes", [4096, 0]);
+        drop(logger);
+
+        // Reopening appends
+        let logger = EventLogger::new(&env, &fname).unwrap();
+        logger
+            .event("compaction_finished")
+            .add_array("inputs", [])
+            .add_array("lsm_files", [0, 2])
+            .add_array("lsm_bytes", [0, 8000]);
+        drop(logger);
+
+        let events = replay_event_log(&env, &fname).unwrap();
+        assert_eq!(2, events.len());
+        assert_eq!("flush_finished", events[0].event);
+        assert_eq!(vec![1, 0], events[0].files_per_level);
+        assert_eq!(vec![4096, 0], events[0].bytes_per_level);
+        assert_eq!("compaction_finished", events[1].event);
+        assert_eq!(vec![0, 8000], events[1].bytes_per_level);
+        assert!(events[0].time_micros <= events[1].time_micros);
+
+        let contents = String::from_utf8(read_file_to_vec(&env, &fname).unwrap()).unwrap();
+        assert!(contents.contains(r#""reason": "memtable \"full\"""#));
+
+        write_data_to_file(Arc::new(PosixEnv::new()), b"{\"event\": \"x\"}\n", &fname).unwrap();
+        assert!(replay_event_log(&env, &fname).unwrap_err().is_corruption());
+    }
+}
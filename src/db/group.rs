@@ -0,0 +1,80 @@
+use std::{sync::Arc, thread};
+
+use super::{DBConfig, DB};
+use crate::{
+    util::{ShardedLRUCache, WriteBufferManager},
+    Result,
+};
+
+/// A set of databases opened together that share the resources which
+/// would otherwise be provisioned for each of them: the Env with its
+/// background threads, the block cache, the memory of the memtables and
+/// the rate limiter.  Meant for services that split their data into
+/// many shards.
+///
+/// The sizes in the config are budgets for the whole group:
+/// block_cache_size bounds the blocks cached for all the databases, and
+/// write_buffer_size the memory of all their memtables, unless the config
+/// brings its own block cache or write buffer manager.
+pub struct DBGroup {
+    dbs: Vec<DB>,
+    write_buffer_manager: Arc<WriteBufferManager>,
+}
+
+impl DBGroup {
+    /// Open the databases with the specified "names", each on a thread of
+    /// its own, so that their recoveries run side by side.  Fails if any
+    /// of them fails to open, closing the ones that did open.
+    pub fn open(names: &[&str], config: &DBConfig) -> Result<Self> {
+        let mut config = config.clone();
+        if config.block_cache.is_none() && config.block_cache_size > 0 {
+            config.block_cache = Some(Arc::new(ShardedLRUCache::new(config.block_cache_size)));
+        }
+        let write_buffer_manager = config
+            .write_buffer_manager
+            .get_or_insert_with(|| Arc::new(WriteBufferManager::new(config.write_buffer_size)))
+            .clone();
+        let config = &config;
+        let results: Vec<Result<DB>> = thread::scope(|scope| {
+            let handles: Vec<_> = names
+                .iter()
+                .map(|name| scope.spawn(move || DB::open(name, config)))
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect()
+        });
+        let dbs = results.into_iter().collect::<Result<_>>()?;
+        Ok(Self {
+            dbs,
+            write_buffer_manager,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.dbs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.dbs.is_empty()
+    }
+
+    /// The databases, in the order of the names they were opened with.
+    pub fn dbs(&self) -> &[DB] {
+        &self.dbs
+    }
+
+    pub fn dbs_mut(&mut self) -> &mut [DB] {
+        &mut self.dbs
+    }
+
+    pub fn into_dbs(self) -> Vec<DB> {
+        self.dbs
+    }
+
+    /// The manager accounting for the memtables of all the databases.
+    pub fn write_buffer_manager(&self) -> &Arc<WriteBufferManager> {
+        &self.write_buffer_manager
+    }
+}
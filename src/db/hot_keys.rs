@@ -0,0 +1,137 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use crate::util::{hash, Random};
+
+/// Rows of the count-min sketch, each indexed by its own hash of the key
+const SKETCH_DEPTH: usize = 4;
+/// Counters in each row of the sketch
+const SKETCH_WIDTH: usize = 2048;
+/// Number of keys kept as candidates for the hottest keys
+const NUM_CANDIDATES: usize = 64;
+
+struct SamplerState {
+    rnd: Random,
+    /// SKETCH_DEPTH rows of SKETCH_WIDTH counters
+    sketch: Vec<u64>,
+    /// The sampled keys with the highest estimated counts so far
+    candidates: HashMap<Vec<u8>, u64>,
+}
+
+impl SamplerState {
+    /// Count "key" in the sketch and return its estimated count: the
+    /// smallest of its counters, which never underestimates.
+    fn add(&mut self, key: &[u8]) -> u64 {
+        let mut estimate = u64::MAX;
+        for row in 0..SKETCH_DEPTH {
+            let index = row * SKETCH_WIDTH + hash(key, row as u32) as usize % SKETCH_WIDTH;
+            self.sketch[index] += 1;
+            estimate = estimate.min(self.sketch[index]);
+        }
+        estimate
+    }
+}
+
+/// Estimates the most frequently read user keys of a DB from a sample
+/// of the keys returned by gets and iterators.  Sampled keys are counted
+/// in a count-min sketch, and the ones with the highest estimates are
+/// tracked as heavy hitters.
+pub struct HotKeySampler {
+    sample_rate: u32,
+    state: Mutex<SamplerState>,
+}
+
+impl HotKeySampler {
+    /// Sample one in "sample_rate" accesses.
+    pub fn new(sample_rate: u32) -> Self {
+        assert!(sample_rate > 0);
+        Self {
+            sample_rate,
+            state: Mutex::new(SamplerState {
+                rnd: Random::new(0xdeadbeef),
+                sketch: vec![0; SKETCH_DEPTH * SKETCH_WIDTH],
+                candidates: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Note an access to "key".
+    pub fn record(&self, key: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        if !state.rnd.one_in(self.sample_rate) {
+            return;
+        }
+        let estimate = state.add(key);
+        if let Some(count) = state.candidates.get_mut(key) {
+            *count = estimate;
+            return;
+        }
+        if state.candidates.len() == NUM_CANDIDATES {
+            // Replace the coldest candidate if "key" is hotter
+            let (coldest, count) = state
+                .candidates
+                .iter()
+                .min_by_key(|(_, count)| **count)
+                .map(|(key, count)| (key.clone(), *count))
+                .unwrap();
+            if estimate <= count {
+                return;
+            }
+            state.candidates.remove(&coldest);
+        }
+        state.candidates.insert(key.to_vec(), estimate);
+    }
+
+    /// Return up to "n" of the hottest keys, hottest first, with their
+    /// estimated number of accesses.
+    pub fn hottest_keys(&self, n: usize) -> Vec<(Vec<u8>, u64)> {
+        let state = self.state.lock().unwrap();
+        let mut keys: Vec<_> = state
+            .candidates
+            .iter()
+            .map(|(key, count)| (key.clone(), count * self.sample_rate as u64))
+            .collect();
+        keys.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        keys.truncate(n);
+        keys
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hot_key_sampler() {
+        let sampler = HotKeySampler::new(1);
+        assert!(sampler.hottest_keys(10).is_empty());
+
+        // A few hot keys among many cold ones
+        let mut rnd = Random::new(301);
+        for i in 0..100000 {
+            let key = match i % 10 {
+                0..=2 => "hot0".to_string(),
+                3..=4 => "hot1".to_string(),
+                5 => "hot2".to_string(),
+                _ => format!("cold{}", rnd.uniform(10000)),
+            };
+            sampler.record(key.as_bytes());
+        }
+        let hottest = sampler.hottest_keys(3);
+        let keys: Vec<_> = hottest.iter().map(|(key, _)| key.as_slice()).collect();
+        assert_eq!(vec![b"hot0".as_slice(), b"hot1", b"hot2"], keys);
+        // Count-min never underestimates, and the cold keys barely add
+        assert!((30000..31000).contains(&hottest[0].1));
+        assert!((20000..21000).contains(&hottest[1].1));
+        assert!((10000..11000).contains(&hottest[2].1));
+        assert_eq!(NUM_CANDIDATES, sampler.hottest_keys(1000).len());
+
+        // Sampled counts are scaled up to estimate all accesses
+        let sampler = HotKeySampler::new(10);
+        for _ in 0..10000 {
+            sampler.record(b"key");
+        }
+        let hottest = sampler.hottest_keys(1);
+        assert_eq!(b"key".as_slice(), hottest[0].0);
+        assert!((8000..12000).contains(&hottest[0].1));
+    }
+}
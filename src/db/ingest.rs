@@ -0,0 +1,197 @@
+use std::{cmp::Ordering, sync::Arc};
+
+use super::{DBConfig, ReadConfig};
+use crate::{
+    dbformat::{extract_user_key, ParsedInternalKey, RangeTombstone, ValueType},
+    env::Env,
+    iterator::Iterator,
+    table::Table,
+    util::Comparator,
+    Error, Result,
+};
+
+/// A table file built outside of the database, to be ingested.
+pub struct ExternalFile {
+    pub table: Arc<Table>,
+    /// The smallest and the largest user key of the file, counting the
+    /// ends of its range tombstones
+    pub smallest: Vec<u8>,
+    pub largest: Vec<u8>,
+}
+
+impl ExternalFile {
+    /// Open the table file at "path", whose keys are internal keys, and
+    /// find the range of user keys it holds.  Fails if it holds none.
+    pub fn open(
+        env: &dyn Env,
+        config: &DBConfig,
+        user_comparator: &dyn Comparator,
+        path: &str,
+    ) -> Result<Self> {
+        let file = env.new_random_access_file(path)?;
+        let size = env.get_file_size(path)?;
+        let table = Table::open(config, file, size, None)?;
+
+        let mut bounds = None;
+        let mut iter = table.new_iterator(&ReadConfig::default());
+        iter.seek_to_first();
+        if iter.valid() {
+            let smallest = parse_user_key(path, iter.key())?;
+            iter.seek_to_last();
+            let largest = parse_user_key(path, iter.key())?;
+            bounds = Some((smallest, largest));
+        }
+        iter.status()?;
+        for tombstone in table.range_tombstones().iter() {
+            let (smallest, largest) =
+                bounds.get_or_insert_with(|| (tombstone.start.clone(), tombstone.end.clone()));
+            if user_comparator.compare(&tombstone.start, smallest) == Ordering::Less {
+                *smallest = tombstone.start.clone();
+            }
+            if user_comparator.compare(&tombstone.end, largest) == Ordering::Greater {
+                *largest = tombstone.end.clone();
+            }
+        }
+        drop(iter);
+
+        match bounds {
+            Some((smallest, largest)) => Ok(Self {
+                table,
+                smallest,
+                largest,
+            }),
+            None => Err(Error::invalid_argument(&format!(
+                "{}: external file is empty",
+                path
+            ))),
+        }
+    }
+}
+
+fn parse_user_key(path: &str, internal_key: &[u8]) -> Result<Vec<u8>> {
+    match ParsedInternalKey::parse(internal_key) {
+        Some(ikey) => Ok(ikey.user_key().to_vec()),
+        None => Err(Error::corruption(&format!(
+            "{}: bad key in external file",
+            path
+        ))),
+    }
+}
+
+/// The entries of an external file as they are ingested: at the sequence
+/// number assigned to the ingestion.  The scan stops with an error at the
+/// first entry that is not a point entry, or that does not come after
+/// the previous one in the order of the user keys.
+pub struct IngestedEntries {
+    iter: Box<dyn Iterator>,
+    user_comparator: Arc<dyn Comparator>,
+    sequence: u64,
+    /// The internal key of the current entry, rewritten
+    key: Vec<u8>,
+    status: Result<()>,
+}
+
+impl IngestedEntries {
+    pub fn new(
+        iter: Box<dyn Iterator>,
+        user_comparator: Arc<dyn Comparator>,
+        sequence: u64,
+    ) -> Self {
+        Self {
+            iter,
+            user_comparator,
+            sequence,
+            key: vec![],
+            status: Ok(()),
+        }
+    }
+
+    /// Rewrite the key of the entry iter is positioned at, if it can be
+    /// ingested.
+    fn update(&mut self) {
+        if !self.iter.valid() {
+            return;
+        }
+        let ikey = match ParsedInternalKey::parse(self.iter.key()) {
+            Some(ikey) if ikey.type_() != ValueType::RangeDeletion => ikey,
+            _ => {
+                self.status = Err(Error::corruption("bad entry in external file"));
+                return;
+            }
+        };
+        // Past the first entry, key holds the previous one
+        if !self.key.is_empty()
+            && self
+                .user_comparator
+                .compare(ikey.user_key(), extract_user_key(&self.key))
+                != Ordering::Greater
+        {
+            self.status = Err(Error::invalid_argument(
+                "external file has keys out of order or more than one entry for a key",
+            ));
+            return;
+        }
+        let mut key = vec![];
+        ParsedInternalKey::new(ikey.user_key(), self.sequence, ikey.type_()).append_to(&mut key);
+        self.key = key;
+    }
+}
+
+impl Iterator for IngestedEntries {
+    fn valid(&self) -> bool {
+        self.status.is_ok() && self.iter.valid()
+    }
+
+    fn seek_to_first(&mut self) {
+        self.key.clear();
+        self.iter.seek_to_first();
+        self.update();
+    }
+
+    fn seek_to_last(&mut self) {
+        unreachable!("external files are only scanned forward")
+    }
+
+    fn seek(&mut self, _target: &[u8]) {
+        unreachable!("external files are only scanned forward")
+    }
+
+    fn seek_for_prev(&mut self, _target: &[u8]) {
+        unreachable!("external files are only scanned forward")
+    }
+
+    fn next(&mut self) {
+        assert!(self.valid());
+        self.iter.next();
+        self.update();
+    }
+
+    fn prev(&mut self) {
+        unreachable!("external files are only scanned forward")
+    }
+
+    fn key(&self) -> &[u8] {
+        assert!(self.valid());
+        &self.key
+    }
+
+    fn value(&self) -> &[u8] {
+        assert!(self.valid());
+        self.iter.value()
+    }
+
+    fn status(&self) -> Result<()> {
+        self.status.clone()?;
+        self.iter.status()
+    }
+}
+
+/// The range tombstones of "file" at the sequence number assigned to the
+/// ingestion.
+pub fn ingested_range_tombstones(file: &ExternalFile, sequence: u64) -> Vec<RangeTombstone> {
+    file.table
+        .range_tombstones()
+        .iter()
+        .map(|tombstone| RangeTombstone::new(&tombstone.start, &tombstone.end, sequence))
+        .collect()
+}
@@ -0,0 +1,56 @@
+use std::sync::Arc;
+
+use crate::{util::MergeOperator, Error, Result};
+
+/// What a point lookup carries over from the sources of the database it
+/// has read, newest first, to the older ones.
+#[derive(Default)]
+pub struct LookupState {
+    /// The largest sequence number of the range tombstones visible to
+    /// the read that cover the key.  Entries older than it are deleted.
+    pub max_covering_tombstone_sequence: u64,
+    /// The merge operands of the key found so far, newest first
+    pub merge_operands: Vec<Vec<u8>>,
+}
+
+impl LookupState {
+    /// Return the value of "key" given "found", the entry that ended the
+    /// lookup if any: its value, or an error, which satisfies
+    /// Error::is_not_found() if the key was deleted.  The merge operands
+    /// found before that entry are applied to it.
+    pub fn finish(
+        self,
+        merge_operator: Option<&Arc<dyn MergeOperator>>,
+        key: &[u8],
+        found: Option<Result<Vec<u8>>>,
+    ) -> Result<Vec<u8>> {
+        if self.merge_operands.is_empty() {
+            return found.unwrap_or_else(|| Err(Error::not_found("")));
+        }
+        let existing_value = match found {
+            Some(Ok(value)) => Some(value),
+            Some(Err(error)) if !error.is_not_found() => return Err(error),
+            _ => None,
+        };
+        let mut operands = self.merge_operands;
+        operands.reverse();
+        full_merge(merge_operator, key, existing_value.as_deref(), &operands)
+    }
+}
+
+/// Apply "operands", oldest first, to "existing_value" with
+/// "merge_operator", failing if merge operands were written to a
+/// database without one.
+pub fn full_merge(
+    merge_operator: Option<&Arc<dyn MergeOperator>>,
+    key: &[u8],
+    existing_value: Option<&[u8]>,
+    operands: &[Vec<u8>],
+) -> Result<Vec<u8>> {
+    match merge_operator {
+        Some(merge_operator) => merge_operator.full_merge(key, existing_value, operands),
+        None => Err(Error::invalid_argument(
+            "merge operands found but no merge operator is configured",
+        )),
+    }
+}
@@ -1,30 +1,7326 @@
-use self::{
-    config::{DBConfig, ReadConfig, WriteConfig},
-    write_batch::WriteBatch,
+use std::{
+    cell::RefCell,
+    cmp::Ordering,
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
+    mem,
+    rc::Rc,
+    sync::{
+        atomic::{self, AtomicBool, AtomicU64},
+        mpsc::{self, TryRecvError},
+        Arc, Condvar, Mutex, MutexGuard, RwLock, Weak,
+    },
+    thread,
+    time::{Duration, Instant},
 };
-use crate::Result;
 
+pub use self::background_operation::{BackgroundOperation, OperationType};
+use self::background_operation::{OperationHandle, OperationTracker};
+use self::builder::build_table;
+use self::column_family::ColumnFamilyData;
+pub use self::column_family::{
+    ColumnFamilyHandle, DEFAULT_COLUMN_FAMILY, DEFAULT_COLUMN_FAMILY_NAME,
+};
+pub(crate) use self::config::check_deadline;
+pub use self::config::{
+    CompressionType, DBConfig, IngestConfig, ReadConfig, TableFileExtension, WriteConfig,
+};
+use self::db_iter::{DBIterator, IteratorSources};
+pub use self::event_listener::{
+    BackgroundErrorInfo, BackgroundErrorReason, CompactionJobInfo, EventListener, FlushJobInfo,
+    TableFileCreationInfo, TableFileCreationReason, TableFileDeletionInfo, WalFileInfo,
+};
+pub use self::event_log::{replay_event_log, LsmEvent};
+use self::event_log::{EventBuilder, EventLogger};
+pub use self::group::DBGroup;
+use self::hot_keys::HotKeySampler;
+use self::ingest::{ingested_range_tombstones, ExternalFile, IngestedEntries};
+use self::merge::full_merge;
+pub(crate) use self::merge::LookupState;
+pub use self::multi_map::{MultiMap, MultiMapIter, MultiMapOperator};
+pub use self::queue::Queue;
+pub(crate) use self::range_del::max_covering_sequence;
+use self::range_del::FragmentedRangeTombstones;
+pub use self::repair::repair_db;
+pub use self::snapshot::Snapshot;
+use self::snapshot::SnapshotList;
+pub use self::sst_file_writer::{SstFileInfo, SstFileWriter};
+pub use self::statistics::{CompactionStats, LevelStatistics, Statistics};
+pub use self::trace::Replayer;
+use self::trace::Tracer;
+pub use self::transaction::{OptimisticTransactionDB, Transaction};
+pub use self::ttl::DBWithTTL;
+pub use self::write_batch::{BatchEntry, WriteBatch, WriteBatchHandler, WriteBatchIter};
+pub use self::write_batch_with_index::WriteBatchWithIndex;
+use crate::{
+    dbformat::{
+        extract_user_key, InternalFilterPolicy, InternalKey, InternalKeyComparator, LookupKey,
+        ParsedInternalKey, RangeTombstone, ValueType, MAX_SEQUENCE_NUMBER, NUM_LEVELS,
+        VALUE_TYPE_FOR_SEEK,
+    },
+    env::{copy_file, Env, FileLock, WritableFile, WriteHint},
+    filename::{
+        current_file_name, descriptor_file_name, lock_file_name, log_file_name, parse_file_name,
+        set_current_file, table_file_name_for, FileType,
+    },
+    iterator::{new_error_iterator, new_merging_iterator, Iterator},
+    log::{Reader, Reporter, Writer},
+    memtable::MemTable,
+    table::{TableBuilder, TableCache},
+    util::{CompactionDecision, FilterPolicy},
+    version::{Compaction, FileMetaData, Version, VersionEdit, VersionSet},
+    Error, Result,
+};
+
+mod background_operation;
+mod builder;
+mod column_family;
 mod config;
+mod db_iter;
+mod event_listener;
+mod event_log;
+mod group;
+mod hot_keys;
+mod ingest;
+mod merge;
+mod multi_map;
+mod queue;
+mod range_del;
+mod repair;
+mod snapshot;
+mod sst_file_writer;
+mod statistics;
+mod trace;
+mod transaction;
+mod ttl;
 mod write_batch;
+mod write_batch_with_index;
+
+/// Number of open files reserved for purposes other than the table cache
+/// (the log, the MANIFEST, the LOCK file, etc.)
+const NUM_NON_TABLE_CACHE_FILES: usize = 10;
+
+/// Fix user-supplied config to use the internal key comparator and
+/// filter policy for the table files of the database, and clip numeric
+/// settings to sane ranges.
+fn sanitize_config(config: &DBConfig, icmp: &InternalKeyComparator) -> DBConfig {
+    DBConfig {
+        comparator: Arc::new(icmp.clone()),
+        max_open_files: config
+            .max_open_files
+            .clamp(64 + NUM_NON_TABLE_CACHE_FILES, 50000),
+        l0_stop_writes_trigger: config.l0_stop_writes_trigger.max(1),
+        filter_policy: config
+            .filter_policy
+            .clone()
+            .map(|policy| Arc::new(InternalFilterPolicy::new(policy)) as Arc<dyn FilterPolicy>),
+        ..config.clone()
+    }
+}
+
+/// Create an empty memtable, with a prefix bloom filter if "config" asks
+/// for one.
+fn new_memtable(config: &DBConfig, icmp: &InternalKeyComparator) -> Arc<MemTable> {
+    let bloom_bits =
+        (config.write_buffer_size as f64 * config.memtable_prefix_bloom_size_ratio * 8.0) as usize;
+    match config.prefix_extractor.clone() {
+        Some(extractor) if bloom_bits > 0 => {
+            MemTable::with_prefix_bloom(icmp.clone(), extractor, bloom_bits)
+        }
+        _ => MemTable::new(icmp.clone()),
+    }
+}
+
+/// Return an empty edit of the column family with id "id".
+/// Return the smallest key above all the keys that start with "prefix",
+/// in bytewise order: "prefix" with its last byte below 0xff increased
+/// and the bytes after it dropped.  Empty if there is no such key.
+fn prefix_successor(prefix: &[u8]) -> Vec<u8> {
+    let mut successor = prefix.to_vec();
+    while let Some(last) = successor.pop() {
+        if last < 0xff {
+            successor.push(last + 1);
+            break;
+        }
+    }
+    successor
+}
+
+fn column_family_edit(id: u32) -> VersionEdit {
+    let mut edit = VersionEdit::new();
+    edit.set_column_family(id);
+    edit
+}
+
+fn table_cache_size(sanitized_config: &DBConfig) -> usize {
+    // Reserve ten files or so for other uses and give the rest to TableCache.
+    sanitized_config.max_open_files - NUM_NON_TABLE_CACHE_FILES
+}
+
+struct CompactionState {
+    compaction: Compaction,
+    /// Sequence numbers < smallest_snapshot are not significant since we
+    /// will never have to service a snapshot below smallest_snapshot.
+    /// Therefore if we have seen a sequence number S <= smallest_snapshot,
+    /// we can drop all entries for the same key with sequence numbers < S.
+    smallest_snapshot: u64,
+    /// Entries newer than this are seen by no snapshot, None if there are
+    /// no snapshots
+    newest_snapshot: Option<u64>,
+    /// Files produced by compaction
+    outputs: Vec<FileMetaData>,
+    /// State kept for output being generated
+    builder: Option<TableBuilder>,
+    total_bytes: u64,
+    /// Range tombstones of the inputs that are kept, each written to the
+    /// outputs it overlaps, clipped to their ranges
+    range_tombstones: Vec<RangeTombstone>,
+    /// Start of the range of the current output, None for the first one
+    output_lower_bound: Option<Vec<u8>>,
+}
+
+impl CompactionState {
+    fn new(compaction: Compaction, smallest_snapshot: u64, newest_snapshot: Option<u64>) -> Self {
+        Self {
+            compaction,
+            smallest_snapshot,
+            newest_snapshot,
+            outputs: vec![],
+            builder: None,
+            total_bytes: 0,
+            range_tombstones: vec![],
+            output_lower_bound: None,
+        }
+    }
+}
+
+/// Writes a memtable out as a level-0 table: an immutable memtable on the
+/// background thread of the Env, or a memtable of recovered log records
+/// right away.
+struct FlushJob {
+    dbname: String,
+    env: Arc<dyn Env>,
+    config: DBConfig,
+    table_cache: Arc<TableCache>,
+    operations: Arc<OperationTracker>,
+    mem: Arc<MemTable>,
+    /// Number of the table to write
+    number: u64,
+}
+
+/// What a FlushJob did
+struct FlushOutput {
+    result: Result<()>,
+    /// The table written, if file_size is not zero
+    meta: FileMetaData,
+    micros: u64,
+}
+
+impl FlushJob {
+    fn run(self) -> FlushOutput {
+        let start = Instant::now();
+        let operation = self.operations.start(OperationType::Flush, None, 0);
+        operation.add_bytes_read(self.mem.approximate_memory_usage() as u64);
+        let mut meta = FileMetaData {
+            number: self.number,
+            ..Default::default()
+        };
+        let mut iter = self.mem.new_iterator();
+        let result = build_table(
+            &self.dbname,
+            self.env.as_ref(),
+            &self.config,
+            &self.table_cache,
+            iter.as_mut(),
+            &self.mem.range_tombstones(),
+            &mut meta,
+        );
+        operation.set_bytes_written(meta.file_size);
+        FlushOutput {
+            result,
+            meta,
+            micros: start.elapsed().as_micros() as u64,
+        }
+    }
+}
+
+/// Writes the outputs of a compaction without the lock of the DB, which
+/// it only takes to number them.  They are installed by
+/// DBInner::finish_compaction().
+struct CompactionJob {
+    job_id: u64,
+    /// Whether the compaction was picked by the user
+    is_manual: bool,
+    start: Instant,
+    /// The input files of the level and of the next one
+    inputs: [Vec<Arc<FileMetaData>>; 2],
+    dbname: String,
+    env: Arc<dyn Env>,
+    config: DBConfig,
+    internal_comparator: InternalKeyComparator,
+    table_cache: Arc<TableCache>,
+    operations: Arc<OperationTracker>,
+}
+
+impl CompactionJob {
+    /// Write the outputs of "compact", locking "shared" only to number
+    /// them.
+    fn do_compaction_work(&self, shared: &DBShared, compact: &mut CompactionState) -> Result<()> {
+        let start = Instant::now();
+        let level = compact.compaction.level();
+        let operation = self
+            .operations
+            .start(OperationType::Compaction, Some(level), level + 1);
+        let user_comparator = self.internal_comparator.user_comparator().clone();
+        let range_tombstones = self.compaction_range_tombstones(compact)?;
+        let mut input = compact
+            .compaction
+            .make_input_iterator(&self.table_cache, self.config.paranoid_checks);
+        input.seek_to_first();
+
+        let mut current_user_key: Option<Vec<u8>> = None;
+        let mut last_sequence_for_key = MAX_SEQUENCE_NUMBER;
+        while input.valid() {
+            let key = input.key();
+            operation.add_bytes_read((key.len() + input.value().len()) as u64);
+
+            // Handle key/value, add to state, etc.
+            let mut drop = false;
+            let mut merge_sequence = None;
+            let mut filtered = None;
+            match ParsedInternalKey::parse(key) {
+                None => {
+                    // Do not hide error keys
+                    current_user_key = None;
+                    last_sequence_for_key = MAX_SEQUENCE_NUMBER;
+                }
+                Some(ikey) => {
+                    if current_user_key.as_deref().is_none_or(|user_key| {
+                        user_comparator.compare(ikey.user_key(), user_key) != Ordering::Equal
+                    }) {
+                        // First occurrence of this user key.  Outputs only
+                        // end between user keys, so that the range
+                        // tombstones covering a key are in the file that
+                        // holds its entries.
+                        let stop_before = compact.compaction.should_stop_before(key);
+                        let max_output_file_size = compact.compaction.max_output_file_size();
+                        if compact.builder.as_ref().is_some_and(|builder| {
+                            stop_before || builder.file_size() >= max_output_file_size
+                        }) || self.crosses_output_partition(compact, key)
+                        {
+                            self.finish_compaction_output_file(
+                                compact,
+                                input.as_ref(),
+                                Some(ikey.user_key()),
+                            )?;
+                        }
+                        current_user_key = Some(ikey.user_key().to_vec());
+                        last_sequence_for_key = MAX_SEQUENCE_NUMBER;
+                    }
+
+                    if last_sequence_for_key <= compact.smallest_snapshot {
+                        // Hidden by an newer entry for same user key
+                        drop = true; // (A)
+                    } else if ikey.sequence()
+                        < range_tombstones
+                            .max_covering_sequence(ikey.user_key(), compact.smallest_snapshot)
+                    {
+                        // Deleted by a range tombstone that every snapshot
+                        // sees
+                        drop = true;
+                    } else if ikey.type_() == ValueType::Deletion
+                        && ikey.sequence() <= compact.smallest_snapshot
+                        && compact.compaction.is_base_level_for_key(ikey.user_key())
+                    {
+                        // For this user key:
+                        // (1) there is no data in higher levels
+                        // (2) data in lower levels will have larger sequence numbers
+                        // (3) data in layers that are being compacted here and have
+                        //     smaller sequence numbers will be dropped in the next
+                        //     few iterations of this loop (by rule (A) above).
+                        // Therefore this deletion marker is obsolete and can be dropped.
+                        drop = true;
+                    }
+
+                    if !drop
+                        && ikey.type_() == ValueType::Merge
+                        && ikey.sequence() <= compact.smallest_snapshot
+                        && self.config.merge_operator.is_some()
+                    {
+                        merge_sequence = Some(ikey.sequence());
+                    }
+                    if !drop
+                        && ikey.type_() == ValueType::Value
+                        && last_sequence_for_key == MAX_SEQUENCE_NUMBER
+                        && compact
+                            .newest_snapshot
+                            .is_none_or(|newest| ikey.sequence() > newest)
+                    {
+                        // The newest value of the key, which no snapshot
+                        // sees
+                        if let Some(filter) = self.config.compaction_filter.as_ref() {
+                            match filter.filter(level, ikey.user_key(), input.value()) {
+                                CompactionDecision::Keep => {}
+                                CompactionDecision::Remove
+                                    if compact.newest_snapshot.is_none()
+                                        && compact
+                                            .compaction
+                                            .is_base_level_for_key(ikey.user_key()) =>
+                                {
+                                    // The older values of the key are
+                                    // dropped by (A)
+                                    drop = true;
+                                }
+                                CompactionDecision::Remove => {
+                                    // Still needed to hide the older values
+                                    // of the key from new reads
+                                    let deletion = InternalKey::new(
+                                        ikey.user_key(),
+                                        ikey.sequence(),
+                                        ValueType::Deletion,
+                                    );
+                                    filtered = Some((deletion.encode().to_vec(), vec![]));
+                                }
+                                CompactionDecision::ChangeValue(value) => {
+                                    filtered = Some((key.to_vec(), value));
+                                }
+                            }
+                        }
+                    }
+                    last_sequence_for_key = ikey.sequence();
+                }
+            }
+
+            // The merge operands of the key that no snapshot sees apart are
+            // combined with the value below them, which takes the place of
+            // them all.  The input is then past the entries merged.
+            let merged = match merge_sequence {
+                Some(sequence) => {
+                    let user_key = current_user_key.clone().unwrap();
+                    Some(self.merge_compaction_entries(
+                        compact,
+                        input.as_mut(),
+                        &range_tombstones,
+                        &operation,
+                        &user_key,
+                        sequence,
+                    ))
+                }
+                None => None,
+            };
+
+            if !drop {
+                // Open output file if necessary
+                if compact.builder.is_none() {
+                    self.open_compaction_output_file(shared, compact)?;
+                }
+                let builder = compact.builder.as_mut().unwrap();
+                let result = match (merged.as_ref(), filtered.as_ref()) {
+                    (Some(entries), _) => entries
+                        .iter()
+                        .try_for_each(|(key, value)| builder.add(key, value)),
+                    (None, Some((key, value))) => builder.add(key, value),
+                    (None, None) => builder.add(input.key(), input.value()),
+                };
+                if result.is_err() {
+                    // Fails with the builder's error
+                    return self.finish_compaction_output_file(compact, input.as_ref(), None);
+                }
+                operation.set_bytes_written(compact.total_bytes + builder.file_size());
+            }
+
+            if merged.is_none() {
+                input.next();
+            }
+        }
+
+        // The range tombstones past the last output need a file of their
+        // own
+        if compact.builder.is_none() && input.status().is_ok() {
+            let lower = compact.output_lower_bound.as_deref();
+            if compact.range_tombstones.iter().any(|tombstone| {
+                lower.is_none_or(|lower| {
+                    user_comparator.compare(&tombstone.end, lower) == Ordering::Greater
+                })
+            }) {
+                self.open_compaction_output_file(shared, compact)?;
+            }
+        }
+        if compact.builder.is_some() {
+            self.finish_compaction_output_file(compact, input.as_ref(), None)?;
+        }
+        input.status()?;
+
+        let mut stats = CompactionStats {
+            micros: start.elapsed().as_micros() as u64,
+            ..Default::default()
+        };
+        for which in 0..2 {
+            for i in 0..compact.compaction.num_input_files(which) {
+                stats.bytes_read += compact.compaction.input(which, i).file_size;
+            }
+        }
+        stats.bytes_written = compact.outputs.iter().map(|output| output.file_size).sum();
+        compact
+            .compaction
+            .edit()
+            .add_compaction_stats(level + 1, stats);
+        Ok(())
+    }
+
+    /// Merge the operands of "user_key", starting with the one "input" is
+    /// positioned at, the newest with sequence number "sequence", into the
+    /// value below them.  Returns the entries that take their place: the
+    /// result of the merge, or the entries themselves if the value below
+    /// them is not part of the compaction or the merge fails.  "input" is
+    /// left past the entries returned.
+    fn merge_compaction_entries(
+        &self,
+        compact: &mut CompactionState,
+        input: &mut dyn Iterator,
+        range_tombstones: &FragmentedRangeTombstones,
+        operation: &OperationHandle,
+        user_key: &[u8],
+        sequence: u64,
+    ) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let user_comparator = self.internal_comparator.user_comparator();
+        // Newest first, ending with the value the operands apply to if
+        // "has_existing_value"
+        let mut entries = vec![(input.key().to_vec(), input.value().to_vec())];
+        let mut has_existing_value = false;
+        let mut resolved = false;
+        input.next();
+        while input.valid() {
+            let entry = ParsedInternalKey::parse(input.key())
+                .filter(|ikey| {
+                    user_comparator.compare(ikey.user_key(), user_key) == Ordering::Equal
+                })
+                .map(|ikey| (ikey.type_(), ikey.sequence()));
+            let (type_, entry_sequence) = match entry {
+                Some(entry) => entry,
+                None => break,
+            };
+            if entry_sequence
+                < range_tombstones.max_covering_sequence(user_key, compact.smallest_snapshot)
+            {
+                // Deleted, and dropped by the caller
+                resolved = true;
+                break;
+            }
+            match type_ {
+                ValueType::Merge | ValueType::Value => {
+                    operation.add_bytes_read((input.key().len() + input.value().len()) as u64);
+                    entries.push((input.key().to_vec(), input.value().to_vec()));
+                    input.next();
+                    if type_ == ValueType::Value {
+                        has_existing_value = true;
+                        resolved = true;
+                        break;
+                    }
+                }
+                ValueType::Deletion | ValueType::RangeDeletion => {
+                    resolved = true;
+                    break;
+                }
+            }
+        }
+        if !resolved && !compact.compaction.is_base_level_for_key(user_key) {
+            // The operands may apply to a value in a deeper level
+            return entries;
+        }
+
+        let existing_value = has_existing_value.then(|| entries.last().unwrap().1.as_slice());
+        let num_operands = entries.len() - usize::from(has_existing_value);
+        let operands: Vec<Vec<u8>> = entries[..num_operands]
+            .iter()
+            .rev()
+            .map(|(_, operand)| operand.clone())
+            .collect();
+        match full_merge(
+            self.config.merge_operator.as_ref(),
+            user_key,
+            existing_value,
+            &operands,
+        ) {
+            Ok(value) => {
+                let key = InternalKey::new(user_key, sequence, ValueType::Value);
+                vec![(key.encode().to_vec(), value)]
+            }
+            // Reads of the key fail with the error instead
+            Err(_) => entries,
+        }
+    }
+
+    /// Collect the range tombstones of the inputs of the compaction,
+    /// keeping in "compact" those that have to be written to the
+    /// outputs.  Returns them all, to drop the entries they delete.
+    fn compaction_range_tombstones(
+        &self,
+        compact: &mut CompactionState,
+    ) -> Result<FragmentedRangeTombstones> {
+        let mut tombstones = vec![];
+        for which in 0..2 {
+            for i in 0..compact.compaction.num_input_files(which) {
+                let file = compact.compaction.input(which, i);
+                tombstones.extend_from_slice(
+                    &self
+                        .table_cache
+                        .range_tombstones(file.number, file.file_size)?,
+                );
+            }
+        }
+        // A tombstone that every snapshot sees has deleted all it covers
+        // once nothing older lies below the outputs
+        let bottommost = compact.compaction.is_bottommost_level();
+        compact.range_tombstones = tombstones
+            .iter()
+            .filter(|tombstone| !bottommost || tombstone.sequence > compact.smallest_snapshot)
+            .cloned()
+            .collect();
+        Ok(FragmentedRangeTombstones::new(
+            self.internal_comparator.user_comparator().clone(),
+            tombstones,
+        ))
+    }
+
+    /// Return true if DBConfig::output_partitioner puts internal key
+    /// "key" in another file than the last key added to the current
+    /// compaction output.
+    fn crosses_output_partition(&self, compact: &CompactionState, key: &[u8]) -> bool {
+        let (partitioner, ikey) = match (
+            self.config.output_partitioner.as_ref(),
+            ParsedInternalKey::parse(key),
+        ) {
+            (Some(partitioner), Some(ikey)) => (partitioner, ikey),
+            _ => return false,
+        };
+        let previous = match compact.builder.as_ref().and_then(|b| b.largest_key()) {
+            Some(previous) => extract_user_key(previous),
+            None => return false,
+        };
+        self.internal_comparator
+            .user_comparator()
+            .compare(previous, ikey.user_key())
+            != Ordering::Equal
+            && partitioner.should_partition(previous, ikey.user_key())
+    }
+
+    fn open_compaction_output_file(
+        &self,
+        shared: &DBShared,
+        compact: &mut CompactionState,
+    ) -> Result<()> {
+        assert!(compact.builder.is_none());
+        let file_number = {
+            let mut inner = shared.lock();
+            let number = inner.versions.new_file_number();
+            inner.pending_outputs.insert(number);
+            number
+        };
+        compact.outputs.push(FileMetaData {
+            number: file_number,
+            ..Default::default()
+        });
+
+        // Make the output file
+        let fname =
+            table_file_name_for(&self.dbname, file_number, self.config.table_file_extension);
+        // The bottom of the tree only changes when compacted into again
+        let hint = if compact.compaction.is_bottommost_level() {
+            WriteHint::TableCold
+        } else {
+            WriteHint::TableHot
+        };
+        let file = self.env.new_writable_file(&fname, hint)?;
+        compact.builder = Some(TableBuilder::new(&self.config, file));
+        Ok(())
+    }
+
+    /// Finish the current output, which holds the user keys below
+    /// "upper_bound" (all the rest if None), along with the parts of the
+    /// range tombstones of the compaction that fall in its range.
+    fn finish_compaction_output_file(
+        &self,
+        compact: &mut CompactionState,
+        input: &dyn Iterator,
+        upper_bound: Option<&[u8]>,
+    ) -> Result<()> {
+        let mut builder = compact.builder.take().unwrap();
+        let output = compact.outputs.last_mut().unwrap();
+        let output_number = output.number;
+        assert!(output_number != 0);
+
+        if let (Some(smallest), Some(largest)) = (builder.smallest_key(), builder.largest_key()) {
+            output.smallest.decode_from(smallest);
+            output.largest.decode_from(largest);
+        }
+        let ucmp = self.internal_comparator.user_comparator();
+        let lower_bound = compact.output_lower_bound.take();
+        let mut num_tombstones = 0;
+        for tombstone in &compact.range_tombstones {
+            let mut clipped = tombstone.clone();
+            if let Some(lower) = lower_bound.as_deref() {
+                if ucmp.compare(&clipped.start, lower) == Ordering::Less {
+                    clipped.start = lower.to_vec();
+                }
+            }
+            if let Some(upper) = upper_bound {
+                if ucmp.compare(&clipped.end, upper) == Ordering::Greater {
+                    clipped.end = upper.to_vec();
+                }
+            }
+            if ucmp.compare(&clipped.start, &clipped.end) == Ordering::Less {
+                output.extend_to_range_tombstone(&self.internal_comparator, &clipped);
+                builder.add_range_tombstone(clipped);
+                num_tombstones += 1;
+            }
+        }
+        compact.output_lower_bound = upper_bound.map(|upper| upper.to_vec());
+
+        // Check for iterator errors
+        let current_entries = builder.num_entries();
+        let mut result = input.status();
+        if result.is_ok() {
+            result = builder.finish();
+        } else {
+            builder.abandon();
+        }
+        let current_bytes = builder.file_size();
+        output.file_size = current_bytes;
+        compact.total_bytes += current_bytes;
+
+        // Finish and check for file errors
+        if result.is_ok() {
+            result = builder.file().sync();
+        }
+        if result.is_ok() {
+            result = builder.file().close();
+        }
+
+        if result.is_ok() && (current_entries > 0 || num_tombstones > 0) {
+            // Verify that the table is usable
+            result = self
+                .table_cache
+                .new_iterator(&ReadConfig::default(), output_number, current_bytes)
+                .status();
+        }
+        result
+    }
+}
+
+impl FlushOutput {
+    /// Record the table in "edit".  Note that if file_size is zero, the
+    /// file has been deleted and should not be added to the manifest.
+    fn add_to(&self, edit: &mut VersionEdit) {
+        let meta = &self.meta;
+        if self.result.is_ok() && meta.file_size > 0 {
+            edit.add_file(
+                0,
+                meta.number,
+                meta.file_size,
+                meta.smallest.clone(),
+                meta.largest.clone(),
+            );
+            edit.add_compaction_stats(
+                0,
+                CompactionStats {
+                    micros: self.micros,
+                    bytes_read: 0,
+                    bytes_written: meta.file_size,
+                },
+            );
+        }
+    }
+}
 
-pub struct DB {}
+/// The flush of the immutable memtable running in the background
+struct PendingFlush {
+    job_id: u64,
+    number: u64,
+    /// The job, until a thread starts running it
+    job: Arc<Mutex<Option<FlushJob>>>,
+    output: mpsc::Receiver<FlushOutput>,
+}
+
+/// A write queued in DBInner::writers, or an operation that must not
+/// run along with writes
+struct QueuedWrite {
+    id: u64,
+    /// The updates, taken from the caller until they are written; None
+    /// for an operation
+    batch: Option<WriteBatch>,
+    sync: bool,
+    deadline: Option<Instant>,
+    /// Signalled when the writer is at the front of the queue, or its
+    /// write is done
+    cv: Arc<Condvar>,
+}
+
+/// What a read of a column family is served from: its memtables, newest
+/// first, and its current version, pinned so that the read can run
+/// without the lock of the DB, and the sequence number it observes
+struct ReadSources {
+    memtables: Vec<Arc<MemTable>>,
+    current: Arc<Version>,
+    sequence: u64,
+}
+
+/// What reads need of the state of a DB, published by DBInner::publish()
+/// whenever it changes, so that reads don't take the lock of the DB
+#[derive(Default)]
+struct ReadState {
+    /// The memtables of each column family, newest first, and its
+    /// current version
+    column_families: BTreeMap<u32, (Vec<Arc<MemTable>>, Arc<Version>)>,
+    bg_error: Option<Error>,
+}
+
+/// How far a secondary instance has followed the logs of the primary, or
+/// a read-only instance read the logs
+#[derive(Clone, Default)]
+struct SecondaryState {
+    /// The oldest log whose records are in the memtables, None before
+    /// the first catch up
+    first_log: Option<u64>,
+    /// The newest log read, and the offset to read it on from
+    log_number: u64,
+    log_offset: usize,
+}
+
+/// Log data that recovery dropped because it was corrupted
+struct DroppedLogData {
+    fname: String,
+    bytes: usize,
+    error: Error,
+}
+
+struct LogReporter {
+    fname: String,
+    /// Corruption stops the recovery if set, and is dropped otherwise
+    paranoid: bool,
+    status: Option<Error>,
+    dropped: Vec<DroppedLogData>,
+}
+
+impl Reporter for LogReporter {
+    fn corruption(&mut self, bytes: usize, error: &Error) {
+        if !self.paranoid {
+            self.dropped.push(DroppedLogData {
+                fname: self.fname.clone(),
+                bytes,
+                error: error.clone(),
+            });
+        } else if self.status.is_none() {
+            self.status = Some(error.clone());
+        }
+    }
+}
+
+/// A range of keys
+pub struct Range<'a> {
+    /// Included in the range
+    pub start: &'a [u8],
+    /// Not included in the range
+    pub limit: &'a [u8],
+}
+
+impl<'a> Range<'a> {
+    pub fn new(start: &'a [u8], limit: &'a [u8]) -> Self {
+        Self { start, limit }
+    }
+}
+
+/// A persistent ordered map from keys to values.  A DB is safe for
+/// concurrent access from multiple threads without any external
+/// synchronization: share it with an Arc.
+///
+/// Writes queue up, and the writer at the front of the queue writes the
+/// batches of those behind it along with its own, as one log record,
+/// without holding the lock of the state of the database.  The other
+/// changes of the database are serialized on that lock.  Reads never
+/// take it: they pin the memtables and the version they read from what
+/// the database publishes whenever those change, and don't wait for
+/// each other.  Compactions run on the background threads of the Env,
+/// and only take the lock to pick their inputs and install their
+/// outputs.
+pub struct DB {
+    shared: Arc<DBShared>,
+    // Shared with "inner", for the reads that run without its lock
+    config: DBConfig,
+    internal_comparator: InternalKeyComparator,
+    table_cache: Arc<TableCache>,
+    operations: Arc<OperationTracker>,
+    key_sampler: Option<Arc<HotKeySampler>>,
+    read_state: Arc<RwLock<ReadState>>,
+    last_sequence: Arc<AtomicU64>,
+    tracer: Mutex<Option<Tracer>>,
+    /// Whether "tracer" is set, so that operations only take its lock
+    /// while a trace is running
+    tracing: AtomicBool,
+}
 
 impl DB {
+    /// Open the database with the specified "name".
+    pub fn open(name: &str, config: &DBConfig) -> Result<Self> {
+        DBInner::open(name, config).map(Self::new)
+    }
+
+    /// Open the database at "primary" as a secondary instance, which
+    /// reads the files of the database while another process, the
+    /// primary, has it open and keeps writing.  The secondary does not
+    /// take the lock of the database, but that of the directory
+    /// "secondary", created if missing, which is otherwise unused.
+    ///
+    /// A secondary never writes to the database: all mutations fail with
+    /// an error that satisfies Error::is_not_supported().  It sees the
+    /// database as of when it was opened, until
+    /// try_catch_up_with_primary() is called.
+    pub fn open_as_secondary(primary: &str, secondary: &str, config: &DBConfig) -> Result<Self> {
+        DBInner::open_as_secondary(primary, secondary, config).map(Self::new)
+    }
+
+    /// Open the database with the specified "name" for reads only, e.g.
+    /// to inspect it or to serve a copy of it.  Nothing is written to the
+    /// database: the updates in its logs are kept in memtables rather
+    /// than written out to tables, nothing is compacted, and all
+    /// mutations fail with an error that satisfies
+    /// Error::is_not_supported().  The lock of the database is taken
+    /// unless DBConfig::skip_lock_when_read_only is set.
+    pub fn open_read_only(name: &str, config: &DBConfig) -> Result<Self> {
+        DBInner::open_read_only(name, config).map(Self::new)
+    }
+
+    fn new(mut inner: DBInner) -> Self {
+        inner.publish();
+        let db = Self {
+            config: inner.config.clone(),
+            internal_comparator: inner.internal_comparator.clone(),
+            table_cache: inner.table_cache.clone(),
+            operations: inner.operations.clone(),
+            key_sampler: inner.key_sampler.clone(),
+            read_state: inner.read_state.clone(),
+            last_sequence: inner.last_sequence.clone(),
+            tracer: Mutex::new(None),
+            tracing: AtomicBool::new(false),
+            shared: Arc::new_cyclic(|shared| {
+                inner.shared = shared.clone();
+                DBShared {
+                    inner: Mutex::new(inner),
+                    background_work_finished: Condvar::new(),
+                }
+            }),
+        };
+        // Compactions could not be scheduled before the DB was shared
+        db.lock().maybe_compact();
+        db
+    }
+
+    fn lock(&self) -> MutexGuard<'_, DBInner> {
+        self.shared.lock()
+    }
+
+    /// Finish the flush of the immutable memtable, if any, and wait until
+    /// the compactions it leads to are done.
+    #[cfg(test)]
+    fn wait_for_background_work(&self) -> Result<()> {
+        let mut inner = self.lock();
+        if inner.finish_flush(true)? {
+            inner.maybe_compact();
+        }
+        drop(self.shared.wait_for_compaction(inner));
+        Ok(())
+    }
+
+    /// Make a secondary instance see the updates the primary has made
+    /// since it last caught up: the MANIFEST is read again, and the new
+    /// records of the logs are added to the memtables.  Once the primary
+    /// has flushed the memtables of the logs replayed so far, they are
+    /// dropped and the logs it still needs are replayed from the start.
+    ///
+    /// Fails with an error that satisfies Error::is_not_supported() on a
+    /// DB not opened with open_as_secondary().  Other errors, e.g. when a
+    /// log was deleted by the primary while being read, leave the
+    /// secondary consistent, and the next call may well succeed.  Note
+    /// that the primary may delete the tables that a version read before
+    /// still refers to.
+    pub fn try_catch_up_with_primary(&self) -> Result<()> {
+        self.lock().try_catch_up_with_primary()
+    }
+
+    /// Set the database entry for "key" to "value".
+    pub fn put(&self, key: &[u8], value: &[u8], config: &WriteConfig) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.put(key, value)?;
+        self.write(&mut batch, config)
+    }
+
+    /// Set the entry for "key" to "value" in the column family of
+    /// "column_family".
+    pub fn put_cf(
+        &self,
+        column_family: &ColumnFamilyHandle,
+        key: &[u8],
+        value: &[u8],
+        config: &WriteConfig,
+    ) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.put_cf(column_family, key, value)?;
+        self.write(&mut batch, config)
+    }
+
+    /// Remove the database entry (if any) for "key".  It is not an error
+    /// if "key" did not exist in the database.
+    pub fn delete(&self, key: &[u8], config: &WriteConfig) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.delete(key)?;
+        self.write(&mut batch, config)
+    }
+
+    /// Remove the entry (if any) for "key" from the column family of
+    /// "column_family".
+    pub fn delete_cf(
+        &self,
+        column_family: &ColumnFamilyHandle,
+        key: &[u8],
+        config: &WriteConfig,
+    ) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.delete_cf(column_family, key)?;
+        self.write(&mut batch, config)
+    }
+
+    /// Merge "operand" into the value of "key" with DBConfig::merge_operator.
+    /// Fails with an error that satisfies Error::is_not_supported() if
+    /// the database has no merge operator.
+    pub fn merge(&self, key: &[u8], operand: &[u8], config: &WriteConfig) -> Result<()> {
+        if self.config.merge_operator.is_none() {
+            return Err(Error::not_supported("no merge operator configured"));
+        }
+        let mut batch = WriteBatch::new();
+        batch.merge(key, operand)?;
+        self.write(&mut batch, config)
+    }
+
+    /// Remove the database entries (if any) for the keys in [begin, end).
+    /// See WriteBatch::delete_range().
+    pub fn delete_range(&self, begin: &[u8], end: &[u8], config: &WriteConfig) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.delete_range(begin, end)?;
+        self.write(&mut batch, config)
+    }
+
+    /// Apply the specified updates to the database.
+    pub fn write(&self, updates: &mut WriteBatch, config: &WriteConfig) -> Result<()> {
+        self.write_batch(updates, config.sync, config.deadline)
+    }
+
+    fn write_batch(
+        &self,
+        updates: &mut WriteBatch,
+        sync: bool,
+        deadline: Option<Instant>,
+    ) -> Result<()> {
+        self.trace_write(updates);
+        self.shared.write(self.lock(), updates, sync, deadline)
+    }
+
+    /// Apply "updates" like write() if "check" passes on the sources of
+    /// the default column family.  The check runs once the writes queued
+    /// before it are done and the memtable has room for the write, and
+    /// no other write runs until the updates are written.  An empty batch
+    /// is only checked.
+    fn write_if(
+        &self,
+        updates: &mut WriteBatch,
+        config: &WriteConfig,
+        check: impl FnOnce(&ReadSources) -> Result<()>,
+    ) -> Result<()> {
+        self.trace_write(updates);
+        self.shared
+            .exclusive(self.lock(), |mut inner| {
+                if updates.count() > 0 {
+                    let result;
+                    (inner, result) = self.shared.make_room_for_write(inner, config.deadline);
+                    if let Err(error) = result {
+                        return (inner, Err(error));
+                    }
+                }
+                let result =
+                    check(&inner.read_sources(DEFAULT_COLUMN_FAMILY, &ReadConfig::default()));
+                if result.is_err() || updates.count() == 0 {
+                    return (inner, result);
+                }
+                if let Err(error) = inner.check_write(updates, config.deadline) {
+                    return (inner, Err(error));
+                }
+                self.shared.write_to_log(inner, updates, config.sync)
+            })
+            .1
+    }
+
+    /// Apply the updates "build" returns for the sequence number they are
+    /// written at, e.g. to key an entry by it, and return that sequence
+    /// number.  No other write runs in between.
+    fn write_with_sequence(
+        &self,
+        config: &WriteConfig,
+        build: impl FnOnce(u64) -> Result<WriteBatch>,
+    ) -> Result<u64> {
+        self.shared
+            .exclusive(self.lock(), |inner| {
+                let (inner, result) = self.shared.make_room_for_write(inner, config.deadline);
+                let sequence = inner.versions.last_sequence() + 1;
+                let mut updates = match result.and_then(|()| build(sequence)) {
+                    Ok(updates) => updates,
+                    Err(error) => return (inner, Err(error)),
+                };
+                if let Err(error) = inner.check_write(&updates, config.deadline) {
+                    return (inner, Err(error));
+                }
+                self.trace_write(&updates);
+                let (inner, result) = self.shared.write_to_log(inner, &mut updates, config.sync);
+                (inner, result.map(|()| sequence))
+            })
+            .1
+    }
+
+    fn trace_write(&self, updates: &WriteBatch) {
+        self.trace(|tracer| tracer.trace_write(updates));
+    }
+
+    /// Record an operation with "trace" if a trace is running.
+    fn trace(&self, trace: impl FnOnce(&mut Tracer)) {
+        if self.tracing.load(atomic::Ordering::Relaxed) {
+            if let Some(tracer) = self.tracer.lock().unwrap().as_mut() {
+                trace(tracer);
+            }
+        }
+    }
+
+    fn check_column_family(&self, column_family: &ColumnFamilyHandle) -> Result<()> {
+        if !self
+            .read_state
+            .read()
+            .unwrap()
+            .column_families
+            .contains_key(&column_family.id())
+        {
+            return Err(Error::invalid_argument(&format!(
+                "column family {} does not exist",
+                column_family.name()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Create a new column family named "name", and return a handle to
+    /// it.  Fails with an error that satisfies Error::is_invalid_argument()
+    /// if a column family with that name exists.
+    pub fn create_column_family(&self, name: &str) -> Result<ColumnFamilyHandle> {
+        self.lock().create_column_family(name)
+    }
+
+    /// Remove the column family of "column_family" with all its entries.
+    /// Iterators over it still see the entries they saw before.  The
+    /// default column family can't be dropped.
+    pub fn drop_column_family(&self, column_family: &ColumnFamilyHandle) -> Result<()> {
+        self.shared
+            .exclusive(self.lock(), |inner| {
+                // Not while a compaction of the column family may be running
+                let mut inner = self.shared.wait_for_compaction(inner);
+                let result = inner.drop_column_family(column_family);
+                (inner, result)
+            })
+            .1
+    }
+
+    /// Return a handle to the column family named "name", if there is
+    /// one.
+    pub fn column_family(&self, name: &str) -> Option<ColumnFamilyHandle> {
+        self.lock().column_family(name)
+    }
+
+    /// Return handles to all the column families of the database, the
+    /// default one first.
+    pub fn column_families(&self) -> Vec<ColumnFamilyHandle> {
+        self.lock().column_families()
+    }
+
+    /// If the database contains an entry for "key" return its value.
+    /// If there is no entry for "key" return an error that satisfies
+    /// Error::is_not_found().
+    pub fn get(&self, key: &[u8], config: &ReadConfig) -> Result<Vec<u8>> {
+        self.get_from(DEFAULT_COLUMN_FAMILY, key, config)
+    }
+
+    /// Like get(), in the column family of "column_family".
+    pub fn get_cf(
+        &self,
+        column_family: &ColumnFamilyHandle,
+        key: &[u8],
+        config: &ReadConfig,
+    ) -> Result<Vec<u8>> {
+        self.check_column_family(column_family)?;
+        self.get_from(column_family.id(), key, config)
+    }
+
+    fn get_from(&self, id: u32, key: &[u8], config: &ReadConfig) -> Result<Vec<u8>> {
+        self.trace(|tracer| tracer.trace_get(key));
+        if let Some(sampler) = self.key_sampler.as_ref() {
+            sampler.record(key);
+        }
+        self.get_below(id, key, config, LookupState::default())
+    }
+
+    /// Like get_from(), with "state" carried over from a source newer
+    /// than the database, e.g. the writes of a transaction that is not
+    /// committed yet.
+    fn get_below(
+        &self,
+        id: u32,
+        key: &[u8],
+        config: &ReadConfig,
+        mut state: LookupState,
+    ) -> Result<Vec<u8>> {
+        if config.fail_on_background_error {
+            if let Some(error) = self.read_state.read().unwrap().bg_error.as_ref() {
+                return Err(error.clone());
+            }
+        }
+        let sources = self.read_sources(id, config)?;
+        check_deadline(config.deadline)?;
+        let lookup_key = LookupKey::new(key, sources.sequence);
+        // Range tombstones found in the newer sources delete the older
+        // entries of the key in the sources after them, and merge
+        // operands are applied to them
+        let mut found = None;
+        for mem in &sources.memtables {
+            found = mem.get(&lookup_key, &mut state);
+            if found.is_some() {
+                break;
+            }
+        }
+        if found.is_none() {
+            found = sources.current.get(
+                config,
+                &lookup_key,
+                &self.internal_comparator,
+                &self.table_cache,
+                &mut state,
+            );
+        }
+        state.finish(self.config.merge_operator.as_ref(), key, found)
+    }
+
+    /// The sequence number reads with "config" observe.
+    fn read_sequence(&self, config: &ReadConfig) -> u64 {
+        match config.snapshot.as_ref() {
+            Some(snapshot) => snapshot.sequence(),
+            None => self.last_sequence.load(atomic::Ordering::Acquire),
+        }
+    }
+
+    /// Pin the memtables and the current version of column family "id"
+    /// that the DB published, for a read with "config".  Fails with an
+    /// error that satisfies Error::is_invalid_argument() if the column
+    /// family was dropped.
+    fn read_sources(&self, id: u32, config: &ReadConfig) -> Result<ReadSources> {
+        // Taken before the sources, which then hold every update up to it
+        let sequence = self.read_sequence(config);
+        let state = self.read_state.read().unwrap();
+        let (memtables, current) = state
+            .column_families
+            .get(&id)
+            .ok_or_else(|| Error::invalid_argument("column family does not exist"))?;
+        Ok(ReadSources {
+            memtables: memtables.clone(),
+            current: current.clone(),
+            sequence,
+        })
+    }
+
+    /// The current version of the default column family, as published.
+    fn current(&self) -> Arc<Version> {
+        self.read_state.read().unwrap().column_families[&DEFAULT_COLUMN_FAMILY]
+            .1
+            .clone()
+    }
+
+    /// Return an iterator over the contents of the database.  The
+    /// iterator yields user keys in order, each with its newest value,
+    /// and skips deleted keys.  The result is initially invalid; the
+    /// caller must call one of the seek methods before using it.
+    pub fn iter(&self, config: &ReadConfig) -> Box<dyn Iterator + '_> {
+        self.iter_from(DEFAULT_COLUMN_FAMILY, config)
+    }
+
+    /// Like iter(), over the column family of "column_family".
+    pub fn iter_cf(
+        &self,
+        column_family: &ColumnFamilyHandle,
+        config: &ReadConfig,
+    ) -> Box<dyn Iterator + '_> {
+        if let Err(error) = self.check_column_family(column_family) {
+            return new_error_iterator(error);
+        }
+        self.iter_from(column_family.id(), config)
+    }
+
+    fn iter_from(&self, id: u32, config: &ReadConfig) -> Box<dyn Iterator + '_> {
+        if let Err(error) = self.check_iterate_bounds(config) {
+            return new_error_iterator(error);
+        }
+        let sources = match self.open_iterator_sources(id, config) {
+            Ok(sources) => sources,
+            Err(error) => return new_error_iterator(error),
+        };
+        let mut iter = DBIterator::new(
+            self.internal_comparator.user_comparator().clone(),
+            sources.iter,
+            sources.sequence,
+            config.iterate_lower_bound.clone(),
+            config.iterate_upper_bound.clone(),
+            config.deadline,
+            Some(sources.version),
+        );
+        iter.set_range_tombstones(sources.range_tombstones);
+        if let Some(merge_operator) = self.config.merge_operator.as_ref() {
+            iter.set_merge_operator(merge_operator.clone());
+        }
+        if let Some(sampler) = self.key_sampler.as_ref() {
+            iter.set_key_sampler(sampler.clone());
+        }
+        if let Some(extractor) = self.config.prefix_extractor.as_ref() {
+            if config.prefix_same_as_start {
+                iter.set_prefix_extractor(extractor.clone());
+            }
+        }
+        let source_config = config.clone();
+        iter.set_source(Box::new(move || {
+            self.open_iterator_sources(id, &source_config)
+        }));
+        if let Some(max_pin) = config.max_iterator_pin {
+            iter.set_max_pin(max_pin, config.refresh_iterators);
+        }
+        Box::new(iter)
+    }
+
+    /// Open what an iterator over column family "id" with "config" reads,
+    /// as of now.
+    fn open_iterator_sources(&self, id: u32, config: &ReadConfig) -> Result<IteratorSources<'_>> {
+        let sources = self.read_sources(id, config)?;
+        let mut tombstones = vec![];
+        for mem in &sources.memtables {
+            tombstones.extend(mem.range_tombstones());
+        }
+        sources
+            .current
+            .add_range_tombstones(&self.table_cache, &mut tombstones)?;
+        Ok(IteratorSources {
+            iter: self.new_internal_iterator(&sources, config),
+            sequence: sources.sequence,
+            range_tombstones: Arc::new(FragmentedRangeTombstones::new(
+                self.internal_comparator.user_comparator().clone(),
+                tombstones,
+            )),
+            version: sources.current,
+        })
+    }
+
+    /// Return the sequence number of the newest update of "key" in
+    /// "sources": of its newest entry, or of the newest range tombstone
+    /// covering it if that is newer.  Returns zero if there is neither.
+    fn latest_sequence_for_key(&self, sources: &ReadSources, key: &[u8]) -> Result<u64> {
+        let ucmp = self.internal_comparator.user_comparator();
+        let mut iter = self.new_internal_iterator(sources, &ReadConfig::default());
+        iter.seek(LookupKey::new(key, MAX_SEQUENCE_NUMBER).internal_key());
+        let mut sequence = 0;
+        if iter.valid() {
+            let parsed = ParsedInternalKey::parse(iter.key())
+                .ok_or_else(|| Error::corruption("bad internal key"))?;
+            if ucmp.compare(parsed.user_key(), key) == Ordering::Equal {
+                sequence = parsed.sequence();
+            }
+        }
+        iter.status()?;
+        let mut tombstones = vec![];
+        for mem in &sources.memtables {
+            tombstones.extend(mem.range_tombstones());
+        }
+        sources
+            .current
+            .add_range_tombstones(&self.table_cache, &mut tombstones)?;
+        Ok(sequence.max(max_covering_sequence(
+            &tombstones,
+            ucmp.as_ref(),
+            key,
+            MAX_SEQUENCE_NUMBER,
+        )))
+    }
+
+    /// Return an iterator over the internal keys of "sources".
+    fn new_internal_iterator(
+        &self,
+        sources: &ReadSources,
+        config: &ReadConfig,
+    ) -> Box<dyn Iterator + '_> {
+        // The memtables stop at the iterate bounds themselves, so that a
+        // scan of a small range does not walk past it in a big memtable
+        let (lower, upper) = (
+            config.iterate_lower_bound.as_deref(),
+            config.iterate_upper_bound.as_deref(),
+        );
+        let prefix_seek = config.prefix_same_as_start;
+        let mut iters: Vec<_> = sources
+            .memtables
+            .iter()
+            .map(|mem| mem.new_owning_iterator(lower, upper, prefix_seek))
+            .collect();
+        let mut table_iters = vec![];
+        sources.current.add_iterators(
+            config,
+            &self.internal_comparator,
+            &self.table_cache,
+            &mut table_iters,
+        );
+        iters.extend(table_iters);
+        new_merging_iterator(Arc::new(self.internal_comparator.clone()), iters)
+    }
+
+    /// Fail if the iterate bounds of "config" leave no key to visit
+    /// because the lower bound is above the upper one, as ordered by the
+    /// comparator of the database, or if prefix_same_as_start would stop
+    /// the iteration short of the upper bound because the bounds have
+    /// different prefixes.  Those are mistakes of the caller, not empty or
+    /// short scans.  An upper bound right past the keys of the prefix of
+    /// the lower one, as the next prefix is, is fine.
+    fn check_iterate_bounds(&self, config: &ReadConfig) -> Result<()> {
+        if let (Some(lower), Some(upper)) = (
+            config.iterate_lower_bound.as_ref(),
+            config.iterate_upper_bound.as_ref(),
+        ) {
+            let ucmp = self.internal_comparator.user_comparator();
+            if ucmp.compare(lower, upper) == Ordering::Greater {
+                return Err(Error::invalid_argument(
+                    "iterate_lower_bound is above iterate_upper_bound",
+                ));
+            }
+            let extractor = match self.config.prefix_extractor.as_ref() {
+                Some(extractor) if config.prefix_same_as_start && extractor.in_domain(lower) => {
+                    extractor
+                }
+                _ => return Ok(()),
+            };
+            let prefix = extractor.transform(lower);
+            if extractor.in_domain(upper) && extractor.transform(upper) == prefix {
+                return Ok(());
+            }
+            let successor = prefix_successor(prefix);
+            if !successor.is_empty() && ucmp.compare(upper, &successor) == Ordering::Greater {
+                return Err(Error::invalid_argument(
+                    "iterate_lower_bound and iterate_upper_bound have different prefixes",
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Compact the underlying storage for the key range [*begin,*end].
+    /// In particular, deleted and overwritten versions are discarded,
+    /// and the data is rearranged to reduce the cost of operations
+    /// needed to access the data.  This operation should typically only
+    /// be invoked by users who understand the underlying implementation.
+    ///
+    /// begin==None is treated as a key before all keys in the database.
+    /// end==None is treated as a key after all keys in the database.
+    /// Therefore the following call will compact the entire database:
+    ///    db.compact_range(None, None)
+    pub fn compact_range(&self, begin: Option<&[u8]>, end: Option<&[u8]>) -> Result<()> {
+        self.shared
+            .compact_range(self.lock(), DEFAULT_COLUMN_FAMILY, begin, end)
+    }
+
+    /// Like compact_range(), in the column family of "column_family".
+    pub fn compact_range_cf(
+        &self,
+        column_family: &ColumnFamilyHandle,
+        begin: Option<&[u8]>,
+        end: Option<&[u8]>,
+    ) -> Result<()> {
+        let inner = self.lock();
+        inner.check_column_family(column_family)?;
+        self.shared
+            .compact_range(inner, column_family.id(), begin, end)
+    }
+
+    /// Write the contents of the memtable out to a level-0 table, and wait
+    /// until that and any flush still running are done.  Flushes normally
+    /// run in the background once the memtable is full; this makes the
+    /// data of the logs so far recoverable without them.
+    pub fn flush(&self) -> Result<()> {
+        self.shared
+            .exclusive(self.lock(), |mut inner| {
+                let result = inner.flush();
+                (inner, result)
+            })
+            .1
+    }
+
+    /// Make an openable copy of the database as of now in the directory
+    /// "dir", which must not exist yet.  The memtable is flushed first, so
+    /// that the copy needs no log: it is made of the live tables, linked
+    /// with Env::link_file() where the Env can, copied otherwise, and of
+    /// copies of the MANIFEST and CURRENT files.
+    pub fn checkpoint(&self, dir: &str) -> Result<()> {
+        self.shared
+            .exclusive(self.lock(), |mut inner| {
+                let result = inner.checkpoint(dir);
+                (inner, result)
+            })
+            .1
+    }
+
+    /// Add the entries of the table files at "paths", built outside of the
+    /// database with SstFileWriter, without going through the log and the memtable.  The
+    /// files must not overlap each other, and their keys must be internal
+    /// keys, each user key at most once.  The entries are written to new
+    /// tables of the database at a sequence number newer than any before,
+    /// so snapshots taken earlier don't see them, and each table is added
+    /// to the deepest level above the older entries of its keys.  The
+    /// memtable is flushed first if it holds keys in the range of a file.
+    pub fn ingest_external_file(&self, paths: &[&str], config: &IngestConfig) -> Result<()> {
+        self.shared
+            .exclusive(self.lock(), |inner| {
+                // The levels the files go to are picked by what is in them,
+                // which a running compaction would change
+                let mut inner = self.shared.wait_for_compaction(inner);
+                let result = inner.ingest_external_file(paths, config);
+                (inner, result)
+            })
+            .1
+    }
+
+    /// Return up to "n" of the most frequently read keys, hottest first,
+    /// with their estimated number of reads by gets and iterators.  The
+    /// estimates come from a sample of the reads since the database was
+    /// opened; empty unless DBConfig::hot_key_sample_rate is set.
+    pub fn hottest_keys(&self, n: usize) -> Vec<(Vec<u8>, u64)> {
+        self.key_sampler
+            .as_ref()
+            .map_or(vec![], |sampler| sampler.hottest_keys(n))
+    }
+
+    /// For each range in "ranges", return the approximate file system
+    /// space used by keys in [range.start .. range.limit).
+    ///
+    /// Note that the returned sizes measure file system space usage, so
+    /// if the user data compresses by a factor of ten, the returned
+    /// sizes will be one-tenth the size of the corresponding user data
+    /// size.
+    ///
+    /// The results may not include the sizes of recently written data.
+    pub fn get_approximate_sizes(&self, ranges: &[Range]) -> Vec<u64> {
+        let current = self.current();
+        let offset_of = |user_key: &[u8]| {
+            let ikey = InternalKey::new(user_key, MAX_SEQUENCE_NUMBER, VALUE_TYPE_FOR_SEEK);
+            current.approximate_offset_of(
+                &self.internal_comparator,
+                ikey.encode(),
+                &self.table_cache,
+            )
+        };
+        ranges
+            .iter()
+            .map(|range| offset_of(range.limit).saturating_sub(offset_of(range.start)))
+            .collect()
+    }
+
+    /// Split "range" into at most "n" consecutive ranges holding roughly
+    /// the same amount of data, e.g. to scan it with n iterators in
+    /// parallel.  The ranges are returned in order as (start, limit)
+    /// pairs: the first one starts at range.start, each other one at the
+    /// limit of the one before, and the last one ends at range.limit.
+    ///
+    /// The split points are boundaries of the table files, weighed by the
+    /// approximate amount of file data before them.  Data that is still
+    /// in the memtable is not accounted for, and fewer than "n" ranges
+    /// are returned if the files offer too few boundaries in the range.
+    pub fn split_range(&self, range: &Range, n: usize) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let ucmp = self.internal_comparator.user_comparator();
+        let whole = vec![(range.start.to_vec(), range.limit.to_vec())];
+        if n <= 1 || ucmp.compare(range.start, range.limit) != Ordering::Less {
+            return whole;
+        }
+
+        let current = self.current();
+        let offset_of = |user_key: &[u8]| {
+            let ikey = InternalKey::new(user_key, MAX_SEQUENCE_NUMBER, VALUE_TYPE_FOR_SEEK);
+            current.approximate_offset_of(
+                &self.internal_comparator,
+                ikey.encode(),
+                &self.table_cache,
+            )
+        };
+        let start_offset = offset_of(range.start);
+        let total = offset_of(range.limit).saturating_sub(start_offset) as u128;
+        if total == 0 {
+            return whole;
+        }
+
+        // The candidate split points are the file boundaries strictly
+        // inside the range
+        let mut boundaries = vec![];
+        for level in 0..NUM_LEVELS {
+            for file in current.files(level) {
+                for key in [file.smallest.user_key(), file.largest.user_key()] {
+                    if ucmp.compare(key, range.start) == Ordering::Greater
+                        && ucmp.compare(key, range.limit) == Ordering::Less
+                    {
+                        boundaries.push(key);
+                    }
+                }
+            }
+        }
+        boundaries.sort_by(|a, b| ucmp.compare(a, b));
+        boundaries.dedup_by(|a, b| ucmp.compare(a, b) == Ordering::Equal);
+
+        // Split at the first boundary past each n-th of the data.  A big
+        // file may carry the data past several of them at once.
+        let mut ranges = vec![];
+        let mut start = range.start.to_vec();
+        let parts = n as u128;
+        let mut next_split = 1;
+        for key in boundaries {
+            if next_split == parts {
+                break;
+            }
+            let offset = offset_of(key).saturating_sub(start_offset) as u128;
+            if offset * parts < total * next_split {
+                continue;
+            }
+            ranges.push((start, key.to_vec()));
+            start = key.to_vec();
+            while next_split < parts && offset * parts >= total * next_split {
+                next_split += 1;
+            }
+        }
+        ranges.push((start, range.limit.to_vec()));
+        ranges
+    }
+
+    /// Delete the keys in "range" by dropping the table files whose keys
+    /// all lie in it, without reading or rewriting them, and covering the
+    /// rest with a range tombstone.  A quick way to reclaim the space of
+    /// a huge deletion.
+    ///
+    /// Snapshots don't protect the dropped files.
+    pub fn delete_files_in_range(&self, range: &Range) -> Result<()> {
+        // The tombstone goes first, so that nothing in the range shows up
+        // again if the files are not dropped.  The file it ends up in is
+        // never dropped: its largest key is "range.limit".
+        self.delete_range(range.start, range.limit, &WriteConfig::default())?;
+        // Not the inputs of a running compaction
+        self.shared
+            .wait_for_compaction(self.lock())
+            .delete_files_in_range(range)
+    }
+
+    /// The flushes and compactions running right now, oldest first.
+    /// Meant for finding out what slow or hung background work is doing.
+    pub fn get_background_operations(&self) -> Vec<BackgroundOperation> {
+        self.operations.operations()
+    }
+
+    /// Return a handle to the current DB state.  Iterators created with
+    /// this handle will all observe a stable snapshot of the current DB
+    /// state.  The caller must call release_snapshot(result) when the
+    /// snapshot is no longer needed.
+    pub fn get_snapshot(&self) -> Arc<Snapshot> {
+        self.lock().get_snapshot()
+    }
+
+    /// Release a previously acquired snapshot.  The caller must not
+    /// use "snapshot" after this call.
+    pub fn release_snapshot(&self, snapshot: Arc<Snapshot>) {
+        self.lock().release_snapshot(snapshot)
+    }
+
+    /// Start recording the writes and gets made through this DB to
+    /// "file", with their timestamps, until end_trace() is called.  The
+    /// trace can be re-executed against another database with a Replayer.
+    pub fn start_trace(&self, file: Box<dyn WritableFile>) -> Result<()> {
+        let mut tracer = self.tracer.lock().unwrap();
+        if tracer.is_some() {
+            return Err(Error::invalid_argument("a trace is already running"));
+        }
+        *tracer = Some(Tracer::new(file)?);
+        self.tracing.store(true, atomic::Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Stop the trace started by start_trace() and close its file.
+    /// Returns the first error hit while writing the trace, if any.
+    pub fn end_trace(&self) -> Result<()> {
+        let mut tracer = self.tracer.lock().unwrap();
+        self.tracing.store(false, atomic::Ordering::Relaxed);
+        match tracer.take() {
+            Some(tracer) => tracer.finish(),
+            None => Err(Error::invalid_argument("no trace is running")),
+        }
+    }
+
+    /// DB implementations can export properties about their state
+    /// via this method.  If "property" is a valid property understood by this
+    /// DB implementation, returns its current value.  Otherwise returns None.
+    ///
+    /// Valid property names include:
+    ///
+    ///  "rebeldb.num-files-at-level<N>" - return the number of files at level <N>,
+    ///     where <N> is an ASCII representation of a level number (e.g. "0").
+    ///  "rebeldb.compaction-score-at-level<N>" - return the compaction score
+    ///     of level <N>.  A score of 1 or more means the level needs compacting.
+    ///  "rebeldb.estimate-pending-compaction-bytes" - return the estimated
+    ///     number of bytes compactions still have to rewrite.
+    ///  "rebeldb.stats" - returns a multi-line string that describes statistics
+    ///     about the internal operation of the DB.
+    ///  "rebeldb.sstables" - returns a multi-line string that describes all
+    ///     of the sstables that make up the db contents.
+    ///  "rebeldb.approximate-memory-usage" - returns the approximate number of
+    ///     bytes of memory in use by the DB.
+    ///  "rebeldb.num-immutable-mem-table" - return the number of memtables
+    ///     that are full and being flushed, which is 0 or 1.
+    ///  "rebeldb.num-entries-active-mem-table" - return the number of entries
+    ///     in the memtable, deletions included.
+    ///  "rebeldb.total-key-bytes-active-mem-table" and
+    ///  "rebeldb.total-value-bytes-active-mem-table" - return the total size
+    ///     of the user keys and of the values in the memtable.
+    pub fn get_property(&self, property: &str) -> Option<String> {
+        self.lock().get_property(property)
+    }
+
+    /// Return a snapshot of the current compaction state: the size and
+    /// score of every level and the estimated compaction debt.
+    pub fn statistics(&self) -> Statistics {
+        self.lock().statistics()
+    }
+
+    /// Return the error that stopped background work, if any.
+    pub fn background_error(&self) -> Option<Error> {
+        self.lock().bg_error.clone()
+    }
+
+    /// Forget the stored background error so that writes are accepted
+    /// again.  Only call this once the cause (e.g. a full disk) has been
+    /// dealt with; the failed work is retried by later flushes.
+    pub fn clear_background_error(&self) {
+        self.lock().clear_background_error()
+    }
+}
+
+impl Drop for DB {
+    fn drop(&mut self) {
+        // A background compaction holds on to the state of the DB while it
+        // runs, but the DB is closed once it is done, so that it can be
+        // opened again right away
+        let mut inner = self.lock();
+        inner.shutting_down = true;
+        self.shared.wait_for_compaction(inner).close();
+    }
+}
+
+/// The state of a DB with its lock, shared with the compactions running
+/// in the background
+struct DBShared {
+    inner: Mutex<DBInner>,
+    /// Signalled whenever a compaction is done
+    background_work_finished: Condvar,
+}
+
+impl DBShared {
+    fn lock(&self) -> MutexGuard<'_, DBInner> {
+        self.inner.lock().unwrap()
+    }
+
+    /// Wait until no compaction is running.  Until "inner" is unlocked
+    /// again, none starts.
+    fn wait_for_compaction<'a>(
+        &self,
+        mut inner: MutexGuard<'a, DBInner>,
+    ) -> MutexGuard<'a, DBInner> {
+        while inner.compacting {
+            inner = self.background_work_finished.wait(inner).unwrap();
+        }
+        inner
+    }
+
+    /// Run the compaction scheduled by DBInner::maybe_compact()
+    fn background_compaction(&self) {
+        let mut inner = self.lock();
+        if !inner.shutting_down && inner.bg_error.is_none() {
+            if let Some(c) = inner.pick_compaction() {
+                inner = self.run_compaction(inner, c, false).0;
+            }
+        }
+        self.end_compaction(&mut inner);
+    }
+
+    /// Let the next compaction start, and schedule it if a level needs it.
+    fn end_compaction(&self, inner: &mut DBInner) {
+        inner.compacting = false;
+        inner.maybe_compact();
+        self.background_work_finished.notify_all();
+    }
+
+    /// Run compaction "c", which was picked by the user if "is_manual".
+    /// The lock of the DB is released while the outputs are written.  A
+    /// failure is recorded as the background error.
+    /// REQUIRES: the caller set inner.compacting
+    fn run_compaction<'a>(
+        &'a self,
+        mut inner: MutexGuard<'a, DBInner>,
+        c: Compaction,
+        is_manual: bool,
+    ) -> (MutexGuard<'a, DBInner>, Result<()>) {
+        let result = if !is_manual && c.is_trivial_move() {
+            inner.move_file(c)
+        } else {
+            let (job, mut compact) = inner.start_compaction(c, is_manual);
+            drop(inner);
+            let result = job.do_compaction_work(self, &mut compact);
+            inner = self.lock();
+            inner.finish_compaction(&job, compact, result)
+        };
+        if let Err(error) = &result {
+            inner.record_background_error(BackgroundErrorReason::Compaction, error.clone());
+        }
+        (inner, result)
+    }
+
+    /// Apply "updates", queued behind the writes before it.  Unless the
+    /// writer in front of it writes them along with its own, this writer
+    /// leads the group of writes behind it.
+    fn write<'a>(
+        &'a self,
+        mut inner: MutexGuard<'a, DBInner>,
+        updates: &mut WriteBatch,
+        sync: bool,
+        deadline: Option<Instant>,
+    ) -> Result<()> {
+        let (id, cv) = inner.push_writer(Some(mem::take(updates)), sync, deadline);
+        loop {
+            if let Some((result, batch)) = inner.finished_writes.remove(&id) {
+                *updates = batch;
+                return result;
+            }
+            if inner.writers.front().unwrap().id == id {
+                inner = self.write_group(inner);
+            } else {
+                inner = cv.wait(inner).unwrap();
+            }
+        }
+    }
+
+    /// Write the batch of the writer at the front of the queue, along with
+    /// those of the writers behind it that can share its log record, and
+    /// report the result to them all.
+    fn write_group<'a>(&'a self, inner: MutexGuard<'a, DBInner>) -> MutexGuard<'a, DBInner> {
+        let leader = inner.writers.front().unwrap();
+        let (sync, deadline) = (leader.sync, leader.deadline);
+        let (mut inner, mut result) = self.make_room_for_write(inner, deadline);
+        if result.is_ok() {
+            let leader = inner.writers.front().unwrap();
+            result = inner.check_write(leader.batch.as_ref().unwrap(), deadline);
+        }
+        let mut group = vec![];
+        if result.is_ok() {
+            group = inner.take_write_group();
+            let mut merged = WriteBatch::new();
+            let updates = if group.len() == 1 {
+                &mut group[0].1
+            } else {
+                for (_, batch) in &group {
+                    merged.append(batch);
+                }
+                &mut merged
+            };
+            (inner, result) = self.write_to_log(inner, updates, sync);
+        } else {
+            // Only the leader fails
+            let leader = inner.writers.front_mut().unwrap();
+            group.push((leader.id, leader.batch.take().unwrap()));
+        }
+        for (id, batch) in group {
+            let writer = inner.writers.pop_front().unwrap();
+            debug_assert_eq!(id, writer.id);
+            inner.finished_writes.insert(id, (result.clone(), batch));
+            writer.cv.notify_one();
+        }
+        if let Some(writer) = inner.writers.front() {
+            writer.cv.notify_one();
+        }
+        inner
+    }
+
+    /// Run "f" once the writes queued before it are done, with no write
+    /// running until it returns, e.g. to switch the memtable.  "f" may
+    /// unlock the DB.
+    fn exclusive<'a, T>(
+        &'a self,
+        mut inner: MutexGuard<'a, DBInner>,
+        f: impl FnOnce(MutexGuard<'a, DBInner>) -> (MutexGuard<'a, DBInner>, T),
+    ) -> (MutexGuard<'a, DBInner>, T) {
+        let (id, cv) = inner.push_writer(None, false, None);
+        while inner.writers.front().unwrap().id != id {
+            inner = cv.wait(inner).unwrap();
+        }
+        let (mut inner, result) = f(inner);
+        inner.writers.pop_front();
+        if let Some(writer) = inner.writers.front() {
+            writer.cv.notify_one();
+        }
+        (inner, result)
+    }
+
+    /// Append "updates" to the log, syncing it if "sync", and insert them
+    /// into the memtables, with the lock of the DB released.
+    /// REQUIRES: the caller is at the front of DBInner::writers, and
+    /// inner.check_write() passed on "updates"
+    fn write_to_log<'a>(
+        &'a self,
+        mut inner: MutexGuard<'a, DBInner>,
+        updates: &mut WriteBatch,
+        sync: bool,
+    ) -> (MutexGuard<'a, DBInner>, Result<()>) {
+        let sequence = inner.versions.last_sequence() + 1;
+        updates.set_sequence(sequence);
+        // Only the writer at the front of the queue switches them
+        let memtables: HashMap<u32, Arc<MemTable>> = std::iter::once(DEFAULT_COLUMN_FAMILY)
+            .chain(updates.column_families())
+            .map(|id| (id, inner.memtable(id).unwrap().clone()))
+            .collect();
+        let mut log = inner.log.take().unwrap();
+        drop(inner);
+
+        let mut sync_error = None;
+        let mut result = log.add_record(updates.contents());
+        if result.is_ok() && sync {
+            if let Err(error) = log.file_mut().sync() {
+                sync_error = Some(error.clone());
+                result = Err(error);
+            }
+        }
+        if result.is_ok() {
+            result = updates
+                .insert_into_column_families(&|id| memtables.get(&id).map(|mem| mem.as_ref()));
+        }
+
+        let mut inner = self.lock();
+        inner.log = Some(log);
+        if let Some(error) = sync_error {
+            // The state of the log file is indeterminate: the record we
+            // just added may or may not show up when the DB is re-opened.
+            // So we force the DB into a mode where all future writes fail.
+            inner.record_background_error(BackgroundErrorReason::WriteLog, error);
+        }
+        if result.is_ok() {
+            let last_sequence = sequence + updates.count() as u64 - 1;
+            inner.versions.set_last_sequence(last_sequence);
+            // The updates are in the memtables for the reads to see
+            inner
+                .last_sequence
+                .store(last_sequence, atomic::Ordering::Release);
+            inner.update_write_buffer_usage();
+        }
+        (inner, result)
+    }
+
+    /// Switch to a new memtable and log file once the current memtable
+    /// has grown past write_buffer_size, flushing the full one to level-0
+    /// in the background.  Writes only wait for a flush when the memtable
+    /// fills up again before the previous one is flushed.  Fails with a
+    /// timed out error if that wait ran past "deadline".
+    /// REQUIRES: the caller is at the front of DBInner::writers
+    fn make_room_for_write<'a>(
+        &'a self,
+        mut inner: MutexGuard<'a, DBInner>,
+        deadline: Option<Instant>,
+    ) -> (MutexGuard<'a, DBInner>, Result<()>) {
+        if let Err(error) = inner.check_writable() {
+            return (inner, Err(error));
+        }
+        // Time the writer spends waiting on flushes and compactions counts
+        // as a write stall when tuning the rate limiter.
+        let start = Instant::now();
+        let mut stalled = false;
+        let mut allow_delay = true;
+        let result = loop {
+            match inner.finish_flush(false) {
+                Ok(true) => inner.maybe_compact(),
+                Ok(false) => {}
+                Err(error) => break Err(error),
+            }
+            let level0_files = inner.versions.num_level_files(0);
+            if allow_delay && level0_files >= inner.config.l0_slowdown_writes_trigger {
+                // We are getting close to hitting a hard limit on the number of
+                // L0 files.  Rather than delaying a single write by several
+                // seconds when we hit the hard limit, start delaying each
+                // individual write by 1ms to reduce latency variance.  Also,
+                // this delay hands over some CPU to the compaction thread in
+                // case it is sharing the same core as the writer.
+                drop(inner);
+                thread::sleep(Duration::from_millis(1));
+                inner = self.lock();
+                allow_delay = false; // Do not delay a single write more than once
+                stalled = true;
+            } else if level0_files >= inner.config.l0_stop_writes_trigger {
+                // There are too many level-0 files.  Wait until a
+                // compaction is done, which maybe_compact() schedules for
+                // level-0 at this point.
+                stalled = true;
+                if let Err(error) = inner.background_status() {
+                    // No compaction runs any more
+                    break Err(error);
+                }
+                inner.maybe_compact();
+                inner = self.background_work_finished.wait(inner).unwrap();
+            } else if !inner.memtable_is_full() {
+                // There is room in current memtable
+                break Ok(());
+            } else if inner.imm.is_some() {
+                // We have filled up the current memtable, but the previous
+                // one is still being flushed, so we wait.
+                stalled = true;
+                if inner.flush.is_none() {
+                    // Its flush failed, and the error was cleared since
+                    inner.schedule_flush();
+                }
+                if let Err(error) = inner.finish_flush(true) {
+                    break Err(error);
+                }
+                inner.maybe_compact();
+            } else if let Err(error) = inner.switch_memtable() {
+                // Attempt to switch to a new memtable and trigger flush of
+                // old one
+                break Err(error);
+            }
+        };
+
+        if stalled {
+            if let Some(rate_limiter) = inner.config.rate_limiter.as_ref() {
+                rate_limiter.tune(
+                    start.elapsed(),
+                    inner.versions.current().pending_compaction_bytes(),
+                );
+            }
+        }
+        let result = result.and_then(|()| check_deadline(deadline));
+        (inner, result)
+    }
+
+    /// Compact the underlying storage of column family "id" for the key
+    /// range [*begin,*end].  See DB::compact_range().
+    fn compact_range<'a>(
+        &'a self,
+        mut inner: MutexGuard<'a, DBInner>,
+        id: u32,
+        begin: Option<&[u8]>,
+        end: Option<&[u8]>,
+    ) -> Result<()> {
+        inner.check_writable()?;
+        inner.background_status()?;
+        let begin =
+            begin.map(|key| InternalKey::new(key, MAX_SEQUENCE_NUMBER, VALUE_TYPE_FOR_SEEK));
+        let end = end.map(|key| InternalKey::new(key, 0, ValueType::Deletion));
+        // The memtable is flushed once no compaction runs, so that no
+        // background compaction picks its table before this one does
+        let mut result;
+        (inner, result) = self.exclusive(inner, |inner| {
+            let mut inner = self.wait_for_compaction(inner);
+            inner.compacting = true;
+            let result = inner.compact_memtable();
+            (inner, result)
+        });
+        let current = inner.versions.current_cf(id);
+        let max_level_with_files = (1..NUM_LEVELS)
+            .filter(|&level| {
+                !current
+                    .get_overlapping_inputs(
+                        &inner.internal_comparator,
+                        level,
+                        begin.as_ref(),
+                        end.as_ref(),
+                    )
+                    .is_empty()
+            })
+            .max()
+            .unwrap_or(1);
+        drop(current);
+
+        for level in 0..max_level_with_files {
+            if result.is_err() {
+                break;
+            }
+            (inner, result) =
+                self.compact_range_level(inner, id, level, begin.as_ref(), end.as_ref());
+        }
+        self.end_compaction(&mut inner);
+        result?;
+        inner.background_status()
+    }
+
+    /// Compact the files of "level" of column family "id" that overlap
+    /// [*begin,*end] into the next level.
+    /// REQUIRES: the caller set inner.compacting
+    fn compact_range_level<'a>(
+        &'a self,
+        mut inner: MutexGuard<'a, DBInner>,
+        id: u32,
+        level: usize,
+        begin: Option<&InternalKey>,
+        end: Option<&InternalKey>,
+    ) -> (MutexGuard<'a, DBInner>, Result<()>) {
+        // Every compaction moves the files it picks out of "level", so
+        // this runs out of files to pick.
+        while let Some(c) = inner.versions.compact_range(id, level, begin, end) {
+            let result;
+            (inner, result) = self.run_compaction(inner, c, true);
+            if result.is_err() {
+                return (inner, result);
+            }
+        }
+        (inner, Ok(()))
+    }
+}
+
+/// The state of a DB, guarded by its lock
+struct DBInner {
+    env: Arc<dyn Env>,
+    config: DBConfig,
+    dbname: String,
+    /// Lock over the persistent DB state.  Some iff successfully acquired.
+    db_lock: Option<Box<dyn FileLock>>,
+    internal_comparator: InternalKeyComparator,
+    table_cache: Arc<TableCache>,
+    mem: Arc<MemTable>,
+    /// Memtable being flushed
+    imm: Option<Arc<MemTable>>,
+    /// The flush of "imm", unless it failed
+    flush: Option<PendingFlush>,
+    /// Memory of "mem" and "imm" reserved in
+    /// DBConfig::write_buffer_manager
+    mem_reserved: usize,
+    log: Option<Writer<Box<dyn WritableFile>>>,
+    logfile_number: u64,
+    /// The column families other than the default one, by id.  Their
+    /// memtables are flushed whenever the log is switched, so that the
+    /// logs before the current one only hold entries of "imm".
+    column_families: BTreeMap<u32, ColumnFamilyData>,
+    versions: VersionSet,
+    /// Table files being written by flushes and compactions.  They are
+    /// not in any version yet, but must not be deleted as obsolete.
+    pending_outputs: BTreeSet<u64>,
+    /// Set by a failed background flush or compaction.  While set, all
+    /// writes fail with this error so that no acknowledged update can be
+    /// lost behind a broken background job.
+    bg_error: Option<Error>,
+    snapshots: SnapshotList,
+    operations: Arc<OperationTracker>,
+    event_logger: Option<EventLogger>,
+    /// Numbers the flushes and compactions in the event log
+    next_job_id: u64,
+    /// Set iff DBConfig::hot_key_sample_rate is not zero
+    key_sampler: Option<Arc<HotKeySampler>>,
+    /// What reads need of this state, published by publish()
+    read_state: Arc<RwLock<ReadState>>,
+    /// versions.last_sequence(), published once the updates up to it are
+    /// in the memtables
+    last_sequence: Arc<AtomicU64>,
+    /// Set for the instances opened with DB::open_read_only() or
+    /// DB::open_as_secondary(), which never write to the database
+    read_only: bool,
+    /// Set iff the DB was opened with DB::open_as_secondary()
+    secondary: Option<SecondaryState>,
+    /// The DB this is the state of, for the compactions to lock it.
+    /// Unset until the DB is opened.
+    shared: Weak<DBShared>,
+    /// Set while a compaction is scheduled or running, in the background
+    /// or for compact_range(): they run one at a time, so that no two
+    /// pick the same files
+    compacting: bool,
+    /// Set once the DB is being closed, so that no compaction starts
+    shutting_down: bool,
+    /// The writes waiting for their turn, oldest first.  The one at the
+    /// front writes those behind it along with its own, with the lock
+    /// released, and is the only one to switch the memtable and the log,
+    /// which it takes meanwhile.
+    writers: VecDeque<QueuedWrite>,
+    /// The results of the writes that are done, by writer id, with their
+    /// batches, until their writers pick them up
+    finished_writes: HashMap<u64, (Result<()>, WriteBatch)>,
+    next_writer_id: u64,
+}
+
+impl DBInner {
+    fn new(name: &str, config: &DBConfig) -> Self {
+        let internal_comparator = InternalKeyComparator::new(config.comparator.clone());
+        let config = sanitize_config(config, &internal_comparator);
+        let key_sampler = match config.hot_key_sample_rate {
+            0 => None,
+            rate => Some(Arc::new(HotKeySampler::new(rate))),
+        };
+        Self {
+            env: config.env.clone(),
+            dbname: name.to_string(),
+            db_lock: None,
+            table_cache: Arc::new(TableCache::new(name, &config, table_cache_size(&config))),
+            mem: new_memtable(&config, &internal_comparator),
+            imm: None,
+            flush: None,
+            mem_reserved: 0,
+            log: None,
+            logfile_number: 0,
+            column_families: BTreeMap::new(),
+            versions: VersionSet::new(name, &config, internal_comparator.clone()),
+            pending_outputs: BTreeSet::new(),
+            internal_comparator,
+            config,
+            bg_error: None,
+            snapshots: SnapshotList::default(),
+            operations: Arc::new(OperationTracker::default()),
+            event_logger: None,
+            next_job_id: 1,
+            key_sampler,
+            read_state: Arc::default(),
+            last_sequence: Arc::default(),
+            read_only: false,
+            secondary: None,
+            shared: Weak::new(),
+            compacting: false,
+            shutting_down: false,
+            writers: VecDeque::new(),
+            finished_writes: HashMap::new(),
+            next_writer_id: 0,
+        }
+    }
+
     fn open(name: &str, config: &DBConfig) -> Result<Self> {
-        todo!()
+        let mut db = Self::new(name, config);
+
+        // Recover handles create_if_missing, error_if_exists
+        let mut edits = BTreeMap::new();
+        let mut dropped = vec![];
+        db.recover(&mut edits, &mut dropped)?;
+
+        let new_log_number = db.versions.new_file_number();
+        let file = db
+            .env
+            .new_writable_file(&log_file_name(&db.dbname, new_log_number), WriteHint::Wal)?;
+        let mut log = Writer::new(file);
+        log.set_compression(db.config.compress_wal);
+        db.log = Some(log);
+        db.logfile_number = new_log_number;
+        db.notify_wal_file_created();
+
+        // The other column families go first, so that the logs are kept
+        // until all of them have their entries in tables
+        let mut edit = edits.remove(&DEFAULT_COLUMN_FAMILY).unwrap_or_default();
+        for (_, mut cf_edit) in edits {
+            cf_edit.set_log_number(new_log_number);
+            db.versions.log_and_apply(&mut cf_edit)?;
+        }
+        edit.set_log_number(new_log_number);
+        edit.set_prev_log_number(0); // No older logs needed after recovery.
+        db.versions.log_and_apply(&mut edit)?;
+        if let Some(fname) = db.config.event_log_file.as_ref() {
+            db.event_logger = Some(EventLogger::new(db.env.as_ref(), fname)?);
+            db.log_event("db_opened", |_| {});
+        }
+        for data in dropped {
+            db.log_event("log_data_dropped", |event| {
+                event
+                    .add_str("file", &data.fname)
+                    .add_u64("bytes", data.bytes as u64)
+                    .add_status(&Err(data.error));
+            });
+        }
+        db.remove_obsolete_files();
+        db.update_write_buffer_usage();
+        Ok(db)
+    }
+
+    fn open_as_secondary(primary: &str, secondary: &str, config: &DBConfig) -> Result<Self> {
+        let mut db = Self::new(primary, config);
+        let _ = db.env.create_dir(secondary);
+        db.db_lock = Some(db.env.lock_file(&lock_file_name(secondary))?);
+        db.read_only = true;
+        db.secondary = Some(SecondaryState::default());
+        db.try_catch_up_with_primary()?;
+        Ok(db)
+    }
+
+    fn open_read_only(name: &str, config: &DBConfig) -> Result<Self> {
+        let mut db = Self::new(name, config);
+        db.read_only = true;
+        if !db.config.skip_lock_when_read_only {
+            db.db_lock = Some(db.env.lock_file(&lock_file_name(name))?);
+        }
+        if !db.env.file_exists(&current_file_name(name)) {
+            return Err(Error::invalid_argument(&format!(
+                "{}: does not exist",
+                name
+            )));
+        }
+        db.read_files(&mut SecondaryState::default())?;
+        Ok(db)
     }
-    fn put(&mut self, key: &[u8], value: &[u8], config: &WriteConfig) -> Result<()> {
-        todo!()
+
+    fn try_catch_up_with_primary(&mut self) -> Result<()> {
+        let mut state = match self.secondary.as_ref() {
+            Some(state) => state.clone(),
+            None => return Err(Error::not_supported("not a secondary instance")),
+        };
+        let result = self.read_files(&mut state);
+        self.secondary = Some(state);
+        self.publish();
+        result
+    }
+
+    /// Read the MANIFEST, and the records of the logs past "state" into
+    /// the memtables, leaving "state" past the last record read.  For the
+    /// instances that don't write, which can't flush the logs to tables.
+    fn read_files(&mut self, state: &mut SecondaryState) -> Result<()> {
+        let last_sequence = self.versions.last_sequence();
+        self.versions.recover()?;
+        if self.versions.last_sequence() < last_sequence {
+            self.versions.set_last_sequence(last_sequence);
+        }
+        let live: BTreeMap<_, _> = self
+            .versions
+            .column_families()
+            .into_iter()
+            .map(|(id, name, _)| (id, name.to_string()))
+            .collect();
+        self.column_families.retain(|id, _| live.contains_key(id));
+        for (id, name) in live {
+            self.column_families
+                .entry(id)
+                .or_insert_with(|| ColumnFamilyData {
+                    handle: ColumnFamilyHandle::new(id, &name),
+                    mem: new_memtable(&self.config, &self.internal_comparator),
+                });
+        }
+
+        let min_log = self.versions.log_number();
+        if state.first_log.is_none_or(|first_log| first_log < min_log) {
+            // The entries of the older logs are in tables now
+            self.mem = new_memtable(&self.config, &self.internal_comparator);
+            for column_family in self.column_families.values_mut() {
+                column_family.mem = new_memtable(&self.config, &self.internal_comparator);
+            }
+            *state = SecondaryState {
+                first_log: Some(min_log),
+                log_number: min_log,
+                log_offset: 0,
+            };
+        }
+        let mut logs = vec![];
+        for filename in self.env.get_children(&self.dbname)? {
+            if let Some((number, FileType::LogFile)) = filename.to_str().and_then(parse_file_name) {
+                if number >= state.log_number {
+                    logs.push(number);
+                }
+            }
+        }
+        logs.sort_unstable();
+        let mut result = Ok(());
+        for log_number in logs {
+            if log_number > state.log_number {
+                state.log_number = log_number;
+                state.log_offset = 0;
+            }
+            match self.tail_log_file(log_number, &mut state.log_offset) {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(error) => {
+                    result = Err(error);
+                    break;
+                }
+            }
+        }
+        self.update_write_buffer_usage();
+        result
     }
-    fn delete(&mut self, key: &[u8], config: &WriteConfig) -> Result<()> {
-        todo!()
+
+    /// Add the records of log file "log_number" from "offset" on to the
+    /// memtables of an instance that doesn't write, leaving "offset" past the last
+    /// record added.  Returns false if it stopped at a record of a column
+    /// family created after the MANIFEST was read.
+    fn tail_log_file(&mut self, log_number: u64, offset: &mut usize) -> Result<bool> {
+        let fname = log_file_name(&self.dbname, log_number);
+        let file = self.env.new_sequential_file(&fname)?;
+        let reporter = Rc::new(RefCell::new(LogReporter {
+            fname: fname.clone(),
+            paranoid: self.config.paranoid_checks,
+            status: None,
+            dropped: vec![],
+        }));
+        let mut reader = Reader::new(file, *offset, true, Some(reporter.clone()));
+
+        // The column families whose updates in the log are not in tables
+        let replayed: BTreeSet<_> = self
+            .versions
+            .column_families()
+            .into_iter()
+            .filter(|&(_, _, number)| number <= log_number)
+            .map(|(id, ..)| id)
+            .chain(std::iter::once(DEFAULT_COLUMN_FAMILY))
+            .collect();
+        let mut batch = WriteBatch::new();
+        let mut max_sequence = 0;
+        let mut complete = true;
+        while let Some(record) = reader.read_record() {
+            if record.len() < 12 {
+                reporter.borrow_mut().corruption(
+                    record.len(),
+                    &Error::corruption(&format!("{}: log record too small", fname)),
+                );
+            } else {
+                batch.set_contents(record);
+                let max_column_family = self.versions.max_column_family();
+                if batch
+                    .column_families()
+                    .into_iter()
+                    .any(|id| id > max_column_family)
+                {
+                    // Read again once the MANIFEST is
+                    complete = false;
+                    break;
+                }
+                batch.insert_into_column_families(&|id| {
+                    replayed
+                        .contains(&id)
+                        .then(|| self.memtable(id))
+                        .flatten()
+                        .map(|mem| mem.as_ref())
+                })?;
+                max_sequence = max_sequence.max(batch.sequence() + batch.count() as u64 - 1);
+            }
+            *offset = reader.last_record_offset() + 1;
+            if let Some(error) = reporter.borrow_mut().status.take() {
+                return Err(error);
+            }
+        }
+        if let Some(error) = reporter.borrow_mut().status.take() {
+            return Err(error);
+        }
+        if self.versions.last_sequence() < max_sequence {
+            self.versions.set_last_sequence(max_sequence);
+        }
+        Ok(complete)
     }
-    fn write(&mut self, updates: &WriteBatch, config: &WriteConfig) -> Result<()> {
-        todo!()
+
+    /// Fail with an error that satisfies Error::is_not_supported() if the
+    /// database can't be written to.
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only {
+            return Err(Error::not_supported("the database was opened read-only"));
+        }
+        Ok(())
     }
-    fn get(&self, key: &[u8], config: &ReadConfig) -> Result<Vec<u8>> {
-        todo!()
+
+    /// Check that "updates" can be written before "deadline": the DB
+    /// takes writes, and the column families of "updates" exist.
+    fn check_write(&self, updates: &WriteBatch, deadline: Option<Instant>) -> Result<()> {
+        self.check_writable()?;
+        self.background_status()?;
+        check_deadline(deadline)?;
+        for id in updates.column_families() {
+            if !self.column_families.contains_key(&id) {
+                return Err(Error::invalid_argument(&format!(
+                    "column family {} does not exist",
+                    id
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Queue a writer of "batch", or of an operation that must not run
+    /// along with writes if None.  Returns its id, and the condition
+    /// variable signalled when it is at the front of the queue or its
+    /// write is done.
+    fn push_writer(
+        &mut self,
+        batch: Option<WriteBatch>,
+        sync: bool,
+        deadline: Option<Instant>,
+    ) -> (u64, Arc<Condvar>) {
+        let id = self.next_writer_id;
+        self.next_writer_id += 1;
+        let cv = Arc::new(Condvar::new());
+        self.writers.push_back(QueuedWrite {
+            id,
+            batch,
+            sync,
+            deadline,
+            cv: cv.clone(),
+        });
+        (id, cv)
+    }
+
+    /// Take the batches of the writer at the front of the queue and of
+    /// the writers behind it that can share its log record, with their
+    /// ids, in queue order.  The writers stay queued.
+    fn take_write_group(&mut self) -> Vec<(u64, WriteBatch)> {
+        let mut writers = self.writers.iter_mut();
+        let first = writers.next().unwrap();
+        let (sync, deadline) = (first.sync, first.deadline);
+        let first_batch = first.batch.take().unwrap();
+
+        // Allow the group to grow up to a maximum size, but if the
+        // original write is small, limit the growth so we do not slow
+        // down the small write too much.
+        let mut size = first_batch.byte_size();
+        let max_size = if size <= 128 << 10 {
+            size + (128 << 10)
+        } else {
+            1 << 20
+        };
+        let mut group = vec![(first.id, first_batch)];
+        for writer in writers {
+            let batch = match writer.batch.as_ref() {
+                Some(batch) => batch,
+                // Not past an operation that waits for the writes
+                None => break,
+            };
+            if writer.sync && !sync {
+                // Do not include a sync write into a group handled by a
+                // non-sync write.
+                break;
+            }
+            if writer.deadline.is_some()
+                && deadline.is_none_or(|deadline| writer.deadline.unwrap() < deadline)
+            {
+                // Nor a write that can't wait as long as the group might
+                break;
+            }
+            size += batch.byte_size();
+            if size > max_size {
+                // Do not make the group too big
+                break;
+            }
+            if batch
+                .column_families()
+                .iter()
+                .any(|id| !self.column_families.contains_key(id))
+            {
+                // Fails on its own
+                break;
+            }
+            group.push((writer.id, writer.batch.take().unwrap()));
+        }
+        group
+    }
+
+    /// Return the memtable of column family "id" that takes writes, if
+    /// the column family exists.
+    fn memtable(&self, id: u32) -> Option<&Arc<MemTable>> {
+        match id {
+            DEFAULT_COLUMN_FAMILY => Some(&self.mem),
+            id => self
+                .column_families
+                .get(&id)
+                .map(|column_family| &column_family.mem),
+        }
+    }
+
+    /// Return the memtables of column family "id", newest first, and its
+    /// current version.
+    /// REQUIRES: the column family exists
+    fn column_family_sources(&self, id: u32) -> (Vec<&Arc<MemTable>>, Arc<Version>) {
+        match id {
+            DEFAULT_COLUMN_FAMILY => (
+                std::iter::once(&self.mem)
+                    .chain(self.imm.as_ref())
+                    .collect(),
+                self.versions.current(),
+            ),
+            id => (
+                vec![&self.column_families[&id].mem],
+                self.versions.current_cf(id),
+            ),
+        }
+    }
+
+    /// Pin the memtables and the current version of column family "id",
+    /// for a read with "config" that runs without the lock.
+    /// REQUIRES: the column family exists
+    fn read_sources(&self, id: u32, config: &ReadConfig) -> ReadSources {
+        let (memtables, current) = self.column_family_sources(id);
+        ReadSources {
+            memtables: memtables.into_iter().cloned().collect(),
+            current,
+            sequence: self.read_sequence(config),
+        }
+    }
+
+    /// Publish the memtables and the current versions of the column
+    /// families, the background error and the last sequence number for
+    /// the reads, which don't take the lock.  Called whenever one of them
+    /// changes.
+    fn publish(&self) {
+        let column_families = std::iter::once(DEFAULT_COLUMN_FAMILY)
+            .chain(self.column_families.keys().copied())
+            .map(|id| {
+                let (memtables, current) = self.column_family_sources(id);
+                (id, (memtables.into_iter().cloned().collect(), current))
+            })
+            .collect();
+        *self.read_state.write().unwrap() = ReadState {
+            column_families,
+            bg_error: self.bg_error.clone(),
+        };
+        self.last_sequence
+            .store(self.versions.last_sequence(), atomic::Ordering::Release);
+    }
+
+    /// Fail with an error that satisfies Error::is_invalid_argument() if
+    /// the column family of "column_family" was dropped.
+    fn check_column_family(&self, column_family: &ColumnFamilyHandle) -> Result<()> {
+        let id = column_family.id();
+        if id != DEFAULT_COLUMN_FAMILY && !self.column_families.contains_key(&id) {
+            return Err(Error::invalid_argument(&format!(
+                "column family {} does not exist",
+                column_family.name()
+            )));
+        }
+        Ok(())
+    }
+
+    fn create_column_family(&mut self, name: &str) -> Result<ColumnFamilyHandle> {
+        self.check_writable()?;
+        self.background_status()?;
+        if self.column_family(name).is_some() {
+            return Err(Error::invalid_argument(&format!(
+                "column family {} exists",
+                name
+            )));
+        }
+        let handle = ColumnFamilyHandle::new(self.versions.max_column_family() + 1, name);
+        let mut edit = column_family_edit(handle.id());
+        edit.add_column_family(name);
+        // None of its entries are in the logs before the current one
+        edit.set_log_number(self.logfile_number);
+        self.versions.log_and_apply(&mut edit)?;
+        self.column_families.insert(
+            handle.id(),
+            ColumnFamilyData {
+                handle: handle.clone(),
+                mem: new_memtable(&self.config, &self.internal_comparator),
+            },
+        );
+        self.publish();
+        Ok(handle)
+    }
+
+    fn drop_column_family(&mut self, column_family: &ColumnFamilyHandle) -> Result<()> {
+        self.check_writable()?;
+        self.background_status()?;
+        if column_family.id() == DEFAULT_COLUMN_FAMILY {
+            return Err(Error::invalid_argument(
+                "the default column family can't be dropped",
+            ));
+        }
+        self.check_column_family(column_family)?;
+        let mut edit = column_family_edit(column_family.id());
+        edit.drop_column_family();
+        self.versions.log_and_apply(&mut edit)?;
+        self.column_families.remove(&column_family.id());
+        self.publish();
+        self.update_write_buffer_usage();
+        self.remove_obsolete_files();
+        Ok(())
+    }
+
+    fn column_family(&self, name: &str) -> Option<ColumnFamilyHandle> {
+        if name == DEFAULT_COLUMN_FAMILY_NAME {
+            return Some(ColumnFamilyHandle::new(DEFAULT_COLUMN_FAMILY, name));
+        }
+        self.column_families
+            .values()
+            .find(|column_family| column_family.handle.name() == name)
+            .map(|column_family| column_family.handle.clone())
+    }
+
+    fn column_families(&self) -> Vec<ColumnFamilyHandle> {
+        std::iter::once(ColumnFamilyHandle::new(
+            DEFAULT_COLUMN_FAMILY,
+            DEFAULT_COLUMN_FAMILY_NAME,
+        ))
+        .chain(
+            self.column_families
+                .values()
+                .map(|column_family| column_family.handle.clone()),
+        )
+        .collect()
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.check_writable()?;
+        self.background_status()?;
+        self.compact_memtable()?;
+        self.maybe_compact();
+        self.background_status()
+    }
+
+    fn checkpoint(&mut self, dir: &str) -> Result<()> {
+        if self.env.file_exists(dir) {
+            return Err(Error::invalid_argument(&format!(
+                "{}: checkpoint directory exists",
+                dir
+            )));
+        }
+        self.flush()?;
+        self.env.create_dir(dir)?;
+        let result = self.write_checkpoint(dir);
+        if result.is_err() {
+            // Leave no partial copy behind
+            for filename in self.env.get_children(dir).unwrap_or_default() {
+                if let Some(filename) = filename.to_str() {
+                    let _ = self.env.remove_file(&format!("{}/{}", dir, filename));
+                }
+            }
+            let _ = self.env.remove_dir(dir);
+        }
+        result
+    }
+
+    fn write_checkpoint(&self, dir: &str) -> Result<()> {
+        let versions = self.versions.current_versions();
+        let extension = self.config.table_file_extension;
+        for file in versions
+            .iter()
+            .flat_map(|current| (0..NUM_LEVELS).flat_map(|level| current.files(level)))
+        {
+            let number = file.number;
+            let mut fname = table_file_name_for(&self.dbname, number, extension);
+            if !self.env.file_exists(&fname) {
+                fname = table_file_name_for(&self.dbname, number, extension.alternate());
+            }
+            let target = format!("{}{}", dir, &fname[self.dbname.len()..]);
+            if self.env.link_file(&fname, &target).is_err() {
+                copy_file(self.env.as_ref(), &fname, &target, WriteHint::TableCold)?;
+            }
+        }
+
+        // The MANIFEST is still appended to, so it is copied as it is now
+        let manifest_number = self.versions.manifest_file_number();
+        copy_file(
+            self.env.as_ref(),
+            &descriptor_file_name(&self.dbname, manifest_number),
+            &descriptor_file_name(dir, manifest_number),
+            WriteHint::Manifest,
+        )?;
+        set_current_file(self.env.clone(), dir, manifest_number)?;
+        self.env.sync_dir(dir)
+    }
+
+    fn ingest_external_file(&mut self, paths: &[&str], config: &IngestConfig) -> Result<()> {
+        self.check_writable()?;
+        self.background_status()?;
+        let user_comparator = self.internal_comparator.user_comparator().clone();
+        let mut files = paths
+            .iter()
+            .map(|path| {
+                ExternalFile::open(
+                    self.env.as_ref(),
+                    &self.config,
+                    user_comparator.as_ref(),
+                    path,
+                )
+            })
+            .collect::<Result<Vec<_>>>()?;
+        files.sort_by(|a, b| user_comparator.compare(&a.smallest, &b.smallest));
+        if files.windows(2).any(|pair| {
+            user_comparator.compare(&pair[0].largest, &pair[1].smallest) != Ordering::Less
+        }) {
+            return Err(Error::invalid_argument("external files overlap"));
+        }
+        if files.iter().any(|file| {
+            std::iter::once(&self.mem)
+                .chain(self.imm.as_ref())
+                .any(|mem| mem.overlaps(&file.smallest, &file.largest))
+        }) {
+            self.flush()?;
+        }
+
+        let sequence = self.versions.last_sequence() + 1;
+        let read_config = ReadConfig::default()
+            .verify_checksums(config.verify_checksums)
+            .fill_cache(false);
+        let current = self.versions.current();
+        let mut edit = VersionEdit::new();
+        let mut numbers = vec![];
+        let mut result = Ok(());
+        for file in &files {
+            let mut meta = FileMetaData {
+                number: self.versions.new_file_number(),
+                ..Default::default()
+            };
+            self.pending_outputs.insert(meta.number);
+            numbers.push(meta.number);
+            let mut entries = IngestedEntries::new(
+                file.table.new_iterator(&read_config),
+                user_comparator.clone(),
+                sequence,
+            );
+            result = build_table(
+                &self.dbname,
+                self.env.as_ref(),
+                &self.config,
+                &self.table_cache,
+                &mut entries,
+                &ingested_range_tombstones(file, sequence),
+                &mut meta,
+            );
+            if result.is_err() {
+                break;
+            }
+            self.notify_table_file_created(0, &meta, TableFileCreationReason::Ingestion);
+
+            // Above the first level that holds keys in its range
+            let level = (0..NUM_LEVELS)
+                .find(|&level| {
+                    !current
+                        .get_overlapping_inputs(
+                            &self.internal_comparator,
+                            level,
+                            Some(&meta.smallest),
+                            Some(&meta.largest),
+                        )
+                        .is_empty()
+                })
+                .map_or(NUM_LEVELS - 1, |level| level.saturating_sub(1));
+            edit.add_file(
+                level,
+                meta.number,
+                meta.file_size,
+                meta.smallest.clone(),
+                meta.largest.clone(),
+            );
+        }
+        drop(current);
+        drop(files);
+        if result.is_ok() {
+            self.versions.set_last_sequence(sequence);
+            result = self.versions.log_and_apply(&mut edit);
+            self.publish();
+        }
+        for number in numbers {
+            self.pending_outputs.remove(&number);
+        }
+        if result.is_err() {
+            // Drop the tables built so far
+            self.remove_obsolete_files();
+            return result;
+        }
+
+        if config.move_files {
+            for path in paths {
+                let _ = self.env.remove_file(path);
+            }
+        }
+        self.maybe_compact();
+        self.background_status()
+    }
+
+    fn delete_files_in_range(&mut self, range: &Range) -> Result<()> {
+        self.check_writable()?;
+        self.background_status()?;
+        let ucmp = self.internal_comparator.user_comparator();
+        let mut edit = VersionEdit::new();
+        let mut deleted = 0;
+        let current = self.versions.current();
+        for level in 0..NUM_LEVELS {
+            for file in current.files(level) {
+                if ucmp.compare(file.smallest.user_key(), range.start) != Ordering::Less
+                    && ucmp.compare(file.largest.user_key(), range.limit) == Ordering::Less
+                {
+                    edit.remove_file(level, file.number);
+                    deleted += 1;
+                }
+            }
+        }
+        drop(current);
+        if deleted == 0 {
+            return Ok(());
+        }
+
+        let result = self.versions.log_and_apply(&mut edit);
+        self.publish();
+        let job_id = self.new_job_id();
+        self.log_event("files_deleted_in_range", |event| {
+            event
+                .add_u64("job", job_id)
+                .add_u64("files", deleted)
+                .add_status(&result);
+        });
+        result?;
+        self.remove_obsolete_files();
+        Ok(())
+    }
+
+    fn get_snapshot(&mut self) -> Arc<Snapshot> {
+        Arc::new(self.snapshots.new_snapshot(self.versions.last_sequence()))
+    }
+
+    fn release_snapshot(&mut self, snapshot: Arc<Snapshot>) {
+        self.snapshots.delete(&snapshot);
+    }
+
+    /// The sequence number reads with "config" observe.
+    fn read_sequence(&self, config: &ReadConfig) -> u64 {
+        match config.snapshot.as_ref() {
+            Some(snapshot) => snapshot.sequence(),
+            None => self.versions.last_sequence(),
+        }
+    }
+
+    fn get_property(&self, property: &str) -> Option<String> {
+        let property = property.strip_prefix("rebeldb.")?;
+        let current = self.versions.current();
+        let parse_level = |level: &str| level.parse().ok().filter(|&level| level < NUM_LEVELS);
+
+        if let Some(level) = property.strip_prefix("num-files-at-level") {
+            Some(current.num_files(parse_level(level)?).to_string())
+        } else if let Some(level) = property.strip_prefix("compaction-score-at-level") {
+            Some(format!("{:.2}", current.level_score(parse_level(level)?)))
+        } else if property == "estimate-pending-compaction-bytes" {
+            Some(current.pending_compaction_bytes().to_string())
+        } else if property == "stats" {
+            let mut value = String::from(
+                "                                     Compactions\n\
+                 Level  Files Size(MB) Score Time(sec) Read(MB) Write(MB)\n\
+                 -------------------------------------------------------\n",
+            );
+            for level in 0..NUM_LEVELS {
+                let files = current.num_files(level);
+                let stats = self.versions.compaction_stats(level);
+                if files > 0 || stats.micros > 0 {
+                    value.push_str(&format!(
+                        "{:3} {:8} {:8.0} {:5.2} {:9.0} {:8.0} {:9.0}\n",
+                        level,
+                        files,
+                        current.level_bytes(level) as f64 / 1048576.0,
+                        current.level_score(level),
+                        stats.micros as f64 / 1e6,
+                        stats.bytes_read as f64 / 1048576.0,
+                        stats.bytes_written as f64 / 1048576.0
+                    ));
+                }
+            }
+            value.push_str(&format!(
+                "Pending compaction bytes: {}\n",
+                current.pending_compaction_bytes()
+            ));
+            Some(value)
+        } else if property == "sstables" {
+            Some(current.debug_string())
+        } else if property == "approximate-memory-usage" {
+            let total_usage = self.table_cache.block_cache_usage()
+                + self.mem.approximate_memory_usage()
+                + self
+                    .imm
+                    .as_ref()
+                    .map_or(0, |imm| imm.approximate_memory_usage());
+            Some(total_usage.to_string())
+        } else if property == "num-immutable-mem-table" {
+            Some(self.imm.iter().count().to_string())
+        } else if property == "num-entries-active-mem-table" {
+            Some(self.mem.num_entries().to_string())
+        } else if property == "total-key-bytes-active-mem-table" {
+            Some(self.mem.total_key_bytes().to_string())
+        } else if property == "total-value-bytes-active-mem-table" {
+            Some(self.mem.total_value_bytes().to_string())
+        } else {
+            None
+        }
+    }
+
+    fn statistics(&self) -> Statistics {
+        let current = self.versions.current();
+        Statistics {
+            levels: (0..NUM_LEVELS)
+                .map(|level| LevelStatistics {
+                    files: current.num_files(level),
+                    bytes: current.level_bytes(level),
+                    score: current.level_score(level),
+                    compaction: self.versions.compaction_stats(level),
+                })
+                .collect(),
+            compaction_level: current.compaction_level(),
+            compaction_score: current.compaction_score(),
+            pending_compaction_bytes: current.pending_compaction_bytes(),
+        }
+    }
+
+    fn clear_background_error(&mut self) {
+        self.bg_error = None;
+        self.publish();
+    }
+
+    /// Remember the first background failure.  Later errors are usually
+    /// consequences of the first one and are dropped, though the listeners
+    /// are told about each of them.
+    fn record_background_error(&mut self, reason: BackgroundErrorReason, error: Error) {
+        self.notify(|listener| {
+            listener.on_background_error(&BackgroundErrorInfo {
+                reason,
+                error: error.clone(),
+            })
+        });
+        if self.bg_error.is_none() {
+            self.bg_error = Some(error);
+            self.publish();
+        }
+    }
+
+    /// Create the initial MANIFEST and CURRENT files of a new database.
+    fn new_db(&self) -> Result<()> {
+        let mut new_db = VersionEdit::new();
+        new_db.set_comparator_name(self.internal_comparator.user_comparator().name());
+        new_db.set_log_number(0);
+        new_db.set_next_file(2);
+        new_db.set_last_sequence(0);
+
+        let manifest = descriptor_file_name(&self.dbname, 1);
+        let mut log = Writer::new(self.env.new_writable_file(&manifest, WriteHint::Manifest)?);
+        let mut result = new_db.write_to(&mut log);
+        if result.is_ok() {
+            result = log.file_mut().sync();
+        }
+        if result.is_ok() {
+            result = log.file_mut().close();
+        }
+        if result.is_ok() {
+            // Make "CURRENT" file that points to the new manifest file.
+            result = set_current_file(self.env.clone(), &self.dbname, 1);
+        }
+        if result.is_ok() && self.config.sync_dir_after_create {
+            result = self.env.sync_dir(&self.dbname);
+        }
+        if result.is_err() {
+            let _ = self.env.remove_file(&manifest);
+        }
+        result
+    }
+
+    /// Create the database directory if needed, recover the last saved
+    /// descriptor and replay the log files it does not cover.  Tables
+    /// written while replaying are recorded in "edits", the edit of each
+    /// column family under its id.
+    /// Load the database state from its files, replaying the logs into
+    /// level-0 tables recorded in "edits".  Log data that is corrupted and
+    /// skipped is added to "dropped".
+    fn recover(
+        &mut self,
+        edits: &mut BTreeMap<u32, VersionEdit>,
+        dropped: &mut Vec<DroppedLogData>,
+    ) -> Result<()> {
+        // Ignore error from create_dir since the creation of the DB is
+        // committed only when the descriptor is created, and this directory
+        // may already exist from a previous failed creation attempt.
+        let _ = self.env.create_dir(&self.dbname);
+        assert!(self.db_lock.is_none());
+        self.db_lock = Some(self.env.lock_file(&lock_file_name(&self.dbname))?);
+
+        if !self.env.file_exists(&current_file_name(&self.dbname)) {
+            if self.config.create_if_missing {
+                self.new_db()?;
+            } else {
+                return Err(Error::invalid_argument(&format!(
+                    "{}: does not exist (create_if_missing is false)",
+                    self.dbname
+                )));
+            }
+        } else if self.config.error_if_exists {
+            return Err(Error::invalid_argument(&format!(
+                "{}: exists (error_if_exists is true)",
+                self.dbname
+            )));
+        }
+
+        self.versions.recover()?;
+        for (id, name, _) in self.versions.column_families() {
+            self.column_families.insert(
+                id,
+                ColumnFamilyData {
+                    handle: ColumnFamilyHandle::new(id, name),
+                    mem: new_memtable(&self.config, &self.internal_comparator),
+                },
+            );
+        }
+
+        // Recover from all newer log files than the ones named in the
+        // descriptor (new log files may have been added by the previous
+        // incarnation without registering them in the descriptor).
+        let min_log = self.versions.log_number();
+        let prev_log = self.versions.prev_log_number();
+        let mut logs = vec![];
+        for filename in self.env.get_children(&self.dbname)? {
+            if let Some((number, FileType::LogFile)) = filename.to_str().and_then(parse_file_name) {
+                if number >= min_log || number == prev_log {
+                    logs.push(number);
+                }
+            }
+        }
+
+        // Recover in the order in which the logs were generated
+        logs.sort_unstable();
+        let mut max_sequence = 0;
+        for log_number in logs {
+            self.recover_log_file(log_number, edits, &mut max_sequence, dropped)?;
+
+            // The previous incarnation may not have written any MANIFEST
+            // records after allocating this log number.  So we manually
+            // update the file number allocation counter in VersionSet.
+            self.versions.mark_file_number_used(log_number);
+        }
+
+        if self.versions.last_sequence() < max_sequence {
+            self.versions.set_last_sequence(max_sequence);
+        }
+        Ok(())
+    }
+
+    /// Replay the updates in log file "log_number", writing them out to
+    /// level-0 tables recorded in "edits".  The updates of column families
+    /// that have them in tables already, or were dropped, are skipped.
+    /// Unless paranoid_checks is set, corrupted records are skipped and
+    /// added to "dropped".
+    fn recover_log_file(
+        &mut self,
+        log_number: u64,
+        edits: &mut BTreeMap<u32, VersionEdit>,
+        max_sequence: &mut u64,
+        dropped: &mut Vec<DroppedLogData>,
+    ) -> Result<()> {
+        // Open the log file
+        let fname = log_file_name(&self.dbname, log_number);
+        let file = self.env.new_sequential_file(&fname)?;
+
+        // We intentionally make log::Reader do checksumming even if
+        // verify_checksums is false so that corruptions cause entire
+        // commits to be skipped instead of propagating bad information
+        // (like overly large sequence numbers).
+        let reporter = Rc::new(RefCell::new(LogReporter {
+            fname: fname.clone(),
+            paranoid: self.config.paranoid_checks,
+            status: None,
+            dropped: vec![],
+        }));
+        let mut reader = Reader::new(file, 0, true, Some(reporter.clone()));
+
+        // The column families whose updates in the log are not in tables
+        let replayed: BTreeSet<_> = self
+            .versions
+            .column_families()
+            .into_iter()
+            .filter(|&(_, _, number)| number <= log_number)
+            .map(|(id, ..)| id)
+            .chain(std::iter::once(DEFAULT_COLUMN_FAMILY))
+            .collect();
+
+        // Read all the records and add to a memtable per column family
+        let mut batch = WriteBatch::new();
+        let mut mems = BTreeMap::new();
+        while let Some(record) = reader.read_record() {
+            if record.len() < 12 {
+                reporter.borrow_mut().corruption(
+                    record.len(),
+                    &Error::corruption(&format!("{}: log record too small", fname)),
+                );
+            } else {
+                batch.set_contents(record);
+                for id in batch
+                    .column_families()
+                    .into_iter()
+                    .chain(std::iter::once(DEFAULT_COLUMN_FAMILY))
+                    .filter(|id| replayed.contains(id))
+                {
+                    mems.entry(id)
+                        .or_insert_with(|| new_memtable(&self.config, &self.internal_comparator));
+                }
+                batch.insert_into_column_families(&|id| mems.get(&id).map(Arc::as_ref))?;
+                let last_sequence = batch.sequence() + batch.count() as u64 - 1;
+                *max_sequence = (*max_sequence).max(last_sequence);
+
+                let full: Vec<_> = mems
+                    .iter()
+                    .filter(|(_, mem)| {
+                        mem.approximate_memory_usage() > self.config.write_buffer_size
+                    })
+                    .map(|(&id, _)| id)
+                    .collect();
+                for id in full {
+                    let table = mems.remove(&id).unwrap();
+                    let edit = edits.entry(id).or_insert_with(|| column_family_edit(id));
+                    self.write_level0_table(&table, edit, 0, TableFileCreationReason::Recovery)?;
+                }
+            }
+
+            if let Some(error) = reporter.borrow_mut().status.take() {
+                return Err(error);
+            }
+        }
+
+        // A corruption at the end of the log stops the loop above before
+        // it can be checked
+        let mut reporter = reporter.borrow_mut();
+        if let Some(error) = reporter.status.take() {
+            return Err(error);
+        }
+        dropped.append(&mut reporter.dropped);
+        for (id, table) in mems {
+            let edit = edits.entry(id).or_insert_with(|| column_family_edit(id));
+            self.write_level0_table(&table, edit, 0, TableFileCreationReason::Recovery)?;
+        }
+        Ok(())
+    }
+
+    /// Write the contents of "mem" to a new level-0 table and record it
+    /// in "edit".  The table is written by job "job_id" for "reason".
+    fn write_level0_table(
+        &mut self,
+        mem: &Arc<MemTable>,
+        edit: &mut VersionEdit,
+        job_id: u64,
+        reason: TableFileCreationReason,
+    ) -> Result<()> {
+        let job = self.new_flush_job(mem.clone());
+        let number = job.number;
+        let output = job.run();
+        self.pending_outputs.remove(&number);
+        output.add_to(edit);
+        if output.result.is_ok() && output.meta.file_size > 0 {
+            self.notify_table_file_created(job_id, &output.meta, reason);
+        }
+        output.result
+    }
+
+    fn new_flush_job(&mut self, mem: Arc<MemTable>) -> FlushJob {
+        let number = self.versions.new_file_number();
+        self.pending_outputs.insert(number);
+        FlushJob {
+            dbname: self.dbname.clone(),
+            env: self.env.clone(),
+            config: self.config.clone(),
+            table_cache: self.table_cache.clone(),
+            operations: self.operations.clone(),
+            mem,
+            number,
+        }
+    }
+
+    /// Return the memtables that take writes: the memtable of the
+    /// default column family, and those of the others.
+    fn memtables(&self) -> impl std::iter::Iterator<Item = &Arc<MemTable>> {
+        std::iter::once(&self.mem).chain(
+            self.column_families
+                .values()
+                .map(|column_family| &column_family.mem),
+        )
+    }
+
+    /// Whether the memtables have to be flushed before taking more
+    /// writes.  An empty memtable always takes them, however small the
+    /// write buffer.
+    fn memtable_is_full(&self) -> bool {
+        let over_budget = self
+            .config
+            .write_buffer_manager
+            .as_ref()
+            .is_some_and(|manager| manager.should_flush());
+        self.memtables().any(|mem| {
+            !mem.is_empty()
+                && (mem.approximate_memory_usage() > self.config.write_buffer_size || over_budget)
+        })
+    }
+
+    /// Write the memtables out as level-0 tables, and wait until they are
+    /// written.  On failure the memtables and logs are kept, so no data is
+    /// lost, and the error is recorded as the background error.
+    fn compact_memtable(&mut self) -> Result<()> {
+        self.finish_flush(true)?;
+        if self.imm.is_some() {
+            // Left behind by a failed flush
+            self.schedule_flush();
+            self.finish_flush(true)?;
+        }
+        if self.memtables().any(|mem| !mem.is_empty()) {
+            self.switch_memtable()?;
+            self.finish_flush(true)?;
+        }
+        Ok(())
+    }
+
+    /// Make the current memtable immutable and start flushing it, with
+    /// new writes going to a new memtable and log file.  The memtables of
+    /// the other column families are written out right away.
+    /// REQUIRES: there is no immutable memtable.
+    fn switch_memtable(&mut self) -> Result<()> {
+        debug_assert!(self.imm.is_none());
+        let new_log_number = self.versions.new_file_number();
+        if let Err(error) = self.flush_column_families(new_log_number) {
+            self.record_background_error(BackgroundErrorReason::Flush, error.clone());
+            return Err(error);
+        }
+        let log_fname = log_file_name(&self.dbname, new_log_number);
+        let file = match self.env.new_writable_file(&log_fname, WriteHint::Wal) {
+            Ok(file) => file,
+            Err(error) => {
+                // Avoid chewing through file number space in a tight loop.
+                self.versions.reuse_file_number(new_log_number);
+                self.record_background_error(BackgroundErrorReason::WriteLog, error.clone());
+                return Err(error);
+            }
+        };
+        let mut log = Writer::new(file);
+        log.set_compression(self.config.compress_wal);
+        self.log = Some(log);
+        self.logfile_number = new_log_number;
+        self.notify_wal_file_created();
+        let mem = new_memtable(&self.config, &self.internal_comparator);
+        self.imm = Some(mem::replace(&mut self.mem, mem));
+        self.publish();
+        self.schedule_flush();
+        Ok(())
+    }
+
+    /// Write the memtables of the column families other than the default
+    /// one out to level-0 tables, with new writes going to new memtables,
+    /// and record that none of their entries in the logs before
+    /// "log_number" are needed any more.  A column family with an empty
+    /// memtable has no entries in the current log, and those in older
+    /// logs are in tables already.
+    fn flush_column_families(&mut self, log_number: u64) -> Result<()> {
+        let ids: Vec<_> = self.column_families.keys().copied().collect();
+        for id in ids {
+            let mem = self.column_families[&id].mem.clone();
+            if mem.is_empty() {
+                continue;
+            }
+            let job_id = self.new_job_id();
+            let start = Instant::now();
+            let mut edit = column_family_edit(id);
+            edit.set_log_number(log_number);
+            let mut result =
+                self.write_level0_table(&mem, &mut edit, job_id, TableFileCreationReason::Flush);
+            if result.is_ok() {
+                result = self.versions.log_and_apply(&mut edit);
+            }
+            let output = edit.new_files().first().map(|(_, meta)| meta);
+            self.notify(|listener| {
+                listener.on_flush_completed(&FlushJobInfo {
+                    job_id,
+                    column_family: self.column_family_name(id),
+                    file_number: output.map(|meta| meta.number),
+                    file_size: output.map_or(0, |meta| meta.file_size),
+                    num_entries: mem.num_entries(),
+                    duration: start.elapsed(),
+                    status: result.clone(),
+                })
+            });
+            result?;
+            self.column_families.get_mut(&id).unwrap().mem =
+                new_memtable(&self.config, &self.internal_comparator);
+        }
+        self.update_write_buffer_usage();
+        Ok(())
+    }
+
+    /// Start flushing the immutable memtable on the background thread of
+    /// the Env.  Its log stays until the flush is installed.
+    fn schedule_flush(&mut self) {
+        let imm = self.imm.clone().unwrap();
+        let job_id = self.new_job_id();
+        self.log_event("flush_started", |event| {
+            event
+                .add_u64("job", job_id)
+                .add_u64("memtable_bytes", imm.approximate_memory_usage() as u64);
+        });
+        let job = self.new_flush_job(imm);
+        let number = job.number;
+        let job = Arc::new(Mutex::new(Some(job)));
+        let (sender, output) = mpsc::channel();
+        let pending = job.clone();
+        self.env.schedule(Box::new(move || {
+            let job = pending.lock().unwrap().take();
+            if let Some(job) = job {
+                // The DB may be gone, with nothing left to install the table
+                let _ = sender.send(job.run());
+            }
+        }));
+        self.flush = Some(PendingFlush {
+            job_id,
+            number,
+            job,
+            output,
+        });
+    }
+
+    /// Install the table written by the flush of the immutable memtable
+    /// once it is done, waiting for it if "wait".  Returns whether a
+    /// flush was installed.  On failure the immutable memtable is kept,
+    /// and the error is recorded as the background error.
+    fn finish_flush(&mut self, wait: bool) -> Result<bool> {
+        let output = match self.flush.as_ref() {
+            None => return Ok(false),
+            Some(flush) if wait => {
+                // Run the job here if no thread has started it yet, as the
+                // threads may all be busy with compactions waiting for the
+                // lock held here
+                let job = flush.job.lock().unwrap().take();
+                match job {
+                    Some(job) => Some(job.run()),
+                    None => flush.output.recv().ok(),
+                }
+            }
+            Some(flush) => match flush.output.try_recv() {
+                Ok(output) => Some(output),
+                Err(TryRecvError::Empty) => return Ok(false),
+                Err(TryRecvError::Disconnected) => None,
+            },
+        };
+        let flush = self.flush.take().unwrap();
+        self.pending_outputs.remove(&flush.number);
+        let output = output.unwrap_or_else(|| FlushOutput {
+            result: Err(Error::io_error("flush job ended without a result")),
+            meta: FileMetaData::default(),
+            micros: 0,
+        });
+
+        // Replace the immutable memtable with the generated Table
+        let mut edit = VersionEdit::new();
+        output.add_to(&mut edit);
+        let mut result = output.result.clone();
+        if result.is_ok() {
+            edit.set_prev_log_number(0);
+            edit.set_log_number(self.logfile_number); // Earlier logs no longer needed
+            result = self.versions.log_and_apply(&mut edit);
+        }
+        self.log_event("flush_finished", |event| {
+            let outputs = edit.new_files();
+            event
+                .add_u64("job", flush.job_id)
+                .add_array("output_files", outputs.iter().map(|(_, meta)| meta.number))
+                .add_u64(
+                    "output_bytes",
+                    outputs.iter().map(|(_, meta)| meta.file_size).sum(),
+                )
+                .add_u64("duration_micros", output.micros)
+                .add_status(&result);
+        });
+        if result.is_ok() && output.meta.file_size > 0 {
+            self.notify_table_file_created(
+                flush.job_id,
+                &output.meta,
+                TableFileCreationReason::Flush,
+            );
+        }
+        self.notify(|listener| {
+            listener.on_flush_completed(&FlushJobInfo {
+                job_id: flush.job_id,
+                column_family: DEFAULT_COLUMN_FAMILY_NAME.to_string(),
+                file_number: (output.meta.file_size > 0).then_some(output.meta.number),
+                file_size: output.meta.file_size,
+                num_entries: self.imm.as_ref().map_or(0, |imm| imm.num_entries()),
+                duration: Duration::from_micros(output.micros),
+                status: result.clone(),
+            })
+        });
+
+        match result {
+            Ok(()) => {
+                self.imm = None;
+                self.publish();
+                self.update_write_buffer_usage();
+                self.remove_obsolete_files();
+                Ok(true)
+            }
+            Err(error) => {
+                if output.meta.file_size > 0 {
+                    let _ = self.env.remove_file(&table_file_name_for(
+                        &self.dbname,
+                        output.meta.number,
+                        self.config.table_file_extension,
+                    ));
+                }
+                self.record_background_error(BackgroundErrorReason::Flush, error.clone());
+                Err(error)
+            }
+        }
+    }
+
+    /// Bring the memory reserved in the write buffer manager in line with
+    /// the current memtables.
+    fn update_write_buffer_usage(&mut self) {
+        if let Some(manager) = self.config.write_buffer_manager.as_ref() {
+            let usage = self
+                .memtables()
+                .map(|mem| mem.approximate_memory_usage())
+                .sum::<usize>()
+                + self
+                    .imm
+                    .as_ref()
+                    .map_or(0, |imm| imm.approximate_memory_usage());
+            if usage > self.mem_reserved {
+                manager.reserve(usage - self.mem_reserved);
+            } else {
+                manager.free(self.mem_reserved - usage);
+            }
+            self.mem_reserved = usage;
+        }
+    }
+
+    /// Schedule a compaction on the background threads of the Env if a
+    /// level needs one and no compaction is running.  Once it is done,
+    /// the next one is scheduled the same way, until no level needs one
+    /// or one of them fails.
+    fn maybe_compact(&mut self) {
+        if self.compacting
+            || self.shutting_down
+            || self.read_only
+            || self.bg_error.is_some()
+            || !self.needs_compaction()
+        {
+            return;
+        }
+        if let Some(shared) = self.shared.upgrade() {
+            self.compacting = true;
+            self.env
+                .schedule(Box::new(move || shared.background_compaction()));
+        }
+    }
+
+    /// Whether a level needs a compaction, or level-0 of the default
+    /// column family has stopped the writes.
+    fn needs_compaction(&self) -> bool {
+        self.versions.needs_compaction() || self.writes_stopped()
+    }
+
+    /// Pick the compaction that a level needs most, or one of level-0 of
+    /// the default column family if it has stopped the writes, which may
+    /// happen below L0_COMPACTION_TRIGGER.
+    fn pick_compaction(&mut self) -> Option<Compaction> {
+        match self.versions.pick_compaction() {
+            None if self.writes_stopped() => {
+                self.versions
+                    .compact_range(DEFAULT_COLUMN_FAMILY, 0, None, None)
+            }
+            c => c,
+        }
+    }
+
+    /// Whether level-0 of the default column family has enough files to
+    /// stop the writes.
+    fn writes_stopped(&self) -> bool {
+        self.versions.num_level_files(0) >= self.config.l0_stop_writes_trigger
+    }
+
+    /// Run compaction "c", a trivial move, by moving its file to the next
+    /// level.
+    fn move_file(&mut self, mut c: Compaction) -> Result<()> {
+        let start = Instant::now();
+        assert_eq!(1, c.num_input_files(0));
+        let file = c.input(0, 0).clone();
+        let level = c.level();
+        c.edit().remove_file(level, file.number);
+        c.edit().add_file(
+            level + 1,
+            file.number,
+            file.file_size,
+            file.smallest.clone(),
+            file.largest.clone(),
+        );
+        let result = self.versions.log_and_apply(c.edit());
+        self.publish();
+        let job_id = self.new_job_id();
+        self.log_event("trivial_move", |event| {
+            event
+                .add_u64("job", job_id)
+                .add_u64("level", level as u64)
+                .add_u64("file_number", file.number)
+                .add_u64("file_size", file.file_size)
+                .add_status(&result);
+        });
+        self.notify(|listener| {
+            listener.on_compaction_completed(&CompactionJobInfo {
+                job_id,
+                column_family: self.column_family_name(c.column_family()),
+                level,
+                output_level: level + 1,
+                input_files: vec![file.number],
+                output_files: vec![file.number],
+                input_bytes: file.file_size,
+                output_bytes: file.file_size,
+                is_manual: false,
+                is_trivial_move: true,
+                duration: start.elapsed(),
+                status: result.clone(),
+            })
+        });
+        result
+    }
+
+    /// Prepare compaction "c", which was picked by the user if
+    /// "is_manual", to write its outputs without the lock.
+    fn start_compaction(
+        &mut self,
+        c: Compaction,
+        is_manual: bool,
+    ) -> (CompactionJob, CompactionState) {
+        let start = Instant::now();
+        let job_id = self.new_job_id();
+        let inputs: [Vec<Arc<FileMetaData>>; 2] = [0, 1].map(|which| {
+            (0..c.num_input_files(which))
+                .map(|i| c.input(which, i).clone())
+                .collect()
+        });
+        self.log_event("compaction_started", |event| {
+            event
+                .add_u64("job", job_id)
+                .add_u64("level", c.level() as u64)
+                .add_u64("output_level", c.level() as u64 + 1)
+                .add_array("input_files", inputs[0].iter().map(|file| file.number))
+                .add_array(
+                    "output_level_input_files",
+                    inputs[1].iter().map(|file| file.number),
+                )
+                .add_u64(
+                    "input_bytes",
+                    inputs.iter().flatten().map(|file| file.file_size).sum(),
+                );
+        });
+        let smallest_snapshot = self
+            .snapshots
+            .oldest()
+            .unwrap_or(self.versions.last_sequence());
+        let job = CompactionJob {
+            job_id,
+            is_manual,
+            start,
+            inputs,
+            dbname: self.dbname.clone(),
+            env: self.env.clone(),
+            config: self.config.clone(),
+            internal_comparator: self.internal_comparator.clone(),
+            table_cache: self.table_cache.clone(),
+            operations: self.operations.clone(),
+        };
+        let compact = CompactionState::new(c, smallest_snapshot, self.snapshots.newest());
+        (job, compact)
+    }
+
+    /// Install the outputs "job" wrote with "result", if it succeeded,
+    /// and report the compaction.
+    fn finish_compaction(
+        &mut self,
+        job: &CompactionJob,
+        mut compact: CompactionState,
+        mut result: Result<()>,
+    ) -> Result<()> {
+        if result.is_ok() {
+            result = self.install_compaction_results(&mut compact);
+            self.publish();
+        }
+        if let Some(mut builder) = compact.builder.take() {
+            // May happen if we get an error
+            builder.abandon();
+        }
+        for output in &compact.outputs {
+            self.pending_outputs.remove(&output.number);
+        }
+        let job_id = job.job_id;
+        let inputs = &job.inputs;
+        self.log_event("compaction_finished", |event| {
+            event
+                .add_u64("job", job_id)
+                .add_array(
+                    "output_files",
+                    compact.outputs.iter().map(|output| output.number),
+                )
+                .add_u64("output_bytes", compact.total_bytes)
+                .add_u64("duration_micros", job.start.elapsed().as_micros() as u64)
+                .add_status(&result);
+        });
+        // Outputs cut short by an error were never finished
+        let outputs: Vec<_> = compact
+            .outputs
+            .iter()
+            .filter(|output| output.file_size > 0)
+            .collect();
+        for output in &outputs {
+            self.notify_table_file_created(job_id, output, TableFileCreationReason::Compaction);
+        }
+        self.notify(|listener| {
+            listener.on_compaction_completed(&CompactionJobInfo {
+                job_id,
+                column_family: self.column_family_name(compact.compaction.column_family()),
+                level: compact.compaction.level(),
+                output_level: compact.compaction.level() + 1,
+                input_files: inputs.iter().flatten().map(|file| file.number).collect(),
+                output_files: outputs.iter().map(|output| output.number).collect(),
+                input_bytes: inputs.iter().flatten().map(|file| file.file_size).sum(),
+                output_bytes: compact.total_bytes,
+                is_manual: job.is_manual,
+                is_trivial_move: false,
+                duration: job.start.elapsed(),
+                status: result.clone(),
+            })
+        });
+        // Release the input version, so that the inputs can be removed
+        // along with the outputs of a failed compaction
+        drop(compact);
+        self.remove_obsolete_files();
+        result
+    }
+
+    fn install_compaction_results(&mut self, compact: &mut CompactionState) -> Result<()> {
+        // Add compaction outputs
+        compact.compaction.add_input_deletions();
+        let level = compact.compaction.level();
+        for output in &compact.outputs {
+            compact.compaction.edit().add_file(
+                level + 1,
+                output.number,
+                output.file_size,
+                output.smallest.clone(),
+                output.largest.clone(),
+            );
+        }
+        self.versions.log_and_apply(compact.compaction.edit())
+    }
+
+    fn new_job_id(&mut self) -> u64 {
+        self.next_job_id += 1;
+        self.next_job_id - 1
+    }
+
+    /// Append "event" to the event log, if there is one, followed by the
+    /// current shape of the LSM tree.
+    fn log_event(&self, event: &str, add_fields: impl FnOnce(&mut EventBuilder<'_>)) {
+        if let Some(logger) = self.event_logger.as_ref() {
+            let mut builder = logger.event(event);
+            add_fields(&mut builder);
+            let current = self.versions.current();
+            builder
+                .add_array(
+                    "lsm_files",
+                    (0..NUM_LEVELS).map(|level| current.num_files(level) as u64),
+                )
+                .add_array(
+                    "lsm_bytes",
+                    (0..NUM_LEVELS).map(|level| current.level_bytes(level)),
+                );
+        }
+    }
+
+    /// Pass an event to each of the listeners of the database, in the
+    /// order they were added.
+    fn notify(&self, event: impl Fn(&dyn EventListener)) {
+        for listener in &self.config.listeners {
+            event(listener.as_ref());
+        }
+    }
+
+    fn notify_table_file_created(
+        &self,
+        job_id: u64,
+        meta: &FileMetaData,
+        reason: TableFileCreationReason,
+    ) {
+        self.notify(|listener| {
+            listener.on_table_file_created(&TableFileCreationInfo {
+                job_id,
+                file_path: table_file_name_for(
+                    &self.dbname,
+                    meta.number,
+                    self.config.table_file_extension,
+                ),
+                file_number: meta.number,
+                file_size: meta.file_size,
+                reason,
+            })
+        });
+    }
+
+    fn notify_wal_file_created(&self) {
+        self.notify(|listener| {
+            listener.on_wal_file_created(&WalFileInfo {
+                file_path: log_file_name(&self.dbname, self.logfile_number),
+                log_number: self.logfile_number,
+            })
+        });
+    }
+
+    fn column_family_name(&self, id: u32) -> String {
+        match self.column_families.get(&id) {
+            Some(cf) => cf.handle.name().to_string(),
+            None => DEFAULT_COLUMN_FAMILY_NAME.to_string(),
+        }
+    }
+
+    /// Delete any unneeded files and stale in-memory entries.
+    fn remove_obsolete_files(&mut self) {
+        // Make a set of all of the live files
+        let mut live = self.pending_outputs.clone();
+        self.versions.add_live_files(&mut live);
+        // Files numbered from here on were created after the set was
+        // made, and can't be told apart from live ones
+        let min_unknown_number = self.versions.next_file_number();
+
+        // Ignoring errors on purpose
+        let filenames = match self.env.get_children(&self.dbname) {
+            Ok(filenames) => filenames,
+            Err(_) => return,
+        };
+        for filename in filenames {
+            let filename = match filename.to_str() {
+                Some(filename) => filename,
+                None => continue,
+            };
+            let (number, type_) = match parse_file_name(filename) {
+                Some(parsed) => parsed,
+                None => continue,
+            };
+            let keep = match type_ {
+                FileType::LogFile => {
+                    number >= self.versions.log_number()
+                        || number == self.versions.prev_log_number()
+                }
+                // Keep my manifest file, and any newer incarnations'
+                // (in case there is a race that allows other incarnations)
+                FileType::DescriptorFile => number >= self.versions.manifest_file_number(),
+                FileType::TableFile | FileType::TempFile => {
+                    live.contains(&number) || number >= min_unknown_number
+                }
+                FileType::CurrentFile | FileType::DBLockFile | FileType::InfoLogFile => true,
+            };
+
+            if !keep {
+                if type_ == FileType::TableFile {
+                    self.table_cache.evict(number);
+                }
+                let file_path = format!("{}/{}", self.dbname, filename);
+                let status = self.env.remove_file(&file_path);
+                match type_ {
+                    FileType::TableFile => self.notify(|listener| {
+                        listener.on_table_file_deleted(&TableFileDeletionInfo {
+                            file_path: file_path.clone(),
+                            file_number: number,
+                            status: status.clone(),
+                        })
+                    }),
+                    FileType::LogFile if status.is_ok() => self.notify(|listener| {
+                        listener.on_wal_file_deleted(&WalFileInfo {
+                            file_path: file_path.clone(),
+                            log_number: number,
+                        })
+                    }),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    fn background_status(&self) -> Result<()> {
+        match &self.bg_error {
+            Some(error) => Err(error.clone()),
+            None => Ok(()),
+        }
+    }
+
+    /// Finish the flush, if any, and give back what the DB holds.  Does
+    /// nothing the second time.
+    fn close(&mut self) {
+        // Do not leave the flush writing to a database that may be opened
+        // again, or destroyed
+        let _ = self.finish_flush(true);
+        if let Some(manager) = self.config.write_buffer_manager.as_ref() {
+            manager.free(self.mem_reserved);
+            self.mem_reserved = 0;
+        }
+        if let Some(lock) = self.db_lock.take() {
+            let _ = self.env.unlock_file(lock);
+        }
+    }
+}
+
+impl Drop for DBInner {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// Destroy the contents of the specified database.
+/// Be very careful using this method.
+///
+/// Only the files the database owns, as recognized by their names, are
+/// removed; the directory itself goes too once it is empty.
+pub fn destroy_db(dbname: &str, config: &DBConfig) -> Result<()> {
+    let env = config.env.clone();
+    let filenames = match env.get_children(dbname) {
+        Ok(filenames) => filenames,
+        // Ignore error in case directory does not exist
+        Err(_) => return Ok(()),
+    };
+
+    let lockname = lock_file_name(dbname);
+    let lock = env.lock_file(&lockname)?;
+    let mut result = Ok(());
+    for filename in filenames {
+        let filename = match filename.to_str() {
+            Some(filename) => filename,
+            None => continue,
+        };
+        match parse_file_name(filename) {
+            // Lock file will be deleted at end
+            Some((_, FileType::DBLockFile)) | None => {}
+            Some(_) => {
+                let del = env.remove_file(&format!("{}/{}", dbname, filename));
+                if result.is_ok() {
+                    result = del;
+                }
+            }
+        }
+    }
+    let _ = env.unlock_file(lock); // Ignore error since state is already gone
+    let _ = env.remove_file(&lockname);
+    let _ = env.remove_dir(dbname); // Ignore error in case dir contains other files
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{BTreeMap, HashSet},
+        ffi::OsString,
+        sync::{
+            atomic::{self, AtomicBool},
+            Mutex,
+        },
+        thread,
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    };
+
+    use super::*;
+    use crate::{
+        dbformat::L0_COMPACTION_TRIGGER,
+        env::{
+            read_file_to_vec, test_dir, write_data_to_file, FaultInjectionEnv, FileLock, Logger,
+            MemEnv, PosixEnv, RandomAccessFile, SequentialFile,
+        },
+        iterator::IterAdapter,
+        util::{
+            CompactionFilter, Comparator, PrefixPartitioner, Random, StringAppendOperator,
+            WriteBufferManager,
+        },
+    };
+
+    fn config() -> DBConfig {
+        DBConfig {
+            create_if_missing: true,
+            ..Default::default()
+        }
+    }
+
+    #[cfg(feature = "bloom")]
+    fn bloom_filter_policy() -> Option<Arc<dyn FilterPolicy>> {
+        Some(Arc::new(crate::BloomFilterPolicy::new(10)))
+    }
+
+    #[cfg(not(feature = "bloom"))]
+    fn bloom_filter_policy() -> Option<Arc<dyn FilterPolicy>> {
+        None
+    }
+
+    fn open(name: &str) -> DB {
+        DB::open(&test_dir(name), &config()).unwrap()
+    }
+
+    fn get(db: &DB, key: &[u8]) -> String {
+        match db.get(key, &ReadConfig::default()) {
+            Ok(value) => String::from_utf8(value).unwrap(),
+            Err(error) if error.is_not_found() => "NOT_FOUND".to_string(),
+            Err(error) => error.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_db_put_get_delete() {
+        let db = open("db_put_get_delete");
+        let config = WriteConfig::default();
+        assert_eq!("NOT_FOUND", get(&db, b"foo"));
+        db.put(b"foo", b"v1", &config).unwrap();
+        assert_eq!("v1", get(&db, b"foo"));
+        db.put(b"foo", b"v2", &config).unwrap();
+        assert_eq!("v2", get(&db, b"foo"));
+        db.delete(b"foo", &config).unwrap();
+        assert_eq!("NOT_FOUND", get(&db, b"foo"));
+
+        let mut batch = WriteBatch::new();
+        batch.put(b"a", b"1").unwrap();
+        batch.put(b"b", b"2").unwrap();
+        batch.delete(b"a").unwrap();
+        db.write(&mut batch, &config).unwrap();
+        assert_eq!("NOT_FOUND", get(&db, b"a"));
+        assert_eq!("2", get(&db, b"b"));
+        assert_eq!(6, db.lock().versions.last_sequence());
+    }
+
+    #[test]
+    fn test_db_background_error() {
+        let db = open("db_background_error");
+        let config = WriteConfig::default();
+        db.put(b"foo", b"v1", &config).unwrap();
+        assert!(db.background_error().is_none());
+
+        db.lock().record_background_error(
+            BackgroundErrorReason::Flush,
+            Error::io_error("flush failed"),
+        );
+        db.lock().record_background_error(
+            BackgroundErrorReason::Compaction,
+            Error::corruption("later error"),
+        );
+        assert!(db.background_error().unwrap().is_io_error());
+
+        // Writes fail fast and leave the database untouched
+        assert!(db.put(b"foo", b"v2", &config).unwrap_err().is_io_error());
+        assert!(db.delete(b"foo", &config).unwrap_err().is_io_error());
+
+        // Reads keep serving data unless asked to fail
+        assert_eq!("v1", get(&db, b"foo"));
+        let strict = ReadConfig {
+            fail_on_background_error: true,
+            ..Default::default()
+        };
+        assert!(db.get(b"foo", &strict).unwrap_err().is_io_error());
+
+        db.clear_background_error();
+        db.put(b"foo", b"v2", &config).unwrap();
+        assert_eq!("v2", get(&db, b"foo"));
+        assert_eq!(
+            "v2",
+            String::from_utf8(db.get(b"foo", &strict).unwrap()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_db_open_options() {
+        let dbname = test_dir("db_open_options");
+        let missing = format!("{}/missing", dbname);
+        assert!(DB::open(&missing, &DBConfig::default())
+            .err()
+            .unwrap()
+            .is_invalid_argument());
+
+        let db = DB::open(&missing, &config()).unwrap();
+        assert_eq!(2, db.lock().versions.manifest_file_number());
+        drop(db);
+
+        let strict = DBConfig {
+            error_if_exists: true,
+            ..config()
+        };
+        assert!(DB::open(&missing, &strict)
+            .err()
+            .unwrap()
+            .is_invalid_argument());
+
+        // Reopening switches to a new MANIFEST
+        let db = DB::open(&missing, &DBConfig::default()).unwrap();
+        assert!(db.lock().versions.manifest_file_number() > 2);
+    }
+
+    #[test]
+    fn test_db_config_builders() {
+        let db_config = DBConfig::default()
+            .create_if_missing(true)
+            .paranoid_checks(true)
+            .write_buffer_size(10000)
+            .block_size(1024)
+            .compression(CompressionType::None);
+        assert!(db_config.create_if_missing && db_config.paranoid_checks);
+        assert_eq!(1024, db_config.block_size);
+        let db = DB::open(&test_dir("db_config_builders"), &db_config).unwrap();
+
+        let sync = WriteConfig::default().sync(true);
+        db.put(b"foo", b"v1", &sync).unwrap();
+        let snapshot = db.get_snapshot();
+        db.put(b"foo", b"v2", &sync).unwrap();
+        let at_snapshot = ReadConfig::default()
+            .verify_checksums(true)
+            .fill_cache(false)
+            .snapshot(snapshot);
+        assert_eq!(b"v1", db.get(b"foo", &at_snapshot).unwrap().as_slice());
+        assert_eq!("v2", get(&db, b"foo"));
+
+        let bounded = ReadConfig::default()
+            .iterate_lower_bound(b"a")
+            .iterate_upper_bound(b"foo");
+        let mut iter = db.iter(&bounded);
+        iter.seek_to_first();
+        assert!(!iter.valid());
+    }
+
+    struct ReverseComparator;
+
+    impl Comparator for ReverseComparator {
+        fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+            b.cmp(a)
+        }
+
+        fn name(&self) -> &str {
+            "rebeldb.ReverseComparator"
+        }
+    }
+
+    #[test]
+    fn test_db_iterator_reads_level_lazily() {
+        let dbname = test_dir("db_iterator_reads_level_lazily");
+        let db_config = config().write_buffer_size(10000).max_file_size(20000);
+        let db = DB::open(&dbname, &db_config).unwrap();
+        let value = [b'v'; 500];
+        for i in 0..200 {
+            db.put(
+                format!("key{:03}", i).as_bytes(),
+                &value,
+                &WriteConfig::default(),
+            )
+            .unwrap();
+        }
+        db.compact_range(None, None).unwrap();
+        let num_files = db.lock().versions.num_level_files(1);
+        assert!(num_files > 2);
+        assert_eq!(0, db.lock().versions.num_level_files(0));
+        drop(db);
+
+        // A seek only reads a block of the one table of the level that
+        // may hold the key, and a scan reads the others as it gets to them
+        let db = DB::open(&dbname, &db_config).unwrap();
+        let mut iter = db.iter(&ReadConfig::default());
+        iter.seek(b"key100");
+        assert_eq!(b"key100", iter.key());
+        assert!(db.table_cache.block_cache_usage() < 2 * db_config.block_size);
+        iter.seek_to_first();
+        let mut count = 0;
+        while iter.valid() {
+            count += 1;
+            iter.next();
+        }
+        iter.status().unwrap();
+        assert_eq!(200, count);
+        assert!(db.table_cache.block_cache_usage() > num_files * db_config.block_size);
+    }
+
+    #[test]
+    fn test_db_custom_comparator() {
+        let dbname = test_dir("db_custom_comparator");
+        let reverse = config()
+            .comparator(Arc::new(ReverseComparator))
+            .write_buffer_size(10000)
+            .max_file_size(20000);
+        let db = DB::open(&dbname, &reverse).unwrap();
+        let value = [b'v'; 500];
+        for i in 0..200 {
+            db.put(
+                format!("key{:03}", i).as_bytes(),
+                &value,
+                &WriteConfig::default(),
+            )
+            .unwrap();
+        }
+        db.compact_range(None, None).unwrap();
+        assert!(db.lock().versions.num_level_files(1) > 1);
+        assert_eq!(500, get(&db, b"key123").len());
+
+        // Keys come out in the order of the comparator, and so do bounds
+        let mut iter = db.iter(&ReadConfig::default());
+        iter.seek_to_first();
+        assert_eq!(b"key199", iter.key());
+        iter.seek(b"key150");
+        iter.next();
+        assert_eq!(b"key149", iter.key());
+        drop(iter);
+        let bounded = ReadConfig::default()
+            .iterate_lower_bound(b"key050")
+            .iterate_upper_bound(b"key040");
+        let mut iter = db.iter(&bounded);
+        iter.seek_to_last();
+        assert_eq!(b"key041", iter.key());
+        drop(iter);
+
+        // The comparator is recorded in the MANIFEST, and a database can't
+        // be opened with another one
+        drop(db);
+        assert!(DB::open(&dbname, &config())
+            .err()
+            .unwrap()
+            .is_invalid_argument());
+        let db = DB::open(&dbname, &reverse).unwrap();
+        assert_eq!(500, get(&db, b"key000").len());
+    }
+
+    #[test]
+    fn test_db_locking() {
+        let dbname = test_dir("db_locking");
+        let db = DB::open(&dbname, &config()).unwrap();
+        // The database can only be opened once at a time, even by the
+        // same process
+        assert!(DB::open(&dbname, &config()).err().unwrap().is_io_error());
+        let handle = thread::spawn({
+            let dbname = dbname.clone();
+            move || DB::open(&dbname, &DBConfig::default()).is_err()
+        });
+        assert!(handle.join().unwrap());
+
+        // Closing the database releases the lock
+        drop(db);
+        let db = DB::open(&dbname, &DBConfig::default()).unwrap();
+        assert_eq!(1, count_files(&db, FileType::DBLockFile));
+    }
+
+    #[test]
+    fn test_db_timeouts() {
+        let db = DB::open(
+            &test_dir("db_timeouts"),
+            &DBConfig {
+                write_buffer_size: 10000,
+                ..config()
+            },
+        )
+        .unwrap();
+        let expired = Some(Instant::now());
+        let late_write = WriteConfig {
+            deadline: expired,
+            ..Default::default()
+        };
+        assert!(db
+            .put(b"foo", b"v1", &late_write)
+            .unwrap_err()
+            .is_timed_out());
+        assert_eq!("NOT_FOUND", get(&db, b"foo"));
+
+        let late_read = ReadConfig {
+            deadline: expired,
+            ..Default::default()
+        };
+        db.put(b"foo", b"v1", &WriteConfig::default()).unwrap();
+        assert!(db.get(b"foo", &late_read).unwrap_err().is_timed_out());
+
+        // A generous deadline does not get in the way
+        let relaxed = WriteConfig {
+            deadline: Some(Instant::now() + Duration::from_secs(60)),
+            ..Default::default()
+        };
+        for i in 0..20 {
+            db.put(format!("key{:02}", i).as_bytes(), &[b'v'; 1000], &relaxed)
+                .unwrap();
+        }
+        db.wait_for_background_work().unwrap();
+        assert!(db.lock().versions.num_level_files(0) > 0);
+
+        // Memtable hits need no file reads, table hits time out
+        let no_io = ReadConfig {
+            io_timeout: Some(Duration::ZERO),
+            ..Default::default()
+        };
+        assert_eq!(vec![b'v'; 1000], db.get(b"key19", &no_io).unwrap());
+        assert!(db.get(b"key00", &no_io).unwrap_err().is_timed_out());
+        assert_eq!(
+            vec![b'v'; 1000],
+            db.get(b"key00", &ReadConfig::default()).unwrap()
+        );
+    }
+
+    fn contents(iter: &mut dyn Iterator, reverse: bool) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let entries = match reverse {
+            true => IterAdapter::from_last(iter),
+            false => IterAdapter::from_first(iter),
+        };
+        entries.collect::<Result<_>>().unwrap()
+    }
+
+    #[test]
+    fn test_db_iterator() {
+        let db = DB::open(
+            &test_dir("db_iterator"),
+            &DBConfig {
+                write_buffer_size: 10000,
+                ..config()
+            },
+        )
+        .unwrap();
+        let config = WriteConfig::default();
+        let mut model = BTreeMap::new();
+        let mut rnd = Random::new(301);
+        for i in 0..2000 {
+            let key = format!("key{:03}", rnd.uniform(300)).into_bytes();
+            if rnd.one_in(4) {
+                db.delete(&key, &config).unwrap();
+                model.remove(&key);
+            } else {
+                let value = format!("value{}", i).repeat(10).into_bytes();
+                db.put(&key, &value, &config).unwrap();
+                model.insert(key, value);
+            }
+        }
+        db.wait_for_background_work().unwrap();
+        let inner = db.lock();
+        assert!(inner.versions.num_level_files(0) + inner.versions.num_level_files(1) > 0);
+        drop(inner);
+
+        let expected: Vec<_> = model.clone().into_iter().collect();
+        let mut iter = db.iter(&ReadConfig::default());
+        assert!(!iter.valid());
+        assert_eq!(expected, contents(iter.as_mut(), false));
+        let mut reversed = contents(iter.as_mut(), true);
+        reversed.reverse();
+        assert_eq!(expected, reversed);
+
+        // Seeks and direction switches
+        for _ in 0..200 {
+            let target = format!("key{:03}", rnd.uniform(310)).into_bytes();
+            iter.seek(&target);
+            let mut index = expected.partition_point(|(key, _)| *key < target);
+            for _ in 0..10 {
+                match expected.get(index) {
+                    Some((key, value)) => {
+                        assert_eq!(key.as_slice(), iter.key());
+                        assert_eq!(value.as_slice(), iter.value());
+                    }
+                    None => {
+                        assert!(!iter.valid());
+                        break;
+                    }
+                }
+                if rnd.one_in(2) {
+                    iter.next();
+                    index += 1;
+                } else if index == 0 {
+                    iter.prev();
+                    assert!(!iter.valid());
+                    break;
+                } else {
+                    iter.prev();
+                    index -= 1;
+                }
+            }
+        }
+        drop(iter);
+
+        // Bounds
+        let bounded = ReadConfig {
+            iterate_lower_bound: Some(b"key100".to_vec()),
+            iterate_upper_bound: Some(b"key200".to_vec()),
+            ..Default::default()
+        };
+        let in_bounds: Vec<_> = model
+            .range(b"key100".to_vec()..b"key200".to_vec())
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        let mut iter = db.iter(&bounded);
+        assert_eq!(in_bounds, contents(iter.as_mut(), false));
+        let mut reversed = contents(iter.as_mut(), true);
+        reversed.reverse();
+        assert_eq!(in_bounds, reversed);
+        iter.seek(b"a");
+        assert_eq!(in_bounds[0].0.as_slice(), iter.key());
+        iter.prev();
+        assert!(!iter.valid());
+        iter.seek(b"key250");
+        assert!(!iter.valid());
+        drop(iter);
+
+        // Inverted bounds are an error, equal ones an empty range
+        let inverted = ReadConfig {
+            iterate_lower_bound: Some(b"key200".to_vec()),
+            iterate_upper_bound: Some(b"key100".to_vec()),
+            ..Default::default()
+        };
+        let mut iter = db.iter(&inverted);
+        iter.seek_to_first();
+        assert!(!iter.valid());
+        assert!(iter.status().unwrap_err().is_invalid_argument());
+        drop(iter);
+        let empty = ReadConfig {
+            iterate_lower_bound: Some(b"key100".to_vec()),
+            iterate_upper_bound: Some(b"key100".to_vec()),
+            ..Default::default()
+        };
+        let mut iter = db.iter(&empty);
+        iter.seek_to_first();
+        assert!(!iter.valid());
+        assert!(iter.status().is_ok());
+        drop(iter);
+
+        // Entries newer than the iterator's sequence are hidden
+        let snapshot = db.lock().versions.last_sequence();
+        db.put(b"key999", b"new", &config).unwrap();
+        let mut iter = db.iter(&ReadConfig::default());
+        iter.seek_to_last();
+        assert_eq!(b"key999", iter.key());
+        drop(iter);
+        let mem = db.lock().mem.clone();
+        let internal = new_merging_iterator(
+            Arc::new(db.internal_comparator.clone()),
+            vec![mem.new_iterator()],
+        );
+        let mut iter = DBIterator::new(
+            db.internal_comparator.user_comparator().clone(),
+            internal,
+            snapshot,
+            None,
+            None,
+            None,
+            None,
+        );
+        iter.seek(b"key999");
+        assert!(!iter.valid());
+        drop(iter);
+        drop(mem);
+
+        // Scans honor the deadline
+        let late = ReadConfig {
+            deadline: Some(Instant::now()),
+            ..Default::default()
+        };
+        let mut iter = db.iter(&late);
+        iter.seek_to_first();
+        assert!(!iter.valid());
+        assert!(iter.status().unwrap_err().is_timed_out());
+        drop(iter);
+
+        // An iterator that may not pin its sources at all refreshes on
+        // every move without skipping or repeating entries, or expires
+        let mut iter = db.iter(&ReadConfig::default());
+        let all = contents(iter.as_mut(), false);
+        drop(iter);
+        let unpinned = ReadConfig {
+            max_iterator_pin: Some(Duration::ZERO),
+            ..Default::default()
+        };
+        let mut iter = db.iter(&unpinned);
+        assert_eq!(all, contents(iter.as_mut(), false));
+        let mut reversed = contents(iter.as_mut(), true);
+        reversed.reverse();
+        assert_eq!(all, reversed);
+        iter.seek(&all[1].0);
+        iter.prev();
+        iter.next();
+        assert_eq!(all[1].0, iter.key());
+        assert!(iter.status().is_ok());
+        drop(iter);
+        let expiring = ReadConfig {
+            refresh_iterators: false,
+            ..unpinned
+        };
+        let mut iter = db.iter(&expiring);
+        iter.seek_to_first();
+        assert!(!iter.valid());
+        assert!(iter.status().unwrap_err().is_expired());
+    }
+
+    #[test]
+    fn test_db_iterator_seek_for_prev() {
+        let db = DB::open(
+            &test_dir("db_iterator_seek_for_prev"),
+            &DBConfig {
+                merge_operator: Some(Arc::new(StringAppendOperator::new(b','))),
+                ..config()
+            },
+        )
+        .unwrap();
+        let config = WriteConfig::default();
+        for key in ["b", "d", "f", "h"] {
+            db.put(key.as_bytes(), b"v1", &config).unwrap();
+        }
+        db.flush().unwrap();
+        db.put(b"d", b"v2", &config).unwrap();
+        db.delete(b"f", &config).unwrap();
+        db.merge(b"h", b"v2", &config).unwrap();
+        db.put(b"j", b"v1", &config).unwrap();
+
+        let mut iter = db.iter(&ReadConfig::default());
+        let mut at = |target: &[u8]| {
+            iter.seek_for_prev(target);
+            match iter.valid() {
+                true => format!(
+                    "{}={}",
+                    String::from_utf8_lossy(iter.key()),
+                    String::from_utf8_lossy(iter.value())
+                ),
+                false => "END".to_string(),
+            }
+        };
+        assert_eq!("END", at(b"a"));
+        assert_eq!("b=v1", at(b"b"));
+        assert_eq!("b=v1", at(b"c"));
+        assert_eq!("d=v2", at(b"d"));
+        assert_eq!("d=v2", at(b"f"));
+        assert_eq!("d=v2", at(b"g"));
+        assert_eq!("h=v1,v2", at(b"h"));
+        assert_eq!("j=v1", at(b"z"));
+        iter.seek_for_prev(b"g");
+        iter.next();
+        assert_eq!(b"h", iter.key());
+        iter.seek_for_prev(b"g");
+        iter.prev();
+        assert_eq!(b"b", iter.key());
+        assert!(iter.status().is_ok());
+        drop(iter);
+
+        // Only keys within the bounds are visited
+        let mut iter = db.iter(&ReadConfig {
+            iterate_lower_bound: Some(b"c".to_vec()),
+            iterate_upper_bound: Some(b"h".to_vec()),
+            ..Default::default()
+        });
+        iter.seek_for_prev(b"z");
+        assert_eq!(b"d", iter.key());
+        iter.seek_for_prev(b"c");
+        assert!(!iter.valid());
+        assert!(iter.status().is_ok());
+    }
+
+    #[test]
+    fn test_db_iterator_refresh() {
+        let db = DB::open(&test_dir("db_iterator_refresh"), &config()).unwrap();
+        let config = WriteConfig::default();
+        for key in ["a", "c", "e"] {
+            db.put(key.as_bytes(), b"v1", &config).unwrap();
+        }
+        let mut iter = db.iter(&ReadConfig::default());
+        iter.seek(b"c");
+        db.put(b"d", b"v1", &config).unwrap();
+        db.put(b"e", b"v2", &config).unwrap();
+        db.flush().unwrap();
+        db.put(b"b", b"v1", &config).unwrap();
+        iter.next();
+        assert_eq!((&b"e"[..], &b"v1"[..]), (iter.key(), iter.value()));
+
+        // Stays at the current key, and sees the new writes
+        iter.prev();
+        iter.refresh().unwrap();
+        assert_eq!(b"c", iter.key());
+        iter.next();
+        assert_eq!(b"d", iter.key());
+        iter.next();
+        assert_eq!((&b"e"[..], &b"v2"[..]), (iter.key(), iter.value()));
+
+        // Moves on in its direction from a key that is gone
+        db.delete(b"e", &config).unwrap();
+        iter.refresh().unwrap();
+        assert!(!iter.valid());
+        iter.seek(b"c");
+        db.delete(b"c", &config).unwrap();
+        iter.refresh().unwrap();
+        assert_eq!(b"d", iter.key());
+        iter.prev();
+        assert_eq!(b"b", iter.key());
+        db.delete(b"b", &config).unwrap();
+        iter.refresh().unwrap();
+        assert_eq!(b"a", iter.key());
+        assert!(iter.status().is_ok());
+
+        // A snapshot is kept across refreshes
+        let snapshot = db.get_snapshot();
+        let mut iter = db.iter(&ReadConfig {
+            snapshot: Some(snapshot.clone()),
+            ..Default::default()
+        });
+        db.put(b"z", b"v1", &config).unwrap();
+        iter.refresh().unwrap();
+        iter.seek_to_last();
+        assert_eq!(b"d", iter.key());
+        drop(iter);
+        db.release_snapshot(snapshot);
+    }
+
+    #[test]
+    fn test_db_snapshot() {
+        let db = DB::open(
+            &test_dir("db_snapshot"),
+            &DBConfig {
+                write_buffer_size: 10000,
+                max_file_size: 20000,
+                ..config()
+            },
+        )
+        .unwrap();
+        let config = WriteConfig::default();
+        db.put(b"foo", b"v1", &config).unwrap();
+        db.put(b"bar", b"v1", &config).unwrap();
+        let s1 = db.get_snapshot();
+        db.put(b"foo", b"v2", &config).unwrap();
+        db.delete(b"bar", &config).unwrap();
+        let s2 = db.get_snapshot();
+        db.put(b"foo", b"v3", &config).unwrap();
+
+        let at = |snapshot: &Arc<Snapshot>| ReadConfig {
+            snapshot: Some(snapshot.clone()),
+            ..Default::default()
+        };
+        let check = |db: &DB| {
+            assert_eq!(b"v1", db.get(b"foo", &at(&s1)).unwrap().as_slice());
+            assert_eq!(b"v1", db.get(b"bar", &at(&s1)).unwrap().as_slice());
+            assert_eq!(b"v2", db.get(b"foo", &at(&s2)).unwrap().as_slice());
+            assert!(db.get(b"bar", &at(&s2)).unwrap_err().is_not_found());
+            assert_eq!("v3", get(db, b"foo"));
+
+            let mut iter = db.iter(&at(&s1));
+            let entries = contents(iter.as_mut(), false);
+            assert_eq!(
+                vec![
+                    (b"bar".to_vec(), b"v1".to_vec()),
+                    (b"foo".to_vec(), b"v1".to_vec())
+                ],
+                entries
+            );
+        };
+        check(&db);
+
+        // Flushes and compactions keep what the snapshots can see
+        for round in 0..3 {
+            for i in 0..200 {
+                db.put(
+                    format!("key{:03}", i).as_bytes(),
+                    format!("{}{:0>500}", round, i).as_bytes(),
+                    &config,
+                )
+                .unwrap();
+            }
+        }
+        assert!(db.lock().versions.num_level_files(1) > 0);
+        check(&db);
+
+        // Once released, old versions can be dropped by compactions
+        let level_bytes = |db: &DB| -> u64 {
+            (0..NUM_LEVELS)
+                .map(|level| db.lock().versions.num_level_bytes(level))
+                .sum()
+        };
+        let s3 = db.get_snapshot();
+        for i in 0..200 {
+            db.put(format!("key{:03}", i).as_bytes(), &[b'x'; 500], &config)
+                .unwrap();
+        }
+        assert_eq!(
+            format!("2{:0>500}", 7).as_bytes(),
+            db.get(b"key007", &at(&s3)).unwrap().as_slice()
+        );
+        db.release_snapshot(s1);
+        db.release_snapshot(s2);
+        db.release_snapshot(s3);
+        assert!(db.lock().snapshots.is_empty());
+        let bytes_with_snapshot = level_bytes(&db);
+        for _ in 0..3 {
+            for i in 0..200 {
+                db.put(format!("key{:03}", i).as_bytes(), &[b'y'; 500], &config)
+                    .unwrap();
+            }
+        }
+        assert!(level_bytes(&db) <= bytes_with_snapshot);
+        assert_eq!("v3", get(&db, b"foo"));
+        assert_eq!("NOT_FOUND", get(&db, b"bar"));
+    }
+
+    #[test]
+    fn test_db_background_operations() {
+        let db = DB::open(
+            &test_dir("db_background_operations"),
+            &DBConfig {
+                write_buffer_size: 10000,
+                // Slow enough for a compaction to outlast its burst, so
+                // that the watcher sees it
+                rate_limiter: Some(Arc::new(crate::RateLimiter::new(1 << 17))),
+                ..config()
+            },
+        )
+        .unwrap();
+        assert!(db.get_background_operations().is_empty());
+
+        // Compactions run in the background, so watch from another thread
+        let tracker = db.operations.clone();
+        let done = Arc::new(AtomicBool::new(false));
+        let watcher = {
+            let done = done.clone();
+            thread::spawn(move || {
+                let mut seen = vec![];
+                while !done.load(atomic::Ordering::SeqCst) {
+                    for operation in tracker.operations() {
+                        if !seen.contains(&operation.operation_type) {
+                            seen.push(operation.operation_type);
+                        }
+                        if operation.operation_type == OperationType::Compaction {
+                            assert_eq!(Some(0), operation.input_level);
+                            assert_eq!(1, operation.output_level);
+                        }
+                    }
+                }
+                seen
+            })
+        };
+        for i in 0..50 {
+            db.put(
+                format!("key{:03}", i % 25).as_bytes(),
+                &[b'v'; 1000],
+                &WriteConfig::default(),
+            )
+            .unwrap();
+        }
+        db.flush().unwrap();
+        db.wait_for_background_work().unwrap();
+        done.store(true, atomic::Ordering::SeqCst);
+        let seen = watcher.join().unwrap();
+        assert!(seen.contains(&OperationType::Flush));
+        assert!(seen.contains(&OperationType::Compaction));
+        assert!(db.lock().versions.num_level_files(1) > 0);
+        assert!(db.get_background_operations().is_empty());
+    }
+
+    #[test]
+    fn test_db_event_log() {
+        let dbname = test_dir("db_event_log");
+        let event_log = format!("{}/EVENTS", dbname);
+        let config = DBConfig {
+            write_buffer_size: 10000,
+            event_log_file: Some(event_log.clone()),
+            ..config()
+        };
+        let db = DB::open(&dbname, &config).unwrap();
+        for i in 0..100 {
+            db.put(
+                format!("key{:03}", i % 40).as_bytes(),
+                &[b'v'; 1000],
+                &WriteConfig::default(),
+            )
+            .unwrap();
+        }
+        db.flush().unwrap();
+        db.wait_for_background_work().unwrap();
+        let files: Vec<_> = (0..NUM_LEVELS)
+            .map(|level| db.lock().versions.num_level_files(level) as u64)
+            .collect();
+        drop(db);
+        let db = DB::open(&dbname, &config).unwrap();
+
+        let events = replay_event_log(db.lock().env.as_ref(), &event_log).unwrap();
+        let names: Vec<_> = events.iter().map(|event| event.event.as_str()).collect();
+        assert_eq!("db_opened", names[0]);
+        assert_eq!("db_opened", *names.last().unwrap());
+        assert!(names.contains(&"flush_finished"));
+        assert!(names.contains(&"compaction_finished"));
+        assert!(events
+            .windows(2)
+            .all(|pair| pair[0].time_micros <= pair[1].time_micros));
+        assert_eq!(
+            names
+                .iter()
+                .filter(|name| **name == "flush_started")
+                .count(),
+            names
+                .iter()
+                .filter(|name| **name == "flush_finished")
+                .count()
+        );
+
+        // The shape logged last before closing matches the database
+        let before_reopen = &events[events.len() - 2];
+        assert_eq!(files, before_reopen.files_per_level);
+        assert_eq!(NUM_LEVELS, before_reopen.bytes_per_level.len());
+
+        let contents =
+            String::from_utf8(read_file_to_vec(db.lock().env.as_ref(), &event_log).unwrap())
+                .unwrap();
+        let compaction = contents
+            .lines()
+            .find(|line| line.contains("\"compaction_finished\""))
+            .unwrap();
+        assert!(compaction.contains("\"status\": \"OK\""));
+        assert!(compaction.contains("\"output_files\": ["));
+    }
+
+    #[test]
+    fn test_db_trace_replay() {
+        let db = open("db_trace_replay_source");
+        let trace = format!("{}/TRACE", test_dir("db_trace_replay"));
+        let write_config = WriteConfig::default();
+        db.put(b"untraced", b"v", &write_config).unwrap();
+        assert!(db.end_trace().unwrap_err().is_invalid_argument());
+
+        db.start_trace(
+            db.lock()
+                .env
+                .new_writable_file(&trace, WriteHint::Other)
+                .unwrap(),
+        )
+        .unwrap();
+        assert!(db
+            .start_trace(
+                db.lock()
+                    .env
+                    .new_writable_file(&trace, WriteHint::Other)
+                    .unwrap()
+            )
+            .unwrap_err()
+            .is_invalid_argument());
+        for i in 0..100 {
+            db.put(format!("key{:03}", i).as_bytes(), b"v1", &write_config)
+                .unwrap();
+        }
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!("v1", get(&db, b"key005"));
+        let mut batch = WriteBatch::new();
+        batch.delete(b"key001").unwrap();
+        batch.put(b"key002", b"v2").unwrap();
+        db.write(&mut batch, &write_config).unwrap();
+        db.end_trace().unwrap();
+        db.put(b"untraced2", b"v", &write_config).unwrap();
+
+        // At the original speed the pause before the get is kept
+        let mut target = open("db_trace_replay_target");
+        let file = target.lock().env.new_sequential_file(&trace).unwrap();
+        let start = Instant::now();
+        let replayed = Replayer::new(&mut target, file)
+            .unwrap()
+            .replay(1.0)
+            .unwrap();
+        assert_eq!(102, replayed);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert_eq!("NOT_FOUND", get(&target, b"untraced"));
+        assert_eq!("NOT_FOUND", get(&target, b"untraced2"));
+        assert_eq!("NOT_FOUND", get(&target, b"key001"));
+        assert_eq!("v2", get(&target, b"key002"));
+        assert_eq!("v1", get(&target, b"key099"));
+
+        // Accelerated replay against another config
+        let mut fast = DB::open(
+            &test_dir("db_trace_replay_fast"),
+            &DBConfig {
+                write_buffer_size: 1000,
+                ..config()
+            },
+        )
+        .unwrap();
+        let file = fast.lock().env.new_sequential_file(&trace).unwrap();
+        let mut replayer = Replayer::new(&mut fast, file).unwrap();
+        assert!(replayer.replay(0.0).unwrap_err().is_invalid_argument());
+        assert_eq!(102, replayer.replay(100.0).unwrap());
+        assert_eq!("v2", get(&fast, b"key002"));
+
+        // Not a trace
+        write_data_to_file(fast.lock().env.clone(), b"garbage!", &trace).unwrap();
+        let file = fast.lock().env.new_sequential_file(&trace).unwrap();
+        assert!(Replayer::new(&mut fast, file)
+            .err()
+            .unwrap()
+            .is_corruption());
+    }
+
+    #[test]
+    fn test_db_get_property() {
+        let db = open("db_get_property");
+        assert_eq!("0", db.get_property("rebeldb.num-files-at-level0").unwrap());
+        assert_eq!("0", db.get_property("rebeldb.num-files-at-level6").unwrap());
+        assert!(db.get_property("rebeldb.num-files-at-level7").is_none());
+        assert!(db.get_property("rebeldb.num-files-at-levelx").is_none());
+        assert_eq!(
+            "0.00",
+            db.get_property("rebeldb.compaction-score-at-level1")
+                .unwrap()
+        );
+        assert_eq!(
+            "0",
+            db.get_property("rebeldb.estimate-pending-compaction-bytes")
+                .unwrap()
+        );
+        assert!(db.get_property("rebeldb.stats").unwrap().contains("Level"));
+        assert_eq!(
+            "--- level 0 ---\n--- level 1 ---\n",
+            &db.get_property("rebeldb.sstables").unwrap()[..32]
+        );
+        let usage: usize = db
+            .get_property("rebeldb.approximate-memory-usage")
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(usage > 0);
+        assert!(db.get_property("rebeldb.unknown").is_none());
+        assert!(db.get_property("leveldb.stats").is_none());
+
+        let stats = db.statistics();
+        assert_eq!(NUM_LEVELS, stats.levels.len());
+        assert_eq!(LevelStatistics::default(), stats.levels[0]);
+        assert_eq!(0, stats.pending_compaction_bytes);
+
+        // Tables show up with their key ranges, the memtable in the memory
+        // usage
+        let db = db;
+        db.put(b"a\x01", &[b'v'; 10000], &WriteConfig::default())
+            .unwrap();
+        let grown: usize = db
+            .get_property("rebeldb.approximate-memory-usage")
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(grown >= usage + 10000);
+        db.delete(b"b", &WriteConfig::default()).unwrap();
+        for (property, value) in [
+            ("num-entries-active-mem-table", "2"),
+            ("total-key-bytes-active-mem-table", "3"),
+            ("total-value-bytes-active-mem-table", "10000"),
+        ] {
+            assert_eq!(
+                value,
+                db.get_property(&format!("rebeldb.{}", property)).unwrap()
+            );
+        }
+        db.compact_range(None, None).unwrap();
+        let sstables = db.get_property("rebeldb.sstables").unwrap();
+        assert!(sstables.contains("['a\\x01' @ 1 : 1 .. 'a\\x01' @ 1 : 1]"));
+    }
+
+    fn count_files(db: &DB, type_: FileType) -> usize {
+        let inner = db.lock();
+        inner
+            .env
+            .get_children(&inner.dbname)
+            .unwrap()
+            .iter()
+            .filter_map(|filename| parse_file_name(filename.to_str().unwrap()))
+            .filter(|(_, t)| *t == type_)
+            .count()
+    }
+
+    /// The number of table files of the current version, once the
+    /// background work is done, so that none are being written
+    fn num_live_files(db: &DB) -> usize {
+        db.wait_for_background_work().unwrap();
+        (0..NUM_LEVELS)
+            .map(|level| db.lock().versions.num_level_files(level))
+            .sum()
+    }
+
+    #[test]
+    fn test_db_remove_obsolete_files() {
+        let db = open("db_remove_obsolete_files");
+        db.put(b"foo", b"v1", &WriteConfig::default()).unwrap();
+        let write_table = |db: &DB, number| {
+            let fname = table_file_name_for(&db.lock().dbname, number, TableFileExtension::Ldb);
+            write_data_to_file(db.lock().env.clone(), b"table", &fname).unwrap();
+        };
+
+        // A table no version refers to is garbage, unless it is still
+        // being written or was created after the live files were listed
+        let pending = db.lock().versions.new_file_number();
+        let orphan = db.lock().versions.new_file_number();
+        let future = db.lock().versions.next_file_number() + 10;
+        db.lock().pending_outputs.insert(pending);
+        for number in [pending, orphan, future] {
+            write_table(&db, number);
+        }
+        db.lock().remove_obsolete_files();
+        assert_eq!(2, count_files(&db, FileType::TableFile));
+
+        // Once the flush or compaction is done with it, it is garbage
+        db.lock().pending_outputs.remove(&pending);
+        db.lock().remove_obsolete_files();
+        assert_eq!(1, count_files(&db, FileType::TableFile));
+        assert_eq!("v1", get(&db, b"foo"));
+    }
+
+    #[test]
+    fn test_db_mem_env() {
+        let dbname = "/mem/db";
+        let in_memory = DBConfig {
+            env: Arc::new(MemEnv::new()),
+            write_buffer_size: 10000,
+            ..config()
+        };
+        let db = DB::open(dbname, &in_memory).unwrap();
+        let value = |i: usize| format!("{:0>500}", i);
+        for i in 0..200 {
+            db.put(
+                format!("key{:03}", i).as_bytes(),
+                value(i).as_bytes(),
+                &WriteConfig::default(),
+            )
+            .unwrap();
+        }
+        db.wait_for_background_work().unwrap();
+        assert!(num_live_files(&db) > 0);
+        assert_eq!(num_live_files(&db), count_files(&db, FileType::TableFile));
+        drop(db);
+
+        // The files outlive the DB, but not the Env
+        let db = DB::open(dbname, &in_memory).unwrap();
+        for i in 0..200 {
+            assert_eq!(value(i), get(&db, format!("key{:03}", i).as_bytes()));
+        }
+        assert!(!std::path::Path::new(dbname).exists());
+    }
+
+    #[test]
+    fn test_db_version_pinning() {
+        let small_files = DBConfig {
+            write_buffer_size: 10000,
+            max_file_size: 20000,
+            ..config()
+        };
+        let mut db = DB::open(&test_dir("db_version_pinning"), &small_files).unwrap();
+        let value = |i: usize, round: usize| format!("{}{:0>500}", round, i);
+        let fill = |db: &mut DB, round: usize| {
+            for i in 0..200 {
+                db.put(
+                    format!("key{:03}", i).as_bytes(),
+                    value(i, round).as_bytes(),
+                    &WriteConfig::default(),
+                )
+                .unwrap();
+            }
+        };
+        let table_exists = |db: &DB, number: u64| {
+            let inner = db.lock();
+            inner.env.file_exists(&table_file_name_for(
+                &inner.dbname,
+                number,
+                TableFileExtension::Ldb,
+            ))
+        };
+
+        // An iterator over the tables of a version, like the one DB::iter()
+        // makes, but without borrowing the DB
+        fill(&mut db, 0);
+        let snapshot = db.lock().versions.last_sequence();
+        let pinned = db.lock().versions.current();
+        let pinned_files: Vec<u64> = (0..NUM_LEVELS)
+            .flat_map(|level| pinned.files(level).iter().map(|file| file.number))
+            .collect();
+        assert!(!pinned_files.is_empty());
+        let mut tables = vec![];
+        pinned.add_iterators(
+            &ReadConfig::default(),
+            &db.internal_comparator,
+            &db.table_cache,
+            &mut tables,
+        );
+        let mut iter = DBIterator::new(
+            db.internal_comparator.user_comparator().clone(),
+            new_merging_iterator(Arc::new(db.internal_comparator.clone()), tables),
+            snapshot,
+            None,
+            None,
+            None,
+            Some(pinned),
+        );
+
+        // Compactions replace every table of the pinned version, which
+        // must stay on disk while the iterator is alive
+        for round in 1..4 {
+            fill(&mut db, round);
+        }
+        let current = db.lock().versions.current();
+        assert!((0..NUM_LEVELS)
+            .flat_map(|level| current.files(level).iter())
+            .all(|file| !pinned_files.contains(&file.number)));
+        drop(current);
+        assert!(db.lock().versions.num_live_versions() > 1);
+        assert!(pinned_files.iter().all(|&number| table_exists(&db, number)));
+
+        // The iterator still sees the data of the pinned version
+        iter.seek_to_first();
+        let mut seen = 0;
+        while iter.valid() {
+            let i: usize = String::from_utf8_lossy(&iter.key()[3..]).parse().unwrap();
+            assert_eq!(value(i, 0).as_bytes(), iter.value());
+            seen += 1;
+            iter.next();
+        }
+        assert!(iter.status().is_ok());
+        assert!(seen > 0);
+
+        drop(iter);
+        db.wait_for_background_work().unwrap();
+        db.lock().remove_obsolete_files();
+        assert_eq!(1, db.lock().versions.num_live_versions());
+        assert!(pinned_files
+            .iter()
+            .all(|&number| !table_exists(&db, number)));
+        assert_eq!(value(7, 3), get(&db, b"key007"));
+    }
+
+    #[test]
+    fn test_db_recover_from_log() {
+        let dbname = test_dir("db_recover_from_log");
+        let db = DB::open(&dbname, &config()).unwrap();
+        let config = WriteConfig::default();
+        db.put(b"foo", b"v1", &config).unwrap();
+        db.put(b"baz", b"v5", &config).unwrap();
+        db.delete(b"baz", &config).unwrap();
+        drop(db);
+
+        let db = DB::open(&dbname, &DBConfig::default()).unwrap();
+        assert_eq!("v1", get(&db, b"foo"));
+        assert_eq!("NOT_FOUND", get(&db, b"baz"));
+        assert_eq!(3, db.lock().versions.last_sequence());
+        // The replayed log was written to a table and removed
+        assert_eq!(1, db.lock().versions.num_level_files(0));
+        assert_eq!(1, count_files(&db, FileType::LogFile));
+
+        db.put(b"foo", b"v2", &config).unwrap();
+        drop(db);
+        let db = DB::open(&dbname, &DBConfig::default()).unwrap();
+        assert_eq!("v2", get(&db, b"foo"));
+        assert_eq!(4, db.lock().versions.last_sequence());
+    }
+
+    #[test]
+    fn test_db_prefix_bloom() {
+        let dbname = test_dir("db_prefix_bloom");
+        let db_config = config().prefix_extractor(Arc::new(crate::FixedPrefixExtractor::new(4)));
+        let db = DB::open(&dbname, &db_config).unwrap();
+        let config = WriteConfig::default();
+        db.put(b"usr1.a", b"v1", &config).unwrap();
+        db.put(b"usr2.a", b"v2", &config).unwrap();
+        db.put(b"us", b"v3", &config).unwrap();
+        db.flush().unwrap();
+
+        // A deletion in the memtable still hides the value in the table,
+        // and keys of prefixes only in the table are found there
+        db.delete(b"usr1.a", &config).unwrap();
+        assert_eq!("NOT_FOUND", get(&db, b"usr1.a"));
+        assert_eq!("v2", get(&db, b"usr2.a"));
+        assert_eq!("v3", get(&db, b"us"));
+        assert_eq!("NOT_FOUND", get(&db, b"usr3.a"));
+        drop(db);
+
+        // Memtables filled from the log have filters too
+        let db = DB::open(&dbname, &db_config).unwrap();
+        assert_eq!("NOT_FOUND", get(&db, b"usr1.a"));
+        assert_eq!("v2", get(&db, b"usr2.a"));
+    }
+
+    #[test]
+    fn test_db_prefix_same_as_start() {
+        let db_config = config().prefix_extractor(Arc::new(crate::FixedPrefixExtractor::new(4)));
+        let db = DB::open(&test_dir("db_prefix_same_as_start"), &db_config).unwrap();
+        let config = WriteConfig::default();
+        for key in ["usr1.a", "usr1.b", "usr2.a"] {
+            db.put(key.as_bytes(), b"v", &config).unwrap();
+        }
+        db.flush().unwrap();
+        // The memtable has no key of prefix usr2
+        for key in ["us", "usr1.c", "usr3.a"] {
+            db.put(key.as_bytes(), b"v", &config).unwrap();
+        }
+
+        let keys = |adapter: IterAdapter<&mut dyn Iterator>| -> Vec<String> {
+            adapter
+                .map(|entry| String::from_utf8(entry.unwrap().0).unwrap())
+                .collect()
+        };
+        let read_config = ReadConfig::default().prefix_same_as_start(true);
+        let mut iter = db.iter(&read_config);
+        assert_eq!(
+            vec!["usr1.a", "usr1.b", "usr1.c"],
+            keys(IterAdapter::from_seek(iter.as_mut(), b"usr1"))
+        );
+        assert_eq!(
+            vec!["usr2.a"],
+            keys(IterAdapter::from_seek(iter.as_mut(), b"usr2"))
+        );
+        assert!(keys(IterAdapter::from_seek(iter.as_mut(), b"usr0")).is_empty());
+        iter.seek(b"usr2.a");
+        iter.prev();
+        assert!(!iter.valid());
+        iter.seek_for_prev(b"usr1.bb");
+        assert_eq!(
+            vec!["usr1.b", "usr1.a"],
+            keys(IterAdapter::new_reverse(iter.as_mut()))
+        );
+        // Without a prefix, the seek target leaves the prefix to the first
+        // key found
+        assert_eq!(
+            vec!["us"],
+            keys(IterAdapter::from_seek(iter.as_mut(), b"u"))
+        );
+        assert_eq!(vec!["usr3.a"], keys(IterAdapter::from_last(iter.as_mut())));
+        iter.status().unwrap();
+
+        // The last key before an upper bound of another prefix
+        let bounded = read_config.clone().iterate_upper_bound(b"usr2");
+        let mut iter = db.iter(&bounded);
+        assert_eq!(
+            vec!["usr1.c", "usr1.b", "usr1.a"],
+            keys(IterAdapter::from_last(iter.as_mut()))
+        );
+
+        // Bounds that prefix_same_as_start would cut short are an error,
+        // unless the upper one just ends the keys of the prefix
+        let bounded = read_config.clone().iterate_lower_bound(b"usr1.b");
+        for (upper, ok) in [
+            (&b"usr1.c"[..], true),
+            (b"usr2", true),
+            (b"usr2.a", false),
+            (b"usr3", false),
+            (b"z", false),
+        ] {
+            let mut iter = db.iter(&bounded.clone().iterate_upper_bound(upper));
+            iter.seek_to_first();
+            assert_eq!(ok, iter.valid(), "{:?}", upper);
+            assert_eq!(ok, iter.status().is_ok(), "{:?}", upper);
+            if !ok {
+                assert!(iter.status().unwrap_err().is_invalid_argument());
+            }
+        }
+        let mut iter = db.iter(
+            &ReadConfig::default()
+                .iterate_lower_bound(b"usr1.b")
+                .iterate_upper_bound(b"z"),
+        );
+        assert_eq!(4, keys(IterAdapter::from_first(iter.as_mut())).len());
+
+        // Without the option, the iteration goes on past the prefix
+        let mut iter = db.iter(&ReadConfig::default());
+        assert_eq!(
+            vec!["usr2.a", "usr3.a"],
+            keys(IterAdapter::from_seek(iter.as_mut(), b"usr2"))
+        );
+    }
+
+    #[test]
+    fn test_db_recover_multiple_logs() {
+        let dbname = test_dir("db_recover_multiple_logs");
+        let env = Arc::new(PosixEnv::new()) as Arc<dyn Env>;
+        drop(DB::open(&dbname, &config()).unwrap());
+
+        // Two logs left behind by a crash, the older one with a corrupted
+        // record
+        let write_log = |number: u64, batches: &[(u64, &[u8], &[u8])]| {
+            let fname = log_file_name(&dbname, number);
+            let mut log = Writer::new(env.new_writable_file(&fname, WriteHint::Wal).unwrap());
+            for &(sequence, key, value) in batches {
+                let mut batch = WriteBatch::new();
+                batch.put(key, value).unwrap();
+                batch.set_sequence(sequence);
+                log.add_record(batch.contents()).unwrap();
+            }
+            log.file_mut().close().unwrap();
+            fname
+        };
+        let older = write_log(100, &[(1, b"a", b"1"), (2, b"b", b"2")]);
+        write_log(101, &[(3, b"a", b"3"), (4, b"c", b"4")]);
+        let mut contents = crate::env::read_file_to_vec(env.as_ref(), &older).unwrap();
+        *contents.last_mut().unwrap() ^= 0x80;
+        write_data_to_file(env.clone(), &contents, &older).unwrap();
+
+        // Paranoid checks refuse to open the database
+        let paranoid = DBConfig {
+            paranoid_checks: true,
+            ..config()
+        };
+        assert!(DB::open(&dbname, &paranoid).err().unwrap().is_corruption());
+
+        // Otherwise the logs are replayed in order, without the corrupted
+        // record, which is reported in the event log
+        let events = format!("{}/EVENTS", dbname);
+        let db = DB::open(
+            &dbname,
+            &DBConfig {
+                event_log_file: Some(events.clone()),
+                ..config()
+            },
+        )
+        .unwrap();
+        assert_eq!("3", get(&db, b"a"));
+        assert_eq!("NOT_FOUND", get(&db, b"b"));
+        assert_eq!("4", get(&db, b"c"));
+        assert_eq!(4, db.lock().versions.last_sequence());
+        assert!(db.lock().versions.next_file_number() > 101);
+        let events =
+            String::from_utf8(crate::env::read_file_to_vec(env.as_ref(), &events).unwrap())
+                .unwrap();
+        assert!(events.contains("log_data_dropped"));
+        assert!(events.contains("000100.log"));
+    }
+
+    #[cfg(feature = "snappy")]
+    #[test]
+    fn test_db_compress_wal() {
+        let dbname = test_dir("db_compress_wal");
+        let env = Arc::new(PosixEnv::new()) as Arc<dyn Env>;
+        let db = DB::open(&dbname, &config().compress_wal(true)).unwrap();
+        let mut batch = WriteBatch::new();
+        for i in 0..100 {
+            batch
+                .put(format!("key{i:03}").as_bytes(), &[b'v'; 1000])
+                .unwrap();
+        }
+        db.write(&mut batch, &WriteConfig::default()).unwrap();
+        let log = log_file_name(&dbname, db.lock().logfile_number);
+        assert!(env.get_file_size(&log).unwrap() < 10000);
+        drop(db);
+
+        // The compressed log is recovered whatever the setting
+        let db = DB::open(&dbname, &config()).unwrap();
+        for i in 0..100 {
+            let value = db
+                .get(format!("key{i:03}").as_bytes(), &ReadConfig::default())
+                .unwrap();
+            assert_eq!(vec![b'v'; 1000], value);
+        }
+        assert_eq!(100, db.lock().versions.last_sequence());
+    }
+
+    #[test]
+    fn test_db_minor_compaction() {
+        let dbname = test_dir("db_minor_compaction");
+        let small_buffer = DBConfig {
+            write_buffer_size: 10000,
+            filter_policy: bloom_filter_policy(),
+            ..config()
+        };
+        let db = DB::open(&dbname, &small_buffer).unwrap();
+        let config = WriteConfig::default();
+        let value = |i: usize| format!("{:0>1000}", i);
+        for i in 0..100 {
+            db.put(
+                format!("key{:03}", i).as_bytes(),
+                value(i).as_bytes(),
+                &config,
+            )
+            .unwrap();
+        }
+        db.delete(b"key042", &config).unwrap();
+        db.wait_for_background_work().unwrap();
+
+        assert!(db.lock().versions.num_level_files(0) > 0);
+        assert_eq!(1, count_files(&db, FileType::LogFile));
+        assert_eq!(num_live_files(&db), count_files(&db, FileType::TableFile));
+        for i in 0..100 {
+            let expected = if i == 42 {
+                "NOT_FOUND".to_string()
+            } else {
+                value(i)
+            };
+            assert_eq!(expected, get(&db, format!("key{:03}", i).as_bytes()));
+        }
+        assert_eq!("NOT_FOUND", get(&db, b"key100"));
+
+        drop(db);
+        let db = DB::open(&dbname, &small_buffer).unwrap();
+        assert_eq!(value(7), get(&db, b"key007"));
+        assert_eq!(value(99), get(&db, b"key099"));
+        assert_eq!("NOT_FOUND", get(&db, b"key042"));
+        assert_eq!(1, count_files(&db, FileType::DescriptorFile));
+    }
+
+    #[test]
+    fn test_db_immutable_memtable() {
+        let env = Arc::new(MemEnv::with_background_threads(1));
+        let db = DB::open(
+            "/mem/db",
+            &DBConfig {
+                env: env.clone(),
+                write_buffer_size: 10000,
+                ..config()
+            },
+        )
+        .unwrap();
+        // The Env has one background thread, so the flush waits for this
+        let (release, hold) = mpsc::channel::<()>();
+        env.schedule(Box::new(move || {
+            let _ = hold.recv();
+        }));
+
+        // Filling the memtable hands it to the flush, but does not wait
+        let config = WriteConfig::default();
+        let value = |i: usize| format!("{:0>1000}", i);
+        for i in 0..12 {
+            db.put(
+                format!("key{:02}", i).as_bytes(),
+                value(i).as_bytes(),
+                &config,
+            )
+            .unwrap();
+        }
+        assert_eq!(
+            "1",
+            db.get_property("rebeldb.num-immutable-mem-table").unwrap()
+        );
+        assert_eq!(0, db.lock().versions.num_level_files(0));
+        assert_eq!(2, count_files(&db, FileType::LogFile));
+
+        // Reads see both memtables
+        db.delete(b"key03", &config).unwrap();
+        for i in 0..12 {
+            let expected = if i == 3 {
+                "NOT_FOUND".to_string()
+            } else {
+                value(i)
+            };
+            assert_eq!(expected, get(&db, format!("key{:02}", i).as_bytes()));
+        }
+        let mut iter = db.iter(&ReadConfig::default());
+        assert_eq!(11, contents(iter.as_mut(), false).len());
+        drop(iter);
+
+        release.send(()).unwrap();
+        db.flush().unwrap();
+        assert_eq!(
+            "0",
+            db.get_property("rebeldb.num-immutable-mem-table").unwrap()
+        );
+        assert_eq!(2, db.lock().versions.num_level_files(0));
+        assert_eq!(1, count_files(&db, FileType::LogFile));
+        assert_eq!(value(11), get(&db, b"key11"));
+        assert_eq!("NOT_FOUND", get(&db, b"key03"));
+    }
+
+    #[test]
+    fn test_db_major_compaction() {
+        let dbname = test_dir("db_major_compaction");
+        let small_files = DBConfig {
+            write_buffer_size: 10000,
+            max_file_size: 20000,
+            ..config()
+        };
+        let db = DB::open(&dbname, &small_files).unwrap();
+        let config = WriteConfig::default();
+        let value = |i: usize, round: usize| format!("{}{:0>500}", round, i);
+        for round in 0..3 {
+            for i in 0..200 {
+                db.put(
+                    format!("key{:03}", i).as_bytes(),
+                    value(i, round).as_bytes(),
+                    &config,
+                )
+                .unwrap();
+            }
+        }
+        for i in (0..200).step_by(10) {
+            db.delete(format!("key{:03}", i).as_bytes(), &config)
+                .unwrap();
+        }
+        db.wait_for_background_work().unwrap();
+
+        // Level-0 was compacted into the next level as it filled up
+        assert!(db.lock().versions.num_level_files(0) < L0_COMPACTION_TRIGGER);
+        assert!(db.lock().versions.num_level_files(1) > 0);
+        assert!(db.lock().versions.current().compaction_score() < 1.);
+        assert_eq!(num_live_files(&db), count_files(&db, FileType::TableFile));
+
+        // Overwritten values were dropped: three rounds of 100KB each
+        // would take more than 300KB if kept
+        let total_bytes: u64 = (0..NUM_LEVELS)
+            .map(|level| db.lock().versions.num_level_bytes(level))
+            .sum();
+        assert!(total_bytes < 250_000);
+
+        let check = |db: &DB| {
+            for i in 0..200 {
+                let expected = if i % 10 == 0 {
+                    "NOT_FOUND".to_string()
+                } else {
+                    value(i, 2)
+                };
+                assert_eq!(expected, get(db, format!("key{:03}", i).as_bytes()));
+            }
+        };
+        check(&db);
+
+        // Flushes and compactions are accounted to the level they wrote
+        let stats = db.statistics();
+        assert!(stats.levels[0].compaction.bytes_written > 0);
+        assert_eq!(0, stats.levels[0].compaction.bytes_read);
+        assert!(stats.levels[1].compaction.bytes_read > 0);
+        assert!(stats.levels[1].compaction.bytes_written > 0);
+        assert!(db
+            .get_property("rebeldb.stats")
+            .unwrap()
+            .contains("Write(MB)"));
+
+        // The statistics cover the lifetime of the database, across
+        // reopens and MANIFEST rewrites
+        drop(db);
+        let db = DB::open(&dbname, &small_files).unwrap();
+        db.wait_for_background_work().unwrap();
+        check(&db);
+        let reopened = db.statistics();
+        // Whether the flush on reopen starts another compaction into
+        // level 1 depends on the shape the background compactions left
+        assert!(
+            reopened.levels[1].compaction.bytes_written >= stats.levels[1].compaction.bytes_written
+        );
+        assert!(
+            reopened.levels[0].compaction.bytes_written >= stats.levels[0].compaction.bytes_written
+        );
+        drop(db);
+        let db = DB::open(&dbname, &small_files).unwrap();
+        db.wait_for_background_work().unwrap();
+        assert_eq!(
+            reopened.levels[1].compaction,
+            db.statistics().levels[1].compaction
+        );
+    }
+
+    #[test]
+    fn test_db_write_stall() {
+        let db = DB::open(
+            &test_dir("db_write_stall"),
+            &DBConfig {
+                write_buffer_size: 10000,
+                l0_slowdown_writes_trigger: 1,
+                l0_stop_writes_trigger: 2,
+                ..config()
+            },
+        )
+        .unwrap();
+        let config = WriteConfig::default();
+        let value = [b'v'; 1000];
+        let mut i = 0;
+        while db.lock().versions.num_level_files(0) == 0 {
+            db.put(format!("key{:03}", i).as_bytes(), &value, &config)
+                .unwrap();
+            i += 1;
+        }
+
+        // Each write is slowed down once level-0 reaches the slowdown
+        // trigger
+        let start = Instant::now();
+        db.put(b"slow", &value, &config).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(1));
+
+        // Hitting the stop trigger waits for a compaction of level-0 in the
+        // background, even though level-0 is below L0_COMPACTION_TRIGGER
+        while db.lock().versions.num_level_files(1) == 0 {
+            assert!(db.lock().versions.num_level_files(0) < 2);
+            db.put(format!("key{:03}", i).as_bytes(), &value, &config)
+                .unwrap();
+            i += 1;
+        }
+        assert!(db.lock().versions.num_level_files(0) < 2);
+        for j in 0..i {
+            assert_eq!(1000, get(&db, format!("key{:03}", j).as_bytes()).len());
+        }
+    }
+
+    #[derive(Default)]
+    struct RecordingListener {
+        flushes: Mutex<Vec<FlushJobInfo>>,
+        compactions: Mutex<Vec<CompactionJobInfo>>,
+        created: Mutex<Vec<TableFileCreationInfo>>,
+        deleted: Mutex<Vec<TableFileDeletionInfo>>,
+        wals: Mutex<Vec<(bool, WalFileInfo)>>,
+        errors: Mutex<Vec<BackgroundErrorInfo>>,
+    }
+
+    impl EventListener for RecordingListener {
+        fn on_flush_completed(&self, info: &FlushJobInfo) {
+            self.flushes.lock().unwrap().push(info.clone());
+        }
+
+        fn on_compaction_completed(&self, info: &CompactionJobInfo) {
+            self.compactions.lock().unwrap().push(info.clone());
+        }
+
+        fn on_table_file_created(&self, info: &TableFileCreationInfo) {
+            self.created.lock().unwrap().push(info.clone());
+        }
+
+        fn on_table_file_deleted(&self, info: &TableFileDeletionInfo) {
+            self.deleted.lock().unwrap().push(info.clone());
+        }
+
+        fn on_wal_file_created(&self, info: &WalFileInfo) {
+            self.wals.lock().unwrap().push((true, info.clone()));
+        }
+
+        fn on_wal_file_deleted(&self, info: &WalFileInfo) {
+            self.wals.lock().unwrap().push((false, info.clone()));
+        }
+
+        fn on_background_error(&self, info: &BackgroundErrorInfo) {
+            self.errors.lock().unwrap().push(info.clone());
+        }
+    }
+
+    #[test]
+    fn test_db_event_listener() {
+        let listener = Arc::new(RecordingListener::default());
+        let db = DB::open(
+            &test_dir("db_event_listener"),
+            &config()
+                .write_buffer_size(10000)
+                .add_event_listener(listener.clone()),
+        )
+        .unwrap();
+        let first_log = db.lock().logfile_number;
+        for i in 0..100 {
+            db.put(
+                format!("key{:03}", i % 40).as_bytes(),
+                &[b'v'; 1000],
+                &WriteConfig::default(),
+            )
+            .unwrap();
+        }
+        db.compact_range(None, None).unwrap();
+
+        let created = listener.created.lock().unwrap().clone();
+        let created_by = |reason| -> BTreeSet<u64> {
+            created
+                .iter()
+                .filter(|info| info.reason == reason)
+                .map(|info| info.file_number)
+                .collect()
+        };
+        let flushes = listener.flushes.lock().unwrap().clone();
+        assert!(!flushes.is_empty());
+        for flush in &flushes {
+            assert!(flush.status.is_ok());
+            assert_eq!(DEFAULT_COLUMN_FAMILY_NAME, flush.column_family);
+            assert!(flush.num_entries > 0);
+            assert!(
+                created_by(TableFileCreationReason::Flush).contains(&flush.file_number.unwrap())
+            );
+        }
+        let compactions = listener.compactions.lock().unwrap().clone();
+        let manual = compactions
+            .iter()
+            .find(|info| info.is_manual && !info.is_trivial_move)
+            .unwrap();
+        assert!(manual.status.is_ok());
+        assert_eq!(manual.level + 1, manual.output_level);
+        assert!(manual.input_bytes > 0 && manual.output_bytes > 0);
+        let compaction_outputs = created_by(TableFileCreationReason::Compaction);
+        assert!(manual
+            .output_files
+            .iter()
+            .all(|number| compaction_outputs.contains(number)));
+        assert!(created.iter().all(|info| info.file_size > 0
+            && info.file_path
+                == table_file_name_for(
+                    &db.lock().dbname,
+                    info.file_number,
+                    db.config.table_file_extension
+                )));
+
+        // The inputs of the manual compaction are gone
+        let deleted: BTreeSet<_> = listener
+            .deleted
+            .lock()
+            .unwrap()
+            .iter()
+            .inspect(|info| assert!(info.status.is_ok()))
+            .map(|info| info.file_number)
+            .collect();
+        assert!(manual
+            .input_files
+            .iter()
+            .all(|number| deleted.contains(number)));
+        assert_eq!(num_live_files(&db), count_files(&db, FileType::TableFile));
+
+        // The first log was deleted once its entries were flushed
+        let wals = listener.wals.lock().unwrap().clone();
+        assert_eq!((true, first_log), (wals[0].0, wals[0].1.log_number));
+        assert!(wals
+            .iter()
+            .any(|(created, info)| !created && info.log_number == first_log));
+
+        db.lock().record_background_error(
+            BackgroundErrorReason::Flush,
+            Error::io_error("flush failed"),
+        );
+        let errors = listener.errors.lock().unwrap().clone();
+        assert_eq!(1, errors.len());
+        assert_eq!(BackgroundErrorReason::Flush, errors[0].reason);
+        assert!(errors[0].error.is_io_error());
+    }
+
+    #[test]
+    fn test_db_compact_range() {
+        let db = DB::open(
+            &test_dir("db_compact_range"),
+            &DBConfig {
+                write_buffer_size: 10000,
+                max_file_size: 20000,
+                ..config()
+            },
+        )
+        .unwrap();
+        let config = WriteConfig::default();
+        let value = |i: usize| format!("{:0>500}", i);
+        for i in 0..200 {
+            db.put(
+                format!("key{:03}", i).as_bytes(),
+                value(i).as_bytes(),
+                &config,
+            )
+            .unwrap();
+        }
+        db.compact_range(None, None).unwrap();
+        assert_eq!(0, db.lock().versions.num_level_files(0));
+        assert!(db.lock().versions.num_level_files(1) > 0);
+        assert_eq!(value(0), get(&db, b"key000"));
+
+        // Compacting a range after deleting it reclaims its space
+        let bytes = |db: &DB| -> u64 {
+            (0..NUM_LEVELS)
+                .map(|level| db.lock().versions.num_level_bytes(level))
+                .sum()
+        };
+        let before = bytes(&db);
+        for i in 50..150 {
+            db.delete(format!("key{:03}", i).as_bytes(), &config)
+                .unwrap();
+        }
+        db.compact_range(Some(b"key050"), Some(b"key149")).unwrap();
+        assert!(bytes(&db) < before * 2 / 3);
+        assert_eq!(0, db.lock().versions.num_level_files(0));
+        assert_eq!(num_live_files(&db), count_files(&db, FileType::TableFile));
+        for i in 0..200 {
+            let expected = if (50..150).contains(&i) {
+                "NOT_FOUND".to_string()
+            } else {
+                value(i)
+            };
+            assert_eq!(expected, get(&db, format!("key{:03}", i).as_bytes()));
+        }
+
+        // Nothing to do for a range without data
+        db.compact_range(Some(b"zzz"), None).unwrap();
+        assert_eq!(value(7), get(&db, b"key007"));
+    }
+
+    #[test]
+    fn test_db_delete_range() {
+        let dbname = test_dir("db_delete_range");
+        let small_files = DBConfig {
+            write_buffer_size: 10000,
+            max_file_size: 20000,
+            ..config()
+        };
+        let db = DB::open(&dbname, &small_files).unwrap();
+        let config = WriteConfig::default();
+        let value = |i: usize| format!("{:0>500}", i);
+        for i in 0..200 {
+            db.put(
+                format!("key{:03}", i).as_bytes(),
+                value(i).as_bytes(),
+                &config,
+            )
+            .unwrap();
+        }
+        db.compact_range(None, None).unwrap();
+        let bytes = |db: &DB| -> u64 {
+            (0..NUM_LEVELS)
+                .map(|level| db.lock().versions.num_level_bytes(level))
+                .sum()
+        };
+        let before = bytes(&db);
+
+        let snapshot = db.get_snapshot();
+        db.delete_range(b"key050", b"key150", &config).unwrap();
+        db.put(b"key100", b"new", &config).unwrap();
+        // An empty range deletes nothing
+        db.delete_range(b"key010", b"key010", &config).unwrap();
+
+        let check = |db: &DB| {
+            for i in 0..200 {
+                let expected = match i {
+                    100 => "new".to_string(),
+                    50..150 => "NOT_FOUND".to_string(),
+                    _ => value(i),
+                };
+                assert_eq!(expected, get(db, format!("key{:03}", i).as_bytes()));
+            }
+            let mut iter = db.iter(&ReadConfig::default());
+            let entries = contents(iter.as_mut(), false);
+            assert_eq!(101, entries.len());
+            assert_eq!(b"key049", entries[49].0.as_slice());
+            assert_eq!(b"key100", entries[50].0.as_slice());
+            assert_eq!(b"key150", entries[51].0.as_slice());
+            let mut reversed = contents(iter.as_mut(), true);
+            reversed.reverse();
+            assert_eq!(entries, reversed);
+            iter.seek(b"key060");
+            assert_eq!(b"key100", iter.key());
+        };
+        let check_snapshot = |db: &DB, snapshot: &Arc<Snapshot>| {
+            let at = ReadConfig {
+                snapshot: Some(snapshot.clone()),
+                ..Default::default()
+            };
+            assert_eq!(value(70).as_bytes(), db.get(b"key070", &at).unwrap());
+            assert_eq!(value(100).as_bytes(), db.get(b"key100", &at).unwrap());
+            let mut iter = db.iter(&at);
+            assert_eq!(200, contents(iter.as_mut(), false).len());
+        };
+
+        // In the memtable
+        check(&db);
+        check_snapshot(&db, &snapshot);
+
+        // In a level-0 table
+        db.flush().unwrap();
+        assert!(db.lock().versions.num_level_files(0) > 0);
+        check(&db);
+        check_snapshot(&db, &snapshot);
+
+        // Compactions keep the tombstone and what it covers while a
+        // snapshot can see the covered keys
+        db.compact_range(None, None).unwrap();
+        assert_eq!(0, db.lock().versions.num_level_files(0));
+        check(&db);
+        check_snapshot(&db, &snapshot);
+
+        // and then drop both, once level-1 is compacted again
+        db.release_snapshot(snapshot);
+        db.put(b"key000", value(0).as_bytes(), &config).unwrap();
+        db.put(b"key199", value(199).as_bytes(), &config).unwrap();
+        db.compact_range(None, None).unwrap();
+        check(&db);
+        assert!(bytes(&db) < before * 2 / 3);
+        assert_eq!(num_live_files(&db), count_files(&db, FileType::TableFile));
+
+        drop(db);
+        let db = DB::open(&dbname, &small_files).unwrap();
+        check(&db);
+    }
+
+    /// Removes the values "expired" and upper-cases those starting with
+    /// "old:", dropping the prefix
+    struct MigratingFilter;
+
+    impl CompactionFilter for MigratingFilter {
+        fn name(&self) -> &str {
+            "MigratingFilter"
+        }
+
+        fn filter(&self, _level: usize, _key: &[u8], existing_value: &[u8]) -> CompactionDecision {
+            if existing_value == b"expired" {
+                CompactionDecision::Remove
+            } else if let Some(value) = existing_value.strip_prefix(b"old:") {
+                CompactionDecision::ChangeValue(value.to_ascii_uppercase())
+            } else {
+                CompactionDecision::Keep
+            }
+        }
+    }
+
+    #[test]
+    fn test_db_compaction_filter() {
+        let db = DB::open(
+            &test_dir("db_compaction_filter"),
+            &config().compaction_filter(Arc::new(MigratingFilter)),
+        )
+        .unwrap();
+        let config = WriteConfig::default();
+        db.put(b"a", b"v", &config).unwrap();
+        db.put(b"b", b"expired", &config).unwrap();
+        db.put(b"c", b"old:x", &config).unwrap();
+        db.put(b"d", b"base", &config).unwrap();
+        db.compact_range(None, None).unwrap();
+        assert_eq!("v", get(&db, b"a"));
+        assert_eq!("NOT_FOUND", get(&db, b"b"));
+        assert_eq!("X", get(&db, b"c"));
+        assert_eq!("base", get(&db, b"d"));
+
+        // The older value stays hidden when the newest one is removed
+        db.put(b"d", b"expired", &config).unwrap();
+        db.compact_range(None, None).unwrap();
+        assert_eq!("NOT_FOUND", get(&db, b"d"));
+
+        // Values that snapshots see are left alone
+        db.put(b"e", b"expired", &config).unwrap();
+        db.put(b"f", b"base", &config).unwrap();
+        let snapshot = db.get_snapshot();
+        db.put(b"f", b"expired", &config).unwrap();
+        db.compact_range(None, None).unwrap();
+        assert_eq!("expired", get(&db, b"e"));
+        assert_eq!("NOT_FOUND", get(&db, b"f"));
+        let read_config = ReadConfig::default().snapshot(snapshot.clone());
+        assert_eq!(b"base", db.get(b"f", &read_config).unwrap().as_slice());
+        db.release_snapshot(snapshot);
+        // Overlaps the table holding "e", so that it is rewritten
+        db.put(b"a", b"v", &config).unwrap();
+        db.put(b"g", b"v", &config).unwrap();
+        db.compact_range(None, None).unwrap();
+        assert_eq!("NOT_FOUND", get(&db, b"e"));
+        assert_eq!("NOT_FOUND", get(&db, b"f"));
+        let mut iter = db.iter(&ReadConfig::default());
+        let keys: Vec<_> = contents(iter.as_mut(), false)
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(vec![b"a".to_vec(), b"c".to_vec(), b"g".to_vec()], keys);
+    }
+
+    #[test]
+    fn test_db_with_ttl() {
+        let dbname = test_dir("db_with_ttl");
+        let db = DBWithTTL::open(
+            &dbname,
+            &config().compaction_filter(Arc::new(MigratingFilter)),
+            Duration::from_secs(3600),
+        )
+        .unwrap();
+        let config = WriteConfig::default();
+        db.put(b"a", b"va", &config).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.put(b"b", b"old:vb").unwrap();
+        batch.put(b"c", b"vc").unwrap();
+        db.write(&batch, &config).unwrap();
+        // Written two hours ago
+        let two_hours_ago = (SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            - 7200) as u32;
+        let mut expired = b"vd".to_vec();
+        expired.extend_from_slice(&two_hours_ago.to_le_bytes());
+        db.db().put(b"d", &expired, &config).unwrap();
+        assert_eq!(
+            b"va",
+            db.get(b"a", &ReadConfig::default()).unwrap().as_slice()
+        );
+        assert_eq!(6, db.db().get(b"a", &ReadConfig::default()).unwrap().len());
+        // Not expired before a compaction
+        assert_eq!(
+            b"vd",
+            db.get(b"d", &ReadConfig::default()).unwrap().as_slice()
+        );
+
+        db.db().compact_range(None, None).unwrap();
+        assert!(db
+            .get(b"d", &ReadConfig::default())
+            .unwrap_err()
+            .is_not_found());
+        let mut iter = db.iter(&ReadConfig::default());
+        let expected: Vec<(Vec<u8>, Vec<u8>)> = [("a", "va"), ("b", "VB"), ("c", "vc")]
+            .iter()
+            .map(|(key, value)| (key.as_bytes().to_vec(), value.as_bytes().to_vec()))
+            .collect();
+        assert_eq!(expected, contents(iter.as_mut(), false));
+        drop(iter);
+
+        let mut batch = WriteBatch::new();
+        batch.merge(b"a", b"x").unwrap();
+        assert!(db.write(&batch, &config).unwrap_err().is_invalid_argument());
+        db.delete(b"a", &config).unwrap();
+        assert!(db
+            .get(b"a", &ReadConfig::default())
+            .unwrap_err()
+            .is_not_found());
+        assert_eq!(Duration::from_secs(3600), db.ttl());
+    }
+
+    #[test]
+    fn test_db_merge() {
+        let dbname = test_dir("db_merge");
+        let without_merge = config();
+        let with_merge = DBConfig {
+            merge_operator: Some(Arc::new(StringAppendOperator::new(b','))),
+            ..config()
+        };
+        let config = WriteConfig::default();
+        let db = DB::open(&dbname, &without_merge).unwrap();
+        assert!(db
+            .merge(b"a", b"x", &config)
+            .unwrap_err()
+            .is_not_supported());
+        drop(db);
+
+        let db = DB::open(&dbname, &with_merge).unwrap();
+        db.put(b"a", b"x", &config).unwrap();
+        db.merge(b"a", b"y", &config).unwrap();
+        let snapshot = db.get_snapshot();
+        db.merge(b"a", b"z", &config).unwrap();
+        db.merge(b"b", b"1", &config).unwrap();
+        db.merge(b"b", b"2", &config).unwrap();
+        db.put(b"c", b"v", &config).unwrap();
+        db.put(b"d", b"old", &config).unwrap();
+        db.delete(b"d", &config).unwrap();
+        db.merge(b"d", b"new", &config).unwrap();
+        db.merge(b"e", b"gone", &config).unwrap();
+        db.delete_range(b"e", b"f", &config).unwrap();
+
+        let check = |db: &DB| {
+            assert_eq!("x,y,z", get(db, b"a"));
+            assert_eq!("1,2", get(db, b"b"));
+            assert_eq!("v", get(db, b"c"));
+            assert_eq!("new", get(db, b"d"));
+            assert_eq!("NOT_FOUND", get(db, b"e"));
+            let mut iter = db.iter(&ReadConfig::default());
+            let entries = contents(iter.as_mut(), false);
+            let expected: Vec<(Vec<u8>, Vec<u8>)> =
+                [("a", "x,y,z"), ("b", "1,2"), ("c", "v"), ("d", "new")]
+                    .iter()
+                    .map(|(key, value)| (key.as_bytes().to_vec(), value.as_bytes().to_vec()))
+                    .collect();
+            assert_eq!(expected, entries);
+            let mut reversed = contents(iter.as_mut(), true);
+            reversed.reverse();
+            assert_eq!(entries, reversed);
+            // Change direction on merged entries
+            iter.seek(b"b");
+            assert_eq!(b"1,2", iter.value());
+            iter.prev();
+            assert_eq!(b"x,y,z", iter.value());
+            iter.next();
+            assert_eq!(b"b", iter.key());
+            iter.seek_to_last();
+            iter.prev();
+            iter.next();
+            assert_eq!(b"new", iter.value());
+        };
+        let check_snapshot = |db: &DB, snapshot: &Arc<Snapshot>| {
+            let at = ReadConfig {
+                snapshot: Some(snapshot.clone()),
+                ..Default::default()
+            };
+            assert_eq!(b"x,y".to_vec(), db.get(b"a", &at).unwrap());
+            assert!(db.get(b"b", &at).unwrap_err().is_not_found());
+        };
+        let num_entries = |db: &DB, user_key: &[u8]| {
+            let sources = db
+                .lock()
+                .read_sources(DEFAULT_COLUMN_FAMILY, &ReadConfig::default());
+            let mut iter = db.new_internal_iterator(&sources, &ReadConfig::default());
+            iter.seek_to_first();
+            let mut count = 0;
+            while iter.valid() {
+                count += usize::from(extract_user_key(iter.key()) == user_key);
+                iter.next();
+            }
+            count
+        };
+
+        // In the memtable
+        check(&db);
+        check_snapshot(&db, &snapshot);
+
+        // In a level-0 table
+        db.flush().unwrap();
+        check(&db);
+        check_snapshot(&db, &snapshot);
+
+        // Compactions keep the operands a snapshot sees apart
+        db.compact_range(None, None).unwrap();
+        check(&db);
+        check_snapshot(&db, &snapshot);
+        assert_eq!(2, num_entries(&db, b"a"));
+        assert_eq!(2, num_entries(&db, b"b"));
+
+        // and merge them once no snapshot does
+        db.release_snapshot(snapshot);
+        db.put(b"0", b"", &config).unwrap();
+        db.put(b"z", b"", &config).unwrap();
+        db.compact_range(None, None).unwrap();
+        assert_eq!(1, num_entries(&db, b"a"));
+        assert_eq!(1, num_entries(&db, b"b"));
+        db.delete(b"0", &config).unwrap();
+        db.delete(b"z", &config).unwrap();
+        check(&db);
+
+        drop(db);
+        let db = DB::open(&dbname, &with_merge).unwrap();
+        check(&db);
+        drop(db);
+
+        // Reads of merge operands fail without a merge operator
+        let db = DB::open(&dbname, &without_merge).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.merge(b"c", b"w").unwrap();
+        db.write(&mut batch, &config).unwrap();
+        assert!(db
+            .get(b"c", &ReadConfig::default())
+            .unwrap_err()
+            .is_invalid_argument());
+        let mut iter = db.iter(&ReadConfig::default());
+        iter.seek(b"c");
+        assert!(!iter.valid());
+        assert!(iter.status().unwrap_err().is_invalid_argument());
+    }
+
+    #[test]
+    fn test_db_checkpoint() {
+        let dbname = test_dir("db_checkpoint");
+        let checkpoint = format!("{}-checkpoint", dbname);
+        let _ = destroy_db(&checkpoint, &config());
+        let small_buffer = DBConfig {
+            write_buffer_size: 10000,
+            ..config()
+        };
+        let db = DB::open(&dbname, &small_buffer).unwrap();
+        let config = WriteConfig::default();
+        let value = |i: usize, round: usize| format!("{:0>100}", i * round);
+        for i in 0..200 {
+            db.put(
+                format!("key{:03}", i).as_bytes(),
+                value(i, 1).as_bytes(),
+                &config,
+            )
+            .unwrap();
+        }
+        db.delete(b"key007", &config).unwrap();
+        db.checkpoint(&checkpoint).unwrap();
+        assert!(db
+            .checkpoint(&checkpoint)
+            .unwrap_err()
+            .is_invalid_argument());
+
+        // Later writes don't show up in the checkpoint
+        for i in 0..200 {
+            db.put(
+                format!("key{:03}", i).as_bytes(),
+                value(i, 2).as_bytes(),
+                &config,
+            )
+            .unwrap();
+        }
+        db.compact_range(None, None).unwrap();
+        drop(db);
+
+        let db = DB::open(&checkpoint, &small_buffer).unwrap();
+        for i in 0..200 {
+            let expected = match i {
+                7 => "NOT_FOUND".to_string(),
+                _ => value(i, 1),
+            };
+            assert_eq!(expected, get(&db, format!("key{:03}", i).as_bytes()));
+        }
+        assert_eq!(num_live_files(&db), count_files(&db, FileType::TableFile));
+        drop(db);
+        destroy_db(&checkpoint, &small_buffer).unwrap();
+
+        let db = DB::open(&dbname, &small_buffer).unwrap();
+        assert_eq!(value(7, 2), get(&db, b"key007"));
+    }
+
+    /// Write a table of "entries", with internal keys at "sequence", to
+    /// "fname".  A None value makes a deletion.
+    fn write_external_file(db: &DB, fname: &str, sequence: u64, entries: &[(&str, Option<&str>)]) {
+        let file = db
+            .lock()
+            .env
+            .new_writable_file(fname, WriteHint::Other)
+            .unwrap();
+        let mut builder = TableBuilder::new(&db.config, file);
+        for (key, value) in entries {
+            let type_ = match value {
+                Some(_) => ValueType::Value,
+                None => ValueType::Deletion,
+            };
+            let key = InternalKey::new(key.as_bytes(), sequence, type_);
+            builder
+                .add(key.encode(), value.unwrap_or_default().as_bytes())
+                .unwrap();
+        }
+        builder.finish().unwrap();
+        builder.file().close().unwrap();
+    }
+
+    #[test]
+    fn test_db_ingest_external_file() {
+        let dbname = test_dir("db_ingest_external_file");
+        let db = DB::open(&dbname, &config()).unwrap();
+        let config = WriteConfig::default();
+        db.put(b"b", b"old", &config).unwrap();
+        db.put(b"c", b"old", &config).unwrap();
+        db.put(b"m", b"mem", &config).unwrap();
+        let snapshot = db.get_snapshot();
+
+        let external = |name: &str| format!("{}/external-{}", dbname, name);
+        write_external_file(
+            &db,
+            &external("1"),
+            0,
+            &[
+                ("a", Some("1")),
+                ("b", Some("2")),
+                ("c", None),
+                ("d", Some("4")),
+            ],
+        );
+        write_external_file(
+            &db,
+            &external("2"),
+            0,
+            &[("x", Some("9")), ("y", Some("10"))],
+        );
+        db.ingest_external_file(&[&external("2"), &external("1")], &IngestConfig::default())
+            .unwrap();
+
+        let check = |db: &DB| {
+            assert_eq!("1", get(db, b"a"));
+            assert_eq!("2", get(db, b"b"));
+            assert_eq!("NOT_FOUND", get(db, b"c"));
+            assert_eq!("4", get(db, b"d"));
+            assert_eq!("mem", get(db, b"m"));
+            assert_eq!("9", get(db, b"x"));
+            assert_eq!("10", get(db, b"y"));
+        };
+        check(&db);
+        // The snapshot predates the ingestion
+        let at = ReadConfig::default().snapshot(snapshot.clone());
+        assert_eq!(b"old".to_vec(), db.get(b"b", &at).unwrap());
+        assert_eq!(b"old".to_vec(), db.get(b"c", &at).unwrap());
+        assert!(db.get(b"x", &at).unwrap_err().is_not_found());
+        db.release_snapshot(snapshot);
+        // Nothing else holds the keys of the second file
+        assert_eq!(1, db.lock().versions.num_level_files(NUM_LEVELS - 1));
+        assert!(db.lock().env.file_exists(&external("1")));
+
+        // Files that overlap each other or hold a key twice are rejected
+        write_external_file(&db, &external("3"), 0, &[("a", Some("")), ("e", Some(""))]);
+        assert!(db
+            .ingest_external_file(&[&external("1"), &external("3")], &IngestConfig::default())
+            .unwrap_err()
+            .is_invalid_argument());
+        let fname = external("4");
+        let file = db
+            .lock()
+            .env
+            .new_writable_file(&fname, WriteHint::Other)
+            .unwrap();
+        let mut builder = TableBuilder::new(&db.config, file);
+        for sequence in [2, 1] {
+            let key = InternalKey::new(b"k", sequence, ValueType::Value);
+            builder.add(key.encode(), b"").unwrap();
+        }
+        builder.finish().unwrap();
+        builder.file().close().unwrap();
+        assert!(db
+            .ingest_external_file(&[&fname], &IngestConfig::default())
+            .unwrap_err()
+            .is_invalid_argument());
+        assert_eq!(num_live_files(&db), count_files(&db, FileType::TableFile));
+        assert_eq!("NOT_FOUND", get(&db, b"k"));
+
+        // Moved files are gone once ingested
+        write_external_file(&db, &external("5"), 0, &[("b", Some("5"))]);
+        db.ingest_external_file(&[&external("5")], &IngestConfig::default().move_files(true))
+            .unwrap();
+        assert!(!db.lock().env.file_exists(&external("5")));
+        assert_eq!("5", get(&db, b"b"));
+
+        drop(db);
+        let db = DB::open(&dbname, &DBConfig::default()).unwrap();
+        assert_eq!("5", get(&db, b"b"));
+        assert_eq!("9", get(&db, b"x"));
+        assert_eq!("NOT_FOUND", get(&db, b"c"));
+    }
+
+    #[test]
+    fn test_db_table_file_extension() {
+        let dbname = test_dir("db_table_file_extension");
+        let with_extension = |extension| DBConfig {
+            write_buffer_size: 10000,
+            table_file_extension: extension,
+            ..config()
+        };
+        let count_extension = |db: &DB, suffix: &str| {
+            let inner = db.lock();
+            inner
+                .env
+                .get_children(&inner.dbname)
+                .unwrap()
+                .iter()
+                .filter(|filename| filename.to_str().unwrap().ends_with(suffix))
+                .count()
+        };
+        let value = |i: usize, round: usize| format!("{}{:0>500}", round, i);
+        let fill = |db: &mut DB, round: usize| {
+            for i in 0..100 {
+                db.put(
+                    format!("key{:03}", i).as_bytes(),
+                    value(i, round).as_bytes(),
+                    &WriteConfig::default(),
+                )
+                .unwrap();
+            }
+        };
+
+        let mut db = DB::open(&dbname, &with_extension(TableFileExtension::Sst)).unwrap();
+        fill(&mut db, 0);
+        assert!(count_extension(&db, ".sst") > 0);
+        assert_eq!(0, count_extension(&db, ".ldb"));
+        drop(db);
+
+        // Tables written with the other extension are still found, and
+        // collected once compacted away
+        let mut db = DB::open(&dbname, &with_extension(TableFileExtension::Ldb)).unwrap();
+        assert_eq!(value(7, 0), get(&db, b"key007"));
+        for round in 1..4 {
+            fill(&mut db, round);
+        }
+        db.wait_for_background_work().unwrap();
+        assert!(count_extension(&db, ".ldb") > 0);
+        assert_eq!(num_live_files(&db), count_files(&db, FileType::TableFile));
+        for i in 0..100 {
+            assert_eq!(value(i, 3), get(&db, format!("key{:03}", i).as_bytes()));
+        }
+    }
+
+    /// Records the hint given for every file created for writing.
+    struct HintRecordingEnv {
+        base: PosixEnv,
+        hints: Mutex<Vec<(String, WriteHint)>>,
+    }
+
+    impl Env for HintRecordingEnv {
+        fn new_sequential_file(&self, fname: &str) -> Result<Box<dyn SequentialFile>> {
+            self.base.new_sequential_file(fname)
+        }
+
+        fn new_random_access_file(&self, fname: &str) -> Result<Box<dyn RandomAccessFile>> {
+            self.base.new_random_access_file(fname)
+        }
+
+        fn new_writable_file(&self, fname: &str, hint: WriteHint) -> Result<Box<dyn WritableFile>> {
+            self.hints.lock().unwrap().push((fname.to_string(), hint));
+            self.base.new_writable_file(fname, hint)
+        }
+
+        fn new_appendable_file(&self, fname: &str) -> Result<Box<dyn WritableFile>> {
+            self.base.new_appendable_file(fname)
+        }
+
+        fn file_exists(&self, fname: &str) -> bool {
+            self.base.file_exists(fname)
+        }
+
+        fn get_children(&self, dname: &str) -> Result<Vec<OsString>> {
+            self.base.get_children(dname)
+        }
+
+        fn remove_file(&self, fname: &str) -> Result<()> {
+            self.base.remove_file(fname)
+        }
+
+        fn create_dir(&self, dname: &str) -> Result<()> {
+            self.base.create_dir(dname)
+        }
+
+        fn remove_dir(&self, dname: &str) -> Result<()> {
+            self.base.remove_dir(dname)
+        }
+
+        fn sync_dir(&self, dname: &str) -> Result<()> {
+            self.base.sync_dir(dname)
+        }
+
+        fn get_file_size(&self, fname: &str) -> Result<u64> {
+            self.base.get_file_size(fname)
+        }
+
+        fn rename_file(&self, src: &str, target: &str) -> Result<()> {
+            self.base.rename_file(src, target)
+        }
+
+        fn lock_file(&self, fname: &str) -> Result<Box<dyn FileLock>> {
+            self.base.lock_file(fname)
+        }
+
+        fn unlock_file(&self, lock: Box<dyn FileLock>) -> Result<()> {
+            self.base.unlock_file(lock)
+        }
+
+        fn new_logger(&self, fname: &str) -> Result<Box<dyn Logger + '_>> {
+            self.base.new_logger(fname)
+        }
+
+        fn schedule(&self, job: Box<dyn FnOnce() + Send>) {
+            self.base.schedule(job)
+        }
+
+        fn start_thread(&self, job: Box<dyn FnOnce() + Send>) {
+            self.base.start_thread(job)
+        }
+    }
+
+    #[test]
+    fn test_db_write_hints() {
+        let env = Arc::new(HintRecordingEnv {
+            base: PosixEnv::new(),
+            hints: Mutex::new(vec![]),
+        });
+        let small_files = DBConfig {
+            env: env.clone(),
+            write_buffer_size: 10000,
+            max_file_size: 20000,
+            ..config()
+        };
+        let db = DB::open(&test_dir("db_write_hints"), &small_files).unwrap();
+        for round in 0..3 {
+            for i in 0..200 {
+                db.put(
+                    format!("key{:03}", i).as_bytes(),
+                    format!("{}{:0>500}", round, i).as_bytes(),
+                    &WriteConfig::default(),
+                )
+                .unwrap();
+            }
+        }
+        assert!(db.lock().versions.num_level_files(1) > 0);
+
+        let hints = env.hints.lock().unwrap();
+        let hints_of = |type_: FileType| -> HashSet<WriteHint> {
+            hints
+                .iter()
+                .filter(|(fname, _)| matches!(parse_file_name(fname), Some((_, t)) if t == type_))
+                .map(|(_, hint)| *hint)
+                .collect()
+        };
+        assert_eq!(HashSet::from([WriteHint::Wal]), hints_of(FileType::LogFile));
+        assert_eq!(
+            HashSet::from([WriteHint::Manifest]),
+            hints_of(FileType::DescriptorFile)
+        );
+        // Flushed tables are hot, compactions into the bottom level cold
+        assert_eq!(
+            HashSet::from([WriteHint::TableHot, WriteHint::TableCold]),
+            hints_of(FileType::TableFile)
+        );
+    }
+
+    #[test]
+    fn test_db_rate_limiter_auto_tune() {
+        let rate_limiter = Arc::new(crate::RateLimiter::new_auto_tuned(1 << 30));
+        for _ in 0..200 {
+            rate_limiter.tune(Duration::ZERO, 0);
+        }
+        let min_rate = rate_limiter.bytes_per_second();
+        let config = DBConfig {
+            write_buffer_size: 10000,
+            compression: CompressionType::None,
+            rate_limiter: Some(rate_limiter.clone()),
+            ..config()
+        };
+        let db = DB::open(&test_dir("db_rate_limiter"), &config).unwrap();
+        for i in 0..50 {
+            db.put(
+                format!("key{:03}", i).as_bytes(),
+                &[b'v'; 1000],
+                &WriteConfig::default(),
+            )
+            .unwrap();
+        }
+
+        // Flushes went through the limiter and stalled the writer
+        db.wait_for_background_work().unwrap();
+        assert!(db.lock().versions.num_level_files(0) > 0);
+        assert!(rate_limiter.total_bytes_through() > 10000);
+        assert!(rate_limiter.bytes_per_second() > min_rate);
+    }
+
+    #[test]
+    fn test_db_group() {
+        let names = ["/mem/db0", "/mem/db1", "/mem/db2", "/mem/db3"];
+        let manager = Arc::new(WriteBufferManager::new(50000));
+        let shared = DBConfig {
+            env: Arc::new(MemEnv::new()),
+            // Only the shared budget makes the memtables flush
+            write_buffer_size: 1 << 20,
+            write_buffer_manager: Some(manager.clone()),
+            ..config()
+        };
+        let mut group = DBGroup::open(&names, &shared).unwrap();
+        assert_eq!(4, group.len());
+        assert!(Arc::ptr_eq(&manager, group.write_buffer_manager()));
+
+        let value = |i: usize| format!("{:0>500}", i);
+        for i in 0..100 {
+            for db in group.dbs_mut() {
+                db.put(
+                    format!("key{:03}", i).as_bytes(),
+                    value(i).as_bytes(),
+                    &WriteConfig::default(),
+                )
+                .unwrap();
+                // Memtables being flushed count until their flushes are
+                // installed, and each DB may switch to a new one meanwhile
+                assert!(manager.memory_usage() <= 50000 + 5 * 4096);
+            }
+        }
+        for db in group.dbs() {
+            assert!(num_live_files(db) > 0);
+            for i in 0..100 {
+                assert_eq!(value(i), get(db, format!("key{:03}", i).as_bytes()));
+            }
+        }
+        // All of them cache blocks in the same cache
+        let usage = group.dbs()[0].table_cache.block_cache_usage();
+        assert!(usage > 0);
+        assert!(group
+            .dbs()
+            .iter()
+            .all(|db| db.table_cache.block_cache_usage() == usage));
+
+        let dbs = group.into_dbs();
+        assert!(manager.memory_usage() > 0);
+        drop(dbs);
+        assert_eq!(0, manager.memory_usage());
+
+        // A database that fails to open fails the group
+        let error = DBGroup::open(&["/mem/db0", "/mem/db0"], &shared)
+            .err()
+            .unwrap();
+        assert!(error.is_io_error());
+        assert_eq!(0, manager.memory_usage());
+        // The one that did open was closed again
+        assert_eq!(1, DBGroup::open(&["/mem/db0"], &shared).unwrap().len());
+    }
+
+    #[test]
+    fn test_db_split_range() {
+        let small_files = DBConfig {
+            write_buffer_size: 10000,
+            max_file_size: 20000,
+            compression: CompressionType::None,
+            ..config()
+        };
+        let db = DB::open(&test_dir("db_split_range"), &small_files).unwrap();
+        let all = Range::new(b"", b"\xff");
+        assert_eq!(
+            vec![(b"".to_vec(), b"\xff".to_vec())],
+            db.split_range(&all, 4)
+        );
+
+        for i in 0..2000 {
+            db.put(
+                format!("key{:04}", i).as_bytes(),
+                &[b'v'; 100],
+                &WriteConfig::default(),
+            )
+            .unwrap();
+        }
+        let count = |start: &[u8], limit: &[u8]| {
+            let mut iter = db.iter(&ReadConfig::default());
+            iter.seek(start);
+            let mut count = 0;
+            while iter.valid() && iter.key() < limit {
+                count += 1;
+                iter.next();
+            }
+            count
+        };
+
+        let ranges = db.split_range(&all, 4);
+        assert_eq!(4, ranges.len());
+        assert_eq!(b"".as_slice(), ranges[0].0);
+        assert_eq!(b"\xff".as_slice(), ranges[3].1);
+        for (i, (start, limit)) in ranges.iter().enumerate() {
+            assert!(start < limit);
+            if i > 0 {
+                assert_eq!(&ranges[i - 1].1, start);
+            }
+            let keys = count(start, limit);
+            assert!((300..=700).contains(&keys), "{} keys in range {}", keys, i);
+        }
+
+        // A part of the key space, and degenerate requests
+        let ranges = db.split_range(&Range::new(b"key1000", b"key1500"), 2);
+        assert_eq!(2, ranges.len());
+        assert_eq!(b"key1000".as_slice(), ranges[0].0);
+        assert_eq!(b"key1500".as_slice(), ranges[1].1);
+        assert_eq!(1, db.split_range(&all, 1).len());
+        assert_eq!(1, db.split_range(&Range::new(b"b", b"a"), 4).len());
+        assert!(db.split_range(&all, 1000).len() <= num_live_files(&db) * 2 + 1);
+    }
+
+    #[test]
+    fn test_db_hottest_keys() {
+        let dbname = test_dir("db_hottest_keys");
+        let db = DB::open(&dbname, &config()).unwrap();
+        for i in 0..100 {
+            db.put(
+                format!("key{:02}", i).as_bytes(),
+                b"v",
+                &WriteConfig::default(),
+            )
+            .unwrap();
+        }
+        get(&db, b"key00");
+        assert!(db.hottest_keys(10).is_empty());
+        drop(db);
+
+        let sampled = DBConfig {
+            hot_key_sample_rate: 1,
+            ..config()
+        };
+        let db = DB::open(&dbname, &sampled).unwrap();
+        for _ in 0..50 {
+            get(&db, b"key07");
+        }
+        for _ in 0..20 {
+            get(&db, b"missing");
+        }
+        // A scan reads every key once; one read in reverse reads key42 too
+        let mut iter = db.iter(&ReadConfig::default());
+        iter.seek_to_first();
+        while iter.valid() {
+            iter.next();
+        }
+        iter.seek(b"key43");
+        iter.prev();
+        assert_eq!(b"key42", iter.key());
+        drop(iter);
+
+        let hottest = db.hottest_keys(3);
+        let keys: Vec<_> = hottest.iter().map(|(key, _)| key.as_slice()).collect();
+        assert_eq!(vec![b"key07".as_slice(), b"missing", b"key42"], keys);
+        assert_eq!(51, hottest[0].1);
+
+        // The reads of the other column families are sampled too
+        let users = db.create_column_family("users").unwrap();
+        for _ in 0..60 {
+            let _ = db.get_cf(&users, b"user", &ReadConfig::default());
+        }
+        assert_eq!(b"user".to_vec(), db.hottest_keys(1)[0].0);
+    }
+
+    #[test]
+    fn test_db_output_partitioner() {
+        let partitioned = DBConfig {
+            write_buffer_size: 10000,
+            // Only the partitioner cuts the outputs
+            max_file_size: 1 << 20,
+            output_partitioner: Some(Arc::new(PrefixPartitioner::new(3))),
+            ..config()
+        };
+        let db = DB::open(&test_dir("db_output_partitioner"), &partitioned).unwrap();
+        let value = |i: usize, round: usize| format!("{}{:0>200}", round, i);
+        for round in 0..3 {
+            for i in 0..100 {
+                for tenant in 0..5 {
+                    db.put(
+                        format!("t{:02}-key{:03}", tenant, i).as_bytes(),
+                        value(i, round).as_bytes(),
+                        &WriteConfig::default(),
+                    )
+                    .unwrap();
+                }
+            }
+        }
+
+        // Every compacted file holds the keys of a single tenant
+        let current = db.lock().versions.current();
+        let mut tenants = HashSet::new();
+        for level in 1..NUM_LEVELS {
+            for file in current.files(level) {
+                let tenant = &file.smallest.user_key()[..3];
+                assert_eq!(tenant, &file.largest.user_key()[..3]);
+                tenants.insert(tenant.to_vec());
+            }
+        }
+        assert_eq!(5, tenants.len());
+        for i in 0..100 {
+            for tenant in 0..5 {
+                let key = format!("t{:02}-key{:03}", tenant, i);
+                assert_eq!(value(i, 2), get(&db, key.as_bytes()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_db_sync_writes() {
+        let env = Arc::new(FaultInjectionEnv::new(Arc::new(MemEnv::new())));
+        let dbname = "/db";
+        let durable = DBConfig {
+            env: env.clone(),
+            sync_dir_after_create: true,
+            ..config()
+        };
+        let sync = WriteConfig {
+            sync: true,
+            ..Default::default()
+        };
+        let db = DB::open(dbname, &durable).unwrap();
+        db.put(b"foo", b"v1", &sync).unwrap();
+        db.put(b"bar", b"v1", &WriteConfig::default()).unwrap();
+        env.set_filesystem_active(false);
+        drop(db);
+        env.crash().unwrap();
+
+        // Only the synced write survives
+        let db = DB::open(dbname, &durable).unwrap();
+        assert_eq!("v1", get(&db, b"foo"));
+        assert_eq!("NOT_FOUND", get(&db, b"bar"));
+
+        // Later logs are synced alike
+        db.put(b"foo", b"v2", &sync).unwrap();
+        env.set_filesystem_active(false);
+        drop(db);
+        env.crash().unwrap();
+        let db = DB::open(dbname, &durable).unwrap();
+        assert_eq!("v2", get(&db, b"foo"));
+    }
+
+    #[test]
+    fn test_db_delete_files_in_range() {
+        let small_files = DBConfig {
+            write_buffer_size: 10000,
+            max_file_size: 20000,
+            compression: CompressionType::None,
+            ..config()
+        };
+        let db = DB::open(&test_dir("db_delete_files_in_range"), &small_files).unwrap();
+        for i in 0..2000 {
+            db.put(
+                format!("key{:04}", i).as_bytes(),
+                &[b'v'; 100],
+                &WriteConfig::default(),
+            )
+            .unwrap();
+        }
+        db.wait_for_background_work().unwrap();
+        let live_before = num_live_files(&db);
+        let range = Range::new(b"key0500", b"key1500");
+        db.delete_files_in_range(&range).unwrap();
+        let live_after = num_live_files(&db);
+        assert!(live_after < live_before);
+        assert_eq!(live_after, count_files(&db, FileType::TableFile));
+
+        // Keys outside the range are untouched; none inside it is left
+        for i in 0..2000 {
+            let key = format!("key{:04}", i);
+            let found = db.get(key.as_bytes(), &ReadConfig::default()).is_ok();
+            assert_eq!(!(500..1500).contains(&i), found, "{}", key);
+        }
+        let mut iter = db.iter(&ReadConfig::default());
+        iter.seek(range.start);
+        assert_eq!(b"key1500", iter.key());
+
+        // Again: only files that compactions left in the range go
+        db.delete_files_in_range(&range).unwrap();
+        assert!(num_live_files(&db) <= live_after);
+        assert_eq!(
+            b"v".repeat(100),
+            db.get(b"key1500", &ReadConfig::default()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_db_delete_files_in_range_partial_overlap() {
+        let db = open("db_delete_files_in_range_partial_overlap");
+        let write = WriteConfig::default();
+        db.put(b"a", b"va", &write).unwrap();
+        db.put(b"b", b"old", &write).unwrap();
+        db.put(b"z", b"vz", &write).unwrap();
+        db.compact_range(None, None).unwrap();
+        db.put(b"b", b"new", &write).unwrap();
+        db.put(b"c", b"vc", &write).unwrap();
+        db.flush().unwrap();
+        db.put(b"bb", b"vbb", &write).unwrap();
+
+        // Straddling files and the memtable keep their keys in the range
+        // only under the tombstone
+        db.delete_files_in_range(&Range::new(b"b", b"d")).unwrap();
+        for key in [&b"b"[..], b"bb", b"c"] {
+            let result = db.get(key, &ReadConfig::default());
+            assert!(result.unwrap_err().is_not_found());
+        }
+        assert_eq!(
+            b"va".to_vec(),
+            db.get(b"a", &ReadConfig::default()).unwrap()
+        );
+        assert_eq!(
+            b"vz".to_vec(),
+            db.get(b"z", &ReadConfig::default()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_db_write_groups() {
+        let db = open("db_write_groups");
+        let mut inner = db.lock();
+        let push = |inner: &mut DBInner, value_size: usize, sync, deadline| {
+            let mut batch = WriteBatch::new();
+            batch.put(b"key", &vec![b'v'; value_size]).unwrap();
+            inner.push_writer(Some(batch), sync, deadline).0
+        };
+        let later = Some(Instant::now() + Duration::from_secs(3600));
+        let a = push(&mut inner, 1, false, None);
+        let b = push(&mut inner, 1, false, None);
+        let c = push(&mut inner, 1, true, None);
+        let d = push(&mut inner, 1, false, None);
+        let e = push(&mut inner, 1, false, later);
+        let f = push(&mut inner, 200 << 10, false, None);
+        let g = push(&mut inner, 1, false, None);
+        inner.push_writer(None, false, None);
+        push(&mut inner, 1, false, None);
+        let take_group = |inner: &mut DBInner| -> Vec<u64> {
+            let group = inner.take_write_group();
+            inner.writers.drain(..group.len());
+            group.into_iter().map(|(id, _)| id).collect()
+        };
+
+        // A sync write ends a non-sync group
+        assert_eq!(vec![a, b], take_group(&mut inner));
+        // A non-sync write joins a sync group, but a write that can't
+        // wait as long as the group might does not
+        assert_eq!(vec![c, d], take_group(&mut inner));
+        // A big write does not fit in a group started by a small one
+        assert_eq!(vec![e], take_group(&mut inner));
+        // An operation ends the group before it
+        assert_eq!(vec![f, g], take_group(&mut inner));
+        inner.writers.clear();
+    }
+
+    #[test]
+    fn test_db_group_commit() {
+        let dbname = test_dir("db_group_commit");
+        let db = Arc::new(DB::open(&dbname, &config()).unwrap());
+        let writers: Vec<_> = (0..4)
+            .map(|t| {
+                let db = db.clone();
+                thread::spawn(move || {
+                    for i in 0..100 {
+                        let mut batch = WriteBatch::new();
+                        batch.put(format!("{}.{}", t, i).as_bytes(), b"v").unwrap();
+                        let config = WriteConfig {
+                            sync: i % 10 == 0,
+                            ..Default::default()
+                        };
+                        db.write(&mut batch, &config).unwrap();
+                        // The batch is given back as it was
+                        assert_eq!(1, batch.count());
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        let inner = db.lock();
+        assert_eq!(400, inner.versions.last_sequence());
+        assert!(inner.writers.is_empty());
+        assert!(inner.finished_writes.is_empty());
+        drop(inner);
+        drop(db);
+
+        let db = DB::open(&dbname, &config()).unwrap();
+        for t in 0..4 {
+            for i in 0..100 {
+                let key = format!("{}.{}", t, i);
+                assert_eq!(
+                    b"v".to_vec(),
+                    db.get(key.as_bytes(), &ReadConfig::default()).unwrap()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_db_shared_across_threads() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<DB>();
+
+        let dbname = test_dir("db_shared_across_threads");
+        let config = DBConfig {
+            write_buffer_size: 10000,
+            ..config()
+        };
+        let db = Arc::new(DB::open(&dbname, &config).unwrap());
+        let threads: Vec<_> = (0..4)
+            .map(|t| {
+                let db = db.clone();
+                thread::spawn(move || {
+                    for i in 0..200 {
+                        let key = format!("{}.{:03}", t, i);
+                        db.put(key.as_bytes(), &[b'x'; 100], &WriteConfig::default())
+                            .unwrap();
+                        assert_eq!(
+                            vec![b'x'; 100],
+                            db.get(key.as_bytes(), &ReadConfig::default()).unwrap()
+                        );
+                        // Reads of the keys of the other threads see
+                        // either nothing or the whole value
+                        let other = format!("{}.{:03}", (t + 1) % 4, i);
+                        match db.get(other.as_bytes(), &ReadConfig::default()) {
+                            Ok(value) => assert_eq!(vec![b'x'; 100], value),
+                            Err(error) => assert!(error.is_not_found()),
+                        }
+                    }
+                    let mut iter = db.iter(&ReadConfig::default());
+                    iter.seek(format!("{}.", t).as_bytes());
+                    for i in 0..200 {
+                        assert_eq!(format!("{}.{:03}", t, i).as_bytes(), iter.key());
+                        iter.next();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(800, db.lock().versions.last_sequence());
+        assert!(count_files(&db, FileType::TableFile) > 0);
+    }
+
+    #[test]
+    fn test_db_reads_without_lock() {
+        let dbname = test_dir("db_reads_without_lock");
+        let db = DB::open(&dbname, &config()).unwrap();
+        db.put(b"foo", b"v1", &WriteConfig::default()).unwrap();
+        db.flush().unwrap();
+        db.put(b"bar", b"v2", &WriteConfig::default()).unwrap();
+
+        // Hold the lock of the DB: reads go through what it published
+        let inner = db.lock();
+        let (sender, receiver) = mpsc::channel();
+        thread::scope(|scope| {
+            let db = &db;
+            scope.spawn(move || {
+                let mut iter = db.iter(&ReadConfig::default());
+                iter.seek_to_first();
+                let mut keys = Vec::new();
+                while iter.valid() {
+                    keys.push(iter.key().to_vec());
+                    iter.next();
+                }
+                let value = db.get(b"foo", &ReadConfig::default());
+                sender.send((value, keys)).unwrap();
+            });
+            let result = receiver.recv_timeout(Duration::from_secs(10));
+            drop(inner);
+            let (value, keys) = result.expect("reads waited for the lock");
+            assert_eq!(b"v1".to_vec(), value.unwrap());
+            assert_eq!(vec![b"bar".to_vec(), b"foo".to_vec()], keys);
+        });
+    }
+
+    #[test]
+    fn test_db_multi_map() {
+        let dbname = test_dir("db_multi_map");
+        assert!(MultiMap::new(&open("db_multi_map_no_operator"))
+            .err()
+            .unwrap()
+            .is_invalid_argument());
+        let config = DBConfig {
+            merge_operator: Some(Arc::new(MultiMapOperator::new())),
+            ..config()
+        };
+        let db = DB::open(&dbname, &config).unwrap();
+        let write = WriteConfig::default();
+        let read = ReadConfig::default();
+        let map = MultiMap::new(&db).unwrap();
+        map.add(b"fruit", b"pear", &write).unwrap();
+        map.add(b"fruit", b"apple", &write).unwrap();
+        map.add(b"fruit", b"apple", &write).unwrap();
+        map.add(b"fruit\x00", b"kiwi", &write).unwrap();
+        map.add(b"veg", b"leek", &write).unwrap();
+        assert_eq!(
+            vec![b"apple".to_vec(), b"pear".to_vec()],
+            map.get(b"fruit", &read).unwrap()
+        );
+        assert!(map.contains(b"fruit", b"pear", &read).unwrap());
+        assert!(!map.contains(b"fruit", b"kiwi", &read).unwrap());
+
+        map.remove(b"fruit", b"pear", &write).unwrap();
+        assert_eq!(vec![b"apple".to_vec()], map.get(b"fruit", &read).unwrap());
+        let pairs: Vec<_> = map.iter(&read).map(Result::unwrap).collect();
+        assert_eq!(
+            vec![
+                (b"fruit".to_vec(), b"apple".to_vec()),
+                (b"fruit\x00".to_vec(), b"kiwi".to_vec()),
+                (b"veg".to_vec(), b"leek".to_vec()),
+            ],
+            pairs
+        );
+
+        map.remove_all(b"fruit\x00", &write).unwrap();
+        assert!(map.get(b"fruit\x00", &read).unwrap().is_empty());
+        assert_eq!(2, map.iter(&read).count());
+
+        // The values merged so far survive compactions
+        map.remove(b"veg", b"leek", &write).unwrap();
+        map.add(b"veg", b"kale", &write).unwrap();
+        db.compact_range(None, None).unwrap();
+        assert_eq!(vec![b"kale".to_vec()], map.get(b"veg", &read).unwrap());
+        assert_eq!(2, map.iter(&read).count());
+
+        // Anything but the map's entries in the database is an error
+        db.put(b"raw", b"\x05", &write).unwrap();
+        assert!(map.iter(&read).last().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_destroy_db() {
+        let dbname = test_dir("destroy_db");
+        let db = DB::open(&dbname, &config()).unwrap();
+        db.put(b"foo", b"v1", &WriteConfig::default()).unwrap();
+        // Files the database doesn't own are left alone
+        write_data_to_file(db.lock().env.clone(), b"mine", &format!("{}/notes", dbname)).unwrap();
+        assert!(destroy_db(&dbname, &config()).is_err(), "the DB is open");
+        drop(db);
+
+        destroy_db(&dbname, &config()).unwrap();
+        let env = PosixEnv::new();
+        let left: Vec<_> = env.get_children(&dbname).unwrap();
+        assert_eq!(vec![OsString::from("notes")], left);
+        assert!(DB::open(&dbname, &DBConfig::default()).is_err());
+
+        env.remove_file(&format!("{}/notes", dbname)).unwrap();
+        destroy_db(&dbname, &config()).unwrap();
+        assert!(!env.file_exists(&dbname));
+        // Destroying a missing database is fine
+        destroy_db(&dbname, &config()).unwrap();
+    }
+
+    #[test]
+    fn test_repair_db() {
+        let dbname = test_dir("repair_db");
+        let small_buffer = DBConfig {
+            write_buffer_size: 10000,
+            ..config()
+        };
+        let db = DB::open(&dbname, &small_buffer).unwrap();
+        for i in 0..1000 {
+            db.put(
+                format!("key{:04}", i).as_bytes(),
+                format!("v{}", i).as_bytes(),
+                &WriteConfig::default(),
+            )
+            .unwrap();
+        }
+        db.delete(b"key0005", &WriteConfig::default()).unwrap();
+        let last_sequence = db.lock().versions.last_sequence();
+        assert!(num_live_files(&db) > 0);
+        drop(db);
+
+        // Lose the MANIFEST
+        let env = PosixEnv::new();
+        for filename in env.get_children(&dbname).unwrap() {
+            let filename = filename.to_str().unwrap();
+            if let Some((_, FileType::DescriptorFile)) = parse_file_name(filename) {
+                env.remove_file(&format!("{}/{}", dbname, filename))
+                    .unwrap();
+            }
+        }
+        assert!(DB::open(&dbname, &small_buffer).is_err());
+
+        repair_db(&dbname, &small_buffer).unwrap();
+        let db = DB::open(&dbname, &small_buffer).unwrap();
+        assert_eq!(last_sequence, db.lock().versions.last_sequence());
+        assert_eq!("NOT_FOUND", get(&db, b"key0005"));
+        for i in (0..1000).filter(|&i| i != 5) {
+            assert_eq!(
+                format!("v{}", i),
+                get(&db, format!("key{:04}", i).as_bytes())
+            );
+        }
+        // The log was converted to a table and put aside
+        assert_eq!(1, count_files(&db, FileType::LogFile));
+        assert!(!env
+            .get_children(&format!("{}/lost", dbname))
+            .unwrap()
+            .is_empty());
+
+        // New writes win over the repaired ones
+        db.put(b"key0001", b"new", &WriteConfig::default()).unwrap();
+        drop(db);
+        let db = DB::open(&dbname, &small_buffer).unwrap();
+        assert_eq!("new", get(&db, b"key0001"));
+    }
+
+    #[test]
+    fn test_db_read_only() {
+        let dbname = test_dir("db_read_only");
+        assert!(DB::open_read_only(&dbname, &config())
+            .err()
+            .unwrap()
+            .is_invalid_argument());
+        let small_buffer = DBConfig {
+            write_buffer_size: 10000,
+            ..config()
+        };
+        let db = DB::open(&dbname, &small_buffer).unwrap();
+        let config = WriteConfig::default();
+        for i in 0..200 {
+            db.put(format!("key{:04}", i).as_bytes(), &[b'v'; 100], &config)
+                .unwrap();
+        }
+        db.delete(b"key0007", &config).unwrap();
+        let users = db.create_column_family("users").unwrap();
+        db.put_cf(&users, b"k", b"users", &config).unwrap();
+
+        // The lock is taken unless skipped
+        assert!(DB::open_read_only(&dbname, &small_buffer)
+            .err()
+            .unwrap()
+            .is_io_error());
+        let skip_lock = DBConfig {
+            skip_lock_when_read_only: true,
+            ..small_buffer.clone()
+        };
+        assert_eq!(
+            "NOT_FOUND",
+            get(
+                &DB::open_read_only(&dbname, &skip_lock).unwrap(),
+                b"key0007"
+            )
+        );
+        drop(db);
+
+        let filenames = |dbname: &str| {
+            let mut filenames = small_buffer.env.get_children(dbname).unwrap();
+            filenames.sort();
+            filenames
+        };
+        let before = filenames(&dbname);
+        let db = DB::open_read_only(&dbname, &small_buffer).unwrap();
+        assert_eq!("NOT_FOUND", get(&db, b"key0007"));
+        assert_eq!(
+            String::from_utf8(vec![b'v'; 100]).unwrap(),
+            get(&db, b"key0199")
+        );
+        let mut iter = db.iter(&ReadConfig::default());
+        assert_eq!(199, contents(iter.as_mut(), false).len());
+        drop(iter);
+        let users = db.column_family("users").unwrap();
+        assert_eq!(
+            b"users",
+            db.get_cf(&users, b"k", &ReadConfig::default())
+                .unwrap()
+                .as_slice()
+        );
+        assert!(db.put(b"a", b"b", &config).unwrap_err().is_not_supported());
+        assert!(db
+            .delete_cf(&users, b"k", &config)
+            .unwrap_err()
+            .is_not_supported());
+        assert!(db.compact_range(None, None).unwrap_err().is_not_supported());
+        assert!(db
+            .drop_column_family(&users)
+            .unwrap_err()
+            .is_not_supported());
+        drop(db);
+        assert_eq!(before, filenames(&dbname));
+    }
+
+    #[test]
+    fn test_db_secondary() {
+        let dbname = test_dir("db_secondary");
+        let secondary_dir = test_dir("db_secondary_instance");
+        let small_buffer = DBConfig {
+            write_buffer_size: 10000,
+            ..config()
+        };
+        let primary = DB::open(&dbname, &small_buffer).unwrap();
+        let config = WriteConfig::default();
+        let value = |i: usize| format!("{:0>100}", i);
+        for i in 0..100 {
+            primary
+                .put(
+                    format!("key{:04}", i).as_bytes(),
+                    value(i).as_bytes(),
+                    &config,
+                )
+                .unwrap();
+        }
+        primary.flush().unwrap();
+        primary.put(b"mem", b"v1", &config).unwrap();
+
+        // Opened without the lock of the primary, with tables and log
+        let secondary = DB::open_as_secondary(&dbname, &secondary_dir, &small_buffer).unwrap();
+        assert_eq!(value(7), get(&secondary, b"key0007"));
+        assert_eq!("v1", get(&secondary, b"mem"));
+        assert!(secondary
+            .put(b"a", b"b", &config)
+            .unwrap_err()
+            .is_not_supported());
+        assert!(secondary.flush().unwrap_err().is_not_supported());
+        assert!(secondary
+            .create_column_family("users")
+            .unwrap_err()
+            .is_not_supported());
+        assert!(primary
+            .try_catch_up_with_primary()
+            .unwrap_err()
+            .is_not_supported());
+
+        // Updates show up once caught up, whether still in the log or
+        // flushed and compacted since
+        primary.put(b"mem", b"v2", &config).unwrap();
+        primary.delete(b"key0007", &config).unwrap();
+        assert_eq!("v1", get(&secondary, b"mem"));
+        secondary.try_catch_up_with_primary().unwrap();
+        assert_eq!("v2", get(&secondary, b"mem"));
+        assert_eq!("NOT_FOUND", get(&secondary, b"key0007"));
+        let snapshot = secondary.get_snapshot();
+        for i in 100..1000 {
+            primary
+                .put(
+                    format!("key{:04}", i).as_bytes(),
+                    value(i).as_bytes(),
+                    &config,
+                )
+                .unwrap();
+        }
+        primary.compact_range(None, None).unwrap();
+        primary.put(b"mem", b"v3", &config).unwrap();
+        let users = primary.create_column_family("users").unwrap();
+        primary.put_cf(&users, b"k", b"users", &config).unwrap();
+        secondary.try_catch_up_with_primary().unwrap();
+        assert_eq!("v3", get(&secondary, b"mem"));
+        let mut iter = secondary.iter(&ReadConfig::default());
+        assert_eq!(1000, contents(iter.as_mut(), false).len());
+        drop(iter);
+        let read_config = ReadConfig::default().snapshot(snapshot.clone());
+        assert_eq!(
+            b"v2",
+            secondary.get(b"mem", &read_config).unwrap().as_slice()
+        );
+        secondary.release_snapshot(snapshot);
+        let secondary_users = secondary.column_family("users").unwrap();
+        assert_eq!(
+            b"users",
+            secondary
+                .get_cf(&secondary_users, b"k", &ReadConfig::default())
+                .unwrap()
+                .as_slice()
+        );
+        // Nothing new
+        secondary.try_catch_up_with_primary().unwrap();
+        assert_eq!("v3", get(&secondary, b"mem"));
+    }
+
+    #[test]
+    fn test_db_column_families() {
+        let dbname = test_dir("db_column_families");
+        let small_buffer = DBConfig {
+            write_buffer_size: 10000,
+            ..config()
+        };
+        let db = DB::open(&dbname, &small_buffer).unwrap();
+        let config = WriteConfig::default();
+        let users = db.create_column_family("users").unwrap();
+        assert!(db
+            .create_column_family("users")
+            .unwrap_err()
+            .is_invalid_argument());
+        assert!(db
+            .create_column_family(DEFAULT_COLUMN_FAMILY_NAME)
+            .unwrap_err()
+            .is_invalid_argument());
+        assert_eq!(Some(users.clone()), db.column_family("users"));
+        assert!(db.column_family("missing").is_none());
+
+        // The column families hold different values for the same keys
+        db.put(b"k", b"default", &config).unwrap();
+        db.put_cf(&users, b"k", b"users", &config).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.put_cf(&users, b"a", b"va").unwrap();
+        batch.delete(b"k").unwrap();
+        db.write(&mut batch, &config).unwrap();
+        assert_eq!("NOT_FOUND", get(&db, b"k"));
+        assert_eq!("NOT_FOUND", get(&db, b"a"));
+        let get_users = |db: &DB, key: &[u8]| match db.get_cf(&users, key, &ReadConfig::default()) {
+            Ok(value) => String::from_utf8(value).unwrap(),
+            Err(error) if error.is_not_found() => "NOT_FOUND".to_string(),
+            Err(error) => error.to_string(),
+        };
+        assert_eq!("users", get_users(&db, b"k"));
+        assert_eq!("va", get_users(&db, b"a"));
+
+        // Filling the memtable of a column family writes it out to its
+        // own levels
+        let value = |i: usize| format!("{:0>100}", i);
+        for i in 0..1000 {
+            db.put_cf(
+                &users,
+                format!("key{:04}", i).as_bytes(),
+                value(i).as_bytes(),
+                &config,
+            )
+            .unwrap();
+        }
+        db.delete_cf(&users, b"key0007", &config).unwrap();
+        let num_files = |db: &DB, id: u32| {
+            let current = db.lock().versions.current_cf(id);
+            (0..NUM_LEVELS)
+                .map(|level| current.num_files(level))
+                .sum::<usize>()
+        };
+        assert!(num_files(&db, users.id()) > num_files(&db, DEFAULT_COLUMN_FAMILY));
+        let mut iter = db.iter_cf(&users, &ReadConfig::default());
+        assert_eq!(1001, contents(iter.as_mut(), false).len());
+        drop(iter);
+        assert_eq!(
+            vec![
+                (b"a".to_vec(), b"va".to_vec()),
+                (b"k".to_vec(), b"users".to_vec())
+            ],
+            contents(
+                db.iter_cf(&users, &ReadConfig::default().iterate_upper_bound(b"key"))
+                    .as_mut(),
+                false
+            )
+        );
+
+        // Both the tables and the log survive a reopen
+        db.put(b"d", b"vd", &config).unwrap();
+        drop(db);
+        let db = DB::open(&dbname, &small_buffer).unwrap();
+        assert_eq!(Some(users.clone()), db.column_family("users"));
+        assert_eq!(2, db.column_families().len());
+        assert_eq!("vd", get(&db, b"d"));
+        assert_eq!("users", get_users(&db, b"k"));
+        assert_eq!("NOT_FOUND", get_users(&db, b"key0007"));
+        assert_eq!(value(999), get_users(&db, b"key0999"));
+        db.flush().unwrap();
+        drop(db);
+        let db = DB::open(&dbname, &small_buffer).unwrap();
+        assert_eq!("vd", get(&db, b"d"));
+        assert_eq!(value(500), get_users(&db, b"key0500"));
+
+        // Compacting a column family leaves the other one alone
+        let default_level0 = db.lock().versions.num_level_files(0);
+        db.compact_range_cf(&users, None, None).unwrap();
+        assert_eq!(0, db.lock().versions.current_cf(users.id()).num_files(0));
+        assert_eq!(default_level0, db.lock().versions.num_level_files(0));
+        assert_eq!(value(500), get_users(&db, b"key0500"));
+        assert_eq!("NOT_FOUND", get_users(&db, b"key0007"));
+
+        // A dropped column family takes its files with it
+        assert!(db
+            .drop_column_family(&db.column_family(DEFAULT_COLUMN_FAMILY_NAME).unwrap())
+            .unwrap_err()
+            .is_invalid_argument());
+        db.drop_column_family(&users).unwrap();
+        assert!(db
+            .get_cf(&users, b"k", &ReadConfig::default())
+            .unwrap_err()
+            .is_invalid_argument());
+        assert!(db
+            .put_cf(&users, b"k", b"v", &config)
+            .unwrap_err()
+            .is_invalid_argument());
+        assert_eq!(num_live_files(&db), count_files(&db, FileType::TableFile));
+        drop(db);
+        let db = DB::open(&dbname, &small_buffer).unwrap();
+        assert!(db.column_family("users").is_none());
+        assert_eq!("vd", get(&db, b"d"));
+        let users = db.create_column_family("users").unwrap();
+        assert!(users.id() > 1);
+        assert!(db
+            .get_cf(&users, b"k", &ReadConfig::default())
+            .unwrap_err()
+            .is_not_found());
     }
-    // TODO: finish it later
-    // fn iter(&self, config:&ReadConfig)
 }
@@ -0,0 +1,228 @@
+use std::collections::BTreeSet;
+
+use super::{ReadConfig, WriteConfig, DB};
+use crate::{
+    iterator::Iterator,
+    util::{extend_size_prefixed_slice, get_size_prefixed_slice, MergeOperator},
+    Error, Result,
+};
+
+/// Tags a merge operand that adds its value to the set
+const ADD: u8 = 1;
+/// Tags a merge operand that removes its value from the set
+const REMOVE: u8 = 0;
+
+/// A map from each key to a set of values, stored in a DB with one entry
+/// per key.  Its value is the set of values of the key, which add() and
+/// remove() change with merge operands instead of reading and rewriting
+/// it: the DB must be configured with a MultiMapOperator, which combines
+/// them.
+///
+/// The DB must hold nothing but the entries of the map.
+pub struct MultiMap<'a> {
+    db: &'a DB,
+}
+
+impl<'a> MultiMap<'a> {
+    pub fn new(db: &'a DB) -> Result<Self> {
+        let name = db
+            .config
+            .merge_operator
+            .as_ref()
+            .map(|operator| operator.name());
+        if name != Some(MultiMapOperator.name()) {
+            return Err(Error::invalid_argument(
+                "MultiMap needs a database that uses MultiMapOperator",
+            ));
+        }
+        Ok(Self { db })
+    }
+
+    /// Add "value" to the values of "key".  Adding a value that is
+    /// already there does nothing.
+    pub fn add(&self, key: &[u8], value: &[u8], config: &WriteConfig) -> Result<()> {
+        self.db.merge(key, &encode_operand(ADD, value), config)
+    }
+
+    /// Remove "value" from the values of "key", if it is there.
+    pub fn remove(&self, key: &[u8], value: &[u8], config: &WriteConfig) -> Result<()> {
+        self.db.merge(key, &encode_operand(REMOVE, value), config)
+    }
+
+    /// Remove all the values of "key".
+    pub fn remove_all(&self, key: &[u8], config: &WriteConfig) -> Result<()> {
+        self.db.delete(key, config)
+    }
+
+    /// Return whether "value" is one of the values of "key".
+    pub fn contains(&self, key: &[u8], value: &[u8], config: &ReadConfig) -> Result<bool> {
+        Ok(self.get(key, config)?.iter().any(|v| v == value))
+    }
+
+    /// Return the values of "key" in order, none if it has no values.
+    pub fn get(&self, key: &[u8], config: &ReadConfig) -> Result<Vec<Vec<u8>>> {
+        match self.db.get(key, config) {
+            Ok(values) => decode_values(&values),
+            Err(error) if error.is_not_found() => Ok(vec![]),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Return an iterator over all the (key, value) pairs of the map,
+    /// ordered by key and then by value.
+    pub fn iter(&self, config: &ReadConfig) -> MultiMapIter<'_> {
+        let mut iter = self.db.iter(config);
+        iter.seek_to_first();
+        MultiMapIter {
+            iter,
+            key: vec![],
+            values: vec![].into_iter(),
+            done: false,
+        }
+    }
+}
+
+/// The (key, value) pairs of a MultiMap.  An error ends the iteration.
+pub struct MultiMapIter<'a> {
+    iter: Box<dyn Iterator + 'a>,
+    /// The key whose remaining values are "values"
+    key: Vec<u8>,
+    values: std::vec::IntoIter<Vec<u8>>,
+    done: bool,
+}
+
+impl std::iter::Iterator for MultiMapIter<'_> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(value) = self.values.next() {
+                return Some(Ok((self.key.clone(), value)));
+            }
+            if self.done {
+                return None;
+            }
+            if !self.iter.valid() {
+                self.done = true;
+                return self.iter.status().err().map(Err);
+            }
+            match decode_values(self.iter.value()) {
+                Ok(values) => {
+                    self.key = self.iter.key().to_vec();
+                    self.values = values.into_iter();
+                }
+                Err(error) => {
+                    self.done = true;
+                    return Some(Err(error));
+                }
+            }
+            self.iter.next();
+        }
+    }
+}
+
+/// The merge operator of the databases that hold a MultiMap.  A value is
+/// a set of values, each prefixed with its length, in order.  An operand
+/// is ADD or REMOVE followed by the value to add to or remove from it.
+pub struct MultiMapOperator;
+
+impl MultiMapOperator {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl Default for MultiMapOperator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MergeOperator for MultiMapOperator {
+    fn name(&self) -> &str {
+        "rebeldb.MultiMapOperator"
+    }
+
+    fn full_merge(
+        &self,
+        _key: &[u8],
+        existing_value: Option<&[u8]>,
+        operands: &[Vec<u8>],
+    ) -> Result<Vec<u8>> {
+        let mut values: BTreeSet<Vec<u8>> = match existing_value {
+            Some(existing_value) => decode_values(existing_value)?.into_iter().collect(),
+            None => BTreeSet::new(),
+        };
+        for operand in operands {
+            match operand.split_first() {
+                Some((&ADD, value)) => {
+                    values.insert(value.to_vec());
+                }
+                Some((&REMOVE, value)) => {
+                    values.remove(value);
+                }
+                _ => return Err(Error::corruption("bad MultiMap operand")),
+            }
+        }
+        let mut encoded = vec![];
+        for value in &values {
+            extend_size_prefixed_slice(&mut encoded, value);
+        }
+        Ok(encoded)
+    }
+}
+
+fn encode_operand(tag: u8, value: &[u8]) -> Vec<u8> {
+    let mut operand = Vec::with_capacity(value.len() + 1);
+    operand.push(tag);
+    operand.extend_from_slice(value);
+    operand
+}
+
+fn decode_values(mut input: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let mut values = vec![];
+    while !input.is_empty() {
+        let value = get_size_prefixed_slice(&mut input)
+            .ok_or_else(|| Error::corruption("bad MultiMap entry"))?;
+        values.push(value.to_vec());
+    }
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_multi_map_operator() {
+        let operator = MultiMapOperator::new();
+        let operands = vec![
+            encode_operand(ADD, b"pear"),
+            encode_operand(ADD, b""),
+            encode_operand(ADD, b"apple"),
+            encode_operand(REMOVE, b"kiwi"),
+            encode_operand(ADD, b"pear"),
+        ];
+        let merged = operator.full_merge(b"k", None, &operands).unwrap();
+        assert_eq!(
+            vec![b"".to_vec(), b"apple".to_vec(), b"pear".to_vec()],
+            decode_values(&merged).unwrap()
+        );
+
+        let operands = vec![encode_operand(REMOVE, b""), encode_operand(ADD, b"fig")];
+        let merged = operator.full_merge(b"k", Some(&merged), &operands).unwrap();
+        assert_eq!(
+            vec![b"apple".to_vec(), b"fig".to_vec(), b"pear".to_vec()],
+            decode_values(&merged).unwrap()
+        );
+
+        assert!(operator
+            .full_merge(b"k", None, &[vec![]])
+            .unwrap_err()
+            .is_corruption());
+        assert!(operator
+            .full_merge(b"k", Some(b"\x05a"), &operands)
+            .unwrap_err()
+            .is_corruption());
+    }
+}
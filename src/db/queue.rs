@@ -0,0 +1,220 @@
+use super::{Range, ReadConfig, WriteBatch, WriteConfig, DB};
+use crate::{
+    util::{BytewiseComparator, Comparator},
+    Error, Result,
+};
+
+/// A FIFO queue of values stored in a DB.  Each value is stored under
+/// its id, the sequence number of the write that enqueued it, encoded
+/// big-endian so that the ids sort in the order the values were
+/// enqueued.
+///
+/// Removed values leave deletion markers behind, which every scan from
+/// the front of the queue would have to skip until compactions drop
+/// them.  The queue remembers where its front is to seek past them, and
+/// trim() drops the files that only hold old entries outright.
+///
+/// The DB must use BytewiseComparator and hold nothing but the queue.
+pub struct Queue<'a> {
+    db: &'a DB,
+    /// No value with a smaller id is in the queue
+    head: u64,
+}
+
+impl<'a> Queue<'a> {
+    pub fn new(db: &'a DB) -> Result<Self> {
+        if db.internal_comparator.user_comparator().name() != BytewiseComparator::new().name() {
+            return Err(Error::invalid_argument(
+                "Queue needs a database that uses BytewiseComparator",
+            ));
+        }
+        Ok(Self { db, head: 0 })
+    }
+
+    /// Add "value" at the back of the queue and return its id.
+    pub fn enqueue(&mut self, value: &[u8], config: &WriteConfig) -> Result<u64> {
+        self.db.write_with_sequence(config, |id| {
+            let mut batch = WriteBatch::new();
+            batch.put(&id.to_be_bytes(), value)?;
+            Ok(batch)
+        })
+    }
+
+    /// Return the id and value at the front of the queue, if any,
+    /// without removing it.
+    pub fn peek(&mut self, config: &ReadConfig) -> Result<Option<(u64, Vec<u8>)>> {
+        let front = {
+            let mut iter = self.db.iter(config);
+            iter.seek(&self.head.to_be_bytes());
+            if iter.valid() {
+                let id = decode_id(iter.key())?;
+                Some((id, iter.value().to_vec()))
+            } else {
+                iter.status()?;
+                None
+            }
+        };
+        if let Some((id, _)) = front {
+            self.head = id;
+        }
+        Ok(front)
+    }
+
+    /// Remove the value at the front of the queue and return it with its
+    /// id, if there is one.
+    pub fn dequeue(&mut self, config: &WriteConfig) -> Result<Option<(u64, Vec<u8>)>> {
+        let front = self.peek(&ReadConfig::default())?;
+        if let Some((id, _)) = front {
+            self.ack(id, config)?;
+        }
+        Ok(front)
+    }
+
+    /// Remove the value with the specified id, e.g. once a value returned
+    /// by peek() has been processed.
+    pub fn ack(&mut self, id: u64, config: &WriteConfig) -> Result<()> {
+        self.db.delete(&id.to_be_bytes(), config)?;
+        if id == self.head {
+            self.head = id + 1;
+        }
+        Ok(())
+    }
+
+    /// Remove all the values with ids below "id".  The table files that
+    /// only hold such values are dropped without writing deletion markers
+    /// for them; a range tombstone covers the rest of the values.  See
+    /// DB::delete_files_in_range().
+    pub fn trim(&mut self, id: u64) -> Result<()> {
+        if id <= self.head {
+            return Ok(());
+        }
+        let (start, limit) = (self.head.to_be_bytes(), id.to_be_bytes());
+        self.db.delete_files_in_range(&Range::new(&start, &limit))?;
+        self.head = id;
+        Ok(())
+    }
+}
+
+fn decode_id(key: &[u8]) -> Result<u64> {
+    let bytes = key
+        .try_into()
+        .map_err(|_| Error::corruption("bad Queue entry"))?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::{
+        db::{CompressionType, DBConfig},
+        dbformat::NUM_LEVELS,
+        env::test_dir,
+    };
+
+    fn config() -> DBConfig {
+        DBConfig {
+            create_if_missing: true,
+            ..Default::default()
+        }
+    }
+
+    fn num_live_files(db: &DB) -> usize {
+        db.wait_for_background_work().unwrap();
+        (0..NUM_LEVELS)
+            .map(|level| db.lock().versions.num_level_files(level))
+            .sum()
+    }
+
+    #[test]
+    fn test_queue() {
+        let small_files = DBConfig {
+            write_buffer_size: 10000,
+            max_file_size: 20000,
+            compression: CompressionType::None,
+            ..config()
+        };
+        let dbname = test_dir("db_queue");
+        let db = DB::open(&dbname, &small_files).unwrap();
+        let write = WriteConfig::default();
+        let read = ReadConfig::default();
+        let mut queue = Queue::new(&db).unwrap();
+        assert_eq!(None, queue.peek(&read).unwrap());
+        let ids: Vec<_> = (0..2000)
+            .map(|i| {
+                queue
+                    .enqueue(format!("{:0100}", i).as_bytes(), &write)
+                    .unwrap()
+            })
+            .collect();
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+
+        let (id, value) = queue.dequeue(&write).unwrap().unwrap();
+        assert_eq!(ids[0], id);
+        assert_eq!(format!("{:0100}", 0).as_bytes(), value);
+        let (id, _) = queue.peek(&read).unwrap().unwrap();
+        assert_eq!(ids[1], id);
+        queue.ack(id, &write).unwrap();
+        assert_eq!(ids[2], queue.peek(&read).unwrap().unwrap().0);
+
+        let live_before = num_live_files(&db);
+        queue.trim(ids[1500]).unwrap();
+        let (id, value) = queue.peek(&read).unwrap().unwrap();
+        assert_eq!(ids[1500], id);
+        assert_eq!(format!("{:0100}", 1500).as_bytes(), value);
+        assert!(num_live_files(&db) < live_before);
+        drop(db);
+
+        let db = DB::open(&dbname, &small_files).unwrap();
+        let mut queue = Queue::new(&db).unwrap();
+        assert_eq!(ids[1500], queue.peek(&read).unwrap().unwrap().0);
+        let id = queue.enqueue(b"last", &write).unwrap();
+        assert!(id > ids[1999]);
+        queue.trim(id).unwrap();
+        assert_eq!(
+            (id, b"last".to_vec()),
+            queue.dequeue(&write).unwrap().unwrap()
+        );
+        assert_eq!(None, queue.dequeue(&write).unwrap());
+    }
+
+    #[test]
+    fn test_queue_concurrent_enqueues() {
+        let db = DB::open(&test_dir("db_queue_concurrent_enqueues"), &config()).unwrap();
+        let mut ids: Vec<_> = thread::scope(|scope| {
+            let threads: Vec<_> = (0..4)
+                .map(|t| {
+                    let db = &db;
+                    scope.spawn(move || {
+                        let mut queue = Queue::new(db).unwrap();
+                        (0..100)
+                            .map(|i| {
+                                let value = format!("{}.{}", t, i);
+                                let id = queue
+                                    .enqueue(value.as_bytes(), &WriteConfig::default())
+                                    .unwrap();
+                                (id, value)
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            threads
+                .into_iter()
+                .flat_map(|thread| thread.join().unwrap())
+                .collect()
+        });
+        ids.sort();
+        ids.dedup_by_key(|(id, _)| *id);
+        assert_eq!(400, ids.len());
+
+        // Every value is there, under the id enqueue() returned
+        let mut queue = Queue::new(&db).unwrap();
+        for (id, value) in ids {
+            let front = queue.dequeue(&WriteConfig::default()).unwrap();
+            assert_eq!(Some((id, value.into_bytes())), front);
+        }
+        assert_eq!(None, queue.peek(&ReadConfig::default()).unwrap());
+    }
+}
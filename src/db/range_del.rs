@@ -0,0 +1,148 @@
+use std::{cmp::Ordering, sync::Arc};
+
+use crate::{dbformat::RangeTombstone, util::Comparator};
+
+/// The part of the key space between two consecutive boundaries of a
+/// set of range tombstones
+struct Fragment {
+    start: Vec<u8>,
+    end: Vec<u8>,
+    /// Of the tombstones covering the fragment, in decreasing order
+    sequences: Vec<u64>,
+}
+
+/// A set of range tombstones, cut into fragments that do not overlap, so
+/// that the tombstones covering a key are found with a binary search
+/// rather than by checking every tombstone.  Iterators, which check
+/// every key they visit, look tombstones up this way.
+pub struct FragmentedRangeTombstones {
+    ucmp: Arc<dyn Comparator>,
+    /// Sorted by start
+    fragments: Vec<Fragment>,
+}
+
+impl FragmentedRangeTombstones {
+    pub fn new(ucmp: Arc<dyn Comparator>, mut tombstones: Vec<RangeTombstone>) -> Self {
+        tombstones.retain(|t| ucmp.compare(&t.start, &t.end) == Ordering::Less);
+        tombstones.sort_by(|a, b| ucmp.compare(&a.start, &b.start));
+        let mut boundaries: Vec<&[u8]> = tombstones
+            .iter()
+            .flat_map(|t| [t.start.as_slice(), t.end.as_slice()])
+            .collect();
+        boundaries.sort_by(|a, b| ucmp.compare(a, b));
+        boundaries.dedup_by(|a, b| ucmp.compare(a, b) == Ordering::Equal);
+
+        // Sweep the boundaries, keeping track of the tombstones that
+        // cover the fragment starting at each
+        let mut fragments = vec![];
+        let mut active: Vec<&RangeTombstone> = vec![];
+        let mut next = 0;
+        for pair in boundaries.windows(2) {
+            let (start, end) = (pair[0], pair[1]);
+            active.retain(|t| ucmp.compare(&t.end, start) == Ordering::Greater);
+            while let Some(t) = tombstones.get(next) {
+                if ucmp.compare(&t.start, start) == Ordering::Greater {
+                    break;
+                }
+                active.push(t);
+                next += 1;
+            }
+            if !active.is_empty() {
+                let mut sequences: Vec<u64> = active.iter().map(|t| t.sequence).collect();
+                sequences.sort_unstable_by(|a, b| b.cmp(a));
+                sequences.dedup();
+                fragments.push(Fragment {
+                    start: start.to_vec(),
+                    end: end.to_vec(),
+                    sequences,
+                });
+            }
+        }
+        Self { ucmp, fragments }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fragments.is_empty()
+    }
+
+    /// Return the largest sequence number <= "read_sequence" of the
+    /// tombstones covering "user_key", or zero if there are none.
+    pub fn max_covering_sequence(&self, user_key: &[u8], read_sequence: u64) -> u64 {
+        let index = self
+            .fragments
+            .partition_point(|f| self.ucmp.compare(&f.start, user_key) != Ordering::Greater);
+        match index.checked_sub(1).map(|index| &self.fragments[index]) {
+            Some(fragment) if self.ucmp.compare(user_key, &fragment.end) == Ordering::Less => {
+                fragment
+                    .sequences
+                    .iter()
+                    .copied()
+                    .find(|&sequence| sequence <= read_sequence)
+                    .unwrap_or(0)
+            }
+            _ => 0,
+        }
+    }
+}
+
+/// Like FragmentedRangeTombstones::max_covering_sequence(), for a few
+/// tombstones that are only checked once, e.g. those of a table file
+/// checked by a point lookup.
+pub fn max_covering_sequence(
+    tombstones: &[RangeTombstone],
+    ucmp: &dyn Comparator,
+    user_key: &[u8],
+    read_sequence: u64,
+) -> u64 {
+    tombstones
+        .iter()
+        .filter(|t| t.sequence <= read_sequence && t.covers(ucmp, user_key))
+        .map(|t| t.sequence)
+        .max()
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::BytewiseComparator;
+
+    #[test]
+    fn test_fragmented_range_tombstones() {
+        let ucmp = Arc::new(BytewiseComparator::new());
+        let tombstones = vec![
+            RangeTombstone::new(b"a", b"e", 10),
+            RangeTombstone::new(b"c", b"g", 20),
+            RangeTombstone::new(b"c", b"d", 5),
+            RangeTombstone::new(b"x", b"x", 30),
+        ];
+        let fragmented = FragmentedRangeTombstones::new(ucmp.clone(), tombstones.clone());
+        assert!(!fragmented.is_empty());
+        for (key, read_sequence, expected) in [
+            (b"0".as_slice(), 100, 0),
+            (b"a", 100, 10),
+            (b"b", 9, 0),
+            (b"c", 100, 20),
+            (b"c", 19, 10),
+            (b"c", 9, 5),
+            (b"d", 9, 0),
+            (b"e", 100, 20),
+            (b"g", 100, 0),
+            // Empty ranges cover nothing
+            (b"x", 100, 0),
+        ] {
+            assert_eq!(
+                expected,
+                fragmented.max_covering_sequence(key, read_sequence),
+                "{:?} at {}",
+                key,
+                read_sequence
+            );
+            assert_eq!(
+                expected,
+                max_covering_sequence(&tombstones, ucmp.as_ref(), key, read_sequence)
+            );
+        }
+        assert!(FragmentedRangeTombstones::new(ucmp, vec![]).is_empty());
+    }
+}
@@ -0,0 +1,336 @@
+//! We recover the contents of the descriptor from the other files we
+//! find.
+//! (1) Any log files are first converted to tables
+//! (2) We scan every table to compute
+//!     (a) smallest/largest for the table
+//!     (b) largest sequence number in the table
+//! (3) We generate descriptor contents:
+//!      - log number is set to zero
+//!      - next-file-number is set to 1 + largest file number we found
+//!      - last-sequence-number is set to largest sequence# found across
+//!        all tables (see 2c)
+//!      - compaction pointers are cleared
+//!      - every table file is added at level 0
+//!
+//! Possible optimization 1:
+//!   (a) Compute total size and use to pick appropriate max-level M
+//!   (b) Sort tables by largest sequence# in the table
+//!   (c) For each table: if it overlaps earlier table, place in level-0,
+//!       else place in level-M.
+//! Possible optimization 2:
+//!   Store per-table metadata (smallest, largest, largest-seq#, ...)
+//!   in the table's meta section to speed up ScanTable.
+
+use std::sync::Arc;
+
+use super::{builder::build_table, sanitize_config, DBConfig, ReadConfig, WriteBatch};
+use crate::{
+    dbformat::{InternalKeyComparator, ParsedInternalKey},
+    env::{Env, WriteHint},
+    filename::{
+        descriptor_file_name, lock_file_name, log_file_name, parse_file_name, set_current_file,
+        table_file_name_for, temp_file_name, FileType,
+    },
+    log::{Reader, Writer},
+    memtable::MemTable,
+    table::TableCache,
+    version::{FileMetaData, VersionEdit},
+    Error, Result,
+};
+
+/// Table cache entries used while repairing; each table is scanned once
+const TABLE_CACHE_SIZE: usize = 10;
+
+struct TableInfo {
+    meta: FileMetaData,
+    max_sequence: u64,
+}
+
+struct Repairer {
+    dbname: String,
+    env: Arc<dyn Env>,
+    icmp: InternalKeyComparator,
+    config: DBConfig,
+    table_cache: TableCache,
+
+    manifests: Vec<String>,
+    table_numbers: Vec<u64>,
+    logs: Vec<u64>,
+    tables: Vec<TableInfo>,
+    next_file_number: u64,
+}
+
+impl Repairer {
+    fn new(dbname: &str, config: &DBConfig) -> Self {
+        let icmp = InternalKeyComparator::new(config.comparator.clone());
+        let config = sanitize_config(config, &icmp);
+        Self {
+            dbname: dbname.to_string(),
+            env: config.env.clone(),
+            table_cache: TableCache::new(dbname, &config, TABLE_CACHE_SIZE),
+            icmp,
+            config,
+            manifests: vec![],
+            table_numbers: vec![],
+            logs: vec![],
+            tables: vec![],
+            next_file_number: 1,
+        }
+    }
+
+    fn run(&mut self) -> Result<()> {
+        self.find_files()?;
+        self.convert_log_files_to_tables();
+        self.extract_meta_data();
+        self.write_descriptor()
+    }
+
+    fn find_files(&mut self) -> Result<()> {
+        let filenames = self.env.get_children(&self.dbname)?;
+        if filenames.is_empty() {
+            return Err(Error::io_error(&format!(
+                "{}: repair found no files",
+                self.dbname
+            )));
+        }
+
+        for filename in filenames {
+            let filename = match filename.to_str() {
+                Some(filename) => filename,
+                None => continue,
+            };
+            let (number, type_) = match parse_file_name(filename) {
+                Some(parsed) => parsed,
+                None => continue,
+            };
+            if type_ == FileType::DescriptorFile {
+                self.manifests.push(filename.to_string());
+                continue;
+            }
+            self.next_file_number = self.next_file_number.max(number + 1);
+            match type_ {
+                FileType::LogFile => self.logs.push(number),
+                FileType::TableFile => self.table_numbers.push(number),
+                _ => {} // Ignore other files
+            }
+        }
+        Ok(())
+    }
+
+    fn convert_log_files_to_tables(&mut self) {
+        self.logs.sort_unstable();
+        for log in self.logs.clone() {
+            let logname = log_file_name(&self.dbname, log);
+            // Whatever the log held that could be read is in a table now,
+            // or lost for good
+            let _ = self.convert_log_to_table(log);
+            self.archive_file(&logname);
+        }
+    }
+
+    fn convert_log_to_table(&mut self, log: u64) -> Result<()> {
+        let file = self
+            .env
+            .new_sequential_file(&log_file_name(&self.dbname, log))?;
+
+        // We intentionally make log::Reader do checksumming so that
+        // corruptions cause entire commits to be skipped instead of
+        // propagating bad information (like overly large sequence
+        // numbers).  Records with bad checksums are dropped, and the
+        // reader goes on with the next one.
+        let mut reader = Reader::new(file, 0, true, None);
+
+        // Read all the records and add to a memtable
+        let mem = MemTable::new(self.icmp.clone());
+        let mut batch = WriteBatch::new();
+        while let Some(record) = reader.read_record() {
+            if record.len() < 12 {
+                continue;
+            }
+            batch.set_contents(record);
+            // A malformed batch keeps whatever it applied
+            let _ = batch.insert_into(&mem);
+        }
+
+        // Do not record a version edit for this conversion to a Table
+        // since extract_meta_data() will also generate edits.
+        let mut meta = FileMetaData {
+            number: self.next_file_number,
+            ..Default::default()
+        };
+        self.next_file_number += 1;
+        let mut iter = mem.new_iterator();
+        build_table(
+            &self.dbname,
+            self.env.as_ref(),
+            &self.config,
+            &self.table_cache,
+            iter.as_mut(),
+            &mem.range_tombstones(),
+            &mut meta,
+        )?;
+        if meta.file_size > 0 {
+            self.table_numbers.push(meta.number);
+        }
+        Ok(())
+    }
+
+    fn extract_meta_data(&mut self) {
+        for number in self.table_numbers.clone() {
+            match self.scan_table(number) {
+                Some(table) => self.tables.push(table),
+                None => {
+                    let extension = self.config.table_file_extension;
+                    for fname in [
+                        table_file_name_for(&self.dbname, number, extension),
+                        table_file_name_for(&self.dbname, number, extension.alternate()),
+                    ] {
+                        if self.env.file_exists(&fname) {
+                            self.archive_file(&fname);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Find the key range and largest sequence number of a table.
+    /// Returns None if the table can't be read in full or is empty.
+    fn scan_table(&self, number: u64) -> Option<TableInfo> {
+        let extension = self.config.table_file_extension;
+        let file_size = self
+            .env
+            .get_file_size(&table_file_name_for(&self.dbname, number, extension))
+            .or_else(|_| {
+                self.env.get_file_size(&table_file_name_for(
+                    &self.dbname,
+                    number,
+                    extension.alternate(),
+                ))
+            })
+            .ok()?;
+        let mut table = TableInfo {
+            meta: FileMetaData {
+                number,
+                file_size,
+                ..Default::default()
+            },
+            max_sequence: 0,
+        };
+
+        // Extract metadata by scanning through table
+        let mut iter = self
+            .table_cache
+            .new_iterator(&ReadConfig::default(), number, file_size);
+        let mut empty = true;
+        iter.seek_to_first();
+        while iter.valid() {
+            let key = iter.key();
+            // Skip keys that can't be parsed
+            if let Some(parsed) = ParsedInternalKey::parse(key) {
+                if empty {
+                    empty = false;
+                    table.meta.smallest.decode_from(key);
+                }
+                table.meta.largest.decode_from(key);
+                table.max_sequence = table.max_sequence.max(parsed.sequence());
+            }
+            iter.next();
+        }
+        if iter.status().is_err() {
+            return None;
+        }
+        drop(iter);
+        let tombstones = self.table_cache.range_tombstones(number, file_size).ok()?;
+        for tombstone in tombstones.iter() {
+            table
+                .meta
+                .extend_to_range_tombstone(self.config.comparator.as_ref(), tombstone);
+            table.max_sequence = table.max_sequence.max(tombstone.sequence);
+        }
+        if empty && tombstones.is_empty() {
+            return None;
+        }
+        Some(table)
+    }
+
+    fn write_descriptor(&mut self) -> Result<()> {
+        let tmp = temp_file_name(&self.dbname, 1);
+        let mut edit = VersionEdit::new();
+        edit.set_comparator_name(self.icmp.user_comparator().name());
+        edit.set_log_number(0);
+        edit.set_next_file(self.next_file_number);
+        edit.set_last_sequence(
+            self.tables
+                .iter()
+                .map(|table| table.max_sequence)
+                .max()
+                .unwrap_or(0),
+        );
+        for table in &self.tables {
+            edit.add_file(
+                0,
+                table.meta.number,
+                table.meta.file_size,
+                table.meta.smallest.clone(),
+                table.meta.largest.clone(),
+            );
+        }
+
+        let mut log = Writer::new(self.env.new_writable_file(&tmp, WriteHint::Manifest)?);
+        let mut result = edit.write_to(&mut log);
+        if result.is_ok() {
+            result = log.file_mut().sync();
+        }
+        if result.is_ok() {
+            result = log.file_mut().close();
+        }
+        if let Err(error) = result {
+            let _ = self.env.remove_file(&tmp);
+            return Err(error);
+        }
+
+        // Discard older manifests
+        for manifest in self.manifests.clone() {
+            self.archive_file(&format!("{}/{}", self.dbname, manifest));
+        }
+
+        // Install new manifest
+        self.env
+            .rename_file(&tmp, &descriptor_file_name(&self.dbname, 1))?;
+        set_current_file(self.env.clone(), &self.dbname, 1)
+    }
+
+    /// Move "fname" into the lost/ subdirectory of the database, so that
+    /// nothing is deleted for good.
+    fn archive_file(&self, fname: &str) {
+        let (dir, base) = match fname.rfind('/') {
+            Some(slash) => (&fname[..slash], &fname[slash + 1..]),
+            None => ("", fname),
+        };
+        let lost = format!("{}/lost", dir);
+        // Ignore error in case the directory already exists
+        let _ = self.env.create_dir(&lost);
+        let _ = self.env.rename_file(fname, &format!("{}/{}", lost, base));
+    }
+}
+
+/// If a DB cannot be opened, you may attempt to call this method to
+/// resurrect as much of the contents of the database as possible.
+/// Some data may be lost, so be careful when calling this function
+/// on a database that contains important information.
+///
+/// The log files are converted to tables, and a new MANIFEST that puts
+/// every table at level 0 replaces the old ones.  Files that are not
+/// used any more, or cannot be read, are moved to the lost/
+/// subdirectory.
+///
+/// Only the default column family is recovered: the tables of the others
+/// end up in it, and their updates in the logs are dropped.
+pub fn repair_db(dbname: &str, config: &DBConfig) -> Result<()> {
+    let env = config.env.clone();
+    let lock = env.lock_file(&lock_file_name(dbname))?;
+    let result = Repairer::new(dbname, config).run();
+    let _ = env.unlock_file(lock);
+    result
+}
@@ -0,0 +1,90 @@
+use std::collections::BTreeMap;
+
+/// Abstract handle to particular state of a DB.
+/// A Snapshot is an immutable object and can therefore be safely
+/// accessed from multiple threads without any external synchronization.
+#[derive(Debug)]
+pub struct Snapshot {
+    sequence: u64,
+}
+
+impl Snapshot {
+    /// The sequence number reads through this snapshot are pinned at.
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+}
+
+/// The sequence numbers of all snapshots handed out by a DB that have
+/// not been released yet.  Several snapshots may share a sequence number.
+#[derive(Default)]
+pub struct SnapshotList {
+    /// Number of live snapshots at each sequence number
+    sequences: BTreeMap<u64, usize>,
+}
+
+impl SnapshotList {
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        self.sequences.is_empty()
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.sequences.values().sum()
+    }
+
+    /// Sequence number of the oldest live snapshot, if any.
+    pub fn oldest(&self) -> Option<u64> {
+        self.sequences.keys().next().copied()
+    }
+
+    /// Sequence number of the newest live snapshot, if any.
+    pub fn newest(&self) -> Option<u64> {
+        self.sequences.keys().next_back().copied()
+    }
+
+    pub fn new_snapshot(&mut self, sequence: u64) -> Snapshot {
+        assert!(self.newest().is_none_or(|newest| newest <= sequence));
+        *self.sequences.entry(sequence).or_default() += 1;
+        Snapshot { sequence }
+    }
+
+    pub fn delete(&mut self, snapshot: &Snapshot) {
+        let count = self
+            .sequences
+            .get_mut(&snapshot.sequence)
+            .expect("snapshot released twice");
+        *count -= 1;
+        if *count == 0 {
+            self.sequences.remove(&snapshot.sequence);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_list() {
+        let mut list = SnapshotList::default();
+        assert!(list.is_empty());
+        assert_eq!(None, list.oldest());
+
+        let s1 = list.new_snapshot(5);
+        let s2 = list.new_snapshot(5);
+        let s3 = list.new_snapshot(9);
+        assert_eq!(3, list.len());
+        assert_eq!(Some(5), list.oldest());
+        assert_eq!(Some(9), list.newest());
+
+        list.delete(&s1);
+        assert_eq!(Some(5), list.oldest());
+        list.delete(&s2);
+        assert_eq!(Some(9), list.oldest());
+        assert_eq!(9, s3.sequence());
+        list.delete(&s3);
+        assert!(list.is_empty());
+    }
+}
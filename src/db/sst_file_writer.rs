@@ -0,0 +1,173 @@
+use std::{cmp::Ordering, sync::Arc};
+
+use super::{sanitize_config, DBConfig};
+use crate::{
+    dbformat::{InternalKey, InternalKeyComparator, ValueType},
+    env::{Env, WriteHint},
+    table::TableBuilder,
+    util::Comparator,
+    Error, Result,
+};
+
+/// What SstFileWriter::finish() reports about the file it wrote.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SstFileInfo {
+    /// The smallest and the largest user key of the file
+    pub smallest_key: Vec<u8>,
+    pub largest_key: Vec<u8>,
+    pub file_size: u64,
+    pub num_entries: u64,
+}
+
+/// Writes a table file outside of any database, to be added to one with
+/// DB::ingest_external_file().  The file is laid out with the comparator,
+/// filter policy, compression and block settings of the config, which
+/// should be those of the database it is ingested into.
+///
+/// Entries must be added in increasing order of their user keys, each
+/// key at most once.
+pub struct SstFileWriter {
+    env: Arc<dyn Env>,
+    fname: String,
+    user_comparator: Arc<dyn Comparator>,
+    builder: TableBuilder,
+    last_key: Option<Vec<u8>>,
+    smallest_key: Option<Vec<u8>>,
+    finished: bool,
+}
+
+impl SstFileWriter {
+    /// Create the file "fname" with the Env of "config".
+    pub fn create(config: &DBConfig, fname: &str) -> Result<Self> {
+        let icmp = InternalKeyComparator::new(config.comparator.clone());
+        let config = sanitize_config(config, &icmp);
+        let file = config.env.new_writable_file(fname, WriteHint::TableCold)?;
+        Ok(Self {
+            env: config.env.clone(),
+            fname: fname.to_string(),
+            user_comparator: icmp.user_comparator().clone(),
+            builder: TableBuilder::new(&config, file),
+            last_key: None,
+            smallest_key: None,
+            finished: false,
+        })
+    }
+
+    /// Add "key" with "value" to the file.
+    pub fn add(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.add_entry(key, ValueType::Value, value)
+    }
+
+    /// Add a deletion of "key" to the file, which removes the value the
+    /// database holds for it once the file is ingested.
+    pub fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.add_entry(key, ValueType::Deletion, b"")
+    }
+
+    fn add_entry(&mut self, key: &[u8], type_: ValueType, value: &[u8]) -> Result<()> {
+        if self.last_key.as_deref().is_some_and(|last_key| {
+            self.user_comparator.compare(key, last_key) != Ordering::Greater
+        }) {
+            return Err(Error::invalid_argument(
+                "keys must be added in increasing order",
+            ));
+        }
+        // Ingestion assigns the entries their sequence number
+        let internal_key = InternalKey::new(key, 0, type_);
+        self.builder.add(internal_key.encode(), value)?;
+        if self.smallest_key.is_none() {
+            self.smallest_key = Some(key.to_vec());
+        }
+        self.last_key = Some(key.to_vec());
+        Ok(())
+    }
+
+    /// Finish writing the file and sync it.  Fails if no entry was added.
+    pub fn finish(mut self) -> Result<SstFileInfo> {
+        self.finished = true;
+        let (smallest_key, largest_key) = match (self.smallest_key.take(), self.last_key.take()) {
+            (Some(smallest_key), Some(largest_key)) => (smallest_key, largest_key),
+            _ => {
+                self.builder.abandon();
+                let _ = self.env.remove_file(&self.fname);
+                return Err(Error::invalid_argument("no entries added to the file"));
+            }
+        };
+        let mut result = self.builder.finish();
+        if result.is_ok() {
+            result = self.builder.file().sync();
+        }
+        if result.is_ok() {
+            result = self.builder.file().close();
+        }
+        if let Err(error) = result {
+            let _ = self.env.remove_file(&self.fname);
+            return Err(error);
+        }
+        Ok(SstFileInfo {
+            smallest_key,
+            largest_key,
+            file_size: self.builder.file_size(),
+            num_entries: self.builder.num_entries(),
+        })
+    }
+}
+
+impl Drop for SstFileWriter {
+    /// A file that is not finished is removed.
+    fn drop(&mut self) {
+        if !self.finished {
+            self.builder.abandon();
+            let _ = self.env.remove_file(&self.fname);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        db::{IngestConfig, ReadConfig, WriteConfig, DB},
+        env::test_dir,
+    };
+
+    #[test]
+    fn test_sst_file_writer() {
+        let dbname = test_dir("sst_file_writer");
+        let config = DBConfig {
+            create_if_missing: true,
+            ..Default::default()
+        };
+        let fname = format!("{}/bulk.ldb", dbname);
+        let mut writer = SstFileWriter::create(&config, &fname).unwrap();
+        writer.add(b"a", b"1").unwrap();
+        writer.delete(b"b").unwrap();
+        writer.add(b"c", b"3").unwrap();
+        assert!(writer.add(b"c", b"4").unwrap_err().is_invalid_argument());
+        assert!(writer.add(b"b", b"2").unwrap_err().is_invalid_argument());
+        let info = writer.finish().unwrap();
+        assert_eq!(b"a", info.smallest_key.as_slice());
+        assert_eq!(b"c", info.largest_key.as_slice());
+        assert_eq!(3, info.num_entries);
+        assert_eq!(config.env.get_file_size(&fname).unwrap(), info.file_size);
+
+        // Files with no entries, or not finished, are removed
+        let empty = format!("{}/empty.ldb", dbname);
+        let writer = SstFileWriter::create(&config, &empty).unwrap();
+        assert!(writer.finish().unwrap_err().is_invalid_argument());
+        assert!(!config.env.file_exists(&empty));
+        let mut writer = SstFileWriter::create(&config, &empty).unwrap();
+        writer.add(b"a", b"").unwrap();
+        drop(writer);
+        assert!(!config.env.file_exists(&empty));
+
+        let db = DB::open(&dbname, &config).unwrap();
+        db.put(b"b", b"old", &WriteConfig::default()).unwrap();
+        db.ingest_external_file(&[&fname], &IngestConfig::default())
+            .unwrap();
+        let read = ReadConfig::default();
+        assert_eq!(b"1".to_vec(), db.get(b"a", &read).unwrap());
+        assert!(db.get(b"b", &read).unwrap_err().is_not_found());
+        assert_eq!(b"3".to_vec(), db.get(b"c", &read).unwrap());
+    }
+}
@@ -0,0 +1,49 @@
+/// The work done by the flushes and compactions that wrote to a level,
+/// summed over the lifetime of the database.  Kept in the MANIFEST so
+/// that it survives restarts.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CompactionStats {
+    /// Time spent
+    pub micros: u64,
+    /// Bytes of table files read as input; flushes read none
+    pub bytes_read: u64,
+    /// Bytes of table files written
+    pub bytes_written: u64,
+}
+
+impl CompactionStats {
+    pub fn add(&mut self, other: &CompactionStats) {
+        self.micros += other.micros;
+        self.bytes_read += other.bytes_read;
+        self.bytes_written += other.bytes_written;
+    }
+}
+
+/// Shape of one level of the LSM tree.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LevelStatistics {
+    /// Number of table files in the level.
+    pub files: usize,
+    /// Combined size of those files.
+    pub bytes: u64,
+    /// Compaction score of the level.  A score of 1 or more means the
+    /// level is over its target and needs a compaction.
+    pub score: f64,
+    /// Work done writing to the level.
+    pub compaction: CompactionStats,
+}
+
+/// A point-in-time snapshot of the compaction state of a database,
+/// returned by DB::statistics().
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Statistics {
+    /// One entry per level, starting with level-0.
+    pub levels: Vec<LevelStatistics>,
+    /// The level the next compaction should pick.
+    pub compaction_level: usize,
+    /// The score of that level.
+    pub compaction_score: f64,
+    /// Estimated number of bytes compactions have to rewrite before
+    /// every level is back under its size target.
+    pub pending_compaction_bytes: u64,
+}
@@ -0,0 +1,173 @@
+use std::{
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use super::{ReadConfig, WriteBatch, WriteConfig, DB};
+use crate::{
+    env::{SequentialFile, WritableFile},
+    util::{decode_fixed32, decode_fixed64, extend_fixed32, extend_fixed64},
+    Error, Result,
+};
+
+/// Written at the start of every trace file.
+const TRACE_MAGIC: &[u8; 8] = b"rbtrace1";
+
+/// A record starts with the time in microseconds (8 bytes), the type of
+/// the operation (1 byte) and the length of its payload (4 bytes).
+const RECORD_HEADER_SIZE: usize = 8 + 1 + 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum TraceType {
+    /// The payload is the contents of the write batch
+    Write = 1,
+    /// The payload is the key looked up
+    Get = 2,
+}
+
+impl TraceType {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(TraceType::Write),
+            2 => Some(TraceType::Get),
+            _ => None,
+        }
+    }
+}
+
+/// Records the operations of a DB between start_trace() and end_trace().
+pub struct Tracer {
+    file: Box<dyn WritableFile>,
+    /// The first error hit while writing the trace.  Tracing must not
+    /// fail the operations being traced, so it is only reported by
+    /// end_trace().
+    status: Result<()>,
+}
+
+impl Tracer {
+    pub fn new(mut file: Box<dyn WritableFile>) -> Result<Self> {
+        file.append(TRACE_MAGIC)?;
+        Ok(Self {
+            file,
+            status: Ok(()),
+        })
+    }
+
+    pub fn trace_write(&mut self, batch: &WriteBatch) {
+        self.add_record(TraceType::Write, batch.contents());
+    }
+
+    pub fn trace_get(&mut self, key: &[u8]) {
+        self.add_record(TraceType::Get, key);
+    }
+
+    fn add_record(&mut self, type_: TraceType, payload: &[u8]) {
+        if self.status.is_err() {
+            return;
+        }
+        let time_micros = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        let mut header = Vec::with_capacity(RECORD_HEADER_SIZE);
+        extend_fixed64(&mut header, time_micros);
+        header.push(type_ as u8);
+        extend_fixed32(&mut header, payload.len() as u32);
+        self.status = self.file.append_slices(&[&header, payload]);
+    }
+
+    /// Flush the trace and close the file.
+    pub fn finish(mut self) -> Result<()> {
+        self.status?;
+        self.file.flush()?;
+        self.file.close()
+    }
+}
+
+/// Re-executes a trace written by DB::start_trace() against a database,
+/// e.g. one opened with a different config, to benchmark it with a
+/// recorded production workload.
+pub struct Replayer<'a> {
+    db: &'a mut DB,
+    file: Box<dyn SequentialFile>,
+}
+
+impl<'a> Replayer<'a> {
+    /// Prepare to replay the trace in "file" against "db".  Fails if the
+    /// file is not a trace.
+    pub fn new(db: &'a mut DB, mut file: Box<dyn SequentialFile>) -> Result<Self> {
+        let mut magic = [0; TRACE_MAGIC.len()];
+        if !read_fully(file.as_mut(), &mut magic)? || &magic != TRACE_MAGIC {
+            return Err(Error::corruption("not a trace file"));
+        }
+        Ok(Self { db, file })
+    }
+
+    /// Execute the traced operations in order and return how many were
+    /// replayed.  The operations are spaced out as they were when traced,
+    /// sped up by "fast_forward": 1.0 replays at the original speed, 2.0
+    /// twice as fast.  Writes must succeed; the results of reads are
+    /// ignored.  A partial record at the end of the trace, as left
+    /// behind by a crash, ends the replay.
+    pub fn replay(&mut self, fast_forward: f64) -> Result<usize> {
+        if fast_forward.is_nan() || fast_forward <= 0.0 {
+            return Err(Error::invalid_argument("fast_forward must be positive"));
+        }
+        let start = Instant::now();
+        let mut first_time_micros = None;
+        let mut replayed = 0;
+        while let Some((time_micros, type_, payload)) = self.read_record()? {
+            let first = *first_time_micros.get_or_insert(time_micros);
+            let offset = time_micros.saturating_sub(first) as f64 / fast_forward;
+            let due = start + Duration::from_micros(offset as u64);
+            let now = Instant::now();
+            if due > now {
+                thread::sleep(due - now);
+            }
+
+            match type_ {
+                TraceType::Write => {
+                    let mut batch = WriteBatch::new();
+                    batch.set_contents(&payload);
+                    self.db.write(&mut batch, &WriteConfig::default())?;
+                }
+                TraceType::Get => {
+                    let _ = self.db.get(&payload, &ReadConfig::default());
+                }
+            }
+            replayed += 1;
+        }
+        Ok(replayed)
+    }
+
+    fn read_record(&mut self) -> Result<Option<(u64, TraceType, Vec<u8>)>> {
+        let mut header = [0; RECORD_HEADER_SIZE];
+        if !read_fully(self.file.as_mut(), &mut header)? {
+            return Ok(None);
+        }
+        let time_micros = decode_fixed64(&header);
+        let type_ = TraceType::from_u8(header[8])
+            .ok_or_else(|| Error::corruption("unknown trace record type"))?;
+        let mut payload = vec![0; decode_fixed32(&header[9..]) as usize];
+        if !read_fully(self.file.as_mut(), &mut payload)? {
+            return Ok(None);
+        }
+        if type_ == TraceType::Write && payload.len() < 12 {
+            return Err(Error::corruption("traced write batch too small"));
+        }
+        Ok(Some((time_micros, type_, payload)))
+    }
+}
+
+/// Fill "dst" from "file".  Returns false if the file ends first.
+fn read_fully(file: &mut dyn SequentialFile, dst: &mut [u8]) -> Result<bool> {
+    let mut filled = 0;
+    while filled < dst.len() {
+        let n = file.read(&mut dst[filled..])?;
+        if n == 0 {
+            return Ok(false);
+        }
+        filled += n;
+    }
+    Ok(true)
+}
@@ -0,0 +1,264 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use super::{DBConfig, ReadConfig, Snapshot, WriteBatchWithIndex, WriteConfig, DB};
+use crate::{Error, Result};
+
+/// A DB whose updates can be grouped into transactions.  Transactions
+/// take no locks: each one buffers its writes, and checks when it is
+/// committed that none of the keys it has read or written was updated
+/// by someone else since.  If one was, the commit fails and nothing is
+/// written.  This suits workloads in which transactions seldom touch
+/// the same keys.
+///
+/// Transactions only read and write the default column family.
+pub struct OptimisticTransactionDB {
+    db: DB,
+}
+
+impl OptimisticTransactionDB {
+    pub fn open(dbname: &str, config: &DBConfig) -> Result<Self> {
+        Ok(Self {
+            db: DB::open(dbname, config)?,
+        })
+    }
+
+    /// Return the underlying database, e.g. to use it outside of any
+    /// transaction.  Its updates conflict with the transactions that
+    /// track the keys they update.
+    pub fn db(&self) -> &DB {
+        &self.db
+    }
+
+    /// Start a transaction, to be committed with "config".
+    pub fn begin_transaction(&self, config: &WriteConfig) -> Transaction<'_> {
+        let comparator = self.db.internal_comparator.user_comparator().clone();
+        Transaction {
+            db: &self.db,
+            config: config.clone(),
+            batch: WriteBatchWithIndex::new(comparator),
+            tracked_keys: BTreeMap::new(),
+            snapshot: None,
+        }
+    }
+}
+
+/// The updates of a transaction of an OptimisticTransactionDB, not yet
+/// committed.  They are seen by the reads of the transaction, and by no
+/// one else until commit() applies them atomically.
+///
+/// Dropping a transaction rolls it back.
+pub struct Transaction<'a> {
+    db: &'a DB,
+    config: WriteConfig,
+    batch: WriteBatchWithIndex,
+    /// The keys read or written, each with the sequence number of the
+    /// database when it first was.  An update of the key with a larger
+    /// sequence number is a conflict.
+    tracked_keys: BTreeMap<Vec<u8>, u64>,
+    snapshot: Option<Arc<Snapshot>>,
+}
+
+impl Transaction<'_> {
+    /// Take a snapshot of the database, which the reads of the
+    /// transaction that set no snapshot of their own observe from now
+    /// on.  The keys tracked from now on are checked for updates since
+    /// the snapshot rather than since they were read or written, so that
+    /// the transaction only commits if what it read is still current.
+    pub fn set_snapshot(&mut self) {
+        if let Some(snapshot) = self.snapshot.replace(self.db.get_snapshot()) {
+            self.db.release_snapshot(snapshot);
+        }
+    }
+
+    /// The snapshot taken by set_snapshot(), if any.
+    pub fn snapshot(&self) -> Option<&Arc<Snapshot>> {
+        self.snapshot.as_ref()
+    }
+
+    /// Return the value of "key", as updated by the transaction, or an
+    /// error that satisfies Error::is_not_found() if it has none.  The
+    /// key is tracked for conflicts.
+    pub fn get(&mut self, key: &[u8], config: &ReadConfig) -> Result<Vec<u8>> {
+        let mut config = config.clone();
+        if config.snapshot.is_none() {
+            config.snapshot = self.snapshot.clone();
+        }
+        let sequence = self.db.read_sequence(&config);
+        let result = self.batch.get_from_batch_and_db(self.db, key, &config);
+        self.track_key(key, sequence);
+        result
+    }
+
+    /// Set the value of "key" to "value" when the transaction commits.
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.batch.put(key, value)?;
+        self.track_write(key);
+        Ok(())
+    }
+
+    /// Remove the value (if any) of "key" when the transaction commits.
+    pub fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.batch.delete(key)?;
+        self.track_write(key);
+        Ok(())
+    }
+
+    /// Merge "operand" into the value of "key" when the transaction
+    /// commits, see WriteBatch::merge().
+    pub fn merge(&mut self, key: &[u8], operand: &[u8]) -> Result<()> {
+        self.batch.merge(key, operand)?;
+        self.track_write(key);
+        Ok(())
+    }
+
+    /// The updates the transaction has buffered.
+    pub fn write_batch(&self) -> &WriteBatchWithIndex {
+        &self.batch
+    }
+
+    /// Write the updates of the transaction to the database, unless one
+    /// of the keys it tracks was updated since it first was read or
+    /// written, in which case nothing is written and the error returned
+    /// satisfies Error::is_busy().  No other write can come in between
+    /// the check and the write.
+    pub fn commit(mut self) -> Result<()> {
+        let db = self.db;
+        let tracked_keys = &self.tracked_keys;
+        db.write_if(self.batch.write_batch_mut(), &self.config, |sources| {
+            for (key, &sequence) in tracked_keys {
+                if db.latest_sequence_for_key(sources, key)? > sequence {
+                    return Err(Error::busy(
+                        "a key of the transaction was updated after it was read or written",
+                    ));
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Discard the updates of the transaction and stop tracking its keys,
+    /// so that it can be used over again.
+    pub fn rollback(&mut self) {
+        self.batch.clear();
+        self.tracked_keys.clear();
+    }
+
+    fn track_write(&mut self, key: &[u8]) {
+        let sequence = match self.snapshot.as_ref() {
+            Some(snapshot) => snapshot.sequence(),
+            None => self.db.read_sequence(&ReadConfig::default()),
+        };
+        self.track_key(key, sequence);
+    }
+
+    fn track_key(&mut self, key: &[u8], sequence: u64) {
+        self.tracked_keys
+            .entry(key.to_vec())
+            .and_modify(|tracked| *tracked = (*tracked).min(sequence))
+            .or_insert(sequence);
+    }
+}
+
+impl Drop for Transaction<'_> {
+    fn drop(&mut self) {
+        if let Some(snapshot) = self.snapshot.take() {
+            self.db.release_snapshot(snapshot);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{env::test_dir, StringAppendOperator};
+
+    fn get(db: &DB, key: &[u8]) -> String {
+        match db.get(key, &ReadConfig::default()) {
+            Ok(value) => String::from_utf8(value).unwrap(),
+            Err(error) if error.is_not_found() => "NOT_FOUND".to_string(),
+            Err(error) => error.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_optimistic_transaction_db() {
+        let dbname = test_dir("optimistic_transaction_db");
+        let config = DBConfig {
+            merge_operator: Some(Arc::new(StringAppendOperator::new(b','))),
+            create_if_missing: true,
+            ..Default::default()
+        };
+        let txn_db = OptimisticTransactionDB::open(&dbname, &config).unwrap();
+        let write_config = WriteConfig::default();
+        let read_config = ReadConfig::default();
+        txn_db.db().put(b"a", b"1", &write_config).unwrap();
+        txn_db.db().put(b"b", b"1", &write_config).unwrap();
+
+        // The writes of a transaction are read by it, and by no one else
+        // before it commits
+        let mut txn = txn_db.begin_transaction(&write_config);
+        assert_eq!(b"1", txn.get(b"a", &read_config).unwrap().as_slice());
+        txn.put(b"a", b"2").unwrap();
+        txn.merge(b"a", b"3").unwrap();
+        txn.delete(b"b").unwrap();
+        txn.merge(b"c", b"4").unwrap();
+        assert_eq!(b"2,3", txn.get(b"a", &read_config).unwrap().as_slice());
+        assert!(txn.get(b"b", &read_config).unwrap_err().is_not_found());
+        assert_eq!(b"4", txn.get(b"c", &read_config).unwrap().as_slice());
+        assert_eq!("1", get(txn_db.db(), b"a"));
+        assert_eq!(4, txn.write_batch().len());
+        txn.commit().unwrap();
+        assert_eq!("2,3", get(txn_db.db(), b"a"));
+        assert_eq!("NOT_FOUND", get(txn_db.db(), b"b"));
+        assert_eq!("4", get(txn_db.db(), b"c"));
+
+        // A key read by one transaction and written by another one that
+        // commits first fails the commit
+        let mut first = txn_db.begin_transaction(&write_config);
+        let mut second = txn_db.begin_transaction(&write_config);
+        assert_eq!(b"2,3", first.get(b"a", &read_config).unwrap().as_slice());
+        first.put(b"d", b"first").unwrap();
+        second.put(b"a", b"second").unwrap();
+        second.commit().unwrap();
+        assert!(first.commit().unwrap_err().is_busy());
+        assert_eq!("second", get(txn_db.db(), b"a"));
+        assert_eq!("NOT_FOUND", get(txn_db.db(), b"d"));
+
+        // So do direct writes, even once flushed, and range deletions
+        let mut txn = txn_db.begin_transaction(&write_config);
+        txn.put(b"e", b"txn").unwrap();
+        txn_db.db().put(b"e", b"db", &write_config).unwrap();
+        txn_db.db().flush().unwrap();
+        assert!(txn.commit().unwrap_err().is_busy());
+        let mut txn = txn_db.begin_transaction(&write_config);
+        assert!(txn.get(b"f", &read_config).unwrap_err().is_not_found());
+        txn.put(b"g", b"txn").unwrap();
+        txn_db.db().delete_range(b"f", b"g", &write_config).unwrap();
+        assert!(txn.commit().unwrap_err().is_busy());
+        assert_eq!("db", get(txn_db.db(), b"e"));
+        assert_eq!("NOT_FOUND", get(txn_db.db(), b"g"));
+
+        // Writes to keys the transaction does not track do not conflict
+        let mut txn = txn_db.begin_transaction(&write_config);
+        txn.put(b"h", b"txn").unwrap();
+        txn_db.db().put(b"i", b"db", &write_config).unwrap();
+        txn.commit().unwrap();
+        assert_eq!("txn", get(txn_db.db(), b"h"));
+
+        // With a snapshot, the reads of the transaction see what was
+        // there when it was taken, and any later write conflicts
+        let mut txn = txn_db.begin_transaction(&write_config);
+        txn.set_snapshot();
+        txn_db.db().put(b"j", b"db", &write_config).unwrap();
+        assert!(txn.get(b"j", &read_config).unwrap_err().is_not_found());
+        assert!(txn.commit().unwrap_err().is_busy());
+
+        // A rolled back transaction writes nothing
+        let mut txn = txn_db.begin_transaction(&write_config);
+        txn.put(b"k", b"txn").unwrap();
+        txn.rollback();
+        assert!(txn.write_batch().is_empty());
+        txn.commit().unwrap();
+        assert_eq!("NOT_FOUND", get(txn_db.db(), b"k"));
+    }
+}
@@ -0,0 +1,209 @@
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use super::{BatchEntry, DBConfig, ReadConfig, WriteBatch, WriteConfig, DB};
+use crate::{
+    iterator::Iterator,
+    util::{decode_fixed32, CompactionDecision, CompactionFilter},
+    Error, Result,
+};
+
+/// Size of the write time appended to each value
+const TIMESTAMP_SIZE: usize = 4;
+
+/// A DB whose entries expire "ttl" after they were written.  Each value
+/// is stored with the time it was written, in seconds since the Unix
+/// epoch as a fixed32, and compactions drop the entries that are older
+/// than "ttl".  Reads strip the time again.
+///
+/// Expiry is only applied by compactions: an expired entry may still be
+/// read until a compaction rewrites the table holding it.  A ttl of zero
+/// keeps entries forever.
+///
+/// Everything in the database must be written through DBWithTTL, which
+/// only writes the default column family.
+pub struct DBWithTTL {
+    db: DB,
+    ttl: Duration,
+}
+
+impl DBWithTTL {
+    /// Open the database at "dbname" with the entries written from now on
+    /// expiring "ttl" after their write.  The compaction filter of
+    /// "config", if any, is passed the values without their write times.
+    pub fn open(dbname: &str, config: &DBConfig, ttl: Duration) -> Result<Self> {
+        let filter = TtlCompactionFilter {
+            ttl: ttl.as_secs(),
+            user_filter: config.compaction_filter.clone(),
+        };
+        let config = config.clone().compaction_filter(Arc::new(filter));
+        Ok(Self {
+            db: DB::open(dbname, &config)?,
+            ttl,
+        })
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// Return the underlying database, e.g. to compact it or to take a
+    /// snapshot.  Values read from it end with their write time.
+    pub fn db(&self) -> &DB {
+        &self.db
+    }
+
+    /// Set the entry for "key" to "value", expiring "ttl" from now.
+    pub fn put(&self, key: &[u8], value: &[u8], config: &WriteConfig) -> Result<()> {
+        self.db.put(key, &append_timestamp(value), config)
+    }
+
+    /// Remove the entry (if any) for "key".
+    pub fn delete(&self, key: &[u8], config: &WriteConfig) -> Result<()> {
+        self.db.delete(key, config)
+    }
+
+    /// Apply "updates" to the database, with all the values it puts
+    /// expiring "ttl" from now.  Fails with an invalid argument error if
+    /// it holds merges or updates of column families.
+    pub fn write(&self, updates: &WriteBatch, config: &WriteConfig) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        for entry in updates.iter() {
+            match entry? {
+                BatchEntry::Put { key, value } => batch.put(key, &append_timestamp(value))?,
+                BatchEntry::Delete { key } => batch.delete(key)?,
+                BatchEntry::DeleteRange { begin, end } => batch.delete_range(begin, end)?,
+                BatchEntry::Merge { .. } | BatchEntry::ColumnFamily { .. } => {
+                    return Err(Error::invalid_argument(
+                        "DBWithTTL only writes puts and deletions of the default column family",
+                    ))
+                }
+            }
+        }
+        self.db.write(&mut batch, config)
+    }
+
+    /// Return the value of "key", or an error that satisfies
+    /// Error::is_not_found() if it has none.
+    pub fn get(&self, key: &[u8], config: &ReadConfig) -> Result<Vec<u8>> {
+        let mut value = self.db.get(key, config)?;
+        if value.len() < TIMESTAMP_SIZE {
+            return Err(Error::corruption("value of a DBWithTTL has no write time"));
+        }
+        value.truncate(value.len() - TIMESTAMP_SIZE);
+        Ok(value)
+    }
+
+    /// Return an iterator over the contents of the database, like
+    /// DB::iter(), with the values stripped of their write times.
+    pub fn iter(&self, config: &ReadConfig) -> Box<dyn Iterator + '_> {
+        Box::new(TtlIterator {
+            iter: self.db.iter(config),
+        })
+    }
+}
+
+/// Seconds since the Unix epoch
+fn current_time() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs() as u32)
+}
+
+fn append_timestamp(value: &[u8]) -> Vec<u8> {
+    let mut stored = Vec::with_capacity(value.len() + TIMESTAMP_SIZE);
+    stored.extend_from_slice(value);
+    stored.extend_from_slice(&current_time().to_le_bytes());
+    stored
+}
+
+/// Removes the values written more than "ttl" seconds ago, and passes
+/// the others to the filter of the user, if any
+struct TtlCompactionFilter {
+    ttl: u64,
+    user_filter: Option<Arc<dyn CompactionFilter>>,
+}
+
+impl CompactionFilter for TtlCompactionFilter {
+    fn name(&self) -> &str {
+        "rebeldb.TtlCompactionFilter"
+    }
+
+    fn filter(&self, level: usize, key: &[u8], existing_value: &[u8]) -> CompactionDecision {
+        if existing_value.len() < TIMESTAMP_SIZE {
+            // Not written by DBWithTTL
+            return CompactionDecision::Keep;
+        }
+        let (value, timestamp) = existing_value.split_at(existing_value.len() - TIMESTAMP_SIZE);
+        if self.ttl > 0 && u64::from(decode_fixed32(timestamp)) + self.ttl < current_time() as u64 {
+            return CompactionDecision::Remove;
+        }
+        match self
+            .user_filter
+            .as_ref()
+            .map(|filter| filter.filter(level, key, value))
+        {
+            None | Some(CompactionDecision::Keep) => CompactionDecision::Keep,
+            Some(CompactionDecision::Remove) => CompactionDecision::Remove,
+            Some(CompactionDecision::ChangeValue(mut value)) => {
+                // Expires when the original value would have
+                value.extend_from_slice(timestamp);
+                CompactionDecision::ChangeValue(value)
+            }
+        }
+    }
+}
+
+/// Strips the write times off the values of a DBWithTTL
+struct TtlIterator<'a> {
+    iter: Box<dyn Iterator + 'a>,
+}
+
+impl Iterator for TtlIterator<'_> {
+    fn valid(&self) -> bool {
+        self.iter.valid()
+    }
+
+    fn seek_to_first(&mut self) {
+        self.iter.seek_to_first();
+    }
+
+    fn seek_to_last(&mut self) {
+        self.iter.seek_to_last();
+    }
+
+    fn seek(&mut self, target: &[u8]) {
+        self.iter.seek(target);
+    }
+
+    fn seek_for_prev(&mut self, target: &[u8]) {
+        self.iter.seek_for_prev(target);
+    }
+
+    fn next(&mut self) {
+        self.iter.next();
+    }
+
+    fn prev(&mut self) {
+        self.iter.prev();
+    }
+
+    fn key(&self) -> &[u8] {
+        self.iter.key()
+    }
+
+    fn value(&self) -> &[u8] {
+        let value = self.iter.value();
+        &value[..value.len().saturating_sub(TIMESTAMP_SIZE)]
+    }
+
+    fn status(&self) -> Result<()> {
+        self.iter.status()
+    }
+
+    fn refresh(&mut self) -> Result<()> {
+        self.iter.refresh()
+    }
+}
@@ -1,11 +1,12 @@
 use std::mem;
 
+use super::{ColumnFamilyHandle, DEFAULT_COLUMN_FAMILY};
 use crate::{
     dbformat::ValueType,
     memtable::MemTable,
     util::{
-        decode_fixed32, decode_fixed64, decode_size_prefixed_slice, encode_fixed32, encode_fixed64,
-        extend_size_prefixed_slice,
+        decode_fixed32, decode_fixed64, decode_size_prefixed_slice, decode_varint32,
+        encode_fixed32, encode_fixed64, extend_size_prefixed_slice, extend_varint32, varint_size,
     },
     Error, Result,
 };
@@ -14,61 +15,234 @@ use crate::{
 const HEADER_SIZE: usize = 12;
 const SEQ_SIZE: usize = mem::size_of::<u64>();
 
+/// Tag of the record that makes the updates after it, up to the next
+/// such record, apply to a column family other than the default one.
+/// It is not a ValueType: the record is no update, does not count in
+/// the batch and takes no sequence number.
+const COLUMN_FAMILY_TAG: u8 = 0x10;
+
 pub trait WriteBatchHandler {
     fn put(&mut self, key: &[u8], value: &[u8]);
     fn delete(&mut self, key: &[u8]);
+    fn delete_range(&mut self, begin: &[u8], end: &[u8]);
+    fn merge(&mut self, key: &[u8], operand: &[u8]);
+
+    /// The updates that follow apply to the column family with id "id",
+    /// until the next call.  Updates before the first call apply to the
+    /// default column family.
+    fn set_column_family(&mut self, _id: u32) {}
 }
 
 struct MemTableInserter<'a> {
     sequence: u64,
-    mem: &'a mut MemTable,
+    /// The memtable of each column family the batch may write to
+    memtables: &'a dyn Fn(u32) -> Option<&'a MemTable>,
+    /// The memtable of the current column family, or None to skip its
+    /// updates
+    mem: Option<&'a MemTable>,
 }
 
 impl<'a> MemTableInserter<'a> {
-    fn new(sequence: u64, mem: &'a mut MemTable) -> Self {
-        Self { sequence, mem }
+    fn new(sequence: u64, memtables: &'a dyn Fn(u32) -> Option<&'a MemTable>) -> Self {
+        Self {
+            sequence,
+            memtables,
+            mem: memtables(DEFAULT_COLUMN_FAMILY),
+        }
+    }
+
+    fn add(&mut self, type_: ValueType, key: &[u8], value: &[u8]) {
+        if let Some(mem) = self.mem {
+            mem.add(self.sequence, type_, key, value);
+        }
+        self.sequence += 1;
     }
 }
 
 impl<'a> WriteBatchHandler for MemTableInserter<'a> {
     fn put(&mut self, key: &[u8], value: &[u8]) {
-        self.mem.add(self.sequence, ValueType::Value, key, value);
-        self.sequence += 1;
+        self.add(ValueType::Value, key, value);
     }
 
     fn delete(&mut self, key: &[u8]) {
-        self.mem.add(self.sequence, ValueType::Deletion, key, &[]);
-        self.sequence += 1;
+        self.add(ValueType::Deletion, key, &[]);
+    }
+
+    fn delete_range(&mut self, begin: &[u8], end: &[u8]) {
+        self.add(ValueType::RangeDeletion, begin, end);
+    }
+
+    fn merge(&mut self, key: &[u8], operand: &[u8]) {
+        self.add(ValueType::Merge, key, operand);
+    }
+
+    fn set_column_family(&mut self, id: u32) {
+        self.mem = (self.memtables)(id);
     }
 }
 
 pub struct WriteBatch {
     rep: Vec<u8>,
+    /// Length of rep, count and column family at each save point,
+    /// oldest first
+    save_points: Vec<(usize, u32, u32)>,
+    /// Limit on the length of rep, or zero for no limit
+    max_bytes: usize,
+    /// The column family the last update applies to
+    column_family: u32,
+    /// Some update applies to a column family other than the default one
+    has_column_families: bool,
+}
+
+impl Default for WriteBatch {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl WriteBatch {
     pub fn new() -> Self {
         Self {
             rep: vec![0; HEADER_SIZE],
+            save_points: vec![],
+            max_bytes: 0,
+            column_family: DEFAULT_COLUMN_FAMILY,
+            has_column_families: false,
         }
     }
 
-    pub fn put(&mut self, key: &[u8], value: &[u8]) {
-        self.set_count(self.count() + 1);
-        self.rep.push(ValueType::Value as u8);
-        extend_size_prefixed_slice(&mut self.rep, key);
-        extend_size_prefixed_slice(&mut self.rep, value);
+    /// Create a batch whose encoding, as returned by approximate_size(),
+    /// may not grow past "max_bytes": put() and delete() fail with an
+    /// error that satisfies Error::is_invalid_argument() instead, and
+    /// leave the batch as it was.  A "max_bytes" of zero means no limit.
+    /// append() does not check the limit.
+    pub fn with_capacity_limit(max_bytes: usize) -> Self {
+        Self {
+            max_bytes,
+            ..Self::new()
+        }
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.add(DEFAULT_COLUMN_FAMILY, ValueType::Value, key, Some(value))
+    }
+
+    /// Like put(), in the column family of "column_family".
+    pub fn put_cf(
+        &mut self,
+        column_family: &ColumnFamilyHandle,
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<()> {
+        self.add(column_family.id(), ValueType::Value, key, Some(value))
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.add(DEFAULT_COLUMN_FAMILY, ValueType::Deletion, key, None)
+    }
+
+    /// Like delete(), in the column family of "column_family".
+    pub fn delete_cf(&mut self, column_family: &ColumnFamilyHandle, key: &[u8]) -> Result<()> {
+        self.add(column_family.id(), ValueType::Deletion, key, None)
+    }
+
+    /// Delete all the keys in [begin, end), as ordered by the comparator
+    /// of the database, with a single entry: unlike deleting the keys one
+    /// by one, this costs the same whatever the number of keys, and does
+    /// not need to know what they are.  The range is empty if "begin" is
+    /// not before "end".
+    pub fn delete_range(&mut self, begin: &[u8], end: &[u8]) -> Result<()> {
+        self.add(
+            DEFAULT_COLUMN_FAMILY,
+            ValueType::RangeDeletion,
+            begin,
+            Some(end),
+        )
     }
 
-    pub fn delete(&mut self, key: &[u8]) {
+    /// Merge "operand" into the value of "key" with the MergeOperator of
+    /// the database, which must have one.  The value is not read: the
+    /// operands are combined with it when the key is read, or compacted.
+    pub fn merge(&mut self, key: &[u8], operand: &[u8]) -> Result<()> {
+        self.add(DEFAULT_COLUMN_FAMILY, ValueType::Merge, key, Some(operand))
+    }
+
+    /// Add an update of type "type_" to column family "id", with "value"
+    /// encoded after "key" if there is one.
+    fn add(&mut self, id: u32, type_: ValueType, key: &[u8], value: Option<&[u8]>) -> Result<()> {
+        let marker_len = match id == self.column_family {
+            true => 0,
+            false => 1 + varint_size(id as u64),
+        };
+        self.check_capacity(
+            marker_len + 1 + size_prefixed_len(key) + value.map_or(0, size_prefixed_len),
+        )?;
+        self.switch_column_family(id);
         self.set_count(self.count() + 1);
-        self.rep.push(ValueType::Deletion as u8);
+        self.rep.push(type_ as u8);
         extend_size_prefixed_slice(&mut self.rep, key);
+        if let Some(value) = value {
+            extend_size_prefixed_slice(&mut self.rep, value);
+        }
+        Ok(())
+    }
+
+    /// Make the updates added next apply to column family "id".
+    fn switch_column_family(&mut self, id: u32) {
+        if id != self.column_family {
+            self.rep.push(COLUMN_FAMILY_TAG);
+            extend_varint32(&mut self.rep, id);
+            self.column_family = id;
+            self.has_column_families = true;
+        }
+    }
+
+    /// Fail if adding "bytes" to the batch would take it past its limit.
+    fn check_capacity(&self, bytes: usize) -> Result<()> {
+        if self.max_bytes > 0 && self.rep.len() + bytes > self.max_bytes {
+            return Err(Error::invalid_argument("WriteBatch size limit exceeded"));
+        }
+        Ok(())
     }
 
     pub fn clear(&mut self) {
         self.rep.clear();
         self.rep.resize(HEADER_SIZE, 0);
+        self.save_points.clear();
+        self.column_family = DEFAULT_COLUMN_FAMILY;
+        self.has_column_families = false;
+    }
+
+    /// Record the state of the batch, to be restored by
+    /// rollback_to_save_point().  Save points nest: each rollback undoes
+    /// the updates since the most recent save point left.
+    pub fn set_save_point(&mut self) {
+        self.save_points
+            .push((self.rep.len(), self.count(), self.column_family));
+    }
+
+    /// Remove the updates added since the most recent save point, and
+    /// the save point itself.  Fails with an error that satisfies
+    /// Error::is_not_found() if there is no save point.
+    pub fn rollback_to_save_point(&mut self) -> Result<()> {
+        let (len, count, column_family) = self
+            .save_points
+            .pop()
+            .ok_or_else(|| Error::not_found("no save point"))?;
+        self.rep.truncate(len);
+        self.set_count(count);
+        self.column_family = column_family;
+        Ok(())
+    }
+
+    /// Remove the most recent save point, keeping the updates added
+    /// since.  Fails with an error that satisfies Error::is_not_found()
+    /// if there is no save point.
+    pub fn pop_save_point(&mut self) -> Result<()> {
+        self.save_points
+            .pop()
+            .map(|_| ())
+            .ok_or_else(|| Error::not_found("no save point"))
     }
 
     pub fn approximate_size(&self) -> usize {
@@ -76,56 +250,36 @@ impl WriteBatch {
     }
 
     pub fn append(&mut self, source: &WriteBatch) {
+        // The updates of "source" start in the default column family
+        self.switch_column_family(DEFAULT_COLUMN_FAMILY);
         self.set_count(self.count() + source.count());
         self.rep.extend_from_slice(&source.rep[HEADER_SIZE..]);
+        self.column_family = source.column_family;
+        self.has_column_families |= source.has_column_families;
     }
 
-    pub fn iterate(&self, mut handler: Box<dyn WriteBatchHandler + '_>) -> Result<()> {
-        if self.rep.len() < HEADER_SIZE {
-            return Err(Error::corruption("malformed WriteBatch (too small)"));
-        }
-
-        let mut index = HEADER_SIZE;
-        let mut found = 0;
-        while index != self.rep.len() {
-            found += 1;
-            let tag = self.rep[index];
-            index += 1;
-            match tag.into() {
-                ValueType::Value => {
-                    let key = match decode_size_prefixed_slice(&self.rep[index..]) {
-                        Some((key, offset)) => {
-                            index += offset;
-                            key
-                        }
-                        None => return Err(Error::corruption("bad WriteBatch Put")),
-                    };
-                    let value = match decode_size_prefixed_slice(&self.rep[index..]) {
-                        Some((value, offset)) => {
-                            index += offset;
-                            value
-                        }
-                        None => return Err(Error::corruption("bad WriteBatch Put")),
-                    };
-                    handler.put(key, value);
-                }
-                ValueType::Deletion => {
-                    let key = match decode_size_prefixed_slice(&self.rep[index..]) {
-                        Some((key, offset)) => {
-                            index += offset;
-                            key
-                        }
-                        None => return Err(Error::corruption("bad WriteBatch Delete")),
-                    };
-                    handler.delete(key);
-                }
+    /// Replay the updates in the batch, in order, on "handler".
+    pub fn iterate(&self, handler: &mut dyn WriteBatchHandler) -> Result<()> {
+        for entry in self.iter() {
+            match entry? {
+                BatchEntry::Put { key, value } => handler.put(key, value),
+                BatchEntry::Delete { key } => handler.delete(key),
+                BatchEntry::DeleteRange { begin, end } => handler.delete_range(begin, end),
+                BatchEntry::Merge { key, operand } => handler.merge(key, operand),
+                BatchEntry::ColumnFamily { id } => handler.set_column_family(id),
             }
         }
+        Ok(())
+    }
 
-        if found != self.count() {
-            Err(Error::corruption("WriteBatch has wrong count"))
-        } else {
-            Ok(())
+    /// Return an iterator over the updates in the batch, in order.  A
+    /// malformed batch yields an error, after which the iterator ends.
+    pub fn iter(&self) -> WriteBatchIter<'_> {
+        WriteBatchIter {
+            rep: &self.rep,
+            index: HEADER_SIZE,
+            found: 0,
+            done: false,
         }
     }
 
@@ -134,7 +288,7 @@ impl WriteBatch {
     }
 
     pub(crate) fn set_count(&mut self, n: u32) {
-        encode_fixed32(&mut self.rep[SEQ_SIZE..HEADER_SIZE], n)
+        encode_fixed32(&mut self.rep[SEQ_SIZE..HEADER_SIZE], n).unwrap();
     }
 
     pub(crate) fn sequence(&self) -> u64 {
@@ -142,7 +296,7 @@ impl WriteBatch {
     }
 
     pub(crate) fn set_sequence(&mut self, seq: u64) {
-        encode_fixed64(&mut self.rep[..SEQ_SIZE], seq)
+        encode_fixed64(&mut self.rep[..SEQ_SIZE], seq).unwrap();
     }
 
     pub(crate) fn contents(&self) -> &[u8] {
@@ -154,32 +308,181 @@ impl WriteBatch {
     }
 
     pub(crate) fn set_contents(&mut self, contents: &[u8]) {
-        self.rep = contents.to_vec()
+        self.rep = contents.to_vec();
+        self.save_points.clear();
+        let column_families: Vec<_> = self
+            .iter()
+            .filter_map(|entry| match entry {
+                Ok(BatchEntry::ColumnFamily { id }) => Some(id),
+                _ => None,
+            })
+            .collect();
+        self.column_family = column_families
+            .last()
+            .copied()
+            .unwrap_or(DEFAULT_COLUMN_FAMILY);
+        self.has_column_families = !column_families.is_empty();
     }
 
-    pub(crate) fn insert_into(&self, memtable: &mut MemTable) -> Result<()> {
-        let inserter = Box::new(MemTableInserter::new(self.sequence(), memtable));
-        self.iterate(inserter)
+    /// Return the ids of the column families the updates apply to, other
+    /// than the default one.
+    pub(crate) fn column_families(&self) -> Vec<u32> {
+        if !self.has_column_families {
+            return vec![];
+        }
+        let mut column_families: Vec<_> = self
+            .iter()
+            .filter_map(|entry| match entry {
+                Ok(BatchEntry::ColumnFamily { id }) if id != DEFAULT_COLUMN_FAMILY => Some(id),
+                _ => None,
+            })
+            .collect();
+        column_families.sort_unstable();
+        column_families.dedup();
+        column_families
+    }
+
+    /// Insert the updates of the default column family into "memtable".
+    /// The updates of other column families are skipped.
+    pub(crate) fn insert_into(&self, memtable: &MemTable) -> Result<()> {
+        self.insert_into_column_families(&|id| (id == DEFAULT_COLUMN_FAMILY).then_some(memtable))
+    }
+
+    /// Insert each update into the memtable "memtables" returns for its
+    /// column family, or skip it if there is none.
+    pub(crate) fn insert_into_column_families<'a>(
+        &self,
+        memtables: &'a dyn Fn(u32) -> Option<&'a MemTable>,
+    ) -> Result<()> {
+        let mut inserter = MemTableInserter::new(self.sequence(), memtables);
+        self.iterate(&mut inserter)
+    }
+}
+
+/// Encoded length of "value" with its size prefix
+fn size_prefixed_len(value: &[u8]) -> usize {
+    varint_size(value.len() as u64) + value.len()
+}
+
+/// An update in a WriteBatch
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BatchEntry<'a> {
+    Put {
+        key: &'a [u8],
+        value: &'a [u8],
+    },
+    Delete {
+        key: &'a [u8],
+    },
+    DeleteRange {
+        begin: &'a [u8],
+        end: &'a [u8],
+    },
+    Merge {
+        key: &'a [u8],
+        operand: &'a [u8],
+    },
+    /// The updates that follow apply to the column family with id "id"
+    ColumnFamily {
+        id: u32,
+    },
+}
+
+/// The updates in a WriteBatch, returned by WriteBatch::iter().
+pub struct WriteBatchIter<'a> {
+    rep: &'a [u8],
+    /// Offset of the next update in rep
+    index: usize,
+    /// Number of updates decoded so far
+    found: u32,
+    done: bool,
+}
+
+impl<'a> WriteBatchIter<'a> {
+    fn next_entry(&mut self) -> Option<Result<BatchEntry<'a>>> {
+        if self.rep.len() < HEADER_SIZE {
+            return Some(Err(Error::corruption("malformed WriteBatch (too small)")));
+        }
+        if self.index == self.rep.len() {
+            if self.found != decode_fixed32(&self.rep[SEQ_SIZE..HEADER_SIZE]) {
+                return Some(Err(Error::corruption("WriteBatch has wrong count")));
+            }
+            return None;
+        }
+
+        let tag = self.rep[self.index];
+        self.index += 1;
+        if tag == COLUMN_FAMILY_TAG {
+            return match decode_varint32(&self.rep[self.index..]) {
+                Some((id, offset)) => {
+                    self.index += offset;
+                    Some(Ok(BatchEntry::ColumnFamily { id }))
+                }
+                None => Some(Err(Error::corruption("bad WriteBatch column family"))),
+            };
+        }
+        self.found += 1;
+        let entry = match ValueType::try_from(tag) {
+            Ok(ValueType::Value) => match (self.read_slice(), self.read_slice()) {
+                (Some(key), Some(value)) => BatchEntry::Put { key, value },
+                _ => return Some(Err(Error::corruption("bad WriteBatch Put"))),
+            },
+            Ok(ValueType::Deletion) => match self.read_slice() {
+                Some(key) => BatchEntry::Delete { key },
+                None => return Some(Err(Error::corruption("bad WriteBatch Delete"))),
+            },
+            Ok(ValueType::RangeDeletion) => match (self.read_slice(), self.read_slice()) {
+                (Some(begin), Some(end)) => BatchEntry::DeleteRange { begin, end },
+                _ => return Some(Err(Error::corruption("bad WriteBatch DeleteRange"))),
+            },
+            Ok(ValueType::Merge) => match (self.read_slice(), self.read_slice()) {
+                (Some(key), Some(operand)) => BatchEntry::Merge { key, operand },
+                _ => return Some(Err(Error::corruption("bad WriteBatch Merge"))),
+            },
+            Err(error) => return Some(Err(error)),
+        };
+        Some(Ok(entry))
+    }
+
+    fn read_slice(&mut self) -> Option<&'a [u8]> {
+        let (slice, offset) = decode_size_prefixed_slice(&self.rep[self.index..])?;
+        self.index += offset;
+        Some(slice)
+    }
+}
+
+impl<'a> Iterator for WriteBatchIter<'a> {
+    type Item = Result<BatchEntry<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let entry = self.next_entry();
+        if !matches!(entry, Some(Ok(_))) {
+            self.done = true;
+        }
+        entry
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::str::from_utf8;
+    use std::{str::from_utf8, sync::Arc};
 
-    use super::WriteBatch;
+    use super::{BatchEntry, WriteBatch, HEADER_SIZE};
     use crate::{
+        db::ColumnFamilyHandle,
         dbformat::{InternalKeyComparator, ParsedInternalKey, ValueType},
         memtable::MemTable,
         util::BytewiseComparator,
     };
 
     fn print_contents(b: &WriteBatch) -> String {
-        let cmp = InternalKeyComparator::new(Box::new(BytewiseComparator::new()));
+        let cmp = InternalKeyComparator::new(Arc::new(BytewiseComparator::new()));
         let mem = MemTable::new(cmp);
-        let status = b.insert_into(&mut mem.borrow_mut());
-        let mem_inner = mem.borrow();
-        let mut iter = mem_inner.new_iterator();
+        let status = b.insert_into(&mem);
+        let mut iter = mem.new_iterator();
         iter.seek_to_first();
         let mut result = String::new();
         let mut count = 0;
@@ -198,11 +501,29 @@ mod tests {
                     result.push_str(&format!("Delete({})", from_utf8(ikey.user_key()).unwrap(),));
                     count += 1;
                 }
+                ValueType::Merge => {
+                    result.push_str(&format!(
+                        "Merge({}, {})",
+                        from_utf8(ikey.user_key()).unwrap(),
+                        from_utf8(iter.value()).unwrap()
+                    ));
+                    count += 1;
+                }
+                ValueType::RangeDeletion => unreachable!(),
             }
             result.push('@');
             result.push_str(&ikey.sequence().to_string());
             iter.next();
         }
+        for tombstone in mem.range_tombstones() {
+            result.push_str(&format!(
+                "DeleteRange({}, {})@{}",
+                from_utf8(&tombstone.start).unwrap(),
+                from_utf8(&tombstone.end).unwrap(),
+                tombstone.sequence
+            ));
+            count += 1;
+        }
 
         if status.is_err() {
             result.push_str("ParseError()");
@@ -222,9 +543,9 @@ mod tests {
     #[test]
     fn test_write_batch_multiple() {
         let mut batch = WriteBatch::new();
-        batch.put("foo".as_bytes(), "bar".as_bytes());
-        batch.delete("box".as_bytes());
-        batch.put("baz".as_bytes(), "boo".as_bytes());
+        batch.put("foo".as_bytes(), "bar".as_bytes()).unwrap();
+        batch.delete("box".as_bytes()).unwrap();
+        batch.put("baz".as_bytes(), "boo".as_bytes()).unwrap();
         batch.set_sequence(100);
         assert_eq!(100, batch.sequence());
         assert_eq!(3, batch.count());
@@ -234,15 +555,44 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_write_batch_merge_and_delete_range() {
+        let mut batch = WriteBatch::new();
+        batch.merge(b"foo", b"1").unwrap();
+        batch.delete_range(b"a", b"c").unwrap();
+        batch.merge(b"foo", b"2").unwrap();
+        batch.set_sequence(10);
+        assert_eq!(
+            "Merge(foo, 2)@12Merge(foo, 1)@10DeleteRange(a, c)@11",
+            print_contents(&batch)
+        );
+        assert_eq!(
+            Some(BatchEntry::DeleteRange {
+                begin: b"a",
+                end: b"c"
+            }),
+            batch.iter().nth(1).map(Result::unwrap)
+        );
+    }
+
     #[test]
     fn test_write_batch_corruption() {
         let mut batch = WriteBatch::new();
-        batch.put("foo".as_bytes(), "bar".as_bytes());
-        batch.delete("box".as_bytes());
+        batch.put("foo".as_bytes(), "bar".as_bytes()).unwrap();
+        batch.delete("box".as_bytes()).unwrap();
         batch.set_sequence(200);
         let content = batch.contents().to_owned();
         batch.set_contents(&content[..content.len() - 1]);
         assert_eq!("Put(foo, bar)@200ParseError()", print_contents(&batch));
+
+        // An unknown tag is rejected, not trusted
+        let mut content = content;
+        content[HEADER_SIZE + 9] = 0x7f;
+        batch.set_contents(&content);
+        assert_eq!("Put(foo, bar)@200ParseError()", print_contents(&batch));
+        let error = batch.iter().nth(1).unwrap().unwrap_err();
+        assert!(error.is_corruption());
+        assert!(error.to_string().contains("unknown value type 127"));
     }
 
     #[test]
@@ -253,14 +603,14 @@ mod tests {
         b2.set_sequence(300);
         b1.append(&b2);
         assert_eq!("", print_contents(&b1));
-        b2.put("a".as_bytes(), "va".as_bytes());
+        b2.put("a".as_bytes(), "va".as_bytes()).unwrap();
         b1.append(&b2);
         assert_eq!("Put(a, va)@200", print_contents(&b1));
         b2.clear();
-        b2.put("b".as_bytes(), "vb".as_bytes());
+        b2.put("b".as_bytes(), "vb".as_bytes()).unwrap();
         b1.append(&b2);
         assert_eq!("Put(a, va)@200Put(b, vb)@201", print_contents(&b1));
-        b2.delete("foo".as_bytes());
+        b2.delete("foo".as_bytes()).unwrap();
         b1.append(&b2);
         assert_eq!(
             "Put(a, va)@200Put(b, vb)@202Put(b, vb)@201Delete(foo)@203",
@@ -273,16 +623,190 @@ mod tests {
         let mut batch = WriteBatch::new();
         let empty_size = batch.approximate_size();
 
-        batch.put("foo".as_bytes(), "bar".as_bytes());
+        batch.put("foo".as_bytes(), "bar".as_bytes()).unwrap();
         let one_key_size = batch.approximate_size();
         assert!(empty_size < one_key_size);
 
-        batch.put("baz".as_bytes(), "boo".as_bytes());
+        batch.put("baz".as_bytes(), "boo".as_bytes()).unwrap();
         let two_key_size = batch.approximate_size();
         assert!(one_key_size < two_key_size);
 
-        batch.delete("box".as_bytes());
+        batch.delete("box".as_bytes()).unwrap();
         let post_delete_size = batch.approximate_size();
         assert!(two_key_size < post_delete_size);
     }
+
+    #[test]
+    fn test_write_batch_iter() {
+        let mut batch = WriteBatch::new();
+        batch.put(b"foo", b"bar").unwrap();
+        batch.delete(b"box").unwrap();
+        let entries: Vec<_> = batch.iter().map(Result::unwrap).collect();
+        assert_eq!(
+            vec![
+                BatchEntry::Put {
+                    key: b"foo",
+                    value: b"bar"
+                },
+                BatchEntry::Delete { key: b"box" },
+            ],
+            entries
+        );
+
+        // A bad count shows up after the updates
+        batch.set_count(3);
+        let mut iter = batch.iter();
+        assert!(iter.next().unwrap().is_ok());
+        assert!(iter.next().unwrap().is_ok());
+        assert!(iter.next().unwrap().unwrap_err().is_corruption());
+        assert!(iter.next().is_none());
+
+        batch.set_contents(b"short");
+        assert_eq!(1, batch.iter().count());
+        assert!(batch.iter().next().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_write_batch_save_points() {
+        let mut batch = WriteBatch::new();
+        assert!(batch.rollback_to_save_point().unwrap_err().is_not_found());
+        batch.put(b"a", b"va").unwrap();
+        batch.set_save_point();
+        batch.put(b"b", b"vb").unwrap();
+        batch.set_save_point();
+        batch.delete(b"a").unwrap();
+        batch.set_save_point();
+        batch.pop_save_point().unwrap();
+        batch.put(b"c", b"vc").unwrap();
+
+        batch.rollback_to_save_point().unwrap();
+        assert_eq!(2, batch.count());
+        assert_eq!("Put(a, va)@0Put(b, vb)@1", print_contents(&batch));
+        batch.rollback_to_save_point().unwrap();
+        assert_eq!("Put(a, va)@0", print_contents(&batch));
+        assert!(batch.rollback_to_save_point().is_err());
+        assert!(batch.pop_save_point().is_err());
+
+        batch.set_save_point();
+        batch.clear();
+        assert!(batch.rollback_to_save_point().is_err());
+        assert_eq!("", print_contents(&batch));
+    }
+
+    #[test]
+    fn test_write_batch_capacity_limit() {
+        let mut batch = WriteBatch::with_capacity_limit(HEADER_SIZE + 20);
+        batch.put(b"foo", b"bar").unwrap();
+        batch.delete(b"box").unwrap();
+        assert_eq!(HEADER_SIZE + 14, batch.approximate_size());
+        let error = batch.put(b"baz", b"boo").unwrap_err();
+        assert!(error.is_invalid_argument());
+        assert_eq!(HEADER_SIZE + 14, batch.approximate_size());
+        batch.delete(b"bo").unwrap();
+        assert_eq!(HEADER_SIZE + 18, batch.approximate_size());
+        assert!(batch.delete(b"b").is_err());
+        assert_eq!(
+            "Delete(bo)@2Delete(box)@1Put(foo, bar)@0",
+            print_contents(&batch)
+        );
+
+        let mut unlimited = WriteBatch::with_capacity_limit(0);
+        unlimited.put(b"k", &[0; 1 << 20]).unwrap();
+    }
+
+    #[test]
+    fn test_write_batch_column_families() {
+        let users = ColumnFamilyHandle::new(1, "users");
+        let mut batch = WriteBatch::new();
+        batch.put(b"a", b"va").unwrap();
+        batch.put_cf(&users, b"b", b"vb").unwrap();
+        batch.set_save_point();
+        batch.delete_cf(&users, b"c").unwrap();
+        batch.delete(b"d").unwrap();
+        assert_eq!(4, batch.count());
+        assert_eq!(vec![1], batch.column_families());
+        let entries: Vec<_> = batch.iter().map(Result::unwrap).collect();
+        assert_eq!(
+            vec![
+                BatchEntry::Put {
+                    key: b"a",
+                    value: b"va"
+                },
+                BatchEntry::ColumnFamily { id: 1 },
+                BatchEntry::Put {
+                    key: b"b",
+                    value: b"vb"
+                },
+                BatchEntry::Delete { key: b"c" },
+                BatchEntry::ColumnFamily { id: 0 },
+                BatchEntry::Delete { key: b"d" },
+            ],
+            entries
+        );
+
+        // Each update goes to the memtable of its column family
+        batch.set_sequence(10);
+        let cmp = InternalKeyComparator::new(Arc::new(BytewiseComparator::new()));
+        let (default, other) = (MemTable::new(cmp.clone()), MemTable::new(cmp));
+        let sequences = |mem: &MemTable| {
+            let mut iter = mem.new_iterator();
+            iter.seek_to_first();
+            let mut sequences = vec![];
+            while iter.valid() {
+                let ikey = ParsedInternalKey::parse(iter.key()).unwrap();
+                sequences.push((ikey.user_key().to_vec(), ikey.sequence()));
+                iter.next();
+            }
+            sequences
+        };
+        batch
+            .insert_into_column_families(&|id| match id {
+                0 => Some(&default),
+                1 => Some(&other),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(
+            vec![(b"a".to_vec(), 10), (b"d".to_vec(), 13)],
+            sequences(&default)
+        );
+        assert_eq!(
+            vec![(b"b".to_vec(), 11), (b"c".to_vec(), 12)],
+            sequences(&other)
+        );
+
+        // A rollback restores the column family the batch was in
+        batch.rollback_to_save_point().unwrap();
+        batch.put_cf(&users, b"e", b"ve").unwrap();
+        assert_eq!(
+            3,
+            batch
+                .iter()
+                .filter(|entry| matches!(entry, Ok(BatchEntry::Put { .. })))
+                .count()
+        );
+        assert_eq!(
+            1,
+            batch
+                .iter()
+                .filter(|entry| matches!(entry, Ok(BatchEntry::ColumnFamily { .. })))
+                .count()
+        );
+
+        // Appended updates start in the default column family
+        let mut other = WriteBatch::new();
+        other.put(b"f", b"vf").unwrap();
+        batch.append(&other);
+        let mut restored = WriteBatch::new();
+        restored.set_contents(batch.contents());
+        assert_eq!(vec![1], restored.column_families());
+        let mem = MemTable::new(InternalKeyComparator::new(Arc::new(
+            BytewiseComparator::new(),
+        )));
+        restored.insert_into(&mem).unwrap();
+        assert_eq!(
+            vec![(b"a".to_vec(), 10), (b"f".to_vec(), 13)],
+            sequences(&mem)
+        );
+    }
 }
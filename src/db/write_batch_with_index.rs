@@ -0,0 +1,512 @@
+use std::{cmp::Ordering, sync::Arc};
+
+use super::{LookupState, ReadConfig, WriteBatch, DB, DEFAULT_COLUMN_FAMILY};
+use crate::{
+    dbformat::{
+        extract_user_key, InternalKeyComparator, LookupKey, ValueType, MAX_SEQUENCE_NUMBER,
+    },
+    iterator::Iterator,
+    memtable::MemTable,
+    util::{Comparator, MergeOperator},
+    Error, Result,
+};
+
+/// A WriteBatch that also indexes its updates by key, so that what it
+/// writes can be read back before it is applied to a database, on its
+/// own or on top of the contents of the database.
+///
+/// The index is a memtable in which each update is stored with its
+/// position in the batch as sequence number: the newest update of a key
+/// comes first, as in a database.  The updates are all of the default
+/// column family.
+pub struct WriteBatchWithIndex {
+    batch: WriteBatch,
+    comparator: InternalKeyComparator,
+    index: Arc<MemTable>,
+    /// The sequence number of the last update in "index"
+    last_sequence: u64,
+}
+
+impl WriteBatchWithIndex {
+    /// Create an empty batch whose keys are ordered by "comparator", which
+    /// must be that of the database it is written to.
+    pub fn new(comparator: Arc<dyn Comparator>) -> Self {
+        let comparator = InternalKeyComparator::new(comparator);
+        Self {
+            batch: WriteBatch::new(),
+            index: MemTable::new(comparator.clone()),
+            comparator,
+            last_sequence: 0,
+        }
+    }
+
+    /// Store the mapping "key->value" in the database.
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.batch.put(key, value)?;
+        self.add(ValueType::Value, key, value);
+        Ok(())
+    }
+
+    /// If the database contains a mapping for "key", erase it.  Else do
+    /// nothing.
+    pub fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.batch.delete(key)?;
+        self.add(ValueType::Deletion, key, b"");
+        Ok(())
+    }
+
+    /// Erase the mappings of the keys in [begin, end).
+    pub fn delete_range(&mut self, begin: &[u8], end: &[u8]) -> Result<()> {
+        self.batch.delete_range(begin, end)?;
+        self.add(ValueType::RangeDeletion, begin, end);
+        Ok(())
+    }
+
+    /// Merge "operand" into the value of "key", see WriteBatch::merge().
+    pub fn merge(&mut self, key: &[u8], operand: &[u8]) -> Result<()> {
+        self.batch.merge(key, operand)?;
+        self.add(ValueType::Merge, key, operand);
+        Ok(())
+    }
+
+    /// Clear all updates buffered in this batch.
+    pub fn clear(&mut self) {
+        self.batch.clear();
+        self.index = MemTable::new(self.comparator.clone());
+        self.last_sequence = 0;
+    }
+
+    /// The number of updates in the batch.
+    pub fn len(&self) -> usize {
+        self.last_sequence as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.last_sequence == 0
+    }
+
+    /// Return the updates of the batch, to be written with DB::write().
+    pub fn write_batch(&self) -> &WriteBatch {
+        &self.batch
+    }
+
+    pub(crate) fn write_batch_mut(&mut self) -> &mut WriteBatch {
+        &mut self.batch
+    }
+
+    /// Return the value "key" would have if the batch was written to
+    /// "db" now: its value in the batch, or in the database as read with
+    /// "config" if the batch does not update it, with the merge operands
+    /// of the batch applied.  Returns an error that satisfies
+    /// Error::is_not_found() if it would have none.
+    pub fn get_from_batch_and_db(
+        &self,
+        db: &DB,
+        key: &[u8],
+        config: &ReadConfig,
+    ) -> Result<Vec<u8>> {
+        let mut state = LookupState::default();
+        match self.get(key, &mut state) {
+            Some(found) => state.finish(db.config.merge_operator.as_ref(), key, Some(found)),
+            None => db.get_below(DEFAULT_COLUMN_FAMILY, key, config, state),
+        }
+    }
+
+    /// Return an iterator over the contents "db" would have if the batch
+    /// was written to it now, like DB::iter(): the contents of the
+    /// database read with "config", updated by those of the batch.  The
+    /// iterate bounds of "config" apply to the batch too.
+    ///
+    /// The iterator reads the batch as it is when each key is visited, so
+    /// the batch must not change while it is used.
+    pub fn iter_with_db<'a>(&'a self, db: &'a DB, config: &ReadConfig) -> Box<dyn Iterator + 'a> {
+        let delta = BatchKeyIterator {
+            iter: self.index.new_bounded_iterator(
+                config.iterate_lower_bound.as_deref(),
+                config.iterate_upper_bound.as_deref(),
+            ),
+        };
+        Box::new(BaseDeltaIterator {
+            batch: self,
+            base: db.iter(config),
+            delta,
+            ucmp: self.comparator.user_comparator().clone(),
+            merge_operator: db.config.merge_operator.clone(),
+            direction: Direction::Forward,
+            key: vec![],
+            value: vec![],
+            valid: false,
+            status: Ok(()),
+        })
+    }
+
+    fn add(&mut self, type_: ValueType, key: &[u8], value: &[u8]) {
+        self.last_sequence += 1;
+        self.index.add(self.last_sequence, type_, key, value);
+    }
+
+    /// Look "key" up in the batch, like MemTable::get(): returns None if
+    /// the batch holds no update of it other than merge operands, which
+    /// are added to "state", and otherwise its newest value, or a not
+    /// found error if the batch deletes it.
+    pub(crate) fn get(&self, key: &[u8], state: &mut LookupState) -> Option<Result<Vec<u8>>> {
+        // The range tombstones of the batch hide every older entry of the
+        // key, those of the database included, so they cannot be carried
+        // over to it as sequence numbers
+        let mut batch_state = LookupState::default();
+        let found = self
+            .index
+            .get(&LookupKey::new(key, MAX_SEQUENCE_NUMBER), &mut batch_state);
+        state.merge_operands.append(&mut batch_state.merge_operands);
+        match found {
+            None if batch_state.max_covering_tombstone_sequence > 0 => {
+                Some(Err(Error::not_found("")))
+            }
+            found => found,
+        }
+    }
+}
+
+/// Yields each user key of the index of a batch once, with the value of
+/// its newest entry
+struct BatchKeyIterator<'a> {
+    iter: Box<dyn Iterator + 'a>,
+}
+
+impl Iterator for BatchKeyIterator<'_> {
+    fn valid(&self) -> bool {
+        self.iter.valid()
+    }
+
+    fn seek_to_first(&mut self) {
+        self.iter.seek_to_first();
+    }
+
+    fn seek_to_last(&mut self) {
+        self.iter.seek_to_last();
+        if self.iter.valid() {
+            // Move to the newest entry of the key
+            let key = LookupKey::new(self.key(), MAX_SEQUENCE_NUMBER);
+            self.iter.seek(key.internal_key());
+        }
+    }
+
+    fn seek(&mut self, target: &[u8]) {
+        self.iter
+            .seek(LookupKey::new(target, MAX_SEQUENCE_NUMBER).internal_key());
+    }
+
+    fn seek_for_prev(&mut self, target: &[u8]) {
+        // Sequence number zero sorts after every entry of "target"
+        self.iter
+            .seek_for_prev(LookupKey::new(target, 0).internal_key());
+        if self.iter.valid() {
+            let key = LookupKey::new(self.key(), MAX_SEQUENCE_NUMBER);
+            self.iter.seek(key.internal_key());
+        }
+    }
+
+    fn next(&mut self) {
+        // Skip the older entries of the key: the sequence numbers of the
+        // index start at one
+        let key = LookupKey::new(self.key(), 0);
+        self.iter.seek(key.internal_key());
+    }
+
+    fn prev(&mut self) {
+        self.iter.prev();
+        if self.iter.valid() {
+            let key = LookupKey::new(self.key(), MAX_SEQUENCE_NUMBER);
+            self.iter.seek(key.internal_key());
+        }
+    }
+
+    fn key(&self) -> &[u8] {
+        extract_user_key(self.iter.key())
+    }
+
+    fn value(&self) -> &[u8] {
+        self.iter.value()
+    }
+
+    fn status(&self) -> Result<()> {
+        self.iter.status()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Forward,
+    Reverse,
+}
+
+/// Merges the user keys of a database iterator, the base, with those of
+/// a batch, the delta.  The value of each key is looked up in the batch,
+/// and taken from the base, with the merge operands of the batch applied
+/// to it, if the batch neither sets nor deletes it.
+struct BaseDeltaIterator<'a> {
+    batch: &'a WriteBatchWithIndex,
+    base: Box<dyn Iterator + 'a>,
+    delta: BatchKeyIterator<'a>,
+    ucmp: Arc<dyn Comparator>,
+    merge_operator: Option<Arc<dyn MergeOperator>>,
+    /// Moving forward, both children are positioned at or after "key",
+    /// and moving backward at or before it
+    direction: Direction,
+    key: Vec<u8>,
+    value: Vec<u8>,
+    valid: bool,
+    status: Result<()>,
+}
+
+impl BaseDeltaIterator<'_> {
+    /// Move the children that are at "key" one key in "direction".
+    fn step(&mut self) {
+        for child in [self.base.as_mut(), &mut self.delta as &mut dyn Iterator] {
+            if child.valid() && self.ucmp.compare(child.key(), &self.key) == Ordering::Equal {
+                match self.direction {
+                    Direction::Forward => child.next(),
+                    Direction::Reverse => child.prev(),
+                }
+            }
+        }
+    }
+
+    /// Position the iterator at the first key of the children in
+    /// "direction" that the batch does not delete.
+    fn find_visible(&mut self) {
+        self.valid = false;
+        while self.status.is_ok() {
+            // A child that stopped at an error would leave keys out
+            if let Err(error) = self.base.status().and_then(|()| self.delta.status()) {
+                self.status = Err(error);
+                return;
+            }
+            // Whether the base is at the next key, the delta possibly too
+            let at_base = match (self.base.valid(), self.delta.valid()) {
+                (false, false) => return,
+                (true, false) => true,
+                (false, true) => false,
+                (true, true) => {
+                    let mut ordering = self.ucmp.compare(self.base.key(), self.delta.key());
+                    if self.direction == Direction::Reverse {
+                        ordering = ordering.reverse();
+                    }
+                    ordering != Ordering::Greater
+                }
+            };
+            self.key = match at_base {
+                true => self.base.key().to_vec(),
+                false => self.delta.key().to_vec(),
+            };
+            let mut state = LookupState::default();
+            let found = match self.batch.get(&self.key, &mut state) {
+                Some(found) => Some(found),
+                None => at_base.then(|| Ok(self.base.value().to_vec())),
+            };
+            match state.finish(self.merge_operator.as_ref(), &self.key, found) {
+                Ok(value) => {
+                    self.value = value;
+                    self.valid = true;
+                    return;
+                }
+                // Deleted by the batch
+                Err(error) if error.is_not_found() => self.step(),
+                Err(error) => self.status = Err(error),
+            }
+        }
+    }
+}
+
+impl Iterator for BaseDeltaIterator<'_> {
+    fn valid(&self) -> bool {
+        self.valid
+    }
+
+    fn seek_to_first(&mut self) {
+        self.base.seek_to_first();
+        self.delta.seek_to_first();
+        self.direction = Direction::Forward;
+        self.find_visible();
+    }
+
+    fn seek_to_last(&mut self) {
+        self.base.seek_to_last();
+        self.delta.seek_to_last();
+        self.direction = Direction::Reverse;
+        self.find_visible();
+    }
+
+    fn seek(&mut self, target: &[u8]) {
+        self.base.seek(target);
+        self.delta.seek(target);
+        self.direction = Direction::Forward;
+        self.find_visible();
+    }
+
+    fn seek_for_prev(&mut self, target: &[u8]) {
+        self.base.seek_for_prev(target);
+        self.delta.seek_for_prev(target);
+        self.direction = Direction::Reverse;
+        self.find_visible();
+    }
+
+    fn next(&mut self) {
+        assert!(self.valid);
+        if self.direction == Direction::Reverse {
+            self.base.seek(&self.key);
+            self.delta.seek(&self.key);
+            self.direction = Direction::Forward;
+        }
+        self.step();
+        self.find_visible();
+    }
+
+    fn prev(&mut self) {
+        assert!(self.valid);
+        if self.direction == Direction::Forward {
+            self.base.seek_for_prev(&self.key);
+            self.delta.seek_for_prev(&self.key);
+            self.direction = Direction::Reverse;
+        }
+        self.step();
+        self.find_visible();
+    }
+
+    fn key(&self) -> &[u8] {
+        assert!(self.valid);
+        &self.key
+    }
+
+    fn value(&self) -> &[u8] {
+        assert!(self.valid);
+        &self.value
+    }
+
+    fn status(&self) -> Result<()> {
+        self.status.clone()?;
+        self.base.status()?;
+        self.delta.status()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        db::{DBConfig, WriteConfig},
+        env::test_dir,
+        iterator::IterAdapter,
+        StringAppendOperator,
+    };
+
+    fn get(db: &DB, key: &[u8]) -> String {
+        match db.get(key, &ReadConfig::default()) {
+            Ok(value) => String::from_utf8(value).unwrap(),
+            Err(error) if error.is_not_found() => "NOT_FOUND".to_string(),
+            Err(error) => error.to_string(),
+        }
+    }
+
+    fn contents(iter: &mut dyn Iterator, reverse: bool) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let entries = match reverse {
+            true => IterAdapter::from_last(iter),
+            false => IterAdapter::from_first(iter),
+        };
+        entries.collect::<Result<_>>().unwrap()
+    }
+
+    #[test]
+    fn test_write_batch_with_index() {
+        let dbname = test_dir("write_batch_with_index");
+        let config = DBConfig {
+            merge_operator: Some(Arc::new(StringAppendOperator::new(b','))),
+            create_if_missing: true,
+            ..Default::default()
+        };
+        let db = DB::open(&dbname, &config).unwrap();
+        let write_config = WriteConfig::default();
+        let read_config = ReadConfig::default();
+        for key in ["a", "b", "c", "d", "e", "f"] {
+            db.put(key.as_bytes(), b"db", &write_config).unwrap();
+        }
+        db.flush().unwrap();
+        db.put(b"g", b"db", &write_config).unwrap();
+
+        let mut batch = WriteBatchWithIndex::new(config.comparator.clone());
+        batch.put(b"a", b"batch").unwrap();
+        batch.delete(b"b").unwrap();
+        batch.merge(b"c", b"1").unwrap();
+        batch.merge(b"c", b"2").unwrap();
+        batch.delete_range(b"d", b"f").unwrap();
+        batch.put(b"d", b"batch").unwrap();
+        batch.merge(b"h", b"1").unwrap();
+        batch.put(b"i", b"batch").unwrap();
+        batch.delete(b"i").unwrap();
+        assert_eq!(9, batch.len());
+
+        let get_merged = |key: &[u8]| match batch.get_from_batch_and_db(&db, key, &read_config) {
+            Ok(value) => String::from_utf8(value).unwrap(),
+            Err(error) if error.is_not_found() => "NOT_FOUND".to_string(),
+            Err(error) => error.to_string(),
+        };
+        assert_eq!("batch", get_merged(b"a"));
+        assert_eq!("NOT_FOUND", get_merged(b"b"));
+        assert_eq!("db,1,2", get_merged(b"c"));
+        assert_eq!("batch", get_merged(b"d"));
+        assert_eq!("NOT_FOUND", get_merged(b"e"));
+        assert_eq!("db", get_merged(b"f"));
+        assert_eq!("db", get_merged(b"g"));
+        assert_eq!("1", get_merged(b"h"));
+        assert_eq!("NOT_FOUND", get_merged(b"i"));
+        assert_eq!("NOT_FOUND", get_merged(b"j"));
+
+        let expected: Vec<_> = [
+            ("a", "batch"),
+            ("c", "db,1,2"),
+            ("d", "batch"),
+            ("f", "db"),
+            ("g", "db"),
+            ("h", "1"),
+        ]
+        .iter()
+        .map(|(k, v)| (k.as_bytes().to_vec(), v.as_bytes().to_vec()))
+        .collect();
+        let mut iter = batch.iter_with_db(&db, &read_config);
+        assert_eq!(expected, contents(iter.as_mut(), false));
+        let mut reversed = expected.clone();
+        reversed.reverse();
+        assert_eq!(reversed, contents(iter.as_mut(), true));
+
+        // Change direction in the middle, and seek into a deleted range
+        iter.seek(b"e");
+        assert_eq!(b"f", iter.key());
+        iter.prev();
+        assert_eq!(b"d", iter.key());
+        iter.prev();
+        assert_eq!(b"c", iter.key());
+        iter.next();
+        assert_eq!(b"d", iter.key());
+        iter.seek(b"i");
+        assert!(!iter.valid());
+        drop(iter);
+
+        // The iterate bounds apply to both
+        let bounded = ReadConfig {
+            iterate_lower_bound: Some(b"b".to_vec()),
+            iterate_upper_bound: Some(b"h".to_vec()),
+            ..ReadConfig::default()
+        };
+        let mut iter = batch.iter_with_db(&db, &bounded);
+        assert_eq!(expected[1..5], contents(iter.as_mut(), false));
+        drop(iter);
+
+        // Nothing is written until the batch is
+        assert_eq!("db", get(&db, b"b"));
+        let mut updates = WriteBatch::new();
+        updates.append(batch.write_batch());
+        db.write(&mut updates, &write_config).unwrap();
+        let mut iter = db.iter(&read_config);
+        assert_eq!(expected, contents(iter.as_mut(), false));
+    }
+}
@@ -1,30 +1,74 @@
-use std::{cmp::Ordering, intrinsics::copy_nonoverlapping, io::BufRead, ptr::null_mut, slice};
+use std::{cmp::Ordering, sync::Arc};
 
 use crate::util::{
-    decode_fixed64, encode_fixed64, encode_varint32, extend_fixed64, Comparator, FilterPolicy,
+    decode_fixed64, extend_fixed64, extend_varint32, Comparator, Error, FilterPolicy,
 };
 
+/// Number of levels in the LSM tree.
+pub const NUM_LEVELS: usize = 7;
+
+/// Level-0 compaction is started when we hit this many files.
+pub const L0_COMPACTION_TRIGGER: usize = 4;
+
+/// Soft limit on number of level-0 files.  We slow down writes at this point.
+pub const L0_SLOWDOWN_WRITES_TRIGGER: usize = 8;
+
+/// Maximum number of level-0 files.  We stop writes at this point.
+pub const L0_STOP_WRITES_TRIGGER: usize = 12;
+
+/// The largest sequence number a write can have.  Sequence numbers take
+/// the upper 56 bits of the 8-byte tag of an internal key.
 pub const MAX_SEQUENCE_NUMBER: u64 = (1 << 56) - 1;
 
-#[derive(Clone, Copy, PartialEq, Debug)]
+/// The kind of entry an internal key stands for: a value of the user key,
+/// a deletion of it, an operand to merge into its value, or the start of
+/// a deletion of a range of user keys.
+///
+/// NOTE: do not change the values of existing entries, as these are
+/// embedded in the on-disk data structures.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 #[repr(u8)]
 pub enum ValueType {
     Deletion = 0x0,
     Value = 0x1,
+    /// Combined with the older entries of the key by the MergeOperator of
+    /// the database when read
+    Merge = 0x2,
+    /// Range tombstones are kept apart from the other entries: in their
+    /// own skiplist in a memtable, and in a meta block in a table.  The
+    /// user key is the start of the range, and the value its end.
+    RangeDeletion = 0xf,
 }
 
-impl From<u8> for ValueType {
-    fn from(value: u8) -> Self {
+impl TryFrom<u8> for ValueType {
+    type Error = Error;
+
+    /// Fails with a corruption error for bytes that are no ValueType,
+    /// e.g. a damaged tag of a key or of a WriteBatch entry.
+    fn try_from(value: u8) -> Result<Self, Error> {
         match value {
-            0x0 => Self::Deletion,
-            0x1 => Self::Value,
-            _ => panic!(),
+            0x0 => Ok(Self::Deletion),
+            0x1 => Ok(Self::Value),
+            0x2 => Ok(Self::Merge),
+            0xf => Ok(Self::RangeDeletion),
+            _ => Err(Error::corruption(&format!("unknown value type {}", value))),
         }
     }
 }
 
-pub const VALUE_TYPE_FOR_SEEK: ValueType = ValueType::Value;
-
+/// The ValueType to seek with: sequence numbers sort in decreasing
+/// order, with the type in the low 8 bits of the tag, so a seek must use
+/// the highest-numbered type of the entries sought.  Range deletions are
+/// never stored among the other entries, so that is Merge.
+pub const VALUE_TYPE_FOR_SEEK: ValueType = ValueType::Merge;
+
+/// The parts of an internal key, the form keys are stored in by the
+/// memtable, the log and the tables: the user key, followed by an 8-byte
+/// tag holding the sequence number of the write and the type of the entry.
+///
+/// Useful e.g. to decode the keys of a table read with table::Table, or
+/// those returned by the internal iterators of the database.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub struct ParsedInternalKey<'a> {
     user_key: &'a [u8],
     sequence: u64,
@@ -40,17 +84,21 @@ impl<'a> ParsedInternalKey<'a> {
         }
     }
 
-    /// Attempt to parse an internal key from "internal_key".
-    pub fn parse(internal_key: &[u8]) -> Option<ParsedInternalKey> {
+    /// Attempt to parse an internal key from "internal_key".  Returns
+    /// None if it is too short to hold a tag, or if the tag has an
+    /// unknown type.  The user key of the result borrows from
+    /// "internal_key".
+    pub fn parse(internal_key: &[u8]) -> Option<ParsedInternalKey<'_>> {
         let n = internal_key.len();
         if n < 8 {
             return None;
         }
         let num = decode_fixed64(&internal_key[n - 8..]);
+        let type_ = ValueType::try_from(num as u8).ok()?;
         Some(ParsedInternalKey::new(
-            unsafe { slice::from_raw_parts(internal_key.as_ptr(), n - 8) },
+            &internal_key[..n - 8],
             num >> 8,
-            (num as u8).into(),
+            type_,
         ))
     }
 
@@ -60,38 +108,56 @@ impl<'a> ParsedInternalKey<'a> {
         extend_fixed64(dst, self.sequence << 8 | self.type_ as u64);
     }
 
-    pub fn user_key(&self) -> &[u8] {
+    /// The key as the user wrote it
+    pub fn user_key(&self) -> &'a [u8] {
         self.user_key
     }
 
+    /// The sequence number of the write of the entry
     pub fn sequence(&self) -> u64 {
         self.sequence
     }
 
+    /// Whether the entry is a value or a deletion
     pub fn type_(&self) -> ValueType {
         self.type_
     }
+
+    /// Return a human-readable form of the key, for logging
+    pub fn debug_string(&self) -> String {
+        format!(
+            "'{}' @ {} : {}",
+            self.user_key.escape_ascii(),
+            self.sequence,
+            self.type_ as u8
+        )
+    }
 }
 
-fn extract_user_key(internal_key: &[u8]) -> &[u8] {
+pub fn extract_user_key(internal_key: &[u8]) -> &[u8] {
     &internal_key[..internal_key.len() - 8]
 }
 
 /// TODO: maybe make it a template?
+#[derive(Clone)]
 pub struct InternalKeyComparator {
-    user_comparator: Box<dyn Comparator>,
+    user_comparator: Arc<dyn Comparator>,
 }
 
 impl InternalKeyComparator {
-    pub fn new(user_comparator: Box<dyn Comparator>) -> Self {
+    pub fn new(user_comparator: Arc<dyn Comparator>) -> Self {
         Self { user_comparator }
     }
 
-    pub fn user_comparator(&self) -> &dyn Comparator {
-        self.user_comparator.as_ref()
+    pub fn user_comparator(&self) -> &Arc<dyn Comparator> {
+        &self.user_comparator
     }
 }
 
+/// Name of InternalKeyComparator, by which tables of internal keys are
+/// recognized.
+pub const INTERNAL_KEY_COMPARATOR_NAME: &str = "leveldb.InternalKeyComparator";
+
 impl Comparator for InternalKeyComparator {
     fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
         // Order by:
@@ -111,7 +177,7 @@ impl Comparator for InternalKeyComparator {
     }
 
     fn name(&self) -> &str {
-        "leveldb.InternalKeyComparator"
+        INTERNAL_KEY_COMPARATOR_NAME
     }
 
     fn find_shortest_separator(&self, start: &[u8], limit: &[u8]) -> Vec<u8> {
@@ -157,12 +223,13 @@ impl Comparator for InternalKeyComparator {
     }
 }
 
-struct InternalFilterPolicy {
-    user_policy: Box<dyn FilterPolicy>,
+/// Filter policy wrapper that converts from internal keys to user keys
+pub struct InternalFilterPolicy {
+    user_policy: Arc<dyn FilterPolicy>,
 }
 
 impl InternalFilterPolicy {
-    fn new(user_policy: Box<dyn FilterPolicy>) -> Self {
+    pub fn new(user_policy: Arc<dyn FilterPolicy>) -> Self {
         Self { user_policy }
     }
 }
@@ -186,7 +253,12 @@ impl FilterPolicy for InternalFilterPolicy {
     }
 }
 
-struct InternalKey {
+/// Modules in this directory should keep internal keys wrapped inside
+/// the following struct instead of plain byte vectors so that we do not
+/// incorrectly use byte-wise comparisons instead of an
+/// InternalKeyComparator.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct InternalKey {
     rep: Vec<u8>,
 }
 
@@ -214,71 +286,103 @@ impl InternalKey {
     pub fn user_key(&self) -> &[u8] {
         extract_user_key(&self.rep)
     }
+
+    pub fn debug_string(&self) -> String {
+        match ParsedInternalKey::parse(&self.rep) {
+            Some(parsed) => parsed.debug_string(),
+            None => format!("(bad){}", self.rep.escape_ascii()),
+        }
+    }
 }
 
-const LOOKUP_KEY_STACK_SPACE: usize = 200;
+/// A deletion of all the user keys in [start, end), as of "sequence": it
+/// hides the entries of those keys with smaller sequence numbers.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RangeTombstone {
+    pub start: Vec<u8>,
+    pub end: Vec<u8>,
+    pub sequence: u64,
+}
 
-enum LookupKeyInner {
-    OnStack([u8; LOOKUP_KEY_STACK_SPACE]),
-    OnHeap(Vec<u8>),
+impl RangeTombstone {
+    pub fn new(start: &[u8], end: &[u8], sequence: u64) -> Self {
+        Self {
+            start: start.to_vec(),
+            end: end.to_vec(),
+            sequence,
+        }
+    }
+
+    /// Decode a tombstone stored as an entry whose key is the internal
+    /// key returned by internal_key(), and whose value is the end of the
+    /// range.  Returns None if "key" is not the key of a range tombstone.
+    pub fn decode(key: &[u8], end: &[u8]) -> Option<Self> {
+        let parsed = ParsedInternalKey::parse(key)?;
+        (parsed.type_() == ValueType::RangeDeletion)
+            .then(|| Self::new(parsed.user_key(), end, parsed.sequence()))
+    }
+
+    /// The internal key tombstones are stored and sorted under: the start
+    /// of the range, with the sequence number of the tombstone.
+    pub fn internal_key(&self) -> InternalKey {
+        InternalKey::new(&self.start, self.sequence, ValueType::RangeDeletion)
+    }
+
+    /// Return whether "user_key" is in the range of the tombstone.
+    pub fn covers(&self, ucmp: &dyn Comparator, user_key: &[u8]) -> bool {
+        ucmp.compare(&self.start, user_key) != Ordering::Greater
+            && ucmp.compare(user_key, &self.end) == Ordering::Less
+    }
 }
 
+/// A helper class useful for DB::get()
 pub struct LookupKey {
-    start: *mut u8,
-    kstart: *mut u8,
-    end: *mut u8,
-    space: LookupKeyInner,
+    /// We construct a byte vector of the form:
+    ///    klength  varint32               <-- 0
+    ///    userkey  char[klength]          <-- kstart
+    ///    tag      uint64
+    data: Vec<u8>,
+    kstart: usize,
 }
 
 impl LookupKey {
+    /// Initialize for looking up user_key at a snapshot with the specified
+    /// sequence number.
     pub fn new(user_key: &[u8], sequence: u64) -> Self {
         let ksize = user_key.len();
-        let needed = ksize + 13;
-        let mut result = Self {
-            start: null_mut(),
-            kstart: null_mut(),
-            end: null_mut(),
-            space: if needed <= LOOKUP_KEY_STACK_SPACE {
-                LookupKeyInner::OnStack([0; LOOKUP_KEY_STACK_SPACE])
-            } else {
-                LookupKeyInner::OnHeap(vec![0; needed])
-            },
-        };
-        result.start = match result.space {
-            LookupKeyInner::OnStack(mut data) => data.as_mut_ptr(),
-            LookupKeyInner::OnHeap(ref mut data) => data.as_mut_ptr(),
-        };
-        let mut target = unsafe { slice::from_raw_parts_mut(result.start, needed) };
-        let koffset = encode_varint32(&mut target, (ksize + 8) as u32);
-        unsafe {
-            result.kstart = result.start.add(koffset);
-            copy_nonoverlapping(user_key.as_ptr(), result.kstart, ksize);
-            encode_fixed64(
-                &mut target[koffset + ksize..],
-                sequence << 8 | VALUE_TYPE_FOR_SEEK as u64,
-            );
-            result.end = result.start.add(koffset + ksize + 8);
-        }
-        result
+        let mut data = Vec::with_capacity(ksize + 13);
+        extend_varint32(&mut data, (ksize + 8) as u32);
+        let kstart = data.len();
+        data.extend_from_slice(user_key);
+        extend_fixed64(&mut data, sequence << 8 | VALUE_TYPE_FOR_SEEK as u64);
+        Self { data, kstart }
     }
 
+    /// Return a key suitable for lookup in a MemTable.
     pub fn memtable_key(&self) -> &[u8] {
-        unsafe { slice::from_raw_parts(self.start, self.end.offset_from(self.start) as usize) }
+        &self.data
     }
 
+    /// Return an internal key (suitable for passing to an internal iterator)
     pub fn internal_key(&self) -> &[u8] {
-        unsafe { slice::from_raw_parts(self.kstart, self.end.offset_from(self.kstart) as usize) }
+        &self.data[self.kstart..]
     }
 
+    /// Return the user key
     pub fn user_key(&self) -> &[u8] {
-        unsafe {
-            slice::from_raw_parts(self.kstart, self.end.offset_from(self.kstart) as usize - 8)
-        }
+        &self.data[self.kstart..self.data.len() - 8]
+    }
+
+    /// Return the sequence number the key is looked up at
+    pub fn sequence(&self) -> u64 {
+        decode_fixed64(&self.data[self.data.len() - 8..]) >> 8
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::sync::Arc;
+
     use super::{InternalKey, InternalKeyComparator, ParsedInternalKey, ValueType};
     use crate::{
         dbformat::{MAX_SEQUENCE_NUMBER, VALUE_TYPE_FOR_SEEK},
@@ -292,20 +396,21 @@ mod tests {
     }
 
     fn shorten(short: &[u8], long: &[u8]) -> Vec<u8> {
-        InternalKeyComparator::new(Box::new(BytewiseComparator::new()))
+        InternalKeyComparator::new(Arc::new(BytewiseComparator::new()))
             .find_shortest_separator(short, long)
     }
 
     fn short_successor(short: &[u8]) -> Vec<u8> {
-        InternalKeyComparator::new(Box::new(BytewiseComparator::new())).find_short_successor(short)
+        InternalKeyComparator::new(Arc::new(BytewiseComparator::new())).find_short_successor(short)
     }
 
     fn test_key(user_key: &[u8], seq: u64, type_: ValueType) {
         let encoded = ikey(user_key, seq, type_);
         let decoded = ParsedInternalKey::parse(&encoded).unwrap();
-        assert_eq!(user_key, decoded.user_key);
-        assert_eq!(seq, decoded.sequence);
-        assert_eq!(type_, decoded.type_);
+        assert_eq!(user_key, decoded.user_key());
+        assert_eq!(seq, decoded.sequence());
+        assert_eq!(type_, decoded.type_());
+        assert_eq!(ParsedInternalKey::new(user_key, seq, type_), decoded);
     }
 
     #[test]
@@ -333,6 +438,21 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_format_internal_key_parse_errors() {
+        assert_eq!(None, ParsedInternalKey::parse(b"1234567"));
+        // Unknown type
+        let mut encoded = ikey(b"foo", 5, ValueType::Value);
+        let n = encoded.len();
+        encoded[n - 8] = 0x7f;
+        assert_eq!(None, ParsedInternalKey::parse(&encoded));
+        encoded[n - 8] = ValueType::Deletion as u8;
+        assert_eq!(
+            "'foo' @ 5 : 0",
+            ParsedInternalKey::parse(&encoded).unwrap().debug_string()
+        );
+    }
+
     #[test]
     fn test_format_internal_key_decode_from_empty() {
         let mut internal_key = InternalKey::new_empty();
@@ -0,0 +1,498 @@
+//! An Env wrapper for testing crash recovery.  It remembers how much of
+//! every file it wrote was synced, so that a crash can be simulated by
+//! throwing away everything that a real machine could lose on power
+//! failure: unsynced data and files whose directory entry was never
+//! synced.  It can also make writes fail on demand.
+
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsString,
+    sync::{Arc, Mutex},
+};
+
+use super::{
+    posix_env::{dirname, is_manifest},
+    read_file_to_vec, Env, FileLock, Logger, RandomAccessFile, SequentialFile, WritableFile,
+    WriteHint,
+};
+use crate::util::{Error, Result};
+
+/// Sync state of a file written through the FaultInjectionEnv.
+#[derive(Clone, Copy, Debug, Default)]
+struct FileState {
+    /// Bytes appended so far
+    pos: u64,
+    /// Bytes that survive a crash
+    pos_at_last_sync: u64,
+}
+
+#[derive(Default)]
+struct FaultState {
+    /// Files written since the env was created or last reset
+    files: HashMap<String, FileState>,
+    /// Files that disappear on a crash because their directory was not
+    /// synced since they were created
+    new_files_since_last_dir_sync: HashSet<String>,
+    /// False after a simulated crash: writes and syncs are then dropped
+    filesystem_inactive: bool,
+    /// Number of writes that succeed before every write fails
+    writes_before_failure: Option<u64>,
+}
+
+impl FaultState {
+    /// Count a write against writes_before_failure.  Returns false if
+    /// the write must fail.
+    fn allow_write(&mut self) -> bool {
+        match self.writes_before_failure.as_mut() {
+            Some(0) => false,
+            Some(remaining) => {
+                *remaining -= 1;
+                true
+            }
+            None => true,
+        }
+    }
+
+    fn sync_dir(&mut self, dname: &str) {
+        self.new_files_since_last_dir_sync
+            .retain(|fname| dirname(fname) != dname);
+    }
+}
+
+/// Wraps another Env, by default a PosixEnv or MemEnv, and forwards
+/// everything to it while keeping track of what a crash would lose.
+pub struct FaultInjectionEnv {
+    base: Arc<dyn Env>,
+    state: Arc<Mutex<FaultState>>,
+}
+
+impl FaultInjectionEnv {
+    pub fn new(base: Arc<dyn Env>) -> Self {
+        Self {
+            base,
+            state: Arc::new(Mutex::new(FaultState::default())),
+        }
+    }
+
+    /// Stop or resume writing.  While the filesystem is inactive, appends
+    /// and syncs report success but are dropped, as if the machine had
+    /// died after the last successful write.
+    pub fn set_filesystem_active(&self, active: bool) {
+        self.state.lock().unwrap().filesystem_inactive = !active;
+    }
+
+    /// Let the next "writes" appends succeed and fail every one after
+    /// that with an IO error.  None turns failing off again.
+    pub fn set_writes_before_failure(&self, writes: Option<u64>) {
+        self.state.lock().unwrap().writes_before_failure = writes;
+    }
+
+    /// Truncate every file written through this env to its size at the
+    /// last sync.
+    pub fn drop_unsynced_file_data(&self) -> Result<()> {
+        let files: Vec<(String, FileState)> = self
+            .state
+            .lock()
+            .unwrap()
+            .files
+            .iter()
+            .map(|(fname, file)| (fname.clone(), *file))
+            .collect();
+        for (fname, file) in files {
+            if file.pos_at_last_sync < file.pos {
+                self.truncate(&fname, file.pos_at_last_sync)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove the files whose directory was not synced after they were
+    /// created.
+    pub fn remove_files_created_after_last_dir_sync(&self) -> Result<()> {
+        let new_files: Vec<String> = self
+            .state
+            .lock()
+            .unwrap()
+            .new_files_since_last_dir_sync
+            .drain()
+            .collect();
+        for fname in new_files {
+            self.base.remove_file(&fname)?;
+        }
+        Ok(())
+    }
+
+    /// Simulate a power failure: drop everything that was not made
+    /// durable, then start over with an active filesystem, as after a
+    /// reboot.  The database must have been dropped first, with the
+    /// filesystem inactive so that closing it doesn't write anything.
+    pub fn crash(&self) -> Result<()> {
+        self.drop_unsynced_file_data()?;
+        self.remove_files_created_after_last_dir_sync()?;
+        self.reset_state();
+        Ok(())
+    }
+
+    /// Forget what was written so far and reactivate the filesystem.
+    pub fn reset_state(&self) {
+        *self.state.lock().unwrap() = FaultState::default();
+    }
+
+    /// Cut "fname" down to its first "size" bytes.
+    fn truncate(&self, fname: &str, size: u64) -> Result<()> {
+        let mut contents = read_file_to_vec(self.base.as_ref(), fname)?;
+        contents.truncate(size as usize);
+        let mut file = self.base.new_writable_file(fname, WriteHint::Other)?;
+        file.append(&contents)?;
+        file.sync()?;
+        file.close()
+    }
+
+    fn wrap(&self, fname: &str, target: Box<dyn WritableFile>, pos: u64) -> Box<dyn WritableFile> {
+        let mut state = self.state.lock().unwrap();
+        let file = state.files.entry(fname.to_string()).or_default();
+        file.pos = pos;
+        file.pos_at_last_sync = file.pos_at_last_sync.min(pos);
+        Box::new(TestWritableFile {
+            target,
+            fname: fname.to_string(),
+            state: self.state.clone(),
+        })
+    }
+}
+
+impl Env for FaultInjectionEnv {
+    fn new_sequential_file(&self, fname: &str) -> Result<Box<dyn SequentialFile>> {
+        self.base.new_sequential_file(fname)
+    }
+
+    fn new_random_access_file(&self, fname: &str) -> Result<Box<dyn RandomAccessFile>> {
+        self.base.new_random_access_file(fname)
+    }
+
+    fn new_writable_file(&self, fname: &str, hint: WriteHint) -> Result<Box<dyn WritableFile>> {
+        let existed = self.base.file_exists(fname);
+        let target = self.base.new_writable_file(fname, hint)?;
+        if !existed {
+            self.state
+                .lock()
+                .unwrap()
+                .new_files_since_last_dir_sync
+                .insert(fname.to_string());
+        }
+        Ok(self.wrap(fname, target, 0))
+    }
+
+    fn new_appendable_file(&self, fname: &str) -> Result<Box<dyn WritableFile>> {
+        let existed = self.base.file_exists(fname);
+        let target = self.base.new_appendable_file(fname)?;
+        let mut pos = 0;
+        if existed {
+            // The existing contents are taken to be durable
+            pos = self.base.get_file_size(fname)?;
+            let mut state = self.state.lock().unwrap();
+            let file = state.files.entry(fname.to_string()).or_default();
+            if file.pos == file.pos_at_last_sync {
+                file.pos_at_last_sync = pos;
+            }
+        } else {
+            self.state
+                .lock()
+                .unwrap()
+                .new_files_since_last_dir_sync
+                .insert(fname.to_string());
+        }
+        Ok(self.wrap(fname, target, pos))
+    }
+
+    fn file_exists(&self, fname: &str) -> bool {
+        self.base.file_exists(fname)
+    }
+
+    fn get_children(&self, dname: &str) -> Result<Vec<OsString>> {
+        self.base.get_children(dname)
+    }
+
+    fn remove_file(&self, fname: &str) -> Result<()> {
+        self.base.remove_file(fname)?;
+        let mut state = self.state.lock().unwrap();
+        state.files.remove(fname);
+        state.new_files_since_last_dir_sync.remove(fname);
+        Ok(())
+    }
+
+    fn create_dir(&self, dname: &str) -> Result<()> {
+        self.base.create_dir(dname)
+    }
+
+    fn remove_dir(&self, dname: &str) -> Result<()> {
+        self.base.remove_dir(dname)
+    }
+
+    fn sync_dir(&self, dname: &str) -> Result<()> {
+        if self.state.lock().unwrap().filesystem_inactive {
+            return Ok(());
+        }
+        self.base.sync_dir(dname)?;
+        self.state.lock().unwrap().sync_dir(dname);
+        Ok(())
+    }
+
+    fn get_file_size(&self, fname: &str) -> Result<u64> {
+        self.base.get_file_size(fname)
+    }
+
+    fn rename_file(&self, src: &str, target: &str) -> Result<()> {
+        self.base.rename_file(src, target)?;
+        let mut state = self.state.lock().unwrap();
+        match state.files.remove(src) {
+            Some(file) => state.files.insert(target.to_string(), file),
+            None => state.files.remove(target),
+        };
+        // Until the directory is synced, a crash may undo the rename
+        // as well as the creation of the source
+        state.new_files_since_last_dir_sync.remove(src);
+        state
+            .new_files_since_last_dir_sync
+            .insert(target.to_string());
+        Ok(())
+    }
+
+    fn link_file(&self, src: &str, target: &str) -> Result<()> {
+        self.base.link_file(src, target)?;
+        // Until the directory is synced, a crash may undo the link
+        self.state
+            .lock()
+            .unwrap()
+            .new_files_since_last_dir_sync
+            .insert(target.to_string());
+        Ok(())
+    }
+
+    fn lock_file(&self, fname: &str) -> Result<Box<dyn FileLock>> {
+        self.base.lock_file(fname)
+    }
+
+    fn unlock_file(&self, lock: Box<dyn FileLock>) -> Result<()> {
+        self.base.unlock_file(lock)
+    }
+
+    fn new_logger(&self, fname: &str) -> Result<Box<dyn Logger + '_>> {
+        self.base.new_logger(fname)
+    }
+
+    fn schedule(&self, job: Box<dyn FnOnce() + Send>) {
+        self.base.schedule(job)
+    }
+
+    fn start_thread(&self, job: Box<dyn FnOnce() + Send>) {
+        self.base.start_thread(job)
+    }
+}
+
+/// Forwards to the wrapped file, recording what was synced.
+struct TestWritableFile {
+    target: Box<dyn WritableFile>,
+    fname: String,
+    state: Arc<Mutex<FaultState>>,
+}
+
+impl TestWritableFile {
+    fn file_state<T>(&self, f: impl FnOnce(&mut FileState) -> T) -> Option<T> {
+        self.state.lock().unwrap().files.get_mut(&self.fname).map(f)
+    }
+}
+
+impl WritableFile for TestWritableFile {
+    fn append(&mut self, data: &[u8]) -> Result<()> {
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.filesystem_inactive {
+                return Ok(());
+            }
+            if !state.allow_write() {
+                return Err(Error::io_error(&format!(
+                    "{}: injected write error",
+                    self.fname
+                )));
+            }
+        }
+        self.target.append(data)?;
+        self.file_state(|file| file.pos += data.len() as u64);
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.target.close()
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if self.state.lock().unwrap().filesystem_inactive {
+            return Ok(());
+        }
+        self.target.flush()
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        if self.state.lock().unwrap().filesystem_inactive {
+            return Ok(());
+        }
+        self.target.sync()?;
+        self.file_state(|file| file.pos_at_last_sync = file.pos);
+        // Like the PosixEnv, syncing a MANIFEST syncs its directory
+        if is_manifest(&self.fname) {
+            self.state.lock().unwrap().sync_dir(dirname(&self.fname));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        db::{DBConfig, ReadConfig, WriteConfig, DB},
+        env::MemEnv,
+    };
+
+    #[test]
+    fn test_fault_injection_drop_unsynced_data() {
+        let env = FaultInjectionEnv::new(Arc::new(MemEnv::new()));
+        let mut file = env.new_writable_file("/dir/f", WriteHint::Other).unwrap();
+        file.append(b"synced").unwrap();
+        file.sync().unwrap();
+        file.append(b" unsynced").unwrap();
+        drop(file);
+
+        // Appendable files keep their existing contents across a crash
+        let mut file = env.new_appendable_file("/dir/f").unwrap();
+        file.append(b" appended").unwrap();
+        drop(file);
+        assert_eq!(24, env.get_file_size("/dir/f").unwrap());
+
+        env.drop_unsynced_file_data().unwrap();
+        assert_eq!(
+            b"synced".to_vec(),
+            read_file_to_vec(&env, "/dir/f").unwrap()
+        );
+
+        // The file itself is lost, as its directory was never synced
+        env.remove_files_created_after_last_dir_sync().unwrap();
+        assert!(!env.file_exists("/dir/f"));
+    }
+
+    #[test]
+    fn test_fault_injection_dir_sync() {
+        let env = FaultInjectionEnv::new(Arc::new(MemEnv::new()));
+        let mut table = env
+            .new_writable_file("/db/000005.ldb", WriteHint::TableHot)
+            .unwrap();
+        table.append(b"table").unwrap();
+        table.sync().unwrap();
+        let mut other = env
+            .new_writable_file("/other/000006.ldb", WriteHint::TableHot)
+            .unwrap();
+        other.sync().unwrap();
+        // Syncing a MANIFEST syncs its directory, so only files created
+        // in that directory later or elsewhere are lost
+        let mut manifest = env
+            .new_writable_file("/db/MANIFEST-000004", WriteHint::Manifest)
+            .unwrap();
+        manifest.sync().unwrap();
+        env.new_writable_file("/db/000007.log", WriteHint::Wal)
+            .unwrap();
+        env.rename_file("/db/000005.ldb", "/db/000008.ldb").unwrap();
+
+        env.crash().unwrap();
+        assert!(env.file_exists("/db/MANIFEST-000004"));
+        assert!(!env.file_exists("/other/000006.ldb"));
+        assert!(!env.file_exists("/db/000007.log"));
+        assert!(!env.file_exists("/db/000008.ldb"));
+
+        // As does syncing the directory itself
+        env.new_writable_file("/other/000009.ldb", WriteHint::TableHot)
+            .unwrap();
+        env.sync_dir("/other").unwrap();
+        env.crash().unwrap();
+        assert!(env.file_exists("/other/000009.ldb"));
+    }
+
+    #[test]
+    fn test_fault_injection_write_failure() {
+        let env = FaultInjectionEnv::new(Arc::new(MemEnv::new()));
+        let mut file = env.new_writable_file("/dir/f", WriteHint::Other).unwrap();
+        env.set_writes_before_failure(Some(2));
+        file.append(b"a").unwrap();
+        file.append(b"b").unwrap();
+        assert!(file.append(b"c").unwrap_err().is_io_error());
+        assert!(file.append(b"d").unwrap_err().is_io_error());
+        env.set_writes_before_failure(None);
+        file.append(b"e").unwrap();
+        drop(file);
+        assert_eq!(b"abe".to_vec(), read_file_to_vec(&env, "/dir/f").unwrap());
+    }
+
+    #[test]
+    fn test_fault_injection_db_crash_recovery() {
+        let env = Arc::new(FaultInjectionEnv::new(Arc::new(MemEnv::new())));
+        let dbname = "/db";
+        let config = DBConfig {
+            env: env.clone(),
+            create_if_missing: true,
+            write_buffer_size: 10000,
+            ..Default::default()
+        };
+        let key = |i: usize| format!("key{:04}", i);
+        let value = |i: usize| format!("{:0>200}", i);
+        let put_range = |db: &DB, range: std::ops::Range<usize>| {
+            for i in range {
+                db.put(
+                    key(i).as_bytes(),
+                    value(i).as_bytes(),
+                    &WriteConfig::default(),
+                )
+                .unwrap();
+            }
+        };
+
+        // Flushed memtables are durable, writes still in the log are not
+        // as the log is never synced
+        let db = DB::open(dbname, &config).unwrap();
+        put_range(&db, 0..300);
+        let stats = db.statistics();
+        assert!(stats.levels.iter().any(|level| level.files > 0));
+        env.set_filesystem_active(false);
+        put_range(&db, 300..310);
+        drop(db);
+        env.crash().unwrap();
+
+        let db = DB::open(dbname, &config).unwrap();
+        let mut recovered = 0;
+        for i in 0..310 {
+            match db.get(key(i).as_bytes(), &ReadConfig::default()) {
+                Ok(v) => {
+                    assert_eq!(value(i).into_bytes(), v);
+                    recovered += 1;
+                }
+                Err(error) => assert!(error.is_not_found()),
+            }
+        }
+        // Whatever survived is a prefix of the writes
+        assert!(recovered < 300);
+        for i in 0..recovered {
+            assert!(db.get(key(i).as_bytes(), &ReadConfig::default()).is_ok());
+        }
+
+        // The recovered database keeps working, through another crash
+        put_range(&db, 1000..1300);
+        env.set_filesystem_active(false);
+        drop(db);
+        env.crash().unwrap();
+        let db = DB::open(dbname, &config).unwrap();
+        assert_eq!(
+            value(1000).into_bytes(),
+            db.get(key(1000).as_bytes(), &ReadConfig::default())
+                .unwrap()
+        );
+    }
+}
@@ -0,0 +1,463 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsString,
+    sync::{Arc, Mutex, RwLock},
+    thread,
+};
+
+use super::{
+    BackgroundPool, Env, FileLock, Logger, RandomAccessFile, SequentialFile, WritableFile,
+    WriteHint,
+};
+use crate::util::{Error, Result};
+
+/// The contents of one file.  Readers and writers that opened the file
+/// share it, and keep it alive after it is removed or replaced.
+#[derive(Default)]
+struct FileState {
+    contents: RwLock<Vec<u8>>,
+}
+
+impl FileState {
+    fn size(&self) -> usize {
+        self.contents.read().unwrap().len()
+    }
+
+    /// Copy the bytes at "offset" into "dst", or as many of them as the
+    /// file holds.  Returns the number of bytes copied.
+    fn read(&self, offset: usize, dst: &mut [u8]) -> usize {
+        let contents = self.contents.read().unwrap();
+        let available = contents.get(offset..).unwrap_or_default();
+        let n = dst.len().min(available.len());
+        dst[..n].copy_from_slice(&available[..n]);
+        n
+    }
+
+    fn append(&self, data: &[u8]) {
+        self.contents.write().unwrap().extend_from_slice(data);
+    }
+}
+
+type FileSystem = HashMap<String, Arc<FileState>>;
+
+/// An Env that keeps all files in memory, for tests and for databases
+/// that need not outlive the process.  Files are dropped with the Env.
+/// Directories are implicit: every file name with a '/' belongs to the
+/// directory before the last one, whether or not it was created.
+#[derive(Default)]
+pub struct MemEnv {
+    file_map: Mutex<FileSystem>,
+    /// The names of the files locked by lock_file().
+    locks: Arc<Mutex<HashSet<String>>>,
+    background: BackgroundPool,
+}
+
+impl MemEnv {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Like new(), with the jobs of schedule() run by "threads" threads
+    /// rather than the default two.
+    pub fn with_background_threads(threads: usize) -> Self {
+        Self {
+            background: BackgroundPool::new(threads),
+            ..Self::default()
+        }
+    }
+
+    fn file(&self, fname: &str) -> Result<Arc<FileState>> {
+        match self.file_map.lock().unwrap().get(fname) {
+            Some(file) => Ok(file.clone()),
+            None => Err(not_found(fname)),
+        }
+    }
+}
+
+fn not_found(fname: &str) -> Error {
+    Error::not_found(&format!("{}: file not found", fname))
+}
+
+impl Env for MemEnv {
+    fn new_sequential_file(&self, fname: &str) -> Result<Box<dyn SequentialFile>> {
+        Ok(Box::new(MemSequentialFile {
+            file: self.file(fname)?,
+            pos: 0,
+        }))
+    }
+
+    fn new_random_access_file(&self, fname: &str) -> Result<Box<dyn RandomAccessFile>> {
+        Ok(Box::new(MemRandomAccessFile {
+            file: self.file(fname)?,
+            filename: fname.to_string(),
+        }))
+    }
+
+    fn new_writable_file(&self, fname: &str, _hint: WriteHint) -> Result<Box<dyn WritableFile>> {
+        // Readers of a replaced file keep its old contents
+        let file = Arc::new(FileState::default());
+        self.file_map
+            .lock()
+            .unwrap()
+            .insert(fname.to_string(), file.clone());
+        Ok(Box::new(MemWritableFile { file }))
+    }
+
+    fn new_appendable_file(&self, fname: &str) -> Result<Box<dyn WritableFile>> {
+        let file = self
+            .file_map
+            .lock()
+            .unwrap()
+            .entry(fname.to_string())
+            .or_default()
+            .clone();
+        Ok(Box::new(MemWritableFile { file }))
+    }
+
+    fn file_exists(&self, fname: &str) -> bool {
+        self.file_map.lock().unwrap().contains_key(fname)
+    }
+
+    fn get_children(&self, dname: &str) -> Result<Vec<OsString>> {
+        let prefix = format!("{}/", dname.trim_end_matches('/'));
+        Ok(self
+            .file_map
+            .lock()
+            .unwrap()
+            .keys()
+            .filter_map(|fname| fname.strip_prefix(&prefix))
+            .filter(|child| !child.contains('/'))
+            .map(OsString::from)
+            .collect())
+    }
+
+    fn remove_file(&self, fname: &str) -> Result<()> {
+        match self.file_map.lock().unwrap().remove(fname) {
+            Some(_) => Ok(()),
+            None => Err(not_found(fname)),
+        }
+    }
+
+    fn create_dir(&self, _dname: &str) -> Result<()> {
+        Ok(())
+    }
+
+    fn remove_dir(&self, _dname: &str) -> Result<()> {
+        Ok(())
+    }
+
+    fn sync_dir(&self, _dname: &str) -> Result<()> {
+        Ok(())
+    }
+
+    fn get_file_size(&self, fname: &str) -> Result<u64> {
+        Ok(self.file(fname)?.size() as u64)
+    }
+
+    fn rename_file(&self, src: &str, target: &str) -> Result<()> {
+        let mut file_map = self.file_map.lock().unwrap();
+        match file_map.remove(src) {
+            Some(file) => {
+                file_map.insert(target.to_string(), file);
+                Ok(())
+            }
+            None => Err(not_found(src)),
+        }
+    }
+
+    fn link_file(&self, src: &str, target: &str) -> Result<()> {
+        let mut file_map = self.file_map.lock().unwrap();
+        if file_map.contains_key(target) {
+            return Err(Error::io_error(&format!("{}: file exists", target)));
+        }
+        match file_map.get(src).cloned() {
+            Some(file) => {
+                file_map.insert(target.to_string(), file);
+                Ok(())
+            }
+            None => Err(not_found(src)),
+        }
+    }
+
+    fn lock_file(&self, fname: &str) -> Result<Box<dyn FileLock>> {
+        if !self.locks.lock().unwrap().insert(fname.to_string()) {
+            return Err(Error::io_error(&format!(
+                "lock {}: already held by process",
+                fname
+            )));
+        }
+        // Like on disk, the lock file is created if missing
+        self.file_map
+            .lock()
+            .unwrap()
+            .entry(fname.to_string())
+            .or_default();
+        Ok(Box::new(MemFileLock {
+            locks: self.locks.clone(),
+            filename: fname.to_string(),
+        }))
+    }
+
+    fn unlock_file(&self, lock: Box<dyn FileLock>) -> Result<()> {
+        drop(lock);
+        Ok(())
+    }
+
+    fn new_logger(&self, _fname: &str) -> Result<Box<dyn Logger + '_>> {
+        Ok(Box::new(NoOpLogger))
+    }
+
+    fn schedule(&self, job: Box<dyn FnOnce() + Send>) {
+        self.background.schedule(job);
+    }
+
+    fn start_thread(&self, job: Box<dyn FnOnce() + Send>) {
+        thread::spawn(job);
+    }
+}
+
+struct MemSequentialFile {
+    file: Arc<FileState>,
+    pos: usize,
+}
+
+impl SequentialFile for MemSequentialFile {
+    fn read(&mut self, dst: &mut [u8]) -> Result<usize> {
+        let n = self.file.read(self.pos, dst);
+        self.pos += n;
+        Ok(n)
+    }
+
+    fn skip(&mut self, n: usize) -> Result<()> {
+        let size = self.file.size();
+        if self.pos > size {
+            return Err(Error::io_error("pos > file size"));
+        }
+        self.pos += n.min(size - self.pos);
+        Ok(())
+    }
+}
+
+struct MemRandomAccessFile {
+    file: Arc<FileState>,
+    filename: String,
+}
+
+impl RandomAccessFile for MemRandomAccessFile {
+    fn read(&self, offset: usize, dst: &mut [u8]) -> Result<()> {
+        if self.file.read(offset, dst) < dst.len() {
+            return Err(Error::io_error(&format!(
+                "{}: read past end of file",
+                self.filename
+            )));
+        }
+        Ok(())
+    }
+}
+
+struct MemWritableFile {
+    file: Arc<FileState>,
+}
+
+impl WritableFile for MemWritableFile {
+    fn append(&mut self, data: &[u8]) -> Result<()> {
+        self.file.append(data);
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Releases the lock when dropped.
+struct MemFileLock {
+    locks: Arc<Mutex<HashSet<String>>>,
+    filename: String,
+}
+
+impl FileLock for MemFileLock {}
+
+impl Drop for MemFileLock {
+    fn drop(&mut self) {
+        self.locks.lock().unwrap().remove(&self.filename);
+    }
+}
+
+struct NoOpLogger;
+
+impl Logger for NoOpLogger {
+    fn log(&self, _info: &str) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::env::{read_file_to_vec, write_data_to_file};
+
+    #[test]
+    fn test_mem_env_basics() {
+        let env = MemEnv::new();
+        env.create_dir("/dir").unwrap();
+        assert!(!env.file_exists("/dir/non_existent"));
+        assert!(env.get_file_size("/dir/non_existent").is_err());
+        assert!(env.get_children("/dir").unwrap().is_empty());
+
+        // Create a file
+        let mut file = env.new_writable_file("/dir/f", WriteHint::Other).unwrap();
+        assert_eq!(0, env.get_file_size("/dir/f").unwrap());
+        drop(file);
+
+        // Check that the file exists
+        assert!(env.file_exists("/dir/f"));
+        assert_eq!(vec![OsString::from("f")], env.get_children("/dir").unwrap());
+        // Files in subdirectories and similarly named directories are
+        // not children
+        env.new_writable_file("/dir/sub/g", WriteHint::Other)
+            .unwrap();
+        env.new_writable_file("/dirx/h", WriteHint::Other).unwrap();
+        assert_eq!(
+            vec![OsString::from("f")],
+            env.get_children("/dir/").unwrap()
+        );
+
+        // Write to the file
+        file = env.new_writable_file("/dir/f", WriteHint::Other).unwrap();
+        file.append(b"abc").unwrap();
+        drop(file);
+
+        // Check that append works
+        file = env.new_appendable_file("/dir/f").unwrap();
+        assert_eq!(3, env.get_file_size("/dir/f").unwrap());
+        file.append(b"hello").unwrap();
+        drop(file);
+        assert_eq!(8, env.get_file_size("/dir/f").unwrap());
+
+        // Check that renaming works
+        assert!(env.rename_file("/dir/non_existent", "/dir/g").is_err());
+        env.rename_file("/dir/f", "/dir/g").unwrap();
+        assert!(!env.file_exists("/dir/f"));
+        assert!(env.file_exists("/dir/g"));
+        assert_eq!(8, env.get_file_size("/dir/g").unwrap());
+
+        // Check that opening non-existent file fails
+        assert!(env
+            .new_sequential_file("/dir/non_existent")
+            .err()
+            .unwrap()
+            .is_not_found());
+        assert!(env.new_random_access_file("/dir/non_existent").is_err());
+
+        // Check that deleting works
+        assert!(env.remove_file("/dir/non_existent").is_err());
+        env.remove_file("/dir/g").unwrap();
+        assert!(!env.file_exists("/dir/g"));
+        assert!(env.get_children("/dir").unwrap().is_empty());
+        env.remove_dir("/dir").unwrap();
+    }
+
+    #[test]
+    fn test_mem_env_read_write() {
+        let env = MemEnv::new();
+        let mut file = env.new_writable_file("/dir/f", WriteHint::Other).unwrap();
+        file.append(b"hello ").unwrap();
+        file.append(b"world").unwrap();
+        drop(file);
+
+        // Read sequentially
+        let mut file = env.new_sequential_file("/dir/f").unwrap();
+        let mut buf = [0; 5];
+        assert_eq!(5, file.read(&mut buf).unwrap());
+        assert_eq!(b"hello", &buf);
+        file.skip(1).unwrap();
+        assert_eq!(5, file.read(&mut buf).unwrap());
+        assert_eq!(b"world", &buf);
+        // Try reading past EOF
+        assert_eq!(0, file.read(&mut buf).unwrap());
+        // Try to skip past end of file
+        file.skip(100).unwrap();
+        assert_eq!(0, file.read(&mut buf).unwrap());
+
+        // Random reads
+        let file = env.new_random_access_file("/dir/f").unwrap();
+        file.read(6, &mut buf).unwrap();
+        assert_eq!(b"world", &buf);
+        file.read(0, &mut buf).unwrap();
+        assert_eq!(b"hello", &buf);
+        file.read(3, &mut buf[..3]).unwrap();
+        assert_eq!(b"lo ", &buf[..3]);
+        // Reads past the end fail
+        assert!(file.read(7, &mut buf).unwrap_err().is_io_error());
+        assert!(file.read(1000, &mut buf).unwrap_err().is_io_error());
+
+        // Replacing the file leaves open readers with the old contents
+        env.new_writable_file("/dir/f", WriteHint::Other).unwrap();
+        file.read(6, &mut buf).unwrap();
+        assert_eq!(b"world", &buf);
+        assert_eq!(0, env.get_file_size("/dir/f").unwrap());
+    }
+
+    #[test]
+    fn test_mem_env_locks() {
+        let env = MemEnv::new();
+        let lock = env.lock_file("/dir/LOCK").unwrap();
+        assert!(env.file_exists("/dir/LOCK"));
+        assert!(env.lock_file("/dir/LOCK").err().unwrap().is_io_error());
+        env.unlock_file(lock).unwrap();
+        let lock = env.lock_file("/dir/LOCK").unwrap();
+        drop(lock);
+        env.lock_file("/dir/LOCK").unwrap();
+        // Locks are per Env, like files
+        MemEnv::new().lock_file("/dir/LOCK").unwrap();
+    }
+
+    #[test]
+    fn test_mem_env_link_file() {
+        let env = Arc::new(MemEnv::new());
+        write_data_to_file(env.clone(), b"hello", "/dir/f").unwrap();
+        env.link_file("/dir/f", "/backup/f").unwrap();
+        assert!(env
+            .link_file("/dir/f", "/backup/f")
+            .unwrap_err()
+            .is_io_error());
+        assert!(env
+            .link_file("/dir/g", "/backup/g")
+            .unwrap_err()
+            .is_not_found());
+
+        // The names share the contents until one of them is removed
+        let mut file = env.new_appendable_file("/dir/f").unwrap();
+        file.append(b" world").unwrap();
+        assert_eq!(
+            b"hello world".to_vec(),
+            read_file_to_vec(env.as_ref(), "/backup/f").unwrap()
+        );
+        env.remove_file("/dir/f").unwrap();
+        assert_eq!(11, env.get_file_size("/backup/f").unwrap());
+    }
+
+    #[test]
+    fn test_mem_env_large_write() {
+        let env = MemEnv::new();
+        let write_data: Vec<u8> = (0..3 * 1024 * 1024).map(|i| i as u8).collect();
+        let mut file = env.new_writable_file("/dir/f", WriteHint::Other).unwrap();
+        file.append(b"foo").unwrap();
+        file.append(&write_data).unwrap();
+        drop(file);
+
+        let contents = read_file_to_vec(&env, "/dir/f").unwrap();
+        assert_eq!(b"foo", &contents[..3]);
+        assert_eq!(write_data, contents[3..]);
+        // The logger is a no-op
+        env.new_logger("/dir/LOG").unwrap().log("discarded");
+        assert!(!env.file_exists("/dir/LOG"));
+    }
+}
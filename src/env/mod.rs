@@ -1,9 +1,21 @@
-use std::{ffi::OsString, sync::Arc};
+use std::{
+    ffi::OsString,
+    io::{Cursor, Read},
+    panic::{self, AssertUnwindSafe},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+};
 
-use crate::util::Result;
+use crate::util::{Error, Result};
 
+pub mod fault_injection;
+pub mod mem_env;
 mod posix_env;
 
+pub use fault_injection::FaultInjectionEnv;
+pub use mem_env::MemEnv;
+pub use posix_env::{EnvOptions, PosixEnv};
+
 /// An Env is an interface used by the leveldb implementation to access
 /// operating system functionality like the filesystem etc.  Callers
 /// may wish to provide a custom Env object when opening a database to
@@ -11,7 +23,7 @@ mod posix_env;
 ///
 /// All Env implementations are safe for concurrent access from
 /// multiple threads without any external synchronization.
-pub trait Env {
+pub trait Env: Send + Sync {
     /// The returned file will only be accessed by one thread at a time.
     fn new_sequential_file(&self, fname: &str) -> Result<Box<dyn SequentialFile>>;
 
@@ -19,7 +31,8 @@ pub trait Env {
     fn new_random_access_file(&self, fname: &str) -> Result<Box<dyn RandomAccessFile>>;
 
     /// The returned file will only be accessed by one thread at a time.
-    fn new_writable_file(&self, fname: &str) -> Result<Box<dyn WritableFile>>;
+    /// "hint" tells what the file will hold, see WriteHint.
+    fn new_writable_file(&self, fname: &str, hint: WriteHint) -> Result<Box<dyn WritableFile>>;
 
     fn new_appendable_file(&self, fname: &str) -> Result<Box<dyn WritableFile>>;
     fn file_exists(&self, fname: &str) -> bool;
@@ -27,37 +40,195 @@ pub trait Env {
     fn remove_file(&self, fname: &str) -> Result<()>;
     fn create_dir(&self, dname: &str) -> Result<()>;
     fn remove_dir(&self, dname: &str) -> Result<()>;
+
+    /// Sync the directory "dname", so that the files created in or
+    /// renamed into it so far survive a crash.
+    fn sync_dir(&self, dname: &str) -> Result<()>;
+
     fn get_file_size(&self, fname: &str) -> Result<u64>;
     fn rename_file(&self, src: &str, target: &str) -> Result<()>;
+
+    /// Make "target" a new name of the existing file "src", sharing its
+    /// contents.  Fails if "target" exists, or if the Env can't link
+    /// files, in which case the error satisfies Error::is_not_supported().
+    fn link_file(&self, src: &str, target: &str) -> Result<()> {
+        let _ = (src, target);
+        Err(Error::not_supported("link_file"))
+    }
+
     fn lock_file(&self, fname: &str) -> Result<Box<dyn FileLock>>;
     fn unlock_file(&self, lock: Box<dyn FileLock>) -> Result<()>;
     fn new_logger(&self, fname: &str) -> Result<Box<dyn Logger + '_>>;
-    // todo: more
-    // fn schedule(function: Box<dyn FnMut()>);
+
+    /// Arrange to run "job" once in a background thread.
+    ///
+    /// "job" may run in an unspecified thread.  Multiple jobs added to the
+    /// same Env may run concurrently in different threads.  I.e., the
+    /// caller may not assume that background work items are serialized.
+    fn schedule(&self, job: Box<dyn FnOnce() + Send>);
+
+    /// Start a new thread, invoking "job" within the new thread.
+    /// When "job" returns, the thread will be destroyed.
+    fn start_thread(&self, job: Box<dyn FnOnce() + Send>);
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Number of threads of the background pool of an Env of this module
+/// unless set otherwise: one to flush while the other compacts.
+pub(crate) const DEFAULT_BACKGROUND_THREADS: usize = 2;
+
+/// The pool of background threads behind Env::schedule() for the Envs in
+/// this module.  Jobs are started in the order they were scheduled, each
+/// on the first thread that is free, so up to "threads" of them run at
+/// the same time.  The threads are started by the first job, and exit
+/// once the pool is dropped and the jobs scheduled before are done.
+pub(crate) struct BackgroundPool {
+    threads: usize,
+    sender: Mutex<Option<mpsc::Sender<Job>>>,
+}
+
+impl BackgroundPool {
+    pub(crate) fn new(threads: usize) -> Self {
+        Self {
+            threads: threads.max(1),
+            sender: Mutex::new(None),
+        }
+    }
+
+    pub(crate) fn schedule(&self, job: Job) {
+        let mut sender = self.sender.lock().unwrap();
+        let sender = sender.get_or_insert_with(|| {
+            let (sender, receiver) = mpsc::channel::<Job>();
+            let receiver = Arc::new(Mutex::new(receiver));
+            for i in 0..self.threads {
+                let receiver = receiver.clone();
+                thread::Builder::new()
+                    .name(format!("rebeldb-bg{}", i))
+                    .spawn(move || loop {
+                        // The lock is only held while waiting for a job
+                        let job = match receiver.lock().unwrap().recv() {
+                            Ok(job) => job,
+                            Err(_) => break,
+                        };
+                        // A panicking job must not take the jobs queued
+                        // after it down with it
+                        let _ = panic::catch_unwind(AssertUnwindSafe(job));
+                    })
+                    .expect("failed to start background thread");
+            }
+            sender
+        });
+        // The threads only stop once the sender is dropped
+        sender.send(job).unwrap();
+    }
+}
+
+impl Default for BackgroundPool {
+    fn default() -> Self {
+        Self::new(DEFAULT_BACKGROUND_THREADS)
+    }
+}
+
+/// What a new file will hold, so that an Env over tiered or remote
+/// storage can place it accordingly, e.g. keep logs and hot tables on
+/// local disks and send cold tables to an object store.  Envs are free to
+/// ignore it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum WriteHint {
+    /// A write-ahead log: appended and synced often, read back only
+    /// during recovery
+    Wal,
+    /// A MANIFEST, the log of edits to the set of table files
+    Manifest,
+    /// A table of recent data, likely to be read and compacted again soon
+    TableHot,
+    /// A table written to the bottom of the tree, holding the oldest data
+    TableCold,
+    /// Large values kept apart from the tables
+    Blob,
+    /// Anything else, e.g. the CURRENT file or a trace
+    Other,
 }
 
 /// A file abstraction for reading sequentially through a file
-pub trait SequentialFile {
+pub trait SequentialFile: Send {
     fn read(&mut self, dst: &mut [u8]) -> Result<usize>;
     fn skip(&mut self, n: usize) -> Result<()>;
 }
 
+impl<T: SequentialFile + ?Sized> SequentialFile for Box<T> {
+    fn read(&mut self, dst: &mut [u8]) -> Result<usize> {
+        self.as_mut().read(dst)
+    }
+
+    fn skip(&mut self, n: usize) -> Result<()> {
+        self.as_mut().skip(n)
+    }
+}
+
+/// In-memory file contents, for parsing data that did not come from an
+/// Env, e.g. in tests and fuzz targets.
+impl SequentialFile for Cursor<Vec<u8>> {
+    fn read(&mut self, dst: &mut [u8]) -> Result<usize> {
+        Ok(Read::read(self, dst).unwrap())
+    }
+
+    fn skip(&mut self, n: usize) -> Result<()> {
+        self.set_position(self.position() + n as u64);
+        Ok(())
+    }
+}
+
 /// A file abstraction for randomly reading the contents of a file.
-pub trait RandomAccessFile {
+pub trait RandomAccessFile: Send + Sync {
     fn read(&self, offset: usize, dst: &mut [u8]) -> Result<()>;
 }
 
 /// A file abstraction for sequential writing.  The implementation
 /// must provide buffering since callers may append small fragments
 /// at a time to the file.
-pub trait WritableFile {
+pub trait WritableFile: Send {
     fn append(&mut self, data: &[u8]) -> Result<()>;
+
+    /// Append the concatenation of "slices".  Implementations that write
+    /// directly to the OS should override this to issue a single
+    /// vectored write instead of one write per slice.
+    fn append_slices(&mut self, slices: &[&[u8]]) -> Result<()> {
+        for slice in slices {
+            self.append(slice)?;
+        }
+        Ok(())
+    }
+
     fn close(&mut self) -> Result<()>;
     fn flush(&mut self) -> Result<()>;
     fn sync(&mut self) -> Result<()>;
 }
 
-pub trait FileLock {}
+impl<T: WritableFile + ?Sized> WritableFile for Box<T> {
+    fn append(&mut self, data: &[u8]) -> Result<()> {
+        self.as_mut().append(data)
+    }
+
+    fn append_slices(&mut self, slices: &[&[u8]]) -> Result<()> {
+        self.as_mut().append_slices(slices)
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.as_mut().close()
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.as_mut().flush()
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.as_mut().sync()
+    }
+}
+
+pub trait FileLock: Send {}
 
 pub trait Logger {
     // todo
@@ -70,7 +241,7 @@ fn write_data_to_file_inner(
     fname: &str,
     should_sync: bool,
 ) -> Result<()> {
-    let mut file = env.new_writable_file(fname)?;
+    let mut file = env.new_writable_file(fname, WriteHint::Other)?;
     let mut result = file.append(data);
     if result.is_ok() && should_sync {
         result = file.sync();
@@ -92,3 +263,58 @@ pub fn write_data_to_file(env: Arc<dyn Env>, data: &[u8], fname: &str) -> Result
 pub fn write_data_to_file_sync(env: Arc<dyn Env>, data: &[u8], fname: &str) -> Result<()> {
     write_data_to_file_inner(env, data, fname, true)
 }
+
+/// Read the whole contents of the named file.
+pub fn read_file_to_vec(env: &dyn Env, fname: &str) -> Result<Vec<u8>> {
+    const BUFFER_SIZE: usize = 8192;
+    let mut file = env.new_sequential_file(fname)?;
+    let mut data = vec![];
+    let mut buf = [0; BUFFER_SIZE];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        data.extend_from_slice(&buf[..n]);
+    }
+    Ok(data)
+}
+
+/// Write a copy of the contents of "src" to "target", syncing it.
+pub fn copy_file(env: &dyn Env, src: &str, target: &str, hint: WriteHint) -> Result<()> {
+    const BUFFER_SIZE: usize = 8192;
+    let mut input = env.new_sequential_file(src)?;
+    let mut file = env.new_writable_file(target, hint)?;
+    let mut buf = [0; BUFFER_SIZE];
+    let mut result = Ok(());
+    loop {
+        match input.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => result = file.append(&buf[..n]),
+            Err(error) => result = Err(error),
+        }
+        if result.is_err() {
+            break;
+        }
+    }
+    if result.is_ok() {
+        result = file.sync();
+    }
+    if result.is_ok() {
+        result = file.close();
+    }
+    drop(file);
+    if result.is_err() {
+        let _ = env.remove_file(target);
+    }
+    result
+}
+
+/// Create a fresh, empty directory for a test and return its path.
+#[cfg(test)]
+pub(crate) fn test_dir(name: &str) -> String {
+    let path = std::env::temp_dir().join(format!("rebeldb-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&path);
+    std::fs::create_dir_all(&path).unwrap();
+    path.to_str().unwrap().to_string()
+}
@@ -1,40 +1,232 @@
 use std::{
     cell::RefCell,
+    collections::BTreeSet,
     ffi::OsString,
     fs::{self, File, OpenOptions},
-    io::{self, Write},
-    mem::MaybeUninit,
+    io::{self, IoSlice, Read, Seek, SeekFrom, Write},
+    os::{fd::AsRawFd, unix::fs::FileExt},
     path::Path,
-    ptr::null_mut,
-    thread::{self, ThreadId},
-    time,
+    ptr, slice,
+    sync::{
+        atomic::{AtomicIsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
 };
 
 use chrono::Local;
 
-use super::{Env, FileLock, Logger, RandomAccessFile, SequentialFile, WritableFile};
+use super::{
+    BackgroundPool, Env, FileLock, Logger, RandomAccessFile, SequentialFile, WritableFile,
+    WriteHint, DEFAULT_BACKGROUND_THREADS,
+};
 use crate::util::{Error, Result};
 
-pub struct PosixEnv {}
+/// Up to 1000 mmap regions for 64-bit binaries; none for 32-bit.
+const DEFAULT_MMAP_LIMIT: usize = if cfg!(target_pointer_width = "64") {
+    1000
+} else {
+    0
+};
+
+/// Options for the resources a PosixEnv may hold on to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EnvOptions {
+    /// Maximum number of read-only files that are mapped into memory.
+    /// Files opened beyond the limit are read with pread() instead.
+    /// Default: 1000 for 64-bit binaries, 0 for 32-bit ones, which keeps
+    /// mmap from exhausting their address space.
+    pub mmap_limit: usize,
+    /// Maximum number of read-only files kept open by pread-based
+    /// random access files.  Files opened beyond the limit are opened
+    /// anew for every read.  Default: 20% of the process' limit on open
+    /// file descriptors.
+    pub open_read_only_file_limit: usize,
+    /// Number of threads that run the jobs of schedule(), e.g. the
+    /// flushes and compactions of the databases using the Env.
+    /// Default: 2
+    pub background_threads: usize,
+}
+
+impl Default for EnvOptions {
+    fn default() -> Self {
+        Self {
+            mmap_limit: DEFAULT_MMAP_LIMIT,
+            open_read_only_file_limit: default_open_read_only_file_limit(),
+            background_threads: DEFAULT_BACKGROUND_THREADS,
+        }
+    }
+}
+
+/// Return the maximum number of read-only files to keep open: 20% of
+/// the file descriptors the process may open, or 50 if that is unknown.
+fn default_open_read_only_file_limit() -> usize {
+    // SAFETY: rlimit is a plain C struct for which all-zeroes is valid.
+    let mut rlim: libc::rlimit = unsafe { std::mem::zeroed() };
+    // SAFETY: "rlim" is a valid rlimit to store the result in.
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut rlim) } != 0 {
+        return 50;
+    }
+    if rlim.rlim_cur == libc::RLIM_INFINITY {
+        return isize::MAX as usize;
+    }
+    // Allow use of 20% of available file descriptors for read-only files.
+    (rlim.rlim_cur / 5) as usize
+}
+
+/// Helper to limit resource usage to avoid exhaustion.  Currently used
+/// to limit read-only file descriptors and mmap file usage so that we do
+/// not run out of file descriptors or virtual memory, or run into kernel
+/// performance problems for very large databases.
+struct Limiter {
+    /// The number of available resources.  Can go negative briefly while
+    /// acquire() backs out of an acquisition past the limit.
+    acquires_allowed: AtomicIsize,
+    #[cfg(test)]
+    max_acquires: isize,
+}
+
+impl Limiter {
+    /// Limit maximum number of resources to "max_acquires".
+    fn new(max_acquires: usize) -> Self {
+        let max_acquires = max_acquires.min(isize::MAX as usize) as isize;
+        Self {
+            acquires_allowed: AtomicIsize::new(max_acquires),
+            #[cfg(test)]
+            max_acquires,
+        }
+    }
+
+    /// If another resource is available, acquire it and return true.
+    /// Else return false.
+    fn acquire(&self) -> bool {
+        let old_acquires_allowed = self.acquires_allowed.fetch_sub(1, Ordering::Relaxed);
+        if old_acquires_allowed > 0 {
+            return true;
+        }
+        self.acquires_allowed.fetch_add(1, Ordering::Relaxed);
+        false
+    }
+
+    /// Release a resource acquired by a previous call to acquire() that
+    /// returned true.
+    fn release(&self) {
+        self.acquires_allowed.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub struct PosixEnv {
+    /// Thread-safe.
+    mmap_limiter: Arc<Limiter>,
+    /// Thread-safe.
+    fd_limiter: Arc<Limiter>,
+    background: BackgroundPool,
+}
+
+impl PosixEnv {
+    pub fn new() -> Self {
+        Self::with_options(EnvOptions::default())
+    }
+
+    pub fn with_options(options: EnvOptions) -> Self {
+        Self {
+            mmap_limiter: Arc::new(Limiter::new(options.mmap_limit)),
+            fd_limiter: Arc::new(Limiter::new(options.open_read_only_file_limit)),
+            background: BackgroundPool::new(options.background_threads),
+        }
+    }
+}
+
+impl Default for PosixEnv {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl Env for PosixEnv {
     /// The returned file will only be accessed by one thread at a time.
     fn new_sequential_file(&self, fname: &str) -> Result<Box<dyn SequentialFile>> {
-        todo!()
+        match File::open(fname) {
+            Ok(file) => Ok(Box::new(PosixSequentialFile {
+                file,
+                filename: fname.to_string(),
+            })),
+            Err(error) => Err(to_db_error(fname, error)),
+        }
     }
 
     /// The returned file may be concurrently accessed by multiple threads.
     fn new_random_access_file(&self, fname: &str) -> Result<Box<dyn RandomAccessFile>> {
-        todo!()
+        let file = File::open(fname).map_err(|error| to_db_error(fname, error))?;
+
+        if !self.mmap_limiter.acquire() {
+            return Ok(Box::new(PosixRandomAccessFile::new(
+                fname,
+                file,
+                self.fd_limiter.clone(),
+            )));
+        }
+
+        let length = match file.metadata() {
+            Ok(metadata) => metadata.len() as usize,
+            Err(error) => {
+                self.mmap_limiter.release();
+                return Err(to_db_error(fname, error));
+            }
+        };
+        // Empty files can't be mapped, and need no reads anyway
+        if length == 0 {
+            self.mmap_limiter.release();
+            return Ok(Box::new(PosixRandomAccessFile::new(
+                fname,
+                file,
+                self.fd_limiter.clone(),
+            )));
+        }
+        // SAFETY: a fresh read-only shared mapping of an open descriptor;
+        // the result is checked for MAP_FAILED below.
+        let base = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                length,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if base == libc::MAP_FAILED {
+            self.mmap_limiter.release();
+            return Err(to_db_error(fname, io::Error::last_os_error()));
+        }
+        // The mapping stays valid after the descriptor is closed.
+        drop(file);
+        Ok(Box::new(PosixMmapReadableFile {
+            base: base as *const u8,
+            length,
+            mmap_limiter: self.mmap_limiter.clone(),
+            filename: fname.to_string(),
+        }))
     }
 
     /// The returned file will only be accessed by one thread at a time.
-    fn new_writable_file(&self, fname: &str) -> Result<Box<dyn WritableFile>> {
-        todo!()
+    fn new_writable_file(&self, fname: &str, _hint: WriteHint) -> Result<Box<dyn WritableFile>> {
+        match OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(fname)
+        {
+            Ok(file) => Ok(Box::new(PosixWritableFile::new(fname, file))),
+            Err(error) => Err(to_db_error(fname, error)),
+        }
     }
 
     fn new_appendable_file(&self, fname: &str) -> Result<Box<dyn WritableFile>> {
-        todo!()
+        match OpenOptions::new().create(true).append(true).open(fname) {
+            Ok(file) => Ok(Box::new(PosixWritableFile::new(fname, file))),
+            Err(error) => Err(to_db_error(fname, error)),
+        }
     }
 
     fn file_exists(&self, fname: &str) -> bool {
@@ -71,6 +263,10 @@ impl Env for PosixEnv {
         }
     }
 
+    fn sync_dir(&self, dname: &str) -> Result<()> {
+        sync_dir(dname)
+    }
+
     fn get_file_size(&self, fname: &str) -> Result<u64> {
         match fs::metadata(fname) {
             Ok(data) => Ok(data.len()),
@@ -85,25 +281,64 @@ impl Env for PosixEnv {
         }
     }
 
+    fn link_file(&self, src: &str, target: &str) -> Result<()> {
+        match fs::hard_link(src, target) {
+            Ok(()) => Ok(()),
+            Err(error) => Err(to_db_error(src, error)),
+        }
+    }
+
     fn lock_file(&self, fname: &str) -> Result<Box<dyn FileLock>> {
-        todo!()
+        let file = match OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(fname)
+        {
+            Ok(file) => file,
+            Err(error) => return Err(to_db_error(fname, error)),
+        };
+
+        if !LOCKS.insert(fname) {
+            return Err(Error::io_error(&format!(
+                "lock {}: already held by process",
+                fname
+            )));
+        }
+
+        if let Err(error) = lock_or_unlock(&file, true) {
+            LOCKS.remove(fname);
+            return Err(to_db_error(&format!("lock {}", fname), error));
+        }
+
+        Ok(Box::new(PosixFileLock {
+            file,
+            filename: fname.to_string(),
+        }))
     }
 
     fn unlock_file(&self, lock: Box<dyn FileLock>) -> Result<()> {
-        todo!()
+        // Dropping the lock releases both the fcntl lock and the entry in
+        // the lock table.
+        drop(lock);
+        Ok(())
     }
 
     fn new_logger(&self, fname: &str) -> Result<Box<dyn Logger + '_>> {
-        match OpenOptions::new()
-            .create(true)
-            .write(true)
-            .append(true)
-            .open(fname)
-        {
+        match OpenOptions::new().create(true).append(true).open(fname) {
             Ok(file) => Ok(Box::new(PosixLogger::new(file))),
-            Err(error) => return Err(to_db_error(fname, error)),
+            Err(error) => Err(to_db_error(fname, error)),
         }
     }
+
+    fn schedule(&self, job: Box<dyn FnOnce() + Send>) {
+        self.background.schedule(job);
+    }
+
+    fn start_thread(&self, job: Box<dyn FnOnce() + Send>) {
+        thread::spawn(job);
+    }
 }
 
 fn to_db_error(target: &str, error: io::Error) -> Error {
@@ -114,26 +349,362 @@ fn to_db_error(target: &str, error: io::Error) -> Error {
     }
 }
 
-struct PosixWritableFile {}
+/// Implements sequential read access in a file using read().
+struct PosixSequentialFile {
+    file: File,
+    filename: String,
+}
+
+impl SequentialFile for PosixSequentialFile {
+    fn read(&mut self, dst: &mut [u8]) -> Result<usize> {
+        loop {
+            match self.file.read(dst) {
+                Ok(n) => return Ok(n),
+                // Retry
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+                Err(error) => return Err(to_db_error(&self.filename, error)),
+            }
+        }
+    }
+
+    fn skip(&mut self, n: usize) -> Result<()> {
+        match self.file.seek(SeekFrom::Current(n as i64)) {
+            Ok(_) => Ok(()),
+            Err(error) => Err(to_db_error(&self.filename, error)),
+        }
+    }
+}
+
+/// Implements random read access in a file using pread().
+///
+/// Instances of this class are thread-safe, as required by the RandomAccessFile
+/// API.  Instances are immutable and read() only calls thread-safe library
+/// functions.
+struct PosixRandomAccessFile {
+    /// None if the file is opened on every read.
+    file: Option<File>,
+    fd_limiter: Arc<Limiter>,
+    filename: String,
+}
+
+impl PosixRandomAccessFile {
+    /// The new instance takes ownership of "file" if the limiter allows
+    /// keeping it open.  Otherwise the file is closed, and reopened on
+    /// every read.
+    fn new(filename: &str, file: File, fd_limiter: Arc<Limiter>) -> Self {
+        let file = if fd_limiter.acquire() {
+            Some(file)
+        } else {
+            None
+        };
+        Self {
+            file,
+            fd_limiter,
+            filename: filename.to_string(),
+        }
+    }
+}
+
+impl RandomAccessFile for PosixRandomAccessFile {
+    fn read(&self, offset: usize, dst: &mut [u8]) -> Result<()> {
+        let result = match &self.file {
+            Some(file) => file.read_exact_at(dst, offset as u64),
+            None => {
+                File::open(&self.filename).and_then(|file| file.read_exact_at(dst, offset as u64))
+            }
+        };
+        result.map_err(|error| to_db_error(&self.filename, error))
+    }
+}
+
+impl Drop for PosixRandomAccessFile {
+    fn drop(&mut self) {
+        if self.file.is_some() {
+            self.fd_limiter.release();
+        }
+    }
+}
+
+/// Implements random read access in a file using mmap().
+///
+/// Instances of this class are thread-safe, as required by the RandomAccessFile
+/// API.  Instances are immutable and read() only reads from the mapping.
+struct PosixMmapReadableFile {
+    /// The start of the read-only mapping of the whole file.
+    base: *const u8,
+    length: usize,
+    mmap_limiter: Arc<Limiter>,
+    filename: String,
+}
+
+// SAFETY: the mapping is read-only and owned by the instance, so sharing
+// the pointer between threads is as safe as sharing a &[u8].
+unsafe impl Send for PosixMmapReadableFile {}
+// SAFETY: see above.
+unsafe impl Sync for PosixMmapReadableFile {}
+
+impl RandomAccessFile for PosixMmapReadableFile {
+    fn read(&self, offset: usize, dst: &mut [u8]) -> Result<()> {
+        if offset
+            .checked_add(dst.len())
+            .is_none_or(|end| end > self.length)
+        {
+            let error = io::Error::from(io::ErrorKind::InvalidInput);
+            return Err(to_db_error(&self.filename, error));
+        }
+        // SAFETY: the range was checked to lie within the mapping, which
+        // lives as long as "self".
+        let contents = unsafe { slice::from_raw_parts(self.base.add(offset), dst.len()) };
+        dst.copy_from_slice(contents);
+        Ok(())
+    }
+}
+
+impl Drop for PosixMmapReadableFile {
+    fn drop(&mut self) {
+        // SAFETY: "base" and "length" describe a mapping made by
+        // new_random_access_file() that is not used after this.
+        unsafe { libc::munmap(self.base as *mut libc::c_void, self.length) };
+        self.mmap_limiter.release();
+    }
+}
+
+/// Tracks the files locked by PosixEnv::lock_file().
+///
+/// We maintain a separate set instead of relying on fcntl(F_SETLK) because
+/// fcntl(F_SETLK) does not provide any protection against multiple uses from
+/// the same process.
+struct PosixLockTable {
+    locked_files: Mutex<BTreeSet<String>>,
+}
+
+impl PosixLockTable {
+    const fn new() -> Self {
+        Self {
+            locked_files: Mutex::new(BTreeSet::new()),
+        }
+    }
+
+    /// Returns false if "fname" is already locked by this process.
+    fn insert(&self, fname: &str) -> bool {
+        self.locked_files.lock().unwrap().insert(fname.to_string())
+    }
+
+    fn remove(&self, fname: &str) {
+        self.locked_files.lock().unwrap().remove(fname);
+    }
+}
+
+static LOCKS: PosixLockTable = PosixLockTable::new();
+
+fn lock_or_unlock(file: &File, lock: bool) -> io::Result<()> {
+    // SAFETY: flock is a plain C struct for which all-zeroes is valid.
+    let mut file_lock_info: libc::flock = unsafe { std::mem::zeroed() };
+    file_lock_info.l_type = if lock { libc::F_WRLCK } else { libc::F_UNLCK } as _;
+    file_lock_info.l_whence = libc::SEEK_SET as _;
+    // Lock/unlock entire file.
+    file_lock_info.l_start = 0;
+    file_lock_info.l_len = 0;
+    // SAFETY: the descriptor is owned by "file" and outlives the call.
+    match unsafe { libc::fcntl(file.as_raw_fd(), libc::F_SETLK, &file_lock_info) } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+/// Instances are returned by PosixEnv::lock_file().  The lock is released
+/// when the instance is dropped.
+struct PosixFileLock {
+    file: File,
+    filename: String,
+}
+
+impl FileLock for PosixFileLock {}
+
+impl Drop for PosixFileLock {
+    fn drop(&mut self) {
+        // Closing the file would release the lock as well, but unlock
+        // explicitly so the lock table never runs ahead of the OS.
+        let _ = lock_or_unlock(&self.file, false);
+        LOCKS.remove(&self.filename);
+    }
+}
+
+const WRITABLE_FILE_BUFFER_SIZE: usize = 65536;
+
+struct PosixWritableFile {
+    /// Data to be written to file.
+    buf: Vec<u8>,
+    /// None after close() has been called.
+    file: Option<File>,
+    /// True if the file's name starts with MANIFEST.
+    is_manifest: bool,
+    filename: String,
+    /// The directory of filename.
+    dirname: String,
+}
+
+impl PosixWritableFile {
+    fn new(filename: &str, file: File) -> Self {
+        Self {
+            buf: Vec::with_capacity(WRITABLE_FILE_BUFFER_SIZE),
+            file: Some(file),
+            is_manifest: is_manifest(filename),
+            filename: filename.to_string(),
+            dirname: dirname(filename).to_string(),
+        }
+    }
+
+    /// Ensure new files referred to by the manifest are in the filesystem.
+    ///
+    /// This needs to happen before the manifest file is flushed to disk, to
+    /// avoid crashing in a state where the manifest refers to files that are
+    /// not yet on disk.
+    fn sync_dir_if_manifest(&self) -> Result<()> {
+        if !self.is_manifest {
+            return Ok(());
+        }
+        sync_dir(&self.dirname)
+    }
+
+    fn closed_error(&self) -> Error {
+        Error::io_error(&format!("{}: file closed", self.filename))
+    }
+
+    fn flush_buffer(&mut self) -> Result<()> {
+        let buf = std::mem::take(&mut self.buf);
+        let result = self.write_unbuffered(&[&buf]);
+        self.buf = buf;
+        self.buf.clear();
+        result
+    }
+
+    /// Write "slices" to the file with as few writev calls as possible.
+    fn write_unbuffered(&mut self, slices: &[&[u8]]) -> Result<()> {
+        let file = match self.file.as_mut() {
+            Some(file) => file,
+            None => return Err(self.closed_error()),
+        };
+        let mut io_slices: Vec<IoSlice> = slices.iter().map(|slice| IoSlice::new(slice)).collect();
+        let mut io_slices = io_slices.as_mut_slice();
+        IoSlice::advance_slices(&mut io_slices, 0);
+        while !io_slices.is_empty() {
+            match file.write_vectored(io_slices) {
+                Ok(0) => {
+                    let error = io::Error::from(io::ErrorKind::WriteZero);
+                    return Err(to_db_error(&self.filename, error));
+                }
+                Ok(written) => IoSlice::advance_slices(&mut io_slices, written),
+                // Retry
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+                Err(error) => return Err(to_db_error(&self.filename, error)),
+            }
+        }
+        Ok(())
+    }
+}
 
 impl WritableFile for PosixWritableFile {
     fn append(&mut self, data: &[u8]) -> Result<()> {
-        todo!()
+        self.append_slices(&[data])
+    }
+
+    fn append_slices(&mut self, slices: &[&[u8]]) -> Result<()> {
+        if self.file.is_none() {
+            return Err(self.closed_error());
+        }
+        let write_size: usize = slices.iter().map(|slice| slice.len()).sum();
+
+        // Fit as much as possible into buffer.
+        if self.buf.len() + write_size <= WRITABLE_FILE_BUFFER_SIZE {
+            for slice in slices {
+                self.buf.extend_from_slice(slice);
+            }
+            return Ok(());
+        }
+
+        // Can't fit in buffer, so need to do at least one write.  Small
+        // writes go to the buffer, large writes are written directly
+        // together with the buffered data in a single writev.
+        if write_size < WRITABLE_FILE_BUFFER_SIZE {
+            self.flush_buffer()?;
+            for slice in slices {
+                self.buf.extend_from_slice(slice);
+            }
+            return Ok(());
+        }
+        let buf = std::mem::take(&mut self.buf);
+        let mut all = Vec::with_capacity(slices.len() + 1);
+        all.push(buf.as_slice());
+        all.extend_from_slice(slices);
+        let result = self.write_unbuffered(&all);
+        self.buf = buf;
+        self.buf.clear();
+        result
     }
 
     fn close(&mut self) -> Result<()> {
-        todo!()
+        let result = self.flush_buffer();
+        // Dropping the file closes the descriptor.
+        self.file = None;
+        result
     }
 
     fn flush(&mut self) -> Result<()> {
-        todo!()
+        self.flush_buffer()
     }
 
     fn sync(&mut self) -> Result<()> {
-        todo!()
+        self.sync_dir_if_manifest()?;
+        self.flush_buffer()?;
+        match self.file.as_ref().map(|file| file.sync_data()) {
+            Some(Ok(())) => Ok(()),
+            Some(Err(error)) => Err(to_db_error(&self.filename, error)),
+            None => Err(self.closed_error()),
+        }
+    }
+}
+
+impl Drop for PosixWritableFile {
+    fn drop(&mut self) {
+        if self.file.is_some() {
+            // Ignoring any potential errors
+            let _ = self.close();
+        }
+    }
+}
+
+/// Syncs the directory "dname", e.g. to persist the files created in it.
+fn sync_dir(dname: &str) -> Result<()> {
+    match File::open(dname).and_then(|dir| dir.sync_all()) {
+        Ok(()) => Ok(()),
+        Err(error) => Err(to_db_error(dname, error)),
+    }
+}
+
+/// Returns the directory name in a path pointing to a file.
+///
+/// Returns "." if the path does not contain any directory separator.
+pub(super) fn dirname(filename: &str) -> &str {
+    match filename.rfind('/') {
+        Some(separator_pos) => &filename[..separator_pos],
+        None => ".",
     }
 }
 
+/// Extracts the file name from a path pointing to a file.
+fn basename(filename: &str) -> &str {
+    match filename.rfind('/') {
+        Some(separator_pos) => &filename[separator_pos + 1..],
+        None => filename,
+    }
+}
+
+/// True if the given file is a manifest file.
+pub(super) fn is_manifest(filename: &str) -> bool {
+    basename(filename).starts_with("MANIFEST")
+}
+
 struct PosixLogger {
     file: RefCell<File>,
 }
@@ -153,11 +724,265 @@ impl Logger for PosixLogger {
         // Record the thread ID.
         let thread_id = thread::current().id();
         let mut info = format!("{} {:?} {}", time, thread_id, info);
-        if info.chars().last().unwrap() != '\n' {
+        if !info.ends_with('\n') {
             info += "\n";
         }
         let mut file_inner = self.file.borrow_mut();
-        file_inner.write(info.as_bytes()).unwrap();
+        file_inner.write_all(info.as_bytes()).unwrap();
         file_inner.flush().unwrap();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{env, sync::Arc};
+
+    use super::*;
+
+    fn test_file(name: &str) -> String {
+        let path =
+            env::temp_dir().join(format!("rebeldb-posix-env-{}-{}", name, std::process::id()));
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_posix_lock_file() {
+        let env = PosixEnv::new();
+        let fname = test_file("lock");
+
+        let lock = env.lock_file(&fname).unwrap();
+        // The same process can't take the lock twice, from any thread
+        assert!(env.lock_file(&fname).err().unwrap().is_io_error());
+        let handle = thread::spawn({
+            let fname = fname.clone();
+            move || PosixEnv::new().lock_file(&fname).is_err()
+        });
+        assert!(handle.join().unwrap());
+
+        env.unlock_file(lock).unwrap();
+        let lock = env.lock_file(&fname).unwrap();
+
+        // Dropping the lock releases it too
+        drop(lock);
+        let lock = env.lock_file(&fname).unwrap();
+        env.unlock_file(lock).unwrap();
+
+        env.remove_file(&fname).unwrap();
+    }
+
+    #[test]
+    fn test_posix_manifest_detection() {
+        assert_eq!("/tmp/db", dirname("/tmp/db/MANIFEST-000002"));
+        assert_eq!(".", dirname("MANIFEST-000002"));
+        assert_eq!("", dirname("/CURRENT"));
+        assert_eq!("MANIFEST-000002", basename("/tmp/db/MANIFEST-000002"));
+        assert_eq!("000005.ldb", basename("000005.ldb"));
+        assert!(is_manifest("/tmp/db/MANIFEST-000002"));
+        assert!(is_manifest("MANIFEST-1"));
+        assert!(!is_manifest("/tmp/db/000005.ldb"));
+        assert!(!is_manifest("/tmp/MANIFEST-dir/000005.ldb"));
+        assert!(!is_manifest("/tmp/db/CURRENT"));
+
+        let env = PosixEnv::new();
+        let dbname = test_file("manifest");
+        env.create_dir(&dbname).unwrap();
+        let fname = format!("{}/MANIFEST-000001", dbname);
+        let mut file = env.new_writable_file(&fname, WriteHint::Other).unwrap();
+        file.append(b"edit").unwrap();
+        // Syncs the containing directory as well as the file
+        file.sync().unwrap();
+        drop(file);
+        assert_eq!(b"edit".to_vec(), fs::read(&fname).unwrap());
+        env.remove_file(&fname).unwrap();
+        env.remove_dir(&dbname).unwrap();
+    }
+
+    #[test]
+    fn test_posix_writable_file_append_slices() {
+        let env = PosixEnv::new();
+        let fname = test_file("append-slices");
+        let large = vec![b'x'; 3 * WRITABLE_FILE_BUFFER_SIZE];
+        let mut expected = vec![];
+
+        let mut file = env.new_writable_file(&fname, WriteHint::Other).unwrap();
+        // Buffered small appends
+        file.append(b"hello").unwrap();
+        file.append_slices(&[b" ", b"world"]).unwrap();
+        expected.extend_from_slice(b"hello world");
+        // A vectored write larger than the buffer goes straight to the file
+        file.append_slices(&[b"[", &large, b"]"]).unwrap();
+        expected.push(b'[');
+        expected.extend_from_slice(&large);
+        expected.push(b']');
+        file.append_slices(&[]).unwrap();
+        file.sync().unwrap();
+        file.close().unwrap();
+        assert!(file.append(b"closed").is_err());
+        drop(file);
+        assert_eq!(expected, fs::read(&fname).unwrap());
+
+        // Appendable files keep the existing contents
+        let mut file = env.new_appendable_file(&fname).unwrap();
+        file.append_slices(&[b"more", b"data"]).unwrap();
+        drop(file);
+        expected.extend_from_slice(b"moredata");
+        assert_eq!(expected, fs::read(&fname).unwrap());
+
+        // Writable files truncate
+        let mut file = env.new_writable_file(&fname, WriteHint::Other).unwrap();
+        file.append(b"new").unwrap();
+        drop(file);
+        assert_eq!(b"new".to_vec(), fs::read(&fname).unwrap());
+
+        env.remove_file(&fname).unwrap();
+    }
+
+    #[test]
+    fn test_posix_sequential_and_random_access_files() {
+        let env = PosixEnv::new();
+        let fname = test_file("read");
+        let contents: Vec<u8> = (0..100000u32).map(|i| (i % 251) as u8).collect();
+        let mut file = env.new_writable_file(&fname, WriteHint::Other).unwrap();
+        file.append(&contents).unwrap();
+        file.close().unwrap();
+        drop(file);
+        assert_eq!(contents.len() as u64, env.get_file_size(&fname).unwrap());
+
+        let mut file = env.new_sequential_file(&fname).unwrap();
+        let mut buf = vec![0; 1000];
+        assert_eq!(1000, file.read(&mut buf).unwrap());
+        assert_eq!(&contents[..1000], buf.as_slice());
+        file.skip(50000).unwrap();
+        assert_eq!(1000, file.read(&mut buf).unwrap());
+        assert_eq!(&contents[51000..52000], buf.as_slice());
+        // Reads stop short at the end of the file, then return nothing
+        file.skip(47500).unwrap();
+        assert_eq!(500, file.read(&mut buf).unwrap());
+        assert_eq!(&contents[99500..], &buf[..500]);
+        assert_eq!(0, file.read(&mut buf).unwrap());
+
+        let file = env.new_random_access_file(&fname).unwrap();
+        let mut buf = vec![0; 10];
+        for offset in [0, 99990, 12345, 1] {
+            file.read(offset, &mut buf).unwrap();
+            assert_eq!(&contents[offset..offset + 10], buf.as_slice());
+        }
+        // Reads past the end fail instead of returning fewer bytes
+        assert!(file.read(99995, &mut buf).unwrap_err().is_io_error());
+
+        // Reads from several threads share one file
+        let file: Arc<dyn RandomAccessFile> = Arc::from(file);
+        let threads: Vec<_> = (0..4)
+            .map(|i| {
+                let file = file.clone();
+                thread::spawn(move || {
+                    let mut buf = vec![0; 100];
+                    file.read(i * 20000, &mut buf).unwrap();
+                    buf
+                })
+            })
+            .collect();
+        for (i, thread) in threads.into_iter().enumerate() {
+            assert_eq!(
+                &contents[i * 20000..i * 20000 + 100],
+                thread.join().unwrap()
+            );
+        }
+
+        env.remove_file(&fname).unwrap();
+        assert!(env
+            .new_sequential_file(&fname)
+            .err()
+            .unwrap()
+            .is_not_found());
+        assert!(env
+            .new_random_access_file(&fname)
+            .err()
+            .unwrap()
+            .is_not_found());
+    }
+
+    #[test]
+    fn test_posix_random_access_file_limits() {
+        let env = PosixEnv::with_options(EnvOptions {
+            mmap_limit: 1,
+            open_read_only_file_limit: 1,
+            ..Default::default()
+        });
+        let available = |limiter: &Limiter| limiter.acquires_allowed.load(Ordering::Relaxed);
+        let fname = test_file("limits");
+        let contents: Vec<u8> = (0..10000u32).map(|i| (i % 253) as u8).collect();
+        fs::write(&fname, &contents).unwrap();
+
+        // The first file is mapped, the second keeps its descriptor open
+        // and the third opens the file on every read
+        let files: Vec<_> = (0..3)
+            .map(|_| env.new_random_access_file(&fname).unwrap())
+            .collect();
+        assert_eq!(0, available(&env.mmap_limiter));
+        assert_eq!(0, available(&env.fd_limiter));
+        for file in &files {
+            let mut buf = vec![0; 100];
+            file.read(9900, &mut buf).unwrap();
+            assert_eq!(&contents[9900..], buf.as_slice());
+            assert!(file.read(9901, &mut buf).unwrap_err().is_io_error());
+            assert!(file.read(usize::MAX, &mut buf).unwrap_err().is_io_error());
+        }
+        drop(files);
+        assert_eq!(env.mmap_limiter.max_acquires, available(&env.mmap_limiter));
+        assert_eq!(env.fd_limiter.max_acquires, available(&env.fd_limiter));
+
+        // Empty files are never mapped
+        fs::write(&fname, b"").unwrap();
+        let file = env.new_random_access_file(&fname).unwrap();
+        assert_eq!(1, available(&env.mmap_limiter));
+        file.read(0, &mut []).unwrap();
+        drop(file);
+
+        env.remove_file(&fname).unwrap();
+    }
+
+    #[test]
+    fn test_posix_schedule_and_start_thread() {
+        // The jobs of the default pool run side by side: the first one
+        // waits for the second
+        let env = PosixEnv::new();
+        let (sender, receiver) = std::sync::mpsc::channel::<()>();
+        let (done, wait_done) = std::sync::mpsc::channel();
+        env.schedule(Box::new(move || {
+            done.send(receiver.recv().is_ok()).unwrap();
+        }));
+        env.schedule(Box::new(move || sender.send(()).unwrap()));
+        assert!(wait_done.recv().unwrap());
+
+        let env = PosixEnv::with_options(EnvOptions {
+            background_threads: 1,
+            ..Default::default()
+        });
+        let (sender, receiver) = std::sync::mpsc::channel();
+
+        // With one thread, scheduled jobs run in order, off the calling
+        // thread, and a panicking one does not stop the rest
+        let caller = thread::current().id();
+        for i in 0..100 {
+            let sender = sender.clone();
+            env.schedule(Box::new(move || {
+                if i == 50 {
+                    panic!("job failed");
+                }
+                sender.send((i, thread::current().id())).unwrap();
+            }));
+        }
+        let done: Vec<_> = (0..99).map(|_| receiver.recv().unwrap()).collect();
+        let expected: Vec<_> = (0..100).filter(|i| *i != 50).collect();
+        assert_eq!(expected, done.iter().map(|(i, _)| *i).collect::<Vec<_>>());
+        assert!(done.iter().all(|(_, id)| *id != caller && *id == done[0].1));
+
+        env.start_thread(Box::new(move || {
+            sender.send((100, thread::current().id())).unwrap();
+        }));
+        let (i, id) = receiver.recv().unwrap();
+        assert_eq!(100, i);
+        assert_ne!(caller, id);
+    }
+}
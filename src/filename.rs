@@ -1,10 +1,12 @@
 use std::sync::Arc;
 
 use crate::{
+    db::TableFileExtension,
     env::{write_data_to_file_sync, Env},
     util::Result,
 };
 
+#[allow(clippy::enum_variant_names)]
 #[derive(PartialEq, Debug)]
 pub enum FileType {
     LogFile,
@@ -31,6 +33,14 @@ pub fn sst_table_file_name(dbname: &str, number: u64) -> String {
     format!("{}/{:06}.{}", dbname, number, "sst")
 }
 
+/// Name of table file "number" with "extension".
+pub fn table_file_name_for(dbname: &str, number: u64, extension: TableFileExtension) -> String {
+    match extension {
+        TableFileExtension::Ldb => table_file_name(dbname, number),
+        TableFileExtension::Sst => sst_table_file_name(dbname, number),
+    }
+}
+
 pub fn descriptor_file_name(dbname: &str, number: u64) -> String {
     assert!(number > 0);
     format!("{}/MANIFEST-{}", dbname, number)
@@ -48,10 +58,12 @@ pub fn temp_file_name(dbname: &str, number: u64) -> String {
     format!("{}/{:06}.{}", dbname, number, "dbtmp")
 }
 
+#[cfg(test)]
 pub fn info_log_file_name(dbname: &str) -> String {
     format!("{}/LOG", dbname)
 }
 
+#[cfg(test)]
 pub fn old_info_log_file_name(dbname: &str) -> String {
     format!("{}/LOG.old", dbname)
 }
@@ -62,55 +74,75 @@ pub fn old_info_log_file_name(dbname: &str) -> String {
 ///    dbname/LOG
 ///    dbname/LOG.old
 ///    dbname/MANIFEST-[0-9]+
-///    dbname/[0-9]+.(log|sst|ldb)
+///    dbname/[0-9]+.(log|sst|ldb|dbtmp)
+///
+/// "filename" may be given with or without its directory.
 pub fn parse_file_name(filename: &str) -> Option<(u64, FileType)> {
-    if filename == "CURRENT" {
-        Some((0, FileType::CurrentFile))
-    } else if filename == "LOCK" {
-        Some((0, FileType::DBLockFile))
-    } else if filename == "LOG" || filename == "LOG.old" {
-        Some((0, FileType::InfoLogFile))
-    } else if filename.starts_with("MANIFEST-") {
-        if let Ok(num) = filename[9..].parse::<u64>() {
-            Some((num, FileType::DescriptorFile))
-        } else {
-            None
-        }
-    } else {
-        let index = filename
-            .chars()
-            .position(|ch| !ch.is_numeric())
-            .unwrap_or(filename.len());
-        if let Ok(num) = filename[..index].parse::<u64>() {
-            let file_type = match &filename[index..] {
-                ".log" => FileType::LogFile,
-                ".sst" | ".ldb" => FileType::TableFile,
-                ".dbtmp" => FileType::TempFile,
-                _ => return None,
-            };
-            Some((num, file_type))
-        } else {
-            None
+    // '/' never occurs inside a multi-byte character, so splitting the
+    // bytes there cannot cut one in half
+    let name = filename.as_bytes();
+    let name = match name.iter().rposition(|&b| b == b'/') {
+        Some(slash) => &name[slash + 1..],
+        None => name,
+    };
+    match name {
+        b"CURRENT" => Some((0, FileType::CurrentFile)),
+        b"LOCK" => Some((0, FileType::DBLockFile)),
+        b"LOG" | b"LOG.old" => Some((0, FileType::InfoLogFile)),
+        _ => {
+            if let Some(rest) = name.strip_prefix(b"MANIFEST-") {
+                match consume_decimal_number(rest)? {
+                    (number, b"") => Some((number, FileType::DescriptorFile)),
+                    _ => None,
+                }
+            } else {
+                let (number, suffix) = consume_decimal_number(name)?;
+                let file_type = match suffix {
+                    b".log" => FileType::LogFile,
+                    b".sst" | b".ldb" => FileType::TableFile,
+                    b".dbtmp" => FileType::TempFile,
+                    _ => return None,
+                };
+                Some((number, file_type))
+            }
         }
     }
 }
 
+/// Parse the ASCII digits at the start of "input" as a number and return
+/// it with the rest of the input.  Returns None if there are no digits
+/// or the number does not fit in a u64.
+fn consume_decimal_number(input: &[u8]) -> Option<(u64, &[u8])> {
+    let digits = input.iter().take_while(|b| b.is_ascii_digit()).count();
+    if digits == 0 {
+        return None;
+    }
+    let mut number: u64 = 0;
+    for &digit in &input[..digits] {
+        number = number.checked_mul(10)?.checked_add((digit - b'0') as u64)?;
+    }
+    Some((number, &input[digits..]))
+}
+
 pub fn set_current_file(env: Arc<dyn Env>, dbname: &str, descriptor_number: u64) -> Result<()> {
     let manifest = descriptor_file_name(dbname, descriptor_number);
     let content = &manifest[dbname.len() + 1..];
     let tmp = temp_file_name(dbname, descriptor_number);
-    if let Ok(_) =
-        write_data_to_file_sync(env.clone(), (content.to_string() + "\n").as_bytes(), &tmp)
-    {
-        env.rename_file(&tmp, &current_file_name(dbname))
-    } else {
-        env.remove_file(&tmp)
+    let mut result =
+        write_data_to_file_sync(env.clone(), (content.to_string() + "\n").as_bytes(), &tmp);
+    if result.is_ok() {
+        result = env.rename_file(&tmp, &current_file_name(dbname));
+    }
+    if result.is_err() {
+        let _ = env.remove_file(&tmp);
     }
+    result
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::util::Random;
 
     #[test]
     fn test_file_name_parse() {
@@ -130,6 +162,14 @@ mod tests {
                 18446744073709551615u64,
                 FileType::LogFile,
             ),
+            ("000123.dbtmp", 123, FileType::TempFile),
+            ("dir/000123.ldb", 123, FileType::TableFile),
+            (
+                "/tmp/dé/テスト/MANIFEST-000005",
+                5,
+                FileType::DescriptorFile,
+            ),
+            ("ü/CURRENT", 0, FileType::CurrentFile),
         ];
 
         for (fname, number, type_) in cases {
@@ -159,69 +199,114 @@ mod tests {
             "100",
             "100.",
             "100.lop",
+            "+100.log",
+            "MANIFEST-+3",
+            "MANIFEST-3/",
+            "١٢٣.log",
+            "12٣.log",
+            "100.lög",
+            "é",
+            "CURRENT/",
+            "dir/",
         ];
 
         for fname in errors {
-            assert!(parse_file_name(fname).is_none());
+            assert!(parse_file_name(fname).is_none(), "{}", fname);
         }
     }
 
     #[test]
     fn test_file_name_construction() {
-        let fname = current_file_name(&"foo");
+        let fname = current_file_name("foo");
         assert_eq!("foo/", &fname[..4]);
         assert_eq!(
             (0, FileType::CurrentFile),
             parse_file_name(&fname[4..]).unwrap()
         );
 
-        let fname = lock_file_name(&"foo");
+        let fname = lock_file_name("foo");
         assert_eq!("foo/", &fname[..4]);
         assert_eq!(
             (0, FileType::DBLockFile),
             parse_file_name(&fname[4..]).unwrap()
         );
 
-        let fname = log_file_name(&"foo", 192);
+        let fname = log_file_name("foo", 192);
         assert_eq!("foo/", &fname[..4]);
         assert_eq!(
             (192, FileType::LogFile),
             parse_file_name(&fname[4..]).unwrap()
         );
 
-        let fname = table_file_name(&"bar", 200);
+        let fname = table_file_name("bar", 200);
         assert_eq!("bar/", &fname[..4]);
         assert_eq!(
             (200, FileType::TableFile),
             parse_file_name(&fname[4..]).unwrap()
         );
 
-        let fname = descriptor_file_name(&"bar", 100);
+        let fname = descriptor_file_name("bar", 100);
         assert_eq!("bar/", &fname[..4]);
         assert_eq!(
             (100, FileType::DescriptorFile),
             parse_file_name(&fname[4..]).unwrap()
         );
 
-        let fname = temp_file_name(&"tmp", 999);
+        let fname = temp_file_name("tmp", 999);
         assert_eq!("tmp/", &fname[..4]);
         assert_eq!(
             (999, FileType::TempFile),
             parse_file_name(&fname[4..]).unwrap()
         );
 
-        let fname = info_log_file_name(&"foo");
+        let fname = info_log_file_name("foo");
         assert_eq!("foo/", &fname[..4]);
         assert_eq!(
             (0, FileType::InfoLogFile),
             parse_file_name(&fname[4..]).unwrap()
         );
 
-        let fname = old_info_log_file_name(&"foo");
+        let fname = old_info_log_file_name("foo");
         assert_eq!("foo/", &fname[..4]);
         assert_eq!(
             (0, FileType::InfoLogFile),
             parse_file_name(&fname[4..]).unwrap()
         );
     }
+
+    #[test]
+    fn test_file_name_round_trip() {
+        let mut rnd = Random::new(301);
+        let mut numbers = vec![1, 9, 10, 999999, 1000000, u64::MAX];
+        for _ in 0..1000 {
+            let number = ((rnd.next() as u64) << 32 | rnd.next() as u64) >> rnd.uniform(64);
+            numbers.push(number.max(1));
+        }
+        for dbname in ["db", "/tmp/some dir/db", "dé", "テスト/データ", "a.b/1.log"] {
+            for &number in &numbers {
+                let cases = [
+                    (log_file_name(dbname, number), FileType::LogFile),
+                    (table_file_name(dbname, number), FileType::TableFile),
+                    (sst_table_file_name(dbname, number), FileType::TableFile),
+                    (
+                        descriptor_file_name(dbname, number),
+                        FileType::DescriptorFile,
+                    ),
+                    (temp_file_name(dbname, number), FileType::TempFile),
+                ];
+                for (fname, type_) in cases {
+                    assert_eq!(Some((number, type_)), parse_file_name(&fname), "{}", fname);
+                }
+            }
+            let cases = [
+                (current_file_name(dbname), FileType::CurrentFile),
+                (lock_file_name(dbname), FileType::DBLockFile),
+                (info_log_file_name(dbname), FileType::InfoLogFile),
+                (old_info_log_file_name(dbname), FileType::InfoLogFile),
+            ];
+            for (fname, type_) in cases {
+                assert_eq!(Some((0, type_)), parse_file_name(&fname), "{}", fname);
+            }
+        }
+    }
 }
@@ -0,0 +1,142 @@
+use super::Iterator;
+use crate::util::Result;
+
+/// Adapts an Iterator to std::iter::Iterator, so that scans can be
+/// written with for loops and iterator combinators.  Each entry is
+/// yielded as a copy of its key and value.
+///
+/// The adapter starts at the entry the iterator is positioned at and
+/// moves in one direction.  Once the iterator runs out, the adapter
+/// yields the error it stopped at, if any, and then ends.  The iterator
+/// stays at the last entry yielded, to be taken back with into_inner().
+pub struct IterAdapter<T> {
+    iter: T,
+    reverse: bool,
+    /// Whether an entry was yielded, which "iter" is still at
+    started: bool,
+    done: bool,
+}
+
+impl<T: Iterator> IterAdapter<T> {
+    /// Yield the entries of "iter" from the one it is positioned at,
+    /// moving forward.
+    pub fn new(iter: T) -> Self {
+        Self {
+            iter,
+            reverse: false,
+            started: false,
+            done: false,
+        }
+    }
+
+    /// Yield the entries of "iter" from the one it is positioned at,
+    /// moving backward.
+    pub fn new_reverse(iter: T) -> Self {
+        Self {
+            reverse: true,
+            ..Self::new(iter)
+        }
+    }
+
+    /// Yield all entries of "iter", in order.
+    pub fn from_first(mut iter: T) -> Self {
+        iter.seek_to_first();
+        Self::new(iter)
+    }
+
+    /// Yield all entries of "iter", in reverse order.
+    pub fn from_last(mut iter: T) -> Self {
+        iter.seek_to_last();
+        Self::new_reverse(iter)
+    }
+
+    /// Yield the entries of "iter" from the first one at or after
+    /// "target", moving forward.
+    pub fn from_seek(mut iter: T, target: &[u8]) -> Self {
+        iter.seek(target);
+        Self::new(iter)
+    }
+
+    pub fn into_inner(self) -> T {
+        self.iter
+    }
+}
+
+impl<T: Iterator> std::iter::Iterator for IterAdapter<T> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        // Move past the entry yielded last only now, so that the iterator
+        // is not moved further than what was taken from the adapter
+        if self.started && self.iter.valid() {
+            if self.reverse {
+                self.iter.prev();
+            } else {
+                self.iter.next();
+            }
+        }
+        self.started = true;
+        if !self.iter.valid() {
+            self.done = true;
+            return self.iter.status().err().map(Err);
+        }
+        Some(Ok((self.iter.key().to_vec(), self.iter.value().to_vec())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        dbformat::{InternalKeyComparator, ValueType},
+        iterator::new_error_iterator,
+        memtable::MemTable,
+        util::{BytewiseComparator, Error},
+    };
+
+    #[test]
+    fn test_iter_adapter() {
+        let icmp = InternalKeyComparator::new(Arc::new(BytewiseComparator::new()));
+        let mem = MemTable::new(icmp);
+        for (seq, key) in ["a", "b", "c", "d"].iter().enumerate() {
+            mem.add(seq as u64 + 1, ValueType::Value, key.as_bytes(), b"v");
+        }
+        let user_keys = |adapter: IterAdapter<_>| -> Vec<u8> {
+            adapter.map(|entry| entry.unwrap().0[0]).collect::<Vec<_>>()
+        };
+        assert_eq!(
+            b"abcd",
+            &user_keys(IterAdapter::from_first(mem.new_iterator()))[..]
+        );
+        assert_eq!(
+            b"dcba",
+            &user_keys(IterAdapter::from_last(mem.new_iterator()))[..]
+        );
+        let mut iter = mem.new_iterator();
+        iter.seek_to_first();
+        iter.next();
+        assert_eq!(b"bcd", &user_keys(IterAdapter::new(iter))[..]);
+
+        // The iterator stays at the last entry taken
+        let mut adapter = IterAdapter::from_first(mem.new_iterator());
+        assert_eq!(2, adapter.by_ref().take(2).count());
+        let iter = adapter.into_inner();
+        assert_eq!(b'b', iter.key()[0]);
+        let mut adapter = IterAdapter::new_reverse(iter);
+        assert_eq!(b'b', adapter.next().unwrap().unwrap().0[0]);
+        assert_eq!(b'a', adapter.next().unwrap().unwrap().0[0]);
+        assert!(adapter.next().is_none());
+        assert!(adapter.next().is_none());
+
+        // The error the iterator stopped at ends the iteration
+        let mut adapter =
+            IterAdapter::from_first(new_error_iterator(Error::corruption("bad block")));
+        assert!(adapter.next().unwrap().unwrap_err().is_corruption());
+        assert!(adapter.next().is_none());
+    }
+}
@@ -0,0 +1,305 @@
+use std::{cmp::Ordering, sync::Arc};
+
+use super::{new_empty_iterator, Iterator};
+use crate::util::{Comparator, Result};
+
+/// Return an iterator that provides the union of the data in
+/// children[0,n-1].  The result does no duplicate suppression.  I.e.,
+/// if a particular key is present in K child iterators, it will be
+/// yielded K times.
+pub fn new_merging_iterator<'a>(
+    comparator: Arc<dyn Comparator>,
+    mut children: Vec<Box<dyn Iterator + 'a>>,
+) -> Box<dyn Iterator + 'a> {
+    match children.len() {
+        0 => new_empty_iterator(),
+        1 => children.pop().unwrap(),
+        _ => Box::new(MergingIterator::new(comparator, children)),
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Forward,
+    Reverse,
+}
+
+/// An iterator over the union of its children, ordered by "comparator".
+/// Moving forward, entries with equal keys are yielded in the order of
+/// their children, which lets callers put newer sources (the memtable)
+/// first.
+pub struct MergingIterator<'a> {
+    comparator: Arc<dyn Comparator>,
+    children: Vec<Box<dyn Iterator + 'a>>,
+    /// Index of the child the merged iterator is positioned at, if any.
+    current: Option<usize>,
+    direction: Direction,
+}
+
+impl<'a> MergingIterator<'a> {
+    pub fn new(comparator: Arc<dyn Comparator>, children: Vec<Box<dyn Iterator + 'a>>) -> Self {
+        Self {
+            comparator,
+            children,
+            current: None,
+            direction: Direction::Forward,
+        }
+    }
+
+    fn find_smallest(&mut self) {
+        let mut smallest: Option<usize> = None;
+        for (i, child) in self.children.iter().enumerate() {
+            if !child.valid() {
+                if child.status().is_err() {
+                    // Stop rather than go on with the other children
+                    // past the entries this one could not read
+                    self.current = None;
+                    return;
+                }
+                continue;
+            }
+            match smallest {
+                Some(index)
+                    if self
+                        .comparator
+                        .compare(child.key(), self.children[index].key())
+                        != Ordering::Less => {}
+                _ => smallest = Some(i),
+            }
+        }
+        self.current = smallest;
+    }
+
+    fn find_largest(&mut self) {
+        let mut largest: Option<usize> = None;
+        for (i, child) in self.children.iter().enumerate().rev() {
+            if !child.valid() {
+                if child.status().is_err() {
+                    // Stop rather than go on with the other children
+                    // past the entries this one could not read
+                    self.current = None;
+                    return;
+                }
+                continue;
+            }
+            match largest {
+                Some(index)
+                    if self
+                        .comparator
+                        .compare(child.key(), self.children[index].key())
+                        != Ordering::Greater => {}
+                _ => largest = Some(i),
+            }
+        }
+        self.current = largest;
+    }
+}
+
+impl Iterator for MergingIterator<'_> {
+    fn valid(&self) -> bool {
+        self.current.is_some()
+    }
+
+    fn seek_to_first(&mut self) {
+        for child in self.children.iter_mut() {
+            child.seek_to_first();
+        }
+        self.find_smallest();
+        self.direction = Direction::Forward;
+    }
+
+    fn seek_to_last(&mut self) {
+        for child in self.children.iter_mut() {
+            child.seek_to_last();
+        }
+        self.find_largest();
+        self.direction = Direction::Reverse;
+    }
+
+    fn seek(&mut self, target: &[u8]) {
+        for child in self.children.iter_mut() {
+            child.seek(target);
+        }
+        self.find_smallest();
+        self.direction = Direction::Forward;
+    }
+
+    fn seek_for_prev(&mut self, target: &[u8]) {
+        for child in self.children.iter_mut() {
+            child.seek_for_prev(target);
+        }
+        self.find_largest();
+        self.direction = Direction::Reverse;
+    }
+
+    fn next(&mut self) {
+        let current = self.current.expect("next() on an invalid iterator");
+
+        // Ensure that all children are positioned after key().
+        // If we are moving in the forward direction, it is already
+        // true for all of the non-current children since current is
+        // the smallest child and key() == children[current].key().
+        // Otherwise, we explicitly position the non-current children.
+        if self.direction != Direction::Forward {
+            let key = self.children[current].key().to_vec();
+            for (i, child) in self.children.iter_mut().enumerate() {
+                if i == current {
+                    continue;
+                }
+                child.seek(&key);
+                if child.valid() && self.comparator.compare(&key, child.key()) == Ordering::Equal {
+                    child.next();
+                }
+            }
+            self.direction = Direction::Forward;
+        }
+
+        self.children[current].next();
+        self.find_smallest();
+    }
+
+    fn prev(&mut self) {
+        let current = self.current.expect("prev() on an invalid iterator");
+
+        // Ensure that all children are positioned before key().
+        // If we are moving in the reverse direction, it is already
+        // true for all of the non-current children since current is
+        // the largest child and key() == children[current].key().
+        // Otherwise, we explicitly position the non-current children.
+        if self.direction != Direction::Reverse {
+            let key = self.children[current].key().to_vec();
+            for (i, child) in self.children.iter_mut().enumerate() {
+                if i == current {
+                    continue;
+                }
+                child.seek_for_prev(&key);
+                if child.valid() && self.comparator.compare(&key, child.key()) == Ordering::Equal {
+                    // Step back one to be < key()
+                    child.prev();
+                }
+            }
+            self.direction = Direction::Reverse;
+        }
+
+        self.children[current].prev();
+        self.find_largest();
+    }
+
+    fn key(&self) -> &[u8] {
+        self.children[self.current.unwrap()].key()
+    }
+
+    fn value(&self) -> &[u8] {
+        self.children[self.current.unwrap()].value()
+    }
+
+    fn status(&self) -> Result<()> {
+        for child in &self.children {
+            child.status()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        dbformat::{InternalKey, InternalKeyComparator, ValueType},
+        memtable::MemTable,
+        util::{BytewiseComparator, Error, Random},
+    };
+
+    #[test]
+    fn test_merging_iterator() {
+        let icmp = InternalKeyComparator::new(Arc::new(BytewiseComparator::new()));
+        let memtables: Vec<_> = (0..3).map(|_| MemTable::new(icmp.clone())).collect();
+        let mut rnd = Random::new(301);
+        let mut expected = vec![];
+        for seq in 1..=300 {
+            let key = format!("key{:03}", rnd.uniform(100));
+            let value = format!("v{}", seq);
+            let memtable = &memtables[rnd.uniform(3) as usize];
+            memtable.add(seq, ValueType::Value, key.as_bytes(), value.as_bytes());
+            let ikey = InternalKey::new(key.as_bytes(), seq, ValueType::Value);
+            expected.push((ikey.encode().to_vec(), value.into_bytes()));
+        }
+        expected.sort_by(|a, b| icmp.compare(&a.0, &b.0));
+
+        let children = memtables.iter().map(|m| m.new_iterator()).collect();
+        let mut iter = MergingIterator::new(Arc::new(icmp.clone()), children);
+        assert!(!iter.valid());
+
+        iter.seek_to_first();
+        for (key, value) in &expected {
+            assert_eq!(key.as_slice(), iter.key());
+            assert_eq!(value.as_slice(), iter.value());
+            iter.next();
+        }
+        assert!(!iter.valid());
+
+        iter.seek_to_last();
+        for (key, _) in expected.iter().rev() {
+            assert_eq!(key.as_slice(), iter.key());
+            iter.prev();
+        }
+        assert!(!iter.valid());
+
+        // Random walks switching direction
+        for _ in 0..100 {
+            let mut index = rnd.uniform(expected.len() as u32) as usize;
+            iter.seek(&expected[index].0);
+            for _ in 0..20 {
+                if rnd.one_in(2) {
+                    iter.next();
+                    index += 1;
+                } else if index == 0 {
+                    iter.prev();
+                    assert!(!iter.valid());
+                    break;
+                } else {
+                    iter.prev();
+                    index -= 1;
+                }
+                if index == expected.len() {
+                    assert!(!iter.valid());
+                    break;
+                }
+                assert_eq!(expected[index].0.as_slice(), iter.key());
+            }
+        }
+        assert!(iter.status().is_ok());
+
+        // Seek past the largest user key
+        iter.seek(InternalKey::new(b"key0995", 0, ValueType::Value).encode());
+        assert!(!iter.valid());
+        iter.seek_for_prev(InternalKey::new(b"key0995", 0, ValueType::Value).encode());
+        assert_eq!(expected.last().unwrap().0.as_slice(), iter.key());
+        iter.seek_for_prev(InternalKey::new(b"key", 0, ValueType::Value).encode());
+        assert!(!iter.valid());
+
+        // Backward seeks to every entry, then moving forward
+        for (index, (key, _)) in expected.iter().enumerate() {
+            iter.seek_for_prev(key);
+            assert_eq!(key.as_slice(), iter.key());
+            iter.next();
+            match expected.get(index + 1) {
+                Some((next, _)) => assert_eq!(next.as_slice(), iter.key()),
+                None => assert!(!iter.valid()),
+            }
+        }
+
+        // A child that failed stops the merge, rather than leaving the
+        // entries it could not read out of it
+        let mut children: Vec<_> = memtables.iter().map(|m| m.new_iterator()).collect();
+        children.push(crate::iterator::new_error_iterator(Error::corruption(
+            "bad block",
+        )));
+        let mut iter = MergingIterator::new(Arc::new(icmp), children);
+        iter.seek_to_first();
+        assert!(!iter.valid());
+        iter.seek_to_last();
+        assert!(!iter.valid());
+        assert!(iter.status().unwrap_err().is_corruption());
+    }
+}
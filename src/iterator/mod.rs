@@ -0,0 +1,183 @@
+use crate::util::{Error, Result};
+
+mod adapter;
+mod merger;
+mod two_level_iterator;
+
+pub use adapter::IterAdapter;
+pub use merger::{new_merging_iterator, MergingIterator};
+pub use two_level_iterator::TwoLevelIterator;
+
+/// An iterator over a sequence of key/value pairs from a source.
+///
+/// An iterator that fails to read its source while it is positioned,
+/// e.g. because a block does not match its checksum, stops right there:
+/// it becomes invalid, and status() returns the error from then on.  It
+/// never skips over what it could not read, so an iteration that ends
+/// with an ok status has seen every entry in its range.
+pub trait Iterator {
+    fn valid(&self) -> bool;
+    fn seek_to_first(&mut self);
+    fn seek_to_last(&mut self);
+    fn seek(&mut self, target: &[u8]);
+    /// Position at the last key in the source that is at or before
+    /// "target".  The iterator is valid after this call iff the source
+    /// contains such an entry.
+    fn seek_for_prev(&mut self, target: &[u8]);
+    fn next(&mut self);
+    fn prev(&mut self);
+    fn key(&self) -> &[u8];
+    fn value(&self) -> &[u8];
+    fn status(&self) -> Result<()>;
+
+    /// Make the iterator read the current state of its source, e.g. the
+    /// updates written to a DB since the iterator was created, and stay
+    /// at the current key if it is still there, or else move on to the
+    /// next one in the direction it was moving.  Iterators over sources
+    /// that don't change fail with an error that satisfies
+    /// Error::is_not_supported().
+    fn refresh(&mut self) -> Result<()> {
+        Err(Error::not_supported("iterator cannot be refreshed"))
+    }
+}
+
+impl<T: Iterator + ?Sized> Iterator for Box<T> {
+    fn valid(&self) -> bool {
+        (**self).valid()
+    }
+
+    fn seek_to_first(&mut self) {
+        (**self).seek_to_first();
+    }
+
+    fn seek_to_last(&mut self) {
+        (**self).seek_to_last();
+    }
+
+    fn seek(&mut self, target: &[u8]) {
+        (**self).seek(target);
+    }
+
+    fn seek_for_prev(&mut self, target: &[u8]) {
+        (**self).seek_for_prev(target);
+    }
+
+    fn next(&mut self) {
+        (**self).next();
+    }
+
+    fn prev(&mut self) {
+        (**self).prev();
+    }
+
+    fn key(&self) -> &[u8] {
+        (**self).key()
+    }
+
+    fn value(&self) -> &[u8] {
+        (**self).value()
+    }
+
+    fn status(&self) -> Result<()> {
+        (**self).status()
+    }
+
+    fn refresh(&mut self) -> Result<()> {
+        (**self).refresh()
+    }
+}
+
+impl<T: Iterator + ?Sized> Iterator for &mut T {
+    fn valid(&self) -> bool {
+        (**self).valid()
+    }
+
+    fn seek_to_first(&mut self) {
+        (**self).seek_to_first();
+    }
+
+    fn seek_to_last(&mut self) {
+        (**self).seek_to_last();
+    }
+
+    fn seek(&mut self, target: &[u8]) {
+        (**self).seek(target);
+    }
+
+    fn seek_for_prev(&mut self, target: &[u8]) {
+        (**self).seek_for_prev(target);
+    }
+
+    fn next(&mut self) {
+        (**self).next();
+    }
+
+    fn prev(&mut self) {
+        (**self).prev();
+    }
+
+    fn key(&self) -> &[u8] {
+        (**self).key()
+    }
+
+    fn value(&self) -> &[u8] {
+        (**self).value()
+    }
+
+    fn status(&self) -> Result<()> {
+        (**self).status()
+    }
+
+    fn refresh(&mut self) -> Result<()> {
+        (**self).refresh()
+    }
+}
+
+/// An iterator over nothing, optionally carrying the error that made it empty.
+struct EmptyIterator {
+    status: Result<()>,
+}
+
+impl Iterator for EmptyIterator {
+    fn valid(&self) -> bool {
+        false
+    }
+
+    fn seek_to_first(&mut self) {}
+
+    fn seek_to_last(&mut self) {}
+
+    fn seek(&mut self, _target: &[u8]) {}
+
+    fn seek_for_prev(&mut self, _target: &[u8]) {}
+
+    fn next(&mut self) {
+        unreachable!()
+    }
+
+    fn prev(&mut self) {
+        unreachable!()
+    }
+
+    fn key(&self) -> &[u8] {
+        unreachable!()
+    }
+
+    fn value(&self) -> &[u8] {
+        unreachable!()
+    }
+
+    fn status(&self) -> Result<()> {
+        self.status.clone()
+    }
+}
+
+/// Return an empty iterator (yields nothing).
+pub fn new_empty_iterator() -> Box<dyn Iterator> {
+    Box::new(EmptyIterator { status: Ok(()) })
+}
+
+/// Return an empty iterator with the specified status.
+pub fn new_error_iterator(error: Error) -> Box<dyn Iterator> {
+    Box::new(EmptyIterator { status: Err(error) })
+}
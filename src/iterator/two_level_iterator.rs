@@ -0,0 +1,291 @@
+use super::Iterator;
+use crate::{db::ReadConfig, Result};
+
+/// A two-level iterator contains an index iterator whose values point
+/// to a sequence of blocks where each block is itself a sequence of
+/// key,value pairs.  The returned two-level iterator yields the
+/// concatenation of all key/value pairs in the sequence of blocks.
+///
+/// "block_function" converts an index value into an iterator over the
+/// contents of the corresponding block, e.g. an encoded BlockHandle into
+/// an iterator over a data block of a table, or the description of a
+/// table file into an iterator over the table.  A block is only opened
+/// once the iteration gets to it.
+pub struct TwoLevelIterator<I, F> {
+    block_function: F,
+    config: ReadConfig,
+    status: Result<()>,
+    index_iter: I,
+    /// May be None
+    data_iter: Option<Box<dyn Iterator>>,
+    /// If data_iter is Some, then "data_block_handle" holds the
+    /// "index_value" passed to block_function to create the data_iter.
+    data_block_handle: Vec<u8>,
+}
+
+impl<I, F> TwoLevelIterator<I, F>
+where
+    I: Iterator,
+    F: FnMut(&ReadConfig, &[u8]) -> Box<dyn Iterator>,
+{
+    pub fn new(index_iter: I, block_function: F, config: &ReadConfig) -> Self {
+        Self {
+            block_function,
+            config: config.clone(),
+            status: Ok(()),
+            index_iter,
+            data_iter: None,
+            data_block_handle: vec![],
+        }
+    }
+
+    fn save_error(&mut self, status: Result<()>) {
+        if self.status.is_ok() && status.is_err() {
+            self.status = status;
+        }
+    }
+
+    /// Stop at a data block that could not be read, or at a corrupted
+    /// index, rather than skipping past it to the next block.  Returns
+    /// whether there was such an error.
+    fn stop_at_error(&mut self) -> bool {
+        let status = match self.data_iter.as_ref() {
+            Some(data_iter) => data_iter.status(),
+            None => Ok(()),
+        }
+        .and_then(|()| self.index_iter.status());
+        if status.is_ok() {
+            return false;
+        }
+        self.set_data_iterator(None);
+        self.save_error(status);
+        true
+    }
+
+    fn skip_empty_data_blocks_forward(&mut self) {
+        while !self.data_iter.as_ref().is_some_and(|iter| iter.valid()) {
+            if self.stop_at_error() {
+                return;
+            }
+            // Move to next block
+            if !self.index_iter.valid() {
+                self.set_data_iterator(None);
+                return;
+            }
+            if let Err(error) = self.config.check_cancelled() {
+                self.set_data_iterator(None);
+                self.save_error(Err(error));
+                return;
+            }
+            self.index_iter.next();
+            self.init_data_block();
+            if let Some(data_iter) = self.data_iter.as_mut() {
+                data_iter.seek_to_first();
+            }
+        }
+    }
+
+    fn skip_empty_data_blocks_backward(&mut self) {
+        while !self.data_iter.as_ref().is_some_and(|iter| iter.valid()) {
+            if self.stop_at_error() {
+                return;
+            }
+            // Move to previous block
+            if !self.index_iter.valid() {
+                self.set_data_iterator(None);
+                return;
+            }
+            if let Err(error) = self.config.check_cancelled() {
+                self.set_data_iterator(None);
+                self.save_error(Err(error));
+                return;
+            }
+            self.index_iter.prev();
+            self.init_data_block();
+            if let Some(data_iter) = self.data_iter.as_mut() {
+                data_iter.seek_to_last();
+            }
+        }
+    }
+
+    fn set_data_iterator(&mut self, data_iter: Option<Box<dyn Iterator>>) {
+        if let Some(old) = self.data_iter.take() {
+            self.save_error(old.status());
+        }
+        self.data_iter = data_iter;
+    }
+
+    fn init_data_block(&mut self) {
+        if !self.index_iter.valid() {
+            self.set_data_iterator(None);
+        } else if let Err(error) = self.config.check_cancelled() {
+            self.set_data_iterator(None);
+            self.save_error(Err(error));
+        } else {
+            let handle = self.index_iter.value();
+            if self.data_iter.is_some() && handle == self.data_block_handle.as_slice() {
+                // data_iter is already constructed with this iterator, so
+                // no need to change anything
+            } else {
+                let iter = (self.block_function)(&self.config, handle);
+                self.data_block_handle = handle.to_vec();
+                self.set_data_iterator(Some(iter));
+            }
+        }
+    }
+}
+
+impl<I, F> Iterator for TwoLevelIterator<I, F>
+where
+    I: Iterator,
+    F: FnMut(&ReadConfig, &[u8]) -> Box<dyn Iterator>,
+{
+    fn valid(&self) -> bool {
+        self.data_iter.as_ref().is_some_and(|iter| iter.valid())
+    }
+
+    fn seek_to_first(&mut self) {
+        self.index_iter.seek_to_first();
+        self.init_data_block();
+        if let Some(data_iter) = self.data_iter.as_mut() {
+            data_iter.seek_to_first();
+        }
+        self.skip_empty_data_blocks_forward();
+    }
+
+    fn seek_to_last(&mut self) {
+        self.index_iter.seek_to_last();
+        self.init_data_block();
+        if let Some(data_iter) = self.data_iter.as_mut() {
+            data_iter.seek_to_last();
+        }
+        self.skip_empty_data_blocks_backward();
+    }
+
+    fn seek(&mut self, target: &[u8]) {
+        self.index_iter.seek(target);
+        self.init_data_block();
+        if let Some(data_iter) = self.data_iter.as_mut() {
+            data_iter.seek(target);
+        }
+        self.skip_empty_data_blocks_forward();
+    }
+
+    fn seek_for_prev(&mut self, target: &[u8]) {
+        // The block holding the first key >= target, if any, holds the
+        // last key <= target, or else the block before it does
+        self.index_iter.seek(target);
+        if !self.index_iter.valid() && self.index_iter.status().is_ok() {
+            self.seek_to_last();
+            return;
+        }
+        self.init_data_block();
+        if let Some(data_iter) = self.data_iter.as_mut() {
+            data_iter.seek_for_prev(target);
+        }
+        self.skip_empty_data_blocks_backward();
+    }
+
+    fn next(&mut self) {
+        assert!(self.valid());
+        self.data_iter.as_mut().unwrap().next();
+        self.skip_empty_data_blocks_forward();
+    }
+
+    fn prev(&mut self) {
+        assert!(self.valid());
+        self.data_iter.as_mut().unwrap().prev();
+        self.skip_empty_data_blocks_backward();
+    }
+
+    fn key(&self) -> &[u8] {
+        assert!(self.valid());
+        self.data_iter.as_ref().unwrap().key()
+    }
+
+    fn value(&self) -> &[u8] {
+        assert!(self.valid());
+        self.data_iter.as_ref().unwrap().value()
+    }
+
+    fn status(&self) -> Result<()> {
+        self.index_iter.status()?;
+        if let Some(data_iter) = self.data_iter.as_ref() {
+            data_iter.status()?;
+        }
+        self.status.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, rc::Rc, sync::Arc};
+
+    use super::*;
+    use crate::{
+        dbformat::{extract_user_key, InternalKeyComparator, LookupKey, ValueType},
+        iterator::IterAdapter,
+        memtable::MemTable,
+        util::BytewiseComparator,
+    };
+
+    #[test]
+    fn test_two_level_iterator() {
+        // Blocks of keys, the third one empty, each indexed by the
+        // largest key it may hold
+        let icmp = InternalKeyComparator::new(Arc::new(BytewiseComparator::new()));
+        let blocks: Vec<_> = [&["a", "b", "c"][..], &["d", "e"], &[], &["f"]]
+            .iter()
+            .map(|keys| {
+                let mem = MemTable::new(icmp.clone());
+                for key in keys.iter() {
+                    mem.add(10, ValueType::Value, key.as_bytes(), b"v");
+                }
+                mem
+            })
+            .collect();
+        let index = MemTable::new(icmp.clone());
+        for (block, (key, sequence)) in [("c", 2), ("e", 2), ("e", 1), ("f", 1)]
+            .into_iter()
+            .enumerate()
+        {
+            index.add(sequence, ValueType::Value, key.as_bytes(), &[block as u8]);
+        }
+
+        let opened = Rc::new(Cell::new(0));
+        let new_iterator = || {
+            let (blocks, opened) = (blocks.clone(), opened.clone());
+            TwoLevelIterator::new(
+                index.new_iterator(),
+                move |_: &ReadConfig, index_value: &[u8]| {
+                    opened.set(opened.get() + 1);
+                    blocks[index_value[0] as usize].new_owning_iterator(None, None, false)
+                },
+                &ReadConfig::default(),
+            )
+        };
+        let user_keys = |adapter: IterAdapter<_>| -> String {
+            adapter
+                .map(|entry| extract_user_key(&entry.unwrap().0)[0] as char)
+                .collect()
+        };
+        assert_eq!("abcdef", user_keys(IterAdapter::from_first(new_iterator())));
+        assert_eq!("fedcba", user_keys(IterAdapter::from_last(new_iterator())));
+
+        // Only the blocks the iteration gets to are opened
+        opened.set(0);
+        let mut iter = new_iterator();
+        iter.seek(LookupKey::new(b"d", 10).internal_key());
+        assert_eq!(b"d", extract_user_key(iter.key()));
+        assert_eq!(1, opened.get());
+        iter.next();
+        iter.next();
+        assert_eq!(b"f", extract_user_key(iter.key()));
+        assert_eq!(3, opened.get());
+        iter.seek_for_prev(LookupKey::new(b"cc", 10).internal_key());
+        assert_eq!(b"c", extract_user_key(iter.key()));
+        iter.seek(LookupKey::new(b"g", 10).internal_key());
+        assert!(!iter.valid());
+        iter.status().unwrap();
+    }
+}
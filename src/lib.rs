@@ -1,12 +1,36 @@
 mod db;
 mod dbformat;
-mod env;
+pub mod env;
 mod filename;
-mod iterator;
-mod log;
+#[cfg(any(test, feature = "fuzzing"))]
+pub mod fuzz;
+pub mod iterator;
+pub mod log;
 mod memtable;
+pub mod table;
 mod util;
+mod version;
 
+pub use db::{
+    destroy_db, repair_db, replay_event_log, BackgroundErrorInfo, BackgroundErrorReason,
+    BackgroundOperation, BatchEntry, ColumnFamilyHandle, CompactionJobInfo, CompactionStats,
+    CompressionType, DBConfig, DBGroup, DBWithTTL, EventListener, FlushJobInfo, IngestConfig,
+    LevelStatistics, LsmEvent, MultiMap, MultiMapIter, MultiMapOperator, OperationType,
+    OptimisticTransactionDB, Queue, Range, ReadConfig, Replayer, Snapshot, SstFileInfo,
+    SstFileWriter, Statistics, TableFileCreationInfo, TableFileCreationReason,
+    TableFileDeletionInfo, TableFileExtension, Transaction, WalFileInfo, WriteBatch,
+    WriteBatchHandler, WriteBatchIter, WriteBatchWithIndex, WriteConfig, DB,
+    DEFAULT_COLUMN_FAMILY_NAME,
+};
 // tmp
+pub use dbformat::{ParsedInternalKey, RangeTombstone, ValueType, MAX_SEQUENCE_NUMBER};
 pub use memtable::MemTable;
-pub use util::{Error, Result};
+#[cfg(feature = "bloom")]
+pub use util::BloomFilterPolicy;
+pub use util::{
+    cache, coding, BoundaryPartitioner, BytewiseComparator, CompactionDecision, CompactionFilter,
+    Comparator, CompositeComparator, Error, ErrorCode, FilterPolicy, FixedPrefixExtractor,
+    MergeOperator, OutputPartitioner, PrefixExtractor, PrefixPartitioner, RateLimiter, Result,
+    ReverseBytewiseComparator, StringAppendOperator, U64Comparator, UInt64AddOperator,
+    WriteBufferManager,
+};
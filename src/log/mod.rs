@@ -1,9 +1,11 @@
 mod reader;
 mod writer;
 
-pub use reader::{Reader, Reporter};
+pub use reader::{IntoRecords, Reader, Records, Reporter};
 pub use writer::Writer;
 
+use crate::util::Error;
+
 #[derive(Clone, Copy, PartialEq, Debug)]
 #[repr(u8)]
 enum RecordType {
@@ -12,23 +14,30 @@ enum RecordType {
     First = 2,
     Middle = 3,
     Last = 4,
-    Unknown = 5,
+    /// Like Full and First, for a record compressed with snappy.  The
+    /// rest of its fragments are Middle and Last ones.
+    CompressedFull = 5,
+    CompressedFirst = 6,
 }
 
-impl From<u8> for RecordType {
-    fn from(value: u8) -> Self {
+impl TryFrom<u8> for RecordType {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self, Error> {
         match value {
-            0 => Self::Zero,
-            1 => Self::Full,
-            2 => Self::First,
-            3 => Self::Middle,
-            4 => Self::Last,
-            _ => Self::Unknown,
+            0 => Ok(Self::Zero),
+            1 => Ok(Self::Full),
+            2 => Ok(Self::First),
+            3 => Ok(Self::Middle),
+            4 => Ok(Self::Last),
+            5 => Ok(Self::CompressedFull),
+            6 => Ok(Self::CompressedFirst),
+            _ => Err(Error::corruption(&format!("unknown record type {}", value))),
         }
     }
 }
 
-const MAX_RECORD_TYPE: RecordType = RecordType::Last;
+const MAX_RECORD_TYPE: RecordType = RecordType::CompressedFirst;
 
 const BLOCK_SIZE: usize = 32768;
 
@@ -37,15 +46,14 @@ const HEADER_SIZE: usize = 4 + 2 + 1;
 
 #[cfg(test)]
 mod tests {
-    use std::{
-        cell::RefCell,
-        mem::{size_of, size_of_val},
-        rc::Rc,
-    };
+    use std::{cell::RefCell, rc::Rc, sync::Arc};
 
     use super::{reader::Reporter, *};
     use crate::{
-        env::{SequentialFile, WritableFile},
+        env::{
+            read_file_to_vec, write_data_to_file, Env, MemEnv, SequentialFile, WritableFile,
+            WriteHint,
+        },
         util::{crc32c, crc32c_mask, encode_fixed32, Error, Random, Result},
     };
 
@@ -81,82 +89,96 @@ mod tests {
 
     const NUM_INITIAL_OFFSET_RECORDS: usize = INITIAL_OFFSET_LAST_RECORD_OFFSETS.len();
 
+    /// The file the log of a LogTest is written to
+    const LOG_FILE: &str = "/log/000001.log";
+
     struct LogTest {
-        dest: Rc<RefCell<StringDest>>,
-        source: Rc<RefCell<StringSource>>,
+        env: Arc<MemEnv>,
         reporter: Rc<RefCell<ReportCollector>>,
-        reading: bool,
-        writer: Box<Writer>,
-        reader: Box<Reader>,
+        writer: Writer<Box<dyn WritableFile>>,
+        /// Opened by the first read, so that it sees the log as modified
+        /// after it was written
+        reader: Option<Reader<TestSource>>,
+        initial_offset: usize,
+        force_error: bool,
     }
 
     impl LogTest {
         fn new() -> Self {
-            let dest = Rc::new(RefCell::new(StringDest::new()));
-            let source = Rc::new(RefCell::new(StringSource::new()));
-            let reporter = Rc::new(RefCell::new(ReportCollector::new()));
+            let env = Arc::new(MemEnv::new());
+            let file = env.new_writable_file(LOG_FILE, WriteHint::Wal).unwrap();
             Self {
-                dest: dest.clone(),
-                source: source.clone(),
-                reporter: reporter.clone(),
-                reading: false,
-                writer: Box::new(Writer::new(dest)),
-                reader: Box::new(Reader::new(source, 0, true, Some(reporter))),
+                env,
+                reporter: Rc::new(RefCell::new(ReportCollector::new())),
+                writer: Writer::new(file),
+                reader: None,
+                initial_offset: 0,
+                force_error: false,
             }
         }
 
         fn reopen_for_append(&mut self) {
-            self.writer = Box::new(Writer::new_at(
-                self.dest.clone(),
-                self.dest.borrow().contents.len(),
-            ));
+            let file = self.env.new_appendable_file(LOG_FILE).unwrap();
+            self.writer = Writer::new_at(file, self.written_bytes());
         }
 
         fn write(&mut self, msg: &[u8]) {
-            assert!(!self.reading, "Write() after starting to read");
+            assert!(self.reader.is_none(), "Write() after starting to read");
             self.writer.add_record(msg).unwrap();
         }
 
         fn written_bytes(&self) -> usize {
-            self.dest.borrow().contents.len()
+            self.env.get_file_size(LOG_FILE).unwrap() as usize
         }
 
         fn read(&mut self) -> Vec<u8> {
-            if !self.reading {
-                self.reading = true;
-                self.source.borrow_mut().contents = self.dest.borrow().contents.clone();
+            if self.reader.is_none() {
+                let mut source = self.contents_source();
+                source.force_error = self.force_error;
+                self.reader = Some(Reader::new(
+                    source,
+                    self.initial_offset,
+                    true,
+                    Some(self.reporter.clone()),
+                ));
             }
-            if let Some(record) = self.reader.read_record() {
+            if let Some(record) = self.reader.as_mut().unwrap().read_record() {
                 record.to_vec()
             } else {
                 "EOF".as_bytes().to_vec()
             }
         }
 
+        /// Rewrite the log with "modify" applied to its contents.  The
+        /// writer is left behind, so nothing can be written after that.
+        fn modify(&mut self, modify: impl FnOnce(&mut Vec<u8>)) {
+            let mut contents = read_file_to_vec(self.env.as_ref(), LOG_FILE).unwrap();
+            modify(&mut contents);
+            write_data_to_file(self.env.clone(), &contents, LOG_FILE).unwrap();
+        }
+
         fn increment_byte(&mut self, offset: usize, delta: u8) {
-            let contents = &mut self.dest.borrow_mut().contents;
-            contents[offset] = contents[offset].wrapping_add(delta);
+            self.modify(|contents| contents[offset] = contents[offset].wrapping_add(delta));
         }
 
         fn set_byte(&mut self, offset: usize, new_byte: u8) {
-            self.dest.borrow_mut().contents[offset] = new_byte;
+            self.modify(|contents| contents[offset] = new_byte);
         }
 
         fn shrink_size(&mut self, bytes: usize) {
-            let mut dest = self.dest.borrow_mut();
-            let len = dest.contents.len();
-            dest.contents.resize(len - bytes, 0);
+            self.modify(|contents| contents.truncate(contents.len() - bytes));
         }
 
         fn fix_checksum(&mut self, header_offset: usize, len: usize) {
-            let range = header_offset + 6..header_offset + 6 + 1 + len;
-            let crc = crc32c(&self.dest.borrow().contents[range]);
-            let crc = crc32c_mask(crc);
-            encode_fixed32(&mut self.dest.borrow_mut().contents[header_offset..], crc);
+            self.modify(|contents| {
+                let range = header_offset + 6..header_offset + 6 + 1 + len;
+                let crc = crc32c_mask(crc32c(&contents[range]));
+                encode_fixed32(&mut contents[header_offset..], crc).unwrap();
+            });
         }
 
         fn force_error(&mut self) {
-            self.source.borrow_mut().force_error = true;
+            self.force_error = true;
         }
 
         fn dropped_bytes(&self) -> usize {
@@ -177,28 +199,29 @@ mod tests {
             }
         }
 
+        /// A source reading the log from the start
+        fn contents_source(&self) -> TestSource {
+            TestSource {
+                file: self.env.new_sequential_file(LOG_FILE).unwrap(),
+                force_error: false,
+            }
+        }
+
         fn write_initial_offset_log(&mut self) {
-            for i in 0..NUM_INITIAL_OFFSET_RECORDS {
-                let record = vec![b'a' + i as u8; INITIAL_OFFSET_RECORD_SIZES[i]];
+            for (i, &size) in INITIAL_OFFSET_RECORD_SIZES.iter().enumerate() {
+                let record = vec![b'a' + i as u8; size];
                 self.write(&record);
             }
         }
 
         fn start_reading_at(&mut self, initial_offset: usize) {
-            self.reader = Box::new(Reader::new(
-                self.source.clone(),
-                initial_offset,
-                true,
-                Some(self.reporter.clone()),
-            ));
+            self.initial_offset = initial_offset;
         }
 
         fn check_offset_past_end_returns_no_records(&mut self, offset_past_end: usize) {
             self.write_initial_offset_log();
-            self.reading = true;
-            self.source.borrow_mut().contents = self.dest.borrow().contents.clone();
             let mut offset_reader = Reader::new(
-                self.source.clone(),
+                self.contents_source(),
                 self.written_bytes() + offset_past_end,
                 true,
                 Some(self.reporter.clone()),
@@ -212,9 +235,8 @@ mod tests {
             expected_record_offset: usize,
         ) {
             self.write_initial_offset_log();
-            self.source.borrow_mut().contents = self.dest.borrow().contents.clone();
             let mut offset_reader = Reader::new(
-                self.source.clone(),
+                self.contents_source(),
                 initial_offset,
                 true,
                 Some(self.reporter.clone()),
@@ -236,78 +258,24 @@ mod tests {
         }
     }
 
-    struct StringDest {
-        contents: Vec<u8>,
-    }
-
-    impl StringDest {
-        fn new() -> Self {
-            Self { contents: vec![] }
-        }
-    }
-
-    impl WritableFile for StringDest {
-        fn append(&mut self, data: &[u8]) -> crate::util::Result<()> {
-            self.contents.extend_from_slice(data);
-            Ok(())
-        }
-
-        fn close(&mut self) -> crate::util::Result<()> {
-            Ok(())
-        }
-
-        fn flush(&mut self) -> crate::util::Result<()> {
-            Ok(())
-        }
-
-        fn sync(&mut self) -> crate::util::Result<()> {
-            Ok(())
-        }
-    }
-
-    struct StringSource {
-        contents: Vec<u8>,
+    /// A log file of the MemEnv of a LogTest, whose next read can be made
+    /// to fail
+    struct TestSource {
+        file: Box<dyn SequentialFile>,
         force_error: bool,
-        returned_partial: bool,
-    }
-
-    impl StringSource {
-        fn new() -> Self {
-            Self {
-                contents: vec![],
-                force_error: false,
-                returned_partial: false,
-            }
-        }
     }
 
-    impl SequentialFile for StringSource {
+    impl SequentialFile for TestSource {
         fn read(&mut self, dst: &mut [u8]) -> Result<usize> {
-            assert!(!self.returned_partial, "must not Read() after eof/error");
             if self.force_error {
                 self.force_error = false;
-                self.returned_partial = true;
                 return Err(Error::corruption("read error"));
             }
-            let read_size = if self.contents.len() < dst.len() {
-                self.returned_partial = true;
-                self.contents.len()
-            } else {
-                dst.len()
-            };
-            dst[..read_size].copy_from_slice(&self.contents[..read_size]);
-            self.contents = self.contents[read_size..].to_vec();
-            Ok(read_size)
+            self.file.read(dst)
         }
 
         fn skip(&mut self, n: usize) -> Result<()> {
-            if n > self.contents.len() {
-                self.contents.clear();
-                Err(Error::not_found("in-memory file skipped past end"))
-            } else {
-                self.contents = self.contents[n..].to_vec();
-                Ok(())
-            }
+            self.file.skip(n)
         }
     }
 
@@ -624,9 +592,7 @@ mod tests {
         log_test.write("correct".as_bytes());
 
         // Wipe the middle block
-        for offset in BLOCK_SIZE..2 * BLOCK_SIZE {
-            log_test.set_byte(offset, b'x');
-        }
+        log_test.modify(|contents| contents[BLOCK_SIZE..2 * BLOCK_SIZE].fill(b'x'));
         assert_eq!("correct".as_bytes(), log_test.read());
         assert_eq!("EOF".as_bytes(), log_test.read());
         let dropped = log_test.dropped_bytes();
@@ -720,4 +686,116 @@ mod tests {
         let mut log_test = LogTest::new();
         log_test.check_offset_past_end_returns_no_records(5);
     }
+
+    #[test]
+    fn test_log_records() {
+        let mut log_test = LogTest::new();
+        log_test.write_initial_offset_log();
+        let records: Vec<_> = Reader::new(log_test.contents_source(), 0, true, None)
+            .into_iter()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(NUM_INITIAL_OFFSET_RECORDS, records.len());
+        for (i, (offset, record)) in records.iter().enumerate() {
+            assert_eq!(INITIAL_OFFSET_LAST_RECORD_OFFSETS[i] as u64, *offset);
+            assert_eq!(
+                vec![b'a' + i as u8; INITIAL_OFFSET_RECORD_SIZES[i]],
+                *record
+            );
+        }
+
+        // The iteration goes on from where the reader is
+        let mut reader = Reader::new(log_test.contents_source(), 1, true, None);
+        assert_eq!(b'b', reader.read_record().unwrap()[0]);
+        let offsets: Vec<_> = reader.records().map(|record| record.unwrap().0).collect();
+        assert_eq!(
+            INITIAL_OFFSET_LAST_RECORD_OFFSETS[2..]
+                .iter()
+                .map(|&offset| offset as u64)
+                .collect::<Vec<_>>(),
+            offsets
+        );
+
+        // A read error ends the records
+        let mut source = log_test.contents_source();
+        source.force_error = true;
+        let mut reader = Reader::new(source, 0, true, None);
+        let mut records = reader.records();
+        assert!(records
+            .next()
+            .unwrap()
+            .unwrap_err()
+            .to_string()
+            .contains("read error"));
+        assert!(records.next().is_none());
+    }
+
+    #[cfg(feature = "snappy")]
+    #[test]
+    fn test_log_compression() {
+        let mut rnd = Random::new(301);
+        let incompressible: Vec<u8> = (0..3 * BLOCK_SIZE)
+            .map(|_| rnd.uniform(256) as u8)
+            .collect();
+        let mut log_test = LogTest::new();
+        log_test.writer.set_compression(true);
+        log_test.write(b"small");
+        log_test.write(&big_string(b"medium", 50000));
+        log_test.write(&incompressible);
+        log_test.write(&big_string(b"large", 10 * BLOCK_SIZE));
+        log_test.write(b"");
+        // The compressible records take a fraction of their size, the
+        // incompressible one spans blocks as is
+        let written = log_test.written_bytes();
+        assert!(written > 3 * BLOCK_SIZE && written < 4 * BLOCK_SIZE);
+        assert_eq!(b"small".to_vec(), log_test.read());
+        assert_eq!(big_string(b"medium", 50000), log_test.read());
+        assert_eq!(incompressible, log_test.read());
+        assert_eq!(big_string(b"large", 10 * BLOCK_SIZE), log_test.read());
+        assert_eq!(b"".to_vec(), log_test.read());
+        assert_eq!("EOF".as_bytes(), log_test.read());
+        assert_eq!(0, log_test.dropped_bytes());
+
+        // A compressed record that does not uncompress is dropped
+        let mut log_test = LogTest::new();
+        log_test.writer.set_compression(true);
+        log_test.write(&big_string(b"medium", 1000));
+        log_test.write(b"foo");
+        let len = log_test.written_bytes() - 2 * HEADER_SIZE - 3;
+        log_test.set_byte(HEADER_SIZE, 0xff);
+        log_test.fix_checksum(0, len);
+        assert_eq!(b"foo".to_vec(), log_test.read());
+        assert_eq!("EOF".as_bytes(), log_test.read());
+        assert_eq!(len, log_test.dropped_bytes());
+        assert_eq!("OK", log_test.match_error("corrupt"));
+    }
+
+    #[test]
+    fn test_log_write_from_another_thread() {
+        // The writer owns its file, so it can move to a background thread
+        let mut log_test = LogTest::new();
+        let fname = "/log/000002.log";
+        let file = log_test
+            .env
+            .new_writable_file(fname, WriteHint::Wal)
+            .unwrap();
+        let mut writer = Writer::new(file);
+        writer = std::thread::spawn(move || {
+            writer.add_record(b"foo").unwrap();
+            writer
+        })
+        .join()
+        .unwrap();
+        writer.add_record(b"bar").unwrap();
+
+        log_test.write(b"foo");
+        log_test.write(b"bar");
+        let env = log_test.env.as_ref();
+        assert_eq!(
+            read_file_to_vec(env, LOG_FILE).unwrap(),
+            read_file_to_vec(env, fname).unwrap()
+        );
+        assert_eq!("foo".as_bytes(), log_test.read());
+        assert_eq!("bar".as_bytes(), log_test.read());
+    }
 }
@@ -3,7 +3,8 @@ use std::{cell::RefCell, ops::Range, rc::Rc, slice::from_raw_parts};
 use super::{RecordType, BLOCK_SIZE, HEADER_SIZE};
 use crate::{
     env::SequentialFile,
-    util::{crc32c, crc32c_unmask, decode_fixed32, Error},
+    table::format::snappy_uncompress,
+    util::{crc32c, crc32c_unmask, decode_fixed32, Error, Result},
 };
 
 #[derive(Debug)]
@@ -11,10 +12,12 @@ use crate::{
 enum ExtendRecordType {
     Eof,
     BadRecord,
+    /// A record whose type byte is not a RecordType
+    BadType(Error),
 }
 
-pub struct Reader {
-    file: Rc<RefCell<dyn SequentialFile>>,
+pub struct Reader<R: SequentialFile> {
+    file: R,
     reporter: Option<Rc<RefCell<dyn Reporter>>>,
     checksum: bool,
     backing_store: [u8; BLOCK_SIZE],
@@ -30,11 +33,14 @@ pub struct Reader {
     /// particular, a run of kMiddleType and kLastType records can be silently
     /// skipped in this mode
     resyncing: bool,
+    /// The error a read of the file failed with, which ended the log,
+    /// until it is taken by a record iterator
+    read_error: Option<Error>,
 }
 
-impl Reader {
+impl<R: SequentialFile> Reader<R> {
     pub fn new(
-        file: Rc<RefCell<dyn SequentialFile>>,
+        file: R,
         initial_offset: usize,
         checksum: bool,
         reporter: Option<Rc<RefCell<dyn Reporter>>>,
@@ -51,18 +57,19 @@ impl Reader {
             end_of_buffer_offset: 0,
             initial_offset,
             resyncing: initial_offset > 0,
+            read_error: None,
         }
     }
 
     pub fn read_record(&mut self) -> Option<&[u8]> {
-        if self.last_record_offset < self.initial_offset {
-            if !self.skip_to_initial_block() {
-                return None;
-            }
+        if self.last_record_offset < self.initial_offset && !self.skip_to_initial_block() {
+            return None;
         }
 
         self.scratch.clear();
         let mut in_fragmented_record = false;
+        // Whether the fragmented record is compressed
+        let mut compressed_record = false;
         let mut prospective_record_offset = 0;
 
         loop {
@@ -89,17 +96,26 @@ impl Reader {
             }
 
             match result.0 {
-                Ok(RecordType::Full) => {
+                Ok(type_ @ (RecordType::Full | RecordType::CompressedFull)) => {
                     if in_fragmented_record && !self.scratch.is_empty() {
                         self.report_corruption(self.scratch.len(), "partial record without end(1)");
                     }
                     assert!(physical_record_offset >= 0);
                     prospective_record_offset = physical_record_offset as usize;
                     self.scratch.clear();
-                    self.last_record_offset = prospective_record_offset;
-                    return Some(&fragment);
+                    if type_ == RecordType::Full {
+                        self.last_record_offset = prospective_record_offset;
+                        return Some(fragment);
+                    }
+                    in_fragmented_record = false;
+                    // "fragment" points into the buffer of the reader
+                    let compressed = fragment.to_vec();
+                    if self.uncompress_into_scratch(&compressed) {
+                        self.last_record_offset = prospective_record_offset;
+                        return Some(&self.scratch);
+                    }
                 }
-                Ok(RecordType::First) => {
+                Ok(type_ @ (RecordType::First | RecordType::CompressedFirst)) => {
                     if in_fragmented_record && !self.scratch.is_empty() {
                         self.report_corruption(self.scratch.len(), "partial record without end(2)");
                     }
@@ -107,6 +123,7 @@ impl Reader {
                     prospective_record_offset = physical_record_offset as usize;
                     self.scratch = fragment.to_vec();
                     in_fragmented_record = true;
+                    compressed_record = type_ == RecordType::CompressedFirst;
                 }
                 Ok(RecordType::Middle) => {
                     if !in_fragmented_record {
@@ -126,6 +143,13 @@ impl Reader {
                         )
                     } else {
                         self.scratch.extend_from_slice(fragment);
+                        if compressed_record {
+                            in_fragmented_record = false;
+                            let compressed = std::mem::take(&mut self.scratch);
+                            if !self.uncompress_into_scratch(&compressed) {
+                                continue;
+                            }
+                        }
                         self.last_record_offset = prospective_record_offset;
                         return Some(&self.scratch);
                     }
@@ -146,16 +170,23 @@ impl Reader {
                         self.scratch.clear();
                     }
                 }
-                record_type => {
+                Err(ExtendRecordType::BadType(error)) => {
+                    let drop_size = if in_fragmented_record {
+                        self.scratch.len() + fragment.len()
+                    } else {
+                        fragment.len()
+                    };
+                    self.report_drop(drop_size, &error);
+                    in_fragmented_record = false;
+                    self.scratch.clear();
+                }
+                Ok(RecordType::Zero) => {
                     let drop_size = if in_fragmented_record {
                         self.scratch.len() + fragment.len()
                     } else {
                         fragment.len()
                     };
-                    self.report_corruption(
-                        drop_size,
-                        &format!("unknown record type {:?}", record_type),
-                    );
+                    self.report_corruption(drop_size, "unexpected zero record type");
                     in_fragmented_record = false;
                     self.scratch.clear();
                 }
@@ -167,6 +198,43 @@ impl Reader {
         self.last_record_offset
     }
 
+    /// Return an iterator over the records from the current position,
+    /// each with the offset it starts at.  Corrupted records are skipped
+    /// and reported, as by read_record().  A failed read of the file ends
+    /// the log: the iterator yields the error last.
+    pub fn records(&mut self) -> Records<'_, R> {
+        Records { reader: self }
+    }
+
+    fn next_record(&mut self) -> Option<Result<(u64, Vec<u8>)>> {
+        match self.read_record() {
+            Some(record) => {
+                let record = record.to_vec();
+                Some(Ok((self.last_record_offset as u64, record)))
+            }
+            None => self.read_error.take().map(Err),
+        }
+    }
+
+    pub fn file_mut(&mut self) -> &mut R {
+        &mut self.file
+    }
+
+    /// Uncompress the compressed record "record" into scratch.  Returns
+    /// false, reporting the record as dropped, if it can't be.
+    fn uncompress_into_scratch(&mut self, record: &[u8]) -> bool {
+        match snappy_uncompress(record) {
+            Ok(uncompressed) => {
+                self.scratch = uncompressed;
+                true
+            }
+            Err(error) => {
+                self.report_drop(record.len(), &error);
+                false
+            }
+        }
+    }
+
     /// Skips all blocks that are completely before "initial_offset".
     /// Returns true on success. Handles reporting.
     fn skip_to_initial_block(&mut self) -> bool {
@@ -179,7 +247,7 @@ impl Reader {
         self.end_of_buffer_offset = block_start_location;
         // Skip to start of first block that can contain the initial record
         if block_start_location > 0 {
-            let result = self.file.borrow_mut().skip(block_start_location);
+            let result = self.file.skip(block_start_location);
             if let Err(error) = result {
                 self.report_drop(block_start_location, &error);
                 return false;
@@ -188,19 +256,22 @@ impl Reader {
         true
     }
 
-    fn read_physical_record<'a>(&mut self) -> (Result<RecordType, ExtendRecordType>, &'a [u8]) {
+    fn read_physical_record<'a>(
+        &mut self,
+    ) -> (std::result::Result<RecordType, ExtendRecordType>, &'a [u8]) {
         loop {
             if self.buffer_range.len() < HEADER_SIZE {
                 if !self.eof {
                     // Last read was a full read, so this is a trailer to skip
                     self.buffer_range = 0..0;
-                    let result = self.file.borrow_mut().read(&mut self.backing_store);
+                    let result = self.file.read(&mut self.backing_store);
                     let read_size = *result.as_ref().unwrap_or(&0);
                     self.buffer_range = 0..read_size;
                     self.end_of_buffer_offset += self.buffer_range.len();
-                    if result.is_err() {
+                    if let Err(error) = result {
                         self.buffer_range = 0..0;
-                        self.report_drop(BLOCK_SIZE, &result.unwrap_err());
+                        self.report_drop(BLOCK_SIZE, &error);
+                        self.read_error = Some(error);
                         self.eof = true;
                         return (Err(ExtendRecordType::Eof), &[]);
                     } else if self.buffer_range.len() < BLOCK_SIZE {
@@ -227,7 +298,7 @@ impl Reader {
             let a = buffer[4] as u32;
             let b = buffer[5] as u32;
             let length = a | (b << 8);
-            let type_: RecordType = buffer[6].into();
+            let type_ = RecordType::try_from(buffer[6]);
             if HEADER_SIZE + length as usize > buffer.len() {
                 let drop_size = buffer.len();
                 self.buffer_range = 0..0;
@@ -241,7 +312,7 @@ impl Reader {
                 return (Err(ExtendRecordType::Eof), &[]);
             }
 
-            if type_ == RecordType::Zero && length == 0 {
+            if type_.as_ref().is_ok_and(|&type_| type_ == RecordType::Zero) && length == 0 {
                 // Skip zero length record without reporting any drops since
                 // such records are produced by the mmap based writing code in
                 // env_posix.cc that preallocates file regions.
@@ -250,7 +321,7 @@ impl Reader {
             }
 
             if self.checksum {
-                let expected_crc = crc32c_unmask(decode_fixed32(&buffer));
+                let expected_crc = crc32c_unmask(decode_fixed32(buffer));
                 let actual_crc = crc32c(&buffer[6..6 + 1 + length as usize]);
                 if actual_crc != expected_crc {
                     // Drop the rest of the buffer since "length" itself may have
@@ -273,7 +344,7 @@ impl Reader {
                 return (Err(ExtendRecordType::BadRecord), &[]);
             }
             return (
-                Ok(type_),
+                type_.map_err(ExtendRecordType::BadType),
                 &buffer[HEADER_SIZE..HEADER_SIZE + length as usize],
             );
         }
@@ -284,21 +355,54 @@ impl Reader {
     }
 
     fn report_drop(&mut self, bytes: usize, reason: &Error) {
-        if self.reporter.is_some()
-            && self
+        if let Some(reporter) = &self.reporter {
+            if self
                 .end_of_buffer_offset
                 .wrapping_sub(self.buffer_range.len() + bytes)
                 >= self.initial_offset
-        {
-            self.reporter
-                .as_mut()
-                .unwrap()
-                .borrow_mut()
-                .corruption(bytes, reason)
+            {
+                reporter.borrow_mut().corruption(bytes, reason)
+            }
         }
     }
 }
 
+/// Iterates over the records of a log, see Reader::records().
+pub struct Records<'a, R: SequentialFile> {
+    reader: &'a mut Reader<R>,
+}
+
+impl<R: SequentialFile> Iterator for Records<'_, R> {
+    type Item = Result<(u64, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.reader.next_record()
+    }
+}
+
+/// Iterates over the records of a log, taking the reader along, see
+/// Reader::records().
+pub struct IntoRecords<R: SequentialFile> {
+    reader: Reader<R>,
+}
+
+impl<R: SequentialFile> Iterator for IntoRecords<R> {
+    type Item = Result<(u64, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.reader.next_record()
+    }
+}
+
+impl<R: SequentialFile> IntoIterator for Reader<R> {
+    type Item = Result<(u64, Vec<u8>)>;
+    type IntoIter = IntoRecords<R>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        IntoRecords { reader: self }
+    }
+}
+
 pub trait Reporter {
     fn corruption(&mut self, bytes: usize, error: &Error);
 }
@@ -1,30 +1,40 @@
-use std::{cell::RefCell, rc::Rc};
+use std::borrow::Cow;
 
 use super::{RecordType, BLOCK_SIZE, HEADER_SIZE, MAX_RECORD_TYPE};
 use crate::{
     env::WritableFile,
+    table::format::snappy_compress,
     util::{crc32c, crc32c_append, crc32c_mask, encode_fixed32, Result},
 };
 
-pub struct Writer {
-    dest: Rc<RefCell<dyn WritableFile>>,
+/// Appends records to a log file.  The writer owns the file: the DB
+/// owns it through the writer, and reaches it with file_mut() to sync it.
+pub struct Writer<W: WritableFile> {
+    dest: W,
     block_offset: usize,
     /// crc32c values for all supported record types.  These are
     /// pre-computed to reduce the overhead of computing the crc of the
     /// record type stored in the header.
     type_crc: [u32; MAX_RECORD_TYPE as usize + 1],
+    /// Whether records are compressed before they are fragmented
+    compression: bool,
 }
 
-impl Writer {
-    pub fn new(dest: Rc<RefCell<dyn WritableFile>>) -> Self {
+impl<W: WritableFile> Writer<W> {
+    /// Create a writer that will append data to "dest".
+    /// "dest" must be initially empty.
+    pub fn new(dest: W) -> Self {
         Self::new_at(dest, 0)
     }
 
-    pub fn new_at(dest: Rc<RefCell<dyn WritableFile>>, dest_len: usize) -> Self {
+    /// Create a writer that will append data to "dest".
+    /// "dest" must have initial length "dest_len".
+    pub fn new_at(dest: W, dest_len: usize) -> Self {
         let mut result = Self {
             dest,
             block_offset: dest_len % BLOCK_SIZE,
             type_crc: [0; MAX_RECORD_TYPE as usize + 1],
+            compression: false,
         };
         for (index, num) in result.type_crc.iter_mut().enumerate() {
             *num = crc32c(&[index as u8])
@@ -32,8 +42,30 @@ impl Writer {
         result
     }
 
+    pub fn file(&self) -> &W {
+        &self.dest
+    }
+
+    pub fn file_mut(&mut self) -> &mut W {
+        &mut self.dest
+    }
+
+    pub fn into_file(self) -> W {
+        self.dest
+    }
+
+    /// Compress the records added from now on with snappy, each as a
+    /// whole before it is split into fragments.  As with table blocks, a
+    /// record that does not shrink by at least 12.5% is stored as it is,
+    /// and so is every record without the snappy cargo feature.  Readers
+    /// of older versions can't read compressed records.
+    pub fn set_compression(&mut self, compression: bool) {
+        self.compression = compression;
+    }
+
     pub fn add_record(&mut self, slice: &[u8]) -> Result<()> {
-        let mut slice_left = slice;
+        let (slice, compressed) = self.compress(slice);
+        let mut slice_left = &slice[..];
         let mut begin = true;
         loop {
             let leftover = BLOCK_SIZE - self.block_offset;
@@ -41,15 +73,19 @@ impl Writer {
                 // Switch to a new block
                 if leftover > 0 {
                     // Fill the trailer (literal below relies on kHeaderSize being 7)
-                    let _ = self.dest.borrow_mut().append(&[0; 7][0..leftover]);
+                    let _ = self.dest.append(&[0; 7][0..leftover]);
                 }
                 self.block_offset = 0;
             }
             let avial = BLOCK_SIZE - self.block_offset - HEADER_SIZE;
             let fragment_length = slice_left.len().min(avial);
             let end = fragment_length == slice_left.len();
-            let type_ = if begin && end {
+            let type_ = if begin && end && compressed {
+                RecordType::CompressedFull
+            } else if begin && end {
                 RecordType::Full
+            } else if begin && compressed {
+                RecordType::CompressedFirst
             } else if begin {
                 RecordType::First
             } else if end {
@@ -66,21 +102,33 @@ impl Writer {
         }
     }
 
+    /// Return the contents to store for record "slice", and whether they
+    /// are compressed.
+    fn compress<'a>(&self, slice: &'a [u8]) -> (Cow<'a, [u8]>, bool) {
+        let compressed = match self.compression {
+            true => snappy_compress(slice),
+            false => None,
+        };
+        match compressed {
+            Some(compressed) if compressed.len() < slice.len() - slice.len() / 8 => {
+                (Cow::Owned(compressed), true)
+            }
+            _ => (Cow::Borrowed(slice), false),
+        }
+    }
+
     fn emit_physical_record(&mut self, type_: RecordType, data: &[u8]) -> Result<()> {
         assert!(data.len() <= 0xffff);
         assert!(self.block_offset + HEADER_SIZE + data.len() <= BLOCK_SIZE);
         let mut buf = [0; HEADER_SIZE];
         let crc = crc32c_append(self.type_crc[type_ as usize], data);
-        encode_fixed32(&mut buf[0..4], crc32c_mask(crc));
+        encode_fixed32(&mut buf[0..4], crc32c_mask(crc))?;
         buf[4] = data.len() as u8;
         buf[5] = (data.len() >> 8) as u8;
         buf[6] = type_ as u8;
-        let mut result = self.dest.borrow_mut().append(&buf);
+        let mut result = self.dest.append_slices(&[&buf, data]);
         if result.is_ok() {
-            result = self.dest.borrow_mut().append(data);
-            if result.is_ok() {
-                result = self.dest.borrow_mut().flush();
-            }
+            result = self.dest.flush();
         }
         self.block_offset += HEADER_SIZE + data.len();
         result
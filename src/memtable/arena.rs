@@ -1,15 +1,28 @@
 /// Used for memory allocation
-use std::{mem, ptr};
+use std::{
+    alloc::{self, Layout},
+    borrow::Borrow,
+    mem,
+    ptr::{self, NonNull},
+    slice,
+};
 
 const BLOCK_SIZE: usize = 4096;
+/// Every block starts at a multiple of this, which allocate_aligned()
+/// relies on
+const BLOCK_ALIGN: usize = 16;
 
 pub struct Arena {
     alloc_ptr: *mut u8,
     alloc_bytes_remaining: usize,
-    blocks: Vec<Vec<u8>>,
+    /// Blocks are allocated with their layout, which they are freed with
+    blocks: Vec<(NonNull<u8>, Layout)>,
     memory_usage: usize,
 }
 
+// SAFETY: the arena owns the blocks its pointer points into
+unsafe impl Send for Arena {}
+
 impl Arena {
     pub fn new() -> Self {
         Self {
@@ -27,11 +40,25 @@ impl Arena {
             self.alloc_bytes_remaining -= bytes;
             return result;
         }
-        self.allocate_fallback(bytes)
+        self.allocate_fallback(bytes, 1)
     }
 
+    /// Allocate with the alignment normally provided by malloc
+    #[cfg(test)]
     pub fn allocate_aligned(&mut self, bytes: usize) -> *mut u8 {
-        let align = mem::size_of::<usize>().max(8);
+        self.allocate_with_align(bytes, mem::size_of::<usize>().max(8))
+    }
+
+    /// Allocate "bytes" bytes for a T, which may be followed by a variable
+    /// part, and that are aligned as a T has to be.  The bytes are zeroed,
+    /// but not initialized as a T.
+    pub fn allocate_for<T>(&mut self, bytes: usize) -> *mut T {
+        debug_assert!(bytes >= mem::size_of::<T>());
+        self.allocate_with_align(bytes, mem::align_of::<T>()) as *mut T
+    }
+
+    fn allocate_with_align(&mut self, bytes: usize, align: usize) -> *mut u8 {
+        debug_assert!(align.is_power_of_two());
         let current_mod = self.alloc_ptr as usize & (align - 1);
         let slop = if current_mod == 0 {
             0
@@ -45,21 +72,34 @@ impl Arena {
             self.alloc_bytes_remaining -= needed;
             return result;
         }
-        let result = self.allocate_fallback(bytes);
-        result
+
+        self.allocate_fallback(bytes, align)
+    }
+
+    /// Allocate "len" bytes, let "fill" write them, and return them as a
+    /// slice that can be read for as long as the arena lives.
+    pub fn allocate_slice(&mut self, len: usize, fill: impl FnOnce(&mut [u8])) -> ArenaSlice {
+        let ptr = self.allocate(len);
+        fill(unsafe { slice::from_raw_parts_mut(ptr, len) });
+        ArenaSlice {
+            ptr: NonNull::new(ptr).unwrap(),
+            len,
+        }
     }
 
     pub fn memory_usage(&self) -> usize {
         self.memory_usage
     }
 
-    fn allocate_fallback(&mut self, bytes: usize) -> *mut u8 {
-        // allocate huge block separately
-        if bytes > BLOCK_SIZE / 4 {
-            return self.allocate_new_block(bytes);
+    fn allocate_fallback(&mut self, bytes: usize, align: usize) -> *mut u8 {
+        // allocate huge block separately, and so blocks that need more
+        // alignment than blocks have
+        if bytes > BLOCK_SIZE / 4 || align > BLOCK_ALIGN {
+            return self.allocate_new_block(bytes, align.max(BLOCK_ALIGN));
         }
 
-        self.alloc_ptr = self.allocate_new_block(BLOCK_SIZE);
+        // A new block is aligned for anything that fits in one
+        self.alloc_ptr = self.allocate_new_block(BLOCK_SIZE, BLOCK_ALIGN);
         self.alloc_bytes_remaining = BLOCK_SIZE;
         let result = self.alloc_ptr;
         self.alloc_ptr = unsafe { self.alloc_ptr.add(bytes) };
@@ -67,16 +107,63 @@ impl Arena {
         result
     }
 
-    fn allocate_new_block(&mut self, block_bytes: usize) -> *mut u8 {
-        self.blocks.push(vec![0u8; block_bytes]);
+    fn allocate_new_block(&mut self, block_bytes: usize, align: usize) -> *mut u8 {
+        // Zero-sized allocations are not allowed
+        let layout = Layout::from_size_align(block_bytes.max(1), align).unwrap();
+        let block = match NonNull::new(unsafe { alloc::alloc_zeroed(layout) }) {
+            Some(block) => block,
+            None => alloc::handle_alloc_error(layout),
+        };
+        self.blocks.push((block, layout));
         self.memory_usage += block_bytes;
-        self.blocks.last_mut().unwrap().as_mut_ptr()
+        block.as_ptr()
+    }
+}
+
+impl Drop for Arena {
+    fn drop(&mut self) {
+        for &(block, layout) in &self.blocks {
+            unsafe { alloc::dealloc(block.as_ptr(), layout) };
+        }
+    }
+}
+
+/// Bytes allocated from an Arena.  Only the arena makes them, and they
+/// must not outlive it: the memtable keeps them in the nodes of its
+/// skiplist, which live in the same arena, and only hands out slices
+/// borrowed from the list.
+pub struct ArenaSlice {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+// SAFETY: the bytes are never written once the slice is made
+unsafe impl Send for ArenaSlice {}
+unsafe impl Sync for ArenaSlice {}
+
+impl ArenaSlice {
+    /// An empty slice that points into no arena
+    pub fn empty() -> Self {
+        Self {
+            ptr: NonNull::dangling(),
+            len: 0,
+        }
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Borrow<[u8]> for ArenaSlice {
+    fn borrow(&self) -> &[u8] {
+        self.as_slice()
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Arena;
+    use super::{Arena, ArenaSlice};
     use crate::util::Random;
 
     #[test]
@@ -126,13 +213,46 @@ mod tests {
                 assert!(arena.memory_usage() <= (bytes as f64 * 1.10) as usize);
             }
         }
-        for i in 0..allocated.len() {
-            let num_bytes = allocated[i].0;
-            let p = allocated[i].1;
+        for (i, &(num_bytes, p)) in allocated.iter().enumerate() {
             for b in 0..num_bytes {
                 // Check the "i"th allocation for the known bit pattern
                 assert_eq!(unsafe { *p.add(b) }, i as u8);
             }
         }
     }
+
+    #[test]
+    fn test_arena_slice() {
+        let mut arena = Arena::new();
+        let slices: Vec<_> = (0..1000usize)
+            .map(|i| arena.allocate_slice(i % 100 + 1, |bytes| bytes.fill(i as u8)))
+            .collect();
+        for (i, slice) in slices.iter().enumerate() {
+            assert_eq!(vec![i as u8; i % 100 + 1], slice.as_slice());
+        }
+        assert!(ArenaSlice::empty().as_slice().is_empty());
+    }
+
+    #[test]
+    fn test_arena_alignment() {
+        #[repr(align(64))]
+        struct Aligned {
+            _bytes: [u8; 64],
+        }
+
+        let mut arena = Arena::new();
+        for i in 0..1000 {
+            arena.allocate(i % 7 + 1);
+            let aligned = arena.allocate_aligned(i % 13 + 1);
+            assert_eq!(0, aligned as usize % 8);
+            let node = arena.allocate_for::<u64>(8 + i % 32);
+            assert_eq!(0, node as usize % std::mem::align_of::<u64>());
+            let node = arena.allocate_for::<Aligned>(64 + i % 100);
+            assert_eq!(0, node as usize % 64);
+        }
+        // A huge allocation is a block of its own
+        let huge = arena.allocate_for::<u64>(100_000);
+        assert_eq!(0, huge as usize % 8);
+        assert!(arena.memory_usage() >= 100_000);
+    }
 }
@@ -0,0 +1,89 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::util::hash;
+
+/// Number of bits set per key
+const NUM_PROBES: u32 = 6;
+
+/// A bloom filter of a fixed number of bits that keys are added to one
+/// at a time, as opposed to the filters of tables which are built from
+/// all their keys at once.  Keys may be added while other threads query
+/// the filter; a query never misses a key whose add() returned.
+pub struct DynamicBloom {
+    bits: Box<[AtomicU64]>,
+}
+
+impl DynamicBloom {
+    /// Create a filter of at least "num_bits" bits, and at least 64.
+    pub fn new(num_bits: usize) -> Self {
+        let words = num_bits.div_ceil(64).max(1);
+        Self {
+            bits: (0..words).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    pub fn add(&self, key: &[u8]) {
+        self.for_each_probe(key, |word, mask| {
+            // Readers only need the bit to show up eventually, and at
+            // the latest once the entry it guards is visible
+            self.bits[word].fetch_or(mask, Ordering::Release);
+            true
+        });
+    }
+
+    /// Return false if "key" was never added.
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.for_each_probe(key, |word, mask| {
+            self.bits[word].load(Ordering::Acquire) & mask != 0
+        })
+    }
+
+    /// Call "probe" with the word and bit of each probe of "key" until it
+    /// returns false.  Returns whether all the probes returned true.
+    fn for_each_probe(&self, key: &[u8], mut probe: impl FnMut(usize, u64) -> bool) -> bool {
+        let num_bits = self.bits.len() as u64 * 64;
+        // Use double-hashing to generate a sequence of hash values, like
+        // the bloom filter of tables does
+        let mut h = hash(key, 0xbc9f1d34);
+        let delta = h.rotate_left(15);
+        for _ in 0..NUM_PROBES {
+            let bitpos = h as u64 % num_bits;
+            if !probe((bitpos / 64) as usize, 1 << (bitpos % 64)) {
+                return false;
+            }
+            h = h.wrapping_add(delta);
+        }
+        true
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        self.bits.len() * 8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dynamic_bloom() {
+        let bloom = DynamicBloom::new(10 * 1000);
+        assert_eq!(157 * 8, bloom.memory_usage());
+        for i in 0..1000u32 {
+            bloom.add(&i.to_le_bytes());
+        }
+        for i in 0..1000u32 {
+            assert!(bloom.may_contain(&i.to_le_bytes()));
+        }
+        // About 1% false positives at 10 bits per key
+        let false_positives = (1000..11000u32)
+            .filter(|i| bloom.may_contain(&i.to_le_bytes()))
+            .count();
+        assert!(false_positives < 300, "{}", false_positives);
+
+        // Even the smallest filter does not miss its keys
+        let bloom = DynamicBloom::new(0);
+        bloom.add(b"key");
+        assert!(bloom.may_contain(b"key"));
+    }
+}
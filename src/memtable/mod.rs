@@ -1,99 +1,210 @@
 mod arena;
+mod dynamic_bloom;
 mod skiplist;
 
 use std::{
-    cell::RefCell, cmp::Ordering, intrinsics::copy_nonoverlapping, ptr::null, rc::Rc, slice,
+    cmp::Ordering,
+    ops::Deref,
+    sync::{
+        atomic::{AtomicU64, Ordering as AtomicOrdering},
+        Arc, OnceLock,
+    },
 };
 
+use arena::ArenaSlice;
+use dynamic_bloom::DynamicBloom;
 use skiplist::{KeyComparator, SkipList, SkipListIterator};
 
 use crate::{
-    dbformat::{InternalKeyComparator, LookupKey, ValueType},
+    db::LookupState,
+    dbformat::{
+        extract_user_key, InternalKeyComparator, LookupKey, ParsedInternalKey, RangeTombstone,
+        ValueType, MAX_SEQUENCE_NUMBER, VALUE_TYPE_FOR_SEEK,
+    },
     iterator::Iterator,
     util::{
-        decode_fixed64, decode_varint32, encode_fixed64, encode_varint32, extend_varint32,
-        varint_size, Comparator, Error, Result,
+        decode_fixed64, decode_size_prefixed_slice, encode_fixed64, encode_varint32,
+        extend_varint32, varint_size, Comparator, Error, PrefixExtractor, Result,
     },
 };
 
-/// Used to get internal key
-fn decode_length_prefixed_slice_ptr(ptr: *const u8) -> (&'static [u8], usize) {
-    let buf = unsafe { slice::from_raw_parts(ptr, 5) };
-    let (len, offset) = decode_varint32(&buf).unwrap();
-    (
-        unsafe { &slice::from_raw_parts(ptr.add(offset), len as usize) },
-        offset + len as usize,
-    )
+/// Used to get internal key and value of an entry, or the internal key of
+/// a lookup key
+fn decode_length_prefixed_slice(data: &[u8]) -> (&[u8], usize) {
+    decode_size_prefixed_slice(data).unwrap()
 }
 
 pub struct MemTableKeyComparator {
     comparator: InternalKeyComparator,
 }
 
-impl MemTableKeyComparator {
-    pub fn new(comparator: InternalKeyComparator) -> Self {
-        Self { comparator }
-    }
-}
+/// compare the internal keys the entries start with
+impl KeyComparator for MemTableKeyComparator {
+    type Key = [u8];
 
-/// compare the inner data which key refers to
-impl KeyComparator<*const u8> for MemTableKeyComparator {
-    fn compare(&self, a: &*const u8, b: &*const u8) -> Ordering {
-        let (a, _) = decode_length_prefixed_slice_ptr(*a);
-        let (b, _) = decode_length_prefixed_slice_ptr(*b);
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        let (a, _) = decode_length_prefixed_slice(a);
+        let (b, _) = decode_length_prefixed_slice(b);
         self.comparator.compare(a, b)
     }
 }
 
-struct MemTableIterator<'a> {
-    iter: SkipListIterator<'a, *const u8, MemTableKeyComparator>,
+type Table = SkipList<ArenaSlice, MemTableKeyComparator>;
+
+/// Iterates over the table "L" derefs to: a borrowed one, or the main
+/// table of a memtable it holds a reference to.
+struct MemTableIterator<L> {
+    iter: SkipListIterator<L, ArenaSlice, MemTableKeyComparator>,
     tmp: Vec<u8>,
+    /// The iterator only visits user keys >= lower_bound and < upper_bound
+    lower_bound: Option<Vec<u8>>,
+    upper_bound: Option<Vec<u8>>,
+    /// Whether the current entry is out of the bounds
+    out_of_bounds: bool,
+    /// If set, seek() first checks the prefix bloom of this memtable,
+    /// and finds nothing if no key has the prefix of the target
+    prefix_filter: Option<Arc<MemTable>>,
 }
 
-impl<'a> MemTableIterator<'a> {
-    pub fn new(table: &'a SkipList<*const u8, MemTableKeyComparator>) -> Self {
+impl<L: Deref<Target = Table>> MemTableIterator<L> {
+    pub fn new(table: L, lower_bound: Option<Vec<u8>>, upper_bound: Option<Vec<u8>>) -> Self {
         Self {
             iter: SkipListIterator::new(table),
             tmp: vec![],
+            lower_bound,
+            upper_bound,
+            out_of_bounds: false,
+            prefix_filter: None,
         }
     }
+
+    fn seek_internal(&mut self, target: &[u8]) {
+        self.encode_target(target);
+        self.iter.seek(&self.tmp)
+    }
+
+    /// Encode "target" into tmp as the skiplist keys are
+    fn encode_target(&mut self, target: &[u8]) {
+        self.tmp.clear();
+        extend_varint32(&mut self.tmp, target.len() as u32);
+        self.tmp.extend_from_slice(target);
+    }
+
+    /// Seek to the first entry of "user_key"
+    fn seek_user_key(&mut self, user_key: &[u8]) {
+        let mut target = vec![];
+        ParsedInternalKey::new(user_key, MAX_SEQUENCE_NUMBER, VALUE_TYPE_FOR_SEEK)
+            .append_to(&mut target);
+        self.seek_internal(&target);
+    }
+
+    fn user_comparator(&self) -> &dyn Comparator {
+        self.iter
+            .list()
+            .comparator
+            .comparator
+            .user_comparator()
+            .as_ref()
+    }
+
+    fn below_lower_bound(&self, user_key: &[u8]) -> bool {
+        self.lower_bound
+            .as_deref()
+            .is_some_and(|lower| self.user_comparator().compare(user_key, lower) == Ordering::Less)
+    }
+
+    fn at_or_above_upper_bound(&self, user_key: &[u8]) -> bool {
+        self.upper_bound
+            .as_deref()
+            .is_some_and(|upper| self.user_comparator().compare(user_key, upper) != Ordering::Less)
+    }
+
+    /// Find out whether the entry the skiplist iterator is at is out of
+    /// the bounds, which ends the iteration in either direction
+    fn check_bounds(&mut self) {
+        self.out_of_bounds = self.iter.valid() && {
+            let user_key = extract_user_key(self.key());
+            self.below_lower_bound(user_key) || self.at_or_above_upper_bound(user_key)
+        };
+    }
 }
 
-impl<'a> Iterator for MemTableIterator<'a> {
+impl<L: Deref<Target = Table>> Iterator for MemTableIterator<L> {
     fn valid(&self) -> bool {
-        self.iter.valid()
+        self.iter.valid() && !self.out_of_bounds
     }
 
     fn seek_to_first(&mut self) {
-        self.iter.seek_to_first()
+        match self.lower_bound.clone() {
+            Some(lower) => self.seek_user_key(&lower),
+            None => self.iter.seek_to_first(),
+        }
+        self.check_bounds();
     }
 
     fn seek_to_last(&mut self) {
-        self.iter.seek_to_last()
+        match self.upper_bound.clone() {
+            Some(upper) => {
+                // Position at the last entry before the upper bound
+                self.seek_user_key(&upper);
+                if self.iter.valid() {
+                    self.iter.prev();
+                } else {
+                    self.iter.seek_to_last();
+                }
+            }
+            None => self.iter.seek_to_last(),
+        }
+        self.check_bounds();
     }
 
     fn seek(&mut self, target: &[u8]) {
-        self.tmp.clear();
-        extend_varint32(&mut self.tmp, target.len() as u32);
-        self.tmp.extend_from_slice(target);
-        self.iter.seek(&self.tmp.as_ptr())
+        if self
+            .prefix_filter
+            .as_ref()
+            .is_some_and(|mem| !mem.prefix_may_match(extract_user_key(target)))
+        {
+            self.iter.invalidate();
+            self.out_of_bounds = false;
+            return;
+        }
+        match self.lower_bound.clone() {
+            Some(lower) if self.below_lower_bound(extract_user_key(target)) => {
+                self.seek_user_key(&lower)
+            }
+            _ => self.seek_internal(target),
+        }
+        self.check_bounds();
+    }
+
+    fn seek_for_prev(&mut self, target: &[u8]) {
+        if self.at_or_above_upper_bound(extract_user_key(target)) {
+            self.seek_to_last();
+            return;
+        }
+        self.encode_target(target);
+        self.iter.seek_for_prev(&self.tmp);
+        self.check_bounds();
     }
 
     fn next(&mut self) {
-        self.iter.next()
+        self.iter.next();
+        self.check_bounds();
     }
 
     fn prev(&mut self) {
-        self.iter.prev()
+        self.iter.prev();
+        self.check_bounds();
     }
 
     fn key(&self) -> &[u8] {
-        decode_length_prefixed_slice_ptr(*self.iter.key()).0
+        decode_length_prefixed_slice(self.iter.key()).0
     }
 
     fn value(&self) -> &[u8] {
-        let (_, offset) = decode_length_prefixed_slice_ptr(*self.iter.key());
-        decode_length_prefixed_slice_ptr(unsafe { self.iter.key().add(offset) }).0
+        let entry = self.iter.key();
+        let (_, offset) = decode_length_prefixed_slice(entry);
+        decode_length_prefixed_slice(&entry[offset..]).0
     }
 
     fn status(&self) -> Result<()> {
@@ -101,23 +212,225 @@ impl<'a> Iterator for MemTableIterator<'a> {
     }
 }
 
+/// The main table of a memtable, kept alive by the iterators over it
+struct OwnedTable(Arc<MemTable>);
+
+impl Deref for OwnedTable {
+    type Target = Table;
+
+    fn deref(&self) -> &Table {
+        &self.0.table
+    }
+}
+
+/// A sorted buffer of recent writes.  Like the skiplist it is built on,
+/// a memtable takes one writer at a time and any number of concurrent
+/// readers, which need no locks; it is shared through an Arc, so that
+/// e.g. a memtable can be flushed on one thread while others read it.
 pub struct MemTable {
-    table: SkipList<*const u8, MemTableKeyComparator>,
+    table: Table,
+    /// The range tombstones, in the same format as the other entries
+    /// but apart from them, so that point lookups and scans need not
+    /// step over them.  Created with the first tombstone, since most
+    /// memtables have none.
+    range_del_table: OnceLock<Table>,
+    num_entries: AtomicU64,
+    total_key_bytes: AtomicU64,
+    total_value_bytes: AtomicU64,
+    /// If set, holds the prefixes of all the user keys added that have
+    /// one, so that get() can skip searching for keys of other prefixes
+    prefix_bloom: Option<(Arc<dyn PrefixExtractor>, DynamicBloom)>,
 }
 
 impl MemTable {
-    pub fn new(comparator: InternalKeyComparator) -> Rc<RefCell<Self>> {
-        Rc::new(RefCell::new(Self {
-            table: SkipList::new(MemTableKeyComparator { comparator }, null()),
-        }))
+    pub fn new(comparator: InternalKeyComparator) -> Arc<Self> {
+        Arc::new(Self::new_inner(comparator, None))
+    }
+
+    /// Create a memtable with a bloom filter of "bloom_bits" bits over
+    /// the prefixes of its keys, as extracted by "prefix_extractor".
+    pub fn with_prefix_bloom(
+        comparator: InternalKeyComparator,
+        prefix_extractor: Arc<dyn PrefixExtractor>,
+        bloom_bits: usize,
+    ) -> Arc<Self> {
+        Arc::new(Self::new_inner(
+            comparator,
+            Some((prefix_extractor, DynamicBloom::new(bloom_bits))),
+        ))
+    }
+
+    fn new_inner(
+        comparator: InternalKeyComparator,
+        prefix_bloom: Option<(Arc<dyn PrefixExtractor>, DynamicBloom)>,
+    ) -> Self {
+        Self {
+            table: SkipList::new(MemTableKeyComparator { comparator }, ArenaSlice::empty()),
+            range_del_table: OnceLock::new(),
+            num_entries: AtomicU64::new(0),
+            total_key_bytes: AtomicU64::new(0),
+            total_value_bytes: AtomicU64::new(0),
+            prefix_bloom,
+        }
     }
 
     pub fn approximate_memory_usage(&self) -> usize {
-        self.table.arena.memory_usage()
+        let bloom_usage = self
+            .prefix_bloom
+            .as_ref()
+            .map_or(0, |(_, bloom)| bloom.memory_usage());
+        self.table.arena.lock().unwrap().memory_usage()
+            + self
+                .range_del_table
+                .get()
+                .map_or(0, |table| table.arena.lock().unwrap().memory_usage())
+            + bloom_usage
+    }
+
+    /// Return false if no key of the memtable has the prefix of
+    /// "user_key", so that it can't be in the memtable either.
+    fn prefix_may_match(&self, user_key: &[u8]) -> bool {
+        match self.prefix_bloom.as_ref() {
+            Some((extractor, bloom)) if extractor.in_domain(user_key) => {
+                bloom.may_contain(extractor.transform(user_key))
+            }
+            _ => true,
+        }
+    }
+
+    /// The number of entries added, deletions included
+    pub fn num_entries(&self) -> u64 {
+        self.num_entries.load(AtomicOrdering::Relaxed)
+    }
+
+    /// The total size of the user keys of the entries
+    pub fn total_key_bytes(&self) -> u64 {
+        self.total_key_bytes.load(AtomicOrdering::Relaxed)
+    }
+
+    /// The total size of the values of the entries
+    pub fn total_value_bytes(&self) -> u64 {
+        self.total_value_bytes.load(AtomicOrdering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        let mut iter = SkipListIterator::new(&self.table);
+        iter.seek_to_first();
+        !iter.valid() && self.range_del_table.get().is_none()
+    }
+
+    /// Return the range tombstones of the memtable, sorted by start.
+    pub fn range_tombstones(&self) -> Vec<RangeTombstone> {
+        let mut tombstones = vec![];
+        let table = match self.range_del_table.get() {
+            Some(table) => table,
+            None => return tombstones,
+        };
+        let mut iter = MemTableIterator::new(table, None, None);
+        iter.seek_to_first();
+        while iter.valid() {
+            tombstones.extend(RangeTombstone::decode(iter.key(), iter.value()));
+            iter.next();
+        }
+        tombstones
+    }
+
+    /// Return true if the memtable holds an entry, or a range tombstone,
+    /// for user keys in [smallest, largest].
+    pub fn overlaps(&self, smallest: &[u8], largest: &[u8]) -> bool {
+        let ucmp = self.table.comparator.comparator.user_comparator();
+        let mut iter = MemTableIterator::new(&self.table, None, None);
+        iter.seek(LookupKey::new(smallest, MAX_SEQUENCE_NUMBER).internal_key());
+        if iter.valid() && ucmp.compare(extract_user_key(iter.key()), largest) != Ordering::Greater
+        {
+            return true;
+        }
+        self.range_tombstones().iter().any(|tombstone| {
+            ucmp.compare(&tombstone.start, largest) != Ordering::Greater
+                && ucmp.compare(&tombstone.end, smallest) == Ordering::Greater
+        })
+    }
+
+    /// Return the largest sequence number, up to the one "key" is looked
+    /// up at, of the range tombstones covering the user key of "key",
+    /// or zero if there are none.
+    fn max_covering_tombstone_sequence(&self, key: &LookupKey) -> u64 {
+        let ucmp = self.table.comparator.comparator.user_comparator();
+        let mut max_sequence = 0;
+        let table = match self.range_del_table.get() {
+            Some(table) => table,
+            None => return max_sequence,
+        };
+        let mut iter = MemTableIterator::new(table, None, None);
+        iter.seek_to_first();
+        while iter.valid() {
+            let tombstone = match RangeTombstone::decode(iter.key(), iter.value()) {
+                // Tombstones are sorted by start, so the rest start past
+                // the key
+                Some(tombstone)
+                    if ucmp.compare(&tombstone.start, key.user_key()) == Ordering::Greater =>
+                {
+                    break
+                }
+                Some(tombstone) => tombstone,
+                None => {
+                    iter.next();
+                    continue;
+                }
+            };
+            if tombstone.sequence <= key.sequence()
+                && tombstone.covers(ucmp.as_ref(), key.user_key())
+            {
+                max_sequence = max_sequence.max(tombstone.sequence);
+            }
+            iter.next();
+        }
+        max_sequence
     }
 
     pub fn new_iterator(&self) -> Box<dyn Iterator + '_> {
-        Box::new(MemTableIterator::new(&self.table))
+        Box::new(MemTableIterator::new(&self.table, None, None))
+    }
+
+    /// Return an iterator over the entries with user keys >= "lower_bound"
+    /// and < "upper_bound".  It never decodes an entry beyond the first
+    /// one out of the bounds, so that scans of a small range of a big
+    /// memtable cost no more than the range.
+    pub fn new_bounded_iterator(
+        &self,
+        lower_bound: Option<&[u8]>,
+        upper_bound: Option<&[u8]>,
+    ) -> Box<dyn Iterator + '_> {
+        Box::new(MemTableIterator::new(
+            &self.table,
+            lower_bound.map(<[u8]>::to_vec),
+            upper_bound.map(<[u8]>::to_vec),
+        ))
+    }
+
+    /// Like new_bounded_iterator(), but the iterator keeps the memtable
+    /// alive instead of borrowing it, so that a reader can go on with it
+    /// once the DB has switched to a new memtable.
+    ///
+    /// If "prefix_seek" is set, seek() leaves the iterator invalid when
+    /// the prefix bloom of the memtable rules out the prefix of the
+    /// target, for readers that only visit the keys with the prefix of
+    /// what they seek.  The other positioning methods are not affected.
+    pub fn new_owning_iterator(
+        self: &Arc<Self>,
+        lower_bound: Option<&[u8]>,
+        upper_bound: Option<&[u8]>,
+        prefix_seek: bool,
+    ) -> Box<dyn Iterator> {
+        let mut iter = MemTableIterator::new(
+            OwnedTable(self.clone()),
+            lower_bound.map(<[u8]>::to_vec),
+            upper_bound.map(<[u8]>::to_vec),
+        );
+        if prefix_seek && self.prefix_bloom.is_some() {
+            iter.prefix_filter = Some(self.clone());
+        }
+        Box::new(iter)
     }
 
     /// Format of an entry is concatenation of:
@@ -126,40 +439,98 @@ impl MemTable {
     ///  tag          : uint64((sequence << 8) | type)
     ///  value_size   : varint32 of value.size()
     ///  value bytes  : char[value.size()]
-    pub fn add(&mut self, seq: u64, type_: ValueType, key: &[u8], value: &[u8]) {
+    ///
+    /// A range tombstone is added with type_ RangeDeletion, the start of
+    /// the range as "key" and its end as "value".
+    pub fn add(&self, seq: u64, type_: ValueType, key: &[u8], value: &[u8]) {
+        let table = self.table_for(type_);
+        table.insert(self.new_entry(table, seq, type_, key, value));
+    }
+
+    /// Like add(), but several threads may add at once.
+    ///
+    /// # Safety
+    ///
+    /// No add() may run at the same time.
+    pub unsafe fn add_concurrently(&self, seq: u64, type_: ValueType, key: &[u8], value: &[u8]) {
+        let table = self.table_for(type_);
+        table.insert_concurrently(self.new_entry(table, seq, type_, key, value));
+    }
+
+    fn table_for(&self, type_: ValueType) -> &Table {
+        match type_ {
+            ValueType::RangeDeletion => self.range_del_table.get_or_init(|| {
+                SkipList::new(
+                    MemTableKeyComparator {
+                        comparator: self.table.comparator.comparator.clone(),
+                    },
+                    ArenaSlice::empty(),
+                )
+            }),
+            _ => &self.table,
+        }
+    }
+
+    fn new_entry(
+        &self,
+        table: &Table,
+        seq: u64,
+        type_: ValueType,
+        key: &[u8],
+        value: &[u8],
+    ) -> ArenaSlice {
+        if let Some((extractor, bloom)) = self.prefix_bloom.as_ref() {
+            if type_ != ValueType::RangeDeletion && extractor.in_domain(key) {
+                bloom.add(extractor.transform(key));
+            }
+        }
+        self.num_entries.fetch_add(1, AtomicOrdering::Relaxed);
+        self.total_key_bytes
+            .fetch_add(key.len() as u64, AtomicOrdering::Relaxed);
+        self.total_value_bytes
+            .fetch_add(value.len() as u64, AtomicOrdering::Relaxed);
         let internal_key_size = key.len() + 8;
         let encoded_len = varint_size(internal_key_size as u64)
             + internal_key_size
             + varint_size(value.len() as u64)
             + value.len();
 
-        let ptr = self.table.arena.allocate(encoded_len);
-        let mem_kv = unsafe { slice::from_raw_parts_mut(ptr, encoded_len) };
-
-        let varint_len = encode_varint32(mem_kv, internal_key_size as u32);
-        unsafe {
-            copy_nonoverlapping(key.as_ptr(), mem_kv[varint_len..].as_mut_ptr(), key.len());
-        }
-        let offset_to_tag = varint_len + key.len();
-        encode_fixed64(&mut mem_kv[offset_to_tag..], (seq << 8) | type_ as u64);
-        let offset_to_value = offset_to_tag + 8;
-        let varint_len = encode_varint32(&mut mem_kv[offset_to_value..], value.len() as u32);
-        unsafe {
-            copy_nonoverlapping(
-                value.as_ptr(),
-                mem_kv[offset_to_value + varint_len..].as_mut_ptr(),
-                value.len(),
-            );
-        }
-
-        self.table.insert(mem_kv.as_ptr());
+        table
+            .arena
+            .lock()
+            .unwrap()
+            .allocate_slice(encoded_len, |mem_kv| {
+                let varint_len = encode_varint32(mem_kv, internal_key_size as u32).unwrap();
+                mem_kv[varint_len..varint_len + key.len()].copy_from_slice(key);
+                let offset_to_tag = varint_len + key.len();
+                encode_fixed64(&mut mem_kv[offset_to_tag..], (seq << 8) | type_ as u64).unwrap();
+                let offset_to_value = offset_to_tag + 8;
+                let varint_len =
+                    encode_varint32(&mut mem_kv[offset_to_value..], value.len() as u32).unwrap();
+                mem_kv[offset_to_value + varint_len..].copy_from_slice(value);
+            })
     }
 
-    pub fn get(&self, key: &LookupKey) -> Option<Result<Vec<u8>>> {
+    /// Look "key" up.  Returns None if the memtable holds no entry for
+    /// it other than merge operands, and otherwise its newest value, or
+    /// a not found error if it is deleted.  The merge operands newer than
+    /// that are added to "state".
+    ///
+    /// state.max_covering_tombstone_sequence, that of the range
+    /// tombstones covering the key in the newer sources read before this
+    /// one, is raised to that of the tombstones of the memtable, and
+    /// hides the entries of the key below it.
+    pub fn get(&self, key: &LookupKey, state: &mut LookupState) -> Option<Result<Vec<u8>>> {
+        state.max_covering_tombstone_sequence = state
+            .max_covering_tombstone_sequence
+            .max(self.max_covering_tombstone_sequence(key));
+        if !self.prefix_may_match(key.user_key()) {
+            return None;
+        }
         let memkey = key.memtable_key();
         let mut iter = SkipListIterator::new(&self.table);
-        iter.seek(&memkey.as_ptr());
-        if iter.valid() {
+        iter.seek(memkey);
+        while iter.valid() {
             // entry format is:
             //    klength  varint32
             //    userkey  char[klength]
@@ -169,31 +540,231 @@ impl MemTable {
             // Check that it belongs to same user key.  We do not check the
             // sequence number since the Seek() call above should have skipped
             // all entries with overly large sequence numbers.
-            let entry = *iter.key();
-            let (ukey_len, ukey_offset) =
-                decode_varint32(unsafe { slice::from_raw_parts(entry, 5) }).unwrap();
-            if self.table.comparator.comparator.user_comparator().compare(
-                unsafe { slice::from_raw_parts(entry.add(ukey_offset), ukey_len as usize - 8) },
-                key.user_key(),
-            ) == Ordering::Equal
+            let entry = iter.key();
+            let (internal_key, value_offset) = decode_length_prefixed_slice(entry);
+            let (user_key, tag) = internal_key.split_at(internal_key.len() - 8);
+            if self
+                .table
+                .comparator
+                .comparator
+                .user_comparator()
+                .compare(user_key, key.user_key())
+                != Ordering::Equal
             {
-                // Correct user key
-                let tag = decode_fixed64(unsafe {
-                    slice::from_raw_parts(entry.add(ukey_offset + ukey_len as usize - 8), 8)
-                });
-                match ValueType::from(tag as u8) {
-                    ValueType::Value => {
-                        let (value, _) = decode_length_prefixed_slice_ptr(unsafe {
-                            entry.add(ukey_offset + ukey_len as usize)
-                        });
-                        return Some(Ok(value.to_vec()));
-                    }
-                    ValueType::Deletion => {
-                        return Some(Err(Error::not_found("")));
-                    }
+                break;
+            }
+            // Correct user key
+            let tag = decode_fixed64(tag);
+            if tag >> 8 < state.max_covering_tombstone_sequence {
+                return Some(Err(Error::not_found("")));
+            }
+            let (value, _) = decode_length_prefixed_slice(&entry[value_offset..]);
+            match ValueType::try_from(tag as u8) {
+                Ok(ValueType::Value) => return Some(Ok(value.to_vec())),
+                Ok(ValueType::Deletion) => return Some(Err(Error::not_found(""))),
+                Ok(ValueType::Merge) => state.merge_operands.push(value.to_vec()),
+                Ok(ValueType::RangeDeletion) => {
+                    return Some(Err(Error::corruption("range tombstone among entries")));
                 }
+                Err(error) => return Some(Err(error)),
             }
+            iter.next();
         }
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::util::BytewiseComparator;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn test_memtable_concurrent_reads() {
+        assert_send_sync::<MemTable>();
+        assert_send_sync::<SkipList<u64, MemTableKeyComparator>>();
+
+        const N: u64 = 2000;
+        let mem = MemTable::new(InternalKeyComparator::new(Arc::new(
+            BytewiseComparator::new(),
+        )));
+        let key = |i: u64| format!("key{:06}", i);
+        thread::scope(|scope| {
+            // Readers see the writes in order, and every entry they see
+            // is complete
+            for _ in 0..4 {
+                scope.spawn(|| loop {
+                    let mut iter = mem.new_iterator();
+                    iter.seek_to_first();
+                    let mut seen = 0;
+                    while iter.valid() {
+                        assert_eq!(key(seen).as_bytes(), &iter.key()[..iter.key().len() - 8]);
+                        assert_eq!(key(seen).as_bytes(), iter.value());
+                        seen += 1;
+                        iter.next();
+                    }
+                    if seen == N {
+                        break;
+                    }
+                });
+            }
+            for i in 0..N {
+                mem.add(
+                    i + 1,
+                    ValueType::Value,
+                    key(i).as_bytes(),
+                    key(i).as_bytes(),
+                );
+            }
+        });
+
+        let lookup = LookupKey::new(key(7).as_bytes(), N);
+        assert_eq!(
+            key(7).into_bytes(),
+            mem.get(&lookup, &mut LookupState::default())
+                .unwrap()
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_memtable_add_concurrently() {
+        const N: u64 = 1000;
+        let mem = MemTable::new(InternalKeyComparator::new(Arc::new(
+            BytewiseComparator::new(),
+        )));
+        thread::scope(|scope| {
+            for t in 0..4 {
+                let mem = &mem;
+                scope.spawn(move || {
+                    for i in 0..N {
+                        let seq = i * 4 + t + 1;
+                        let key = format!("key{:06}", seq % 100);
+                        // No add() runs meanwhile
+                        unsafe {
+                            mem.add_concurrently(seq, ValueType::Value, key.as_bytes(), b"v")
+                        };
+                    }
+                });
+            }
+        });
+
+        let mut iter = mem.new_iterator();
+        iter.seek_to_first();
+        let mut entries = 0;
+        while iter.valid() {
+            entries += 1;
+            iter.next();
+        }
+        assert_eq!(4 * N, entries);
+        assert_eq!(4 * N, mem.num_entries());
+        assert_eq!(4 * N * 9, mem.total_key_bytes());
+        assert_eq!(4 * N, mem.total_value_bytes());
+    }
+
+    #[test]
+    fn test_memtable_iterator_bounds() {
+        let mem = MemTable::new(InternalKeyComparator::new(Arc::new(
+            BytewiseComparator::new(),
+        )));
+        for (seq, key) in [b"a", b"b", b"c", b"d", b"e"].iter().enumerate() {
+            mem.add(seq as u64 + 1, ValueType::Value, *key, b"v");
+        }
+        // A second version of "b" is visited before the first one
+        mem.add(6, ValueType::Deletion, b"b", b"");
+
+        let user_keys = |iter: &mut Box<dyn Iterator + '_>, forward: bool| {
+            let mut keys = vec![];
+            while iter.valid() {
+                keys.push(extract_user_key(iter.key()).to_vec());
+                if forward {
+                    iter.next()
+                } else {
+                    iter.prev()
+                }
+            }
+            keys
+        };
+
+        let mut iter = mem.new_bounded_iterator(Some(b"b"), Some(b"d"));
+        iter.seek_to_first();
+        assert_eq!(
+            vec![b"b".to_vec(), b"b".to_vec(), b"c".to_vec()],
+            user_keys(&mut iter, true)
+        );
+        iter.seek_to_last();
+        assert_eq!(
+            vec![b"c".to_vec(), b"b".to_vec(), b"b".to_vec()],
+            user_keys(&mut iter, false)
+        );
+
+        // Seeking below the lower bound lands on it, and seeking at or
+        // above the upper bound finds nothing
+        let mut target = vec![];
+        ParsedInternalKey::new(b"a", MAX_SEQUENCE_NUMBER, VALUE_TYPE_FOR_SEEK)
+            .append_to(&mut target);
+        iter.seek(&target);
+        assert_eq!(b"b", extract_user_key(iter.key()));
+        target.clear();
+        ParsedInternalKey::new(b"d", MAX_SEQUENCE_NUMBER, VALUE_TYPE_FOR_SEEK)
+            .append_to(&mut target);
+        iter.seek(&target);
+        assert!(!iter.valid());
+
+        // Bounds beyond the keys of the memtable, and only one bound
+        let mut iter = mem.new_bounded_iterator(None, Some(b"z"));
+        iter.seek_to_last();
+        assert_eq!(b"e", extract_user_key(iter.key()));
+        let mut iter = mem.new_bounded_iterator(Some(b"d"), None);
+        iter.seek_to_first();
+        assert_eq!(
+            vec![b"d".to_vec(), b"e".to_vec()],
+            user_keys(&mut iter, true)
+        );
+        let mut iter = mem.new_bounded_iterator(Some(b"x"), Some(b"z"));
+        iter.seek_to_first();
+        assert!(!iter.valid());
+        iter.seek_to_last();
+        assert!(!iter.valid());
+    }
+
+    #[test]
+    fn test_memtable_prefix_bloom() {
+        let mem = MemTable::with_prefix_bloom(
+            InternalKeyComparator::new(Arc::new(BytewiseComparator::new())),
+            Arc::new(crate::util::FixedPrefixExtractor::new(4)),
+            1024,
+        );
+        mem.add(1, ValueType::Value, b"usr1.a", b"v1");
+        mem.add(2, ValueType::Value, b"usr", b"v2");
+
+        let get = |key: &[u8]| {
+            mem.get(&LookupKey::new(key, 10), &mut LookupState::default())
+                .map(Result::unwrap)
+        };
+        assert!(mem.prefix_may_match(b"usr1.b"));
+        assert!(!mem.prefix_may_match(b"usr2.a"));
+        assert_eq!(Some(b"v1".to_vec()), get(b"usr1.a"));
+        assert_eq!(None, get(b"usr1.b"));
+        assert_eq!(None, get(b"usr2.a"));
+        // Keys without a prefix are always searched for
+        assert!(mem.prefix_may_match(b"us"));
+        assert_eq!(Some(b"v2".to_vec()), get(b"usr"));
+
+        // Prefix seeks skip the memtable if the bloom rules the prefix out
+        let mut iter = mem.new_owning_iterator(None, None, true);
+        iter.seek(LookupKey::new(b"usr0.a", 10).internal_key());
+        assert!(!iter.valid());
+        iter.seek(LookupKey::new(b"usr1", 10).internal_key());
+        assert_eq!(b"usr1.a", extract_user_key(iter.key()));
+        iter.seek_to_first();
+        assert_eq!(b"usr", extract_user_key(iter.key()));
+        let mut iter = mem.new_owning_iterator(None, None, false);
+        iter.seek(LookupKey::new(b"usr0.a", 10).internal_key());
+        assert_eq!(b"usr1.a", extract_user_key(iter.key()));
+    }
+}
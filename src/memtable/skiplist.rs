@@ -1,6 +1,15 @@
 use std::{
-    cmp, mem, ptr,
-    sync::atomic::{self, AtomicPtr, AtomicUsize},
+    borrow::Borrow,
+    cell::RefCell,
+    cmp,
+    marker::PhantomData,
+    mem,
+    ops::Deref,
+    ptr,
+    sync::{
+        atomic::{self, AtomicPtr, AtomicU32, AtomicUsize},
+        Mutex,
+    },
 };
 
 use super::arena::Arena;
@@ -9,9 +18,23 @@ use crate::util::Random;
 const MAX_HEIGHT: usize = 12;
 const BRANCHING: u32 = 4;
 
-/// Used for skiplist::Node<Key>.key, Key is random type
-pub trait KeyComparator<Key> {
-    fn compare(&self, a: &Key, b: &Key) -> cmp::Ordering;
+/// Seeds the per-thread generators of concurrent inserts
+static NEXT_SEED: AtomicU32 = AtomicU32::new(0xdeadbeef);
+
+thread_local! {
+    /// Draws the heights of the nodes a thread inserts concurrently, so
+    /// that concurrent writers share no generator
+    static THREAD_RND: RefCell<Random> =
+        RefCell::new(Random::new(NEXT_SEED.fetch_add(1, atomic::Ordering::Relaxed)));
+}
+
+/// Orders the keys of a SkipList.  The list stores keys of any type that
+/// borrows as Self::Key, which is also what it is searched with, so that
+/// a list can own its keys and still be searched with borrowed ones.
+pub trait KeyComparator {
+    type Key: ?Sized;
+
+    fn compare(&self, a: &Self::Key, b: &Self::Key) -> cmp::Ordering;
 }
 
 struct Node<Key> {
@@ -20,95 +43,212 @@ struct Node<Key> {
 }
 
 impl<Key> Node<Key> {
+    /// Links beyond index 0 live in the memory allocated right after the node,
+    /// so they have to be reached through the raw pointer.
+    unsafe fn link(&self, height: usize) -> &AtomicPtr<Node<Key>> {
+        &*self.next.as_ptr().add(height)
+    }
+
     pub unsafe fn next(&self, height: usize) -> *mut Node<Key> {
-        self.next
-            .get_unchecked(height)
-            .load(atomic::Ordering::Acquire)
+        self.link(height).load(atomic::Ordering::Acquire)
+    }
+
+    pub unsafe fn set_next(&self, height: usize, next: *mut Node<Key>) {
+        self.link(height).store(next, atomic::Ordering::Release)
     }
 
-    pub unsafe fn set_next(&mut self, height: usize, next: *mut Node<Key>) {
-        self.next
-            .get_unchecked(height)
-            .store(next, atomic::Ordering::Release)
+    pub unsafe fn cas_next(
+        &self,
+        height: usize,
+        expected: *mut Node<Key>,
+        next: *mut Node<Key>,
+    ) -> bool {
+        self.link(height)
+            .compare_exchange(
+                expected,
+                next,
+                atomic::Ordering::AcqRel,
+                atomic::Ordering::Relaxed,
+            )
+            .is_ok()
     }
 
     pub unsafe fn no_barrier_next(&self, height: usize) -> *mut Node<Key> {
-        self.next
-            .get_unchecked(height)
-            .load(atomic::Ordering::Relaxed)
+        self.link(height).load(atomic::Ordering::Relaxed)
     }
 
-    pub unsafe fn no_barrier_set_next(&mut self, height: usize, next: *mut Node<Key>) {
-        self.next
-            .get_unchecked(height)
-            .store(next, atomic::Ordering::Relaxed)
+    pub unsafe fn no_barrier_set_next(&self, height: usize, next: *mut Node<Key>) {
+        self.link(height).store(next, atomic::Ordering::Relaxed)
     }
 
     pub fn key(&self) -> &Key {
         &self.key
     }
 }
-pub struct SkipList<Key, C: KeyComparator<Key>> {
+
+/// The nodes live in the arena of the list, which is freed as a whole, so
+/// keys are never dropped: they should not own anything outside the arena.
+///
+/// Thread safety
+/// -------------
+///
+/// Inserts are serialized by a lock, but reads take none: a node is
+/// fully built before a release store links it into the list, and is
+/// never changed or removed afterwards, so readers that follow the links
+/// with acquire loads only ever see complete nodes.  Any number of
+/// threads can thus read while one writes.
+///
+/// insert_concurrently() lets several threads write at once instead: it
+/// links the new node at each level with a compare-and-swap, and searches
+/// that level again when another writer got there first.  It must not
+/// run at the same time as insert().
+pub struct SkipList<Key, C: KeyComparator> {
     pub(super) comparator: C,
-    pub(super) arena: Arena,
+    pub(super) arena: Mutex<Arena>,
     head: *const Node<Key>,
     max_height: AtomicUsize,
-    rnd: Random,
+    /// Held while inserting
+    rnd: Mutex<Random>,
 }
 
-impl<Key, C: KeyComparator<Key>> SkipList<Key, C> {
-    /// requires a dummy value since there is no `Default` for raw pointers
+// SAFETY: the nodes are only reached through the list, which only hands
+// out shared references to their keys, and writes to them are serialized
+// as described above.
+unsafe impl<Key: Send + Sync, C: KeyComparator + Send> Send for SkipList<Key, C> {}
+unsafe impl<Key: Send + Sync, C: KeyComparator + Sync> Sync for SkipList<Key, C> {}
+
+impl<Key: Borrow<C::Key>, C: KeyComparator> SkipList<Key, C> {
+    /// requires a dummy key for the head node, which is never compared
     pub fn new(comparator: C, dummy: Key) -> Self {
         let mut result = Self {
             comparator,
-            arena: Arena::new(),
+            arena: Mutex::new(Arena::new()),
             head: ptr::null(),
             max_height: AtomicUsize::new(1),
-            rnd: Random::new(0xdeadbeef),
+            rnd: Mutex::new(Random::new(0xdeadbeef)),
         };
         result.head = result.new_node(dummy, MAX_HEIGHT);
         result
     }
 
-    pub fn insert(&mut self, key: Key) {
+    pub fn insert(&self, key: Key) {
+        let mut rnd = self.rnd.lock().unwrap();
         let mut prev = [ptr::null::<Node<Key>>(); MAX_HEIGHT];
-        let _ = self.find_greater_or_equal(&key, Some(&mut prev));
-        let height = self.random_height();
+        let _ = self.find_greater_or_equal(key.borrow(), Some(&mut prev));
+        let height = Self::random_height(&mut rnd);
 
         if height > self.get_max_height() {
-            for i in self.get_max_height()..height {
-                prev[i] = self.head;
+            for prev_i in prev.iter_mut().take(height).skip(self.get_max_height()) {
+                *prev_i = self.head;
             }
             self.max_height.store(height, atomic::Ordering::Release);
         }
 
         let new_node = self.new_node(key, height);
-        for i in 0..height {
+        for (i, &prev_i) in prev.iter().enumerate().take(height) {
             unsafe {
-                let prev_i = &mut *(prev[i] as *mut Node<Key>);
+                let prev_i = &*prev_i;
                 // no need to use barrier now, it happens later
-                new_node.no_barrier_set_next(i, prev_i.no_barrier_next(i));
+                (*new_node).no_barrier_set_next(i, prev_i.no_barrier_next(i));
                 prev_i.set_next(i, new_node);
             }
         }
     }
 
+    /// Insert "key" while other threads may be inserting too.  Like
+    /// insert(), REQUIRES: nothing that compares equal to key is
+    /// currently in the list.
+    ///
+    /// # Safety
+    ///
+    /// No insert() may run at the same time: it links nodes without the
+    /// compare-and-swap this relies on.
+    pub unsafe fn insert_concurrently(&self, key: Key) {
+        let height = THREAD_RND.with(|rnd| Self::random_height(&mut rnd.borrow_mut()));
+        let mut max_height = self.get_max_height();
+        while height > max_height {
+            match self.max_height.compare_exchange_weak(
+                max_height,
+                height,
+                atomic::Ordering::AcqRel,
+                atomic::Ordering::Acquire,
+            ) {
+                Ok(_) => max_height = height,
+                Err(current) => max_height = current,
+            }
+        }
+
+        let new_node = self.new_node(key, height);
+        let key = unsafe { (*new_node).key() }.borrow();
+        let mut prev = [ptr::null::<Node<Key>>(); MAX_HEIGHT];
+        let mut next = [ptr::null_mut::<Node<Key>>(); MAX_HEIGHT];
+        let mut before = self.head;
+        for level in (0..max_height).rev() {
+            (prev[level], next[level]) = self.find_splice_for_level(key, before, level);
+            before = prev[level];
+        }
+        // Link bottom up, so that the node is in the list as soon as it
+        // is at level 0
+        for level in 0..height {
+            loop {
+                unsafe {
+                    (*new_node).no_barrier_set_next(level, next[level]);
+                    if (*prev[level]).cas_next(level, next[level], new_node) {
+                        break;
+                    }
+                }
+                // Another writer linked a node after prev first
+                (prev[level], next[level]) = self.find_splice_for_level(key, prev[level], level);
+            }
+        }
+    }
+
+    /// Return the last node at "level", starting at "before", with a key
+    /// < key, and the node that follows it.  "before" must come before key.
+    fn find_splice_for_level(
+        &self,
+        key: &C::Key,
+        before: *const Node<Key>,
+        level: usize,
+    ) -> (*const Node<Key>, *mut Node<Key>) {
+        let mut current = before;
+        loop {
+            let next = unsafe { (*current).next(level) };
+            if !next.is_null()
+                && self
+                    .comparator
+                    .compare(unsafe { (*next).key() }.borrow(), key)
+                    == cmp::Ordering::Less
+            {
+                current = next;
+            } else {
+                break (current, next);
+            }
+        }
+    }
+
     fn get_max_height(&self) -> usize {
         self.max_height.load(atomic::Ordering::Acquire)
     }
 
-    fn new_node(&mut self, key: Key, height: usize) -> &mut Node<Key> {
-        let node_memory = self.arena.allocate_aligned(
+    fn new_node(&self, key: Key, height: usize) -> *mut Node<Key> {
+        let node_memory = self.arena.lock().unwrap().allocate_for::<Node<Key>>(
             mem::size_of::<Node<Key>>() + mem::size_of::<AtomicPtr<Node<Key>>>() * (height - 1),
-        ) as *mut Node<Key>;
-        let node_ref = unsafe { &mut *node_memory };
-        node_ref.key = key;
-        node_ref
+        );
+        // The memory is not a Node yet, so nothing in it must be dropped
+        unsafe {
+            ptr::write(ptr::addr_of_mut!((*node_memory).key), key);
+            let links = ptr::addr_of_mut!((*node_memory).next) as *mut AtomicPtr<Node<Key>>;
+            for i in 0..height {
+                ptr::write(links.add(i), AtomicPtr::new(ptr::null_mut()));
+            }
+        }
+        node_memory
     }
 
-    fn random_height(&mut self) -> usize {
+    fn random_height(rnd: &mut Random) -> usize {
         let mut height = 1;
-        while height < MAX_HEIGHT && self.rnd.one_in(BRANCHING) {
+        while height < MAX_HEIGHT && rnd.one_in(BRANCHING) {
             height += 1;
         }
         height
@@ -117,7 +257,7 @@ impl<Key, C: KeyComparator<Key>> SkipList<Key, C> {
     /// Return the earliest node that comes at or after the key.
     fn find_greater_or_equal(
         &self,
-        key: &Key,
+        key: &C::Key,
         mut prev: Option<&mut [*const Node<Key>]>,
     ) -> *const Node<Key> {
         let mut current = self.head;
@@ -127,7 +267,7 @@ impl<Key, C: KeyComparator<Key>> SkipList<Key, C> {
             if !next.is_null()
                 && self
                     .comparator
-                    .compare(unsafe { next.as_ref().unwrap() }.key(), key)
+                    .compare(unsafe { next.as_ref().unwrap() }.key().borrow(), key)
                     == cmp::Ordering::Less
             {
                 current = next;
@@ -145,7 +285,7 @@ impl<Key, C: KeyComparator<Key>> SkipList<Key, C> {
     }
 
     // Return the latest node with a key < key.
-    fn find_less(&self, key: &Key) -> *const Node<Key> {
+    fn find_less(&self, key: &C::Key) -> *const Node<Key> {
         let mut current = self.head;
         let mut level = self.get_max_height() - 1;
         loop {
@@ -153,7 +293,7 @@ impl<Key, C: KeyComparator<Key>> SkipList<Key, C> {
             if !next.is_null()
                 && self
                     .comparator
-                    .compare(unsafe { next.as_ref().unwrap() }.key(), key)
+                    .compare(unsafe { next.as_ref().unwrap() }.key().borrow(), key)
                     == cmp::Ordering::Less
             {
                 current = next;
@@ -186,35 +326,54 @@ impl<Key, C: KeyComparator<Key>> SkipList<Key, C> {
     }
 
     #[cfg(test)]
-    pub fn contains(&self, key: &Key) -> bool {
+    pub fn contains(&self, key: &C::Key) -> bool {
         let result = self.find_greater_or_equal(key, None);
         !result.is_null()
             && self
                 .comparator
-                .compare(unsafe { result.as_ref().unwrap() }.key(), key)
+                .compare(unsafe { result.as_ref().unwrap() }.key().borrow(), key)
                 == cmp::Ordering::Equal
     }
 }
 
-pub struct SkipListIterator<'a, Key, C: KeyComparator<Key>> {
-    list: &'a SkipList<Key, C>,
+/// Iterates over the list "L" derefs to, e.g. a borrowed list, or a
+/// handle that keeps the list alive, so that an iterator can outlive the
+/// scope it was created in without borrowing from it.
+pub struct SkipListIterator<L, Key, C: KeyComparator> {
+    list: L,
     node: *const Node<Key>,
+    _comparator: PhantomData<C>,
 }
 
-impl<'a, Key, C: KeyComparator<Key>> SkipListIterator<'a, Key, C> {
-    pub fn new(list: &'a SkipList<Key, C>) -> Self {
+impl<L, Key, C> SkipListIterator<L, Key, C>
+where
+    L: Deref<Target = SkipList<Key, C>>,
+    Key: Borrow<C::Key>,
+    C: KeyComparator,
+{
+    pub fn new(list: L) -> Self {
         Self {
             list,
             node: ptr::null(),
+            _comparator: PhantomData,
         }
     }
 
+    pub fn list(&self) -> &SkipList<Key, C> {
+        &self.list
+    }
+
+    /// Leave the iterator at no entry, as before the first seek
+    pub fn invalidate(&mut self) {
+        self.node = ptr::null();
+    }
+
     pub fn valid(&self) -> bool {
         !self.node.is_null()
     }
 
-    pub fn key(&self) -> &'a Key {
-        unsafe { self.node.as_ref().unwrap() }.key()
+    pub fn key(&self) -> &C::Key {
+        unsafe { self.node.as_ref().unwrap() }.key().borrow()
     }
 
     pub fn next(&mut self) {
@@ -224,16 +383,26 @@ impl<'a, Key, C: KeyComparator<Key>> SkipListIterator<'a, Key, C> {
     pub fn prev(&mut self) {
         self.node = self
             .list
-            .find_less(unsafe { self.node.as_ref().unwrap() }.key());
+            .find_less(unsafe { self.node.as_ref().unwrap() }.key().borrow());
         if self.node == self.list.head {
             self.node = ptr::null();
         }
     }
 
-    pub fn seek(&mut self, target: &Key) {
+    pub fn seek(&mut self, target: &C::Key) {
         self.node = self.list.find_greater_or_equal(target, None);
     }
 
+    /// Position at the last entry with a key <= target
+    pub fn seek_for_prev(&mut self, target: &C::Key) {
+        self.seek(target);
+        if !self.valid() {
+            self.seek_to_last();
+        } else if self.list.comparator.compare(self.key(), target) == cmp::Ordering::Greater {
+            self.prev();
+        }
+    }
+
     pub fn seek_to_first(&mut self) {
         self.node = unsafe { self.list.head.as_ref().unwrap().next(0) }
     }
@@ -255,7 +424,9 @@ mod tests {
 
     struct U8Comparator {}
 
-    impl KeyComparator<u64> for U8Comparator {
+    impl KeyComparator for U8Comparator {
+        type Key = u64;
+
         fn compare(&self, a: &u64, b: &u64) -> std::cmp::Ordering {
             a.cmp(b)
         }
@@ -281,7 +452,7 @@ mod tests {
         const R: u64 = 5000;
         let mut rnd = Random::new(1000);
         let mut keys = BTreeSet::new();
-        let mut list = SkipList::new(U8Comparator {}, 0);
+        let list = SkipList::new(U8Comparator {}, 0);
         for _ in 0..N {
             let key = rnd.next() as u64 % R;
             if keys.insert(key) {
@@ -328,6 +499,19 @@ mod tests {
             }
         }
 
+        // Backward seeks land on the last key <= the target
+        for i in 0..R {
+            let mut list_iter = SkipListIterator::new(&list);
+            list_iter.seek_for_prev(&i);
+            match keys.range(..=i).next_back() {
+                Some(value) => {
+                    assert!(list_iter.valid());
+                    assert_eq!(value, list_iter.key());
+                }
+                None => assert!(!list_iter.valid()),
+            }
+        }
+
         // Backward iteration test
         {
             let mut list_iter = SkipListIterator::new(&list);
@@ -342,4 +526,34 @@ mod tests {
             assert!(!list_iter.valid());
         }
     }
+
+    #[test]
+    fn test_skiplist_insert_concurrently() {
+        const THREADS: u64 = 4;
+        const N: u64 = 5000;
+        let list = SkipList::new(U8Comparator {}, 0);
+        std::thread::scope(|scope| {
+            for t in 0..THREADS {
+                let list = &list;
+                scope.spawn(move || {
+                    for i in 0..N {
+                        // No insert() runs meanwhile
+                        unsafe { list.insert_concurrently(i * THREADS + t + 1) };
+                    }
+                });
+            }
+        });
+
+        let mut iter = SkipListIterator::new(&list);
+        iter.seek_to_first();
+        for key in 1..=THREADS * N {
+            assert!(iter.valid());
+            assert_eq!(key, *iter.key());
+            iter.next();
+        }
+        assert!(!iter.valid());
+        for key in (1..=THREADS * N).step_by(97) {
+            assert!(list.contains(&key));
+        }
+    }
 }
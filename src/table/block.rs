@@ -0,0 +1,598 @@
+use std::{cmp::Ordering, mem::size_of, ops::Range, sync::Arc};
+
+use crate::{
+    iterator::{new_empty_iterator, new_error_iterator, Iterator},
+    util::{decode_fixed32, decode_varint32, extend_fixed32, extend_varint32, Comparator, Error},
+    Result,
+};
+
+/// BlockBuilder generates blocks where keys are prefix-compressed:
+///
+/// When we store a key, we drop the prefix shared with the previous
+/// string.  This helps reduce the space requirement significantly.
+/// Furthermore, once every K keys, we do not apply the prefix
+/// compression and store the entire key.  We call this a "restart
+/// point".  The tail end of the block stores the offsets of all of the
+/// restart points, and can be used to do a binary search when looking
+/// for a particular key.  Values are stored as-is (without compression)
+/// immediately following the corresponding key.
+///
+/// An entry for a particular key-value pair has the form:
+///     shared_bytes: varint32
+///     unshared_bytes: varint32
+///     value_length: varint32
+///     key_delta: char[unshared_bytes]
+///     value: char[value_length]
+/// shared_bytes == 0 for restart points.
+///
+/// The trailer of the block has the form:
+///     restarts: uint32[num_restarts]
+///     num_restarts: uint32
+/// restarts[i] contains the offset within the block of the ith restart point.
+pub struct BlockBuilder {
+    comparator: Arc<dyn Comparator>,
+    block_restart_interval: usize,
+    /// Destination buffer
+    buffer: Vec<u8>,
+    /// Restart points
+    restarts: Vec<u32>,
+    /// Number of entries emitted since restart
+    counter: usize,
+    /// Has finish() been called?
+    finished: bool,
+    last_key: Vec<u8>,
+}
+
+impl BlockBuilder {
+    /// Create a builder that emits a restart point every
+    /// "block_restart_interval" keys.
+    /// REQUIRES: block_restart_interval >= 1
+    pub fn new(comparator: Arc<dyn Comparator>, block_restart_interval: usize) -> Self {
+        assert!(block_restart_interval >= 1);
+        Self {
+            comparator,
+            block_restart_interval,
+            buffer: vec![],
+            // First restart point is at offset 0
+            restarts: vec![0],
+            counter: 0,
+            finished: false,
+            last_key: vec![],
+        }
+    }
+
+    /// Reset the contents as if the BlockBuilder was just constructed.
+    pub fn reset(&mut self) {
+        self.buffer.clear();
+        self.restarts.clear();
+        self.restarts.push(0);
+        self.counter = 0;
+        self.finished = false;
+        self.last_key.clear();
+    }
+
+    /// REQUIRES: finish() has not been called since the last call to reset().
+    /// REQUIRES: key is larger than any previously added key
+    pub fn add(&mut self, key: &[u8], value: &[u8]) {
+        assert!(!self.finished);
+        assert!(self.counter <= self.block_restart_interval);
+        assert!(
+            self.buffer.is_empty()
+                || self.comparator.compare(key, &self.last_key) == Ordering::Greater
+        );
+        let mut shared = 0;
+        if self.counter < self.block_restart_interval {
+            // See how much sharing to do with previous string
+            let min_length = self.last_key.len().min(key.len());
+            while shared < min_length && self.last_key[shared] == key[shared] {
+                shared += 1;
+            }
+        } else {
+            // Restart compression
+            self.restarts.push(self.buffer.len() as u32);
+            self.counter = 0;
+        }
+        let non_shared = key.len() - shared;
+
+        // Add "<shared><non_shared><value_size>" to buffer
+        extend_varint32(&mut self.buffer, shared as u32);
+        extend_varint32(&mut self.buffer, non_shared as u32);
+        extend_varint32(&mut self.buffer, value.len() as u32);
+
+        // Add string delta to buffer followed by value
+        self.buffer.extend_from_slice(&key[shared..]);
+        self.buffer.extend_from_slice(value);
+
+        // Update state
+        self.last_key.truncate(shared);
+        self.last_key.extend_from_slice(&key[shared..]);
+        assert_eq!(self.last_key, key);
+        self.counter += 1;
+    }
+
+    /// Finish building the block and return a slice that refers to the
+    /// block contents.  The returned slice will remain valid for the
+    /// lifetime of this builder or until reset() is called.
+    pub fn finish(&mut self) -> &[u8] {
+        // Append restart array
+        for &restart in &self.restarts {
+            extend_fixed32(&mut self.buffer, restart);
+        }
+        extend_fixed32(&mut self.buffer, self.restarts.len() as u32);
+        self.finished = true;
+        &self.buffer
+    }
+
+    /// Returns an estimate of the current (uncompressed) size of the block
+    /// we are building.
+    pub fn current_size_estimate(&self) -> usize {
+        // Raw data buffer, restart array and restart array length
+        self.buffer.len() + self.restarts.len() * size_of::<u32>() + size_of::<u32>()
+    }
+
+    /// Return true iff no entries have been added since the last reset()
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+}
+
+/// An immutable, parsed block produced by BlockBuilder.  Malformed
+/// contents are not rejected here; iterating such a block yields a
+/// corruption status instead.
+pub struct Block {
+    data: Vec<u8>,
+    /// Offset in data of restart array
+    restart_offset: usize,
+}
+
+impl Block {
+    /// Initialize the block with the specified contents.
+    pub fn new(contents: Vec<u8>) -> Self {
+        let mut result = Self {
+            data: contents,
+            restart_offset: 0,
+        };
+        if result.data.len() < size_of::<u32>() {
+            // Error marker
+            result.data.clear();
+        } else {
+            let max_restarts_allowed = (result.data.len() - size_of::<u32>()) / size_of::<u32>();
+            if result.num_restarts() as usize > max_restarts_allowed {
+                // The size is too small for num_restarts()
+                result.data.clear();
+            } else {
+                result.restart_offset =
+                    result.data.len() - (1 + result.num_restarts() as usize) * size_of::<u32>();
+            }
+        }
+        result
+    }
+
+    /// Size of the block contents in bytes, including the restart array.
+    pub fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    fn num_restarts(&self) -> u32 {
+        assert!(self.data.len() >= size_of::<u32>());
+        decode_fixed32(&self.data[self.data.len() - size_of::<u32>()..])
+    }
+
+    /// Returns an iterator over the block entries, ordered by "comparator".
+    /// "seek" binary searches the restart array before scanning linearly.
+    pub fn new_iterator(self: &Arc<Self>, comparator: Arc<dyn Comparator>) -> Box<dyn Iterator> {
+        if self.data.len() < size_of::<u32>() {
+            return new_error_iterator(Error::corruption("bad block contents"));
+        }
+        if self.num_restarts() == 0 {
+            return new_empty_iterator();
+        }
+        Box::new(BlockIterator::new(self.clone(), comparator))
+    }
+}
+
+/// Helper routine: decode the next block entry starting at "data",
+/// returning (shared, non_shared, value_length, header_length).
+/// Returns None if any errors are detected.
+fn decode_entry(data: &[u8]) -> Option<(usize, usize, usize, usize)> {
+    let (shared, n0) = decode_varint32(data)?;
+    let (non_shared, n1) = decode_varint32(&data[n0..])?;
+    let (value_length, n2) = decode_varint32(&data[n0 + n1..])?;
+    let header_length = n0 + n1 + n2;
+    if data.len() - header_length < non_shared as usize + value_length as usize {
+        return None;
+    }
+    Some((
+        shared as usize,
+        non_shared as usize,
+        value_length as usize,
+        header_length,
+    ))
+}
+
+struct BlockIterator {
+    block: Arc<Block>,
+    comparator: Arc<dyn Comparator>,
+    /// Offset in data of restart array
+    restarts: usize,
+    /// Number of uint32 entries in restart array
+    num_restarts: u32,
+    /// current is offset in data of current entry.  >= restarts if !valid
+    current: usize,
+    /// Index of restart block in which current falls
+    restart_index: u32,
+    key: Vec<u8>,
+    value: Range<usize>,
+    status: Result<()>,
+}
+
+impl BlockIterator {
+    fn new(block: Arc<Block>, comparator: Arc<dyn Comparator>) -> Self {
+        let restarts = block.restart_offset;
+        let num_restarts = block.num_restarts();
+        Self {
+            block,
+            comparator,
+            restarts,
+            num_restarts,
+            current: restarts,
+            restart_index: num_restarts,
+            key: vec![],
+            value: 0..0,
+            status: Ok(()),
+        }
+    }
+
+    fn data(&self) -> &[u8] {
+        &self.block.data
+    }
+
+    /// Return the offset in data just past the end of the current entry.
+    fn next_entry_offset(&self) -> usize {
+        self.value.end
+    }
+
+    fn get_restart_point(&self, index: u32) -> usize {
+        assert!(index < self.num_restarts);
+        let offset = self.restarts + index as usize * size_of::<u32>();
+        decode_fixed32(&self.data()[offset..]) as usize
+    }
+
+    fn seek_to_restart_point(&mut self, index: u32) {
+        self.key.clear();
+        self.restart_index = index;
+        // current will be fixed by parse_next_key();
+
+        // parse_next_key() starts at the end of value, so set value accordingly
+        let offset = self.get_restart_point(index);
+        self.value = offset..offset;
+    }
+
+    fn corruption_error(&mut self) {
+        self.current = self.restarts;
+        self.restart_index = self.num_restarts;
+        self.status = Err(Error::corruption("bad entry in block"));
+        self.key.clear();
+        self.value = 0..0;
+    }
+
+    fn parse_next_key(&mut self) -> bool {
+        self.current = self.next_entry_offset();
+        if self.current >= self.restarts {
+            // No more entries to return.  Mark as invalid.
+            self.current = self.restarts;
+            self.restart_index = self.num_restarts;
+            return false;
+        }
+
+        // Decode next entry
+        let entry = decode_entry(&self.block.data[self.current..self.restarts]);
+        match entry {
+            Some((shared, non_shared, value_length, header_length)) if self.key.len() >= shared => {
+                let key_start = self.current + header_length;
+                self.key.truncate(shared);
+                self.key
+                    .extend_from_slice(&self.block.data[key_start..key_start + non_shared]);
+                self.value = key_start + non_shared..key_start + non_shared + value_length;
+                while self.restart_index + 1 < self.num_restarts
+                    && self.get_restart_point(self.restart_index + 1) < self.current
+                {
+                    self.restart_index += 1;
+                }
+                true
+            }
+            _ => {
+                self.corruption_error();
+                false
+            }
+        }
+    }
+}
+
+impl Iterator for BlockIterator {
+    fn valid(&self) -> bool {
+        self.current < self.restarts
+    }
+
+    fn seek_to_first(&mut self) {
+        self.seek_to_restart_point(0);
+        self.parse_next_key();
+    }
+
+    fn seek_to_last(&mut self) {
+        self.seek_to_restart_point(self.num_restarts - 1);
+        while self.parse_next_key() && self.next_entry_offset() < self.restarts {
+            // Keep skipping
+        }
+    }
+
+    fn seek(&mut self, target: &[u8]) {
+        // Binary search in restart array to find the last restart point
+        // with a key < target
+        let mut left = 0;
+        let mut right = self.num_restarts - 1;
+        let mut current_key_compare = Ordering::Equal;
+
+        if self.valid() {
+            // If we're already scanning, use the current position as a starting
+            // point. This is beneficial if the key we're seeking to is ahead of the
+            // current position.
+            current_key_compare = self.comparator.compare(&self.key, target);
+            if current_key_compare == Ordering::Less {
+                // key is smaller than target
+                left = self.restart_index;
+            } else if current_key_compare == Ordering::Greater {
+                right = self.restart_index;
+            } else {
+                // We're seeking to the key we're already at.
+                return;
+            }
+        }
+
+        while left < right {
+            let mid = (left + right).div_ceil(2);
+            let region_offset = self.get_restart_point(mid);
+            let region = self.block.data.get(region_offset..self.restarts);
+            let mid_key = match region.and_then(decode_entry) {
+                Some((0, non_shared, _, header_length)) => {
+                    let key_start = region_offset + header_length;
+                    &self.block.data[key_start..key_start + non_shared]
+                }
+                _ => {
+                    self.corruption_error();
+                    return;
+                }
+            };
+            if self.comparator.compare(mid_key, target) == Ordering::Less {
+                // Key at "mid" is smaller than "target".  Therefore all
+                // blocks before "mid" are uninteresting.
+                left = mid;
+            } else {
+                // Key at "mid" is >= "target".  Therefore all blocks at or
+                // after "mid" are uninteresting.
+                right = mid - 1;
+            }
+        }
+
+        // We might be able to use our current position within the restart block.
+        // This is true if we determined the key we desire is in the current block
+        // and is after than the current key.
+        assert!(current_key_compare == Ordering::Equal || self.valid());
+        let skip_seek = left == self.restart_index && current_key_compare == Ordering::Less;
+        if !skip_seek {
+            self.seek_to_restart_point(left);
+        }
+        // Linear search (within restart block) for first key >= target
+        loop {
+            if !self.parse_next_key() {
+                return;
+            }
+            if self.comparator.compare(&self.key, target) != Ordering::Less {
+                return;
+            }
+        }
+    }
+
+    fn seek_for_prev(&mut self, target: &[u8]) {
+        self.seek(target);
+        if !self.valid() {
+            if self.status.is_ok() {
+                self.seek_to_last();
+            }
+        } else if self.comparator.compare(&self.key, target) == Ordering::Greater {
+            self.prev();
+        }
+    }
+
+    fn next(&mut self) {
+        assert!(self.valid());
+        self.parse_next_key();
+    }
+
+    fn prev(&mut self) {
+        assert!(self.valid());
+
+        // Scan backwards to a restart point before current
+        let original = self.current;
+        while self.get_restart_point(self.restart_index) >= original {
+            if self.restart_index == 0 {
+                // No more entries
+                self.current = self.restarts;
+                self.restart_index = self.num_restarts;
+                return;
+            }
+            self.restart_index -= 1;
+        }
+
+        self.seek_to_restart_point(self.restart_index);
+        // Loop until end of current entry hits the start of original entry
+        while self.parse_next_key() && self.next_entry_offset() < original {}
+    }
+
+    fn key(&self) -> &[u8] {
+        assert!(self.valid());
+        &self.key
+    }
+
+    fn value(&self) -> &[u8] {
+        assert!(self.valid());
+        &self.data()[self.value.clone()]
+    }
+
+    fn status(&self) -> Result<()> {
+        self.status.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::{BytewiseComparator, Random};
+
+    fn comparator() -> Arc<dyn Comparator> {
+        Arc::new(BytewiseComparator::new())
+    }
+
+    fn build(entries: &[(Vec<u8>, Vec<u8>)], restart_interval: usize) -> Arc<Block> {
+        let mut builder = BlockBuilder::new(comparator(), restart_interval);
+        for (key, value) in entries {
+            builder.add(key, value);
+        }
+        Arc::new(Block::new(builder.finish().to_vec()))
+    }
+
+    fn random_entries(rnd: &mut Random, n: usize) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut keys: Vec<Vec<u8>> = (0..n)
+            .map(|_| {
+                (0..rnd.skewed(4))
+                    .map(|_| b'a' + rnd.uniform(4) as u8)
+                    .collect()
+            })
+            .collect();
+        keys.sort();
+        keys.dedup();
+        keys.into_iter()
+            .map(|key| {
+                let value = (0..rnd.skewed(5)).map(|_| rnd.uniform(256) as u8).collect();
+                (key, value)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_block_empty() {
+        let mut builder = BlockBuilder::new(comparator(), 16);
+        assert!(builder.is_empty());
+        assert_eq!(2 * size_of::<u32>(), builder.current_size_estimate());
+        let block = Arc::new(Block::new(builder.finish().to_vec()));
+        assert_eq!(2 * size_of::<u32>(), block.size());
+        let mut iter = block.new_iterator(comparator());
+        iter.seek_to_first();
+        assert!(!iter.valid());
+        iter.seek_to_last();
+        assert!(!iter.valid());
+        iter.seek(b"foo");
+        assert!(!iter.valid());
+        assert!(iter.status().is_ok());
+    }
+
+    #[test]
+    fn test_block_prefix_compression() {
+        let entries = [
+            (b"apple".to_vec(), b"1".to_vec()),
+            (b"applesauce".to_vec(), b"2".to_vec()),
+            (b"application".to_vec(), b"3".to_vec()),
+        ];
+        let uncompressed = build(&entries, 1);
+        let compressed = build(&entries, 16);
+        assert!(compressed.size() < uncompressed.size());
+        assert_eq!(1, compressed.num_restarts());
+        assert_eq!(3, uncompressed.num_restarts());
+    }
+
+    #[test]
+    fn test_block_iteration_and_seek() {
+        let mut rnd = Random::new(301);
+        for restart_interval in [1, 2, 3, 16, 1000] {
+            let entries = random_entries(&mut rnd, 500);
+            let block = build(&entries, restart_interval);
+            let mut iter = block.new_iterator(comparator());
+
+            iter.seek_to_first();
+            for (key, value) in &entries {
+                assert!(iter.valid());
+                assert_eq!(key.as_slice(), iter.key());
+                assert_eq!(value.as_slice(), iter.value());
+                iter.next();
+            }
+            assert!(!iter.valid());
+
+            iter.seek_to_last();
+            for (key, value) in entries.iter().rev() {
+                assert!(iter.valid());
+                assert_eq!(key.as_slice(), iter.key());
+                assert_eq!(value.as_slice(), iter.value());
+                iter.prev();
+            }
+            assert!(!iter.valid());
+
+            // Seek to every key, then to a key just after it, in random order
+            for _ in 0..entries.len() {
+                let index = rnd.uniform(entries.len() as u32) as usize;
+                let (key, _) = &entries[index];
+                iter.seek(key);
+                assert!(iter.valid());
+                assert_eq!(key.as_slice(), iter.key());
+
+                let mut target = key.clone();
+                target.push(0);
+                iter.seek(&target);
+                match entries.get(index + 1) {
+                    Some((next, _)) => assert_eq!(next.as_slice(), iter.key()),
+                    None => assert!(!iter.valid()),
+                }
+
+                // Both land on the key itself seeking backward
+                iter.seek_for_prev(key);
+                assert_eq!(key.as_slice(), iter.key());
+                iter.seek_for_prev(&target);
+                assert_eq!(key.as_slice(), iter.key());
+            }
+            iter.seek_for_prev(&[b'z'; 100]);
+            assert_eq!(entries.last().unwrap().0.as_slice(), iter.key());
+            iter.seek_for_prev(b"A");
+            match entries.first() {
+                Some((first, _)) if first.is_empty() => assert_eq!(b"", iter.key()),
+                _ => assert!(!iter.valid()),
+            }
+            assert!(iter.status().is_ok());
+        }
+    }
+
+    #[test]
+    fn test_block_corrupted() {
+        let mut iter = Arc::new(Block::new(vec![0; 2])).new_iterator(comparator());
+        iter.seek_to_first();
+        assert!(!iter.valid());
+        assert!(iter.status().unwrap_err().is_corruption());
+
+        // num_restarts larger than the block can hold
+        let mut contents = vec![];
+        extend_fixed32(&mut contents, 100);
+        let mut iter = Arc::new(Block::new(contents)).new_iterator(comparator());
+        iter.seek_to_first();
+        assert!(iter.status().unwrap_err().is_corruption());
+
+        // Entry lengths pointing past the restart array
+        let mut contents = vec![];
+        extend_varint32(&mut contents, 0);
+        extend_varint32(&mut contents, 50);
+        extend_varint32(&mut contents, 0);
+        extend_fixed32(&mut contents, 0);
+        extend_fixed32(&mut contents, 1);
+        let mut iter = Arc::new(Block::new(contents)).new_iterator(comparator());
+        iter.seek_to_first();
+        assert!(!iter.valid());
+        assert!(iter.status().unwrap_err().is_corruption());
+    }
+}
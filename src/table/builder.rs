@@ -0,0 +1,414 @@
+use std::{borrow::Cow, cmp::Ordering, sync::Arc};
+
+use super::{
+    block::BlockBuilder,
+    filter_block::FilterBlockBuilder,
+    format::{snappy_compress, BlockHandle, Footer, BLOCK_TRAILER_SIZE},
+};
+use crate::{
+    db::{CompressionType, DBConfig},
+    dbformat::{ParsedInternalKey, RangeTombstone, INTERNAL_KEY_COMPARATOR_NAME},
+    env::WritableFile,
+    util::{
+        crc32c, crc32c_append, crc32c_mask, encode_fixed32, BytewiseComparator, Comparator,
+        FilterPolicy, RateLimiter,
+    },
+    Error, Result,
+};
+
+/// TableBuilder provides the interface used to build a Table
+/// (an immutable and sorted map from keys to values).
+pub struct TableBuilder {
+    comparator: Arc<dyn Comparator>,
+    block_size: usize,
+    block_restart_interval: usize,
+    compression: CompressionType,
+    zstd_compression_level: i32,
+    file: Box<dyn WritableFile>,
+    offset: u64,
+    status: Result<()>,
+    data_block: BlockBuilder,
+    index_block: BlockBuilder,
+    filter_policy: Option<Arc<dyn FilterPolicy>>,
+    filter_block: Option<FilterBlockBuilder>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    /// The keys are internal keys, which add() checks are well-formed
+    internal_keys: bool,
+    smallest_key: Vec<u8>,
+    last_key: Vec<u8>,
+    num_entries: u64,
+    /// Written to the range deletion block by finish()
+    range_tombstones: Vec<RangeTombstone>,
+    /// Either finish() or abandon() has been called.
+    closed: bool,
+    /// We do not emit the index entry for a block until we have seen the
+    /// first key for the next data block.  This allows us to use shorter
+    /// keys in the index block.  For example, consider a block boundary
+    /// between the keys "the quick brown fox" and "the who".  We can use
+    /// "the r" as the key for the index block entry since it is >= all
+    /// entries in the first block and < all entries in subsequent
+    /// blocks.
+    ///
+    /// Invariant: pending_index_entry is true only if data_block is empty.
+    pending_index_entry: bool,
+    /// Handle to add to index block
+    pending_handle: BlockHandle,
+}
+
+impl TableBuilder {
+    /// Create a builder that will store the contents of the table it is
+    /// building in "file".  It is up to the caller to sync and close the
+    /// file after calling finish().
+    pub fn new(config: &DBConfig, file: Box<dyn WritableFile>) -> Self {
+        let mut filter_block = config.filter_policy.clone().map(FilterBlockBuilder::new);
+        if let Some(filter_block) = filter_block.as_mut() {
+            filter_block.start_block(0);
+        }
+        Self {
+            comparator: config.comparator.clone(),
+            block_size: config.block_size,
+            block_restart_interval: config.block_restart_interval,
+            compression: config.compression,
+            zstd_compression_level: config.zstd_compression_level,
+            file,
+            offset: 0,
+            status: Ok(()),
+            data_block: BlockBuilder::new(config.comparator.clone(), config.block_restart_interval),
+            // Index blocks are searched with binary search only, so there is
+            // no point in delta encoding their keys.
+            index_block: BlockBuilder::new(config.comparator.clone(), 1),
+            filter_policy: config.filter_policy.clone(),
+            filter_block,
+            rate_limiter: config.rate_limiter.clone(),
+            internal_keys: config.comparator.name() == INTERNAL_KEY_COMPARATOR_NAME,
+            smallest_key: vec![],
+            last_key: vec![],
+            num_entries: 0,
+            range_tombstones: vec![],
+            closed: false,
+            pending_index_entry: false,
+            pending_handle: BlockHandle::default(),
+        }
+    }
+
+    /// Add key,value to the table being constructed.  Fails with a
+    /// corruption error, which sticks to the builder, if key is not
+    /// after any previously added key according to comparator, or is not
+    /// a valid internal key in a table of internal keys.
+    /// REQUIRES: finish(), abandon() have not been called
+    pub fn add(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        assert!(!self.closed);
+        self.status.clone()?;
+        if self.internal_keys && ParsedInternalKey::parse(key).is_none() {
+            let error = Error::corruption("malformed internal key added to table");
+            return self.save_status(Err(error));
+        }
+        if self.num_entries > 0 && self.comparator.compare(key, &self.last_key) != Ordering::Greater
+        {
+            let error = Error::corruption("keys added to table out of order");
+            return self.save_status(Err(error));
+        }
+
+        if self.pending_index_entry {
+            assert!(self.data_block.is_empty());
+            let separator = self.comparator.find_shortest_separator(&self.last_key, key);
+            let mut handle_encoding = vec![];
+            self.pending_handle.encode_to(&mut handle_encoding);
+            self.index_block.add(&separator, &handle_encoding);
+            self.pending_index_entry = false;
+        }
+
+        if let Some(filter_block) = self.filter_block.as_mut() {
+            filter_block.add_key(key);
+        }
+
+        if self.num_entries == 0 {
+            self.smallest_key = key.to_vec();
+        }
+        self.last_key.clear();
+        self.last_key.extend_from_slice(key);
+        self.num_entries += 1;
+        self.data_block.add(key, value);
+
+        let estimated_block_size = self.data_block.current_size_estimate();
+        if estimated_block_size >= self.block_size {
+            self.flush();
+        }
+        self.status()
+    }
+
+    /// Add a range tombstone to the table, in any order with respect to
+    /// the other tombstones and the keys.  Range tombstones are stored
+    /// in a meta block of their own, and are not counted by
+    /// num_entries().
+    /// REQUIRES: the table holds internal keys; finish(), abandon() have
+    /// not been called
+    pub fn add_range_tombstone(&mut self, tombstone: RangeTombstone) {
+        assert!(!self.closed);
+        self.range_tombstones.push(tombstone);
+    }
+
+    /// Advanced operation: flush any buffered key/value pairs to file.
+    /// Can be used to ensure that two adjacent entries never live in
+    /// the same data block.  Most clients should not need to use this method.
+    /// REQUIRES: finish(), abandon() have not been called
+    pub fn flush(&mut self) {
+        assert!(!self.closed);
+        if self.status.is_err() || self.data_block.is_empty() {
+            return;
+        }
+        assert!(!self.pending_index_entry);
+        let (contents, type_) = compress_block(
+            self.data_block.finish(),
+            self.compression,
+            self.zstd_compression_level,
+        );
+        if let Some(rate_limiter) = self.rate_limiter.as_ref() {
+            rate_limiter.request(contents.len() + BLOCK_TRAILER_SIZE);
+        }
+        let result = write_raw_block(self.file.as_mut(), &mut self.offset, &contents, type_);
+        self.data_block.reset();
+        match result {
+            Ok(handle) => {
+                self.pending_handle = handle;
+                self.pending_index_entry = true;
+                self.status = self.file.flush();
+                if let Some(filter_block) = self.filter_block.as_mut() {
+                    filter_block.start_block(self.offset);
+                }
+            }
+            Err(error) => self.status = Err(error),
+        }
+    }
+
+    /// Return non-ok iff some error has been detected.
+    pub fn status(&self) -> Result<()> {
+        self.status.clone()
+    }
+
+    /// Finish building the table.  Stops using the file passed to the
+    /// constructor after this function returns.
+    /// REQUIRES: finish(), abandon() have not been called
+    pub fn finish(&mut self) -> Result<()> {
+        self.flush();
+        assert!(!self.closed);
+        self.closed = true;
+
+        self.status.clone()?;
+
+        // Write filter block
+        let mut filter_block_handle = None;
+        if let Some(filter_block) = self.filter_block.as_mut() {
+            let result = write_raw_block(
+                self.file.as_mut(),
+                &mut self.offset,
+                filter_block.finish(),
+                CompressionType::None,
+            );
+            filter_block_handle = Some(self.save_status(result)?);
+        }
+
+        // Write range deletion block
+        let mut range_del_block_handle = None;
+        if !self.range_tombstones.is_empty() {
+            let mut entries: Vec<_> = self
+                .range_tombstones
+                .iter()
+                .map(|tombstone| (tombstone.internal_key(), &tombstone.end))
+                .collect();
+            entries.sort_by(|a, b| self.comparator.compare(a.0.encode(), b.0.encode()));
+            let mut block = BlockBuilder::new(self.comparator.clone(), self.block_restart_interval);
+            for (key, end) in entries {
+                block.add(key.encode(), end);
+            }
+            let result = write_block(
+                self.file.as_mut(),
+                &mut self.offset,
+                block.finish(),
+                self.compression,
+                self.zstd_compression_level,
+            );
+            range_del_block_handle = Some(self.save_status(result)?);
+        }
+
+        // Write metaindex block, whose keys are ordered bytewise whatever
+        // the comparator of the table
+        let mut metaindex_block = BlockBuilder::new(
+            Arc::new(BytewiseComparator::new()),
+            self.block_restart_interval,
+        );
+        if let (Some(policy), Some(handle)) = (&self.filter_policy, filter_block_handle) {
+            // Add mapping from "filter.Name" to location of filter data
+            let key = filter_block_key(policy.as_ref());
+            let mut handle_encoding = vec![];
+            handle.encode_to(&mut handle_encoding);
+            metaindex_block.add(&key, &handle_encoding);
+        }
+        if let Some(handle) = range_del_block_handle {
+            let mut handle_encoding = vec![];
+            handle.encode_to(&mut handle_encoding);
+            metaindex_block.add(RANGE_DEL_BLOCK_KEY, &handle_encoding);
+        }
+        let result = write_block(
+            self.file.as_mut(),
+            &mut self.offset,
+            metaindex_block.finish(),
+            self.compression,
+            self.zstd_compression_level,
+        );
+        let metaindex_handle = self.save_status(result)?;
+
+        // Write index block
+        if self.pending_index_entry {
+            let successor = self.comparator.find_short_successor(&self.last_key);
+            let mut handle_encoding = vec![];
+            self.pending_handle.encode_to(&mut handle_encoding);
+            self.index_block.add(&successor, &handle_encoding);
+            self.pending_index_entry = false;
+        }
+        let result = write_block(
+            self.file.as_mut(),
+            &mut self.offset,
+            self.index_block.finish(),
+            self.compression,
+            self.zstd_compression_level,
+        );
+        let index_handle = self.save_status(result)?;
+
+        // Write footer
+        let mut footer_encoding = vec![];
+        Footer::new(metaindex_handle, index_handle).encode_to(&mut footer_encoding);
+        self.status = self.file.append(&footer_encoding);
+        if self.status.is_ok() {
+            self.offset += footer_encoding.len() as u64;
+        }
+        self.status()
+    }
+
+    /// Indicate that the contents of this builder should be abandoned.  Stops
+    /// using the file passed to the constructor after this function returns.
+    /// If the caller is not going to call finish(), it must call abandon()
+    /// before destroying this builder.
+    /// REQUIRES: finish(), abandon() have not been called
+    pub fn abandon(&mut self) {
+        assert!(!self.closed);
+        self.closed = true;
+    }
+
+    /// Number of calls to add() so far.
+    pub fn num_entries(&self) -> u64 {
+        self.num_entries
+    }
+
+    /// The first key added, if any.
+    pub fn smallest_key(&self) -> Option<&[u8]> {
+        (self.num_entries > 0).then_some(self.smallest_key.as_slice())
+    }
+
+    /// The last key added, if any.
+    pub fn largest_key(&self) -> Option<&[u8]> {
+        (self.num_entries > 0).then_some(self.last_key.as_slice())
+    }
+
+    /// Size of the file generated so far.  If invoked after a successful
+    /// finish() call, returns the size of the final generated file.
+    pub fn file_size(&self) -> u64 {
+        self.offset
+    }
+
+    /// The file the table is being written to, so that the caller can
+    /// sync and close it once the table is finished.
+    pub fn file(&mut self) -> &mut dyn WritableFile {
+        self.file.as_mut()
+    }
+
+    fn save_status<T>(&mut self, result: Result<T>) -> Result<T> {
+        if let Err(error) = &result {
+            self.status = Err(error.clone());
+        }
+        result
+    }
+}
+
+/// Key of the metaindex entry that points at the range deletion block.
+/// Its entries are the range tombstones of the table, keyed by the
+/// internal key of their start and holding their end.
+pub const RANGE_DEL_BLOCK_KEY: &[u8] = b"rebeldb.range_del";
+
+/// Key of the metaindex entry that points at the filter block built
+/// with "policy".
+pub fn filter_block_key(policy: &dyn FilterPolicy) -> Vec<u8> {
+    let mut key = b"filter.".to_vec();
+    key.extend_from_slice(policy.name().as_bytes());
+    key
+}
+
+/// Compress "raw" with "compression".  Returns the block contents to
+/// store and how they are compressed.
+fn compress_block(
+    raw: &[u8],
+    compression: CompressionType,
+    zstd_compression_level: i32,
+) -> (Cow<'_, [u8]>, CompressionType) {
+    let compressed = match compression {
+        CompressionType::None => None,
+        CompressionType::Snappy => snappy_compress(raw),
+        CompressionType::Zstd => zstd_compress(raw, zstd_compression_level),
+    };
+    match compressed {
+        // Store uncompressed form if compression is unsupported
+        // or compressed less than 12.5%
+        Some(compressed) if compressed.len() < raw.len() - raw.len() / 8 => {
+            (Cow::Owned(compressed), compression)
+        }
+        _ => (Cow::Borrowed(raw), CompressionType::None),
+    }
+}
+
+/// Compress "raw" into a single zstd frame without a dictionary.
+#[cfg(feature = "zstd")]
+fn zstd_compress(raw: &[u8], _level: i32) -> Option<Vec<u8>> {
+    use ruzstd::encoding::{compress, CompressionLevel};
+
+    // The encoder only implements its fastest level so far
+    let mut compressed = Vec::with_capacity(raw.len() / 2);
+    compress(raw, &mut compressed, CompressionLevel::Fastest);
+    Some(compressed)
+}
+
+#[cfg(not(feature = "zstd"))]
+fn zstd_compress(_raw: &[u8], _level: i32) -> Option<Vec<u8>> {
+    None
+}
+
+fn write_block(
+    file: &mut dyn WritableFile,
+    offset: &mut u64,
+    raw: &[u8],
+    compression: CompressionType,
+    zstd_compression_level: i32,
+) -> Result<BlockHandle> {
+    let (contents, type_) = compress_block(raw, compression, zstd_compression_level);
+    write_raw_block(file, offset, &contents, type_)
+}
+
+/// File format contains a sequence of blocks where each block has:
+///    block_data: uint8[n]
+///    type: uint8
+///    crc: uint32
+fn write_raw_block(
+    file: &mut dyn WritableFile,
+    offset: &mut u64,
+    contents: &[u8],
+    type_: CompressionType,
+) -> Result<BlockHandle> {
+    let handle = BlockHandle::new(*offset, contents.len() as u64);
+    let mut trailer = [0; BLOCK_TRAILER_SIZE];
+    trailer[0] = type_ as u8;
+    let crc = crc32c_append(crc32c(contents), &trailer[..1]);
+    // Extend crc to cover block type
+    encode_fixed32(&mut trailer[1..], crc32c_mask(crc))?;
+    file.append_slices(&[contents, &trailer])?;
+    *offset += (contents.len() + BLOCK_TRAILER_SIZE) as u64;
+    Ok(handle)
+}
@@ -0,0 +1,276 @@
+use std::{mem::size_of, sync::Arc};
+
+use crate::util::{decode_fixed32, extend_fixed32, FilterPolicy};
+
+/// Generate new filter every 2KB of data
+const FILTER_BASE_LG: u8 = 11;
+const FILTER_BASE: u64 = 1 << FILTER_BASE_LG;
+
+/// A FilterBlockBuilder is used to construct all of the filters for a
+/// particular Table.  It generates a single string which is stored as
+/// a special block in the Table.
+///
+/// The sequence of calls to FilterBlockBuilder must match the regexp:
+///     (start_block add_key*)* finish
+pub struct FilterBlockBuilder {
+    policy: Arc<dyn FilterPolicy>,
+    /// Flattened key contents
+    keys: Vec<u8>,
+    /// Starting index in keys of each key
+    start: Vec<usize>,
+    /// Filter data computed so far
+    result: Vec<u8>,
+    filter_offsets: Vec<u32>,
+}
+
+impl FilterBlockBuilder {
+    pub fn new(policy: Arc<dyn FilterPolicy>) -> Self {
+        Self {
+            policy,
+            keys: vec![],
+            start: vec![],
+            result: vec![],
+            filter_offsets: vec![],
+        }
+    }
+
+    /// Called whenever a new data block starts at "block_offset".
+    pub fn start_block(&mut self, block_offset: u64) {
+        let filter_index = block_offset / FILTER_BASE;
+        assert!(filter_index >= self.filter_offsets.len() as u64);
+        while filter_index > self.filter_offsets.len() as u64 {
+            self.generate_filter();
+        }
+    }
+
+    pub fn add_key(&mut self, key: &[u8]) {
+        self.start.push(self.keys.len());
+        self.keys.extend_from_slice(key);
+    }
+
+    /// Finish building the filter block and return its contents.
+    pub fn finish(&mut self) -> &[u8] {
+        if !self.start.is_empty() {
+            self.generate_filter();
+        }
+
+        // Append array of per-filter offsets
+        let array_offset = self.result.len() as u32;
+        for &offset in &self.filter_offsets {
+            extend_fixed32(&mut self.result, offset);
+        }
+
+        extend_fixed32(&mut self.result, array_offset);
+        // Save encoding parameter in result
+        self.result.push(FILTER_BASE_LG);
+        &self.result
+    }
+
+    fn generate_filter(&mut self) {
+        self.filter_offsets.push(self.result.len() as u32);
+        if self.start.is_empty() {
+            // Fast path if there are no keys for this filter
+            return;
+        }
+
+        // Make list of keys from flattened key structure
+        // Simplify length computation
+        self.start.push(self.keys.len());
+        let keys: Vec<&[u8]> = self
+            .start
+            .windows(2)
+            .map(|range| &self.keys[range[0]..range[1]])
+            .collect();
+
+        // Generate filter for current set of keys and append to result.
+        self.policy.create_filter(&keys, &mut self.result);
+
+        self.keys.clear();
+        self.start.clear();
+    }
+}
+
+pub struct FilterBlockReader {
+    policy: Arc<dyn FilterPolicy>,
+    /// Filter data (the whole filter block)
+    data: Vec<u8>,
+    /// Offset in data of the beginning of the offset array
+    offset: usize,
+    /// Number of entries in offset array
+    num: usize,
+    /// Encoding parameter (see FILTER_BASE_LG)
+    base_lg: u8,
+}
+
+impl FilterBlockReader {
+    /// Parse a filter block produced by FilterBlockBuilder.  Malformed
+    /// contents yield a reader that treats every key as a potential match.
+    pub fn new(policy: Arc<dyn FilterPolicy>, contents: Vec<u8>) -> Self {
+        let mut result = Self {
+            policy,
+            data: vec![],
+            offset: 0,
+            num: 0,
+            base_lg: 0,
+        };
+        let n = contents.len();
+        // 1 byte for base_lg and 4 for start of offset array
+        if n < size_of::<u32>() + 1 {
+            return result;
+        }
+        let last_word = decode_fixed32(&contents[n - 5..]) as usize;
+        if last_word > n - 5 {
+            return result;
+        }
+        result.base_lg = contents[n - 1];
+        result.offset = last_word;
+        result.num = (n - 5 - last_word) / size_of::<u32>();
+        result.data = contents;
+        result
+    }
+
+    pub fn key_may_match(&self, block_offset: u64, key: &[u8]) -> bool {
+        let index = block_offset
+            .checked_shr(self.base_lg as u32)
+            .unwrap_or_default() as usize;
+        if index < self.num {
+            let entry = self.offset + index * size_of::<u32>();
+            let start = decode_fixed32(&self.data[entry..]) as usize;
+            let limit = decode_fixed32(&self.data[entry + size_of::<u32>()..]) as usize;
+            if start <= limit && limit <= self.offset {
+                let filter = &self.data[start..limit];
+                return self.policy.key_may_match(key, filter);
+            } else if start == limit {
+                // Empty filters do not match any keys
+                return false;
+            }
+        }
+        // Errors are treated as potential matches
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::{hash, Random};
+
+    /// For testing: emit an array with one hash value per key
+    struct TestHashFilter {}
+
+    impl FilterPolicy for TestHashFilter {
+        fn name(&self) -> &str {
+            "TestHashFilter"
+        }
+
+        fn create_filter(&self, keys: &[&[u8]], dst: &mut Vec<u8>) {
+            for key in keys {
+                extend_fixed32(dst, hash(key, 1));
+            }
+        }
+
+        fn key_may_match(&self, key: &[u8], filter: &[u8]) -> bool {
+            let h = hash(key, 1);
+            filter
+                .chunks_exact(size_of::<u32>())
+                .any(|chunk| decode_fixed32(chunk) == h)
+        }
+    }
+
+    fn policy() -> Arc<dyn FilterPolicy> {
+        Arc::new(TestHashFilter {})
+    }
+
+    #[test]
+    fn test_filter_block_empty_builder() {
+        let mut builder = FilterBlockBuilder::new(policy());
+        let block = builder.finish().to_vec();
+        assert_eq!(vec![0, 0, 0, 0, FILTER_BASE_LG], block);
+        let reader = FilterBlockReader::new(policy(), block);
+        assert!(reader.key_may_match(0, b"foo"));
+        assert!(reader.key_may_match(100000, b"foo"));
+    }
+
+    #[test]
+    fn test_filter_block_single_chunk() {
+        let mut builder = FilterBlockBuilder::new(policy());
+        builder.start_block(100);
+        builder.add_key(b"foo");
+        builder.add_key(b"bar");
+        builder.add_key(b"box");
+        builder.start_block(200);
+        builder.add_key(b"box");
+        builder.start_block(300);
+        builder.add_key(b"hello");
+        let block = builder.finish().to_vec();
+        let reader = FilterBlockReader::new(policy(), block);
+        assert!(reader.key_may_match(100, b"foo"));
+        assert!(reader.key_may_match(100, b"bar"));
+        assert!(reader.key_may_match(100, b"box"));
+        assert!(reader.key_may_match(100, b"hello"));
+        assert!(reader.key_may_match(100, b"foo"));
+        assert!(!reader.key_may_match(100, b"missing"));
+        assert!(!reader.key_may_match(100, b"other"));
+    }
+
+    #[test]
+    fn test_filter_block_multi_chunk() {
+        let mut builder = FilterBlockBuilder::new(policy());
+
+        // First filter
+        builder.start_block(0);
+        builder.add_key(b"foo");
+        builder.start_block(2000);
+        builder.add_key(b"bar");
+
+        // Second filter
+        builder.start_block(3100);
+        builder.add_key(b"box");
+
+        // Third filter is empty
+
+        // Last filter
+        builder.start_block(9000);
+        builder.add_key(b"box");
+        builder.add_key(b"hello");
+
+        let block = builder.finish().to_vec();
+        let reader = FilterBlockReader::new(policy(), block);
+
+        // Check first filter
+        assert!(reader.key_may_match(0, b"foo"));
+        assert!(reader.key_may_match(2000, b"bar"));
+        assert!(!reader.key_may_match(0, b"box"));
+        assert!(!reader.key_may_match(0, b"hello"));
+
+        // Check second filter
+        assert!(reader.key_may_match(3100, b"box"));
+        assert!(!reader.key_may_match(3100, b"foo"));
+        assert!(!reader.key_may_match(3100, b"bar"));
+        assert!(!reader.key_may_match(3100, b"hello"));
+
+        // Check third filter (empty)
+        assert!(!reader.key_may_match(4100, b"foo"));
+        assert!(!reader.key_may_match(4100, b"bar"));
+        assert!(!reader.key_may_match(4100, b"box"));
+        assert!(!reader.key_may_match(4100, b"hello"));
+
+        // Check last filter
+        assert!(reader.key_may_match(9000, b"box"));
+        assert!(reader.key_may_match(9000, b"hello"));
+        assert!(!reader.key_may_match(9000, b"foo"));
+        assert!(!reader.key_may_match(9000, b"bar"));
+    }
+
+    #[test]
+    fn test_filter_block_malformed() {
+        let mut rnd = Random::new(301);
+        for len in 0..16 {
+            let contents: Vec<u8> = (0..len).map(|_| rnd.uniform(256) as u8).collect();
+            let reader = FilterBlockReader::new(policy(), contents);
+            // Must not panic; errors are treated as potential matches
+            reader.key_may_match(0, b"foo");
+            reader.key_may_match(1 << 20, b"foo");
+        }
+    }
+}
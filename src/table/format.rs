@@ -0,0 +1,232 @@
+use crate::{
+    db::{CompressionType, ReadConfig},
+    env::RandomAccessFile,
+    util::{
+        crc32c, crc32c_unmask, decode_fixed32, decode_fixed64, decode_varint64, extend_fixed32,
+        extend_varint64, Error, Result,
+    },
+};
+
+/// Maximum encoding length of a BlockHandle
+pub const BLOCK_HANDLE_MAX_ENCODED_LENGTH: usize = 10 + 10;
+
+/// Encoded length of a Footer.  Note that the serialization of a
+/// Footer will always occupy exactly this many bytes.  It consists
+/// of two block handles and a magic number.
+pub const FOOTER_ENCODED_LENGTH: usize = 2 * BLOCK_HANDLE_MAX_ENCODED_LENGTH + 8;
+
+/// TABLE_MAGIC_NUMBER was picked by running
+///    echo http://code.google.com/p/leveldb/ | sha1sum
+/// and taking the leading 64 bits.
+pub const TABLE_MAGIC_NUMBER: u64 = 0xdb4775248b80fb57;
+
+/// 1-byte type + 32-bit crc
+pub const BLOCK_TRAILER_SIZE: usize = 5;
+
+/// BlockHandle is a pointer to the extent of a file that stores a data
+/// block or a meta block.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct BlockHandle {
+    offset: u64,
+    size: u64,
+}
+
+impl BlockHandle {
+    pub fn new(offset: u64, size: u64) -> Self {
+        Self { offset, size }
+    }
+
+    /// The offset of the block in the file.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// The size of the stored block
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn encode_to(&self, dst: &mut Vec<u8>) {
+        extend_varint64(dst, self.offset);
+        extend_varint64(dst, self.size);
+    }
+
+    /// Return the handle and the number of bytes consumed from "input".
+    pub fn decode_from(input: &[u8]) -> Result<(Self, usize)> {
+        if let Some((offset, offset_len)) = decode_varint64(input) {
+            if let Some((size, size_len)) = decode_varint64(&input[offset_len..]) {
+                return Ok((Self { offset, size }, offset_len + size_len));
+            }
+        }
+        Err(Error::corruption("bad block handle"))
+    }
+}
+
+/// Footer encapsulates the fixed information stored at the tail
+/// end of every table file.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Footer {
+    metaindex_handle: BlockHandle,
+    index_handle: BlockHandle,
+}
+
+impl Footer {
+    pub fn new(metaindex_handle: BlockHandle, index_handle: BlockHandle) -> Self {
+        Self {
+            metaindex_handle,
+            index_handle,
+        }
+    }
+
+    /// The block handle for the metaindex block of the table
+    pub fn metaindex_handle(&self) -> &BlockHandle {
+        &self.metaindex_handle
+    }
+
+    /// The block handle for the index block of the table
+    pub fn index_handle(&self) -> &BlockHandle {
+        &self.index_handle
+    }
+
+    pub fn encode_to(&self, dst: &mut Vec<u8>) {
+        let original_size = dst.len();
+        self.metaindex_handle.encode_to(dst);
+        self.index_handle.encode_to(dst);
+        // Padding
+        dst.resize(original_size + 2 * BLOCK_HANDLE_MAX_ENCODED_LENGTH, 0);
+        extend_fixed32(dst, (TABLE_MAGIC_NUMBER & 0xffffffff) as u32);
+        extend_fixed32(dst, (TABLE_MAGIC_NUMBER >> 32) as u32);
+        assert_eq!(dst.len(), original_size + FOOTER_ENCODED_LENGTH);
+    }
+
+    pub fn decode_from(input: &[u8]) -> Result<Self> {
+        if input.len() < FOOTER_ENCODED_LENGTH {
+            return Err(Error::corruption("footer is too short"));
+        }
+        let magic = decode_fixed64(&input[FOOTER_ENCODED_LENGTH - 8..]);
+        if magic != TABLE_MAGIC_NUMBER {
+            return Err(Error::corruption("not an sstable (bad magic number)"));
+        }
+        let (metaindex_handle, len) = BlockHandle::decode_from(input)?;
+        let (index_handle, _) = BlockHandle::decode_from(&input[len..])?;
+        Ok(Self {
+            metaindex_handle,
+            index_handle,
+        })
+    }
+}
+
+/// Read the block identified by "handle" from "file".  On success
+/// return the block contents with the trailer stripped, uncompressed.
+pub fn read_block(
+    file: &dyn RandomAccessFile,
+    config: &ReadConfig,
+    handle: &BlockHandle,
+) -> Result<Vec<u8>> {
+    // Read the block contents as well as the type/crc footer.
+    // See table/builder.rs for the code that built this structure.
+    let n = handle.size() as usize;
+    let mut buf = vec![0; n + BLOCK_TRAILER_SIZE];
+    file.read(handle.offset() as usize, &mut buf)?;
+
+    // Check the crc of the type and the block contents
+    if config.verify_checksums {
+        let crc = crc32c_unmask(decode_fixed32(&buf[n + 1..]));
+        let actual = crc32c(&buf[..n + 1]);
+        if actual != crc {
+            return Err(Error::corruption("block checksum mismatch"));
+        }
+    }
+
+    match CompressionType::try_from(buf[n]) {
+        Ok(CompressionType::None) => {
+            buf.truncate(n);
+            Ok(buf)
+        }
+        Ok(CompressionType::Snappy) => snappy_uncompress(&buf[..n]),
+        Ok(CompressionType::Zstd) => zstd_uncompress(&buf[..n]),
+        Err(_) => Err(Error::corruption("bad block type")),
+    }
+}
+
+/// Compress "raw" with snappy, or return None if the snappy feature is
+/// not enabled.
+#[cfg(feature = "snappy")]
+pub(crate) fn snappy_compress(raw: &[u8]) -> Option<Vec<u8>> {
+    snap::raw::Encoder::new().compress_vec(raw).ok()
+}
+
+#[cfg(not(feature = "snappy"))]
+pub(crate) fn snappy_compress(_raw: &[u8]) -> Option<Vec<u8>> {
+    None
+}
+
+#[cfg(feature = "snappy")]
+pub(crate) fn snappy_uncompress(contents: &[u8]) -> Result<Vec<u8>> {
+    snap::raw::Decoder::new()
+        .decompress_vec(contents)
+        .map_err(|_| Error::corruption("corrupted compressed block contents"))
+}
+
+#[cfg(not(feature = "snappy"))]
+pub(crate) fn snappy_uncompress(_contents: &[u8]) -> Result<Vec<u8>> {
+    Err(Error::not_supported(
+        "snappy compressed block, but the snappy feature is not enabled",
+    ))
+}
+
+#[cfg(feature = "zstd")]
+fn zstd_uncompress(contents: &[u8]) -> Result<Vec<u8>> {
+    use std::io::Read;
+
+    use ruzstd::decoding::StreamingDecoder;
+
+    let mut uncompressed = vec![];
+    match StreamingDecoder::new(contents).map(|mut decoder| decoder.read_to_end(&mut uncompressed))
+    {
+        Ok(Ok(_)) => Ok(uncompressed),
+        _ => Err(Error::corruption(
+            "corrupted zstd compressed block contents",
+        )),
+    }
+}
+
+#[cfg(not(feature = "zstd"))]
+fn zstd_uncompress(_contents: &[u8]) -> Result<Vec<u8>> {
+    Err(Error::not_supported(
+        "zstd compressed block, but the zstd feature is not enabled",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_block_handle_encode_decode() {
+        for (offset, size) in [(0, 0), (1, 1), (100, 4096), (u64::MAX, u64::MAX)] {
+            let handle = BlockHandle::new(offset, size);
+            let mut encoded = vec![];
+            handle.encode_to(&mut encoded);
+            assert!(encoded.len() <= BLOCK_HANDLE_MAX_ENCODED_LENGTH);
+            let (decoded, len) = BlockHandle::decode_from(&encoded).unwrap();
+            assert_eq!(handle, decoded);
+            assert_eq!(encoded.len(), len);
+            assert!(BlockHandle::decode_from(&encoded[..len - 1]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_format_footer_encode_decode() {
+        let footer = Footer::new(BlockHandle::new(1000, 200), BlockHandle::new(1205, 80));
+        let mut encoded = vec![];
+        footer.encode_to(&mut encoded);
+        assert_eq!(FOOTER_ENCODED_LENGTH, encoded.len());
+        assert_eq!(footer, Footer::decode_from(&encoded).unwrap());
+
+        let last = encoded.len() - 1;
+        encoded[last] ^= 0xff;
+        assert!(Footer::decode_from(&encoded).unwrap_err().is_corruption());
+        assert!(Footer::decode_from(&encoded[..last]).is_err());
+    }
+}
@@ -0,0 +1,665 @@
+pub(crate) mod block;
+mod builder;
+mod filter_block;
+pub(crate) mod format;
+mod reader;
+mod table_cache;
+
+pub use block::Block;
+pub use builder::TableBuilder;
+pub use reader::Table;
+pub use table_cache::TableCache;
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        mem,
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+    };
+
+    use super::*;
+    use crate::{
+        db::{CompressionType, DBConfig, ReadConfig},
+        dbformat::{InternalKey, InternalKeyComparator, RangeTombstone, ValueType},
+        env::{RandomAccessFile, WritableFile},
+        util::{BytewiseComparator, Cache, Error, Random, Result, ShardedLRUCache},
+    };
+
+    struct StringSink {
+        contents: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl WritableFile for StringSink {
+        fn append(&mut self, data: &[u8]) -> Result<()> {
+            self.contents.lock().unwrap().extend_from_slice(data);
+            Ok(())
+        }
+
+        fn close(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        fn sync(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    struct StringSource {
+        contents: Vec<u8>,
+        reads: Arc<AtomicUsize>,
+    }
+
+    impl StringSource {
+        fn new(contents: Vec<u8>) -> Self {
+            Self {
+                contents,
+                reads: Arc::new(AtomicUsize::new(0)),
+            }
+        }
+    }
+
+    impl RandomAccessFile for StringSource {
+        fn read(&self, offset: usize, dst: &mut [u8]) -> Result<()> {
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            if offset + dst.len() > self.contents.len() {
+                return Err(Error::invalid_argument("invalid Read offset"));
+            }
+            dst.copy_from_slice(&self.contents[offset..offset + dst.len()]);
+            Ok(())
+        }
+    }
+
+    struct TableConstructor {
+        data: BTreeMap<Vec<u8>, Vec<u8>>,
+        config: DBConfig,
+        table: Option<Arc<Table>>,
+        reads: Arc<AtomicUsize>,
+        block_cache: Option<Arc<dyn Cache<block::Block>>>,
+    }
+
+    impl TableConstructor {
+        fn new(config: DBConfig) -> Self {
+            Self {
+                data: BTreeMap::new(),
+                config,
+                table: None,
+                reads: Arc::new(AtomicUsize::new(0)),
+                block_cache: None,
+            }
+        }
+
+        fn add(&mut self, key: &[u8], value: &[u8]) {
+            self.data.insert(key.to_vec(), value.to_vec());
+        }
+
+        fn finish(&mut self) -> usize {
+            let contents = Arc::new(Mutex::new(vec![]));
+            let sink = StringSink {
+                contents: contents.clone(),
+            };
+            let mut builder = TableBuilder::new(&self.config, Box::new(sink));
+            for (key, value) in &self.data {
+                builder.add(key, value).unwrap();
+            }
+            builder.finish().unwrap();
+            assert_eq!(contents.lock().unwrap().len() as u64, builder.file_size());
+
+            let size = contents.lock().unwrap().len();
+            let source = StringSource::new(mem::take(&mut *contents.lock().unwrap()));
+            self.reads = source.reads.clone();
+            self.table = Some(
+                Table::open(
+                    &self.config,
+                    Box::new(source),
+                    size as u64,
+                    self.block_cache.clone(),
+                )
+                .unwrap(),
+            );
+            size
+        }
+
+        fn table(&self) -> &Arc<Table> {
+            self.table.as_ref().unwrap()
+        }
+
+        fn approximate_offset_of(&self, key: &[u8]) -> u64 {
+            self.table().approximate_offset_of(key)
+        }
+    }
+
+    fn between(value: u64, low: u64, high: u64) -> bool {
+        value >= low && value <= high
+    }
+
+    #[test]
+    fn test_table_empty() {
+        let mut constructor = TableConstructor::new(DBConfig::default());
+        constructor.finish();
+        let mut iter = constructor.table().new_iterator(&ReadConfig::default());
+        iter.seek_to_first();
+        assert!(!iter.valid());
+        iter.seek_to_last();
+        assert!(!iter.valid());
+        iter.seek(b"foo");
+        assert!(!iter.valid());
+        assert!(iter.status().is_ok());
+        assert!(constructor
+            .table()
+            .get(&ReadConfig::default(), b"foo")
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_table_randomized_iteration() {
+        let mut rnd = Random::new(301);
+        for num_entries in [1, 2, 10, 100, 3000] {
+            let config = DBConfig {
+                block_size: 256,
+                ..Default::default()
+            };
+            let mut constructor = TableConstructor::new(config);
+            for _ in 0..num_entries {
+                let key: Vec<u8> = (0..rnd.skewed(4))
+                    .map(|_| b' ' + rnd.uniform(95) as u8)
+                    .collect();
+                let value: Vec<u8> = (0..rnd.skewed(5))
+                    .map(|_| b' ' + rnd.uniform(95) as u8)
+                    .collect();
+                constructor.add(&key, &value);
+            }
+            constructor.finish();
+            let table = constructor.table();
+            let model: Vec<_> = constructor.data.iter().collect();
+
+            // Forward scan
+            let mut iter = table.new_iterator(&ReadConfig::default());
+            iter.seek_to_first();
+            for (key, value) in &model {
+                assert!(iter.valid());
+                assert_eq!(key.as_slice(), iter.key());
+                assert_eq!(value.as_slice(), iter.value());
+                iter.next();
+            }
+            assert!(!iter.valid());
+
+            // Backward scan
+            iter.seek_to_last();
+            for (key, value) in model.iter().rev() {
+                assert!(iter.valid());
+                assert_eq!(key.as_slice(), iter.key());
+                assert_eq!(value.as_slice(), iter.value());
+                iter.prev();
+            }
+            assert!(!iter.valid());
+
+            // Point lookups land on the first entry at or after the key
+            for (index, (key, value)) in model.iter().enumerate() {
+                iter.seek(key);
+                assert!(iter.valid());
+                assert_eq!(key.as_slice(), iter.key());
+                if index + 1 < model.len() {
+                    iter.next();
+                    assert_eq!(model[index + 1].0.as_slice(), iter.key());
+                }
+                let (found_key, found_value) =
+                    table.get(&ReadConfig::default(), key).unwrap().unwrap();
+                assert_eq!(key.as_slice(), found_key);
+                assert_eq!(value.as_slice(), found_value);
+            }
+
+            // Backward lookups land on the last entry at or before the key
+            for _ in 0..100 {
+                let target: Vec<u8> = (0..rnd.skewed(4))
+                    .map(|_| b' ' + rnd.uniform(95) as u8)
+                    .collect();
+                iter.seek_for_prev(&target);
+                match model.iter().rev().find(|(key, _)| **key <= target) {
+                    Some((key, _)) => assert_eq!(key.as_slice(), iter.key()),
+                    None => assert!(!iter.valid()),
+                }
+            }
+            assert!(iter.status().is_ok());
+        }
+    }
+
+    #[test]
+    fn test_table_approximate_offset_of_plain() {
+        let config = DBConfig {
+            block_size: 1024,
+            compression: CompressionType::None,
+            ..Default::default()
+        };
+        let mut constructor = TableConstructor::new(config);
+        constructor.add(b"k01", b"hello");
+        constructor.add(b"k02", b"hello2");
+        constructor.add(b"k03", &[b'x'; 10000]);
+        constructor.add(b"k04", &[b'x'; 200000]);
+        constructor.add(b"k05", &[b'x'; 300000]);
+        constructor.add(b"k06", b"hello3");
+        constructor.add(b"k07", &[b'x'; 100000]);
+        constructor.finish();
+
+        assert!(between(constructor.approximate_offset_of(b"abc"), 0, 0));
+        assert!(between(constructor.approximate_offset_of(b"k01"), 0, 0));
+        assert!(between(constructor.approximate_offset_of(b"k01a"), 0, 0));
+        assert!(between(constructor.approximate_offset_of(b"k02"), 0, 0));
+        assert!(between(constructor.approximate_offset_of(b"k03"), 0, 0));
+        assert!(between(
+            constructor.approximate_offset_of(b"k04"),
+            10000,
+            11000
+        ));
+        assert!(between(
+            constructor.approximate_offset_of(b"k04a"),
+            210000,
+            211000
+        ));
+        assert!(between(
+            constructor.approximate_offset_of(b"k05"),
+            210000,
+            211000
+        ));
+        assert!(between(
+            constructor.approximate_offset_of(b"k06"),
+            510000,
+            511000
+        ));
+        assert!(between(
+            constructor.approximate_offset_of(b"k07"),
+            510000,
+            511000
+        ));
+        assert!(between(
+            constructor.approximate_offset_of(b"xyz"),
+            610000,
+            612000
+        ));
+    }
+
+    /// Build a table of mostly compressible values with "compression",
+    /// check that it reads back and return its size.
+    fn build_compressed_table(compression: CompressionType) -> usize {
+        let config = DBConfig {
+            block_size: 1024,
+            compression,
+            ..Default::default()
+        };
+        let mut constructor = TableConstructor::new(config);
+        for i in 0..1000 {
+            constructor.add(
+                format!("key{:06}", i).as_bytes(),
+                &[b'a' + (i % 26) as u8; 100],
+            );
+        }
+        // Incompressible values are stored as they are
+        let mut rnd = Random::new(301);
+        let random: Vec<u8> = (0..5000).map(|_| rnd.uniform(256) as u8).collect();
+        constructor.add(b"random", &random);
+        let size = constructor.finish();
+
+        let table = constructor.table();
+        let mut iter = table.new_iterator(&ReadConfig::default());
+        iter.seek_to_first();
+        for (key, value) in &constructor.data {
+            assert_eq!(key.as_slice(), iter.key());
+            assert_eq!(value.as_slice(), iter.value());
+            iter.next();
+        }
+        assert!(!iter.valid());
+        assert!(iter.status().is_ok());
+        let (_, value) = table
+            .get(&ReadConfig::default(), b"random")
+            .unwrap()
+            .unwrap();
+        assert_eq!(random, value);
+        size
+    }
+
+    #[test]
+    fn test_table_compression() {
+        let plain = build_compressed_table(CompressionType::None);
+        let snappy = build_compressed_table(CompressionType::Snappy);
+        if cfg!(feature = "snappy") {
+            assert!(snappy < plain / 4, "{} vs {}", snappy, plain);
+        } else {
+            assert_eq!(plain, snappy);
+        }
+
+        let zstd = build_compressed_table(CompressionType::Zstd);
+        if cfg!(feature = "zstd") {
+            assert!(zstd < plain / 3, "{} vs {}", zstd, plain);
+        } else {
+            // Falls back to storing the blocks uncompressed
+            assert_eq!(plain, zstd);
+        }
+    }
+
+    #[test]
+    fn test_table_open_corrupted() {
+        let config = DBConfig::default();
+        let too_short = StringSource::new(vec![0; 10]);
+        assert!(Table::open(&config, Box::new(too_short), 10, None)
+            .err()
+            .unwrap()
+            .is_corruption());
+
+        let mut constructor = TableConstructor::new(DBConfig::default());
+        constructor.add(b"foo", b"bar");
+        let contents = Arc::new(Mutex::new(vec![]));
+        let mut builder = TableBuilder::new(
+            &constructor.config,
+            Box::new(StringSink {
+                contents: contents.clone(),
+            }),
+        );
+        builder.add(b"foo", b"bar").unwrap();
+        builder.finish().unwrap();
+        let mut contents = mem::take(&mut *contents.lock().unwrap());
+        let last = contents.len() - 1;
+        contents[last] ^= 0x1;
+        let size = contents.len() as u64;
+        let bad_magic = StringSource::new(contents);
+        assert!(Table::open(&config, Box::new(bad_magic), size, None)
+            .err()
+            .unwrap()
+            .is_corruption());
+    }
+
+    #[test]
+    fn test_table_iterator_stops_at_corrupted_block() {
+        let config = DBConfig {
+            block_size: 256,
+            ..Default::default()
+        };
+        let contents = Arc::new(Mutex::new(vec![]));
+        let mut builder = TableBuilder::new(
+            &config,
+            Box::new(StringSink {
+                contents: contents.clone(),
+            }),
+        );
+        for i in 0..1000 {
+            builder
+                .add(format!("key{:06}", i).as_bytes(), b"value")
+                .unwrap();
+        }
+        builder.finish().unwrap();
+        let mut contents = mem::take(&mut *contents.lock().unwrap());
+        // Somewhere in the middle of the data blocks
+        let offset = contents.len() / 3;
+        contents[offset] ^= 0xff;
+        let size = contents.len() as u64;
+        let table =
+            Table::open(&config, Box::new(StringSource::new(contents)), size, None).unwrap();
+        let read_config = ReadConfig {
+            verify_checksums: true,
+            ..Default::default()
+        };
+
+        // Neither direction skips past the bad block to the next one
+        let mut iter = table.new_iterator(&read_config);
+        iter.seek_to_first();
+        let mut count = 0;
+        while iter.valid() {
+            assert_eq!(format!("key{:06}", count).as_bytes(), &iter.key()[..9]);
+            count += 1;
+            iter.next();
+        }
+        assert!(count > 0 && count < 1000, "{} entries", count);
+        assert!(iter.status().unwrap_err().is_corruption());
+
+        let mut iter = table.new_iterator(&read_config);
+        iter.seek_to_last();
+        let mut last = 1000;
+        while iter.valid() {
+            last -= 1;
+            assert_eq!(format!("key{:06}", last).as_bytes(), &iter.key()[..9]);
+            iter.prev();
+        }
+        assert!(last > count, "stopped at {}", last);
+        assert!(iter.status().unwrap_err().is_corruption());
+    }
+
+    #[cfg(feature = "bloom")]
+    #[test]
+    fn test_table_filter_skips_block_reads() {
+        let config = DBConfig {
+            block_size: 256,
+            filter_policy: Some(Arc::new(crate::BloomFilterPolicy::new(10))),
+            ..Default::default()
+        };
+        let mut constructor = TableConstructor::new(config);
+        for i in 0..1000 {
+            constructor.add(format!("key{:06}", i * 2).as_bytes(), b"value");
+        }
+        constructor.finish();
+        let table = constructor.table().clone();
+        let read_config = ReadConfig::default();
+
+        // Every key that was added must still be found
+        for i in 0..1000 {
+            let key = format!("key{:06}", i * 2);
+            let (found, _) = table.get(&read_config, key.as_bytes()).unwrap().unwrap();
+            assert_eq!(key.as_bytes(), found.as_slice());
+        }
+
+        // Missing keys are almost always rejected without reading a block
+        let reads_before = constructor.reads.load(Ordering::SeqCst);
+        let mut found = 0;
+        for i in 0..1000 {
+            let key = format!("key{:06}", i * 2 + 1);
+            if table.get(&read_config, key.as_bytes()).unwrap().is_some() {
+                found += 1;
+            }
+        }
+        let reads = constructor.reads.load(Ordering::SeqCst) - reads_before;
+        assert_eq!(reads, found);
+        assert!(reads < 50, "{} block reads for missing keys", reads);
+
+        // A table written with a filter stays readable without one
+        let mut plain = TableConstructor::new(DBConfig::default());
+        plain.data = constructor.data.clone();
+        plain.finish();
+        assert!(plain
+            .table()
+            .get(&read_config, b"key000001")
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn test_table_iterator_cancel() {
+        let config = DBConfig {
+            block_size: 256,
+            ..Default::default()
+        };
+        let mut constructor = TableConstructor::new(config);
+        for i in 0..1000 {
+            constructor.add(format!("key{:06}", i).as_bytes(), b"value");
+        }
+        constructor.finish();
+
+        let cancel = Arc::new(AtomicBool::new(false));
+        let read_config = ReadConfig {
+            cancel: Some(cancel.clone()),
+            ..Default::default()
+        };
+        let mut iter = constructor.table().new_iterator(&read_config);
+        iter.seek_to_first();
+        let mut count = 0;
+        while iter.valid() {
+            count += 1;
+            if count == 100 {
+                cancel.store(true, Ordering::SeqCst);
+            }
+            iter.next();
+        }
+        // The scan stops at the end of the current block
+        assert!((100..150).contains(&count), "{} entries", count);
+        assert!(iter.status().unwrap_err().is_cancelled());
+
+        let reads = constructor.reads.load(Ordering::SeqCst);
+        iter.seek_to_last();
+        assert!(!iter.valid());
+        iter.seek(b"key000500");
+        assert!(!iter.valid());
+        assert_eq!(reads, constructor.reads.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_table_block_cache() {
+        let config = DBConfig {
+            block_size: 256,
+            ..Default::default()
+        };
+        let cache: Arc<dyn Cache<block::Block>> = Arc::new(ShardedLRUCache::new(1 << 20));
+        let mut constructor = TableConstructor::new(config);
+        constructor.block_cache = Some(cache.clone());
+        for i in 0..1000 {
+            constructor.add(format!("key{:06}", i).as_bytes(), b"value");
+        }
+        constructor.finish();
+        let table = constructor.table().clone();
+
+        // Reads that do not fill the cache leave it empty
+        let no_fill = ReadConfig {
+            fill_cache: false,
+            ..Default::default()
+        };
+        assert!(table.get(&no_fill, b"key000500").unwrap().is_some());
+        assert_eq!(0, cache.total_charge());
+
+        // A full scan loads every block once, later scans hit the cache
+        let read_config = ReadConfig::default();
+        let scan = || {
+            let mut iter = table.new_iterator(&read_config);
+            iter.seek_to_first();
+            let mut count = 0;
+            while iter.valid() {
+                count += 1;
+                iter.next();
+            }
+            assert!(iter.status().is_ok());
+            count
+        };
+        assert_eq!(1000, scan());
+        let usage = cache.total_charge();
+        assert!(usage > 0);
+        let reads = constructor.reads.load(Ordering::SeqCst);
+        assert_eq!(1000, scan());
+        assert!(table.get(&read_config, b"key000500").unwrap().is_some());
+        assert_eq!(reads, constructor.reads.load(Ordering::SeqCst));
+        assert_eq!(usage, cache.total_charge());
+
+        // Tables sharing the cache do not see each other's blocks
+        let mut other = TableConstructor::new(constructor.config.clone());
+        other.block_cache = Some(cache.clone());
+        other.add(b"key000500", b"other");
+        other.finish();
+        let (_, value) = other
+            .table()
+            .get(&read_config, b"key000500")
+            .unwrap()
+            .unwrap();
+        assert_eq!(b"other", value.as_slice());
+    }
+
+    #[test]
+    fn test_table_range_tombstones() {
+        let config = DBConfig {
+            comparator: Arc::new(InternalKeyComparator::new(Arc::new(
+                BytewiseComparator::new(),
+            ))),
+            ..Default::default()
+        };
+        let mut constructor = TableConstructor::new(config.clone());
+        constructor.add(InternalKey::new(b"b", 5, ValueType::Value).encode(), b"v");
+        constructor.finish();
+        assert!(constructor.table().range_tombstones().is_empty());
+
+        // Tombstones are added in any order, and read back sorted
+        let contents = Arc::new(Mutex::new(vec![]));
+        let mut builder = TableBuilder::new(
+            &config,
+            Box::new(StringSink {
+                contents: contents.clone(),
+            }),
+        );
+        builder
+            .add(InternalKey::new(b"b", 5, ValueType::Value).encode(), b"v")
+            .unwrap();
+        let tombstones = [
+            RangeTombstone::new(b"a", b"c", 3),
+            RangeTombstone::new(b"a", b"z", 7),
+            RangeTombstone::new(b"x", b"y", 1),
+        ];
+        for tombstone in [&tombstones[2], &tombstones[0], &tombstones[1]] {
+            builder.add_range_tombstone(tombstone.clone());
+        }
+        builder.finish().unwrap();
+        assert_eq!(1, builder.num_entries());
+        let contents = mem::take(&mut *contents.lock().unwrap());
+        let size = contents.len() as u64;
+        let table =
+            Table::open(&config, Box::new(StringSource::new(contents)), size, None).unwrap();
+        let expected = [
+            tombstones[1].clone(),
+            tombstones[0].clone(),
+            tombstones[2].clone(),
+        ];
+        assert_eq!(&expected[..], &table.range_tombstones()[..]);
+        let (found, _) = table
+            .get(
+                &ReadConfig::default(),
+                InternalKey::new(b"b", 9, ValueType::Value).encode(),
+            )
+            .unwrap()
+            .unwrap();
+        assert_eq!(InternalKey::new(b"b", 5, ValueType::Value).encode(), found);
+    }
+
+    #[test]
+    fn test_table_builder_checks_keys() {
+        let sink = || {
+            Box::new(StringSink {
+                contents: Arc::new(Mutex::new(vec![])),
+            })
+        };
+        let mut builder = TableBuilder::new(&DBConfig::default(), sink());
+        assert_eq!(None, builder.smallest_key());
+        builder.add(b"a", b"v").unwrap();
+        builder.add(b"c", b"v").unwrap();
+        assert_eq!(Some(b"a".as_slice()), builder.smallest_key());
+        assert_eq!(Some(b"c".as_slice()), builder.largest_key());
+        assert_eq!(2, builder.num_entries());
+
+        // Out of order keys are rejected, and fail the table
+        assert!(builder.add(b"b", b"v").unwrap_err().is_corruption());
+        assert!(builder.add(b"d", b"v").unwrap_err().is_corruption());
+        assert_eq!(2, builder.num_entries());
+        assert!(builder.finish().unwrap_err().is_corruption());
+
+        // As are malformed internal keys
+        let config = DBConfig {
+            comparator: Arc::new(InternalKeyComparator::new(Arc::new(
+                BytewiseComparator::new(),
+            ))),
+            ..Default::default()
+        };
+        let mut builder = TableBuilder::new(&config, sink());
+        let key = InternalKey::new(b"a", 1, ValueType::Value);
+        builder.add(key.encode(), b"v").unwrap();
+        assert!(builder.add(b"short", b"v").unwrap_err().is_corruption());
+        builder.abandon();
+    }
+}
@@ -0,0 +1,247 @@
+use std::sync::Arc;
+
+use super::{
+    block::Block,
+    builder::{filter_block_key, RANGE_DEL_BLOCK_KEY},
+    filter_block::FilterBlockReader,
+    format::{read_block, BlockHandle, Footer, FOOTER_ENCODED_LENGTH},
+};
+use crate::{
+    db::{DBConfig, ReadConfig},
+    dbformat::RangeTombstone,
+    env::RandomAccessFile,
+    iterator::{new_error_iterator, Iterator, TwoLevelIterator},
+    util::{extend_fixed64, BytewiseComparator, Cache, Comparator, Error, FilterPolicy},
+    Result,
+};
+
+/// A Table is a sorted map from strings to strings.  Tables are
+/// immutable and persistent.  A Table may be safely accessed from
+/// multiple threads without external synchronization.
+pub struct Table {
+    comparator: Arc<dyn Comparator>,
+    file: Box<dyn RandomAccessFile>,
+    metaindex_handle: BlockHandle,
+    index_block: Arc<Block>,
+    filter: Option<FilterBlockReader>,
+    range_tombstones: Arc<[RangeTombstone]>,
+    /// The cache for data blocks and the id that prefixes the cache keys
+    /// of this table's blocks
+    block_cache: Option<(Arc<dyn Cache<Block>>, u64)>,
+}
+
+impl Table {
+    /// Attempt to open the table that is stored in bytes [0..file_size)
+    /// of "file", and read the metadata entries necessary to allow
+    /// retrieving data from the table.
+    ///
+    /// Data blocks are looked up in and added to "block_cache", if given.
+    ///
+    /// If successful, returns the newly opened table.  If there was an
+    /// error while initializing the table, returns a non-ok status.
+    pub fn open(
+        config: &DBConfig,
+        file: Box<dyn RandomAccessFile>,
+        size: u64,
+        block_cache: Option<Arc<dyn Cache<Block>>>,
+    ) -> Result<Arc<Table>> {
+        if size < FOOTER_ENCODED_LENGTH as u64 {
+            return Err(Error::corruption("file is too short to be an sstable"));
+        }
+
+        let mut footer_space = [0; FOOTER_ENCODED_LENGTH];
+        file.read(
+            (size - FOOTER_ENCODED_LENGTH as u64) as usize,
+            &mut footer_space,
+        )?;
+        let footer = Footer::decode_from(&footer_space)?;
+
+        // Read the index block
+        let index_block_contents =
+            read_block(file.as_ref(), &ReadConfig::default(), footer.index_handle())?;
+
+        // We've successfully read the footer and the index block: we're
+        // ready to serve requests.
+        let mut table = Self {
+            comparator: config.comparator.clone(),
+            file,
+            metaindex_handle: *footer.metaindex_handle(),
+            index_block: Arc::new(Block::new(index_block_contents)),
+            filter: None,
+            range_tombstones: Arc::new([]),
+            block_cache: block_cache.map(|cache| {
+                let id = cache.new_id();
+                (cache, id)
+            }),
+        };
+        table.read_meta(config.filter_policy.as_ref())?;
+        Ok(Arc::new(table))
+    }
+
+    /// Load the filter block named by "policy" if the table has one, and
+    /// the range tombstones.  Errors reading the filter are ignored since
+    /// the filter is not needed for operation, but the table can't be read
+    /// without its range tombstones.
+    fn read_meta(&mut self, policy: Option<&Arc<dyn FilterPolicy>>) -> Result<()> {
+        // Do not need any checksum verification for the metaindex block
+        let contents = read_block(
+            self.file.as_ref(),
+            &ReadConfig::default(),
+            &self.metaindex_handle,
+        )?;
+        let meta = Arc::new(Block::new(contents));
+        let mut iter = meta.new_iterator(Arc::new(BytewiseComparator::new()));
+        if let Some(policy) = policy {
+            let key = filter_block_key(policy.as_ref());
+            iter.seek(&key);
+            if iter.valid() && iter.key() == key.as_slice() {
+                self.read_filter(policy, iter.value());
+            }
+        }
+        iter.seek(RANGE_DEL_BLOCK_KEY);
+        if iter.valid() && iter.key() == RANGE_DEL_BLOCK_KEY {
+            let (handle, _) = BlockHandle::decode_from(iter.value())?;
+            self.read_range_tombstones(&handle)?;
+        }
+        iter.status()
+    }
+
+    fn read_range_tombstones(&mut self, handle: &BlockHandle) -> Result<()> {
+        let config = ReadConfig {
+            verify_checksums: true,
+            ..Default::default()
+        };
+        let block = Arc::new(Block::new(read_block(self.file.as_ref(), &config, handle)?));
+        let mut iter = block.new_iterator(self.comparator.clone());
+        let mut tombstones = vec![];
+        iter.seek_to_first();
+        while iter.valid() {
+            match RangeTombstone::decode(iter.key(), iter.value()) {
+                Some(tombstone) => tombstones.push(tombstone),
+                None => return Err(Error::corruption("bad entry in range deletion block")),
+            }
+            iter.next();
+        }
+        iter.status()?;
+        self.range_tombstones = tombstones.into();
+        Ok(())
+    }
+
+    /// The range tombstones of the table, sorted by start
+    pub fn range_tombstones(&self) -> Arc<[RangeTombstone]> {
+        self.range_tombstones.clone()
+    }
+
+    fn read_filter(&mut self, policy: &Arc<dyn FilterPolicy>, filter_handle_value: &[u8]) {
+        let handle = match BlockHandle::decode_from(filter_handle_value) {
+            Ok((handle, _)) => handle,
+            Err(_) => return,
+        };
+        let config = ReadConfig {
+            verify_checksums: true,
+            ..Default::default()
+        };
+        if let Ok(contents) = read_block(self.file.as_ref(), &config, &handle) {
+            self.filter = Some(FilterBlockReader::new(policy.clone(), contents));
+        }
+    }
+
+    /// Returns a new iterator over the table contents.
+    /// The result of new_iterator() is initially invalid (caller must
+    /// call one of the seek methods on the iterator before using it).
+    pub fn new_iterator(self: &Arc<Self>, config: &ReadConfig) -> Box<dyn Iterator> {
+        let table = self.clone();
+        Box::new(TwoLevelIterator::new(
+            self.index_block.new_iterator(self.comparator.clone()),
+            move |config, index_value| table.block_reader(config, index_value),
+            config,
+        ))
+    }
+
+    /// Find the first entry at or after "key" and return its key and value,
+    /// or None if every entry in the table is before "key".  With a filter
+    /// policy configured, None is also returned when the filter proves
+    /// that "key" is absent, without reading the data block.
+    pub fn get(&self, config: &ReadConfig, key: &[u8]) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let mut result = None;
+        let mut index_iter = self.index_block.new_iterator(self.comparator.clone());
+        index_iter.seek(key);
+        if index_iter.valid() {
+            if let (Some(filter), Ok((handle, _))) = (
+                self.filter.as_ref(),
+                BlockHandle::decode_from(index_iter.value()),
+            ) {
+                if !filter.key_may_match(handle.offset(), key) {
+                    // Not found
+                    return Ok(None);
+                }
+            }
+            let mut block_iter = self.block_reader(config, index_iter.value());
+            block_iter.seek(key);
+            if block_iter.valid() {
+                result = Some((block_iter.key().to_vec(), block_iter.value().to_vec()));
+            }
+            block_iter.status()?;
+        }
+        index_iter.status()?;
+        Ok(result)
+    }
+
+    /// Given a key, return an approximate byte offset in the file where
+    /// the data for that key begins (or would begin if the key were
+    /// present in the file).  The returned value is in terms of file
+    /// bytes, and so includes effects like compression of the underlying data.
+    /// E.g., the approximate offset of the last key in the table will
+    /// be close to the file length.
+    pub fn approximate_offset_of(&self, key: &[u8]) -> u64 {
+        let mut index_iter = self.index_block.new_iterator(self.comparator.clone());
+        index_iter.seek(key);
+        if index_iter.valid() {
+            if let Ok((handle, _)) = BlockHandle::decode_from(index_iter.value()) {
+                return handle.offset();
+            }
+            // Strange: we can't decode the block handle in the index block.
+            // We'll just return the offset of the metaindex block, which is
+            // close to the whole file size for this case.
+        }
+        // key is past the last key in the file.  Approximate the offset
+        // by returning the offset of the metaindex block (which is
+        // right near the end of the file).
+        self.metaindex_handle.offset()
+    }
+
+    /// Convert an index iterator value (i.e., an encoded BlockHandle)
+    /// into an iterator over the contents of the corresponding block.
+    /// The iterator pins the block in the block cache until it is dropped.
+    fn block_reader(&self, config: &ReadConfig, index_value: &[u8]) -> Box<dyn Iterator> {
+        let block = BlockHandle::decode_from(index_value)
+            .and_then(|(handle, _)| self.read_data_block(config, &handle));
+        match block {
+            Ok(block) => block.new_iterator(self.comparator.clone()),
+            Err(error) => new_error_iterator(error),
+        }
+    }
+
+    fn read_data_block(&self, config: &ReadConfig, handle: &BlockHandle) -> Result<Arc<Block>> {
+        let (cache, cache_id) = match self.block_cache.as_ref() {
+            Some((cache, cache_id)) => (cache, *cache_id),
+            None => {
+                let contents = read_block(self.file.as_ref(), config, handle)?;
+                return Ok(Arc::new(Block::new(contents)));
+            }
+        };
+        let mut cache_key = Vec::with_capacity(16);
+        extend_fixed64(&mut cache_key, cache_id);
+        extend_fixed64(&mut cache_key, handle.offset());
+        if let Some(block) = cache.lookup(&cache_key) {
+            return Ok(block);
+        }
+        let block = Block::new(read_block(self.file.as_ref(), config, handle)?);
+        if config.fill_cache {
+            let charge = block.size();
+            Ok(cache.insert(&cache_key, block, charge))
+        } else {
+            Ok(Arc::new(block))
+        }
+    }
+}
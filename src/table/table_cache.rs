@@ -0,0 +1,250 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+};
+
+use super::{block::Block, Table};
+use crate::{
+    db::{DBConfig, ReadConfig},
+    dbformat::RangeTombstone,
+    env::Env,
+    filename::table_file_name_for,
+    iterator::{new_error_iterator, Iterator},
+    util::{Cache, ShardedLRUCache},
+    Result,
+};
+
+/// Open tables by file number, with the least recently used one closed
+/// first once there are more than "entries" of them.
+#[derive(Default)]
+struct LruTables {
+    /// file number => (table, last use)
+    tables: HashMap<u64, (Arc<Table>, u64)>,
+    /// last use => file number
+    lru: BTreeMap<u64, u64>,
+    /// Incremented on every use
+    clock: u64,
+}
+
+impl LruTables {
+    fn lookup(&mut self, file_number: u64) -> Option<Arc<Table>> {
+        self.clock += 1;
+        let (table, last_use) = self.tables.get_mut(&file_number)?;
+        self.lru.remove(last_use);
+        *last_use = self.clock;
+        self.lru.insert(self.clock, file_number);
+        Some(table.clone())
+    }
+
+    fn insert(&mut self, file_number: u64, table: Arc<Table>, entries: usize) {
+        self.clock += 1;
+        self.remove(file_number);
+        self.tables.insert(file_number, (table, self.clock));
+        self.lru.insert(self.clock, file_number);
+        while self.tables.len() > entries {
+            let (_, oldest) = self.lru.pop_first().unwrap();
+            self.tables.remove(&oldest);
+        }
+    }
+
+    fn remove(&mut self, file_number: u64) {
+        if let Some((_, last_use)) = self.tables.remove(&file_number) {
+            self.lru.remove(&last_use);
+        }
+    }
+}
+
+/// Keeps the table files of a database open so that reads and
+/// compactions do not have to reopen the file and parse the table footer
+/// and index on every access.  At most "entries" tables are kept open;
+/// tables still in use by an iterator stay open until it is dropped.
+/// The cache is shared with the flushes running in the background.
+pub struct TableCache {
+    env: Arc<dyn Env>,
+    dbname: String,
+    config: DBConfig,
+    entries: usize,
+    tables: Mutex<LruTables>,
+    /// Data blocks of all tables, shared so that the memory they use is
+    /// bounded by DBConfig::block_cache_size as a whole, or by the
+    /// capacity of DBConfig::block_cache
+    block_cache: Option<Arc<dyn Cache<Block>>>,
+}
+
+impl TableCache {
+    pub fn new(dbname: &str, config: &DBConfig, entries: usize) -> Self {
+        assert!(entries > 0);
+        Self {
+            env: config.env.clone(),
+            dbname: dbname.to_string(),
+            config: config.clone(),
+            entries,
+            tables: Mutex::new(LruTables::default()),
+            block_cache: match (config.block_cache.as_ref(), config.block_cache_size) {
+                (Some(cache), _) => Some(cache.clone()),
+                (None, 0) => None,
+                (None, size) => Some(Arc::new(ShardedLRUCache::new(size))),
+            },
+        }
+    }
+
+    /// Return an iterator for the specified file number (the corresponding
+    /// file length must be exactly "file_size" bytes).
+    pub fn new_iterator(
+        &self,
+        config: &ReadConfig,
+        file_number: u64,
+        file_size: u64,
+    ) -> Box<dyn Iterator> {
+        match self.find_table(file_number, file_size) {
+            Ok(table) => table.new_iterator(config),
+            Err(error) => new_error_iterator(error),
+        }
+    }
+
+    /// If a seek to internal key "key" in specified file finds an entry,
+    /// return its key and value.
+    pub fn get(
+        &self,
+        config: &ReadConfig,
+        file_number: u64,
+        file_size: u64,
+        key: &[u8],
+    ) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        self.find_table(file_number, file_size)?.get(config, key)
+    }
+
+    /// Return the range tombstones of the specified file.
+    pub fn range_tombstones(
+        &self,
+        file_number: u64,
+        file_size: u64,
+    ) -> Result<Arc<[RangeTombstone]>> {
+        Ok(self.find_table(file_number, file_size)?.range_tombstones())
+    }
+
+    /// Return the approximate offset of "key" in the specified file, or 0
+    /// if the file cannot be opened.
+    pub fn approximate_offset_of(&self, file_number: u64, file_size: u64, key: &[u8]) -> u64 {
+        self.find_table(file_number, file_size)
+            .map_or(0, |table| table.approximate_offset_of(key))
+    }
+
+    /// Evict any entry for the specified file number
+    pub fn evict(&self, file_number: u64) {
+        self.tables.lock().unwrap().remove(file_number);
+    }
+
+    /// Number of tables currently held open by the cache.
+    pub fn num_open_tables(&self) -> usize {
+        self.tables.lock().unwrap().tables.len()
+    }
+
+    /// Bytes of data blocks held by the block cache.
+    pub fn block_cache_usage(&self) -> usize {
+        self.block_cache
+            .as_ref()
+            .map_or(0, |cache| cache.total_charge())
+    }
+
+    fn find_table(&self, file_number: u64, file_size: u64) -> Result<Arc<Table>> {
+        if let Some(table) = self.tables.lock().unwrap().lookup(file_number) {
+            return Ok(table);
+        }
+
+        // Tables may have been written with either extension
+        let extension = self.config.table_file_extension;
+        let fname = table_file_name_for(&self.dbname, file_number, extension);
+        let file = match self.env.new_random_access_file(&fname) {
+            Ok(file) => file,
+            Err(error) => {
+                let alternate =
+                    table_file_name_for(&self.dbname, file_number, extension.alternate());
+                match self.env.new_random_access_file(&alternate) {
+                    Ok(file) => file,
+                    Err(_) => return Err(error),
+                }
+            }
+        };
+        // We do not cache error results so that if the error is transient,
+        // or somebody repairs the file, we recover automatically.
+        let table = Table::open(&self.config, file, file_size, self.block_cache.clone())?;
+        self.tables
+            .lock()
+            .unwrap()
+            .insert(file_number, table.clone(), self.entries);
+        Ok(table)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        dbformat::{InternalKey, ValueType},
+        env::{test_dir, WriteHint},
+        filename::table_file_name,
+        table::TableBuilder,
+    };
+
+    fn write_table(config: &DBConfig, dbname: &str, number: u64) -> u64 {
+        let file = config
+            .env
+            .new_writable_file(&table_file_name(dbname, number), WriteHint::TableHot)
+            .unwrap();
+        let mut builder = TableBuilder::new(config, file);
+        let key = InternalKey::new(format!("key{}", number).as_bytes(), 1, ValueType::Value);
+        builder.add(key.encode(), b"value").unwrap();
+        builder.finish().unwrap();
+        builder.file().close().unwrap();
+        builder.file_size()
+    }
+
+    #[test]
+    fn test_table_cache_lru() {
+        let dbname = test_dir("table_cache_lru");
+        let config = DBConfig::default();
+        let sizes: Vec<_> = (1..=3)
+            .map(|number| write_table(&config, &dbname, number))
+            .collect();
+        let cache = TableCache::new(&dbname, &config, 2);
+        let read_config = ReadConfig::default();
+        let lookup = |number: u64| {
+            let key = InternalKey::new(format!("key{}", number).as_bytes(), 1, ValueType::Value);
+            cache
+                .get(
+                    &read_config,
+                    number,
+                    sizes[number as usize - 1],
+                    key.encode(),
+                )
+                .map(|entry| entry.unwrap().1)
+        };
+
+        assert_eq!(b"value", lookup(1).unwrap().as_slice());
+        assert_eq!(b"value", lookup(2).unwrap().as_slice());
+        assert_eq!(b"value", lookup(1).unwrap().as_slice());
+        assert_eq!(2, cache.num_open_tables());
+
+        // Opening a third table closes the least recently used one
+        assert_eq!(b"value", lookup(3).unwrap().as_slice());
+        assert_eq!(2, cache.num_open_tables());
+        for number in 1..=3 {
+            config
+                .env
+                .remove_file(&table_file_name(&dbname, number))
+                .unwrap();
+        }
+        assert!(lookup(1).is_ok());
+        assert!(lookup(3).is_ok());
+        assert!(lookup(2).is_err());
+
+        // An iterator keeps its table usable after eviction
+        let mut iter = cache.new_iterator(&read_config, 3, sizes[2]);
+        cache.evict(3);
+        assert_eq!(1, cache.num_open_tables());
+        iter.seek_to_first();
+        assert!(iter.valid());
+        assert_eq!(b"value", iter.value());
+    }
+}
@@ -0,0 +1,371 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use super::hash;
+
+/// A Cache is an interface that maps keys to values.  It has internal
+/// synchronization and may be safely accessed concurrently from
+/// multiple threads.  It may automatically evict entries to make room
+/// for new entries.  Values have a specified charge against the cache
+/// capacity.  For example, a cache where the values are variable
+/// length strings, may use the length of the string as the charge for
+/// the string.
+///
+/// The Arc returned by insert() and lookup() is the handle of the entry:
+/// an entry is pinned while any clone of its handle is alive.  Pinned
+/// entries still count against the capacity but are never evicted.
+pub trait Cache<T>: Send + Sync {
+    /// Insert a mapping from key->value into the cache and assign it
+    /// the specified charge against the total cache capacity.  Any
+    /// existing mapping for "key" is replaced.
+    fn insert(&self, key: &[u8], value: T, charge: usize) -> Arc<T>;
+
+    /// If the cache has no mapping for "key", returns None.
+    /// Else return a handle that corresponds to the mapping.
+    fn lookup(&self, key: &[u8]) -> Option<Arc<T>>;
+
+    /// If the cache contains entry for key, erase it.  Outstanding
+    /// handles keep the value alive, but it no longer counts against
+    /// the capacity.
+    fn erase(&self, key: &[u8]);
+
+    /// Return a new numeric id.  May be used by multiple clients who are
+    /// sharing the same cache to partition the key space.  Typically the
+    /// client will allocate a new id at startup and prepend the id to
+    /// its cache keys.
+    fn new_id(&self) -> u64;
+
+    /// Remove all cache entries that are not actively in use.
+    fn prune(&self);
+
+    /// Return an estimate of the combined charges of all elements stored
+    /// in the cache.
+    fn total_charge(&self) -> usize;
+}
+
+struct LruEntry<T> {
+    value: Arc<T>,
+    charge: usize,
+    /// Position in LruState::lru
+    last_use: u64,
+}
+
+impl<T> LruEntry<T> {
+    fn pinned(&self) -> bool {
+        Arc::strong_count(&self.value) > 1
+    }
+}
+
+struct LruState<T> {
+    entries: HashMap<Vec<u8>, LruEntry<T>>,
+    /// last use => key, oldest first
+    lru: BTreeMap<u64, Vec<u8>>,
+    /// Incremented on every use
+    clock: u64,
+    usage: usize,
+}
+
+impl<T> LruState<T> {
+    fn remove(&mut self, key: &[u8]) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.lru.remove(&entry.last_use);
+            self.usage -= entry.charge;
+        }
+    }
+
+    /// Drop the least recently used entries that are not pinned until
+    /// the usage is within "capacity".
+    fn evict(&mut self, capacity: usize) {
+        let mut victims = vec![];
+        let mut usage = self.usage;
+        for key in self.lru.values() {
+            if usage <= capacity {
+                break;
+            }
+            let entry = &self.entries[key];
+            if !entry.pinned() {
+                usage -= entry.charge;
+                victims.push(key.clone());
+            }
+        }
+        for key in victims {
+            self.remove(&key);
+        }
+    }
+}
+
+/// A cache that evicts the least recently used entries once the total
+/// charge exceeds its capacity.
+pub struct LRUCache<T> {
+    capacity: usize,
+    state: Mutex<LruState<T>>,
+    last_id: AtomicU64,
+}
+
+impl<T> LRUCache<T> {
+    /// Create a cache with a fixed size capacity.  A capacity of zero
+    /// turns caching off: insert() only hands the value back.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            state: Mutex::new(LruState {
+                entries: HashMap::new(),
+                lru: BTreeMap::new(),
+                clock: 0,
+                usage: 0,
+            }),
+            last_id: AtomicU64::new(0),
+        }
+    }
+}
+
+impl<T: Send + Sync> Cache<T> for LRUCache<T> {
+    fn insert(&self, key: &[u8], value: T, charge: usize) -> Arc<T> {
+        let value = Arc::new(value);
+        if self.capacity == 0 {
+            return value;
+        }
+        let mut state = self.state.lock().unwrap();
+        state.remove(key);
+        state.clock += 1;
+        let last_use = state.clock;
+        state.entries.insert(
+            key.to_vec(),
+            LruEntry {
+                value: value.clone(),
+                charge,
+                last_use,
+            },
+        );
+        state.lru.insert(last_use, key.to_vec());
+        state.usage += charge;
+        state.evict(self.capacity);
+        value
+    }
+
+    fn lookup(&self, key: &[u8]) -> Option<Arc<T>> {
+        let mut state = self.state.lock().unwrap();
+        state.clock += 1;
+        let clock = state.clock;
+        let entry = state.entries.get_mut(key)?;
+        let (value, last_use) = (entry.value.clone(), entry.last_use);
+        entry.last_use = clock;
+        state.lru.remove(&last_use);
+        state.lru.insert(clock, key.to_vec());
+        Some(value)
+    }
+
+    fn erase(&self, key: &[u8]) {
+        self.state.lock().unwrap().remove(key);
+    }
+
+    fn new_id(&self) -> u64 {
+        self.last_id.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    fn prune(&self) {
+        self.state.lock().unwrap().evict(0);
+    }
+
+    fn total_charge(&self) -> usize {
+        self.state.lock().unwrap().usage
+    }
+}
+
+const NUM_SHARD_BITS: u32 = 4;
+const NUM_SHARDS: usize = 1 << NUM_SHARD_BITS;
+
+/// An LRUCache split into shards by key hash, so that concurrent users
+/// of different keys rarely contend on the same lock.  Each shard gets
+/// an equal part of the capacity and evicts on its own.
+pub struct ShardedLRUCache<T> {
+    shards: Vec<LRUCache<T>>,
+    last_id: AtomicU64,
+}
+
+impl<T> ShardedLRUCache<T> {
+    pub fn new(capacity: usize) -> Self {
+        let per_shard = capacity.div_ceil(NUM_SHARDS);
+        Self {
+            shards: (0..NUM_SHARDS).map(|_| LRUCache::new(per_shard)).collect(),
+            last_id: AtomicU64::new(0),
+        }
+    }
+
+    fn shard(&self, key: &[u8]) -> &LRUCache<T> {
+        let hash = hash(key, 0);
+        &self.shards[(hash >> (32 - NUM_SHARD_BITS)) as usize]
+    }
+}
+
+impl<T: Send + Sync> Cache<T> for ShardedLRUCache<T> {
+    fn insert(&self, key: &[u8], value: T, charge: usize) -> Arc<T> {
+        self.shard(key).insert(key, value, charge)
+    }
+
+    fn lookup(&self, key: &[u8]) -> Option<Arc<T>> {
+        self.shard(key).lookup(key)
+    }
+
+    fn erase(&self, key: &[u8]) {
+        self.shard(key).erase(key)
+    }
+
+    fn new_id(&self) -> u64 {
+        self.last_id.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    fn prune(&self) {
+        for shard in &self.shards {
+            shard.prune();
+        }
+    }
+
+    fn total_charge(&self) -> usize {
+        self.shards.iter().map(|shard| shard.total_charge()).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::extend_fixed32;
+
+    fn key(k: u32) -> Vec<u8> {
+        let mut buf = vec![];
+        extend_fixed32(&mut buf, k);
+        buf
+    }
+
+    fn lookup(cache: &dyn Cache<u32>, k: u32) -> Option<u32> {
+        cache.lookup(&key(k)).map(|value| *value)
+    }
+
+    #[test]
+    fn test_lru_cache_hit_and_miss() {
+        let cache = LRUCache::new(1000);
+        assert_eq!(None, lookup(&cache, 100));
+
+        cache.insert(&key(100), 101, 1);
+        assert_eq!(Some(101), lookup(&cache, 100));
+        assert_eq!(None, lookup(&cache, 200));
+
+        cache.insert(&key(200), 201, 1);
+        cache.insert(&key(100), 102, 1);
+        assert_eq!(Some(102), lookup(&cache, 100));
+        assert_eq!(Some(201), lookup(&cache, 200));
+        assert_eq!(2, cache.total_charge());
+
+        cache.erase(&key(200));
+        assert_eq!(None, lookup(&cache, 200));
+        cache.erase(&key(200));
+        assert_eq!(1, cache.total_charge());
+    }
+
+    #[test]
+    fn test_lru_cache_eviction_policy() {
+        let cache = LRUCache::new(100);
+        cache.insert(&key(100), 101, 1);
+        cache.insert(&key(200), 201, 1);
+        cache.insert(&key(300), 301, 1);
+        let pinned = cache.lookup(&key(300)).unwrap();
+
+        // Frequently used entry must be kept around,
+        // as must things that are still in use.
+        for i in 0..200 {
+            cache.insert(&key(1000 + i), 2000 + i, 1);
+            assert_eq!(Some(2000 + i), lookup(&cache, 1000 + i));
+            assert_eq!(Some(101), lookup(&cache, 100));
+        }
+        assert_eq!(Some(101), lookup(&cache, 100));
+        assert_eq!(None, lookup(&cache, 200));
+        assert_eq!(Some(301), lookup(&cache, 300));
+        assert_eq!(301, *pinned);
+        drop(pinned);
+        assert!(cache.total_charge() <= 100);
+    }
+
+    #[test]
+    fn test_lru_cache_heavy_entries() {
+        // Add a bunch of light and heavy entries and then count the
+        // combined size of items still in the cache, which must be
+        // approximately the same as the total capacity.
+        let cache = LRUCache::new(100);
+        let (light, heavy) = (1, 10);
+        let mut added = 0;
+        let mut index = 0;
+        while added < 200 {
+            let weight = if index & 1 == 1 { light } else { heavy };
+            cache.insert(&key(index), 1000 + index, weight);
+            added += weight;
+            index += 1;
+        }
+
+        let mut cached_weight = 0;
+        for i in 0..index {
+            let weight = if i & 1 == 1 { light } else { heavy };
+            if let Some(value) = lookup(&cache, i) {
+                cached_weight += weight;
+                assert_eq!(1000 + i, value);
+            }
+        }
+        assert!(cached_weight <= 100 + 10);
+        assert_eq!(cached_weight, cache.total_charge());
+    }
+
+    #[test]
+    fn test_lru_cache_pinning() {
+        let cache = LRUCache::new(1);
+        let first = cache.insert(&key(100), 101, 1);
+        // Over capacity, but the pinned entry must survive
+        let second = cache.insert(&key(200), 201, 1);
+        assert_eq!(Some(101), lookup(&cache, 100));
+        assert_eq!(2, cache.total_charge());
+
+        // Replaced and erased values stay valid while pinned
+        cache.insert(&key(100), 102, 1);
+        cache.erase(&key(200));
+        assert_eq!(101, *first);
+        assert_eq!(201, *second);
+        assert_eq!(Some(102), lookup(&cache, 100));
+        assert_eq!(None, lookup(&cache, 200));
+
+        drop(first);
+        drop(second);
+        cache.prune();
+        assert_eq!(0, cache.total_charge());
+        assert_eq!(None, lookup(&cache, 100));
+    }
+
+    #[test]
+    fn test_lru_cache_zero_capacity() {
+        let cache = LRUCache::new(0);
+        assert_eq!(101, *cache.insert(&key(100), 101, 1));
+        assert_eq!(None, lookup(&cache, 100));
+        assert_eq!(0, cache.total_charge());
+    }
+
+    #[test]
+    fn test_sharded_lru_cache() {
+        let cache = ShardedLRUCache::new(NUM_SHARDS * 10);
+        let a = cache.new_id();
+        let b = cache.new_id();
+        assert_ne!(a, b);
+
+        for i in 0..1000 {
+            cache.insert(&key(i), i, 1);
+        }
+        assert!(cache.total_charge() <= NUM_SHARDS * 10);
+        assert_eq!(Some(999), lookup(&cache, 999));
+
+        let pinned = cache.lookup(&key(999)).unwrap();
+        cache.prune();
+        assert_eq!(1, cache.total_charge());
+        assert_eq!(999, *pinned);
+    }
+}
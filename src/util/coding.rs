@@ -1,55 +1,67 @@
-use std::{intrinsics::copy_nonoverlapping, mem::size_of};
+//! Endian-neutral encoding helpers shared by every on-disk format.
+//!
+//! * Fixed-length numbers are encoded with least-significant byte first.
+//! * Variable-length numbers ("varints") use 7 bits per byte, with the high
+//!   bit set on every byte but the last.
+//! * Size-prefixed slices are a varint32 length followed by the raw bytes.
+//!
+//! The `encode_*` functions write into caller-provided buffers and report a
+//! too-short buffer as an error, while the `extend_*` functions append to a
+//! `Vec<u8>` and cannot fail.
+
+use std::mem::size_of;
+
+use super::{Error, Result};
+
+/// Maximum number of bytes a varint32 can occupy.
+pub const MAX_VARINT32_LENGTH: usize = 5;
+
+/// Maximum number of bytes a varint64 can occupy.
+pub const MAX_VARINT64_LENGTH: usize = 10;
+
+fn buffer_too_small(needed: usize, available: usize) -> Error {
+    Error::invalid_argument(&format!(
+        "buffer too small: need {} bytes, got {}",
+        needed, available
+    ))
+}
 
-pub fn encode_fixed32(dst: &mut [u8], value: u32) {
-    unsafe {
-        let bytes = value.to_le_bytes();
-        copy_nonoverlapping(bytes.as_ptr(), dst.as_mut_ptr(), size_of::<u32>());
+/// Write "value" into the first 4 bytes of "dst".
+/// Return the number of bytes written.
+pub fn encode_fixed32(dst: &mut [u8], value: u32) -> Result<usize> {
+    let len = size_of::<u32>();
+    if dst.len() < len {
+        return Err(buffer_too_small(len, dst.len()));
     }
+    dst[..len].copy_from_slice(&value.to_le_bytes());
+    Ok(len)
 }
 
-pub fn encode_fixed64(dst: &mut [u8], value: u64) {
-    unsafe {
-        let bytes = value.to_le_bytes();
-        copy_nonoverlapping(bytes.as_ptr(), dst.as_mut_ptr(), size_of::<u64>());
+/// Write "value" into the first 8 bytes of "dst".
+/// Return the number of bytes written.
+pub fn encode_fixed64(dst: &mut [u8], value: u64) -> Result<usize> {
+    let len = size_of::<u64>();
+    if dst.len() < len {
+        return Err(buffer_too_small(len, dst.len()));
     }
+    dst[..len].copy_from_slice(&value.to_le_bytes());
+    Ok(len)
 }
 
-/// Return the index after encoded data
-pub fn encode_varint32(dst: &mut [u8], value: u32) -> usize {
-    const B: u32 = 128;
-    let mut index = 0;
-    if value < (1 << 7) {
-        dst[index] = value as u8;
-        index += 1;
-    } else if value < (1 << 14) {
-        dst[index] = (value | B) as u8;
-        dst[index + 1] = (value >> 7) as u8;
-        index += 2;
-    } else if value < (1 << 21) {
-        dst[index] = (value | B) as u8;
-        dst[index + 1] = ((value >> 7) | B) as u8;
-        dst[index + 2] = (value >> 14) as u8;
-        index += 3;
-    } else if value < (1 << 28) {
-        dst[index] = (value | B) as u8;
-        dst[index + 1] = ((value >> 7) | B) as u8;
-        dst[index + 2] = ((value >> 14) | B) as u8;
-        dst[index + 3] = (value >> 21) as u8;
-        index += 4;
-    } else {
-        dst[index] = (value | B) as u8;
-        dst[index + 1] = ((value >> 7) | B) as u8;
-        dst[index + 2] = ((value >> 14) | B) as u8;
-        dst[index + 3] = ((value >> 21) | B) as u8;
-        dst[index + 4] = (value >> 28) as u8;
-        index += 5;
-    }
-    index
+/// Write "value" as a varint into the front of "dst".
+/// Return the number of bytes written, which is at most `MAX_VARINT32_LENGTH`.
+pub fn encode_varint32(dst: &mut [u8], value: u32) -> Result<usize> {
+    encode_varint64(dst, value as u64)
 }
 
-/// Return the index after encoded data
-pub fn encode_varint64(dst: &mut [u8], mut value: u64) -> usize {
+/// Write "value" as a varint into the front of "dst".
+/// Return the number of bytes written, which is at most `MAX_VARINT64_LENGTH`.
+pub fn encode_varint64(dst: &mut [u8], mut value: u64) -> Result<usize> {
     const B: u64 = 128;
+    let len = varint_size(value);
+    if dst.len() < len {
+        return Err(buffer_too_small(len, dst.len()));
+    }
     let mut index = 0;
     while value >= B {
         dst[index] = (value | B) as u8;
@@ -57,23 +69,36 @@ pub fn encode_varint64(dst: &mut [u8], mut value: u64) -> usize {
         index += 1;
     }
     dst[index] = value as u8;
-    index + 1
+    Ok(index + 1)
 }
 
+/// Read a little-endian u32 from the first 4 bytes of "input".
+///
+/// # Panics
+///
+/// Panics if "input" is shorter than 4 bytes.
 pub fn decode_fixed32(input: &[u8]) -> u32 {
     let (bytes, _) = input.split_at(size_of::<u32>());
     u32::from_le_bytes(bytes.try_into().unwrap())
 }
 
+/// Read a little-endian u64 from the first 8 bytes of "input".
+///
+/// # Panics
+///
+/// Panics if "input" is shorter than 8 bytes.
 pub fn decode_fixed64(input: &[u8]) -> u64 {
     let (bytes, _) = input.split_at(size_of::<u64>());
     u64::from_le_bytes(bytes.try_into().unwrap())
 }
 
+/// Decode a varint32 from the front of "input".
+/// Return Some(value, bytes consumed), or None if "input" is truncated or
+/// the encoding is longer than `MAX_VARINT32_LENGTH`.
 pub fn decode_varint32(input: &[u8]) -> Option<(u32, usize)> {
     let mut result = 0;
-    for i in 0..input.len().min(5) {
-        let byte = input[i] as u32;
+    for (i, &byte) in input.iter().take(MAX_VARINT32_LENGTH).enumerate() {
+        let byte = byte as u32;
         if byte & 128 != 0 {
             // More
             result |= (byte & 127) << (i * 7);
@@ -85,10 +110,13 @@ pub fn decode_varint32(input: &[u8]) -> Option<(u32, usize)> {
     None
 }
 
+/// Decode a varint64 from the front of "input".
+/// Return Some(value, bytes consumed), or None if "input" is truncated or
+/// the encoding is longer than `MAX_VARINT64_LENGTH`.
 pub fn decode_varint64(input: &[u8]) -> Option<(u64, usize)> {
     let mut result = 0;
-    for i in 0..input.len().min(10) {
-        let byte = input[i] as u64;
+    for (i, &byte) in input.iter().take(MAX_VARINT64_LENGTH).enumerate() {
+        let byte = byte as u64;
         if byte & 128 != 0 {
             // More
             result |= (byte & 127) << (i * 7);
@@ -100,36 +128,37 @@ pub fn decode_varint64(input: &[u8]) -> Option<(u64, usize)> {
     None
 }
 
+/// Append "value" to "dst" as 4 little-endian bytes.
 pub fn extend_fixed32(dst: &mut Vec<u8>, value: u32) {
-    let mut buf = [0u8; size_of::<u32>()];
-    encode_fixed32(&mut buf, value);
-    dst.extend_from_slice(&buf);
+    dst.extend_from_slice(&value.to_le_bytes());
 }
 
+/// Append "value" to "dst" as 8 little-endian bytes.
 pub fn extend_fixed64(dst: &mut Vec<u8>, value: u64) {
-    let mut buf = [0u8; size_of::<u64>()];
-    encode_fixed64(&mut buf, value);
-    dst.extend_from_slice(&buf);
+    dst.extend_from_slice(&value.to_le_bytes());
 }
 
+/// Append "value" to "dst" as a varint.
 pub fn extend_varint32(dst: &mut Vec<u8>, value: u32) {
-    let mut buf = [0u8; 5];
-    let len = encode_varint32(&mut buf, value);
-    dst.extend_from_slice(&buf[..len]);
+    extend_varint64(dst, value as u64)
 }
 
+/// Append "value" to "dst" as a varint.
 pub fn extend_varint64(dst: &mut Vec<u8>, value: u64) {
-    let mut buf = [0u8; 10];
-    let len = encode_varint64(&mut buf, value);
+    let mut buf = [0u8; MAX_VARINT64_LENGTH];
+    // The buffer always has room for the longest varint
+    let len = encode_varint64(&mut buf, value).unwrap();
     dst.extend_from_slice(&buf[..len]);
 }
 
+/// Append "value" to "dst" prefixed with its length as a varint32.
 pub fn extend_size_prefixed_slice(dst: &mut Vec<u8>, value: &[u8]) {
     extend_varint32(dst, value.len() as u32);
     dst.extend_from_slice(value);
 }
 
-/// Return Some(result, remain) if success
+/// Decode a size-prefixed slice from the front of "input".
+/// Return Some(slice, bytes consumed), or None if "input" is truncated.
 pub fn decode_size_prefixed_slice(input: &[u8]) -> Option<(&[u8], usize)> {
     let (len, offset) = decode_varint32(input)?;
     if offset + len as usize <= input.len() {
@@ -140,6 +169,28 @@ pub fn decode_size_prefixed_slice(input: &[u8]) -> Option<(&[u8], usize)> {
     }
 }
 
+/// Decode a varint32 from the front of "input" and advance "input" past it.
+pub fn get_varint32(input: &mut &[u8]) -> Option<u32> {
+    let (value, len) = decode_varint32(input)?;
+    *input = &input[len..];
+    Some(value)
+}
+
+/// Decode a varint64 from the front of "input" and advance "input" past it.
+pub fn get_varint64(input: &mut &[u8]) -> Option<u64> {
+    let (value, len) = decode_varint64(input)?;
+    *input = &input[len..];
+    Some(value)
+}
+
+/// Decode a size-prefixed slice from the front of "input" and advance
+/// "input" past it.
+pub fn get_size_prefixed_slice<'a>(input: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let (value, len) = decode_size_prefixed_slice(input)?;
+    *input = &input[len..];
+    Some(value)
+}
+
 /// Get byte size of a varint
 pub fn varint_size(mut value: u64) -> usize {
     let mut len = 1;
@@ -176,7 +227,7 @@ mod tests {
         for power in 0..63 {
             let v = 1u64 << power;
             extend_fixed64(&mut s, v - 1);
-            extend_fixed64(&mut s, v + 0);
+            extend_fixed64(&mut s, v);
             extend_fixed64(&mut s, v + 1);
         }
 
@@ -187,7 +238,7 @@ mod tests {
             assert_eq!(v - 1, actual);
             i += size_of::<u64>();
             let actual = decode_fixed64(&s[i..i + size_of::<u64>()]);
-            assert_eq!(v + 0, actual);
+            assert_eq!(v, actual);
             i += size_of::<u64>();
             let actual = decode_fixed64(&s[i..i + size_of::<u64>()]);
             assert_eq!(v + 1, actual);
@@ -239,12 +290,8 @@ mod tests {
     #[test]
     fn test_coding_varint64() {
         // Construct the list of values to check
-        let mut values = vec![];
         // Some special values
-        values.push(0);
-        values.push(100);
-        values.push(u64::MAX);
-        values.push(u64::MAX - 1);
+        let mut values = vec![0, 100, u64::MAX, u64::MAX - 1];
         for k in 0..64 {
             // Test values near powers of two
             let power = 1 << k;
@@ -308,27 +355,49 @@ mod tests {
     #[test]
     fn test_coding_strings() {
         let mut s = vec![];
-        extend_size_prefixed_slice(&mut s, &"".as_bytes());
-        extend_size_prefixed_slice(&mut s, &"foo".as_bytes());
-        extend_size_prefixed_slice(&mut s, &"bar".as_bytes());
-        extend_size_prefixed_slice(&mut s, &(vec![b'x'; 200].as_slice()));
+        extend_size_prefixed_slice(&mut s, "".as_bytes());
+        extend_size_prefixed_slice(&mut s, "foo".as_bytes());
+        extend_size_prefixed_slice(&mut s, "bar".as_bytes());
+        extend_size_prefixed_slice(&mut s, vec![b'x'; 200].as_slice());
 
         let mut offset = 0;
         let (result, len) = decode_size_prefixed_slice(&s[offset..]).unwrap();
         offset += len;
-        assert_eq!(str::from_utf8(&result).unwrap(), "");
+        assert_eq!(str::from_utf8(result).unwrap(), "");
         let (result, len) = decode_size_prefixed_slice(&s[offset..]).unwrap();
         offset += len;
-        assert_eq!(str::from_utf8(&result).unwrap(), "foo");
+        assert_eq!(str::from_utf8(result).unwrap(), "foo");
         let (result, len) = decode_size_prefixed_slice(&s[offset..]).unwrap();
         offset += len;
-        assert_eq!(str::from_utf8(&result).unwrap(), "bar");
+        assert_eq!(str::from_utf8(result).unwrap(), "bar");
         let (result, len) = decode_size_prefixed_slice(&s[offset..]).unwrap();
         offset += len;
         assert_eq!(
-            str::from_utf8(&result).unwrap(),
-            str::from_utf8(&vec![b'x'; 200]).unwrap()
+            str::from_utf8(result).unwrap(),
+            str::from_utf8(&[b'x'; 200]).unwrap()
         );
         assert_eq!(offset, s.len())
     }
+
+    #[test]
+    fn test_coding_encode_short_buffer() {
+        let mut buf = [0u8; MAX_VARINT64_LENGTH];
+        assert!(encode_fixed32(&mut buf[..3], 1).is_err());
+        assert!(encode_fixed64(&mut buf[..7], 1).is_err());
+        assert!(encode_varint32(&mut buf[..1], 1 << 7).is_err());
+        assert!(encode_varint64(&mut buf[..9], u64::MAX).is_err());
+        assert!(encode_varint32(&mut [], 0).is_err());
+
+        assert_eq!(4, encode_fixed32(&mut buf, 1).unwrap());
+        assert_eq!(8, encode_fixed64(&mut buf, 1).unwrap());
+        assert_eq!(
+            MAX_VARINT32_LENGTH,
+            encode_varint32(&mut buf, u32::MAX).unwrap()
+        );
+        assert_eq!(
+            MAX_VARINT64_LENGTH,
+            encode_varint64(&mut buf, u64::MAX).unwrap()
+        );
+        assert_eq!(Some((u64::MAX, MAX_VARINT64_LENGTH)), decode_varint64(&buf));
+    }
 }
@@ -0,0 +1,28 @@
+/// What a CompactionFilter decides for a value
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CompactionDecision {
+    /// Write the value out unchanged
+    Keep,
+    /// Delete the key, as if by a deletion written along with the value
+    Remove,
+    /// Replace the value with this one
+    ChangeValue(Vec<u8>),
+}
+
+/// A CompactionFilter looks at the values compactions rewrite and may
+/// remove or change them, e.g. to expire old entries, to collect rows
+/// that were only marked as deleted, or to migrate values to a new
+/// format lazily.
+///
+/// Only the newest value of a key is passed, and only if no snapshot
+/// sees it, so snapshots keep reading what they did.  Deletions and merge
+/// operands are not passed, and neither are values that stay in the
+/// memtable or are flushed.
+pub trait CompactionFilter: Send + Sync {
+    /// The name of the filter
+    fn name(&self) -> &str;
+
+    /// Decide what to do with "existing_value" of "key", which a
+    /// compaction of "level" into the next level is about to write out.
+    fn filter(&self, level: usize, key: &[u8], existing_value: &[u8]) -> CompactionDecision;
+}
@@ -1,7 +1,8 @@
-use std::cmp::Ordering;
+use std::{cmp::Ordering, sync::Arc};
 
-/// Used for slice compare with custom order
-pub trait Comparator {
+/// Used for slice compare with custom order.  A Comparator may be shared
+/// between threads, so implementations must be thread-safe.
+pub trait Comparator: Send + Sync {
     fn compare(&self, a: &[u8], b: &[u8]) -> Ordering;
     fn name(&self) -> &str;
     fn find_shortest_separator(&self, start: &[u8], _limit: &[u8]) -> Vec<u8> {
@@ -14,6 +15,12 @@ pub trait Comparator {
 
 pub struct BytewiseComparator {}
 
+impl Default for BytewiseComparator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl BytewiseComparator {
     pub fn new() -> Self {
         Self {}
@@ -36,7 +43,7 @@ impl Comparator for BytewiseComparator {
         if let Some(diff_index) = diff_index {
             let diff_byte = result[diff_index];
             if diff_byte < u8::MAX && diff_byte + 1 < limit[diff_index] {
-                result[diff_index] = result[diff_index] + 1;
+                result[diff_index] += 1;
                 result.resize(diff_index + 1, Default::default());
             }
         }
@@ -47,9 +54,314 @@ impl Comparator for BytewiseComparator {
         let mut result = key.to_vec();
         let index = (0..result.len()).find(|&index| result[index] != 0xff);
         if let Some(index) = index {
-            result[index] = result[index] + 1;
+            result[index] += 1;
             result.resize(index + 1, Default::default());
         }
         result
     }
 }
+
+/// Orders keys the opposite way of BytewiseComparator, e.g. to iterate
+/// over time-stamped keys newest first.
+#[derive(Default)]
+pub struct ReverseBytewiseComparator {}
+
+impl ReverseBytewiseComparator {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl Comparator for ReverseBytewiseComparator {
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        b.cmp(a)
+    }
+
+    fn name(&self) -> &str {
+        "rebeldb.ReverseBytewiseComparator"
+    }
+
+    fn find_shortest_separator(&self, start: &[u8], limit: &[u8]) -> Vec<u8> {
+        // "start" is above "limit" byte-wise, so the prefix of "start" up
+        // to and including the first byte where they differ is too.
+        let min_length = start.len().min(limit.len());
+        let diff_index = (0..min_length)
+            .find(|&index| start[index] != limit[index])
+            .unwrap_or(min_length);
+        if diff_index < start.len()
+            && (diff_index == limit.len() || start[diff_index] > limit[diff_index])
+        {
+            start[..diff_index + 1].to_vec()
+        } else {
+            // Do not shorten if one string is a prefix of the other
+            start.to_vec()
+        }
+    }
+
+    fn find_short_successor(&self, _key: &[u8]) -> Vec<u8> {
+        // The empty key comes after every other key
+        vec![]
+    }
+}
+
+/// Splits keys into a prefix of a fixed length and the rest, and orders
+/// them by prefix with one comparator and then by the rest with another.
+/// A key shorter than the prefix length is all prefix.
+pub struct CompositeComparator {
+    prefix_len: usize,
+    prefix: Arc<dyn Comparator>,
+    suffix: Arc<dyn Comparator>,
+    name: String,
+}
+
+impl CompositeComparator {
+    pub fn new(
+        prefix_len: usize,
+        prefix: Arc<dyn Comparator>,
+        suffix: Arc<dyn Comparator>,
+    ) -> Self {
+        let name = format!(
+            "rebeldb.CompositeComparator({},{},{})",
+            prefix_len,
+            prefix.name(),
+            suffix.name()
+        );
+        Self {
+            prefix_len,
+            prefix,
+            suffix,
+            name,
+        }
+    }
+
+    fn split<'a>(&self, key: &'a [u8]) -> (&'a [u8], &'a [u8]) {
+        key.split_at(key.len().min(self.prefix_len))
+    }
+
+    /// Whether "prefix" alone is a key that sorts after any key with
+    /// prefix "after", which holds if the prefix comparator orders it
+    /// above "after" and it is not longer than a prefix.
+    fn prefix_above(&self, prefix: &[u8], after: &[u8]) -> bool {
+        prefix.len() <= self.prefix_len && self.prefix.compare(prefix, after) == Ordering::Greater
+    }
+}
+
+impl Comparator for CompositeComparator {
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        let (a_prefix, a_suffix) = self.split(a);
+        let (b_prefix, b_suffix) = self.split(b);
+        self.prefix
+            .compare(a_prefix, b_prefix)
+            .then_with(|| self.suffix.compare(a_suffix, b_suffix))
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn find_shortest_separator(&self, start: &[u8], limit: &[u8]) -> Vec<u8> {
+        let (start_prefix, start_suffix) = self.split(start);
+        let (limit_prefix, limit_suffix) = self.split(limit);
+        match self.prefix.compare(start_prefix, limit_prefix) {
+            Ordering::Less => {
+                let separator = self
+                    .prefix
+                    .find_shortest_separator(start_prefix, limit_prefix);
+                if self.prefix_above(&separator, start_prefix) {
+                    return separator;
+                }
+            }
+            Ordering::Equal if start_prefix.len() == self.prefix_len => {
+                let mut separator = start_prefix.to_vec();
+                separator.extend(
+                    self.suffix
+                        .find_shortest_separator(start_suffix, limit_suffix),
+                );
+                return separator;
+            }
+            _ => {}
+        }
+        start.to_vec()
+    }
+
+    fn find_short_successor(&self, key: &[u8]) -> Vec<u8> {
+        let (prefix, suffix) = self.split(key);
+        let successor = self.prefix.find_short_successor(prefix);
+        if self.prefix_above(&successor, prefix) {
+            return successor;
+        }
+        if prefix.len() < self.prefix_len {
+            return key.to_vec();
+        }
+        let mut successor = prefix.to_vec();
+        successor.extend(self.suffix.find_short_successor(suffix));
+        successor
+    }
+}
+
+/// Orders keys that are 8-byte big-endian integers numerically.  That is
+/// the byte-wise order, which also holds for the shorter keys that the
+/// separators and successors may be: such a key is the number its bytes
+/// make when padded with zeros, and sorts right before the 8-byte key of
+/// that number.  Unlike the byte-wise separators, those of this
+/// comparator carry over into higher bytes: the separator of 0x00ffffff
+/// and 0x01000001 is 0x01.  Keys longer than 8 bytes are compared and
+/// separated byte-wise.
+#[derive(Default)]
+pub struct U64Comparator {}
+
+impl U64Comparator {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+/// The number "key" makes when padded with zeros to 8 bytes
+fn padded_u64(key: &[u8]) -> u64 {
+    let mut bytes = [0; 8];
+    bytes[..key.len()].copy_from_slice(key);
+    u64::from_be_bytes(bytes)
+}
+
+impl Comparator for U64Comparator {
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        a.cmp(b)
+    }
+
+    fn name(&self) -> &str {
+        "rebeldb.U64Comparator"
+    }
+
+    fn find_shortest_separator(&self, start: &[u8], limit: &[u8]) -> Vec<u8> {
+        if start.len() > 8 || limit.len() > 8 {
+            return BytewiseComparator::new().find_shortest_separator(start, limit);
+        }
+        let (start_value, limit_value) = (padded_u64(start), padded_u64(limit));
+        // Find the number above start and below limit with the most
+        // trailing zero bytes, which are left out of the separator.  The
+        // separator has to be above start, since leaving out the zeros of
+        // start itself would make a key that sorts before it.
+        for zero_bytes in (0..8).rev() {
+            let unit = 1u64 << (8 * zero_bytes);
+            let value = match (start_value / unit + 1).checked_mul(unit) {
+                Some(value) if value < limit_value => value,
+                _ => continue,
+            };
+            let len = 8 - zero_bytes;
+            if len < start.len() {
+                return value.to_be_bytes()[..len].to_vec();
+            }
+            break;
+        }
+        start.to_vec()
+    }
+
+    fn find_short_successor(&self, key: &[u8]) -> Vec<u8> {
+        // The shortest key at or after a number is the byte-wise one
+        BytewiseComparator::new().find_short_successor(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::Random;
+
+    fn random_key(rnd: &mut Random) -> Vec<u8> {
+        let len = rnd.uniform(8) as usize;
+        // Few distinct bytes, so that keys often share prefixes
+        (0..len)
+            .map(|_| [0x00, b'a', b'b', b'c', 0xfe, 0xff][rnd.uniform(6) as usize])
+            .collect()
+    }
+
+    /// Check the separators and successors of "cmp" for random keys.
+    fn check_comparator(cmp: &dyn Comparator) {
+        let mut rnd = Random::new(301);
+        for _ in 0..10000 {
+            let (mut start, mut limit) = (random_key(&mut rnd), random_key(&mut rnd));
+            if cmp.compare(&start, &limit) == Ordering::Greater {
+                std::mem::swap(&mut start, &mut limit);
+            }
+            let separator = cmp.find_shortest_separator(&start, &limit);
+            assert_ne!(Ordering::Less, cmp.compare(&separator, &start));
+            if cmp.compare(&start, &limit) == Ordering::Less {
+                assert_eq!(Ordering::Less, cmp.compare(&separator, &limit));
+            }
+            let successor = cmp.find_short_successor(&start);
+            assert_ne!(Ordering::Less, cmp.compare(&successor, &start));
+        }
+    }
+
+    #[test]
+    fn test_reverse_bytewise_comparator() {
+        let cmp = ReverseBytewiseComparator::new();
+        assert_eq!(Ordering::Less, cmp.compare(b"b", b"a"));
+        assert_eq!(Ordering::Less, cmp.compare(b"ab", b"a"));
+        assert_eq!(b"e".to_vec(), cmp.find_shortest_separator(b"efgh", b"abc"));
+        assert_eq!(
+            b"abd".to_vec(),
+            cmp.find_shortest_separator(b"abde", b"abc")
+        );
+        assert_eq!(b"abc".to_vec(), cmp.find_shortest_separator(b"abc", b"ab"));
+        check_comparator(&cmp);
+    }
+
+    #[test]
+    fn test_composite_comparator() {
+        // Ascending by a 2-byte prefix, then descending
+        let cmp = CompositeComparator::new(
+            2,
+            Arc::new(BytewiseComparator::new()),
+            Arc::new(ReverseBytewiseComparator::new()),
+        );
+        assert_eq!(Ordering::Less, cmp.compare(b"aa9", b"ab1"));
+        assert_eq!(Ordering::Less, cmp.compare(b"ab9", b"ab1"));
+        assert_eq!(Ordering::Less, cmp.compare(b"ab1", b"ab"));
+        assert_eq!(Ordering::Less, cmp.compare(b"a", b"aa"));
+        assert_eq!(b"ab".to_vec(), cmp.find_shortest_separator(b"aa9", b"ac1"));
+        assert_eq!(
+            b"ab8".to_vec(),
+            cmp.find_shortest_separator(b"ab8xyz", b"ab1")
+        );
+        assert_eq!(b"b".to_vec(), cmp.find_short_successor(b"ab1"));
+        assert_eq!(
+            "rebeldb.CompositeComparator(2,leveldb.BytewiseComparator,rebeldb.ReverseBytewiseComparator)",
+            cmp.name()
+        );
+        check_comparator(&cmp);
+
+        let cmp = CompositeComparator::new(
+            3,
+            Arc::new(ReverseBytewiseComparator::new()),
+            Arc::new(BytewiseComparator::new()),
+        );
+        check_comparator(&cmp);
+    }
+
+    #[test]
+    fn test_u64_comparator() {
+        let cmp = U64Comparator::new();
+        let key = |value: u64| value.to_be_bytes();
+        assert_eq!(Ordering::Less, cmp.compare(&key(255), &key(256)));
+        assert_eq!(
+            vec![0, 0, 0, 0, 1],
+            cmp.find_shortest_separator(&key(0xff_ffff), &key(0x100_0001))
+        );
+        // The byte-wise separator can't carry, and leaves the key as is
+        assert_eq!(
+            key(0xff_ffff).to_vec(),
+            BytewiseComparator::new().find_shortest_separator(&key(0xff_ffff), &key(0x100_0001))
+        );
+        assert_eq!(
+            vec![0, 0, 0, 0, 0, 0, 2],
+            cmp.find_shortest_separator(&key(0x1ff), &key(0x300))
+        );
+        assert_eq!(
+            key(0x100).to_vec(),
+            cmp.find_shortest_separator(&key(0x100), &key(0x101))
+        );
+        assert_eq!(vec![1], cmp.find_short_successor(&key(5)));
+        check_comparator(&cmp);
+    }
+}
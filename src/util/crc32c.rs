@@ -6,12 +6,12 @@ pub use crc32c::{crc32c, crc32c_append};
 //. contains embedded CRCs.  Therefore we recommend that CRCs stored
 //. somewhere (e.g., in files) should be masked before being stored.
 pub fn crc32c_mask(crc: u32) -> u32 {
-    ((crc >> 15) | (crc << 17)).wrapping_add(MASK_DELTA)
+    crc.rotate_right(15).wrapping_add(MASK_DELTA)
 }
 
 pub fn crc32c_unmask(masked_crc: u32) -> u32 {
     let rot = masked_crc.wrapping_sub(MASK_DELTA);
-    (rot >> 17) | (rot << 15)
+    rot.rotate_left(15)
 }
 
 #[cfg(test)]
@@ -11,20 +11,20 @@ pub fn hash(data: &[u8], seed: u32) -> u32 {
     while i + 4 <= data.len() {
         let w = decode_fixed32(&data[i..]);
         i += 4;
-        h += w;
+        h = h.wrapping_add(w);
         h = h.wrapping_mul(m);
         h ^= h >> 16;
     }
 
     let diff = data.len() - i;
     if diff >= 3 {
-        h += (data[i + 2] as u32) << 16
+        h = h.wrapping_add((data[i + 2] as u32) << 16)
     };
     if diff >= 2 {
-        h += (data[i + 1] as u32) << 8
+        h = h.wrapping_add((data[i + 1] as u32) << 8)
     };
     if diff >= 1 {
-        h += data[i] as u32;
+        h = h.wrapping_add(data[i] as u32);
         h = h.wrapping_mul(m);
         h ^= h >> r;
     }
@@ -0,0 +1,145 @@
+use super::{decode_fixed64, Error, Result};
+
+/// A MergeOperator combines the operands written with WriteBatch::merge()
+/// into the value of a key, so that updates like adding to a counter or
+/// appending to a list are written without reading the value first.
+/// The operands of a key are only combined when it is read, or when a
+/// compaction reaches the value they apply to.
+pub trait MergeOperator: Send + Sync {
+    /// The name of the operator.  Like the name of a comparator, it
+    /// identifies how the operands are to be combined.
+    fn name(&self) -> &str;
+
+    /// Return the value of "key" obtained by applying "operands", oldest
+    /// first, to "existing_value", which is None if the key had no value
+    /// or was deleted.  An error fails the read or the compaction that
+    /// merged the operands.
+    fn full_merge(
+        &self,
+        key: &[u8],
+        existing_value: Option<&[u8]>,
+        operands: &[Vec<u8>],
+    ) -> Result<Vec<u8>>;
+}
+
+/// Treats values and operands as 64-bit unsigned integers encoded in
+/// fixed-width little-endian, and adds them up, wrapping on overflow.
+/// Counters can then be incremented with a merge of the increment.
+pub struct UInt64AddOperator;
+
+impl UInt64AddOperator {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl Default for UInt64AddOperator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn decode_u64(bytes: &[u8]) -> Result<u64> {
+    if bytes.len() != 8 {
+        return Err(Error::corruption("bad UInt64AddOperator operand"));
+    }
+    Ok(decode_fixed64(bytes))
+}
+
+impl MergeOperator for UInt64AddOperator {
+    fn name(&self) -> &str {
+        "rebeldb.UInt64AddOperator"
+    }
+
+    fn full_merge(
+        &self,
+        _key: &[u8],
+        existing_value: Option<&[u8]>,
+        operands: &[Vec<u8>],
+    ) -> Result<Vec<u8>> {
+        let mut sum = existing_value.map_or(Ok(0), decode_u64)?;
+        for operand in operands {
+            sum = sum.wrapping_add(decode_u64(operand)?);
+        }
+        Ok(sum.to_le_bytes().to_vec())
+    }
+}
+
+/// Appends the operands to the value, with "delimiter" between each
+/// of them, to build a list out of the elements merged one at a time.
+pub struct StringAppendOperator {
+    delimiter: u8,
+}
+
+impl StringAppendOperator {
+    pub fn new(delimiter: u8) -> Self {
+        Self { delimiter }
+    }
+}
+
+impl MergeOperator for StringAppendOperator {
+    fn name(&self) -> &str {
+        "rebeldb.StringAppendOperator"
+    }
+
+    fn full_merge(
+        &self,
+        _key: &[u8],
+        existing_value: Option<&[u8]>,
+        operands: &[Vec<u8>],
+    ) -> Result<Vec<u8>> {
+        let mut value = existing_value.map(<[u8]>::to_vec);
+        for operand in operands {
+            match value.as_mut() {
+                Some(value) => {
+                    value.push(self.delimiter);
+                    value.extend_from_slice(operand);
+                }
+                None => value = Some(operand.clone()),
+            }
+        }
+        Ok(value.unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge_operators() {
+        let add = UInt64AddOperator::new();
+        let operands = vec![2u64.to_le_bytes().to_vec(), 3u64.to_le_bytes().to_vec()];
+        assert_eq!(
+            5u64.to_le_bytes().to_vec(),
+            add.full_merge(b"k", None, &operands).unwrap()
+        );
+        assert_eq!(
+            15u64.to_le_bytes().to_vec(),
+            add.full_merge(b"k", Some(&10u64.to_le_bytes()), &operands)
+                .unwrap()
+        );
+        assert!(add
+            .full_merge(b"k", Some(b"short"), &operands)
+            .unwrap_err()
+            .is_corruption());
+
+        let append = StringAppendOperator::new(b',');
+        let operands = vec![b"b".to_vec(), b"c".to_vec()];
+        assert_eq!(
+            b"b,c",
+            append.full_merge(b"k", None, &operands).unwrap().as_slice()
+        );
+        assert_eq!(
+            b"a,b,c",
+            append
+                .full_merge(b"k", Some(b"a"), &operands)
+                .unwrap()
+                .as_slice()
+        );
+        assert_eq!(
+            b"a",
+            append.full_merge(b"k", Some(b"a"), &[]).unwrap().as_slice()
+        );
+    }
+}
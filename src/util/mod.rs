@@ -1,16 +1,36 @@
-mod coding;
+#[cfg(feature = "bloom")]
+mod bloom;
+pub mod cache;
+pub mod coding;
+mod compaction_filter;
 mod comparator;
 mod crc32c;
 mod filter_policy;
 mod hash;
+mod merge_operator;
+mod partitioner;
+mod prefix_extractor;
 mod random;
+mod rate_limiter;
 mod result;
+mod write_buffer_manager;
 
+#[cfg(feature = "bloom")]
+pub use bloom::BloomFilterPolicy;
+pub use cache::{Cache, ShardedLRUCache};
 pub use coding::*;
-pub use comparator::{BytewiseComparator, Comparator};
-pub use filter_policy::{BloomFilterPolicy, FilterPolicy};
+pub use compaction_filter::{CompactionDecision, CompactionFilter};
+pub use comparator::{
+    BytewiseComparator, Comparator, CompositeComparator, ReverseBytewiseComparator, U64Comparator,
+};
+pub use filter_policy::FilterPolicy;
 pub use hash::hash;
+pub use merge_operator::{MergeOperator, StringAppendOperator, UInt64AddOperator};
+pub use partitioner::{BoundaryPartitioner, OutputPartitioner, PrefixPartitioner};
+pub use prefix_extractor::{FixedPrefixExtractor, PrefixExtractor};
 pub use random::Random;
-pub use result::{Error, Result};
+pub use rate_limiter::RateLimiter;
+pub use result::{Error, ErrorCode, Result};
+pub use write_buffer_manager::WriteBufferManager;
 
 pub use self::crc32c::*;
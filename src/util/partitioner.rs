@@ -0,0 +1,98 @@
+use std::{cmp::Ordering, sync::Arc};
+
+use super::Comparator;
+
+/// An OutputPartitioner tells compactions where to cut their output
+/// files besides where they reach DBConfig::max_file_size, e.g. at the
+/// start of every tenant's keys.  A key range that never shares a file
+/// with other keys can be dropped by deleting whole files, and is read
+/// from files holding nothing else.
+///
+/// Files that a compaction moves to the next level without rewriting
+/// them keep the boundaries they were written with.
+pub trait OutputPartitioner: Send + Sync {
+    /// Return true if "previous" and "key", two consecutive user keys
+    /// written by a compaction, must go to different files.
+    fn should_partition(&self, previous: &[u8], key: &[u8]) -> bool;
+}
+
+/// Cuts the output files at a fixed set of user keys: no file holds
+/// keys from both sides of any of the boundaries.
+pub struct BoundaryPartitioner {
+    comparator: Arc<dyn Comparator>,
+    /// Sorted by "comparator"
+    boundaries: Vec<Vec<u8>>,
+}
+
+impl BoundaryPartitioner {
+    pub fn new(comparator: Arc<dyn Comparator>, mut boundaries: Vec<Vec<u8>>) -> Self {
+        boundaries.sort_by(|a, b| comparator.compare(a, b));
+        Self {
+            comparator,
+            boundaries,
+        }
+    }
+}
+
+impl OutputPartitioner for BoundaryPartitioner {
+    fn should_partition(&self, previous: &[u8], key: &[u8]) -> bool {
+        // Is there a boundary in (previous, key]?
+        let index = self
+            .boundaries
+            .partition_point(|b| self.comparator.compare(b, previous) != Ordering::Greater);
+        self.boundaries
+            .get(index)
+            .is_some_and(|b| self.comparator.compare(b, key) != Ordering::Greater)
+    }
+}
+
+/// Cuts the output files wherever the first "prefix_len" bytes of the
+/// keys change, e.g. to give every tenant of a key space prefixed by
+/// fixed-length tenant ids files of their own.
+pub struct PrefixPartitioner {
+    prefix_len: usize,
+}
+
+impl PrefixPartitioner {
+    pub fn new(prefix_len: usize) -> Self {
+        Self { prefix_len }
+    }
+
+    fn prefix<'a>(&self, key: &'a [u8]) -> &'a [u8] {
+        &key[..key.len().min(self.prefix_len)]
+    }
+}
+
+impl OutputPartitioner for PrefixPartitioner {
+    fn should_partition(&self, previous: &[u8], key: &[u8]) -> bool {
+        self.prefix(previous) != self.prefix(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::BytewiseComparator;
+
+    #[test]
+    fn test_boundary_partitioner() {
+        let partitioner = BoundaryPartitioner::new(
+            Arc::new(BytewiseComparator::new()),
+            vec![b"m".to_vec(), b"d".to_vec()],
+        );
+        assert!(!partitioner.should_partition(b"a", b"c"));
+        assert!(partitioner.should_partition(b"c", b"d"));
+        assert!(!partitioner.should_partition(b"d", b"da"));
+        assert!(partitioner.should_partition(b"c", b"z"));
+        assert!(!partitioner.should_partition(b"m", b"z"));
+    }
+
+    #[test]
+    fn test_prefix_partitioner() {
+        let partitioner = PrefixPartitioner::new(3);
+        assert!(!partitioner.should_partition(b"t01-a", b"t01-b"));
+        assert!(partitioner.should_partition(b"t01-z", b"t02-a"));
+        assert!(partitioner.should_partition(b"t0", b"t01"));
+        assert!(!partitioner.should_partition(b"t", b"t"));
+    }
+}
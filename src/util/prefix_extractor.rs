@@ -0,0 +1,63 @@
+/// A PrefixExtractor maps a user key to its prefix, e.g. the id of the
+/// tenant or table a key belongs to.  Point lookups of keys whose
+/// prefix was never written can then be answered by a filter over the
+/// prefixes, without searching for the key itself.
+pub trait PrefixExtractor: Send + Sync {
+    /// The name of the extractor.  Like the name of a comparator, it
+    /// identifies how the prefixes were computed.
+    fn name(&self) -> &str;
+
+    /// Return whether "key" has a prefix at all.  Keys outside of the
+    /// domain are never filtered.
+    fn in_domain(&self, key: &[u8]) -> bool;
+
+    /// Return the prefix of "key".
+    ///
+    /// REQUIRES: in_domain(key)
+    fn transform<'a>(&self, key: &'a [u8]) -> &'a [u8];
+}
+
+/// Takes the first "prefix_len" bytes of the keys as their prefix.
+/// Shorter keys have no prefix.
+pub struct FixedPrefixExtractor {
+    prefix_len: usize,
+    name: String,
+}
+
+impl FixedPrefixExtractor {
+    pub fn new(prefix_len: usize) -> Self {
+        Self {
+            prefix_len,
+            name: format!("rebeldb.FixedPrefix.{}", prefix_len),
+        }
+    }
+}
+
+impl PrefixExtractor for FixedPrefixExtractor {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn in_domain(&self, key: &[u8]) -> bool {
+        key.len() >= self.prefix_len
+    }
+
+    fn transform<'a>(&self, key: &'a [u8]) -> &'a [u8] {
+        &key[..self.prefix_len]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fixed_prefix_extractor() {
+        let extractor = FixedPrefixExtractor::new(3);
+        assert_eq!("rebeldb.FixedPrefix.3", extractor.name());
+        assert!(!extractor.in_domain(b"ab"));
+        assert!(extractor.in_domain(b"abc"));
+        assert_eq!(b"abc", extractor.transform(b"abc"));
+        assert_eq!(b"abc", extractor.transform(b"abcdef"));
+    }
+}
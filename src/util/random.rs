@@ -24,14 +24,16 @@ impl Random {
         self.seed
     }
 
+    #[cfg(test)]
     pub fn uniform(&mut self, n: u32) -> u32 {
         self.next() % n
     }
 
     pub fn one_in(&mut self, n: u32) -> bool {
-        (self.next() % n) == 0
+        self.next().is_multiple_of(n)
     }
 
+    #[cfg(test)]
     pub fn skewed(&mut self, max_log: u32) -> u32 {
         let tmp = 1 << self.uniform(max_log + 1);
         self.uniform(tmp)
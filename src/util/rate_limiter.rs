@@ -0,0 +1,235 @@
+use std::{
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Tokens are handed out in refills of this period's worth of bytes, so
+/// a single request never holds the bucket for longer than this.
+const REFILL_PERIOD: Duration = Duration::from_millis(100);
+
+/// An auto-tuned limiter never goes below this fraction of its maximum rate.
+const AUTO_TUNE_MIN_RATE_DIVISOR: u64 = 20;
+
+/// Debt that background I/O could not clear within this many seconds at
+/// the current rate means compactions are falling behind.
+const DEBT_HIGH_SECONDS: u64 = 10;
+
+/// Debt that is cleared within this many seconds means the rate can be
+/// lowered without risking stalls.
+const DEBT_LOW_SECONDS: u64 = 1;
+
+/// Factors applied by tune() when raising and lowering the rate.  The rate
+/// goes up quickly to get rid of stalls and comes down slowly.
+const RATE_INCREASE: f64 = 1.25;
+const RATE_DECREASE: f64 = 0.95;
+
+struct State {
+    bytes_per_second: u64,
+    max_bytes_per_second: u64,
+    available_bytes: f64,
+    last_refill: Instant,
+    total_bytes_through: u64,
+}
+
+impl State {
+    fn burst_bytes(&self) -> f64 {
+        (self.bytes_per_second as f64 * REFILL_PERIOD.as_secs_f64()).max(1.)
+    }
+
+    fn min_bytes_per_second(&self) -> u64 {
+        (self.max_bytes_per_second / AUTO_TUNE_MIN_RATE_DIVISOR).max(1)
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.last_refill = now;
+        self.available_bytes = (self.available_bytes
+            + elapsed.as_secs_f64() * self.bytes_per_second as f64)
+            .min(self.burst_bytes());
+    }
+}
+
+/// A RateLimiter bounds the bandwidth of background I/O (flushes and
+/// compactions) so that it does not starve foreground reads and writes.
+/// It is a token bucket refilled at bytes_per_second().
+///
+/// An auto-tuned limiter starts at its maximum rate and is adjusted by
+/// the database through tune(): the rate goes up while writes stall or
+/// compaction debt piles up, and down while background work keeps up
+/// easily.  It always stays within [max / 20, max].
+///
+/// A RateLimiter may be shared by several databases.
+pub struct RateLimiter {
+    state: Mutex<State>,
+    auto_tuned: bool,
+}
+
+impl RateLimiter {
+    /// Create a limiter with a fixed rate of "bytes_per_second".
+    pub fn new(bytes_per_second: u64) -> Self {
+        Self::with_mode(bytes_per_second, false)
+    }
+
+    /// Create a limiter whose rate is tuned between
+    /// max_bytes_per_second / 20 and max_bytes_per_second.
+    pub fn new_auto_tuned(max_bytes_per_second: u64) -> Self {
+        Self::with_mode(max_bytes_per_second, true)
+    }
+
+    fn with_mode(bytes_per_second: u64, auto_tuned: bool) -> Self {
+        assert!(bytes_per_second > 0);
+        Self {
+            state: Mutex::new(State {
+                bytes_per_second,
+                max_bytes_per_second: bytes_per_second,
+                available_bytes: 0.,
+                last_refill: Instant::now(),
+                total_bytes_through: 0,
+            }),
+            auto_tuned,
+        }
+    }
+
+    /// Block until "bytes" may be written.
+    pub fn request(&self, bytes: usize) {
+        let mut remaining = bytes as u64;
+        while remaining > 0 {
+            let mut state = self.state.lock().unwrap();
+            state.refill(Instant::now());
+
+            // Requests larger than a refill are granted piecewise
+            let chunk = remaining.min(state.burst_bytes() as u64);
+            if state.available_bytes >= chunk as f64 {
+                state.available_bytes -= chunk as f64;
+                state.total_bytes_through += chunk;
+                remaining -= chunk;
+                continue;
+            }
+
+            let wait = (chunk as f64 - state.available_bytes) / state.bytes_per_second as f64;
+            drop(state);
+            thread::sleep(Duration::from_secs_f64(wait));
+        }
+    }
+
+    /// The current rate.
+    pub fn bytes_per_second(&self) -> u64 {
+        self.state.lock().unwrap().bytes_per_second
+    }
+
+    /// Change the rate.  For an auto-tuned limiter this changes the
+    /// maximum rate and the current rate is clamped to the new range.
+    pub fn set_bytes_per_second(&self, bytes_per_second: u64) {
+        assert!(bytes_per_second > 0);
+        let mut state = self.state.lock().unwrap();
+        state.max_bytes_per_second = bytes_per_second;
+        state.bytes_per_second = if self.auto_tuned {
+            state
+                .bytes_per_second
+                .clamp(state.min_bytes_per_second(), bytes_per_second)
+        } else {
+            bytes_per_second
+        };
+    }
+
+    /// Whether tune() adjusts the rate.
+    pub fn is_auto_tuned(&self) -> bool {
+        self.auto_tuned
+    }
+
+    /// Total number of bytes granted by request().
+    pub fn total_bytes_through(&self) -> u64 {
+        self.state.lock().unwrap().total_bytes_through
+    }
+
+    /// Feed back how the background work is doing.  "stall_time" is how
+    /// long foreground writes were blocked on background work since the
+    /// previous call and "pending_compaction_bytes" is the current
+    /// compaction debt.  Does nothing unless the limiter is auto-tuned.
+    pub fn tune(&self, stall_time: Duration, pending_compaction_bytes: u64) {
+        if !self.auto_tuned {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let rate = state.bytes_per_second;
+        let new_rate = if !stall_time.is_zero()
+            || pending_compaction_bytes > rate.saturating_mul(DEBT_HIGH_SECONDS)
+        {
+            (rate as f64 * RATE_INCREASE).ceil() as u64
+        } else if pending_compaction_bytes < rate.saturating_mul(DEBT_LOW_SECONDS) {
+            (rate as f64 * RATE_DECREASE) as u64
+        } else {
+            rate
+        };
+        state.bytes_per_second =
+            new_rate.clamp(state.min_bytes_per_second(), state.max_bytes_per_second);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter_request() {
+        let limiter = RateLimiter::new(1 << 20);
+        let start = Instant::now();
+        // The bucket starts empty, so 200KB take at least ~200ms
+        for _ in 0..20 {
+            limiter.request(10 << 10);
+        }
+        assert!(start.elapsed() >= Duration::from_millis(150));
+        assert_eq!(200 << 10, limiter.total_bytes_through());
+
+        // Requests larger than a refill are split up
+        limiter.request(300 << 10);
+        assert_eq!(500 << 10, limiter.total_bytes_through());
+    }
+
+    #[test]
+    fn test_rate_limiter_fixed_rate_ignores_tune() {
+        let limiter = RateLimiter::new(1000);
+        assert!(!limiter.is_auto_tuned());
+        limiter.tune(Duration::from_secs(1), u64::MAX);
+        assert_eq!(1000, limiter.bytes_per_second());
+    }
+
+    #[test]
+    fn test_rate_limiter_auto_tune() {
+        let limiter = RateLimiter::new_auto_tuned(100 << 20);
+        assert_eq!(100 << 20, limiter.bytes_per_second());
+
+        // Background work keeps up: the rate decays to the minimum
+        for _ in 0..200 {
+            limiter.tune(Duration::ZERO, 0);
+        }
+        assert_eq!(5 << 20, limiter.bytes_per_second());
+
+        // Debt within [1s, 10s] of I/O keeps the rate
+        limiter.tune(Duration::ZERO, 20 << 20);
+        assert_eq!(5 << 20, limiter.bytes_per_second());
+
+        // Stalls raise it
+        limiter.tune(Duration::from_millis(10), 0);
+        let raised = limiter.bytes_per_second();
+        assert!(raised > 5 << 20);
+
+        // So does debt that would take too long to clear
+        limiter.tune(Duration::ZERO, 1 << 40);
+        assert!(limiter.bytes_per_second() > raised);
+        for _ in 0..100 {
+            limiter.tune(Duration::ZERO, 1 << 40);
+        }
+        assert_eq!(100 << 20, limiter.bytes_per_second());
+
+        // Lowering the maximum clamps the current rate
+        limiter.set_bytes_per_second(10 << 20);
+        assert_eq!(10 << 20, limiter.bytes_per_second());
+        for _ in 0..200 {
+            limiter.tune(Duration::ZERO, 0);
+        }
+        assert_eq!(512 << 10, limiter.bytes_per_second());
+    }
+}
@@ -1,23 +1,57 @@
-// TODO: refactor this later
 use std::{error, fmt::Display};
 
-#[derive(Debug, Clone, PartialEq)]
-enum Code {
+use super::{
+    decode_size_prefixed_slice, decode_varint64, extend_size_prefixed_slice, extend_varint64,
+};
+
+/// The kind of an Error.  The numeric values are stable, so that they
+/// can be handed across an FFI or network boundary.
+///
+/// NOTE: do not change the values of existing entries.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ErrorCode {
     NotFound = 1,
     Corruption = 2,
     NotSupported = 3,
     InvalidArgument = 4,
     IOError = 5,
+    TimedOut = 6,
+    Cancelled = 7,
+    Expired = 8,
+    /// A write conflicts with another one, e.g. of a transaction
+    Busy = 9,
+}
+
+impl ErrorCode {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(ErrorCode::NotFound),
+            2 => Some(ErrorCode::Corruption),
+            3 => Some(ErrorCode::NotSupported),
+            4 => Some(ErrorCode::InvalidArgument),
+            5 => Some(ErrorCode::IOError),
+            6 => Some(ErrorCode::TimedOut),
+            7 => Some(ErrorCode::Cancelled),
+            8 => Some(ErrorCode::Expired),
+            9 => Some(ErrorCode::Busy),
+            _ => None,
+        }
+    }
 }
 
-impl Display for Code {
+impl Display for ErrorCode {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let str = match self {
-            Code::NotFound => "NotFound",
-            Code::Corruption => "Corruption",
-            Code::NotSupported => "NotSupported",
-            Code::InvalidArgument => "InvalidArgument",
-            Code::IOError => "IOError",
+            ErrorCode::NotFound => "NotFound",
+            ErrorCode::Corruption => "Corruption",
+            ErrorCode::NotSupported => "NotSupported",
+            ErrorCode::InvalidArgument => "InvalidArgument",
+            ErrorCode::IOError => "IOError",
+            ErrorCode::TimedOut => "TimedOut",
+            ErrorCode::Cancelled => "Cancelled",
+            ErrorCode::Expired => "Expired",
+            ErrorCode::Busy => "Busy",
         };
         f.write_str(str)
     }
@@ -25,70 +59,196 @@ impl Display for Code {
 
 #[derive(Debug, Clone)]
 pub struct Error {
-    code: Code,
+    code: ErrorCode,
     msg: String,
+    /// The file and the offset in it the error was found at, if known
+    context: Option<(String, u64)>,
 }
 
 impl Error {
     pub fn not_found(msg: &str) -> Self {
         Self {
-            code: Code::NotFound,
+            code: ErrorCode::NotFound,
             msg: msg.to_owned(),
+            context: None,
         }
     }
 
     pub fn corruption(msg: &str) -> Self {
         Self {
-            code: Code::Corruption,
+            code: ErrorCode::Corruption,
             msg: msg.to_owned(),
+            context: None,
         }
     }
 
     pub fn not_supported(msg: &str) -> Self {
         Self {
-            code: Code::NotSupported,
+            code: ErrorCode::NotSupported,
             msg: msg.to_owned(),
+            context: None,
         }
     }
 
     pub fn invalid_argument(msg: &str) -> Self {
         Self {
-            code: Code::InvalidArgument,
+            code: ErrorCode::InvalidArgument,
             msg: msg.to_owned(),
+            context: None,
         }
     }
 
     pub fn io_error(msg: &str) -> Self {
         Self {
-            code: Code::IOError,
+            code: ErrorCode::IOError,
+            msg: msg.to_owned(),
+            context: None,
+        }
+    }
+
+    pub fn timed_out(msg: &str) -> Self {
+        Self {
+            code: ErrorCode::TimedOut,
+            msg: msg.to_owned(),
+            context: None,
+        }
+    }
+
+    pub fn cancelled(msg: &str) -> Self {
+        Self {
+            code: ErrorCode::Cancelled,
             msg: msg.to_owned(),
+            context: None,
+        }
+    }
+
+    pub fn expired(msg: &str) -> Self {
+        Self {
+            code: ErrorCode::Expired,
+            msg: msg.to_owned(),
+            context: None,
+        }
+    }
+
+    pub fn busy(msg: &str) -> Self {
+        Self {
+            code: ErrorCode::Busy,
+            msg: msg.to_owned(),
+            context: None,
+        }
+    }
+
+    pub fn code(&self) -> ErrorCode {
+        self.code
+    }
+
+    pub fn message(&self) -> &str {
+        &self.msg
+    }
+
+    /// Record that the error was found at "offset" in "file".
+    pub fn with_context(mut self, file: &str, offset: u64) -> Self {
+        self.context = Some((file.to_owned(), offset));
+        self
+    }
+
+    /// The file the error was found in, if known.
+    pub fn file(&self) -> Option<&str> {
+        self.context.as_ref().map(|(file, _)| file.as_str())
+    }
+
+    /// The offset in file() the error was found at, if known.
+    pub fn offset(&self) -> Option<u64> {
+        self.context.as_ref().map(|(_, offset)| *offset)
+    }
+
+    /// Append the wire representation of the error to "dst": the code
+    /// (1 byte), the size-prefixed message, and a flag byte followed by
+    /// the size-prefixed file name and the varint64 offset if the error
+    /// has a context.
+    pub fn encode_to(&self, dst: &mut Vec<u8>) {
+        dst.push(self.code as u8);
+        extend_size_prefixed_slice(dst, self.msg.as_bytes());
+        match &self.context {
+            Some((file, offset)) => {
+                dst.push(1);
+                extend_size_prefixed_slice(dst, file.as_bytes());
+                extend_varint64(dst, *offset);
+            }
+            None => dst.push(0),
         }
     }
 
+    /// Decode an error written by encode_to() from the front of "input".
+    /// Returns the error and the number of bytes it took, or None if the
+    /// input is malformed.
+    pub fn decode_from(input: &[u8]) -> Option<(Self, usize)> {
+        let code = ErrorCode::from_u8(*input.first()?)?;
+        let mut index = 1;
+        let (msg, len) = decode_size_prefixed_slice(&input[index..])?;
+        index += len;
+        let msg = String::from_utf8(msg.to_vec()).ok()?;
+        let flag = *input.get(index)?;
+        index += 1;
+        let context = match flag {
+            0 => None,
+            1 => {
+                let (file, len) = decode_size_prefixed_slice(&input[index..])?;
+                index += len;
+                let file = String::from_utf8(file.to_vec()).ok()?;
+                let (offset, len) = decode_varint64(&input[index..])?;
+                index += len;
+                Some((file, offset))
+            }
+            _ => return None,
+        };
+        Some((Self { code, msg, context }, index))
+    }
+
     pub fn is_not_found(&self) -> bool {
-        self.code == Code::NotFound
+        self.code == ErrorCode::NotFound
     }
 
     pub fn is_corruption(&self) -> bool {
-        self.code == Code::Corruption
+        self.code == ErrorCode::Corruption
     }
 
     pub fn is_not_supported(&self) -> bool {
-        self.code == Code::NotSupported
+        self.code == ErrorCode::NotSupported
     }
 
     pub fn is_invalid_argument(&self) -> bool {
-        self.code == Code::InvalidArgument
+        self.code == ErrorCode::InvalidArgument
     }
 
     pub fn is_io_error(&self) -> bool {
-        self.code == Code::IOError
+        self.code == ErrorCode::IOError
+    }
+
+    pub fn is_timed_out(&self) -> bool {
+        self.code == ErrorCode::TimedOut
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.code == ErrorCode::Cancelled
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.code == ErrorCode::Expired
+    }
+
+    pub fn is_busy(&self) -> bool {
+        self.code == ErrorCode::Busy
     }
 }
 
 impl Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_fmt(format_args!("{}: {}", self.code.to_string(), self.msg))
+        f.write_fmt(format_args!("{}: {}", self.code, self.msg))?;
+        match &self.context {
+            Some((file, offset)) => f.write_fmt(format_args!(" ({} at offset {})", file, offset)),
+            None => Ok(()),
+        }
     }
 }
 
@@ -98,10 +258,11 @@ pub type Result<T> = std::result::Result<T, Error>;
 
 #[cfg(test)]
 mod tests {
-    use super::Result;
+    use super::{ErrorCode, Result};
     use crate::util::result::Error;
 
     #[test]
+    #[allow(clippy::unnecessary_literal_unwrap)]
     fn test_result_move() {
         let ok: Result<()> = Ok(());
         let ok2 = ok;
@@ -113,4 +274,44 @@ mod tests {
         assert!(error.is_not_found());
         assert_eq!("NotFound: custom NotFound message", error.to_string());
     }
+
+    #[test]
+    fn test_result_wire_format() {
+        let errors = [
+            Error::not_found(""),
+            Error::io_error("disk full"),
+            Error::corruption("bad block").with_context("000005.ldb", 4096),
+        ];
+        let mut encoded = vec![];
+        for error in &errors {
+            error.encode_to(&mut encoded);
+        }
+        let mut input = encoded.as_slice();
+        for error in &errors {
+            let (decoded, len) = Error::decode_from(input).unwrap();
+            assert_eq!(error.code(), decoded.code());
+            assert_eq!(error.to_string(), decoded.to_string());
+            assert_eq!(error.file(), decoded.file());
+            assert_eq!(error.offset(), decoded.offset());
+            input = &input[len..];
+        }
+        assert!(input.is_empty());
+
+        assert_eq!(
+            "Corruption: bad block (000005.ldb at offset 4096)",
+            errors[2].to_string()
+        );
+        assert_eq!(5, errors[1].code() as u8);
+        assert_eq!(Some(ErrorCode::IOError), ErrorCode::from_u8(5));
+        assert_eq!(None, ErrorCode::from_u8(0));
+
+        // Truncated or unknown input is rejected
+        for len in 0..encoded.len() {
+            if let Some((_, used)) = Error::decode_from(&encoded[..len]) {
+                assert!(used <= len);
+            }
+        }
+        assert!(Error::decode_from(&[10, 0, 0]).is_none());
+        assert!(Error::decode_from(&[1, 0, 2]).is_none());
+    }
 }
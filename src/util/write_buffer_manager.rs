@@ -0,0 +1,46 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// A WriteBufferManager bounds the memory used by the memtables of all
+/// the databases sharing it.  Each database reports the memory of its
+/// memtable through reserve() and free(); once the total goes past
+/// buffer_size(), should_flush() asks them to flush their memtables
+/// early, before they reach their own write_buffer_size.
+pub struct WriteBufferManager {
+    buffer_size: usize,
+    memory_used: AtomicUsize,
+}
+
+impl WriteBufferManager {
+    pub fn new(buffer_size: usize) -> Self {
+        Self {
+            buffer_size,
+            memory_used: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn buffer_size(&self) -> usize {
+        self.buffer_size
+    }
+
+    /// Total memory reported by the databases sharing the manager.
+    pub fn memory_usage(&self) -> usize {
+        self.memory_used.load(Ordering::Relaxed)
+    }
+
+    /// Return true if the memtables together use more than the buffer
+    /// size.
+    pub fn should_flush(&self) -> bool {
+        self.memory_usage() > self.buffer_size
+    }
+
+    /// Account for "bytes" more of memtable memory.
+    pub fn reserve(&self, bytes: usize) {
+        self.memory_used.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Release "bytes" of memtable memory reserved before.
+    pub fn free(&self, bytes: usize) {
+        let previous = self.memory_used.fetch_sub(bytes, Ordering::Relaxed);
+        debug_assert!(previous >= bytes);
+    }
+}
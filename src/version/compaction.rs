@@ -0,0 +1,220 @@
+use std::{cmp::Ordering, sync::Arc};
+
+use super::{FileMetaData, Version, VersionEdit};
+use crate::{
+    db::ReadConfig,
+    dbformat::{InternalKeyComparator, NUM_LEVELS},
+    iterator::{new_merging_iterator, Iterator},
+    table::TableCache,
+    util::Comparator,
+};
+
+/// Maximum bytes of overlaps in grandparent (i.e., level+2) before we
+/// stop building a single file in a level->level+1 compaction.
+pub(super) fn max_grand_parent_overlap_bytes(max_file_size: u64) -> u64 {
+    10 * max_file_size
+}
+
+/// Maximum number of bytes in all compacted files.  We avoid expanding
+/// the lower level file set of a compaction if it would make the
+/// total compaction cover more than this many bytes.
+pub(super) fn expanded_compaction_byte_size_limit(max_file_size: u64) -> u64 {
+    25 * max_file_size
+}
+
+pub(super) fn total_file_size(files: &[Arc<FileMetaData>]) -> u64 {
+    files.iter().map(|file| file.file_size).sum()
+}
+
+/// A Compaction encapsulates information about a compaction.
+pub struct Compaction {
+    icmp: InternalKeyComparator,
+    column_family: u32,
+    level: usize,
+    max_output_file_size: u64,
+    input_version: Arc<Version>,
+    edit: VersionEdit,
+
+    /// Each compaction reads inputs from "level" and "level+1"
+    pub(super) inputs: [Vec<Arc<FileMetaData>>; 2],
+
+    /// State used to check for number of overlapping grandparent files
+    /// (parent == level + 1, grandparent == level + 2)
+    pub(super) grandparents: Vec<Arc<FileMetaData>>,
+    /// Index in grandparents
+    grandparent_index: usize,
+    /// Some output key has been seen
+    seen_key: bool,
+    /// Bytes of overlap between current output and grandparent files
+    overlapped_bytes: u64,
+
+    // State for implementing is_base_level_for_key
+    /// level_ptrs holds indices into input_version.files(): our state
+    /// is that we are positioned at one of the file ranges for each
+    /// higher level than the ones involved in this compaction (i.e. for
+    /// all L >= level + 2).
+    level_ptrs: [usize; NUM_LEVELS],
+}
+
+impl Compaction {
+    pub(super) fn new(
+        icmp: InternalKeyComparator,
+        column_family: u32,
+        level: usize,
+        max_file_size: u64,
+        input_version: Arc<Version>,
+    ) -> Self {
+        let mut edit = VersionEdit::new();
+        edit.set_column_family(column_family);
+        Self {
+            icmp,
+            column_family,
+            level,
+            max_output_file_size: max_file_size,
+            input_version,
+            edit,
+            inputs: Default::default(),
+            grandparents: vec![],
+            grandparent_index: 0,
+            seen_key: false,
+            overlapped_bytes: 0,
+            level_ptrs: [0; NUM_LEVELS],
+        }
+    }
+
+    /// Return the id of the column family whose files are compacted.
+    pub fn column_family(&self) -> u32 {
+        self.column_family
+    }
+
+    /// Return the level that is being compacted.  Inputs from "level"
+    /// and "level+1" will be merged to produce a set of "level+1" files.
+    pub fn level(&self) -> usize {
+        self.level
+    }
+
+    /// Return the object that holds the edits to the descriptor done
+    /// by this compaction.
+    pub fn edit(&mut self) -> &mut VersionEdit {
+        &mut self.edit
+    }
+
+    /// "which" must be either 0 or 1
+    pub fn num_input_files(&self, which: usize) -> usize {
+        self.inputs[which].len()
+    }
+
+    /// Return the ith input file at "level()+which" ("which" must be 0 or 1).
+    pub fn input(&self, which: usize, i: usize) -> &Arc<FileMetaData> {
+        &self.inputs[which][i]
+    }
+
+    /// Maximum size of files to build during this compaction.
+    pub fn max_output_file_size(&self) -> u64 {
+        self.max_output_file_size
+    }
+
+    /// Create an iterator that reads over the inputs, and verifies the
+    /// checksums of what it reads if DBConfig::paranoid_checks is set.
+    pub fn make_input_iterator(
+        &self,
+        table_cache: &TableCache,
+        paranoid_checks: bool,
+    ) -> Box<dyn Iterator> {
+        let config = ReadConfig {
+            verify_checksums: paranoid_checks,
+            fill_cache: false,
+            ..Default::default()
+        };
+        let children = self
+            .inputs
+            .iter()
+            .flatten()
+            .map(|file| table_cache.new_iterator(&config, file.number, file.file_size))
+            .collect();
+        new_merging_iterator(Arc::new(self.icmp.clone()), children)
+    }
+
+    /// The version the inputs were picked from.
+    pub fn input_version(&self) -> &Arc<Version> {
+        &self.input_version
+    }
+
+    /// Is this a trivial compaction that can be implemented by just
+    /// moving a single input file to the next level (no merging or splitting)
+    pub fn is_trivial_move(&self) -> bool {
+        // Avoid a move if there is lots of overlapping grandparent data.
+        // Otherwise, the move could create a parent file that will require
+        // a very expensive merge later on.
+        self.num_input_files(0) == 1
+            && self.num_input_files(1) == 0
+            && total_file_size(&self.grandparents)
+                <= max_grand_parent_overlap_bytes(self.max_output_file_size)
+    }
+
+    /// Returns true if no level below "level+1" holds any files, so that
+    /// the outputs will be the oldest data in the database.
+    pub fn is_bottommost_level(&self) -> bool {
+        (self.level + 2..NUM_LEVELS).all(|level| self.input_version.files(level).is_empty())
+    }
+
+    /// Add all inputs to this compaction as delete operations to the edit.
+    pub fn add_input_deletions(&mut self) {
+        for which in 0..2 {
+            for file in &self.inputs[which] {
+                self.edit.remove_file(self.level + which, file.number);
+            }
+        }
+    }
+
+    /// Returns true if the information we have available guarantees that
+    /// the compaction is producing data in "level+1" for which no data exists
+    /// in levels greater than "level+1".
+    pub fn is_base_level_for_key(&mut self, user_key: &[u8]) -> bool {
+        // Maybe use binary search to find right entry instead of linear search?
+        let user_cmp = self.icmp.user_comparator();
+        for level in self.level + 2..NUM_LEVELS {
+            let files = self.input_version.files(level);
+            while let Some(file) = files.get(self.level_ptrs[level]) {
+                if user_cmp.compare(user_key, file.largest.user_key()) != Ordering::Greater {
+                    // We've advanced far enough
+                    if user_cmp.compare(user_key, file.smallest.user_key()) != Ordering::Less {
+                        // Key falls in this file's range, so definitely not base level
+                        return false;
+                    }
+                    break;
+                }
+                self.level_ptrs[level] += 1;
+            }
+        }
+        true
+    }
+
+    /// Returns true iff we should stop building the current output
+    /// before processing "internal_key".
+    pub fn should_stop_before(&mut self, internal_key: &[u8]) -> bool {
+        // Scan to find earliest grandparent file that contains key.
+        while let Some(grandparent) = self.grandparents.get(self.grandparent_index) {
+            if self
+                .icmp
+                .compare(internal_key, grandparent.largest.encode())
+                != Ordering::Greater
+            {
+                break;
+            }
+            if self.seen_key {
+                self.overlapped_bytes += grandparent.file_size;
+            }
+            self.grandparent_index += 1;
+        }
+        self.seen_key = true;
+
+        if self.overlapped_bytes > max_grand_parent_overlap_bytes(self.max_output_file_size) {
+            // Too much overlap for current output; start new output
+            self.overlapped_bytes = 0;
+            true
+        } else {
+            false
+        }
+    }
+}
@@ -0,0 +1,618 @@
+use std::{cmp::Ordering, collections::BTreeSet};
+
+use crate::{
+    db::CompactionStats,
+    dbformat::{InternalKey, RangeTombstone, ValueType, MAX_SEQUENCE_NUMBER, NUM_LEVELS},
+    env::WritableFile,
+    log::Writer,
+    util::{
+        extend_size_prefixed_slice, extend_varint32, extend_varint64, get_size_prefixed_slice,
+        get_varint32, get_varint64, Comparator, Error, Result,
+    },
+};
+
+/// Tag numbers for serialized VersionEdit.  These numbers are written to
+/// disk and should not be changed.
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[repr(u32)]
+enum Tag {
+    Comparator = 1,
+    LogNumber = 2,
+    NextFileNumber = 3,
+    LastSequence = 4,
+    CompactPointer = 5,
+    DeletedFile = 6,
+    NewFile = 7,
+    // 8 was used for large value refs
+    PrevLogNumber = 9,
+    CompactionStats = 10,
+    // Column family records, numbered apart from the tags above
+    ColumnFamily = 200,
+    ColumnFamilyAdd = 201,
+    ColumnFamilyDrop = 202,
+    MaxColumnFamily = 203,
+}
+
+impl Tag {
+    fn from_u32(value: u32) -> Option<Self> {
+        match value {
+            1 => Some(Self::Comparator),
+            2 => Some(Self::LogNumber),
+            3 => Some(Self::NextFileNumber),
+            4 => Some(Self::LastSequence),
+            5 => Some(Self::CompactPointer),
+            6 => Some(Self::DeletedFile),
+            7 => Some(Self::NewFile),
+            9 => Some(Self::PrevLogNumber),
+            10 => Some(Self::CompactionStats),
+            200 => Some(Self::ColumnFamily),
+            201 => Some(Self::ColumnFamilyAdd),
+            202 => Some(Self::ColumnFamilyDrop),
+            203 => Some(Self::MaxColumnFamily),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FileMetaData {
+    pub number: u64,
+    /// File size in bytes
+    pub file_size: u64,
+    /// Smallest internal key served by table
+    pub smallest: InternalKey,
+    /// Largest internal key served by table
+    pub largest: InternalKey,
+}
+
+impl FileMetaData {
+    /// Widen [smallest,largest] to the range of "tombstone", a range
+    /// tombstone stored in the file.  The end of the range is excluded
+    /// from it, so the largest key is the first internal key of "end".
+    /// "icmp" orders internal keys.
+    pub fn extend_to_range_tombstone(&mut self, icmp: &dyn Comparator, tombstone: &RangeTombstone) {
+        let smallest = tombstone.internal_key();
+        if self.smallest.encode().is_empty()
+            || icmp.compare(smallest.encode(), self.smallest.encode()) == Ordering::Less
+        {
+            self.smallest = smallest;
+        }
+        let largest = InternalKey::new(
+            &tombstone.end,
+            MAX_SEQUENCE_NUMBER,
+            ValueType::RangeDeletion,
+        );
+        if self.largest.encode().is_empty()
+            || icmp.compare(largest.encode(), self.largest.encode()) == Ordering::Greater
+        {
+            self.largest = largest;
+        }
+    }
+}
+
+/// A VersionEdit describes the difference between two versions of the
+/// set of live table files.  Edits are appended to the MANIFEST as log
+/// records and replayed in order on recovery.
+///
+/// An edit applies to the level structure of a single column family,
+/// the default one unless set_column_family() says otherwise.  The log
+/// number of an edit to another column family is that column family's
+/// own: its entries in older logs are all in tables.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct VersionEdit {
+    comparator: Option<String>,
+    column_family: u32,
+    column_family_add: Option<String>,
+    column_family_drop: bool,
+    max_column_family: Option<u32>,
+    log_number: Option<u64>,
+    prev_log_number: Option<u64>,
+    next_file_number: Option<u64>,
+    last_sequence: Option<u64>,
+    compact_pointers: Vec<(usize, InternalKey)>,
+    deleted_files: BTreeSet<(usize, u64)>,
+    new_files: Vec<(usize, FileMetaData)>,
+    compaction_stats: Vec<(usize, CompactionStats)>,
+}
+
+impl VersionEdit {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    pub fn set_comparator_name(&mut self, name: &str) {
+        self.comparator = Some(name.to_string());
+    }
+
+    /// Make the edit apply to the column family with the specified id.
+    pub fn set_column_family(&mut self, id: u32) {
+        self.column_family = id;
+    }
+
+    /// Create the column family of the edit, under the specified name.
+    pub fn add_column_family(&mut self, name: &str) {
+        self.column_family_add = Some(name.to_string());
+    }
+
+    /// Drop the column family of the edit along with all its files.
+    pub fn drop_column_family(&mut self) {
+        self.column_family_drop = true;
+    }
+
+    /// Record that no column family id above "id" was ever handed out.
+    pub fn set_max_column_family(&mut self, id: u32) {
+        self.max_column_family = Some(id);
+    }
+
+    pub fn set_log_number(&mut self, num: u64) {
+        self.log_number = Some(num);
+    }
+
+    pub fn set_prev_log_number(&mut self, num: u64) {
+        self.prev_log_number = Some(num);
+    }
+
+    pub fn set_next_file(&mut self, num: u64) {
+        self.next_file_number = Some(num);
+    }
+
+    pub fn set_last_sequence(&mut self, seq: u64) {
+        self.last_sequence = Some(seq);
+    }
+
+    pub fn set_compact_pointer(&mut self, level: usize, key: InternalKey) {
+        self.compact_pointers.push((level, key));
+    }
+
+    /// Add the specified file at the specified number.
+    /// REQUIRES: This version has not been saved (see VersionSet::save_to)
+    /// REQUIRES: "smallest" and "largest" are smallest and largest keys in file
+    pub fn add_file(
+        &mut self,
+        level: usize,
+        file: u64,
+        file_size: u64,
+        smallest: InternalKey,
+        largest: InternalKey,
+    ) {
+        self.new_files.push((
+            level,
+            FileMetaData {
+                number: file,
+                file_size,
+                smallest,
+                largest,
+            },
+        ));
+    }
+
+    /// Delete the specified "file" from the specified "level".
+    pub fn remove_file(&mut self, level: usize, file: u64) {
+        self.deleted_files.insert((level, file));
+    }
+
+    /// Record the work done writing to "level", to be added to the
+    /// lifetime statistics of the level.
+    pub fn add_compaction_stats(&mut self, level: usize, stats: CompactionStats) {
+        self.compaction_stats.push((level, stats));
+    }
+
+    pub fn comparator_name(&self) -> Option<&str> {
+        self.comparator.as_deref()
+    }
+
+    pub fn column_family(&self) -> u32 {
+        self.column_family
+    }
+
+    pub fn column_family_add(&self) -> Option<&str> {
+        self.column_family_add.as_deref()
+    }
+
+    pub fn is_column_family_drop(&self) -> bool {
+        self.column_family_drop
+    }
+
+    pub fn max_column_family(&self) -> Option<u32> {
+        self.max_column_family
+    }
+
+    pub fn log_number(&self) -> Option<u64> {
+        self.log_number
+    }
+
+    pub fn prev_log_number(&self) -> Option<u64> {
+        self.prev_log_number
+    }
+
+    pub fn next_file_number(&self) -> Option<u64> {
+        self.next_file_number
+    }
+
+    pub fn last_sequence(&self) -> Option<u64> {
+        self.last_sequence
+    }
+
+    pub fn compact_pointers(&self) -> &[(usize, InternalKey)] {
+        &self.compact_pointers
+    }
+
+    pub fn deleted_files(&self) -> &BTreeSet<(usize, u64)> {
+        &self.deleted_files
+    }
+
+    pub fn new_files(&self) -> &[(usize, FileMetaData)] {
+        &self.new_files
+    }
+
+    pub fn compaction_stats(&self) -> &[(usize, CompactionStats)] {
+        &self.compaction_stats
+    }
+
+    pub fn encode_to(&self, dst: &mut Vec<u8>) {
+        if let Some(comparator) = &self.comparator {
+            extend_varint32(dst, Tag::Comparator as u32);
+            extend_size_prefixed_slice(dst, comparator.as_bytes());
+        }
+        if self.column_family != 0 {
+            extend_varint32(dst, Tag::ColumnFamily as u32);
+            extend_varint32(dst, self.column_family);
+        }
+        if let Some(name) = &self.column_family_add {
+            extend_varint32(dst, Tag::ColumnFamilyAdd as u32);
+            extend_size_prefixed_slice(dst, name.as_bytes());
+        }
+        if self.column_family_drop {
+            extend_varint32(dst, Tag::ColumnFamilyDrop as u32);
+        }
+        if let Some(max_column_family) = self.max_column_family {
+            extend_varint32(dst, Tag::MaxColumnFamily as u32);
+            extend_varint32(dst, max_column_family);
+        }
+        if let Some(log_number) = self.log_number {
+            extend_varint32(dst, Tag::LogNumber as u32);
+            extend_varint64(dst, log_number);
+        }
+        if let Some(prev_log_number) = self.prev_log_number {
+            extend_varint32(dst, Tag::PrevLogNumber as u32);
+            extend_varint64(dst, prev_log_number);
+        }
+        if let Some(next_file_number) = self.next_file_number {
+            extend_varint32(dst, Tag::NextFileNumber as u32);
+            extend_varint64(dst, next_file_number);
+        }
+        if let Some(last_sequence) = self.last_sequence {
+            extend_varint32(dst, Tag::LastSequence as u32);
+            extend_varint64(dst, last_sequence);
+        }
+
+        for (level, key) in &self.compact_pointers {
+            extend_varint32(dst, Tag::CompactPointer as u32);
+            // level
+            extend_varint32(dst, *level as u32);
+            extend_size_prefixed_slice(dst, key.encode());
+        }
+
+        for &(level, number) in &self.deleted_files {
+            extend_varint32(dst, Tag::DeletedFile as u32);
+            // level
+            extend_varint32(dst, level as u32);
+            extend_varint64(dst, number);
+        }
+
+        for (level, file) in &self.new_files {
+            extend_varint32(dst, Tag::NewFile as u32);
+            // level
+            extend_varint32(dst, *level as u32);
+            extend_varint64(dst, file.number);
+            extend_varint64(dst, file.file_size);
+            extend_size_prefixed_slice(dst, file.smallest.encode());
+            extend_size_prefixed_slice(dst, file.largest.encode());
+        }
+
+        for (level, stats) in &self.compaction_stats {
+            extend_varint32(dst, Tag::CompactionStats as u32);
+            // level
+            extend_varint32(dst, *level as u32);
+            extend_varint64(dst, stats.micros);
+            extend_varint64(dst, stats.bytes_read);
+            extend_varint64(dst, stats.bytes_written);
+        }
+    }
+
+    pub fn decode_from(src: &[u8]) -> Result<Self> {
+        let mut result = Self::new();
+        let mut input = src;
+        let mut msg = None;
+
+        while msg.is_none() && !input.is_empty() {
+            let tag = match get_varint32(&mut input) {
+                Some(tag) => tag,
+                None => break,
+            };
+            match Tag::from_u32(tag) {
+                Some(Tag::Comparator) => match get_size_prefixed_slice(&mut input) {
+                    Some(name) => {
+                        result.comparator = Some(String::from_utf8_lossy(name).into_owned())
+                    }
+                    None => msg = Some("comparator name"),
+                },
+                Some(Tag::ColumnFamily) => match get_varint32(&mut input) {
+                    Some(id) => result.column_family = id,
+                    None => msg = Some("column family id"),
+                },
+                Some(Tag::ColumnFamilyAdd) => match get_size_prefixed_slice(&mut input) {
+                    Some(name) => {
+                        result.column_family_add = Some(String::from_utf8_lossy(name).into_owned())
+                    }
+                    None => msg = Some("column family name"),
+                },
+                Some(Tag::ColumnFamilyDrop) => result.column_family_drop = true,
+                Some(Tag::MaxColumnFamily) => match get_varint32(&mut input) {
+                    Some(id) => result.max_column_family = Some(id),
+                    None => msg = Some("max column family"),
+                },
+                Some(Tag::LogNumber) => match get_varint64(&mut input) {
+                    Some(number) => result.log_number = Some(number),
+                    None => msg = Some("log number"),
+                },
+                Some(Tag::PrevLogNumber) => match get_varint64(&mut input) {
+                    Some(number) => result.prev_log_number = Some(number),
+                    None => msg = Some("previous log number"),
+                },
+                Some(Tag::NextFileNumber) => match get_varint64(&mut input) {
+                    Some(number) => result.next_file_number = Some(number),
+                    None => msg = Some("next file number"),
+                },
+                Some(Tag::LastSequence) => match get_varint64(&mut input) {
+                    Some(seq) => result.last_sequence = Some(seq),
+                    None => msg = Some("last sequence number"),
+                },
+                Some(Tag::CompactPointer) => {
+                    match (get_level(&mut input), get_internal_key(&mut input)) {
+                        (Some(level), Some(key)) => result.compact_pointers.push((level, key)),
+                        _ => msg = Some("compaction pointer"),
+                    }
+                }
+                Some(Tag::DeletedFile) => match (get_level(&mut input), get_varint64(&mut input)) {
+                    (Some(level), Some(number)) => {
+                        result.deleted_files.insert((level, number));
+                    }
+                    _ => msg = Some("deleted file"),
+                },
+                Some(Tag::NewFile) => match (
+                    get_level(&mut input),
+                    get_varint64(&mut input),
+                    get_varint64(&mut input),
+                    get_internal_key(&mut input),
+                    get_internal_key(&mut input),
+                ) {
+                    (Some(level), Some(number), Some(file_size), Some(smallest), Some(largest)) => {
+                        result.new_files.push((
+                            level,
+                            FileMetaData {
+                                number,
+                                file_size,
+                                smallest,
+                                largest,
+                            },
+                        ))
+                    }
+                    _ => msg = Some("new-file entry"),
+                },
+                Some(Tag::CompactionStats) => match (
+                    get_level(&mut input),
+                    get_varint64(&mut input),
+                    get_varint64(&mut input),
+                    get_varint64(&mut input),
+                ) {
+                    (Some(level), Some(micros), Some(bytes_read), Some(bytes_written)) => {
+                        result.compaction_stats.push((
+                            level,
+                            CompactionStats {
+                                micros,
+                                bytes_read,
+                                bytes_written,
+                            },
+                        ))
+                    }
+                    _ => msg = Some("compaction stats"),
+                },
+                None => msg = Some("unknown tag"),
+            }
+        }
+
+        if msg.is_none() && !input.is_empty() {
+            msg = Some("invalid tag");
+        }
+
+        match msg {
+            Some(msg) => Err(Error::corruption(&format!("VersionEdit: {}", msg))),
+            None => Ok(result),
+        }
+    }
+
+    /// Append this edit to a MANIFEST as a single log record.
+    pub fn write_to<W: WritableFile>(&self, log: &mut Writer<W>) -> Result<()> {
+        let mut record = vec![];
+        self.encode_to(&mut record);
+        log.add_record(&record)
+    }
+}
+
+fn get_internal_key(input: &mut &[u8]) -> Option<InternalKey> {
+    let mut key = InternalKey::new_empty();
+    let encoded = get_size_prefixed_slice(input)?;
+    key.decode_from(encoded).then_some(key)
+}
+
+fn get_level(input: &mut &[u8]) -> Option<usize> {
+    let level = get_varint32(input)? as usize;
+    (level < NUM_LEVELS).then_some(level)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        dbformat::ValueType,
+        env::{SequentialFile, WritableFile},
+        log::Reader,
+    };
+
+    fn test_encode_decode(edit: &VersionEdit) {
+        let mut encoded = vec![];
+        edit.encode_to(&mut encoded);
+        let parsed = VersionEdit::decode_from(&encoded).unwrap();
+        assert_eq!(edit, &parsed);
+        let mut encoded2 = vec![];
+        parsed.encode_to(&mut encoded2);
+        assert_eq!(encoded, encoded2);
+    }
+
+    fn sample_edit() -> VersionEdit {
+        const BIG: u64 = 1 << 50;
+        let mut edit = VersionEdit::new();
+        for i in 0..4 {
+            test_encode_decode(&edit);
+            edit.add_file(
+                3,
+                BIG + 300 + i,
+                BIG + 400 + i,
+                InternalKey::new(b"foo", BIG + 500 + i, ValueType::Value),
+                InternalKey::new(b"zoo", BIG + 600 + i, ValueType::Deletion),
+            );
+            edit.remove_file(4, BIG + 700 + i);
+            edit.set_compact_pointer(
+                i as usize,
+                InternalKey::new(b"x", BIG + 900 + i, ValueType::Value),
+            );
+            edit.add_compaction_stats(
+                i as usize,
+                CompactionStats {
+                    micros: BIG + 1100 + i,
+                    bytes_read: i,
+                    bytes_written: BIG + 1200 + i,
+                },
+            );
+        }
+
+        edit.set_comparator_name("foo");
+        edit.set_log_number(BIG + 100);
+        edit.set_next_file(BIG + 200);
+        edit.set_last_sequence(BIG + 1000);
+        edit
+    }
+
+    #[test]
+    fn test_version_edit_encode_decode() {
+        let mut edit = sample_edit();
+        test_encode_decode(&edit);
+        edit.set_prev_log_number(7);
+        test_encode_decode(&edit);
+        edit.set_column_family(3);
+        edit.add_column_family("users");
+        edit.set_max_column_family(3);
+        test_encode_decode(&edit);
+        edit.drop_column_family();
+        test_encode_decode(&edit);
+        edit.clear();
+        assert_eq!(VersionEdit::new(), edit);
+    }
+
+    #[test]
+    fn test_version_edit_decode_corruption() {
+        let mut encoded = vec![];
+        sample_edit().encode_to(&mut encoded);
+
+        // Every truncation either fails or decodes a prefix of the edits
+        for len in 0..encoded.len() {
+            let _ = VersionEdit::decode_from(&encoded[..len]);
+        }
+        assert!(VersionEdit::decode_from(&encoded[..encoded.len() - 1])
+            .unwrap_err()
+            .is_corruption());
+
+        // Unknown tag
+        let mut unknown = vec![];
+        extend_varint32(&mut unknown, 8);
+        extend_varint64(&mut unknown, 1);
+        assert!(VersionEdit::decode_from(&unknown)
+            .unwrap_err()
+            .is_corruption());
+
+        // Level out of range
+        let mut bad_level = vec![];
+        extend_varint32(&mut bad_level, Tag::DeletedFile as u32);
+        extend_varint32(&mut bad_level, NUM_LEVELS as u32);
+        extend_varint64(&mut bad_level, 1);
+        assert!(VersionEdit::decode_from(&bad_level)
+            .unwrap_err()
+            .is_corruption());
+    }
+
+    struct VecFile {
+        contents: Vec<u8>,
+        offset: usize,
+    }
+
+    impl WritableFile for VecFile {
+        fn append(&mut self, data: &[u8]) -> Result<()> {
+            self.contents.extend_from_slice(data);
+            Ok(())
+        }
+
+        fn close(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        fn sync(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SequentialFile for VecFile {
+        fn read(&mut self, dst: &mut [u8]) -> Result<usize> {
+            let n = dst.len().min(self.contents.len() - self.offset);
+            dst[..n].copy_from_slice(&self.contents[self.offset..self.offset + n]);
+            self.offset += n;
+            Ok(n)
+        }
+
+        fn skip(&mut self, n: usize) -> Result<()> {
+            self.offset = (self.offset + n).min(self.contents.len());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_version_edit_manifest_round_trip() {
+        let file = VecFile {
+            contents: vec![],
+            offset: 0,
+        };
+        let mut edits = vec![sample_edit(), VersionEdit::new()];
+        edits[1].set_log_number(42);
+        edits[1].remove_file(0, 12);
+
+        let mut writer = Writer::new(file);
+        for edit in &edits {
+            edit.write_to(&mut writer).unwrap();
+        }
+
+        let mut reader = Reader::new(writer.into_file(), 0, true, None);
+        for edit in &edits {
+            let record = reader.read_record().unwrap();
+            assert_eq!(edit, &VersionEdit::decode_from(record).unwrap());
+        }
+        assert!(reader.read_record().is_none());
+    }
+}
@@ -0,0 +1,7 @@
+mod compaction;
+mod edit;
+mod set;
+
+pub use compaction::Compaction;
+pub use edit::{FileMetaData, VersionEdit};
+pub use set::{Version, VersionSet};
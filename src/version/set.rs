@@ -0,0 +1,1689 @@
+use std::{
+    cell::RefCell,
+    cmp::{Ordering, Reverse},
+    collections::{BTreeMap, BTreeSet},
+    rc::Rc,
+    str,
+    sync::{Arc, Weak},
+    time::Instant,
+};
+
+use super::{
+    compaction::{expanded_compaction_byte_size_limit, total_file_size, Compaction},
+    FileMetaData, VersionEdit,
+};
+use crate::{
+    db::{
+        check_deadline, max_covering_sequence, CompactionStats, DBConfig, LookupState, ReadConfig,
+        DEFAULT_COLUMN_FAMILY, DEFAULT_COLUMN_FAMILY_NAME,
+    },
+    dbformat::{
+        InternalKey, InternalKeyComparator, LookupKey, ParsedInternalKey, RangeTombstone,
+        ValueType, L0_COMPACTION_TRIGGER, NUM_LEVELS, VALUE_TYPE_FOR_SEEK,
+    },
+    env::{read_file_to_vec, Env, WritableFile, WriteHint},
+    filename::{current_file_name, descriptor_file_name, set_current_file},
+    iterator::{Iterator, TwoLevelIterator},
+    log::{Reader, Reporter, Writer},
+    table::TableCache,
+    util::{decode_fixed64, extend_fixed64, Comparator, Error, Result},
+};
+
+/// The set of table files that make up the database at one point in
+/// time.  Versions are immutable once built; a VersionEdit applied to a
+/// Version produces a new Version.
+#[derive(Default)]
+pub struct Version {
+    /// List of files per level
+    files: [Vec<Arc<FileMetaData>>; NUM_LEVELS],
+
+    // Level that should be compacted next and its compaction score.
+    // Score < 1 means compaction is not strictly needed.  These fields
+    // are initialized by finalize().
+    compaction_score: f64,
+    compaction_level: usize,
+    level_scores: [f64; NUM_LEVELS],
+    pending_compaction_bytes: u64,
+}
+
+impl Version {
+    /// Files at "level", ordered by smallest key.  Files at levels > 0
+    /// never overlap.
+    pub fn files(&self, level: usize) -> &[Arc<FileMetaData>] {
+        &self.files[level]
+    }
+
+    pub fn num_files(&self, level: usize) -> usize {
+        self.files[level].len()
+    }
+
+    /// Return a human readable string that describes this version's
+    /// contents.
+    pub fn debug_string(&self) -> String {
+        let mut r = String::new();
+        for (level, files) in self.files.iter().enumerate() {
+            // E.g.,
+            //   --- level 1 ---
+            //   17:123['a' .. 'd']
+            //   20:43['e' .. 'g']
+            r.push_str(&format!("--- level {} ---\n", level));
+            for file in files {
+                r.push_str(&format!(
+                    " {}:{}[{} .. {}]\n",
+                    file.number,
+                    file.file_size,
+                    file.smallest.debug_string(),
+                    file.largest.debug_string()
+                ));
+            }
+        }
+        r
+    }
+
+    /// Return the approximate offset in the database of the data for
+    /// internal key "ikey": the bytes of the table files of this version
+    /// that hold keys before it.
+    pub fn approximate_offset_of(
+        &self,
+        icmp: &InternalKeyComparator,
+        ikey: &[u8],
+        table_cache: &TableCache,
+    ) -> u64 {
+        let mut result = 0;
+        for level in 0..NUM_LEVELS {
+            for file in &self.files[level] {
+                if icmp.compare(file.largest.encode(), ikey) != Ordering::Greater {
+                    // Entire file is before "ikey", so just add the file size
+                    result += file.file_size;
+                } else if icmp.compare(file.smallest.encode(), ikey) == Ordering::Greater {
+                    // Entire file is after "ikey", so ignore
+                    if level > 0 {
+                        // Files other than level 0 are sorted by smallest, so
+                        // no further files in this level will contain data for
+                        // "ikey".
+                        break;
+                    }
+                } else {
+                    // "ikey" falls in the range for this table.  Add the
+                    // approximate offset of "ikey" within the table.
+                    result += table_cache.approximate_offset_of(file.number, file.file_size, ikey);
+                }
+            }
+        }
+        result
+    }
+
+    /// Lookup the value for "key" in the table files of this version.
+    /// Returns None if no file holds an entry for the user key other than
+    /// merge operands, which are added to "state".  Otherwise the newest
+    /// other entry decides: its value, or an error that satisfies
+    /// Error::is_not_found() if the entry is a deletion or is covered by
+    /// a range tombstone.  state.max_covering_tombstone_sequence is
+    /// raised to the sequence number of the newest range tombstone
+    /// visible to the read that covers the user key in the files looked
+    /// at, and entries older than it are treated as deleted.
+    pub fn get(
+        &self,
+        config: &ReadConfig,
+        key: &LookupKey,
+        icmp: &InternalKeyComparator,
+        table_cache: &TableCache,
+        state: &mut LookupState,
+    ) -> Option<Result<Vec<u8>>> {
+        let ikey = key.internal_key();
+        let user_key = key.user_key();
+        let ucmp = icmp.user_comparator();
+
+        for level in 0..NUM_LEVELS {
+            let files: Vec<&Arc<FileMetaData>> = if level == 0 {
+                // Level-0 files may overlap each other.  Find all files that
+                // overlap user_key and process them in order from newest to oldest.
+                let mut files: Vec<_> = self.files[0]
+                    .iter()
+                    .filter(|file| {
+                        ucmp.compare(user_key, file.smallest.user_key()) != Ordering::Less
+                            && ucmp.compare(user_key, file.largest.user_key()) != Ordering::Greater
+                    })
+                    .collect();
+                files.sort_by_key(|file| Reverse(file.number));
+                files
+            } else {
+                // Binary search to find earliest index whose largest key >= ikey.
+                let index = find_file(icmp, &self.files[level], ikey);
+                match self.files[level].get(index) {
+                    // All of "file" is past any data for user_key
+                    Some(file)
+                        if ucmp.compare(user_key, file.smallest.user_key()) == Ordering::Less =>
+                    {
+                        vec![]
+                    }
+                    Some(file) => vec![file],
+                    None => vec![],
+                }
+            };
+
+            for file in files {
+                if let Err(error) =
+                    check_deadline(config.deadline).and_then(|()| config.check_cancelled())
+                {
+                    return Some(Err(error));
+                }
+                let tombstones = match table_cache.range_tombstones(file.number, file.file_size) {
+                    Ok(tombstones) => tombstones,
+                    Err(error) => return Some(Err(error)),
+                };
+                state.max_covering_tombstone_sequence =
+                    state
+                        .max_covering_tombstone_sequence
+                        .max(max_covering_sequence(
+                            &tombstones,
+                            ucmp.as_ref(),
+                            user_key,
+                            key.sequence(),
+                        ));
+                // Entries of the key are read newest first until one that
+                // is not a merge operand
+                let mut seek_key = ikey.to_vec();
+                loop {
+                    let start = Instant::now();
+                    let entry = table_cache.get(config, file.number, file.file_size, &seek_key);
+                    if config
+                        .io_timeout
+                        .is_some_and(|io_timeout| start.elapsed() > io_timeout)
+                    {
+                        return Some(Err(Error::timed_out("file read exceeded io_timeout")));
+                    }
+                    let (found_key, value) = match entry {
+                        Ok(Some(entry)) => entry,
+                        Ok(None) => break,
+                        Err(error) => return Some(Err(error)),
+                    };
+                    let parsed = match ParsedInternalKey::parse(&found_key) {
+                        Some(parsed) => parsed,
+                        None => return Some(Err(Error::corruption("corrupted key in table"))),
+                    };
+                    if ucmp.compare(parsed.user_key(), user_key) != Ordering::Equal {
+                        break;
+                    }
+                    if parsed.sequence() < state.max_covering_tombstone_sequence {
+                        return Some(Err(Error::not_found("")));
+                    }
+                    match parsed.type_() {
+                        ValueType::Value => return Some(Ok(value)),
+                        ValueType::Deletion => return Some(Err(Error::not_found(""))),
+                        ValueType::Merge => state.merge_operands.push(value),
+                        ValueType::RangeDeletion => {
+                            return Some(Err(Error::corruption(
+                                "range tombstone among the keys of a table",
+                            )))
+                        }
+                    }
+                    if parsed.sequence() == 0 {
+                        break;
+                    }
+                    seek_key.clear();
+                    ParsedInternalKey::new(user_key, parsed.sequence() - 1, VALUE_TYPE_FOR_SEEK)
+                        .append_to(&mut seek_key);
+                }
+            }
+        }
+        None
+    }
+
+    /// Append to "iters" a sequence of iterators that will
+    /// yield the contents of this Version when merged together.
+    pub fn add_iterators(
+        &self,
+        config: &ReadConfig,
+        icmp: &InternalKeyComparator,
+        table_cache: &Arc<TableCache>,
+        iters: &mut Vec<Box<dyn Iterator>>,
+    ) {
+        // Merge all level zero files together since they may overlap
+        iters.extend(
+            self.files[0]
+                .iter()
+                .map(|file| table_cache.new_iterator(config, file.number, file.file_size)),
+        );
+
+        // For levels > 0, we can use a concatenating iterator that
+        // sequentially walks through the non-overlapping files in the
+        // level, opening them lazily.
+        for level in 1..NUM_LEVELS {
+            if !self.files[level].is_empty() {
+                iters.push(self.new_concatenating_iterator(config, icmp, table_cache, level));
+            }
+        }
+    }
+
+    fn new_concatenating_iterator(
+        &self,
+        config: &ReadConfig,
+        icmp: &InternalKeyComparator,
+        table_cache: &Arc<TableCache>,
+        level: usize,
+    ) -> Box<dyn Iterator> {
+        let table_cache = table_cache.clone();
+        Box::new(TwoLevelIterator::new(
+            LevelFileNumIterator::new(icmp.clone(), self.files[level].clone()),
+            move |config, file_value| {
+                table_cache.new_iterator(
+                    config,
+                    decode_fixed64(file_value),
+                    decode_fixed64(&file_value[8..]),
+                )
+            },
+            config,
+        ))
+    }
+
+    /// Append the range tombstones of the table files of this Version to
+    /// "tombstones".
+    pub fn add_range_tombstones(
+        &self,
+        table_cache: &TableCache,
+        tombstones: &mut Vec<RangeTombstone>,
+    ) -> Result<()> {
+        for file in self.files.iter().flatten() {
+            tombstones
+                .extend_from_slice(&table_cache.range_tombstones(file.number, file.file_size)?);
+        }
+        Ok(())
+    }
+
+    /// Return all files in "level" that overlap [begin,end].  None for
+    /// "begin" means before all keys and None for "end" after all keys.
+    pub fn get_overlapping_inputs(
+        &self,
+        icmp: &InternalKeyComparator,
+        level: usize,
+        begin: Option<&InternalKey>,
+        end: Option<&InternalKey>,
+    ) -> Vec<Arc<FileMetaData>> {
+        let user_cmp = icmp.user_comparator();
+        let mut user_begin = begin.map(|key| key.user_key().to_vec());
+        let mut user_end = end.map(|key| key.user_key().to_vec());
+        let mut inputs = vec![];
+        let mut i = 0;
+        while i < self.files[level].len() {
+            let file = &self.files[level][i];
+            i += 1;
+            let file_start = file.smallest.user_key();
+            let file_limit = file.largest.user_key();
+            let before_begin = |begin: &[u8]| user_cmp.compare(file_limit, begin) == Ordering::Less;
+            let after_end = |end: &[u8]| user_cmp.compare(file_start, end) == Ordering::Greater;
+            if user_begin.as_deref().is_some_and(before_begin)
+                || user_end.as_deref().is_some_and(after_end)
+            {
+                // "file" is completely before or after the specified range; skip it
+                continue;
+            }
+
+            inputs.push(file.clone());
+            if level == 0 {
+                // Level-0 files may overlap each other.  So check if the newly
+                // added file has expanded the range.  If so, restart search.
+                if user_begin
+                    .as_deref()
+                    .is_some_and(|begin| user_cmp.compare(file_start, begin) == Ordering::Less)
+                {
+                    user_begin = Some(file_start.to_vec());
+                    inputs.clear();
+                    i = 0;
+                } else if user_end
+                    .as_deref()
+                    .is_some_and(|end| user_cmp.compare(file_limit, end) == Ordering::Greater)
+                {
+                    user_end = Some(file_limit.to_vec());
+                    inputs.clear();
+                    i = 0;
+                }
+            }
+        }
+        inputs
+    }
+
+    /// Combined file size of all files at "level".
+    pub fn level_bytes(&self, level: usize) -> u64 {
+        self.files[level].iter().map(|file| file.file_size).sum()
+    }
+
+    /// The highest compaction score over all levels.  A score of 1 or
+    /// more means the level it belongs to needs a compaction.
+    pub fn compaction_score(&self) -> f64 {
+        self.compaction_score
+    }
+
+    /// The level with the highest compaction score.
+    pub fn compaction_level(&self) -> usize {
+        self.compaction_level
+    }
+
+    /// Compaction score of "level".  The last level is never compacted
+    /// and always scores 0.
+    pub fn level_score(&self, level: usize) -> f64 {
+        self.level_scores[level]
+    }
+
+    /// Estimated number of bytes compactions have to rewrite before every
+    /// level is back under its size target.
+    pub fn pending_compaction_bytes(&self) -> u64 {
+        self.pending_compaction_bytes
+    }
+}
+
+/// Return the smallest and largest key of all entries in "inputs".
+/// REQUIRES: inputs is not empty
+fn get_range<'a>(
+    icmp: &InternalKeyComparator,
+    inputs: impl IntoIterator<Item = &'a Arc<FileMetaData>>,
+) -> (InternalKey, InternalKey) {
+    let mut inputs = inputs.into_iter();
+    let first = inputs.next().expect("empty compaction input");
+    let mut smallest = &first.smallest;
+    let mut largest = &first.largest;
+    for file in inputs {
+        if icmp.compare(file.smallest.encode(), smallest.encode()) == Ordering::Less {
+            smallest = &file.smallest;
+        }
+        if icmp.compare(file.largest.encode(), largest.encode()) == Ordering::Greater {
+            largest = &file.largest;
+        }
+    }
+    (smallest.clone(), largest.clone())
+}
+
+/// Finds the minimum file b2=(l2, u2) in "level_files" for which l2 > u1
+/// and user_key(l2) = user_key(u1), where u1 is "largest_key".
+fn find_smallest_boundary_file<'a>(
+    icmp: &InternalKeyComparator,
+    level_files: &'a [Arc<FileMetaData>],
+    largest_key: &InternalKey,
+) -> Option<&'a Arc<FileMetaData>> {
+    let user_cmp = icmp.user_comparator();
+    let mut smallest_boundary_file: Option<&Arc<FileMetaData>> = None;
+    for file in level_files {
+        if icmp.compare(file.smallest.encode(), largest_key.encode()) == Ordering::Greater
+            && user_cmp.compare(file.smallest.user_key(), largest_key.user_key()) == Ordering::Equal
+            && smallest_boundary_file.is_none_or(|boundary| {
+                icmp.compare(file.smallest.encode(), boundary.smallest.encode()) == Ordering::Less
+            })
+        {
+            smallest_boundary_file = Some(file);
+        }
+    }
+    smallest_boundary_file
+}
+
+/// Extracts the largest file b1 from "compaction_files" and then searches
+/// for a b2 in "level_files" for which user_key(u1) = user_key(l2).  If it
+/// finds such a file b2 (known as a boundary file) it adds it to
+/// "compaction_files" and then searches again using this new upper bound.
+///
+/// If there are two blocks, b1=(l1, u1) and b2=(l2, u2) and
+/// user_key(u1) = user_key(l2), and if we compact b1 but not b2 then a
+/// subsequent get operation will yield an incorrect result because it will
+/// return the record from b2 in level i rather than from b1 because it
+/// searches level by level for records matching the supplied user key.
+fn add_boundary_inputs(
+    icmp: &InternalKeyComparator,
+    level_files: &[Arc<FileMetaData>],
+    compaction_files: &mut Vec<Arc<FileMetaData>>,
+) {
+    if compaction_files.is_empty() {
+        return;
+    }
+    let (_, mut largest_key) = get_range(icmp, compaction_files.iter());
+    while let Some(file) = find_smallest_boundary_file(icmp, level_files, &largest_key) {
+        compaction_files.push(file.clone());
+        largest_key = file.largest.clone();
+    }
+}
+
+/// An internal iterator.  For a given version/level pair, yields
+/// information about the files in the level.  For a given entry, key()
+/// is the largest key that occurs in the file, and value() is a 16-byte
+/// value containing the file number and file size, both encoded using
+/// extend_fixed64.
+struct LevelFileNumIterator {
+    icmp: InternalKeyComparator,
+    files: Vec<Arc<FileMetaData>>,
+    /// files.len() when the iterator is invalid
+    index: usize,
+    /// Backing store for value().  Holds the file number and size.
+    value_buf: Vec<u8>,
+}
+
+impl LevelFileNumIterator {
+    fn new(icmp: InternalKeyComparator, files: Vec<Arc<FileMetaData>>) -> Self {
+        let index = files.len();
+        Self {
+            icmp,
+            files,
+            index,
+            value_buf: Vec::with_capacity(16),
+        }
+    }
+
+    fn set_index(&mut self, index: usize) {
+        self.index = index;
+        self.value_buf.clear();
+        if let Some(file) = self.files.get(index) {
+            extend_fixed64(&mut self.value_buf, file.number);
+            extend_fixed64(&mut self.value_buf, file.file_size);
+        }
+    }
+}
+
+impl Iterator for LevelFileNumIterator {
+    fn valid(&self) -> bool {
+        self.index < self.files.len()
+    }
+
+    fn seek_to_first(&mut self) {
+        self.set_index(0);
+    }
+
+    fn seek_to_last(&mut self) {
+        // Invalid if there are no files
+        self.set_index(self.files.len().checked_sub(1).unwrap_or(self.files.len()));
+    }
+
+    fn seek(&mut self, target: &[u8]) {
+        self.set_index(find_file(&self.icmp, &self.files, target));
+    }
+
+    fn seek_for_prev(&mut self, target: &[u8]) {
+        let index = find_file(&self.icmp, &self.files, target);
+        let at_target = self.files.get(index).is_some_and(|file| {
+            self.icmp.compare(file.largest.encode(), target) == Ordering::Equal
+        });
+        if at_target {
+            self.set_index(index);
+        } else {
+            // Marks as invalid when before the first file
+            self.set_index(index.checked_sub(1).unwrap_or(self.files.len()));
+        }
+    }
+
+    fn next(&mut self) {
+        assert!(self.valid());
+        self.set_index(self.index + 1);
+    }
+
+    fn prev(&mut self) {
+        assert!(self.valid());
+        // Marks as invalid when at the first file
+        self.set_index(self.index.checked_sub(1).unwrap_or(self.files.len()));
+    }
+
+    fn key(&self) -> &[u8] {
+        assert!(self.valid());
+        self.files[self.index].largest.encode()
+    }
+
+    fn value(&self) -> &[u8] {
+        assert!(self.valid());
+        &self.value_buf
+    }
+
+    fn status(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Return the smallest index i such that files[i].largest >= key.
+/// Return files.len() if there is no such file.
+/// REQUIRES: "files" contains a sorted list of non-overlapping files.
+pub fn find_file(icmp: &InternalKeyComparator, files: &[Arc<FileMetaData>], key: &[u8]) -> usize {
+    files.partition_point(|file| icmp.compare(file.largest.encode(), key) == Ordering::Less)
+}
+
+fn max_bytes_for_level(level: usize) -> f64 {
+    // Note: the result for level zero is not really used since we set
+    // the level-0 compaction threshold based on number of files.
+
+    // Result for both level-0 and level-1
+    let mut result = 10. * 1048576.0;
+    for _ in 1..level {
+        result *= 10.;
+    }
+    result
+}
+
+/// Add the statistics recorded in "edit" to the per-level totals.
+fn add_compaction_stats(totals: &mut [CompactionStats; NUM_LEVELS], edit: &VersionEdit) {
+    for (level, stats) in edit.compaction_stats() {
+        totals[*level].add(stats);
+    }
+}
+
+/// Precompute the compaction scores and debt of a freshly built version.
+fn finalize(version: &mut Version) {
+    // Precomputed best level for next compaction
+    let mut best_level = 0;
+    let mut best_score = -1.;
+
+    for level in 0..NUM_LEVELS - 1 {
+        let score = if level == 0 {
+            // We treat level-0 specially by bounding the number of files
+            // instead of number of bytes for two reasons:
+            //
+            // (1) With larger write-buffer sizes, it is nice not to do too
+            // many level-0 compactions.
+            //
+            // (2) The files in level-0 are merged on every read and
+            // therefore we wish to avoid too many files when the individual
+            // file size is small (perhaps because of a small write-buffer
+            // setting, or very high compression ratios, or lots of
+            // overwrites/deletions).
+            version.num_files(level) as f64 / L0_COMPACTION_TRIGGER as f64
+        } else {
+            // Compute the ratio of current size to size limit.
+            version.level_bytes(level) as f64 / max_bytes_for_level(level)
+        };
+        version.level_scores[level] = score;
+
+        if score > best_score {
+            best_level = level;
+            best_score = score;
+        }
+    }
+
+    version.compaction_level = best_level;
+    version.compaction_score = best_score;
+    version.pending_compaction_bytes = estimate_pending_compaction_bytes(version);
+}
+
+fn estimate_pending_compaction_bytes(version: &Version) -> u64 {
+    let mut pending = 0.;
+
+    // Once enough level-0 files pile up they are merged with the whole of
+    // level-1, and all of their bytes move down a level.
+    let mut incoming = 0.;
+    let level0_bytes = version.level_bytes(0) as f64;
+    if version.num_files(0) >= L0_COMPACTION_TRIGGER || level0_bytes >= max_bytes_for_level(1) {
+        pending += level0_bytes + version.level_bytes(1) as f64;
+        incoming = level0_bytes;
+    }
+
+    // Every byte a level holds over its target is merged into the next
+    // level, rewriting the overlapping part of that level along with it.
+    for level in 1..NUM_LEVELS - 1 {
+        let level_bytes = version.level_bytes(level) as f64 + incoming;
+        let target = max_bytes_for_level(level);
+        incoming = 0.;
+        if level_bytes > target {
+            let excess = level_bytes - target;
+            let fanout = version.level_bytes(level + 1) as f64 / level_bytes;
+            pending += excess * (fanout + 1.);
+            incoming = excess;
+        }
+    }
+    pending as u64
+}
+
+#[derive(Default)]
+struct LevelState {
+    deleted_files: BTreeSet<u64>,
+    added_files: Vec<Arc<FileMetaData>>,
+}
+
+/// A helper class so we can efficiently apply a whole sequence
+/// of edits to a particular state without creating intermediate
+/// Versions that contain full copies of the intermediate state.
+struct Builder<'a> {
+    icmp: &'a InternalKeyComparator,
+    base: Arc<Version>,
+    levels: [LevelState; NUM_LEVELS],
+}
+
+impl<'a> Builder<'a> {
+    /// Initialize a builder with the files from "base" and other info
+    /// from "icmp".
+    fn new(icmp: &'a InternalKeyComparator, base: Arc<Version>) -> Self {
+        Self {
+            icmp,
+            base,
+            levels: Default::default(),
+        }
+    }
+
+    /// Apply all of the edits in "edit" to the current state.
+    fn apply(&mut self, edit: &VersionEdit, compact_pointer: &mut [Vec<u8>; NUM_LEVELS]) {
+        // Update compaction pointers
+        for (level, key) in edit.compact_pointers() {
+            compact_pointer[*level] = key.encode().to_vec();
+        }
+
+        // Delete files
+        for &(level, number) in edit.deleted_files() {
+            self.levels[level].deleted_files.insert(number);
+        }
+
+        // Add new files
+        for (level, file) in edit.new_files() {
+            self.levels[*level].deleted_files.remove(&file.number);
+            self.levels[*level].added_files.push(Arc::new(file.clone()));
+        }
+    }
+
+    /// Save the current state in a new Version.
+    fn save_to(self) -> Version {
+        let mut version = Version::default();
+        for (level, state) in self.levels.into_iter().enumerate() {
+            let files = &mut version.files[level];
+            files.extend(
+                self.base.files[level]
+                    .iter()
+                    .chain(state.added_files.iter())
+                    .filter(|file| !state.deleted_files.contains(&file.number))
+                    .cloned(),
+            );
+            files.sort_by(|a, b| by_smallest_key(self.icmp, a, b));
+
+            if level > 0 {
+                // Make sure there is no overlap in levels > 0
+                for pair in files.windows(2) {
+                    assert_eq!(
+                        self.icmp
+                            .compare(pair[0].largest.encode(), pair[1].smallest.encode()),
+                        Ordering::Less,
+                        "overlapping ranges in same level"
+                    );
+                }
+            }
+        }
+        version
+    }
+}
+
+fn by_smallest_key(icmp: &InternalKeyComparator, a: &FileMetaData, b: &FileMetaData) -> Ordering {
+    match icmp.compare(a.smallest.encode(), b.smallest.encode()) {
+        // Break ties by file number
+        Ordering::Equal => a.number.cmp(&b.number),
+        order => order,
+    }
+}
+
+#[derive(Default)]
+struct LogReporter {
+    status: Option<Error>,
+}
+
+impl Reporter for LogReporter {
+    fn corruption(&mut self, _bytes: usize, error: &Error) {
+        if self.status.is_none() {
+            self.status = Some(error.clone());
+        }
+    }
+}
+
+/// The level structure of a column family: its current Version and the
+/// state kept to pick its compactions.
+struct ColumnFamilyVersions {
+    name: String,
+    current: Arc<Version>,
+    /// The entries of the column family in logs older than this one are
+    /// all in tables.  Unused for the default column family, which goes
+    /// by VersionSet::log_number.
+    log_number: u64,
+
+    /// Per-level key at which the next compaction at that level should start.
+    /// Either an empty vector, or a valid InternalKey.
+    compact_pointer: [Vec<u8>; NUM_LEVELS],
+
+    /// Per-level work done by flushes and compactions since the
+    /// column family was created.
+    compaction_stats: [CompactionStats; NUM_LEVELS],
+}
+
+impl ColumnFamilyVersions {
+    fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            current: Arc::new(Version::default()),
+            log_number: 0,
+            compact_pointer: Default::default(),
+            compaction_stats: Default::default(),
+        }
+    }
+}
+
+/// The VersionSet tracks the current Version of every column family of
+/// the database along with the file-number and sequence-number counters,
+/// and persists every change to them in the MANIFEST.
+pub struct VersionSet {
+    env: Arc<dyn Env>,
+    dbname: String,
+    icmp: InternalKeyComparator,
+    next_file_number: u64,
+    manifest_file_number: u64,
+    last_sequence: u64,
+    log_number: u64,
+    /// 0 or backing store for memtable being compacted
+    prev_log_number: u64,
+    max_file_size: u64,
+    /// DBConfig::sync_dir_after_create
+    sync_dir_after_create: bool,
+
+    // Opened lazily
+    descriptor_log: Option<Writer<Box<dyn WritableFile>>>,
+    /// The column families by id.  The default one is always there.
+    column_families: BTreeMap<u32, ColumnFamilyVersions>,
+    /// The largest column family id ever handed out
+    max_column_family: u32,
+    /// Every version that was current at some point, as long as a read,
+    /// iterator or compaction still holds it.  Their files are live.
+    versions: Vec<Weak<Version>>,
+}
+
+impl VersionSet {
+    pub fn new(dbname: &str, config: &DBConfig, icmp: InternalKeyComparator) -> Self {
+        Self {
+            env: config.env.clone(),
+            dbname: dbname.to_string(),
+            icmp,
+            next_file_number: 2,
+            manifest_file_number: 0, // Filled by recover()
+            last_sequence: 0,
+            log_number: 0,
+            prev_log_number: 0,
+            max_file_size: config.max_file_size as u64,
+            sync_dir_after_create: config.sync_dir_after_create,
+            descriptor_log: None,
+            column_families: BTreeMap::from([(
+                DEFAULT_COLUMN_FAMILY,
+                ColumnFamilyVersions::new(DEFAULT_COLUMN_FAMILY_NAME),
+            )]),
+            max_column_family: DEFAULT_COLUMN_FAMILY,
+            versions: vec![],
+        }
+    }
+
+    fn default_column_family(&self) -> &ColumnFamilyVersions {
+        &self.column_families[&DEFAULT_COLUMN_FAMILY]
+    }
+
+    /// Apply "edit" to the current version of its column family to form
+    /// a new descriptor that is both saved to persistent state and
+    /// installed as the new current version.  An edit that adds a column
+    /// family starts from an empty version.
+    pub fn log_and_apply(&mut self, edit: &mut VersionEdit) -> Result<()> {
+        let id = edit.column_family();
+        let mut added = edit.column_family_add().map(ColumnFamilyVersions::new);
+        assert_eq!(added.is_some(), !self.column_families.contains_key(&id));
+        assert!(id != DEFAULT_COLUMN_FAMILY || !edit.is_column_family_drop());
+        if id == DEFAULT_COLUMN_FAMILY {
+            match edit.log_number() {
+                Some(log_number) => {
+                    assert!(log_number >= self.log_number);
+                    assert!(log_number < self.next_file_number);
+                }
+                None => edit.set_log_number(self.log_number),
+            }
+        } else if let Some(log_number) = edit.log_number() {
+            assert!(log_number < self.next_file_number);
+        }
+        if edit.prev_log_number().is_none() {
+            edit.set_prev_log_number(self.prev_log_number);
+        }
+        if added.is_some() {
+            edit.set_max_column_family(self.max_column_family.max(id));
+        }
+        edit.set_next_file(self.next_file_number);
+        edit.set_last_sequence(self.last_sequence);
+
+        let column_family = match added.as_mut() {
+            Some(column_family) => column_family,
+            None => self.column_families.get_mut(&id).unwrap(),
+        };
+        let mut builder = Builder::new(&self.icmp, column_family.current.clone());
+        builder.apply(edit, &mut column_family.compact_pointer);
+        let mut version = builder.save_to();
+        finalize(&mut version);
+
+        // Initialize new descriptor log file if necessary by creating
+        // a temporary file that contains a snapshot of the current version.
+        let mut new_manifest_file = None;
+        let mut result = Ok(());
+        if self.descriptor_log.is_none() {
+            let manifest = descriptor_file_name(&self.dbname, self.manifest_file_number);
+            result = self
+                .env
+                .new_writable_file(&manifest, WriteHint::Manifest)
+                .and_then(|file| {
+                    let mut log = Writer::new(file);
+                    let result = self.write_snapshot(&mut log);
+                    self.descriptor_log = Some(log);
+                    result
+                });
+            new_manifest_file = Some(manifest);
+        }
+
+        // The new files the edit refers to must not outlive their
+        // directory entries
+        if result.is_ok() && self.sync_dir_after_create {
+            result = self.env.sync_dir(&self.dbname);
+        }
+
+        // Write new record to MANIFEST log
+        if result.is_ok() {
+            result = edit.write_to(self.descriptor_log.as_mut().unwrap());
+        }
+        if result.is_ok() {
+            result = self.descriptor_log.as_mut().unwrap().file_mut().sync();
+        }
+
+        // If we just created a new descriptor file, install it by writing a
+        // new CURRENT file that points to it.
+        if result.is_ok() && new_manifest_file.is_some() {
+            result = set_current_file(self.env.clone(), &self.dbname, self.manifest_file_number);
+            if result.is_ok() && self.sync_dir_after_create {
+                result = self.env.sync_dir(&self.dbname);
+            }
+        }
+
+        // Install the new version
+        if result.is_ok() {
+            if let Some(column_family) = added {
+                self.column_families.insert(id, column_family);
+                self.max_column_family = self.max_column_family.max(id);
+            }
+            if edit.is_column_family_drop() {
+                self.column_families.remove(&id);
+            } else {
+                self.append_version(id, version);
+                let column_family = self.column_families.get_mut(&id).unwrap();
+                add_compaction_stats(&mut column_family.compaction_stats, edit);
+                if id != DEFAULT_COLUMN_FAMILY {
+                    column_family.log_number =
+                        edit.log_number().unwrap_or(column_family.log_number);
+                }
+            }
+            if id == DEFAULT_COLUMN_FAMILY {
+                self.log_number = edit.log_number().unwrap();
+            }
+            self.prev_log_number = edit.prev_log_number().unwrap();
+        } else if let Some(manifest) = new_manifest_file {
+            self.descriptor_log = None;
+            let _ = self.env.remove_file(&manifest);
+        }
+        result
+    }
+
+    /// Make "version" the current version of column family "id".  The
+    /// previous one stays live until the last reference to it is dropped.
+    fn append_version(&mut self, id: u32, version: Version) {
+        self.versions.retain(|version| version.strong_count() > 0);
+        let version = Arc::new(version);
+        self.versions.push(Arc::downgrade(&version));
+        self.column_families.get_mut(&id).unwrap().current = version;
+    }
+
+    /// Return the number of versions that are still referenced,
+    /// including the current one.
+    #[cfg(test)]
+    pub fn num_live_versions(&self) -> usize {
+        self.versions
+            .iter()
+            .filter(|version| version.strong_count() > 0)
+            .count()
+    }
+
+    /// Recover the last saved descriptor from persistent storage.
+    pub fn recover(&mut self) -> Result<()> {
+        // Read "CURRENT" file, which contains a pointer to the current manifest file
+        let current = read_file_to_vec(self.env.as_ref(), &current_file_name(&self.dbname))?;
+        let current = match current.strip_suffix(b"\n") {
+            Some(current) if !current.is_empty() => current,
+            _ => return Err(Error::corruption("CURRENT file does not end with newline")),
+        };
+        let current = str::from_utf8(current)
+            .map_err(|_| Error::corruption("CURRENT file is not valid UTF-8"))?;
+
+        let dscname = format!("{}/{}", self.dbname, current);
+        let file = match self.env.new_sequential_file(&dscname) {
+            Ok(file) => file,
+            Err(error) if error.is_not_found() => {
+                return Err(Error::corruption(&format!(
+                    "CURRENT points to a non-existent file: {}",
+                    error
+                )))
+            }
+            Err(error) => return Err(error),
+        };
+
+        let mut log_number = None;
+        let mut prev_log_number = None;
+        let mut next_file = None;
+        let mut last_sequence = None;
+        let mut max_column_family = DEFAULT_COLUMN_FAMILY;
+        let mut column_families = BTreeMap::new();
+        column_families.insert(
+            DEFAULT_COLUMN_FAMILY,
+            (
+                ColumnFamilyVersions::new(DEFAULT_COLUMN_FAMILY_NAME),
+                Builder::new(&self.icmp, Arc::new(Version::default())),
+            ),
+        );
+
+        let reporter = Rc::new(RefCell::new(LogReporter::default()));
+        let mut reader = Reader::new(file, 0, true, Some(reporter.clone()));
+        while let Some(record) = reader.read_record() {
+            let edit = VersionEdit::decode_from(record)?;
+            if let Some(name) = edit.comparator_name() {
+                if name != self.icmp.user_comparator().name() {
+                    return Err(Error::invalid_argument(&format!(
+                        "{} does not match existing comparator {}",
+                        name,
+                        self.icmp.user_comparator().name()
+                    )));
+                }
+            }
+
+            let id = edit.column_family();
+            if let Some(name) = edit.column_family_add() {
+                if column_families.contains_key(&id) {
+                    return Err(Error::corruption("column family added twice"));
+                }
+                column_families.insert(
+                    id,
+                    (
+                        ColumnFamilyVersions::new(name),
+                        Builder::new(&self.icmp, Arc::new(Version::default())),
+                    ),
+                );
+            }
+            let (column_family, builder) = column_families
+                .get_mut(&id)
+                .ok_or_else(|| Error::corruption("edit of an unknown column family"))?;
+            if edit.is_column_family_drop() {
+                if id == DEFAULT_COLUMN_FAMILY {
+                    return Err(Error::corruption("default column family dropped"));
+                }
+                column_families.remove(&id);
+            } else {
+                builder.apply(&edit, &mut column_family.compact_pointer);
+                add_compaction_stats(&mut column_family.compaction_stats, &edit);
+                if id != DEFAULT_COLUMN_FAMILY {
+                    column_family.log_number =
+                        edit.log_number().unwrap_or(column_family.log_number);
+                }
+            }
+
+            if id == DEFAULT_COLUMN_FAMILY {
+                log_number = edit.log_number().or(log_number);
+            }
+            max_column_family = edit
+                .max_column_family()
+                .map_or(max_column_family, |max| max.max(max_column_family));
+            prev_log_number = edit.prev_log_number().or(prev_log_number);
+            next_file = edit.next_file_number().or(next_file);
+            last_sequence = edit.last_sequence().or(last_sequence);
+
+            if let Some(error) = reporter.borrow_mut().status.take() {
+                return Err(error);
+            }
+        }
+        if let Some(error) = reporter.borrow_mut().status.take() {
+            return Err(error);
+        }
+
+        let next_file =
+            next_file.ok_or_else(|| Error::corruption("no meta-nextfile entry in descriptor"))?;
+        let log_number =
+            log_number.ok_or_else(|| Error::corruption("no meta-lognumber entry in descriptor"))?;
+        let last_sequence = last_sequence
+            .ok_or_else(|| Error::corruption("no last-sequence-number entry in descriptor"))?;
+        let prev_log_number = prev_log_number.unwrap_or(0);
+
+        let column_families: Vec<_> = column_families
+            .into_iter()
+            .map(|(id, (column_family, builder))| {
+                let mut version = builder.save_to();
+                finalize(&mut version);
+                (id, column_family, version)
+            })
+            .collect();
+        self.column_families.clear();
+        for (id, column_family, version) in column_families {
+            self.column_families.insert(id, column_family);
+            self.append_version(id, version);
+        }
+        self.max_column_family = max_column_family;
+        self.manifest_file_number = next_file;
+        self.next_file_number = next_file + 1;
+        self.last_sequence = last_sequence;
+        self.log_number = log_number;
+        self.prev_log_number = prev_log_number;
+        self.mark_file_number_used(prev_log_number);
+        self.mark_file_number_used(log_number);
+        Ok(())
+    }
+
+    /// Return the current version of the default column family.
+    pub fn current(&self) -> Arc<Version> {
+        self.default_column_family().current.clone()
+    }
+
+    /// Return the current version of column family "id".
+    /// REQUIRES: the column family exists
+    pub fn current_cf(&self, id: u32) -> Arc<Version> {
+        self.column_families[&id].current.clone()
+    }
+
+    /// Return the current versions of all the column families.
+    pub fn current_versions(&self) -> Vec<Arc<Version>> {
+        self.column_families
+            .values()
+            .map(|column_family| column_family.current.clone())
+            .collect()
+    }
+
+    /// Return the id and name of every column family but the default
+    /// one, along with the number of the oldest log that may hold some
+    /// of its entries.
+    pub fn column_families(&self) -> Vec<(u32, &str, u64)> {
+        self.column_families
+            .iter()
+            .filter(|(&id, _)| id != DEFAULT_COLUMN_FAMILY)
+            .map(|(&id, column_family)| (id, column_family.name.as_str(), column_family.log_number))
+            .collect()
+    }
+
+    /// Return the largest column family id ever handed out.  Ids are
+    /// not reused, even once their column family is dropped.
+    pub fn max_column_family(&self) -> u32 {
+        self.max_column_family
+    }
+
+    /// Return the work done writing to "level" of the default column
+    /// family since the database was created.
+    pub fn compaction_stats(&self, level: usize) -> CompactionStats {
+        self.default_column_family().compaction_stats[level]
+    }
+
+    /// Return the current manifest file number
+    pub fn manifest_file_number(&self) -> u64 {
+        self.manifest_file_number
+    }
+
+    /// Return the number the next allocated file will get.  Files with
+    /// this number or higher did not exist when it was returned.
+    pub fn next_file_number(&self) -> u64 {
+        self.next_file_number
+    }
+
+    /// Allocate and return a new file number
+    pub fn new_file_number(&mut self) -> u64 {
+        self.next_file_number += 1;
+        self.next_file_number - 1
+    }
+
+    /// Arrange to reuse "file_number" unless a newer file number has
+    /// already been allocated.
+    /// REQUIRES: "file_number" was returned by a call to new_file_number().
+    pub fn reuse_file_number(&mut self, file_number: u64) {
+        if self.next_file_number == file_number + 1 {
+            self.next_file_number = file_number;
+        }
+    }
+
+    /// Mark the specified file number as used.
+    pub fn mark_file_number_used(&mut self, number: u64) {
+        if self.next_file_number <= number {
+            self.next_file_number = number + 1;
+        }
+    }
+
+    /// Return the number of Table files at the specified level.
+    pub fn num_level_files(&self, level: usize) -> usize {
+        self.default_column_family().current.num_files(level)
+    }
+
+    /// Return the combined file size of all files at the specified level.
+    #[cfg(test)]
+    pub fn num_level_bytes(&self, level: usize) -> u64 {
+        self.default_column_family().current.level_bytes(level)
+    }
+
+    /// Return the last sequence number.
+    pub fn last_sequence(&self) -> u64 {
+        self.last_sequence
+    }
+
+    /// Set the last sequence number to "seq".
+    pub fn set_last_sequence(&mut self, seq: u64) {
+        assert!(seq >= self.last_sequence);
+        self.last_sequence = seq;
+    }
+
+    /// Return the current log file number.
+    pub fn log_number(&self) -> u64 {
+        self.log_number
+    }
+
+    /// Return the log file number for the log file that is currently
+    /// being compacted, or zero if there is no such log file.
+    pub fn prev_log_number(&self) -> u64 {
+        self.prev_log_number
+    }
+
+    /// Returns true iff some level of some column family needs a
+    /// compaction.
+    pub fn needs_compaction(&self) -> bool {
+        self.column_families
+            .values()
+            .any(|column_family| column_family.current.compaction_score >= 1.)
+    }
+
+    /// Pick column family, level and inputs for a new compaction, from
+    /// the column family that needs it most.
+    /// Returns None if there is no compaction to be done.
+    pub fn pick_compaction(&mut self) -> Option<Compaction> {
+        let (&id, column_family) = self
+            .column_families
+            .iter()
+            .filter(|(_, column_family)| column_family.current.compaction_score >= 1.)
+            .max_by(|(_, a), (_, b)| {
+                a.current
+                    .compaction_score
+                    .total_cmp(&b.current.compaction_score)
+            })?;
+        let current = column_family.current.clone();
+        let level = current.compaction_level;
+        assert!(level + 1 < NUM_LEVELS);
+        let mut c = Compaction::new(
+            self.icmp.clone(),
+            id,
+            level,
+            self.max_file_size,
+            current.clone(),
+        );
+
+        // Pick the first file that comes after compact_pointer[level]
+        let compact_pointer = &column_family.compact_pointer[level];
+        let first = current.files[level].iter().find(|file| {
+            compact_pointer.is_empty()
+                || self.icmp.compare(file.largest.encode(), compact_pointer) == Ordering::Greater
+        });
+        // Wrap-around to the beginning of the key space
+        let first = first.unwrap_or(&current.files[level][0]);
+        c.inputs[0].push(first.clone());
+
+        // Files in level 0 may overlap each other, so pick up all overlapping ones
+        if level == 0 {
+            let (smallest, largest) = get_range(&self.icmp, &c.inputs[0]);
+            // Note that the next call will discard the file we placed in
+            // c.inputs[0] earlier and replace it with an overlapping set
+            // which will include the picked file.
+            c.inputs[0] =
+                current.get_overlapping_inputs(&self.icmp, 0, Some(&smallest), Some(&largest));
+            assert!(!c.inputs[0].is_empty());
+        }
+
+        self.setup_other_inputs(&mut c);
+        Some(c)
+    }
+
+    /// Return a compaction object for compacting the range [begin,end] in
+    /// the specified level of column family "id".  Returns None if there
+    /// is nothing in that level that overlaps the specified range.
+    pub fn compact_range(
+        &mut self,
+        id: u32,
+        level: usize,
+        begin: Option<&InternalKey>,
+        end: Option<&InternalKey>,
+    ) -> Option<Compaction> {
+        let current = self.current_cf(id);
+        let mut inputs = current.get_overlapping_inputs(&self.icmp, level, begin, end);
+        if inputs.is_empty() {
+            return None;
+        }
+
+        // Avoid compacting too much in one shot in case the range is large.
+        // But we cannot do this for level-0 since level-0 files can overlap
+        // and we must not pick one file and drop another older file if the
+        // two files overlap.
+        if level > 0 {
+            let mut total = 0;
+            if let Some(i) = inputs.iter().position(|file| {
+                total += file.file_size;
+                total >= self.max_file_size
+            }) {
+                inputs.truncate(i + 1);
+            }
+        }
+
+        let mut c = Compaction::new(self.icmp.clone(), id, level, self.max_file_size, current);
+        c.inputs[0] = inputs;
+        self.setup_other_inputs(&mut c);
+        Some(c)
+    }
+
+    fn setup_other_inputs(&mut self, c: &mut Compaction) {
+        let current = c.input_version().clone();
+        let level = c.level();
+        let icmp = &self.icmp;
+
+        add_boundary_inputs(icmp, current.files(level), &mut c.inputs[0]);
+        let (smallest, mut largest) = get_range(icmp, &c.inputs[0]);
+
+        c.inputs[1] =
+            current.get_overlapping_inputs(icmp, level + 1, Some(&smallest), Some(&largest));
+        add_boundary_inputs(icmp, current.files(level + 1), &mut c.inputs[1]);
+
+        // Get entire range covered by compaction
+        let (mut all_start, mut all_limit) =
+            get_range(icmp, c.inputs[0].iter().chain(c.inputs[1].iter()));
+
+        // See if we can grow the number of inputs in "level" without
+        // changing the number of "level+1" files we pick up.
+        if !c.inputs[1].is_empty() {
+            let mut expanded0 =
+                current.get_overlapping_inputs(icmp, level, Some(&all_start), Some(&all_limit));
+            add_boundary_inputs(icmp, current.files(level), &mut expanded0);
+            let inputs1_size = total_file_size(&c.inputs[1]);
+            let expanded0_size = total_file_size(&expanded0);
+            if expanded0.len() > c.inputs[0].len()
+                && inputs1_size + expanded0_size
+                    < expanded_compaction_byte_size_limit(self.max_file_size)
+            {
+                let (new_start, new_limit) = get_range(icmp, &expanded0);
+                let mut expanded1 = current.get_overlapping_inputs(
+                    icmp,
+                    level + 1,
+                    Some(&new_start),
+                    Some(&new_limit),
+                );
+                add_boundary_inputs(icmp, current.files(level + 1), &mut expanded1);
+                if expanded1.len() == c.inputs[1].len() {
+                    largest = new_limit;
+                    c.inputs[0] = expanded0;
+                    c.inputs[1] = expanded1;
+                    (all_start, all_limit) =
+                        get_range(icmp, c.inputs[0].iter().chain(c.inputs[1].iter()));
+                }
+            }
+        }
+
+        // Compute the set of grandparent files that overlap this compaction
+        // (parent == level+1; grandparent == level+2)
+        if level + 2 < NUM_LEVELS {
+            c.grandparents =
+                current.get_overlapping_inputs(icmp, level + 2, Some(&all_start), Some(&all_limit));
+        }
+
+        // Update the place where we will do the next compaction for this level.
+        // We update this immediately instead of waiting for the VersionEdit
+        // to be applied so that if the compaction fails, we will try a different
+        // key range next time.
+        self.column_families
+            .get_mut(&c.column_family())
+            .unwrap()
+            .compact_pointer[level] = largest.encode().to_vec();
+        c.edit().set_compact_pointer(level, largest);
+    }
+
+    /// Add all files listed in any live version to "live".
+    pub fn add_live_files(&self, live: &mut BTreeSet<u64>) {
+        for version in self.versions.iter().filter_map(Weak::upgrade) {
+            for files in &version.files {
+                live.extend(files.iter().map(|file| file.number));
+            }
+        }
+    }
+
+    /// Save current contents to "log", with one edit per column family
+    fn write_snapshot<W: WritableFile>(&self, log: &mut Writer<W>) -> Result<()> {
+        // Save metadata
+        let mut edit = VersionEdit::new();
+        edit.set_comparator_name(self.icmp.user_comparator().name());
+        if self.max_column_family != DEFAULT_COLUMN_FAMILY {
+            edit.set_max_column_family(self.max_column_family);
+        }
+        for (&id, column_family) in &self.column_families {
+            if id != DEFAULT_COLUMN_FAMILY {
+                edit.set_column_family(id);
+                edit.add_column_family(&column_family.name);
+                edit.set_log_number(column_family.log_number);
+            }
+            add_column_family_snapshot(&mut edit, column_family);
+            edit.write_to(log)?;
+            edit.clear();
+        }
+        Ok(())
+    }
+}
+
+/// Add the compaction pointers, statistics and files of "column_family"
+/// to "edit".
+fn add_column_family_snapshot(edit: &mut VersionEdit, column_family: &ColumnFamilyVersions) {
+    // Save compaction pointers
+    for (level, key) in column_family.compact_pointer.iter().enumerate() {
+        if !key.is_empty() {
+            let mut internal_key = InternalKey::new_empty();
+            internal_key.decode_from(key);
+            edit.set_compact_pointer(level, internal_key);
+        }
+    }
+
+    // Save the lifetime statistics, which later edits add to
+    for (level, stats) in column_family.compaction_stats.iter().enumerate() {
+        if *stats != CompactionStats::default() {
+            edit.add_compaction_stats(level, *stats);
+        }
+    }
+
+    // Save files
+    for (level, files) in column_family.current.files.iter().enumerate() {
+        for file in files {
+            edit.add_file(
+                level,
+                file.number,
+                file.file_size,
+                file.smallest.clone(),
+                file.largest.clone(),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        env::{test_dir, write_data_to_file, PosixEnv, WriteHint},
+        filename::set_current_file,
+        util::BytewiseComparator,
+    };
+
+    fn new_version_set(dbname: &str) -> VersionSet {
+        let config = DBConfig {
+            env: Arc::new(PosixEnv::new()),
+            ..Default::default()
+        };
+        let icmp = InternalKeyComparator::new(config.comparator.clone());
+        VersionSet::new(dbname, &config, icmp)
+    }
+
+    fn ikey(user_key: &str, seq: u64) -> InternalKey {
+        InternalKey::new(user_key.as_bytes(), seq, ValueType::Value)
+    }
+
+    /// Write the initial MANIFEST-1 of a new database, as DB::open does.
+    fn create(dbname: &str) {
+        let env: Arc<dyn Env> = Arc::new(PosixEnv::new());
+        let mut new_db = VersionEdit::new();
+        new_db.set_comparator_name(BytewiseComparator::new().name());
+        new_db.set_log_number(0);
+        new_db.set_next_file(2);
+        new_db.set_last_sequence(0);
+        let mut record = vec![];
+        new_db.encode_to(&mut record);
+        let mut log = Writer::new(
+            env.new_writable_file(&descriptor_file_name(dbname, 1), WriteHint::Manifest)
+                .unwrap(),
+        );
+        log.add_record(&record).unwrap();
+        log.file_mut().close().unwrap();
+        set_current_file(env, dbname, 1).unwrap();
+    }
+
+    #[test]
+    fn test_version_set_log_and_apply_recover() {
+        let dbname = test_dir("version_set");
+        create(&dbname);
+
+        let mut versions = new_version_set(&dbname);
+        versions.recover().unwrap();
+        assert_eq!(2, versions.manifest_file_number());
+        assert_eq!(0, versions.num_level_files(0));
+
+        let f1 = versions.new_file_number();
+        let f2 = versions.new_file_number();
+        let f3 = versions.new_file_number();
+        let mut edit = VersionEdit::new();
+        edit.add_file(0, f1, 100, ikey("a", 5), ikey("m", 6));
+        edit.add_file(0, f2, 200, ikey("c", 7), ikey("z", 8));
+        edit.add_file(1, f3, 300, ikey("a", 1), ikey("k", 2));
+        edit.set_compact_pointer(1, ikey("f", 3));
+        versions.set_last_sequence(8);
+        versions.log_and_apply(&mut edit).unwrap();
+        assert_eq!(2, versions.num_level_files(0));
+        assert_eq!(300, versions.num_level_bytes(0));
+        assert_eq!(1, versions.num_level_files(1));
+
+        let f4 = versions.new_file_number();
+        let mut edit = VersionEdit::new();
+        edit.remove_file(1, f3);
+        edit.add_file(1, f4, 400, ikey("b", 1), ikey("q", 2));
+        edit.set_log_number(f4);
+        versions.log_and_apply(&mut edit).unwrap();
+
+        let mut live = BTreeSet::new();
+        versions.add_live_files(&mut live);
+        assert_eq!(BTreeSet::from([f1, f2, f4]), live);
+
+        // A fresh VersionSet sees the same state
+        let mut recovered = new_version_set(&dbname);
+        recovered.recover().unwrap();
+        let version = recovered.current();
+        let numbers: Vec<_> = version.files(0).iter().map(|f| f.number).collect();
+        assert_eq!(vec![f1, f2], numbers);
+        assert_eq!(f4, version.files(1)[0].number);
+        assert_eq!(ikey("b", 1), version.files(1)[0].smallest);
+        assert_eq!(8, recovered.last_sequence());
+        assert_eq!(f4, recovered.log_number());
+        assert!(recovered.new_file_number() > f4);
+        assert_eq!(
+            ikey("f", 3).encode(),
+            recovered.default_column_family().compact_pointer[1].as_slice()
+        );
+    }
+
+    #[test]
+    fn test_version_set_compaction_scores() {
+        const MB: u64 = 1048576;
+        let dbname = test_dir("version_set_scores");
+        create(&dbname);
+        let mut versions = new_version_set(&dbname);
+        versions.recover().unwrap();
+        assert_eq!(0, versions.current().pending_compaction_bytes());
+
+        let mut edit = VersionEdit::new();
+        for i in 0..4 {
+            let number = versions.new_file_number();
+            edit.add_file(0, number, MB, ikey("a", 10 + i), ikey("z", 20 + i));
+        }
+        let number = versions.new_file_number();
+        edit.add_file(1, number, 12 * MB, ikey("a", 1), ikey("z", 1));
+        let number = versions.new_file_number();
+        edit.add_file(2, number, 50 * MB, ikey("a", 1), ikey("z", 1));
+        versions.log_and_apply(&mut edit).unwrap();
+
+        let version = versions.current();
+        assert_eq!(1.0, version.level_score(0));
+        assert_eq!(1.2, version.level_score(1));
+        assert_eq!(0.5, version.level_score(2));
+        assert_eq!(0.0, version.level_score(NUM_LEVELS - 1));
+        assert_eq!(1, version.compaction_level());
+        assert_eq!(1.2, version.compaction_score());
+        // Level-0 merges 4MB into the 12MB of level-1, which then holds
+        // 6MB over its 10MB target that also rewrite their level-2 overlap.
+        assert_eq!(
+            (16. * MB as f64 + 6. * MB as f64 * (50. / 16. + 1.)) as u64,
+            version.pending_compaction_bytes()
+        );
+
+        // Recovery computes the same scores
+        let mut recovered = new_version_set(&dbname);
+        recovered.recover().unwrap();
+        assert_eq!(1.2, recovered.current().compaction_score());
+        assert_eq!(
+            version.pending_compaction_bytes(),
+            recovered.current().pending_compaction_bytes()
+        );
+    }
+
+    #[test]
+    fn test_version_set_pick_compaction() {
+        let dbname = test_dir("version_set_pick_compaction");
+        create(&dbname);
+        let mut versions = new_version_set(&dbname);
+        versions.recover().unwrap();
+        assert!(versions.pick_compaction().is_none());
+
+        // Overlapping level-0 files pull each other in, and the level-1
+        // inputs cover their combined range.  [m,q] does not overlap [a,e]
+        // and is left for a later compaction.
+        let mut edit = VersionEdit::new();
+        let ranges = [("a", "c"), ("b", "e"), ("m", "p"), ("o", "q")];
+        for (i, (smallest, largest)) in ranges.iter().enumerate() {
+            let number = versions.new_file_number();
+            edit.add_file(
+                0,
+                number,
+                1000,
+                ikey(smallest, 10 + i as u64),
+                ikey(largest, 10),
+            );
+        }
+        let level1: Vec<_> = [("a", "b"), ("d", "f"), ("g", "k"), ("n", "z")]
+            .iter()
+            .map(|(smallest, largest)| {
+                let number = versions.new_file_number();
+                edit.add_file(1, number, 1000, ikey(smallest, 1), ikey(largest, 1));
+                number
+            })
+            .collect();
+        versions.log_and_apply(&mut edit).unwrap();
+        assert!(versions.needs_compaction());
+
+        let c = versions.pick_compaction().unwrap();
+        assert_eq!(0, c.level());
+        assert_eq!(2, c.num_input_files(0));
+        let inputs: Vec<_> = (0..c.num_input_files(1))
+            .map(|i| c.input(1, i).number)
+            .collect();
+        assert_eq!(vec![level1[0], level1[1]], inputs);
+        assert!(!c.is_trivial_move());
+
+        let version = versions.current();
+        let overlapping = version.get_overlapping_inputs(
+            &versions.icmp,
+            1,
+            Some(&ikey("e", 100)),
+            Some(&ikey("h", 100)),
+        );
+        let numbers: Vec<_> = overlapping.iter().map(|file| file.number).collect();
+        assert_eq!(vec![level1[1], level1[2]], numbers);
+        assert_eq!(
+            4,
+            version
+                .get_overlapping_inputs(&versions.icmp, 1, None, None)
+                .len()
+        );
+    }
+
+    #[test]
+    fn test_version_set_recover_errors() {
+        let dbname = test_dir("version_set_errors");
+        let env: Arc<dyn Env> = Arc::new(PosixEnv::new());
+
+        // No CURRENT file
+        assert!(new_version_set(&dbname)
+            .recover()
+            .unwrap_err()
+            .is_not_found());
+
+        let current = current_file_name(&dbname);
+        write_data_to_file(env.clone(), b"MANIFEST-1", &current).unwrap();
+        assert!(new_version_set(&dbname)
+            .recover()
+            .unwrap_err()
+            .is_corruption());
+
+        write_data_to_file(env.clone(), b"MANIFEST-9\n", &current).unwrap();
+        assert!(new_version_set(&dbname)
+            .recover()
+            .unwrap_err()
+            .is_corruption());
+
+        // Comparator mismatch
+        let mut edit = VersionEdit::new();
+        edit.set_comparator_name("leveldb.OtherComparator");
+        let mut record = vec![];
+        edit.encode_to(&mut record);
+        let mut log = Writer::new(
+            env.new_writable_file(&descriptor_file_name(&dbname, 9), WriteHint::Manifest)
+                .unwrap(),
+        );
+        log.add_record(&record).unwrap();
+        log.file_mut().close().unwrap();
+        assert!(new_version_set(&dbname)
+            .recover()
+            .unwrap_err()
+            .is_invalid_argument());
+    }
+
+    #[test]
+    fn test_level_file_num_iterator() {
+        let icmp = InternalKeyComparator::new(Arc::new(BytewiseComparator::new()));
+        let files: Vec<_> = [(1, "a", "c"), (2, "e", "g"), (3, "h", "k")]
+            .iter()
+            .map(|&(number, smallest, largest)| {
+                Arc::new(FileMetaData {
+                    number,
+                    file_size: number * 100,
+                    smallest: ikey(smallest, 1),
+                    largest: ikey(largest, 1),
+                })
+            })
+            .collect();
+        let file_number = |iter: &LevelFileNumIterator| {
+            assert_eq!(
+                decode_fixed64(&iter.value()[8..]),
+                decode_fixed64(iter.value()) * 100
+            );
+            decode_fixed64(iter.value())
+        };
+        let mut iter = LevelFileNumIterator::new(icmp, files);
+        assert!(!iter.valid());
+
+        iter.seek(ikey("d", 1).encode());
+        assert_eq!(2, file_number(&iter));
+        assert_eq!(ikey("g", 1).encode(), iter.key());
+        iter.seek(ikey("a", 1).encode());
+        assert_eq!(1, file_number(&iter));
+        iter.seek(ikey("l", 1).encode());
+        assert!(!iter.valid());
+
+        // The last file whose largest key is <= the target
+        iter.seek_for_prev(ikey("d", 1).encode());
+        assert_eq!(1, file_number(&iter));
+        iter.seek_for_prev(ikey("g", 1).encode());
+        assert_eq!(2, file_number(&iter));
+        iter.seek_for_prev(ikey("b", 1).encode());
+        assert!(!iter.valid());
+
+        iter.seek_to_last();
+        assert_eq!(3, file_number(&iter));
+        iter.prev();
+        iter.prev();
+        assert_eq!(1, file_number(&iter));
+        iter.prev();
+        assert!(!iter.valid());
+        iter.seek_to_first();
+        iter.next();
+        iter.next();
+        iter.next();
+        assert!(!iter.valid());
+
+        let mut empty = LevelFileNumIterator::new(iter.icmp.clone(), vec![]);
+        empty.seek_to_last();
+        assert!(!empty.valid());
+    }
+}
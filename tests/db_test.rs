@@ -0,0 +1,449 @@
+//! Ports of the leveldb db_test cases, run through the public API against
+//! both a directory on disk and an in-memory environment.
+
+use std::sync::Arc;
+
+use rebeldb::{
+    destroy_db,
+    env::{MemEnv, PosixEnv},
+    iterator::IterAdapter,
+    CompressionType, DBConfig, Range, ReadConfig, Snapshot, WriteConfig, DB,
+};
+
+struct DBTest {
+    dbname: String,
+    config: DBConfig,
+    db: Option<DB>,
+}
+
+impl DBTest {
+    fn db(&mut self) -> &mut DB {
+        self.db.as_mut().unwrap()
+    }
+
+    fn reopen(&mut self) {
+        self.reopen_with(self.config.clone());
+    }
+
+    fn reopen_with(&mut self, config: DBConfig) {
+        // Close the database before opening it again, to release the lock
+        self.db = None;
+        self.config = config;
+        self.db = Some(DB::open(&self.dbname, &self.config).unwrap());
+    }
+
+    fn put(&mut self, key: &[u8], value: &[u8]) {
+        self.db().put(key, value, &WriteConfig::default()).unwrap();
+    }
+
+    fn delete(&mut self, key: &[u8]) {
+        self.db().delete(key, &WriteConfig::default()).unwrap();
+    }
+
+    fn get(&mut self, key: &[u8]) -> String {
+        self.get_at(key, None)
+    }
+
+    fn get_at(&mut self, key: &[u8], snapshot: Option<&Arc<Snapshot>>) -> String {
+        let config = ReadConfig {
+            snapshot: snapshot.cloned(),
+            ..Default::default()
+        };
+        match self.db().get(key, &config) {
+            Ok(value) => String::from_utf8(value).unwrap(),
+            Err(error) if error.is_not_found() => "NOT_FOUND".to_string(),
+            Err(error) => error.to_string(),
+        }
+    }
+
+    fn num_table_files_at_level(&mut self, level: usize) -> usize {
+        self.db()
+            .get_property(&format!("rebeldb.num-files-at-level{}", level))
+            .unwrap()
+            .parse()
+            .unwrap()
+    }
+
+    fn size(&mut self, start: &[u8], limit: &[u8]) -> u64 {
+        self.db().get_approximate_sizes(&[Range::new(start, limit)])[0]
+    }
+
+    /// Return the keys and values the iterator sees, in order, as a
+    /// string of "key->value" pairs.
+    fn contents(&mut self, config: &ReadConfig) -> String {
+        IterAdapter::from_first(self.db().iter(config))
+            .map(|entry| {
+                let (key, value) = entry.unwrap();
+                format!(
+                    "{}->{}",
+                    String::from_utf8_lossy(&key),
+                    String::from_utf8_lossy(&value)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// Run "test" on a fresh database in a temporary directory, and on one in
+/// a MemEnv.
+fn run(name: &str, test: impl Fn(&mut DBTest)) {
+    let dbname = std::env::temp_dir()
+        .join(format!("rebeldb-db_test-{}-{}", name, std::process::id()))
+        .to_str()
+        .unwrap()
+        .to_string();
+    let configs = [
+        (
+            dbname,
+            DBConfig {
+                env: Arc::new(PosixEnv::new()),
+                ..Default::default()
+            },
+        ),
+        (
+            format!("/rebeldb-db_test/{}", name),
+            DBConfig {
+                env: Arc::new(MemEnv::new()),
+                ..Default::default()
+            },
+        ),
+    ];
+    for (dbname, config) in configs {
+        let _ = destroy_db(&dbname, &config);
+        let config = DBConfig {
+            create_if_missing: true,
+            ..config
+        };
+        let mut t = DBTest {
+            db: Some(DB::open(&dbname, &config).unwrap()),
+            dbname,
+            config,
+        };
+        test(&mut t);
+        t.db = None;
+        destroy_db(&t.dbname, &t.config).unwrap();
+    }
+}
+
+fn key(i: usize) -> Vec<u8> {
+    format!("key{:06}", i).into_bytes()
+}
+
+/// A deterministic string of "len" printable bytes that does not compress.
+fn random_string(seed: &mut u32, len: usize) -> String {
+    (0..len)
+        .map(|_| {
+            *seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (b' ' + ((*seed >> 16) % 95) as u8) as char
+        })
+        .collect()
+}
+
+#[test]
+fn read_write() {
+    run("read_write", |t| {
+        t.put(b"foo", b"v1");
+        assert_eq!("v1", t.get(b"foo"));
+        t.put(b"bar", b"v2");
+        t.put(b"foo", b"v3");
+        assert_eq!("v3", t.get(b"foo"));
+        assert_eq!("v2", t.get(b"bar"));
+    });
+}
+
+#[test]
+fn put_delete_get() {
+    run("put_delete_get", |t| {
+        t.put(b"foo", b"v1");
+        assert_eq!("v1", t.get(b"foo"));
+        t.put(b"foo", b"v2");
+        assert_eq!("v2", t.get(b"foo"));
+        t.delete(b"foo");
+        assert_eq!("NOT_FOUND", t.get(b"foo"));
+    });
+}
+
+#[test]
+fn get_from_immutable_layer() {
+    run("get_from_immutable_layer", |t| {
+        t.reopen_with(DBConfig {
+            write_buffer_size: 100000, // Small write buffer
+            ..t.config.clone()
+        });
+        t.put(b"foo", b"v1");
+        assert_eq!("v1", t.get(b"foo"));
+
+        // Fill the memtable, so that it is flushed in the background
+        t.put(b"k1", &[b'x'; 100000]);
+        t.put(b"k2", &[b'y'; 100000]);
+        assert_eq!("v1", t.get(b"foo"));
+
+        t.db().flush().unwrap();
+        assert!(t.num_table_files_at_level(0) > 0);
+        assert_eq!("v1", t.get(b"foo"));
+    });
+}
+
+#[test]
+fn recover() {
+    run("recover", |t| {
+        t.put(b"foo", b"v1");
+        t.put(b"baz", b"v5");
+
+        t.reopen();
+        assert_eq!("v1", t.get(b"foo"));
+
+        assert_eq!("v1", t.get(b"foo"));
+        assert_eq!("v5", t.get(b"baz"));
+        t.put(b"bar", b"v2");
+        t.put(b"foo", b"v3");
+
+        t.reopen();
+        assert_eq!("v3", t.get(b"foo"));
+        t.put(b"foo", b"v4");
+        assert_eq!("v4", t.get(b"foo"));
+        assert_eq!("v2", t.get(b"bar"));
+        assert_eq!("v5", t.get(b"baz"));
+    });
+}
+
+#[test]
+fn recovery_with_empty_log() {
+    run("recovery_with_empty_log", |t| {
+        t.put(b"foo", b"v1");
+        t.put(b"foo", b"v2");
+        t.reopen();
+        t.reopen();
+        t.put(b"foo", b"v3");
+        t.reopen();
+        assert_eq!("v3", t.get(b"foo"));
+    });
+}
+
+#[test]
+fn compactions_generate_multiple_files() {
+    run("compactions_generate_multiple_files", |t| {
+        t.reopen_with(DBConfig {
+            write_buffer_size: 100_000_000, // Large write buffer
+            ..t.config.clone()
+        });
+
+        let mut seed = 301;
+
+        // Write 8MB (80 values, each 100K)
+        assert_eq!(0, t.num_table_files_at_level(0));
+        let values: Vec<_> = (0..80)
+            .map(|i| {
+                let value = random_string(&mut seed, 100000);
+                t.put(&key(i), value.as_bytes());
+                value
+            })
+            .collect();
+
+        // Reopening moves updates to level-0
+        t.reopen();
+        t.db().compact_range(None, None).unwrap();
+
+        assert_eq!(0, t.num_table_files_at_level(0));
+        assert!(t.num_table_files_at_level(1) > 1);
+        for (i, value) in values.iter().enumerate() {
+            assert_eq!(*value, t.get(&key(i)));
+        }
+    });
+}
+
+#[test]
+fn sparse_merge() {
+    run("sparse_merge", |t| {
+        t.reopen_with(DBConfig {
+            compression: CompressionType::None,
+            ..t.config.clone()
+        });
+
+        // Suppose there is:
+        //    small amount of data with prefix A
+        //    large amount of data with prefix B
+        //    small amount of data with prefix C
+        // and that recent updates have made small changes to all three
+        // prefixes.  Check that we do not do a compaction that merges all
+        // of B in one shot.
+        let value = [b'x'; 1000];
+        t.put(b"A", b"va");
+        // Write approximately 10MB of "B" values
+        for i in 0..10000 {
+            t.put(format!("B{:010}", i).as_bytes(), &value);
+        }
+        t.put(b"C", b"vc");
+        t.db().compact_range(None, None).unwrap();
+
+        // Make sparse update
+        t.put(b"A", b"va2");
+        t.put(b"B100", b"bvalue2");
+        t.put(b"C", b"vc2");
+        t.db().compact_range(None, None).unwrap();
+        assert!(t.num_table_files_at_level(1) > 1);
+
+        assert_eq!("va2", t.get(b"A"));
+        assert_eq!("bvalue2", t.get(b"B100"));
+        assert_eq!("vc2", t.get(b"C"));
+        assert_eq!("x".repeat(1000), t.get(b"B0000005000"));
+    });
+}
+
+fn between(value: u64, low: u64, high: u64) -> bool {
+    let result = value >= low && value <= high;
+    if !result {
+        eprintln!("Value {} is not in range [{}, {}]", value, low, high);
+    }
+    result
+}
+
+#[test]
+fn approximate_sizes() {
+    run("approximate_sizes", |t| {
+        t.reopen_with(DBConfig {
+            write_buffer_size: 100_000_000, // Large write buffer
+            compression: CompressionType::None,
+            ..t.config.clone()
+        });
+
+        assert!(between(t.size(b"", b"xyz"), 0, 0));
+        t.reopen();
+        assert!(between(t.size(b"", b"xyz"), 0, 0));
+
+        // Write 8MB (80 values, each 100K)
+        assert_eq!(0, t.num_table_files_at_level(0));
+        const N: usize = 80;
+        const S1: u64 = 100000;
+        const S2: u64 = 105000; // Allow some expansion from metadata
+        let mut seed = 301;
+        for i in 0..N {
+            t.put(&key(i), random_string(&mut seed, S1 as usize).as_bytes());
+        }
+
+        // 0 because get_approximate_sizes() does not account for memtable
+        // space
+        assert!(between(t.size(b"", &key(50)), 0, 0));
+
+        // Reopening moves updates to level-0
+        t.reopen();
+        for compact_start in (0..N).step_by(10) {
+            for i in (0..N).step_by(10) {
+                let i = i as u64;
+                assert!(between(t.size(b"", &key(i as usize)), S1 * i, S2 * i));
+                let mut past = key(i as usize);
+                past.extend_from_slice(b".suffix");
+                assert!(between(t.size(b"", &past), S1 * (i + 1), S2 * (i + 1)));
+                assert!(between(
+                    t.size(&key(i as usize), &key(i as usize + 10)),
+                    S1 * 10,
+                    S2 * 10
+                ));
+            }
+            assert!(between(t.size(b"", &key(50)), S1 * 50, S2 * 50));
+
+            let start = key(compact_start);
+            let end = key(compact_start + 9);
+            t.db().compact_range(Some(&start), Some(&end)).unwrap();
+        }
+
+        assert_eq!(0, t.num_table_files_at_level(0));
+        assert!(t.num_table_files_at_level(1) > 0);
+    });
+}
+
+#[test]
+fn snapshot() {
+    run("snapshot", |t| {
+        t.put(b"foo", b"v1");
+        let s1 = t.db().get_snapshot();
+        t.put(b"foo", b"v2");
+        let s2 = t.db().get_snapshot();
+        t.put(b"foo", b"v3");
+        let s3 = t.db().get_snapshot();
+
+        t.put(b"foo", b"v4");
+        assert_eq!("v1", t.get_at(b"foo", Some(&s1)));
+        assert_eq!("v2", t.get_at(b"foo", Some(&s2)));
+        assert_eq!("v3", t.get_at(b"foo", Some(&s3)));
+        assert_eq!("v4", t.get(b"foo"));
+
+        t.db().release_snapshot(s3);
+        assert_eq!("v1", t.get_at(b"foo", Some(&s1)));
+        assert_eq!("v2", t.get_at(b"foo", Some(&s2)));
+        assert_eq!("v4", t.get(b"foo"));
+
+        t.db().release_snapshot(s1);
+        assert_eq!("v2", t.get_at(b"foo", Some(&s2)));
+        assert_eq!("v4", t.get(b"foo"));
+
+        t.db().release_snapshot(s2);
+        assert_eq!("v4", t.get(b"foo"));
+    });
+}
+
+#[test]
+fn hidden_values_are_removed() {
+    run("hidden_values_are_removed", |t| {
+        t.reopen_with(DBConfig {
+            compression: CompressionType::None,
+            ..t.config.clone()
+        });
+        let mut seed = 301;
+        let big = random_string(&mut seed, 50000);
+        t.put(b"foo", big.as_bytes());
+        t.put(b"pastfoo", b"v");
+        t.reopen();
+        assert!(t.num_table_files_at_level(0) > 0);
+        assert_eq!(big, t.get(b"foo"));
+        assert!(between(t.size(b"", b"pastfoo"), 50000, 60000));
+
+        t.put(b"foo", b"tiny");
+        t.put(b"pastfoo2", b"v2"); // Advance sequence number one more
+        t.reopen();
+        assert_eq!("tiny", t.get(b"foo"));
+
+        // Compaction drops the overwritten value
+        t.db().compact_range(None, Some(b"x")).unwrap();
+        assert_eq!("tiny", t.get(b"foo"));
+        assert_eq!(0, t.num_table_files_at_level(0));
+        assert!(between(t.size(b"", b"pastfoo"), 0, 1000));
+    });
+}
+
+#[test]
+fn db_iterator_boundary() {
+    run("db_iterator_boundary", |t| {
+        for key in [b"a", b"b", b"c", b"d", b"e"] {
+            t.put(key, key);
+        }
+        t.delete(b"c");
+        let bounded = |lower: Option<&[u8]>, upper: Option<&[u8]>| ReadConfig {
+            iterate_lower_bound: lower.map(|key| key.to_vec()),
+            iterate_upper_bound: upper.map(|key| key.to_vec()),
+            ..Default::default()
+        };
+
+        assert_eq!("b->b, d->d", t.contents(&bounded(Some(b"b"), Some(b"e"))));
+        assert_eq!("a->a, b->b", t.contents(&bounded(None, Some(b"c"))));
+        assert_eq!("d->d, e->e", t.contents(&bounded(Some(b"bb"), None)));
+        assert_eq!("", t.contents(&bounded(Some(b"c"), Some(b"d"))));
+
+        // Seeks land within the bounds
+        let config = bounded(Some(b"b"), Some(b"e"));
+        let db = t.db();
+        let mut iter = db.iter(&config);
+        iter.seek(b"a");
+        assert_eq!(b"b", iter.key());
+        iter.prev();
+        assert!(!iter.valid());
+        iter.seek_to_last();
+        assert_eq!(b"d", iter.key());
+        iter.next();
+        assert!(!iter.valid());
+        iter.seek(b"e");
+        assert!(!iter.valid());
+        iter.status().unwrap();
+    });
+}